@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Opening files inside archives, so ROM sets and test suites can be used without unpacking
+// them first. Paths ending in .gz are decompressed, `set.zip#file` names a file in a zip
+// archive and a plain `set.zip` opens the only file in it. Anything else is read as-is.
+// Archives need the `compressed` feature.
+
+enum Kind<'a> {
+    Plain,
+    Gzip,
+    // The archive and the name of the file in it, if given
+    Zip(&'a str, Option<&'a str>),
+}
+
+fn kind(path: &Path) -> Kind<'_> {
+    let path = match path.to_str() {
+        Some(path) => path,
+        None => return Kind::Plain,
+    };
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".gz") {
+        return Kind::Gzip;
+    }
+    if lower.ends_with(".zip") {
+        return Kind::Zip(path, None);
+    }
+    match lower.find(".zip#") {
+        Some(end) => Kind::Zip(&path[..end + 4], Some(&path[end + 5..])),
+        None => Kind::Plain,
+    }
+}
+
+pub fn is_archive<P: AsRef<Path>>(path: P) -> bool {
+    !matches!(kind(path.as_ref()), Kind::Plain)
+}
+
+// The name of what's inside, for picking a format by extension: `game.tap.gz` is a .tap and
+// so is `games.zip#game.tap`
+pub fn inner(path: &Path) -> &Path {
+    match kind(path) {
+        Kind::Gzip => path.to_str().map_or(path, |p| Path::new(&p[..p.len() - 3])),
+        Kind::Zip(_, Some(name)) => Path::new(name),
+        _ => path,
+    }
+}
+
+// Reads the whole file, decompressing it if it's in an archive
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// A reader for the file, .gz files are decompressed as they're read
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    match kind(path.as_ref()) {
+        Kind::Plain => Ok(Box::new(File::open(path)?)),
+        #[cfg(feature = "compressed")]
+        Kind::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(File::open(
+            path,
+        )?))),
+        #[cfg(feature = "compressed")]
+        Kind::Zip(archive, name) => Ok(Box::new(io::Cursor::new(unzip(archive, name)?))),
+        #[cfg(not(feature = "compressed"))]
+        Kind::Gzip => Err(unsupported(path.as_ref())),
+        #[cfg(not(feature = "compressed"))]
+        Kind::Zip(archive, _) => Err(unsupported(Path::new(archive))),
+    }
+}
+
+#[cfg(not(feature = "compressed"))]
+fn unsupported(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{:?} is compressed, this build doesn't have the `compressed` feature",
+            path
+        ),
+    )
+}
+
+#[cfg(feature = "compressed")]
+fn unzip(archive: &str, name: Option<&str>) -> io::Result<Vec<u8>> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut zip = zip::ZipArchive::new(File::open(archive)?).map_err(invalid)?;
+    let mut file = match name {
+        Some(name) => zip.by_name(name).map_err(|e| match e {
+            zip::result::ZipError::FileNotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {} in {}", name, archive),
+            ),
+            e => invalid(e),
+        })?,
+        None => {
+            let files: Vec<&str> = zip.file_names().filter(|f| !f.ends_with('/')).collect();
+            match files[..] {
+                [only] => {
+                    let only = only.to_string();
+                    zip.by_name(&only).map_err(invalid)?
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} holds {} files, pick one with {}#<file>",
+                            archive,
+                            files.len(),
+                            archive
+                        ),
+                    ))
+                }
+            }
+        }
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inner, is_archive};
+    use std::path::Path;
+
+    #[test]
+    fn names() {
+        assert_eq!(inner(Path::new("game.TAP.gz")), Path::new("game.TAP"));
+        assert_eq!(inner(Path::new("roms/set.zip#a.hex")), Path::new("a.hex"));
+        assert_eq!(inner(Path::new("set.zip")), Path::new("set.zip"));
+        assert_eq!(inner(Path::new("zexdoc.com")), Path::new("zexdoc.com"));
+        assert!(is_archive("set.ZIP"));
+        assert!(!is_archive("pacman#1.rom"));
+    }
+
+    #[cfg(feature = "compressed")]
+    #[test]
+    fn archives() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("z80-rs-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gz = dir.join("test.bin.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&gz).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(b"\x3E\x42").unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(super::read(&gz).unwrap(), b"\x3E\x42");
+
+        let zip = dir.join("set.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&zip).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("a.rom", options).unwrap();
+        writer.write_all(b"AAAA").unwrap();
+        writer.start_file("b.rom", options).unwrap();
+        writer.write_all(b"BB").unwrap();
+        writer.finish().unwrap();
+        let zip = zip.to_str().unwrap();
+        assert_eq!(super::read(format!("{}#b.rom", zip)).unwrap(), b"BB");
+        assert!(super::read(zip).is_err());
+        assert!(super::read(format!("{}#c.rom", zip)).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,813 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+use crate::expr::Expr;
+use crate::ihex::Segment;
+use crate::instruction_info::{Condition, Instruction, Mnemonic, Operand, Register};
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+
+// A two pass assembler for small programs: test code, patches typed into the debugger and
+// `.asm` files loaded like HEX files. Standard Zilog syntax, a statement per line:
+//
+//   label:  LD A, (IX+5)      ; comment
+//   count   EQU 10
+//           ORG 8000h
+//           DB 1, 'A', "text"  (also DEFB and DEFM)
+//           DW label, $ + 2    (also DEFW)
+//           DS 16, 0FFh        (also DEFS, without a fill value the space is left as it is)
+//           END
+//
+// Labels end with a colon or start in the first column. Operands are `expr` expressions,
+// with `$` for the address of the statement; register names can't be used as labels.
+// Encodings come from running the decoder over every opcode, so whatever the disassembler
+// shows (IXH, SLL, `RES 0, (IX+$05), B` and the other undocumented forms included)
+// assembles back to the same instruction.
+
+// An operand without its value, which is what picks the opcode
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum Shape {
+    Reg(Register),
+    AltAf,
+    Imm8,
+    Imm16,
+    Number(u8),
+    Indirect(Register),
+    Indexed(Register),
+    Absolute,
+    Port,
+    PortC,
+    Relative,
+    Condition(Condition),
+}
+
+impl Shape {
+    fn of(operand: Operand) -> Shape {
+        match operand {
+            Operand::Reg(reg) => Shape::Reg(reg),
+            Operand::AltAf => Shape::AltAf,
+            Operand::Imm8(_) => Shape::Imm8,
+            Operand::Imm16(_) => Shape::Imm16,
+            Operand::Number(n) => Shape::Number(n),
+            Operand::Indirect(reg) => Shape::Indirect(reg),
+            Operand::Indexed(reg, _) => Shape::Indexed(reg),
+            Operand::Absolute(_) => Shape::Absolute,
+            Operand::Port(_) => Shape::Port,
+            Operand::PortC => Shape::PortC,
+            Operand::Relative(_) => Shape::Relative,
+            Operand::Condition(cc) => Shape::Condition(cc),
+        }
+    }
+}
+
+// Mnemonic, operands and the register DDCB / FDCB forms copy their result to
+type Key = (Mnemonic, Option<Shape>, Option<Shape>, Option<Register>);
+
+// Prefix and opcode, as in `Instruction`
+type Encoding = (u16, u8);
+
+// An operand as written can be read several ways, `C` is a register or a condition and a
+// number can be any kind of immediate. The first one the instruction has is used.
+type Candidates = Vec<(Shape, i64)>;
+
+// Every instruction the decoder knows. Where several opcodes decode the same (NEG, IM, the
+// ED copy of LD HL, (nn), prefixed opcodes that don't use IX / IY) the first one found is
+// kept, which is the documented one. Built on first use.
+fn encodings() -> &'static HashMap<Key, Encoding> {
+    static TABLE: OnceLock<HashMap<Key, Encoding>> = OnceLock::new();
+    TABLE.get_or_init(build_encodings)
+}
+
+fn build_encodings() -> HashMap<Key, Encoding> {
+    let prefixes: [&[u8]; 7] = [
+        &[],
+        &[0xCB],
+        &[0xED],
+        &[0xDD],
+        &[0xFD],
+        &[0xDD, 0xCB, 0],
+        &[0xFD, 0xCB, 0],
+    ];
+    let mut table = HashMap::new();
+    for prefix in prefixes.iter() {
+        for op in 0..=0xFF {
+            let mut bytes = prefix.to_vec();
+            bytes.extend([op, 0, 0, 0]);
+            let i = match Instruction::decode_bytes(&bytes) {
+                Some(i) => i,
+                None => continue,
+            };
+            let shape = |operand: Option<Operand>| {
+                operand.map(|operand| match (i.mnemonic, operand) {
+                    // The RST target is part of the opcode
+                    (Mnemonic::Rst, Operand::Imm8(p)) => Shape::Number(p),
+                    _ => Shape::of(operand),
+                })
+            };
+            table
+                .entry((i.mnemonic, shape(i.dst), shape(i.src), i.copy))
+                .or_insert((i.prefix, i.opcode));
+        }
+    }
+    table
+}
+
+// Code and data by address, and the labels they were assembled with
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    // In source order, a segment per run of consecutive bytes
+    pub segments: Vec<Segment>,
+    pub symbols: Symbols,
+}
+
+impl Assembly {
+    // Writes the code through the address map, like `Memory::load_slice`
+    pub fn load(&self, memory: &mut Memory) {
+        for (addr, data) in &self.segments {
+            memory.load_slice(*addr, data);
+        }
+    }
+
+    // Lowest address written to, 0 if nothing was
+    pub fn origin(&self) -> u16 {
+        self.segments
+            .iter()
+            .map(|(addr, _)| *addr)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // Everything from `origin` up to the last byte written, gaps are zero
+    pub fn to_binary(&self) -> Vec<u8> {
+        let origin = self.origin() as usize;
+        let end = self
+            .segments
+            .iter()
+            .map(|(addr, data)| *addr as usize + data.len())
+            .max()
+            .unwrap_or(origin);
+        let mut binary = vec![0; end - origin];
+        for (addr, data) in &self.segments {
+            let start = *addr as usize - origin;
+            binary[start..start + data.len()].copy_from_slice(data);
+        }
+        binary
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    assemble_at(source, 0, &Symbols::default())
+}
+
+// Assembles from `addr` (until the first ORG) with `symbols` already defined, e.g. the
+// labels of the program being patched
+pub fn assemble_at(source: &str, addr: u16, symbols: &Symbols) -> Result<Assembly, String> {
+    let mut asm = Assembler::new(symbols.clone());
+    asm.pass(source, addr)?;
+    asm.strict = true;
+    asm.pass(source, addr)?;
+    Ok(Assembly {
+        segments: asm.segments,
+        symbols: asm.symbols,
+    })
+}
+
+// Assembled source as segments, for loading `.asm` files alongside HEX files
+pub fn parse(source: &str) -> Result<Vec<Segment>, String> {
+    assemble(source).map(|asm| asm.segments)
+}
+
+pub fn is_source(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".asm")
+}
+
+struct Assembler {
+    table: &'static HashMap<Key, Encoding>,
+    mnemonics: HashMap<&'static str, Mnemonic>,
+    symbols: Symbols,
+    // Labels defined by the source, as opposed to the ones passed in
+    labels: HashSet<String>,
+    // Address of the next byte, 0x10000 once the code reaches the end of memory
+    addr: u32,
+    segments: Vec<Segment>,
+    // The first pass only works out addresses, so labels further down read as 0 and values
+    // aren't range checked
+    strict: bool,
+    done: bool,
+}
+
+impl Assembler {
+    fn new(symbols: Symbols) -> Self {
+        let table = encodings();
+        let mnemonics = table.keys().map(|key| (key.0.name(), key.0)).collect();
+        Self {
+            table,
+            mnemonics,
+            symbols,
+            labels: HashSet::new(),
+            addr: 0,
+            segments: Vec::new(),
+            strict: false,
+            done: false,
+        }
+    }
+
+    fn pass(&mut self, source: &str, addr: u16) -> Result<(), String> {
+        self.addr = addr as u32;
+        self.segments.clear();
+        self.done = false;
+        for (n, line) in source.lines().enumerate() {
+            self.statement(line)
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+            if self.done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, line: &str) -> Result<(), String> {
+        let line = match separators(line, ';').first() {
+            Some(&end) => &line[..end],
+            None => line,
+        };
+        let (label, rest) = self.split_label(line);
+        let rest = rest.trim();
+        let (word, args) = match rest.find(char::is_whitespace) {
+            Some(n) => (&rest[..n], rest[n..].trim()),
+            None => (rest, ""),
+        };
+        let directive = word.trim_start_matches('.').to_ascii_uppercase();
+        if directive == "EQU" {
+            let label = label.ok_or("EQU without a label")?;
+            let value = self.value(args)?;
+            return self.define(label, value as u16);
+        }
+        if let Some(label) = label {
+            self.define(label, self.addr as u16)?;
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let operands: Vec<&str> = match args.is_empty() {
+            true => Vec::new(),
+            false => split(args, ','),
+        };
+        match directive.as_str() {
+            "ORG" => {
+                let addr = self.constant(single(&operands)?)?;
+                self.addr = u16::try_from(addr)
+                    .map_err(|_| format!("ORG {:X} is outside the address space", addr))?
+                    as u32;
+                Ok(())
+            }
+            "DB" | "DEFB" | "DEFM" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    match string(operand) {
+                        Some(text) => bytes.extend(text.bytes()),
+                        None => bytes.push(self.byte(self.value(operand)?)?),
+                    }
+                }
+                self.emit(&bytes)
+            }
+            "DW" | "DEFW" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    bytes.extend(self.word(self.value(operand)?)?.to_le_bytes());
+                }
+                self.emit(&bytes)
+            }
+            "DS" | "DEFS" => {
+                let (count, fill) = match operands[..] {
+                    [count] => (count, None),
+                    [count, fill] => (count, Some(fill)),
+                    _ => return Err("DS takes a size and an optional fill value".to_string()),
+                };
+                let count = usize::try_from(self.constant(count)?)
+                    .map_err(|_| "Negative DS size".to_string())?;
+                match fill {
+                    Some(fill) => {
+                        let fill = self.byte(self.value(fill)?)?;
+                        self.emit(&vec![fill; count])
+                    }
+                    None => self.skip(count),
+                }
+            }
+            "END" => {
+                self.done = true;
+                Ok(())
+            }
+            _ => {
+                let code = self.instruction(word, &operands)?;
+                self.emit(&code)
+            }
+        }
+    }
+
+    // `label:` in front of the statement, or a name in the first column that isn't an
+    // instruction or a directive
+    fn split_label<'a>(&self, line: &'a str) -> (Option<&'a str>, &'a str) {
+        let trimmed = line.trim_start();
+        let end = trimmed
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(trimmed.len());
+        let name = &trimmed[..end];
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return (None, line);
+        }
+        if let Some(rest) = trimmed[end..].strip_prefix(':') {
+            return (Some(name), rest);
+        }
+        if trimmed.len() == line.len() && !self.is_keyword(name) {
+            return (Some(name), &trimmed[end..]);
+        }
+        (None, line)
+    }
+
+    fn is_keyword(&self, word: &str) -> bool {
+        let word = word.trim_start_matches('.').to_ascii_uppercase();
+        self.mnemonics.contains_key(word.as_str())
+            || [
+                "ORG", "EQU", "DB", "DEFB", "DEFM", "DW", "DEFW", "DS", "DEFS", "END",
+            ]
+            .contains(&word.as_str())
+    }
+
+    fn define(&mut self, label: &str, value: u16) -> Result<(), String> {
+        if !self.strict && !self.labels.insert(label.to_string()) {
+            return Err(format!("`{}` is defined twice", label));
+        }
+        self.symbols.insert(label, value);
+        Ok(())
+    }
+
+    // Value of an expression, in the first pass 0 if it can't be worked out yet
+    fn value(&self, text: &str) -> Result<i64, String> {
+        match self.constant(text) {
+            Err(_) if !self.strict => Ok(0),
+            value => value,
+        }
+    }
+
+    // Value of an expression that has to be known in the first pass, as it moves the code
+    fn constant(&self, text: &str) -> Result<i64, String> {
+        Expr::parse_at(text, &self.symbols, self.addr as u16)?
+            .value()
+            .ok_or_else(|| format!("`{}` isn't a constant", text.trim()))
+    }
+
+    fn byte(&self, value: i64) -> Result<u8, String> {
+        match self.strict && !(-0x80..=0xFF).contains(&value) {
+            true => Err(format!("{} doesn't fit in a byte", value)),
+            false => Ok(value as u8),
+        }
+    }
+
+    fn word(&self, value: i64) -> Result<u16, String> {
+        match self.strict && !(-0x8000..=0xFFFF).contains(&value) {
+            true => Err(format!("{} doesn't fit in a word", value)),
+            false => Ok(value as u16),
+        }
+    }
+
+    fn displacement(&self, value: i64) -> Result<u8, String> {
+        match self.strict && !(-0x80..=0x7F).contains(&value) {
+            true => Err(format!("Displacement {} is out of range", value)),
+            false => Ok(value as u8),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.strict && !bytes.is_empty() {
+            match self.segments.last_mut() {
+                Some((start, data)) if *start as u32 + data.len() as u32 == self.addr => {
+                    data.extend_from_slice(bytes)
+                }
+                _ => self.segments.push((self.addr as u16, bytes.to_vec())),
+            }
+        }
+        self.skip(bytes.len())
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), String> {
+        self.addr += count as u32;
+        match self.addr > 0x1_0000 {
+            true => Err("Past the end of the address space".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    fn instruction(&self, word: &str, operands: &[&str]) -> Result<Vec<u8>, String> {
+        let mnemonic = *self
+            .mnemonics
+            .get(word.to_ascii_uppercase().as_str())
+            .ok_or_else(|| format!("Unknown instruction `{}`", word))?;
+        let candidates = operands
+            .iter()
+            .map(|operand| self.operand(operand))
+            .collect::<Result<Vec<Candidates>, String>>()?;
+        let a = vec![(Shape::Reg(Register::A), 0)];
+        let copy = |candidates: &Candidates| match candidates[0] {
+            (Shape::Reg(reg), _) => Some(reg),
+            _ => None,
+        };
+        // Operands as written, then the other ways of writing the same thing: `IN (C)` has
+        // no destination, DDCB / FDCB forms copy to the last register, A is optional in
+        // `SUB A, n` style instructions and ADD, ADC and SBC can leave it out
+        let mut layouts = Vec::new();
+        match &candidates[..] {
+            [] => layouts.push((None, None, None)),
+            [x] => {
+                layouts.push((Some(x), None, None));
+                layouts.push((None, Some(x), None));
+                if matches!(mnemonic, Mnemonic::Add | Mnemonic::Adc | Mnemonic::Sbc) {
+                    layouts.push((Some(&a), Some(x), None));
+                }
+            }
+            [x, y] => {
+                layouts.push((Some(x), Some(y), None));
+                if let Some(reg) = copy(y) {
+                    layouts.push((Some(x), None, Some(reg)));
+                }
+                let alu = [
+                    Mnemonic::Sub,
+                    Mnemonic::And,
+                    Mnemonic::Xor,
+                    Mnemonic::Or,
+                    Mnemonic::Cp,
+                ];
+                if alu.contains(&mnemonic) && x == &a {
+                    layouts.push((None, Some(y), None));
+                }
+            }
+            [x, y, z] => {
+                if let Some(reg) = copy(z) {
+                    layouts.push((Some(x), Some(y), Some(reg)));
+                }
+            }
+            _ => return Err("Too many operands".to_string()),
+        }
+        for (dst, src, copy) in layouts {
+            for dst in options(dst) {
+                for src in options(src) {
+                    let key = (mnemonic, dst.map(|o| o.0), src.map(|o| o.0), copy);
+                    if let Some(&encoding) = self.table.get(&key) {
+                        return self.encode(encoding, [dst, src]);
+                    }
+                }
+            }
+        }
+        Err(match operands {
+            [] => format!("{} needs operands", mnemonic.name()),
+            _ => format!("No {} {}", mnemonic.name(), operands.join(", ")),
+        })
+    }
+
+    fn operand(&self, text: &str) -> Result<Candidates, String> {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+        if upper == "AF'" {
+            return Ok(vec![(Shape::AltAf, 0)]);
+        }
+        if let Some(reg) = register(&upper) {
+            let mut candidates = vec![(Shape::Reg(reg), 0)];
+            if reg == Register::C {
+                candidates.push((Shape::Condition(Condition::C), 0));
+            }
+            return Ok(candidates);
+        }
+        if let Some(cc) = condition(&upper) {
+            return Ok(vec![(Shape::Condition(cc), 0)]);
+        }
+        if let Some(inner) = parenthesized(text) {
+            let inner = inner.trim();
+            match register(&inner.to_ascii_uppercase()) {
+                Some(Register::C) => return Ok(vec![(Shape::PortC, 0)]),
+                Some(reg @ (Register::BC | Register::DE | Register::HL | Register::SP)) => {
+                    return Ok(vec![(Shape::Indirect(reg), 0)])
+                }
+                // (IX) is JP (IX) or (IX+0)
+                Some(reg @ (Register::IX | Register::IY)) => {
+                    return Ok(vec![(Shape::Indirect(reg), 0), (Shape::Indexed(reg), 0)])
+                }
+                _ => {}
+            }
+            let index = register(&inner.get(..2).unwrap_or("").to_ascii_uppercase());
+            if let Some(reg @ (Register::IX | Register::IY)) = index {
+                let offset = inner[2..].trim_start();
+                if offset.starts_with(['+', '-']) {
+                    let d = self.value(offset.strip_prefix('+').unwrap_or(offset))?;
+                    return Ok(vec![(Shape::Indexed(reg), d)]);
+                }
+            }
+            let value = self.value(inner)?;
+            return Ok(vec![(Shape::Absolute, value), (Shape::Port, value)]);
+        }
+        let value = self.value(text)?;
+        let mut candidates = vec![
+            (Shape::Imm8, value),
+            (Shape::Imm16, value),
+            (Shape::Relative, value),
+        ];
+        if let Ok(n) = u8::try_from(value) {
+            candidates.push((Shape::Number(n), value));
+        }
+        Ok(candidates)
+    }
+
+    fn encode(
+        &self,
+        (prefix, opcode): Encoding,
+        operands: [Option<&(Shape, i64)>; 2],
+    ) -> Result<Vec<u8>, String> {
+        let mut args = Vec::new();
+        let mut target = None;
+        for &&(shape, value) in operands.iter().flatten() {
+            match shape {
+                Shape::Indexed(_) => args.push(self.displacement(value)?),
+                Shape::Imm8 | Shape::Port => args.push(self.byte(value)?),
+                Shape::Imm16 | Shape::Absolute => args.extend(self.word(value)?.to_le_bytes()),
+                Shape::Relative => {
+                    target = Some(value);
+                    args.push(0);
+                }
+                _ => {}
+            }
+        }
+        let mut code = match prefix {
+            0 => Vec::new(),
+            0x100.. => prefix.to_be_bytes().to_vec(),
+            _ => vec![prefix as u8],
+        };
+        // DDCB / FDCB opcodes follow the displacement
+        if prefix > 0xFF {
+            code.extend(args);
+            code.push(opcode);
+        } else {
+            code.push(opcode);
+            code.extend(args);
+        }
+        if let Some(target) = target {
+            let offset = target - (self.addr as i64 + code.len() as i64);
+            let last = code.len() - 1;
+            code[last] = match self.strict && !(-0x80..=0x7F).contains(&offset) {
+                true => return Err(format!("{:04X} is out of reach, {} bytes", target, offset)),
+                false => offset as u8,
+            };
+        }
+        Ok(code)
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    Some(match name {
+        "A" => Register::A,
+        "B" => Register::B,
+        "C" => Register::C,
+        "D" => Register::D,
+        "E" => Register::E,
+        "H" => Register::H,
+        "L" => Register::L,
+        "I" => Register::I,
+        "R" => Register::R,
+        "AF" => Register::AF,
+        "BC" => Register::BC,
+        "DE" => Register::DE,
+        "HL" => Register::HL,
+        "SP" => Register::SP,
+        "IX" => Register::IX,
+        "IY" => Register::IY,
+        "IXH" => Register::IXH,
+        "IXL" => Register::IXL,
+        "IYH" => Register::IYH,
+        "IYL" => Register::IYL,
+        _ => return None,
+    })
+}
+
+// C is handled as a register
+fn condition(name: &str) -> Option<Condition> {
+    Some(match name {
+        "NZ" => Condition::NZ,
+        "Z" => Condition::Z,
+        "NC" => Condition::NC,
+        "PO" => Condition::PO,
+        "PE" => Condition::PE,
+        "P" => Condition::P,
+        "M" => Condition::M,
+        _ => return None,
+    })
+}
+
+// Byte offsets of `sep` outside quotes and parentheses. A quote right after a letter is
+// the one in AF'.
+fn separators(text: &str, sep: char) -> Vec<usize> {
+    let mut found = Vec::new();
+    let (mut quote, mut depth, mut prev) = (None, 0, ' ');
+    for (n, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' => quote = Some(c),
+                '\'' if !prev.is_ascii_alphanumeric() => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ if c == sep && depth == 0 => found.push(n),
+                _ => {}
+            },
+        }
+        prev = c;
+    }
+    found
+}
+
+fn split(text: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for end in separators(text, sep) {
+        parts.push(text[start..end].trim());
+        start = end + 1;
+    }
+    parts.push(text[start..].trim());
+    parts
+}
+
+// Each reading of an operand, or the lack of one
+fn options(candidates: Option<&Candidates>) -> Vec<Option<&(Shape, i64)>> {
+    match candidates {
+        Some(candidates) => candidates.iter().map(Some).collect(),
+        None => vec![None],
+    }
+}
+
+fn single<'a>(operands: &[&'a str]) -> Result<&'a str, String> {
+    match operands {
+        [operand] => Ok(operand),
+        _ => Err("Expected one operand".to_string()),
+    }
+}
+
+// What's inside the parentheses if they enclose all of `text`, so `(2+3)*2` is a number
+fn parenthesized(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        depth += match c {
+            '(' => 1,
+            ')' => -1,
+            _ => 0,
+        };
+        if depth < 0 {
+            return None;
+        }
+    }
+    Some(inner)
+}
+
+// The text of a quoted string
+fn string(text: &str) -> Option<&str> {
+    ['"', '\''].iter().find_map(|&quote| {
+        let inner = text.strip_prefix(quote)?.strip_suffix(quote)?;
+        (!inner.contains(quote)).then_some(inner)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, assemble_at};
+    use crate::instruction_info::Instruction;
+    use crate::memory::Memory;
+    use crate::symbols::Symbols;
+
+    fn code(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().to_binary()
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(code("LD A, 5"), [0x3E, 0x05]);
+        assert_eq!(code("LD A, 0BFh"), [0x3E, 0xBF]);
+        assert_eq!(code("ld a,(ix-2)"), [0xDD, 0x7E, 0xFE]);
+        assert_eq!(code("LD (IY+5), 0x42"), [0xFD, 0x36, 0x05, 0x42]);
+        assert_eq!(code("OUT (0xFE), A"), [0xD3, 0xFE]);
+        assert_eq!(code("IN A, (C)"), [0xED, 0x78]);
+        assert_eq!(code("IN (C)"), [0xED, 0x70]);
+        assert_eq!(code("JP (IX)"), [0xDD, 0xE9]);
+        assert_eq!(code("LD A, (IX)"), [0xDD, 0x7E, 0x00]);
+        assert_eq!(code("LD HL, (1234h)"), [0x2A, 0x34, 0x12]);
+        assert_eq!(code("LD BC, (1234h)"), [0xED, 0x4B, 0x34, 0x12]);
+        assert_eq!(code("LD A, (2 + 3) * 2"), [0x3E, 0x0A]);
+        assert_eq!(code("SUB A, B\nSUB B\nADD 5"), [0x90, 0x90, 0xC6, 0x05]);
+        assert_eq!(
+            code("JR C, $\nRET C\nLD C, 'x'"),
+            [0x38, 0xFE, 0xD8, 0x0E, 0x78]
+        );
+        assert_eq!(code("RST 38h\nIM 2\nEX AF, AF'"), [0xFF, 0xED, 0x5E, 0x08]);
+        assert_eq!(code("BIT 7, (HL)\nNEG"), [0xCB, 0x7E, 0xED, 0x44]);
+        assert_eq!(code("RES 0, (IX+3), B"), [0xDD, 0xCB, 0x03, 0x80]);
+        assert_eq!(code("RLC (IY-1), A"), [0xFD, 0xCB, 0xFF, 0x07]);
+        assert_eq!(code("LD IXH, 1"), [0xDD, 0x26, 0x01]);
+
+        for source in [
+            "LD A, 256",
+            "LD (IX+128), A",
+            "JR 200",
+            "LD A, B, C",
+            "IM 3",
+            " FOO",
+            "LD A, missing",
+            "LD A, (HL",
+            "LD HL, A",
+        ] {
+            assert!(assemble(source).is_err(), "{}", source);
+        }
+        assert_eq!(
+            assemble("\n\n  XOR Q").unwrap_err(),
+            "line 3: Unknown register or symbol `Q`"
+        );
+    }
+
+    // Every instruction the disassembler shows assembles to something that disassembles
+    // the same
+    #[test]
+    fn round_trip() {
+        let prefixes: [&[u8]; 7] = [
+            &[],
+            &[0xCB],
+            &[0xED],
+            &[0xDD],
+            &[0xFD],
+            &[0xDD, 0xCB, 0x85],
+            &[0xFD, 0xCB, 0x05],
+        ];
+        for prefix in prefixes.iter() {
+            for op in 0..=0xFF {
+                let mut bytes = prefix.to_vec();
+                bytes.extend([op, 0xF6, 0x34, 0x12]);
+                let text = Instruction::decode_bytes(&bytes).unwrap().to_string();
+                let assembled = assemble_at(&text, 0x8000, &Symbols::default())
+                    .unwrap_or_else(|e| panic!("{:02X?} {}: {}", bytes, text, e));
+                let code = assembled.to_binary();
+                let again = Instruction::decode_bytes(&code).unwrap();
+                assert_eq!(again.to_string(), text, "{:02X?}", bytes);
+                assert_eq!(again.size as usize, code.len(), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn programs() {
+        let source = "
+; Prints the message through port 1
+port    EQU 1
+        ORG 8000h
+start:  LD HL, message
+loop:   LD A, (HL)
+        OR A
+        JR Z, done
+        OUT (port), A
+        INC HL
+        JR loop
+done    HALT
+message DB \"Hi, there\", 0
+        DW start, $
+        DS 2
+        DS 2, 0FFh
+        END
+        NOP
+";
+        let asm = assemble(source).unwrap();
+        assert_eq!(asm.origin(), 0x8000);
+        assert_eq!(asm.symbols.addr("loop"), Some(0x8003));
+        assert_eq!(asm.symbols.addr("message"), Some(0x800D));
+        let binary = asm.to_binary();
+        assert_eq!(
+            binary[..0x0D],
+            [0x21, 0x0D, 0x80, 0x7E, 0xB7, 0x28, 0x05, 0xD3, 0x01, 0x23, 0x18, 0xF7, 0x76]
+        );
+        assert_eq!(&binary[0x0D..0x17], b"Hi, there\0");
+        assert_eq!(binary[0x17..], [0x00, 0x80, 0x17, 0x80, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(asm.segments.len(), 2);
+
+        let mut memory = Memory::default();
+        memory.load_slice(0x801B, &[0x55, 0x55]);
+        asm.load(&mut memory);
+        assert_eq!(memory.peek(0x801B), 0x55);
+        assert_eq!(memory.peek(0x801D), 0xFF);
+
+        let mut symbols = Symbols::default();
+        symbols.insert("print", 0x1234);
+        let patch = assemble_at("CALL print\nJR $+2", 0x4000, &symbols).unwrap();
+        assert_eq!(
+            patch.segments,
+            [(0x4000, vec![0xCD, 0x34, 0x12, 0x18, 0x00])]
+        );
+
+        assert!(assemble("a: NOP\na: NOP").is_err());
+        assert!(assemble("EQU 5").is_err());
+        assert!(assemble("ORG 0FFFFh\nLD A, 1").is_err());
+    }
+}
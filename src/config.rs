@@ -0,0 +1,366 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use crate::archive;
+use crate::device::DeviceRef;
+use crate::interconnect::{Interconnect, Preset};
+use crate::memory::Region;
+use crate::peripherals::serial::Stdio;
+use crate::peripherals::sio::CHANNEL_A;
+use crate::peripherals::{Acia, Console, Ctc, Dma, IntervalTimer, Pio, Sio};
+
+// Machine description loaded from a TOML file, e.g:
+//
+// name = "My SBC"
+// preset = "cpm"
+// clock = 7372800
+// pc = 0x0000
+//
+// [[rom]]
+// file = "basic.bin"
+// address = 0x0000
+//
+// [[ram]]
+// start = 0x8000
+// end = 0xFFFF
+//
+// Once any ROM or RAM is declared, addresses outside of them are unmapped.
+//
+// [[port]]
+// device = "console"
+// start = 0x80
+//
+// Devices are "console", "ctc", "pio" and "sio" (four ports each, the SIO's channel A is
+// connected to the terminal), "acia" (two ports, also on the terminal) and "dma" (one
+// port). Z80 family devices are daisy chained in the order they're listed, the first has
+// the highest interrupt priority.
+//
+// [[interrupt]]
+// period = 69888
+// vector = 0xFF
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub preset: PresetName,
+    pub clock: Option<usize>,
+    pub pc: Option<u16>,
+    pub sp: Option<u16>,
+    pub interrupt_mode: Option<u8>,
+    // Value read from unmapped addresses
+    pub open_bus: Option<u8>,
+    #[serde(default)]
+    pub rom: Vec<RomConfig>,
+    #[serde(default)]
+    pub ram: Vec<RegionConfig>,
+    #[serde(default)]
+    pub mirror: Vec<MirrorConfig>,
+    #[serde(default)]
+    pub port: Vec<PortConfig>,
+    #[serde(default)]
+    pub interrupt: Vec<InterruptConfig>,
+    // ROM paths are relative to the machine file
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresetName {
+    #[default]
+    Cpm,
+    PacMan,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RomConfig {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub address: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionConfig {
+    pub start: u16,
+    pub end: u16,
+}
+
+// `start..=end` aliases the memory starting at `of`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub start: u16,
+    pub end: u16,
+    pub of: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    pub device: String,
+    pub start: u8,
+    pub end: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterruptConfig {
+    // T states between interrupts
+    pub period: usize,
+    #[serde(default = "default_vector")]
+    pub vector: u8,
+    #[serde(default)]
+    pub nmi: bool,
+}
+
+fn default_vector() -> u8 {
+    0xFF
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl MachineConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut config = Self::parse(&fs::read_to_string(path)?)?;
+        config.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(config)
+    }
+
+    pub fn parse(source: &str) -> io::Result<Self> {
+        let config: MachineConfig = toml::from_str(source).map_err(invalid_data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        for region in &self.ram {
+            if region.start > region.end {
+                return Err(invalid_data(format!(
+                    "RAM region {:04X}-{:04X} ends before it starts",
+                    region.start, region.end
+                )));
+            }
+        }
+        for mirror in &self.mirror {
+            if mirror.start > mirror.end {
+                return Err(invalid_data(format!(
+                    "Mirror {:04X}-{:04X} ends before it starts",
+                    mirror.start, mirror.end
+                )));
+            }
+        }
+        for port in &self.port {
+            if port.end.unwrap_or(port.start) < port.start {
+                return Err(invalid_data(format!(
+                    "Port range for {} ends before it starts",
+                    port.device
+                )));
+            }
+        }
+        if self.interrupt.iter().any(|int| int.period == 0) {
+            return Err(invalid_data("Interrupt period must be non zero"));
+        }
+        Ok(())
+    }
+
+    fn device(name: &str) -> io::Result<DeviceRef> {
+        match name {
+            "acia" => {
+                let mut acia = Acia::default();
+                acia.connect(Stdio::default());
+                Ok(Rc::new(RefCell::new(acia)))
+            }
+            "console" => Ok(Rc::new(RefCell::new(Console::default()))),
+            "ctc" => Ok(Rc::new(RefCell::new(Ctc::default()))),
+            "dma" => Ok(Rc::new(RefCell::new(Dma::default()))),
+            "pio" => Ok(Rc::new(RefCell::new(Pio::default()))),
+            "sio" => {
+                let mut sio = Sio::default();
+                sio.connect(CHANNEL_A, Stdio::default());
+                Ok(Rc::new(RefCell::new(sio)))
+            }
+            _ => Err(invalid_data(format!("Unknown device: {}", name))),
+        }
+    }
+
+    pub fn build(&self) -> io::Result<Interconnect> {
+        let preset = match self.preset {
+            PresetName::Cpm => Preset::Cpm,
+            PresetName::PacMan => Preset::PacMan,
+        };
+        let mut builder = Interconnect::builder().preset(preset);
+        if let Some(clock) = self.clock {
+            builder = builder.clock_speed(clock);
+        }
+        if let Some(pc) = self.pc {
+            builder = builder.pc(pc);
+        }
+        if let Some(sp) = self.sp {
+            builder = builder.sp(sp);
+        }
+        if let Some(mode) = self.interrupt_mode {
+            if mode > 2 {
+                return Err(invalid_data(format!("Invalid interrupt mode: {}", mode)));
+            }
+            builder = builder.interrupt_mode(mode);
+        }
+        for port in &self.port {
+            let ports = port.start..=port.end.unwrap_or(port.start);
+            builder = builder.port_device(ports, Self::device(&port.device)?);
+        }
+        for int in &self.interrupt {
+            let timer = IntervalTimer::new(int.period, int.vector, int.nmi);
+            builder = builder.device(Rc::new(RefCell::new(timer)));
+        }
+
+        let mut i = builder.build();
+        if let Some(open_bus) = self.open_bus {
+            i.cpu.memory.open_bus = open_bus;
+        }
+        if !self.rom.is_empty() || !self.ram.is_empty() {
+            i.cpu.memory.clear_regions();
+            i.cpu.memory.map(0x0000..=0xFFFF, Region::Unmapped);
+        }
+        for ram in &self.ram {
+            i.cpu.memory.map(ram.start..=ram.end, Region::Ram);
+        }
+        for mirror in &self.mirror {
+            i.cpu
+                .memory
+                .map(mirror.start..=mirror.end, Region::Mirror(mirror.of));
+        }
+        for rom in &self.rom {
+            let data = archive::read(self.base_dir.join(&rom.file))?;
+            let start = rom.address as usize;
+            if start + data.len() > 0x1_0000 {
+                return Err(invalid_data(format!(
+                    "{:?} does not fit at {:04X}",
+                    rom.file, rom.address
+                )));
+            }
+            i.cpu.memory.load_rom(rom.address, &data);
+            if !data.is_empty() {
+                let end = (start + data.len() - 1) as u16;
+                i.cpu.memory.map(rom.address..=end, Region::Rom);
+            }
+        }
+        Ok(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRW;
+
+    #[test]
+    fn parse_machine() {
+        let config = MachineConfig::parse(
+            r#"
+            name = "test board"
+            clock = 7372800
+            pc = 0x8000
+            sp = 0xFF00
+            interrupt_mode = 1
+
+            [[ram]]
+            start = 0x8000
+            end = 0xFFFF
+
+            [[port]]
+            device = "console"
+            start = 0x80
+            end = 0x81
+
+            [[port]]
+            device = "ctc"
+            start = 0x10
+            end = 0x13
+
+            [[interrupt]]
+            period = 1000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.name.as_deref(), Some("test board"));
+        assert_eq!(config.preset, PresetName::Cpm);
+
+        let i = config.build().unwrap();
+        assert_eq!(i.clock_speed, 7_372_800);
+        assert_eq!(i.cpu.reg.pc, 0x8000);
+        assert_eq!(i.cpu.reg.sp, 0xFF00);
+        assert_eq!(i.cpu.int.mode, 1);
+        assert_eq!(i.devices.len(), 3);
+    }
+
+    #[test]
+    fn load_rom() {
+        let dir = std::env::temp_dir().join("z80-rs-config-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.bin"), [0x3E, 0x42]).unwrap();
+        fs::write(
+            dir.join("machine.toml"),
+            "[[rom]]\nfile = \"test.bin\"\naddress = 0x1000\n\
+             [[rom]]\nfile = \"test.bin\"\naddress = 0x2101\n\
+             [[ram]]\nstart = 0x2000\nend = 0x2FFF\n",
+        )
+        .unwrap();
+
+        let mut i = MachineConfig::load(dir.join("machine.toml"))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(i.cpu.memory[0x1000], 0x3E);
+        assert_eq!(i.cpu.memory[0x1001], 0x42);
+        // Not page aligned, the RAM around it still takes writes
+        assert_eq!(i.cpu.memory[0x2101], 0x3E);
+        i.cpu.write8(0x2100, 0x11);
+        i.cpu.write8(0x2101, 0x22);
+        assert_eq!(i.cpu.read8(0x2100), 0x11);
+        assert_eq!(i.cpu.read8(0x2101), 0x3E);
+    }
+
+    #[test]
+    fn memory_regions() {
+        let mut i = MachineConfig::parse(
+            r#"
+            open_bus = 0x00
+
+            [[ram]]
+            start = 0x8000
+            end = 0x87FF
+
+            [[mirror]]
+            start = 0x8800
+            end = 0x8FFF
+            of = 0x8000
+            "#,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        i.cpu.write8(0x8801, 0x12);
+        assert_eq!(i.cpu.read8(0x8001), 0x12);
+        i.cpu.memory[0x4000] = 0x34;
+        assert_eq!(i.cpu.read8(0x4000), 0x00);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(MachineConfig::parse("clock = \"fast\"").is_err());
+        assert!(MachineConfig::parse("[[ram]]\nstart = 0x9000\nend = 0x8000").is_err());
+        let config = MachineConfig::parse("[[port]]\ndevice = \"floppy\"\nstart = 1").unwrap();
+        assert!(config.build().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::memory::ADDRESS_SPACE;
+
+// Which addresses have been executed. Every byte of an executed instruction is marked, so
+// the executed ranges line up with the code. Hit counts are optional and counted per
+// instruction start address.
+pub struct Coverage {
+    executed: Vec<u64>,
+    hits: Option<Vec<u32>>,
+}
+
+impl Coverage {
+    pub fn new(count_hits: bool) -> Self {
+        Self {
+            executed: vec![0; ADDRESS_SPACE / 64],
+            hits: count_hits.then(|| vec![0; ADDRESS_SPACE]),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record(&mut self, pc: u16, len: u8) {
+        for n in 0..len as u16 {
+            let addr = pc.wrapping_add(n) as usize;
+            self.executed[addr / 64] |= 1 << (addr % 64);
+        }
+        if let Some(hits) = &mut self.hits {
+            hits[pc as usize] = hits[pc as usize].saturating_add(1);
+        }
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.executed[addr as usize / 64] & (1 << (addr % 64)) != 0
+    }
+
+    // None unless hit counting was enabled
+    pub fn hits(&self, addr: u16) -> Option<u32> {
+        self.hits.as_ref().map(|hits| hits[addr as usize])
+    }
+
+    pub fn counts_hits(&self) -> bool {
+        self.hits.is_some()
+    }
+
+    // Amount of executed addresses
+    pub fn len(&self) -> usize {
+        self.executed.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.executed.iter().all(|&w| w == 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.executed.iter_mut().for_each(|w| *w = 0);
+        if let Some(hits) = &mut self.hits {
+            hits.iter_mut().for_each(|h| *h = 0);
+        }
+    }
+
+    // Contiguous runs of executed addresses
+    pub fn ranges(&self) -> Vec<RangeInclusive<u16>> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for addr in 0..=0xFFFF_u16 {
+            match (self.is_executed(addr), start) {
+                (true, None) => start = Some(addr),
+                (false, Some(s)) => {
+                    ranges.push(s..=addr - 1);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            ranges.push(s..=0xFFFF);
+        }
+        ranges
+    }
+
+    // One `start-end` line per executed range, followed by `addr count` lines for every
+    // instruction that was hit if counting is enabled
+    pub fn write_report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for range in self.ranges() {
+            writeln!(out, "{:04X}-{:04X}", range.start(), range.end())?;
+        }
+        if let Some(hits) = &self.hits {
+            writeln!(out)?;
+            for (addr, count) in hits.iter().enumerate().filter(|(_, &c)| c > 0) {
+                writeln!(out, "{:04X} {}", addr, count)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_report(&mut out)?;
+        out.flush()
+    }
+
+    // The raw bitmap, 8K with bit n of byte n / 8 set if address n was executed
+    pub fn bitmap(&self) -> Vec<u8> {
+        self.executed.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coverage;
+
+    #[test]
+    fn ranges_and_hits() {
+        let mut coverage = Coverage::new(true);
+        assert!(coverage.is_empty());
+        coverage.record(0x0100, 2);
+        coverage.record(0x0102, 3);
+        coverage.record(0x0100, 2);
+        coverage.record(0xFFFF, 1);
+        assert!(coverage.is_executed(0x0104));
+        assert!(!coverage.is_executed(0x0105));
+        assert_eq!(coverage.len(), 6);
+        assert_eq!(coverage.hits(0x0100), Some(2));
+        assert_eq!(coverage.hits(0x0101), Some(0));
+        assert_eq!(coverage.ranges(), vec![0x0100..=0x0104, 0xFFFF..=0xFFFF]);
+
+        let mut out = Vec::new();
+        coverage.write_report(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0100-0104\nFFFF-FFFF\n\n0100 2\n0102 1\nFFFF 1\n"
+        );
+        let bitmap = coverage.bitmap();
+        assert_eq!(bitmap.len(), 0x2000);
+        assert_eq!(bitmap[0x20], 0x1F);
+        assert_eq!(bitmap[0x1FFF], 0x80);
+
+        coverage.clear();
+        assert!(coverage.is_empty());
+        assert_eq!(Coverage::new(false).hits(0x0100), None);
+    }
+}
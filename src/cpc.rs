@@ -0,0 +1,607 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::device::Device;
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Page, PAGE_SIZE};
+use crate::peripherals::crtc::HORIZONTAL_TOTAL;
+use crate::peripherals::joystick::Button;
+use crate::peripherals::ppi::{PORT_A, PORT_B, PORT_C};
+use crate::peripherals::{Ay, Crtc, Joystick, KeyMatrix, Ppi};
+use crate::video::Framebuffer;
+
+pub const CLOCK: usize = 4_000_000;
+// The OS at 0000-3FFF and BASIC at C000-FFFF, over the RAM
+pub const ROM_SIZE: usize = 0x4000;
+const UPPER_ROM: u16 = 0xC000;
+// Keyboard rows, the last being the joystick
+pub const KEY_ROWS: usize = 10;
+// 64 characters of 4 T states on each of 312 lines
+pub const FRAME_CYCLES: usize = 64 * 4 * 312;
+
+// 48 characters of 16 pixels (mode 2 pixels) by 272 lines: the 40 x 200 screen of the
+// default CRTC set up with 4 characters and 36 lines of border around it
+const COLUMNS: usize = 48;
+const BORDER_CHARS: usize = 4;
+const BORDER_LINES: usize = 36;
+pub const WIDTH: usize = COLUMNS * 16;
+pub const HEIGHT: usize = 272;
+
+// The CRTC registers the firmware sets up for a 50Hz screen
+const CRTC_REGS: [u8; 14] = [63, 40, 46, 0x8E, 38, 0, 25, 30, 0, 7, 0, 0, 0x30, 0x00];
+
+// Red, green and blue levels (off, half, full) of the 32 hardware colours
+const PALETTE: [[u8; 3]; 32] = [
+    [1, 1, 1],
+    [1, 1, 1],
+    [0, 2, 1],
+    [2, 2, 1],
+    [0, 0, 1],
+    [2, 0, 1],
+    [0, 1, 1],
+    [2, 1, 1],
+    [2, 0, 1],
+    [2, 2, 1],
+    [2, 2, 0],
+    [2, 2, 2],
+    [2, 0, 0],
+    [2, 0, 2],
+    [2, 1, 0],
+    [2, 1, 2],
+    [0, 0, 1],
+    [0, 2, 1],
+    [0, 2, 0],
+    [0, 2, 2],
+    [0, 0, 0],
+    [0, 0, 2],
+    [0, 1, 0],
+    [0, 1, 2],
+    [1, 0, 1],
+    [1, 2, 1],
+    [1, 2, 0],
+    [1, 2, 2],
+    [1, 0, 0],
+    [1, 0, 2],
+    [1, 1, 0],
+    [1, 1, 2],
+];
+const LEVELS: [u8; 3] = [0x00, 0x80, 0xFF];
+const BLACK: u8 = 20;
+const BORDER: usize = 16;
+
+// Keyboard rows, bit 0 first. '\0' are keys without a character (function keys, SHIFT,
+// CONTROL, COPY...), the four arrows are up, right, down and left.
+pub const KEYS: [&str; KEY_ROWS] = [
+    "\u{2191}\u{2192}\u{2193}\0\0\0\0\0",
+    "\u{2190}\0\0\0\0\0\0\0",
+    "\0[\r]\0\0\\\0",
+    "^-@P;:/.",
+    "09OILKM,",
+    "87UYHJN ",
+    "65RTGFBV",
+    "43EWSDCX",
+    "12\x1BQ\tA\0Z",
+    "\0\0\0\0\0\0\0\x7F",
+];
+pub const SHIFT: (usize, usize) = (2, 5);
+pub const CONTROL: (usize, usize) = (2, 7);
+const JOYSTICK_ROW: usize = 9;
+
+// Row and column of the key for `c`: upper case letters, digits, the punctuation on the
+// keys, space, RETURN (\r), ESC, TAB, DEL (\x7F) and the arrows
+pub fn key_position(c: char) -> Option<(usize, usize)> {
+    if c == '\0' {
+        return None;
+    }
+    KEYS.iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.chars().position(|key| key == c).map(|n| (row, n)))
+}
+
+// The Gate Array: the video output, the ROM enables and the interrupts, timed by the CRTC
+// whose syncs it watches. It counts the CRTC's HSYNCs and interrupts every 52 (6 times a
+// frame, 300 times a second at 50Hz), and two HSYNCs into each VSYNC it starts counting
+// again from 0 so the interrupts stay locked to the frame (interrupting there too unless the
+// last one was less than 32 lines ago). The interrupt is held until the CPU acknowledges it,
+// which clears bit 5 of the count so the next one can't come in less than 32 lines.
+//
+// Writes are to 7Fxx (A15 low, A14 high) with the function in bits 6-7: select pen 0-15 or
+// the border (bit 4), set the selected pen's colour, or set the mode and ROM enables. The
+// mode takes effect from the next line.
+pub struct GateArray {
+    // Hardware colours of the 16 pens and the border
+    pub pens: [u8; 17],
+    pub pen: usize,
+    pub mode: u8,
+    pub lower_rom: bool,
+    pub upper_rom: bool,
+    pub crtc: Rc<RefCell<Crtc>>,
+    pub frame: Rc<RefCell<Framebuffer>>,
+    pub cycles: u64,
+    // Characters the CRTC has stepped through
+    chars: u64,
+    line_mode: u8,
+    hsync_count: u8,
+    // HSYNCs to go after VSYNC starts before resetting the count
+    vsync_delay: u8,
+    interrupt: bool,
+}
+
+impl GateArray {
+    pub fn new(crtc: Rc<RefCell<Crtc>>) -> Self {
+        Self {
+            pens: [BLACK; 17],
+            pen: 0,
+            mode: 1,
+            lower_rom: true,
+            upper_rom: true,
+            crtc,
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            cycles: 0,
+            chars: 0,
+            line_mode: 1,
+            hsync_count: 0,
+            vsync_delay: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        match value >> 6 {
+            0 => {
+                self.pen = match value & 0x10 {
+                    0 => value as usize & 0x0F,
+                    _ => BORDER,
+                }
+            }
+            1 => self.pens[self.pen] = value & 0x1F,
+            2 => {
+                self.mode = value & 3;
+                self.lower_rom = value & 0x04 == 0;
+                self.upper_rom = value & 0x08 == 0;
+                if value & 0x10 != 0 {
+                    self.hsync_count = 0;
+                    self.interrupt = false;
+                }
+            }
+            // RAM banking on the 6128
+            _ => {}
+        }
+    }
+
+    // Maps the OS and BASIC ROMs in or out over the RAM, with writes going to the RAM either
+    // way
+    pub fn remap(&self, memory: &mut Memory, os: usize, basic: usize) {
+        for (start, enabled, rom) in [
+            (0x0000, self.lower_rom, os),
+            (UPPER_ROM, self.upper_rom, basic),
+        ] {
+            for offset in (0..ROM_SIZE).step_by(PAGE_SIZE) {
+                let index = (start as usize + offset) / PAGE_SIZE;
+                let ram = start as usize + offset;
+                match enabled {
+                    true => {
+                        memory.map_page(index, Page::Rom(rom + offset));
+                        memory.map_ram_under_rom(index, Some(ram));
+                    }
+                    false => {
+                        memory.map_page(index, Page::Ram(ram));
+                        memory.map_ram_under_rom(index, None);
+                    }
+                }
+            }
+        }
+    }
+
+    fn end_hsync(&mut self) {
+        self.line_mode = self.mode;
+        self.hsync_count += 1;
+        if self.vsync_delay > 0 {
+            self.vsync_delay -= 1;
+            if self.vsync_delay == 0 {
+                self.interrupt |= self.hsync_count >= 32;
+                self.hsync_count = 0;
+                return;
+            }
+        }
+        if self.hsync_count == 52 {
+            self.hsync_count = 0;
+            self.interrupt = true;
+        }
+    }
+
+    // Draws the 16 pixels of the CRTC's current character, if it's in the window
+    fn draw(&self, memory: &Memory, crtc: &Crtc) {
+        let chars = crtc.regs[HORIZONTAL_TOTAL] as usize + 1;
+        let x = (crtc.hc as usize + BORDER_CHARS) % chars;
+        let y = (crtc.line + BORDER_LINES) % crtc.lines_per_frame().max(1);
+        if x >= COLUMNS || y >= HEIGHT {
+            return;
+        }
+        let mut frame = self.frame.borrow_mut();
+        let row = &mut frame.row_mut(y)[x * 16 * 4..(x + 1) * 16 * 4];
+        if !crtc.display() {
+            let colour = self.colour(BORDER);
+            row.chunks_mut(4)
+                .for_each(|pixel| pixel.copy_from_slice(&colour));
+            return;
+        }
+        // 16K screens, each character two bytes from the 2K block of its scanline
+        let ma = crtc.ma() as usize;
+        let addr = (ma & 0x3000) << 2 | (crtc.ra as usize & 7) << 11 | (ma & 0x3FF) << 1;
+        for (n, pixels) in row.chunks_mut(8 * 4).enumerate() {
+            let byte = memory.ram[addr + n];
+            let (count, width) = match self.line_mode {
+                0 | 3 => (2, 4),
+                1 => (4, 2),
+                _ => (8, 1),
+            };
+            for pixel in 0..count {
+                let colour = self.colour(pen(byte, self.line_mode, pixel));
+                for out in pixels[pixel * width * 4..(pixel + 1) * width * 4].chunks_mut(4) {
+                    out.copy_from_slice(&colour);
+                }
+            }
+        }
+    }
+
+    fn colour(&self, pen: usize) -> [u8; 4] {
+        let [r, g, b] = PALETTE[self.pens[pen] as usize];
+        [
+            LEVELS[r as usize],
+            LEVELS[g as usize],
+            LEVELS[b as usize],
+            0xFF,
+        ]
+    }
+}
+
+// Pen of pixel `n` of a screen byte. Mode 2 has 8 pixels of 1 bit, mode 1 4 of 2 bits and
+// mode 0 2 of 4 bits, the bits of each pixel interleaved with the others' (mode 3 is mode
+// 0 with only the low 2 bits).
+fn pen(byte: u8, mode: u8, n: usize) -> usize {
+    let bit = |bit: usize| (byte as usize >> bit) & 1;
+    match mode {
+        2 => bit(7 - n),
+        1 => bit(7 - n) | bit(3 - n) << 1,
+        _ => {
+            let pen = bit(7 - n) | bit(3 - n) << 1 | bit(5 - n) << 2 | bit(1 - n) << 3;
+            if mode == 3 {
+                pen & 3
+            } else {
+                pen
+            }
+        }
+    }
+}
+
+impl Device for GateArray {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+
+    // Catches the CRTC up, a character every 4 T states (1MHz)
+    fn scan(&mut self, memory: &Memory) {
+        let crtc = self.crtc.clone();
+        let mut crtc = crtc.borrow_mut();
+        while (self.chars + 1) * 4 <= self.cycles {
+            self.chars += 1;
+            self.draw(memory, &crtc);
+            let (hsync, vsync) = (crtc.hsync, crtc.vsync);
+            crtc.step();
+            if hsync && !crtc.hsync {
+                self.end_hsync();
+            }
+            if !vsync && crtc.vsync {
+                self.vsync_delay = 2;
+            }
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.interrupt.then_some(0xFF)
+    }
+
+    fn level_interrupt(&self) -> bool {
+        true
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        self.interrupt = false;
+        self.hsync_count &= 0x1F;
+    }
+}
+
+// The Amstrad CPC 464: 64K of RAM with the OS and BASIC ROMs over its ends, a Gate Array
+// and 6845 CRTC for the display and interrupts, an AY-3-8912 PSG and an 8255 PPI.
+//
+// The devices decode the upper address byte, so they're reached with OUT (C),r: the Gate
+// Array at 7Fxx, the CRTC at BCxx-BFxx and the PPI at F4xx-F7xx. The PPI's port A is the
+// PSG's data bus, with the PSG function (select, write or read) in port C bits 6-7 and the
+// keyboard row in bits 0-3; the keyboard is read through the PSG's I/O port A. Port B has
+// VSYNC in bit 0 and links saying it's an Amstrad with a 50Hz screen. There are no
+// expansion ROMs on the 464, BASIC answers whatever upper ROM is selected on DFxx.
+pub struct Cpc {
+    pub gate_array: Rc<RefCell<GateArray>>,
+    pub crtc: Rc<RefCell<Crtc>>,
+    pub psg: Rc<RefCell<Ay>>,
+    pub ppi: Rc<RefCell<Ppi>>,
+    pub keyboard: KeyMatrix,
+    pub joystick: Joystick,
+}
+
+impl Cpc {
+    pub fn install(i: &mut Interconnect, os: &[u8], basic: &[u8]) -> Result<Self, String> {
+        for (name, rom) in [("OS", os), ("BASIC", basic)] {
+            if rom.is_empty() || rom.len() > ROM_SIZE {
+                return Err(format!(
+                    "{} ROM is {} bytes, expected up to {}",
+                    name,
+                    rom.len(),
+                    ROM_SIZE
+                ));
+            }
+        }
+        let mut memory = Memory::default();
+        let os_base = memory.rom.len();
+        memory.rom.extend_from_slice(os);
+        memory.rom.resize(os_base + ROM_SIZE, 0xFF);
+        let basic_base = memory.rom.len();
+        memory.rom.extend_from_slice(basic);
+        memory.rom.resize(basic_base + ROM_SIZE, 0xFF);
+
+        let mut crtc = Crtc::default();
+        crtc.regs[..CRTC_REGS.len()].copy_from_slice(&CRTC_REGS);
+        crtc.address_shift = 8;
+        let crtc = Rc::new(RefCell::new(crtc));
+        let gate_array = i.add_device(GateArray::new(crtc.clone()));
+        gate_array.borrow().remap(&mut memory, os_base, basic_base);
+        let registers = gate_array.clone();
+        memory.add_paging_register(0xC000, 0x4000, move |memory, value| {
+            let mut gate_array = registers.borrow_mut();
+            gate_array.write(value);
+            gate_array.remap(memory, os_base, basic_base);
+        });
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+        i.frame_cycles = Some(FRAME_CYCLES);
+        i.display = Some(gate_array.borrow().frame.clone());
+        i.register_port_decoded(0x4000, 0x0000, crtc.clone());
+
+        // An AY-3-8912, the 8910 with one I/O port
+        let psg = i.add_device(Ay::new(1_000_000, CLOCK as u64, 44_100));
+
+        let keyboard = KeyMatrix::default();
+        let joystick = Joystick::default();
+        let data = Rc::new(Cell::new(0xFF));
+        let control = Rc::new(Cell::new(0));
+        let mut ppi = Ppi::default();
+        ppi.address_shift = 8;
+        let bus = data.clone();
+        ppi.on_output(PORT_A, move |value| bus.set(value));
+        let (ay, bus, lines) = (psg.clone(), data.clone(), control.clone());
+        ppi.on_output(PORT_C, move |value| {
+            lines.set(value);
+            match value >> 6 {
+                3 => ay.borrow_mut().select(bus.get()),
+                2 => ay.borrow_mut().write(bus.get()),
+                _ => {}
+            }
+        });
+        let (ay, keys, stick) = (psg.clone(), keyboard.clone(), joystick.clone());
+        ppi.on_input(PORT_A, move || {
+            let lines = control.get();
+            if lines >> 6 != 1 {
+                return 0xFF;
+            }
+            let row = lines as usize & 0x0F;
+            let pressed = match row {
+                JOYSTICK_ROW => keys.row(row) | joystick_bits(&stick),
+                row if row < KEY_ROWS => keys.row(row),
+                _ => 0,
+            };
+            let mut ay = ay.borrow_mut();
+            ay.inputs[0] = !pressed;
+            ay.read()
+        });
+        let sync = crtc.clone();
+        ppi.on_input(PORT_B, move || 0x7E | sync.borrow().vsync as u8);
+        let ppi = Rc::new(RefCell::new(ppi));
+        i.register_port_decoded(0x0800, 0x0000, ppi.clone());
+        Ok(Self {
+            gate_array,
+            crtc,
+            psg,
+            ppi,
+            keyboard,
+            joystick,
+        })
+    }
+
+    // Installs the 32K ROM at `path`, the OS followed by BASIC
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let rom = archive::read(&path)?;
+        if rom.len() != ROM_SIZE * 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ROM is {} bytes, expected {}", rom.len(), ROM_SIZE * 2),
+            ));
+        }
+        let (os, basic) = rom.split_at(ROM_SIZE);
+        let machine = Self::install(i, os, basic)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        info!("Loaded CPC 464 ROM {:?}", path.as_ref());
+        Ok(machine)
+    }
+
+    // Presses or releases the key for `c`, false if there's no such key
+    pub fn set_key(&self, c: char, pressed: bool) -> bool {
+        match key_position(c) {
+            Some((row, column)) => {
+                self.keyboard.set(row, column, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// The joystick's row: up, down, left, right, fire 2 and fire 1 from bit 0
+fn joystick_bits(joystick: &Joystick) -> u8 {
+    [
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+        Button::Fire2,
+        Button::Fire,
+    ]
+    .iter()
+    .enumerate()
+    .filter(|(_, &button)| joystick.pressed(button))
+    .fold(0, |bits, (n, _)| bits | 1 << n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_position, pen, Cpc, BORDER_CHARS, BORDER_LINES, FRAME_CYCLES, ROM_SIZE};
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+    use crate::peripherals::joystick::Button;
+
+    const WHITE: [u8; 4] = [0xFF; 4];
+    const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+    const BLUE: [u8; 4] = [0x00, 0x00, 0x80, 0xFF];
+
+    // DI, IM 1, LD B,0, EI then JP $; the interrupt routine at 0038 does INC B, EI, RET
+    fn cpc() -> (Interconnect, Cpc) {
+        let mut os = vec![0; ROM_SIZE];
+        os[..9].copy_from_slice(&[0xF3, 0xED, 0x56, 0x06, 0x00, 0xFB, 0xC3, 0x06, 0x00]);
+        os[0x38..0x3B].copy_from_slice(&[0x04, 0xFB, 0xC9]);
+        let mut i = Interconnect::builder().build();
+        let cpc = Cpc::install(&mut i, &os, &[0x22; ROM_SIZE]).unwrap();
+        i.cpu.reg.sp = 0x8000;
+        (i, cpc)
+    }
+
+    fn run(i: &mut Interconnect, cycles: usize) {
+        let end = i.cpu.cycles + cycles;
+        while i.cpu.cycles < end {
+            i.step();
+        }
+    }
+
+    #[test]
+    fn roms() {
+        let (mut i, cpc) = cpc();
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        assert_eq!(i.cpu.read8(0xC000), 0x22);
+        // Writes go to the RAM under the ROMs
+        i.cpu.write8(0x0000, 0x11);
+        i.cpu.write8(0xFFFF, 0x33);
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        assert_eq!(i.cpu.read8(0xFFFF), 0x22);
+        // Mode 2, both ROMs off
+        i.cpu.memory.paging_write(0x7F00, 0x8E);
+        assert_eq!(i.cpu.read8(0x0000), 0x11);
+        assert_eq!(i.cpu.read8(0xFFFF), 0x33);
+        assert_eq!(cpc.gate_array.borrow().mode, 2);
+        // The upper ROM back on
+        i.cpu.memory.paging_write(0x7F00, 0x84);
+        assert_eq!(i.cpu.read8(0x0000), 0x11);
+        assert_eq!(i.cpu.read8(0xFFFF), 0x22);
+        assert!(Cpc::install(&mut i, &[], &[0; ROM_SIZE]).is_err());
+        assert!(Cpc::install(&mut i, &[0; ROM_SIZE], &[0; ROM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn interrupts() {
+        let (mut i, cpc) = cpc();
+        // Every 52 lines, 6 a frame
+        run(&mut i, 53 * 64 * 4);
+        assert_eq!(i.cpu.reg.b, 1);
+        run(&mut i, FRAME_CYCLES * 2 - 53 * 64 * 4);
+        assert_eq!(i.cpu.reg.b, 12);
+        // The count reset with the VSYNC
+        let frame = cpc.crtc.borrow().line;
+        run(&mut i, (312 - frame + 240 + 1) * 64 * 4);
+        assert!(cpc.crtc.borrow().vsync);
+        let b = i.cpu.reg.b;
+        run(&mut i, 2 * 64 * 4);
+        assert_eq!(i.cpu.reg.b, b + 1);
+        // Resetting the count clears a pending interrupt
+        i.cpu.memory.paging_write(0x7F00, 0x91);
+        run(&mut i, 51 * 64 * 4);
+        assert_eq!(i.cpu.reg.b, b + 1);
+    }
+
+    #[test]
+    fn keyboard() {
+        let (mut i, cpc) = cpc();
+        // A out, B in, C out; select PSG register 14
+        i.cpu.io.write(0xF782, 0x82);
+        i.cpu.io.write(0xF40E, 0x0E);
+        i.cpu.io.write(0xF6C0, 0xC0);
+        i.cpu.io.write(0xF600, 0x00);
+        // A in, read row 5
+        i.cpu.io.write(0xF792, 0x92);
+        i.cpu.io.write(0xF645, 0x45);
+        assert_eq!(i.cpu.io.read(0xF400), 0xFF);
+        assert!(cpc.set_key(' ', true));
+        assert_eq!(i.cpu.io.read(0xF400), 0x7F);
+        cpc.keyboard.press(8, 0);
+        assert_eq!(i.cpu.io.read(0xF400), 0x7F);
+        i.cpu.io.write(0xF648, 0x48);
+        assert_eq!(i.cpu.io.read(0xF400), 0xFE);
+        cpc.joystick.press(Button::Fire);
+        i.cpu.io.write(0xF649, 0x49);
+        assert_eq!(i.cpu.io.read(0xF400), 0xDF);
+        assert_eq!(key_position('\r'), Some((2, 2)));
+        assert_eq!(key_position('\0'), None);
+
+        // VSYNC on port B bit 0
+        assert_eq!(i.cpu.io.read(0xF500), 0x7E);
+        cpc.crtc.borrow_mut().vsync = true;
+        assert_eq!(i.cpu.io.read(0xF500), 0x7F);
+    }
+
+    #[test]
+    fn display() {
+        let (mut i, cpc) = cpc();
+        assert_eq!(pen(0x88, 1, 0), 3);
+        assert_eq!(pen(0x40, 0, 1), 1);
+        assert_eq!(pen(0x02, 0, 0), 8);
+        // Mode 2 with pen 0 black, pen 1 bright white and a blue border
+        i.cpu.memory.paging_write(0x7F00, 0x8E);
+        for value in [0x00, 0x54, 0x01, 0x4B, 0x10, 0x44] {
+            i.cpu.memory.paging_write(0x7F00, value);
+        }
+        // The screen starts at C000, the second scanline of each row 800 on
+        i.cpu.write8(0xC000, 0x80);
+        i.cpu.write8(0xC801, 0x01);
+        // The start address reads back from the CRTC
+        i.cpu.io.write(0xBC00, 13);
+        i.cpu.io.write(0xBD00, 1);
+        assert_eq!(i.cpu.io.read(0xBF00), 1);
+        i.cpu.io.write(0xBD00, 0);
+        run(&mut i, FRAME_CYCLES * 2);
+        let frame = cpc.gate_array.borrow().frame.clone();
+        let frame = frame.borrow();
+        let (x, y) = (BORDER_CHARS * 16, BORDER_LINES);
+        assert_eq!(frame.pixel(0, 0), BLUE);
+        assert_eq!(frame.pixel(x - 1, y), BLUE);
+        assert_eq!(frame.pixel(x, y - 1), BLUE);
+        assert_eq!(frame.pixel(x, y), WHITE);
+        assert_eq!(frame.pixel(x + 1, y), BLACK);
+        assert_eq!(frame.pixel(x + 15, y + 1), WHITE);
+        assert_eq!(frame.pixel(x + 14, y + 1), BLACK);
+        assert_eq!(frame.pixel(x + 40 * 16, y), BLUE);
+        assert_eq!(frame.pixel(x, y + 200), BLUE);
+    }
+}
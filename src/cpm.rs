@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::instruction_info::Register::{BC, HL};
+use crate::interconnect::HookAction;
+
+// CP/M record size, disks are read and written a record at a time
+pub const RECORD: usize = 128;
+// Size of the CCP and BDOS, which sit right below the BIOS
+pub const SYSTEM_SIZE: u16 = 0x1600;
+// BDOS entry, relative to the CCP
+const BDOS_OFFSET: u16 = 0x0806;
+// Entries in the BIOS jump table
+const ENTRIES: u16 = 17;
+
+// Disk parameter block, describes the disk layout to the BDOS. The defaults are the
+// standard 8" single sided single density format (77 tracks of 26 records, 2 system
+// tracks, 1K blocks), which is also what most .dsk images for emulators use.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Dpb {
+    // Records per track
+    pub spt: u16,
+    pub bsh: u8,
+    pub blm: u8,
+    pub exm: u8,
+    // Highest block number
+    pub dsm: u16,
+    // Highest directory entry number
+    pub drm: u16,
+    pub al0: u8,
+    pub al1: u8,
+    pub cks: u16,
+    // System tracks
+    pub off: u16,
+    // Physical record for each logical one (1 based), empty if records are in order
+    pub skew: Vec<u8>,
+}
+
+impl Default for Dpb {
+    fn default() -> Self {
+        Self {
+            spt: 26,
+            bsh: 3,
+            blm: 7,
+            exm: 0,
+            dsm: 242,
+            drm: 63,
+            al0: 0xC0,
+            al1: 0x00,
+            cks: 16,
+            off: 2,
+            skew: vec![
+                1, 7, 13, 19, 25, 5, 11, 17, 23, 3, 9, 15, 21, 2, 8, 14, 20, 26, 6, 12, 18, 24, 4,
+                10, 16, 22,
+            ],
+        }
+    }
+}
+
+impl Dpb {
+    // Overrides fields of the default from a list like `spt=32,dsm=127,off=1,skew=`,
+    // numbers are decimal or 0x prefixed hex, an empty skew turns translation off
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut dpb = Self::default();
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got `{}`", field))?;
+            let number = |value: &str| -> Result<u16, String> {
+                match value.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => value.parse(),
+                }
+                .map_err(|_| format!("Bad value for {}: `{}`", key, value))
+            };
+            match key {
+                "spt" => dpb.spt = number(value)?,
+                "bsh" => dpb.bsh = number(value)? as u8,
+                "blm" => dpb.blm = number(value)? as u8,
+                "exm" => dpb.exm = number(value)? as u8,
+                "dsm" => dpb.dsm = number(value)?,
+                "drm" => dpb.drm = number(value)?,
+                "al0" => dpb.al0 = number(value)? as u8,
+                "al1" => dpb.al1 = number(value)? as u8,
+                "cks" => dpb.cks = number(value)?,
+                "off" => dpb.off = number(value)?,
+                "skew" => {
+                    dpb.skew = value
+                        .split(' ')
+                        .filter(|s| !s.is_empty())
+                        .map(|s| number(s).map(|n| n as u8))
+                        .collect::<Result<_, _>>()?
+                }
+                _ => return Err(format!("Unknown DPB field `{}`", key)),
+            }
+        }
+        if !dpb.skew.is_empty() && dpb.skew.len() != dpb.spt as usize {
+            return Err("The skew table needs an entry per record".to_string());
+        }
+        Ok(dpb)
+    }
+
+    // As the BDOS expects it in memory
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.spt.to_le_bytes().to_vec();
+        out.extend_from_slice(&[self.bsh, self.blm, self.exm]);
+        out.extend_from_slice(&self.dsm.to_le_bytes());
+        out.extend_from_slice(&self.drm.to_le_bytes());
+        out.extend_from_slice(&[self.al0, self.al1]);
+        out.extend_from_slice(&self.cks.to_le_bytes());
+        out.extend_from_slice(&self.off.to_le_bytes());
+        out
+    }
+}
+
+// A raw disk image (.dsk, .img): every record of every track in physical order, starting
+// with track 0. Writes go straight to the file if the image was opened from one.
+#[derive(Debug, Default)]
+pub struct Disk {
+    pub data: Vec<u8>,
+    pub dpb: Dpb,
+    path: Option<PathBuf>,
+}
+
+impl Disk {
+    pub fn new(data: Vec<u8>, dpb: Dpb) -> Self {
+        Self {
+            data,
+            dpb,
+            path: None,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, dpb: Dpb) -> io::Result<Self> {
+        // Disks in archives can't be written back, changes are kept in memory
+        let path = path.as_ref();
+        Ok(Self {
+            data: archive::read(path)?,
+            dpb,
+            path: (!archive::is_archive(path)).then(|| path.to_path_buf()),
+        })
+    }
+
+    // Byte offset of a record, `record` is 0 based and physical
+    fn offset(&self, track: u16, record: u16) -> Option<usize> {
+        let offset = (track as usize * self.dpb.spt as usize + record as usize) * RECORD;
+        match record < self.dpb.spt && offset + RECORD <= self.data.len() {
+            true => Some(offset),
+            false => None,
+        }
+    }
+
+    pub fn read(&self, track: u16, record: u16) -> Option<&[u8]> {
+        let offset = self.offset(track, record)?;
+        Some(&self.data[offset..offset + RECORD])
+    }
+
+    pub fn write(&mut self, track: u16, record: u16, data: &[u8]) -> io::Result<()> {
+        let offset = self
+            .offset(track, record)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No such record"))?;
+        self.data[offset..offset + RECORD].copy_from_slice(&data[..RECORD]);
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(&data[..RECORD])?;
+        }
+        Ok(())
+    }
+}
+
+// A CP/M 2.2 BIOS implemented in the host, independent of any disk controller. Installed
+// with `Interconnect::install_cpm_bios`, which puts the jump table at `base` with a PC hook
+// on every entry, builds the disk parameter headers after it and cold boots. Booting loads
+// the CCP and BDOS from the system tracks of drive A to `base - SYSTEM_SIZE`, skipping the
+// first `boot_records` (the cold start loader), and sets up page zero. A CP/M 2.2 system
+// built for a 64K machine has its BIOS at FA00, the default.
+//
+// The console reads from `input`, and from stdin once that's empty if `stdin` is set.
+// Otherwise CONIN waits, spinning like a polling loop would. Output is collected in
+// `output` and echoed to stdout if `echo` is set.
+pub struct CpmBios {
+    pub base: u16,
+    pub boot_records: usize,
+    pub disks: Vec<Option<Disk>>,
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+    pub stdin: bool,
+    pub echo: bool,
+    // Disk parameter header addresses, one per drive
+    dph: Vec<u16>,
+    drive: usize,
+    track: u16,
+    record: u16,
+    dma: u16,
+}
+
+impl Default for CpmBios {
+    fn default() -> Self {
+        Self {
+            base: 0xFA00,
+            boot_records: 1,
+            disks: Vec::new(),
+            input: VecDeque::new(),
+            output: Vec::new(),
+            stdin: false,
+            echo: false,
+            dph: Vec::new(),
+            drive: 0,
+            track: 0,
+            record: 0,
+            dma: 0x0080,
+        }
+    }
+}
+
+impl CpmBios {
+    // Puts `disk` in drive 0 (A:) to 15 (P:)
+    pub fn mount(&mut self, drive: usize, disk: Disk) {
+        if self.disks.len() <= drive {
+            self.disks.resize_with(drive + 1, || None);
+        }
+        self.disks[drive] = Some(disk);
+    }
+
+    pub fn ccp(&self) -> u16 {
+        self.base.wrapping_sub(SYSTEM_SIZE)
+    }
+
+    // Address of each BIOS entry, BOOT first
+    pub fn entries(&self) -> impl Iterator<Item = u16> {
+        let base = self.base;
+        (0..ENTRIES).map(move |n| base + n * 3)
+    }
+
+    // Writes the jump table (a RET per entry, the hooks do the work) and the disk
+    // parameter headers with what they point to
+    pub(crate) fn build_tables(&mut self, cpu: &mut Cpu) -> Result<(), String> {
+        for entry in self.entries() {
+            cpu.memory.load_slice(entry, &[0xC9, 0x00, 0x00]);
+        }
+        let mut tables = Vec::new();
+        let start = self.base as usize + 0x40;
+        let dirbuf = start;
+        tables.resize(RECORD, 0);
+        self.dph.clear();
+        for disk in &self.disks {
+            let disk = match disk {
+                Some(disk) => disk,
+                None => {
+                    self.dph.push(0);
+                    continue;
+                }
+            };
+            let dpb = &disk.dpb;
+            let dph = start + tables.len();
+            let mut next = dph + 16;
+            let mut alloc = |len: usize| {
+                let addr = next;
+                next += len;
+                addr
+            };
+            let xlt = if dpb.skew.is_empty() {
+                0
+            } else {
+                alloc(dpb.skew.len())
+            };
+            let dpb_addr = alloc(15);
+            let csv = alloc(dpb.cks as usize);
+            let alv = alloc(dpb.dsm as usize / 8 + 1);
+            for word in [xlt, 0, 0, 0, dirbuf, dpb_addr, csv, alv] {
+                tables.extend_from_slice(&(word as u16).to_le_bytes());
+            }
+            tables.extend_from_slice(&dpb.skew);
+            tables.extend_from_slice(&dpb.to_bytes());
+            tables.resize(next - start, 0);
+            self.dph.push(dph as u16);
+        }
+        if start + tables.len() > 0x1_0000 {
+            return Err(format!(
+                "BIOS tables need {} bytes, there's no room above {:04X}",
+                tables.len(),
+                start
+            ));
+        }
+        cpu.memory.load_slice(start as u16, &tables);
+        Ok(())
+    }
+
+    // Runs the BIOS function for the entry at `addr`
+    pub fn call(&mut self, cpu: &mut Cpu, addr: u16) -> HookAction {
+        match (addr - self.base) / 3 {
+            0 => {
+                cpu.memory.poke(0x0003, 0x00);
+                cpu.memory.poke(0x0004, 0x00);
+                return self.boot(cpu);
+            }
+            1 => return self.boot(cpu),
+            2 => cpu.reg.a = if self.input.is_empty() { 0x00 } else { 0xFF },
+            3 => match self.read_console() {
+                Some(byte) => cpu.reg.a = byte,
+                None => {
+                    cpu.cycles += 4;
+                    return HookAction::Skip;
+                }
+            },
+            4 => {
+                self.output.push(cpu.reg.c);
+                if self.echo {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&[cpu.reg.c]).ok();
+                    stdout.flush().ok();
+                }
+            }
+            // LIST and PUNCH go nowhere, READER is at end of file
+            5 | 6 => {}
+            7 => cpu.reg.a = 0x1A,
+            8 => self.track = 0,
+            9 => {
+                let drive = cpu.reg.c as usize;
+                let dph = self.dph.get(drive).copied().unwrap_or(0);
+                if dph != 0 {
+                    self.drive = drive;
+                }
+                cpu.write_pair(HL, dph);
+            }
+            10 => self.track = cpu.read_pair(BC),
+            11 => self.record = cpu.read_pair(BC),
+            12 => self.dma = cpu.read_pair(BC),
+            13 => cpu.reg.a = self.transfer(cpu, false) as u8,
+            14 => cpu.reg.a = self.transfer(cpu, true) as u8,
+            15 => cpu.reg.a = 0xFF,
+            _ => {
+                let record = cpu.read_pair(BC);
+                let skew = self.disk().map_or(&[][..], |disk| &disk.dpb.skew[..]);
+                let physical = skew.get(record as usize).map_or(record, |&r| r as u16);
+                cpu.write_pair(HL, physical);
+            }
+        }
+        HookAction::Return
+    }
+
+    fn disk(&self) -> Option<&Disk> {
+        self.disks.get(self.drive)?.as_ref()
+    }
+
+    fn read_console(&mut self) -> Option<u8> {
+        if let Some(byte) = self.input.pop_front() {
+            return Some(byte);
+        }
+        let mut byte = [0];
+        match self.stdin && io::stdin().read(&mut byte).ok()? == 1 {
+            // Line endings from the terminal become the CR CP/M expects
+            true if byte[0] == b'\n' => Some(b'\r'),
+            true => Some(byte[0]),
+            false => None,
+        }
+    }
+
+    // Reads or writes the selected record, returns true on an error
+    fn transfer(&mut self, cpu: &mut Cpu, write: bool) -> bool {
+        let (track, dma) = (self.track, self.dma);
+        let disk = match self.disks.get_mut(self.drive).and_then(Option::as_mut) {
+            Some(disk) => disk,
+            None => return true,
+        };
+        // Translated record numbers are 1 based
+        let record = match disk.dpb.skew.is_empty() {
+            true => self.record,
+            false => self.record.wrapping_sub(1),
+        };
+        if write {
+            let data: Vec<u8> = (0..RECORD as u16)
+                .map(|n| cpu.memory.peek(dma.wrapping_add(n)))
+                .collect();
+            return disk.write(track, record, &data).is_err();
+        }
+        match disk.read(track, record) {
+            Some(data) => {
+                cpu.memory.load_slice(dma, data);
+                false
+            }
+            None => true,
+        }
+    }
+
+    // Loads the CCP and BDOS, sets up page zero and jumps to the CCP with the current drive
+    // in C
+    fn boot(&mut self, cpu: &mut Cpu) -> HookAction {
+        let ccp = self.ccp();
+        if let Some(Some(disk)) = self.disks.first() {
+            let start = self.boot_records * RECORD;
+            let end = (start + SYSTEM_SIZE as usize).min(disk.data.len());
+            if let Some(system) = disk.data.get(start..end) {
+                cpu.memory.load_slice(ccp, system);
+            }
+        }
+        let [low, high] = (self.base + 3).to_le_bytes();
+        cpu.memory.load_slice(0x0000, &[0xC3, low, high]);
+        let [low, high] = (ccp + BDOS_OFFSET).to_le_bytes();
+        cpu.memory.load_slice(0x0005, &[0xC3, low, high]);
+        self.dma = 0x0080;
+        cpu.reg.c = cpu.memory.peek(0x0004);
+        cpu.reg.sp = 0x0100;
+        cpu.reg.pc = ccp;
+        HookAction::Execute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CpmBios, Disk, Dpb, RECORD};
+    use crate::interconnect::{Interconnect, Preset};
+
+    #[test]
+    fn dpb() {
+        let dpb = Dpb::parse("spt=0x20,dsm=127,skew=").unwrap();
+        assert_eq!(dpb.spt, 32);
+        assert_eq!(dpb.dsm, 127);
+        assert!(dpb.skew.is_empty());
+        assert_eq!(
+            Dpb::default().to_bytes(),
+            [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xC0, 0, 16, 0, 2, 0]
+        );
+        assert!(Dpb::parse("spt=4").is_err());
+        assert!(Dpb::parse("sides=2").is_err());
+        assert!(Dpb::parse("spt").is_err());
+    }
+
+    #[test]
+    fn boot_and_read() {
+        // Two tracks of four records, no skew, one system track
+        let dpb = Dpb::parse("spt=4,off=1,dsm=7,drm=15,cks=4,skew=").unwrap();
+        let mut image = vec![0xE5; 8 * RECORD];
+        // The "CCP" in the second record of the system track: select A:, read track 1
+        // record 2 to 8000, print its first byte and HALT.
+        // LD C, 0; CALL SELDSK; LD BC, 1; CALL SETTRK; LD BC, 2; CALL SETSEC;
+        // LD BC, 8000; CALL SETDMA; CALL READ; LD A, (8000); LD C, A; CALL CONOUT; HALT
+        let ccp = [
+            0x0E, 0x00, 0xCD, 0x1B, 0xFA, 0x01, 0x01, 0x00, 0xCD, 0x1E, 0xFA, 0x01, 0x02, 0x00,
+            0xCD, 0x21, 0xFA, 0x01, 0x00, 0x80, 0xCD, 0x24, 0xFA, 0xCD, 0x27, 0xFA, 0x3A, 0x00,
+            0x80, 0x4F, 0xCD, 0x0C, 0xFA, 0x76,
+        ];
+        image[RECORD..RECORD + ccp.len()].copy_from_slice(&ccp);
+        image[6 * RECORD] = b'Z';
+
+        let mut bios = CpmBios::default();
+        bios.mount(0, Disk::new(image, dpb));
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let bios = i.install_cpm_bios(bios).unwrap();
+        while !i.cpu.int.halt {
+            i.step();
+        }
+        assert_eq!(bios.borrow().output, b"Z");
+        assert_eq!(i.peek(0x8000), b'Z');
+        assert_eq!(i.cpu.reg.a, b'Z');
+        // Page zero and the disk parameter header
+        assert_eq!(
+            i.dump_range(0x0000, 8).get(6..29),
+            Some("C3 03 FA 00 00 C3 06 EC")
+        );
+        let dph = i.cpu.reg.l as u16 | (i.cpu.reg.h as u16) << 8;
+        assert_eq!(dph, 0xFAC0);
+        assert_eq!(i.cpu.memory.peek16(dph + 10), dph + 16);
+        assert_eq!(i.cpu.memory.peek16(dph + 16), 4);
+    }
+}
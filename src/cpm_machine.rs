@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::assembler::assemble;
+use crate::cpm::{Disk, RECORD, SYSTEM_SIZE};
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Page, PAGE_SIZE};
+use crate::peripherals::serial::SerialBackend;
+use crate::peripherals::sio::CHANNEL_A;
+use crate::peripherals::{fdc, Fdc, Sio};
+
+pub const CLOCK: usize = 4_000_000;
+// The boot ROM sits over the bottom of RAM until a write to the bank port with bit 0 set
+pub const ROM_SIZE: usize = 0x1000;
+pub const BANK_PORT: u8 = 0x20;
+pub const SIO_PORTS: u8 = 0x00;
+pub const FDC_PORTS: u8 = 0x10;
+pub const DRIVES: usize = 16;
+// Where the BIOS runs, right below it the CCP and BDOS of a 64K system
+pub const BIOS: u16 = 0xFA00;
+// The BIOS is kept in the boot ROM from here
+const BIOS_IMAGE: u16 = 0x0100;
+// 38400 baud
+const SIO_CHAR_CYCLES: usize = CLOCK * 10 / 38_400;
+
+// Copies the BIOS to the top of RAM and cold boots it
+const LOADER: &str = "
+        DI
+        LD SP, 0
+        LD HL, BIOS_IMAGE
+        LD DE, BIOS
+        LD BC, 10000h - BIOS
+        LDIR
+        JP BIOS
+";
+
+// The CP/M 2.2 BIOS. The console is SIO channel A, polled, and the disks are on the floppy
+// controller with their tables after the code. Booting loads the CCP and BDOS from the
+// system tracks of A: from the second sector on, after the cold start loader.
+const BIOS_SOURCE: &str = "
+CCP     EQU BIOS - SYSTEM_SIZE
+BDOS    EQU CCP + 0806h
+IOBYTE  EQU 0003h
+CDISK   EQU 0004h
+
+        ORG BIOS
+        JP BOOT
+WBOOTE: JP WBOOT
+        JP CONST
+        JP CONIN
+        JP CONOUT
+        JP LIST
+        JP PUNCH
+        JP READER
+        JP HOME
+        JP SELDSK
+        JP SETTRK
+        JP SETSEC
+        JP SETDMA
+        JP READ
+        JP WRITE
+        JP LISTST
+        JP SECTRAN
+
+BOOT:   LD SP, 0080h
+        LD A, 1
+        OUT (BANK_PORT), A
+        LD HL, SIOINIT
+        LD B, SIOEND - SIOINIT
+SIOLP:  LD A, (HL)
+        OUT (SIO_A_CTL), A
+        INC HL
+        DJNZ SIOLP
+        XOR A
+        LD (IOBYTE), A
+        LD (CDISK), A
+        LD HL, SIGNON
+        CALL PRINT
+
+WBOOT:  LD SP, 0080h
+        XOR A
+        OUT (FDC_DRIVE), A
+        OUT (FDC_TRACK_HIGH), A
+        LD E, A
+        LD HL, CCP
+        LD B, SYSTEM_RECORDS
+        LD C, 2
+LOAD:   LD A, E
+        OUT (FDC_TRACK), A
+        LD A, C
+        OUT (FDC_SECTOR), A
+        LD A, L
+        OUT (FDC_DMA_LOW), A
+        LD A, H
+        OUT (FDC_DMA_HIGH), A
+        XOR A
+        CALL FDCCMD
+        JP NZ, BOOTERR
+        PUSH DE
+        LD DE, RECORD
+        ADD HL, DE
+        POP DE
+        INC C
+        LD A, (DPB0)
+        CP C
+        JP NC, NEXT
+        LD C, 1
+        INC E
+NEXT:   DJNZ LOAD
+
+        LD A, 0C3h
+        LD (0000h), A
+        LD HL, WBOOTE
+        LD (0001h), HL
+        LD (0005h), A
+        LD HL, BDOS
+        LD (0006h), HL
+        LD BC, 0080h
+        CALL SETDMA
+        LD A, (CDISK)
+        LD C, A
+        JP CCP
+
+BOOTERR: LD HL, ERROR
+        CALL PRINT
+        DI
+STOP:   JP STOP
+
+; Prints the string at HL up to a 0
+PRINT:  LD A, (HL)
+        OR A
+        RET Z
+        LD C, A
+        CALL CONOUT
+        INC HL
+        JP PRINT
+
+CONST:  IN A, (SIO_A_CTL)
+        AND 01h
+        RET Z
+        LD A, 0FFh
+        RET
+
+CONIN:  IN A, (SIO_A_CTL)
+        AND 01h
+        JP Z, CONIN
+        IN A, (SIO_A_DATA)
+        AND 7Fh
+        RET
+
+CONOUT: IN A, (SIO_A_CTL)
+        AND 04h
+        JP Z, CONOUT
+        LD A, C
+        OUT (SIO_A_DATA), A
+        RET
+
+; Nothing on the list and punch devices, the reader is at end of file
+LIST:
+PUNCH:  RET
+
+LISTST: LD A, 0FFh
+        RET
+
+READER: LD A, 1Ah
+        RET
+
+HOME:   LD BC, 0
+SETTRK: LD A, C
+        OUT (FDC_TRACK), A
+        LD A, B
+        OUT (FDC_TRACK_HIGH), A
+        RET
+
+SETSEC: LD A, C
+        OUT (FDC_SECTOR), A
+        RET
+
+SETDMA: LD A, C
+        OUT (FDC_DMA_LOW), A
+        LD A, B
+        OUT (FDC_DMA_HIGH), A
+        RET
+
+; Disk parameter header of drive C in HL, 0 if there's no such drive
+SELDSK: LD HL, 0
+        LD A, C
+        CP NDISKS
+        RET NC
+        LD L, C
+        ADD HL, HL
+        LD DE, DPHS
+        ADD HL, DE
+        LD E, (HL)
+        INC HL
+        LD D, (HL)
+        EX DE, HL
+        LD A, H
+        OR L
+        RET Z
+        LD A, C
+        OUT (FDC_DRIVE), A
+        RET
+
+; Sectors are numbered from 1, through the table at DE if there is one
+SECTRAN: LD A, D
+        OR E
+        JP Z, NOXLT
+        EX DE, HL
+        ADD HL, BC
+        LD L, (HL)
+        LD H, 0
+        RET
+NOXLT:  LD H, B
+        LD L, C
+        INC HL
+        RET
+
+READ:   LD A, FDC_READ
+        JP DISKIO
+WRITE:  LD A, FDC_WRITE
+DISKIO: CALL FDCCMD
+        RET Z
+        LD A, 1
+        RET
+
+; Runs controller command A, returns the status in A with Z set if it worked
+FDCCMD: OUT (FDC_COMMAND), A
+FDCWT:  IN A, (FDC_STATUS)
+        CP FDC_BUSY
+        JP Z, FDCWT
+        OR A
+        RET
+
+; Channel A reset, x16 clock with 1 stop bit, 8 bit receive and transmit enabled
+SIOINIT: DB 18h, 04h, 44h, 03h, 0C1h, 05h, 68h
+SIOEND:
+
+SIGNON: DB 0Dh, 0Ah, '64K CP/M 2.2', 0Dh, 0Ah, 0
+ERROR:  DB 0Dh, 0Ah, 'BOOT ERROR', 0Dh, 0Ah, 0
+";
+
+// A generic CP/M business machine in the style of the Kaypro and Osborne: 64K of RAM, a
+// boot ROM over the bottom 4K at reset, a Z80 SIO with the console on channel A (00-03)
+// and a simple floppy controller (10-17, see `Fdc`) with up to 16 drives. Writing 1 to
+// port 20 banks the ROM out for good.
+//
+// The boot ROM holds a CP/M 2.2 BIOS for the machine, copied to FA00 and booted from
+// there, so stock 64K CP/M 2.2 disks boot: the CCP and BDOS are read from the system
+// tracks of A: by the BIOS, which then drives the SIO and the controller like a BIOS on a
+// real machine would. The disk parameter tables are built from each disk's `Dpb`.
+pub struct CpmMachine {
+    pub sio: Rc<RefCell<Sio>>,
+    pub fdc: Rc<RefCell<Fdc>>,
+}
+
+impl CpmMachine {
+    // The machine at reset with `disks` in A:, B: and so on and the console connected to
+    // `backend`
+    pub fn install<B: SerialBackend + 'static>(
+        i: &mut Interconnect,
+        disks: Vec<Disk>,
+        backend: B,
+    ) -> Result<Self, String> {
+        if disks.is_empty() || disks.len() > DRIVES {
+            return Err(format!("{} disks, expected 1 to {}", disks.len(), DRIVES));
+        }
+        let rom = boot_rom(&disks)?;
+        let mut memory = Memory::default();
+        memory.load_rom(0x0000, &rom);
+        memory.add_paging_register(0x00FF, BANK_PORT as u16, |memory, value| {
+            if value & 1 != 0 {
+                for page in 0..ROM_SIZE / PAGE_SIZE {
+                    memory.map_page(page, Page::Ram(page * PAGE_SIZE));
+                }
+            }
+        });
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+
+        let mut sio = Sio::new(SIO_CHAR_CYCLES);
+        sio.connect(CHANNEL_A, backend);
+        let sio = i.add_device(sio);
+        i.register_port(SIO_PORTS..=SIO_PORTS + 3, sio.clone());
+        let mut fdc = Fdc::default();
+        for (drive, disk) in disks.into_iter().enumerate() {
+            fdc.mount(drive, disk);
+        }
+        let fdc = i.add_device(fdc);
+        i.register_port(FDC_PORTS..=FDC_PORTS + 7, fdc.clone());
+        Ok(Self { sio, fdc })
+    }
+}
+
+// The loader followed by the BIOS, with the port numbers and the disk tables filled in
+fn boot_rom(disks: &[Disk]) -> Result<Vec<u8>, String> {
+    let equates = [
+        ("BIOS", BIOS),
+        ("BIOS_IMAGE", BIOS_IMAGE),
+        ("SYSTEM_SIZE", SYSTEM_SIZE),
+        ("SYSTEM_RECORDS", SYSTEM_SIZE / RECORD as u16),
+        ("RECORD", RECORD as u16),
+        ("NDISKS", disks.len() as u16),
+        ("BANK_PORT", BANK_PORT as u16),
+        ("SIO_A_CTL", SIO_PORTS as u16),
+        ("SIO_A_DATA", SIO_PORTS as u16 + 1),
+        ("FDC_DRIVE", (FDC_PORTS as u16) + fdc::DRIVE),
+        ("FDC_TRACK", (FDC_PORTS as u16) + fdc::TRACK),
+        ("FDC_SECTOR", (FDC_PORTS as u16) + fdc::SECTOR),
+        ("FDC_COMMAND", (FDC_PORTS as u16) + fdc::COMMAND),
+        ("FDC_STATUS", (FDC_PORTS as u16) + fdc::STATUS),
+        ("FDC_DMA_LOW", (FDC_PORTS as u16) + fdc::DMA_LOW),
+        ("FDC_DMA_HIGH", (FDC_PORTS as u16) + fdc::DMA_HIGH),
+        ("FDC_TRACK_HIGH", (FDC_PORTS as u16) + fdc::TRACK_HIGH),
+        ("FDC_READ", fdc::READ as u16),
+        ("FDC_WRITE", fdc::WRITE as u16),
+        ("FDC_BUSY", fdc::BUSY as u16),
+    ]
+    .iter()
+    .fold(String::new(), |mut out, (name, value)| {
+        writeln!(out, "{} EQU {}", name, value).unwrap();
+        out
+    });
+    let loader = assemble(&format!("{}{}", equates, LOADER))?.to_binary();
+    let bios = format!("{}{}{}", equates, BIOS_SOURCE, disk_tables(disks));
+    let bios = assemble(&bios)
+        .map_err(|e| format!("The BIOS doesn't fit: {}", e))?
+        .to_binary();
+    let mut rom = vec![0xFF; ROM_SIZE];
+    rom[..loader.len()].copy_from_slice(&loader);
+    let image = BIOS_IMAGE as usize;
+    rom[image..image + bios.len()].copy_from_slice(&bios);
+    Ok(rom)
+}
+
+// A disk parameter header per drive, what they point to and the directory buffer they
+// share. DPB0 is drive A's, which booting reads the records per track from.
+fn disk_tables(disks: &[Disk]) -> String {
+    let mut out = String::from("DPHS:\n");
+    for n in 0..disks.len() {
+        writeln!(out, "        DW DPH{}", n).unwrap();
+    }
+    for (n, disk) in disks.iter().enumerate() {
+        let dpb = &disk.dpb;
+        let xlt = match dpb.skew.is_empty() {
+            true => "0".to_string(),
+            false => format!("XLT{}", n),
+        };
+        writeln!(
+            out,
+            "DPH{n}: DW {xlt}, 0, 0, 0, DIRBUF, DPB{n}, CSV{n}, ALV{n}"
+        )
+        .unwrap();
+        writeln!(out, "DPB{}: DW {}", n, dpb.spt).unwrap();
+        writeln!(out, "        DB {}, {}, {}", dpb.bsh, dpb.blm, dpb.exm).unwrap();
+        writeln!(out, "        DW {}, {}", dpb.dsm, dpb.drm).unwrap();
+        writeln!(out, "        DB {}, {}", dpb.al0, dpb.al1).unwrap();
+        writeln!(out, "        DW {}, {}", dpb.cks, dpb.off).unwrap();
+        if !dpb.skew.is_empty() {
+            let skew: Vec<String> = dpb.skew.iter().map(u8::to_string).collect();
+            writeln!(out, "XLT{}: DB {}", n, skew.join(", ")).unwrap();
+        }
+    }
+    out.push_str("DIRBUF: DS RECORD\n");
+    for (n, disk) in disks.iter().enumerate() {
+        let dpb = &disk.dpb;
+        writeln!(out, "CSV{}: DS {}", n, dpb.cks).unwrap();
+        writeln!(out, "ALV{}: DS {}", n, dpb.dsm / 8 + 1).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CpmMachine, BIOS};
+    use crate::assembler::assemble;
+    use crate::cpm::{Disk, Dpb, RECORD};
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+    use crate::peripherals::serial::Pipe;
+
+    // A "CCP" that prints OK through the BIOS, reads logical sector 1 of track 2 (physical
+    // 7 after the skew) to 8000, prints its first byte and writes it back changed
+    const CCP: &str = "
+        ORG 0E400h
+        LD C, 'O'
+        CALL CONOUT
+        LD C, 'K'
+        CALL CONOUT
+        LD C, 1
+        CALL SELDSK
+        LD (NODISK), HL
+        LD C, 0
+        CALL SELDSK
+        LD E, (HL)
+        INC HL
+        LD D, (HL)
+        LD BC, 1
+        CALL SECTRAN
+        LD B, H
+        LD C, L
+        CALL SETSEC
+        LD BC, 2
+        CALL SETTRK
+        LD BC, 8000h
+        CALL SETDMA
+        CALL READ
+        LD (RESULT), A
+        LD A, (8000h)
+        LD C, A
+        CALL CONOUT
+        LD A, 'W'
+        LD (8000h), A
+        CALL WRITE
+DONE:   JP DONE
+NODISK: DW 0FFFFh
+RESULT: DB 0FFh
+CONOUT  EQU 0FA0Ch
+SELDSK  EQU 0FA1Bh
+SETTRK  EQU 0FA1Eh
+SETSEC  EQU 0FA21h
+SETDMA  EQU 0FA24h
+READ    EQU 0FA27h
+WRITE   EQU 0FA2Ah
+SECTRAN EQU 0FA30h
+";
+
+    #[test]
+    fn boots() {
+        let ccp = assemble(CCP).unwrap();
+        let done = ccp.symbols.addr("DONE").unwrap();
+        let dpb = Dpb::default();
+        let mut image = vec![0xE5; 77 * dpb.spt as usize * RECORD];
+        let code = ccp.to_binary();
+        image[RECORD..RECORD + code.len()].copy_from_slice(&code);
+        let record = (2 * dpb.spt as usize + 6) * RECORD;
+        image[record] = b'Z';
+        let pipe = Pipe::default();
+        let mut i = Interconnect::builder().build();
+        let machine =
+            CpmMachine::install(&mut i, vec![Disk::new(image, dpb)], pipe.clone()).unwrap();
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        for _ in 0..100_000 {
+            if i.cpu.reg.pc == done {
+                break;
+            }
+            i.step();
+        }
+        assert_eq!(i.cpu.reg.pc, done);
+        // The last character is still on its way out
+        for _ in 0..1000 {
+            i.step();
+        }
+        assert_eq!(pipe.take_output(), b"\r\n64K CP/M 2.2\r\nOKZ");
+        assert_eq!(i.cpu.memory.peek16(ccp.symbols.addr("NODISK").unwrap()), 0);
+        assert_eq!(i.cpu.read8(ccp.symbols.addr("RESULT").unwrap()), 0);
+        let fdc = machine.fdc.borrow();
+        assert_eq!(fdc.disks[0].as_ref().unwrap().data[record], b'W');
+        // Page zero in RAM, where the ROM was
+        assert_eq!(i.cpu.read8(0x0000), 0xC3);
+        assert_eq!(i.cpu.memory.peek16(0x0001), BIOS + 3);
+        assert_eq!(i.cpu.memory.peek16(0x0006), 0xEC06);
+
+        let mut i = Interconnect::builder().build();
+        assert!(CpmMachine::install(&mut i, Vec::new(), Pipe::default()).is_err());
+        // Too many tables to fit above the BIOS
+        let disks = (0..16)
+            .map(|_| Disk::new(Vec::new(), Dpb::default()))
+            .collect();
+        assert!(CpmMachine::install(&mut i, disks, Pipe::default()).is_err());
+    }
+}
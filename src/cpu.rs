@@ -1,23 +1,38 @@
 use std::ops::BitXor;
 
+use log::debug;
+
+use crate::debugger::{BreakEvent, BreakOn, Trap, WatchKind, Watchpoints};
+use crate::device::IoBus;
+use crate::event::{Event, EventQueue};
 use crate::instruction_info::{Instruction, Register, Register::*};
-use crate::memory::{Memory, MemoryRW};
+use crate::memory::{Memory, MemoryRW, Region};
+use crate::profile::MemoryStats;
+use crate::replay::{Input, InputLog};
 
 pub struct Cpu {
-    pub current_instruction: String,
     pub opcode: u16,
     pub next_opcode: u16,
-    pub breakpoint: bool,
     pub debug: bool,
     pub reg: Registers,
     pub flags: Flags,
     pub cycles: usize, // CPU T states
-    pub io: Io,
+    pub io: IoBus,
     pub int: Interrupt,
     pub instruction: Instruction,
     pub int_pending: bool,
     pub cpm_compat: bool,
     pub memory: Memory,
+    pub events: EventQueue,
+    pub watchpoints: Watchpoints,
+    pub break_on: BreakOn,
+    // Per address read / write counts, collected while set
+    pub mem_stats: Option<MemoryStats>,
+    // Device reads and accepted interrupts, see `InputLog`
+    pub input: InputLog,
+    // Replaces the opcode the CPU reads in its M1 cycle, for hardware that puts its own
+    // byte on the data bus while the CPU fetches (the ZX81's display NOPs)
+    pub m1_filter: Option<fn(u16, u8) -> u8>,
 }
 
 #[derive(Default)]
@@ -52,14 +67,6 @@ pub struct Registers {
     pub iy: u16,
 }
 
-#[derive(Default)]
-pub struct Io {
-    pub port: u8,
-    pub value: u8,
-    pub input: bool,
-    output: bool,
-}
-
 #[derive(Default, Debug)]
 pub struct Flags {
     pub sf: bool, // Sign
@@ -96,7 +103,13 @@ pub struct Interrupt {
     pub int: bool,
     pub iff1: bool,
     pub iff2: bool,
+    // Set by EI, maskable interrupts aren't accepted until the instruction after it is done
+    pub ei_pending: bool,
     pub mode: u8,
+    // A maskable interrupt was accepted / a RETI executed during the last step, for the
+    // daisy chain. Cleared by the interconnect once the devices have been told.
+    pub acknowledged: bool,
+    pub reti: bool,
 }
 
 impl Flags {
@@ -176,18 +189,14 @@ impl Flags {
 impl MemoryRW for Cpu {
     #[inline]
     fn read8(&self, addr: u16) -> u8 {
-        if self.cpm_compat {
-            self.memory[addr]
-        } else if addr < 0x4000 {
-            self.memory.rom[addr as usize]
-        } else if addr == 0x5000 {
-            self.int.int as u8
-        } else if addr < 0x5000 {
-            println!("Reading from RAM");
-            self.memory.ram[addr as usize - 0x4000]
-        } else {
-            self.memory.rom[addr as usize]
+        let byte = self.fetch8(addr);
+        if let Some(stats) = &self.mem_stats {
+            stats.read(addr);
         }
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(addr, WatchKind::Read, byte, byte);
+        }
+        byte
     }
 
     fn read8_inc(&mut self, addr: u16) -> u8 {
@@ -197,7 +206,7 @@ impl MemoryRW for Cpu {
 
     #[inline]
     fn read16(&self, addr: u16) -> u16 {
-        u16::from_le_bytes([self.read8(addr), self.read8(addr + 1)])
+        u16::from_le_bytes([self.read8(addr), self.read8(addr.wrapping_add(1))])
     }
 
     #[inline]
@@ -208,39 +217,116 @@ impl MemoryRW for Cpu {
 
     #[inline]
     fn write8(&mut self, addr: u16, byte: u8) {
-        if self.cpm_compat {
-            self.memory[addr] = byte;
-        } else if !self.cpm_compat && addr < 0x4000 {
-            self.memory.ram[addr as usize] = byte;
-        } else if !self.cpm_compat && addr < 0x5000 {
-            self.memory.ram[addr as usize - 0x4000] = byte;
-        } else if addr == 0x5000 {
-            self.int_pending = true;
-        } else {
-            self.memory.ram[addr as usize] = byte;
+        if self.events.watches(addr) {
+            self.events.push(Event::MemWrite { addr, value: byte });
+        }
+        if let Some(stats) = &mut self.mem_stats {
+            stats.write(addr);
         }
+        if !self.watchpoints.is_empty() {
+            let old = self.memory[addr];
+            self.watchpoints.check(addr, WatchKind::Write, old, byte);
+        }
+        if self.break_on.stack_write {
+            let sp = self.reg.sp;
+            if addr < sp && sp - addr <= self.break_on.stack_window {
+                self.break_on.trigger(BreakEvent::StackWrite { addr, sp });
+            }
+        }
+        if self.memory.hook_write(addr, byte) {
+            return;
+        }
+        if !self.memory.write_mapped(addr, byte) && self.break_on.rom_write != Trap::Off {
+            let pc = self.watchpoints.pc;
+            self.break_on.trigger(BreakEvent::RomWrite {
+                addr,
+                value: byte,
+                pc,
+            });
+        }
+        self.memory.mapped_paging_write(addr, byte);
     }
 }
 
-impl Cpu {
-    pub fn default() -> Self {
+impl Default for Cpu {
+    fn default() -> Self {
         Self {
             opcode: 0,
             next_opcode: 0,
             reg: Registers::default(),
             flags: Flags::new(),
             cycles: 0,
-            current_instruction: String::new(),
             debug: false,
-            breakpoint: false,
-            io: Io::default(),
+            io: IoBus::default(),
             int: Interrupt::default(),
             int_pending: false,
             instruction: Instruction::default(),
             memory: Memory::default(),
             cpm_compat: false,
+            events: EventQueue::default(),
+            watchpoints: Watchpoints::default(),
+            break_on: BreakOn::default(),
+            mem_stats: None,
+            input: InputLog::default(),
+            m1_filter: None,
         }
     }
+}
+
+impl Cpu {
+    // Called for IN instructions on ports without a registered device
+    pub fn set_in_handler<F: FnMut(u16) -> u8 + 'static>(&mut self, handler: F) {
+        self.io.set_in_handler(handler);
+    }
+
+    // Called for OUT instructions on ports without a registered device
+    pub fn set_out_handler<F: FnMut(u16, u8) + 'static>(&mut self, handler: F) {
+        self.io.set_out_handler(handler);
+    }
+
+    // Registers, flags and interrupt state as a JSON object, numbers in decimal:
+    // {"pc":..,"sp":..,"af":..,"bc":..,"de":..,"hl":..,"ix":..,"iy":..,"af_":..,"bc_":..,
+    //  "de_":..,"hl_":..,"i":..,"r":..,"flags":{"s":..,"z":..,"y":..,"h":..,"x":..,"p":..,
+    //  "n":..,"c":..},"im":..,"iff1":..,"iff2":..,"halted":..,"cycles":..}
+    // Keys are only ever added, so scripts reading it keep working.
+    pub fn to_json(&self) -> String {
+        let reg = &self.reg;
+        let pair = |h: u8, l: u8| (h as u16) << 8 | l as u16;
+        let f = &self.flags;
+        format!(
+            "{{\"pc\":{},\"sp\":{},\"af\":{},\"bc\":{},\"de\":{},\"hl\":{},\"ix\":{},\"iy\":{},\
+             \"af_\":{},\"bc_\":{},\"de_\":{},\"hl_\":{},\"i\":{},\"r\":{},\
+             \"flags\":{{\"s\":{},\"z\":{},\"y\":{},\"h\":{},\"x\":{},\"p\":{},\"n\":{},\"c\":{}}},\
+             \"im\":{},\"iff1\":{},\"iff2\":{},\"halted\":{},\"cycles\":{}}}",
+            reg.pc,
+            reg.sp,
+            self.read_pair(AF),
+            self.read_pair(BC),
+            self.read_pair(DE),
+            self.read_pair(HL),
+            reg.ix,
+            reg.iy,
+            pair(reg.a_, f.get_shadow()),
+            pair(reg.b_, reg.c_),
+            pair(reg.d_, reg.e_),
+            pair(reg.h_, reg.l_),
+            reg.i,
+            reg.r,
+            f.sf,
+            f.zf,
+            f.yf,
+            f.hf,
+            f.xf,
+            f.pf,
+            f.nf,
+            f.cf,
+            self.int.mode,
+            self.int.iff1,
+            self.int.iff2,
+            self.int.halt,
+            self.cycles
+        )
+    }
 
     fn read_reg(&self, reg: Register) -> u8 {
         match reg {
@@ -270,11 +356,12 @@ impl Cpu {
                 self.read8(self.reg.iy.wrapping_add(offset as u16))
             }
             _ => {
-                println!(
-                    "Called by:{}, Opcode:{:02X}",
-                    self.current_instruction, self.opcode
-                );
-                panic!("Register not supported:{:#?}", reg)
+                panic!(
+                    "Register not supported:{:#?}, at {:04X}, opcode:{:02X}",
+                    reg,
+                    self.instruction_pc(),
+                    self.opcode
+                )
             }
         }
     }
@@ -294,20 +381,20 @@ impl Cpu {
             HL => self.write8(self.read_pair(HL), value), // HL is only used indexed to memory
             IXH => self.reg.ix = (self.reg.ix & 0x00FF) | ((value as u16) << 8),
             IXL => self.reg.ix = (self.reg.ix & 0xFF00) | value as u16,
-            IYH => self.reg.iy = (self.reg.iy & 0x00FF) | ((value as u16) << 8) as u16,
+            IYH => self.reg.iy = (self.reg.iy & 0x00FF) | ((value as u16) << 8),
             IYL => self.reg.iy = (self.reg.iy & 0xFF00) | value as u16,
             IxIm => {
-                let byte = self.read8(self.reg.pc + 1) as i8;
+                let byte = self.read8(self.reg.pc.wrapping_add(1)) as i8;
                 self.write8(self.reg.ix.wrapping_add(byte as u16), value)
             }
             IyIm => {
-                let byte = self.read8(self.reg.pc + 1) as i8;
+                let byte = self.read8(self.reg.pc.wrapping_add(1)) as i8;
                 self.write8(self.reg.iy.wrapping_add(byte as u16), value)
             }
-            _ => panic!(format!(
-                "Writing to RP: {:#?}, is not supported by write_reg, called by: {}, opcode:{:02X}{:02X}",
-                dst, self.current_instruction, self.opcode, self.next_opcode
-            )),
+            _ => panic!(
+                "Writing to RP: {:#?}, is not supported by write_reg, at {:04X}, opcode:{:02X}{:02X}",
+                dst, self.instruction_pc(), self.opcode, self.next_opcode
+            ),
         }
     }
 
@@ -346,7 +433,7 @@ impl Cpu {
             IX => self.reg.ix,
             IY => self.reg.iy,
             SP => self.reg.sp,
-            AF => ((self.reg.a as u16) << 8 | (self.flags.get() as u16)),
+            AF => (self.reg.a as u16) << 8 | (self.flags.get() as u16),
             _ => panic!(
                 "read_pair() called with reg:{:#?}, opcode:{:02X}{:02X}",
                 reg, self.opcode, self.next_opcode
@@ -367,7 +454,7 @@ impl Cpu {
 
     // Add Immediate to Accumulator with Carry
     pub(crate) fn adc_im(&mut self) {
-        let value = self.read8(self.reg.pc + 1) as u16;
+        let value = self.read8(self.reg.pc.wrapping_add(1)) as u16;
 
         // Add immediate with accumulator + carry flag value
         let carry = self.flags.cf as u8;
@@ -402,7 +489,7 @@ impl Cpu {
             self.adv_cycles(3);
         }
         let result: u16 = (self.reg.a as u16)
-            .wrapping_add(value as u16)
+            .wrapping_add(value)
             .wrapping_add(self.flags.cf as u16);
 
         self.flags.sf = (result & 0x80) != 0;
@@ -435,7 +522,7 @@ impl Cpu {
 
         self.flags.sf = (result & 0x8000) != 0;
         self.flags.zf = (result & 0xFFFF) == 0;
-        self.flags.hf = self.hf_add_w(hl, value as u16, true);
+        self.flags.hf = self.hf_add_w(hl, value, true);
         self.flags.pf =
             (hl & 0x8000) == (value & 0x8000) && (hl & 0x8000) != ((result & 0x8000) as u16);
         self.flags.yf = (result & 0x2000) != 0;
@@ -458,7 +545,7 @@ impl Cpu {
         self.write_pair(HL, result as u16);
 
         self.flags.cf = ((result >> 8) & 0x0100) != 0;
-        self.flags.hf = self.hf_add_w(hl, add as u16, false);
+        self.flags.hf = self.hf_add_w(hl, add, false);
         self.flags.nf = false;
         self.flags.yf = ((result >> 8) & 0x20) != 0;
         self.flags.xf = ((result >> 8) & 0x08) != 0;
@@ -474,7 +561,7 @@ impl Cpu {
         self.write_pair(dst, result as u16);
 
         self.flags.cf = ((result >> 8) & 0x0100) != 0;
-        self.flags.hf = self.hf_add_w(self.read_pair(HL), add as u16, false);
+        self.flags.hf = self.hf_add_w(self.read_pair(HL), add, false);
         self.flags.nf = false;
         self.flags.yf = ((result >> 8) & 0x20) != 0;
         self.flags.xf = ((result >> 8) & 0x08) != 0;
@@ -493,11 +580,11 @@ impl Cpu {
             self.adv_pc(2);
             self.adv_cycles(15);
         }
-        if reg == IXL || reg == IXH || reg == IYL || reg == IYL {
+        if reg == IXL || reg == IXH || reg == IYL || reg == IYH {
             self.adv_cycles(4);
             self.adv_pc(1);
         }
-        let result = (self.reg.a as u16).wrapping_add(value as u16);
+        let result = (self.reg.a as u16).wrapping_add(value);
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
@@ -517,8 +604,8 @@ impl Cpu {
     // Add Immediate to Accumulator
     fn adi(&mut self) {
         // Read next byte of immediate data (low).
-        let value = self.read8(self.reg.pc + 1) as u16;
-        let result = (self.reg.a as u16).wrapping_add(value as u16);
+        let value = self.read8(self.reg.pc.wrapping_add(1)) as u16;
+        let result = (self.reg.a as u16).wrapping_add(value);
 
         // Set CPU flags with new accumulator values
         self.flags.sf = (result & 0x80) != 0;
@@ -558,10 +645,10 @@ impl Cpu {
         self.flags.xf = (result & 0x08) != 0;
         self.flags.nf = false;
         self.flags.hf = true;
-        self.flags.pf = self.parity(result as u8);
+        self.flags.pf = self.parity(result);
         self.flags.cf = false;
 
-        self.reg.a = result as u8;
+        self.reg.a = result;
 
         self.adv_cycles(4);
         self.adv_pc(1);
@@ -569,7 +656,7 @@ impl Cpu {
 
     fn ani(&mut self) {
         // The byte of immediate data is ANDed with the contents of the accumulator
-        let value = self.read8(self.reg.pc + 1);
+        let value = self.read8(self.reg.pc.wrapping_add(1));
         let result = self.reg.a as u16 & value as u16;
 
         self.flags.sf = (result & 0x80) != 0;
@@ -602,7 +689,7 @@ impl Cpu {
         // P/V is set to the same value as Z .
         // S is reset unless the instruction is BIT 7, r, and bit 7 of r is set.
         // Match towards DDCBnn
-        match self.read8(self.reg.pc + 1) {
+        match self.read8(self.reg.pc.wrapping_add(1)) {
             0x78..=0x7D => {
                 if self.reg.r & (1 << 7) != 0 {
                     self.flags.sf = true;
@@ -655,7 +742,7 @@ impl Cpu {
     // "Generic" function for conditional JR operations
     fn jr_cond(&mut self, cond: bool) {
         // E.g if zero flag == 0 { JR + offset
-        let byte = self.read8(self.reg.pc + 1) as i8;
+        let byte = self.read8(self.reg.pc.wrapping_add(1)) as i8;
         if cond {
             self.jr(byte as i16);
         } else {
@@ -671,7 +758,7 @@ impl Cpu {
     fn jp_cond(&mut self, cond: bool) {
         if cond {
             self.reg.prev_pc = self.reg.pc;
-            self.reg.pc = self.read16(self.reg.pc + 1);
+            self.reg.pc = self.read16(self.reg.pc.wrapping_add(1));
         } else {
             self.adv_pc(3);
         }
@@ -682,7 +769,7 @@ impl Cpu {
     fn pchl(&mut self) {
         self.adv_cycles(4);
         self.reg.prev_pc = self.reg.pc;
-        self.reg.pc = self.read_pair(Register::HL) as u16;
+        self.reg.pc = self.read_pair(Register::HL);
     }
 
     #[inline]
@@ -714,7 +801,7 @@ impl Cpu {
                     value = self.read8(self.read_pair(src)) as u16;
                     self.adv_cycles(3);
                 } else if src == IxIm || src == IyIm {
-                    let offset = self.read8(self.reg.pc + 1) as i8;
+                    let offset = self.read8(self.reg.pc.wrapping_add(1)) as i8;
                     self.adv_pc(1);
                     self.adv_cycles(15);
                     let addr: u16 = if src == IxIm {
@@ -754,7 +841,7 @@ impl Cpu {
             IxIm | IyIm => {
                 self.adv_pc(1);
                 // displacement
-                let offset = self.read8(self.reg.pc + 1) as i8;
+                let offset = self.read8(self.reg.pc.wrapping_add(1)) as i8;
                 // base address
                 let value = match dst {
                     IxIm => self.reg.ix.wrapping_add(offset as u16),
@@ -802,8 +889,8 @@ impl Cpu {
             self.reg.pc = self.reg.pc.wrapping_sub(2);
             self.adv_cycles(5);
         }
-        if self.read_pair(BC) <= 0 {
-            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) as u8 & 0x7f);
+        if self.read_pair(BC) == 0 {
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) & 0x7f);
         }
     }
     // Same as LDI but HL & DE are also decremented
@@ -819,8 +906,66 @@ impl Cpu {
             self.reg.pc = self.reg.pc.wrapping_sub(2);
             self.adv_cycles(5);
         }
-        if self.read_pair(BC) <= 0 {
-            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) as u8 & 0x7f);
+        if self.read_pair(BC) == 0 {
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) & 0x7f);
+        }
+    }
+
+    // INI / IND: reads the port at BC into (HL), steps HL by `step` and decrements B
+    fn ini(&mut self, step: i8) {
+        let port = self.read_pair(BC);
+        self.events.push(Event::IoRead { port });
+        let value = self.port_read(port);
+        let hl = self.read_pair(HL);
+        self.write8(hl, value);
+        self.write_pair(HL, hl.wrapping_add(step as u16));
+        self.reg.b = self.reg.b.wrapping_sub(1);
+        self.block_io_flags(value, self.reg.c.wrapping_add(step as u8));
+        self.adv_cycles(16);
+        self.adv_pc(2);
+    }
+    fn inir(&mut self, step: i8) {
+        self.ini(step);
+        self.repeat_block_io();
+    }
+
+    // OUTI / OUTD: decrements B, then writes (HL) to the port at BC and steps HL by `step`
+    fn outi(&mut self, step: i8) {
+        self.reg.b = self.reg.b.wrapping_sub(1);
+        let hl = self.read_pair(HL);
+        let value = self.read8(hl);
+        self.port_write(self.read_pair(BC), value);
+        self.write_pair(HL, hl.wrapping_add(step as u16));
+        self.block_io_flags(value, self.reg.l);
+        self.adv_cycles(16);
+        self.adv_pc(2);
+    }
+    fn otir(&mut self, step: i8) {
+        self.outi(step);
+        self.repeat_block_io();
+    }
+
+    // S, Z, Y and X come from B, N is bit 7 of the byte moved. H, C and P come from adding
+    // the byte to C after its step (INI/IND) or to L after HL's (OUTI/OUTD).
+    fn block_io_flags(&mut self, value: u8, offset: u8) {
+        let k = value as u16 + offset as u16;
+        let b = self.reg.b;
+        self.flags.sf = (b & 0x80) != 0;
+        self.flags.zf = b == 0;
+        self.flags.yf = (b & 0x20) != 0;
+        self.flags.xf = (b & 0x08) != 0;
+        self.flags.nf = (value & 0x80) != 0;
+        self.flags.hf = k > 0xFF;
+        self.flags.cf = k > 0xFF;
+        self.flags.pf = self.parity((k as u8 & 0x07) ^ b);
+    }
+
+    // The repeating forms run again until B is 0
+    fn repeat_block_io(&mut self) {
+        if self.reg.b != 0 {
+            self.reg.prev_pc = self.reg.pc;
+            self.reg.pc = self.reg.pc.wrapping_sub(2);
+            self.adv_cycles(5);
         }
     }
 
@@ -830,9 +975,9 @@ impl Cpu {
     // TODO & LOAD INDIRECT BUG?
     fn ld_mem_nn_rp(&mut self, reg: Register) {
         let ptr = if reg == HL {
-            self.read16(self.reg.pc + 1)
+            self.read16(self.reg.pc.wrapping_add(1))
         } else {
-            self.read16(self.reg.pc + 2)
+            self.read16(self.reg.pc.wrapping_add(2))
         };
         self.write16(ptr, self.read_pair(reg));
         if reg == HL {
@@ -865,7 +1010,7 @@ impl Cpu {
             self.adv_cycles(4);
             self.adv_pc(1);
         }
-        self.write_pair(reg, self.read16(self.reg.pc + 1));
+        self.write_pair(reg, self.read16(self.reg.pc.wrapping_add(1)));
 
         self.adv_cycles(10);
         self.adv_pc(3);
@@ -874,7 +1019,7 @@ impl Cpu {
     // LD **, A
     // Store Accumulator direct
     fn ld_nn_r(&mut self) {
-        let imm = self.read16(self.reg.pc + 1);
+        let imm = self.read16(self.reg.pc.wrapping_add(1));
         self.adv_pc(3);
         self.write8(imm, self.reg.a);
         self.adv_cycles(13);
@@ -892,7 +1037,7 @@ impl Cpu {
         self.write16(self.reg.sp, ret);
         match addr {
             0xCC | 0xCD | 0xC4 | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC | 0x66 => {
-                self.reg.pc = self.read16(self.reg.pc + 1);
+                self.reg.pc = self.read16(self.reg.pc.wrapping_add(1));
             }
             _ => {
                 // println!("CALL to address:{:04X}", addr);
@@ -938,7 +1083,7 @@ impl Cpu {
         } else if reg == HL {
             self.adv_cycles(3);
         }
-        if reg == IXL || reg == IXH || reg == IYL || reg == IYL {
+        if reg == IXL || reg == IXH || reg == IYL || reg == IYH {
             self.adv_cycles(4);
             self.adv_pc(1);
         }
@@ -947,7 +1092,7 @@ impl Cpu {
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
-        self.flags.hf = self.hf_sub(self.reg.a, value as u8, false);
+        self.flags.hf = self.hf_sub(self.reg.a, value, false);
         self.flags.nf = true;
         // The XF & YF flags use the non compared value
         self.flags.yf = (value & 0x20) != 0;
@@ -962,14 +1107,13 @@ impl Cpu {
     // TODO Use addressing modes here
     // Compare Immediate with Accumulator
     fn cp_im(&mut self) {
-        let value = self.read8(self.reg.pc + 1);
+        let value = self.read8(self.reg.pc.wrapping_add(1));
         let result = (self.reg.a as i16).wrapping_sub(value as i16);
-        let overflow = (self.reg.a as i8).overflowing_sub(value as i8).1;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
         self.flags.yf = (value & 0x20) != 0;
-        self.flags.hf = self.hf_sub(self.reg.a, value as u8, false);
+        self.flags.hf = self.hf_sub(self.reg.a, value, false);
         self.flags.xf = (value & 0x08) != 0;
         self.flags.pf = self.overflow_sub(self.reg.a, value, result as u8);
         // self.flags.pf = overflow;
@@ -1005,8 +1149,8 @@ impl Cpu {
             self.reg.pc = self.reg.pc.wrapping_sub(2);
             self.adv_cycles(5);
         }
-        if self.read_pair(BC) <= 0 {
-            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) as u8 & 0x7f);
+        if self.read_pair(BC) == 0 {
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) & 0x7f);
         }
     }
     // Extended instruction
@@ -1109,16 +1253,37 @@ impl Cpu {
     // EI & DI instructions
     fn interrupt(&mut self, value: bool) {
         self.int.int = value;
-        if value {
-            self.int.irq = true;
-        } else if !value {
-            self.int.iff1 = false;
-            self.int.iff2 = false;
-        }
+        self.int.iff1 = value;
+        self.int.iff2 = value;
+        self.int.ei_pending = value;
         self.adv_cycles(4);
         self.adv_pc(1);
     }
 
+    // Asserts /INT, `vector` is the value the device places on the data bus.
+    // The request stays pending until it is accepted or cleared.
+    pub fn int_request(&mut self, vector: u8) {
+        self.int.irq = true;
+        self.int.vector = vector;
+    }
+
+    pub fn int_clear(&mut self) {
+        self.int.irq = false;
+    }
+
+    // Raises a non maskable interrupt, serviced after the current instruction
+    pub fn nmi(&mut self) {
+        self.int.nmi_pending = true;
+    }
+
+    // Pushes the current PC and jumps to the interrupt routine
+    fn interrupt_call(&mut self, addr: u16) {
+        self.reg.sp = self.reg.sp.wrapping_sub(2);
+        self.write16(self.reg.sp, self.reg.pc);
+        self.reg.prev_pc = self.reg.pc;
+        self.reg.pc = addr;
+    }
+
     // Rotate Accumulator Left Through Carry
     fn rla(&mut self) {
         // The contents of the accumulator are rotated one bit position to the left.
@@ -1166,7 +1331,7 @@ impl Cpu {
         self.flags.hf = false;
         self.flags.yf = (a & 0x20) != 0;
         self.flags.xf = (a & 0x08) != 0;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         self.adv_pc(2);
         self.adv_cycles(18);
     }
@@ -1204,7 +1369,7 @@ impl Cpu {
         self.flags.yf = (value & 0x20) != 0;
         self.flags.xf = (value & 0x08) != 0;
         self.flags.cf = (value & 0x80) != 0;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         self.adv_pc(2);
         self.adv_cycles(8);
         if reg == HL {
@@ -1222,7 +1387,7 @@ impl Cpu {
         self.flags.yf = (value & 0x20) != 0;
         self.flags.xf = (value & 0x08) != 0;
         self.flags.cf = (value & 0x80) != 0;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         self.adv_pc(2);
         self.adv_cycles(8);
         if reg == HL {
@@ -1328,7 +1493,7 @@ impl Cpu {
         self.flags.xf = (value & 0x08) != 0;
         self.flags.nf = false;
         self.flags.hf = false;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         if reg == HL {
             self.adv_cycles(7);
         }
@@ -1352,7 +1517,7 @@ impl Cpu {
         self.flags.xf = (value & 0x08) != 0;
         self.flags.nf = false;
         self.flags.hf = false;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         if reg == HL {
             self.adv_cycles(7);
         }
@@ -1374,7 +1539,7 @@ impl Cpu {
         self.flags.xf = (value & 0x08) != 0;
         self.flags.nf = false;
         self.flags.hf = false;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         if reg == HL {
             self.adv_cycles(7);
         }
@@ -1395,7 +1560,7 @@ impl Cpu {
         self.flags.xf = (value & 0x08) != 0;
         self.flags.nf = false;
         self.flags.hf = false;
-        self.flags.pf = self.parity(value as u8);
+        self.flags.pf = self.parity(value);
         if reg == HL {
             self.adv_cycles(7);
         }
@@ -1418,33 +1583,33 @@ impl Cpu {
     fn mvi(&mut self, reg: Register) {
         // The MVI instruction uses a 8-bit data quantity, as opposed to
         // LXI which uses a 16-bit data quantity.
-        // let value = self.read8(self.reg.pc + 1);
+        // let value = self.read8(self.reg.pc.wrapping_add(1));
         match reg {
             IXH | IXL | IYL | IYH => {
                 self.adv_cycles(4);
                 self.adv_pc(1);
-                self.write_reg(reg, self.read8(self.reg.pc + 1));
+                self.write_reg(reg, self.read8(self.reg.pc.wrapping_add(1)));
             }
             IyIm | IxIm => {
                 // First increment of PC should be automatic with the read or something..
                 // Second increment is OK, we can
                 self.adv_pc(1);
-                let offset = self.read8(self.reg.pc + 1) as i8;
+                let offset = self.read8(self.reg.pc.wrapping_add(1)) as i8;
                 let addr = if reg == IxIm {
                     self.reg.ix.wrapping_add(offset as u16)
                 } else {
                     self.reg.iy.wrapping_add(offset as u16)
                 };
-                self.write8(addr, self.read8(self.reg.pc + 2));
+                self.write8(addr, self.read8(self.reg.pc.wrapping_add(2)));
                 self.adv_cycles(12);
                 self.adv_pc(1);
             }
             HL => {
                 self.adv_cycles(3);
                 let hl = self.read_pair(HL);
-                self.write8(hl, self.read8(self.reg.pc + 1));
+                self.write8(hl, self.read8(self.reg.pc.wrapping_add(1)));
             }
-            _ => self.write_reg(reg, self.read8(self.reg.pc + 1)),
+            _ => self.write_reg(reg, self.read8(self.reg.pc.wrapping_add(1))),
         }
 
         self.adv_cycles(7);
@@ -1453,7 +1618,7 @@ impl Cpu {
 
     // LD A, (**)
     fn ld_r_mem_nn(&mut self) {
-        let addr = self.read16(self.reg.pc + 1);
+        let addr = self.read16(self.reg.pc.wrapping_add(1));
         self.reg.a = self.read8(addr);
         self.adv_cycles(13);
         self.adv_pc(3);
@@ -1463,11 +1628,11 @@ impl Cpu {
     fn lhld(&mut self, reg: Register) {
         // Load the HL register with 16 bits found at addr & addr + 1
         let addr: u16 = if reg == HL {
-            self.read16(self.reg.pc + 1)
+            self.read16(self.reg.pc.wrapping_add(1))
         } else {
-            self.read16(self.reg.pc + 2)
+            self.read16(self.reg.pc.wrapping_add(2))
         };
-        self.write_pair(reg, self.read16(addr) as u16);
+        self.write_pair(reg, self.read16(addr));
         self.adv_pc(3);
         if reg == IX || reg == IY {
             self.adv_pc(1);
@@ -1509,7 +1674,7 @@ impl Cpu {
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = result == 0;
         self.flags.hf = self.hf_add(value, 1, false);
-        self.flags.pf = self.overflow_add(value, 1, result as u8);
+        self.flags.pf = self.overflow_add(value, 1, result);
         self.flags.nf = false;
         self.flags.yf = (result & 0x20) != 0;
         self.flags.xf = (result & 0x08) != 0;
@@ -1604,14 +1769,13 @@ impl Cpu {
     // TODO: SBI & SUI can be consolidated to one function
     // Subtract Immediate with Borrow
     fn sbi(&mut self) {
-        let imm = self.read8(self.reg.pc + 1);
+        let imm = self.read8(self.reg.pc.wrapping_add(1));
         let value = imm + self.flags.cf as u8;
         let result = (self.reg.a as u16).wrapping_sub(value as u16);
-        let overflow = (self.reg.a as i8).overflowing_sub(value as i8).1;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
-        self.flags.hf = self.hf_sub(self.reg.a, value as u8, false);
+        self.flags.hf = self.hf_sub(self.reg.a, value, false);
         // self.flags.pf = overflow;
         self.flags.pf = self.overflow_sub(imm, value, result as u8);
         self.flags.yf = (result & 0x20) != 0;
@@ -1642,15 +1806,15 @@ impl Cpu {
         let (result, overflow) = (self.reg.a).overflowing_sub(value);
 
         self.flags.sf = (result & 0x80) != 0;
-        self.flags.zf = (result & 0xFF) == 0;
+        self.flags.zf = result == 0;
         self.flags.hf = self.hf_sub(self.reg.a, value, false);
-        self.flags.pf = self.overflow_sub(self.reg.a, value, result as u8);
+        self.flags.pf = self.overflow_sub(self.reg.a, value, result);
         self.flags.nf = true;
         self.flags.yf = (result & 0x20) != 0;
         self.flags.xf = (result & 0x08) != 0;
         // self.flags.cf = (result & 0x0100) != 0;
         self.flags.cf = overflow;
-        self.reg.a = result as u8;
+        self.reg.a = result;
 
         self.adv_cycles(4);
         self.adv_pc(1);
@@ -1658,13 +1822,12 @@ impl Cpu {
 
     // SUI Subtract Immediate From Accumulator
     fn sui(&mut self) {
-        let value = self.read8(self.reg.pc + 1);
+        let value = self.read8(self.reg.pc.wrapping_add(1));
         let result = (self.reg.a as u16).wrapping_sub(value as u16);
-        let overflow = (self.reg.a as i8).overflowing_sub(value as i8).1;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
-        self.flags.hf = self.hf_sub(self.reg.a, value as u8, false);
+        self.flags.hf = self.hf_sub(self.reg.a, value, false);
         self.flags.pf = self.overflow_sub(self.reg.a, value, result as u8);
         self.flags.nf = true;
         self.flags.yf = (result & 0x20) != 0;
@@ -1697,7 +1860,7 @@ impl Cpu {
             self.adv_pc(15);
         }
 
-        if reg == IXL || reg == IXH || reg == IYL || reg == IYL {
+        if reg == IXL || reg == IXH || reg == IYL || reg == IYH {
             self.adv_cycles(4);
             self.adv_pc(1);
         }
@@ -1706,13 +1869,13 @@ impl Cpu {
         // Issue here is the value of memory[HL] is wrong?
         // in Zazu's emulator the value passed to XOR is 0xe5 with a result of 0x00db
         self.flags.sf = (result & 0x80) != 0;
-        self.flags.zf = (result & 0xFF) == 0;
+        self.flags.zf = result == 0;
         self.flags.hf = false;
         self.flags.nf = false;
         self.flags.yf = (result & 0x20) != 0;
         self.flags.xf = (result & 0x08) != 0;
         self.flags.cf = false;
-        self.flags.pf = self.parity(result as u8);
+        self.flags.pf = self.parity(result);
         self.reg.a = result;
         self.adv_cycles(4);
         self.adv_pc(1);
@@ -1720,8 +1883,8 @@ impl Cpu {
 
     // XRI Exclusive-Or Immediate with Accumulator
     fn xri(&mut self) {
-        let imm = self.read8(self.reg.pc + 1);
-        let result: u8 = self.reg.a ^ imm as u8;
+        let imm = self.read8(self.reg.pc.wrapping_add(1));
+        let result: u8 = self.reg.a ^ imm;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = result == 0;
@@ -1780,7 +1943,7 @@ impl Cpu {
 
     fn xthl(&mut self) {
         // Swap HL with top word in stack
-        let hl = self.read_pair(Register::HL) as u16;
+        let hl = self.read_pair(Register::HL);
         let new_hl = self.read16(self.reg.sp);
         // Write old HL values to memory
         self.write16(self.reg.sp, hl);
@@ -1802,58 +1965,110 @@ impl Cpu {
         self.adv_cycles(10);
     }
 
-    fn ret(&mut self) {
-        let low = self.memory[self.reg.sp];
-        let high = self.memory[self.reg.sp.wrapping_add(1)];
+    pub(crate) fn ret(&mut self) {
+        let low = self.read8(self.reg.sp);
+        let high = self.read8(self.reg.sp.wrapping_add(1));
         let ret: u16 = (high as u16) << 8 | (low as u16);
         // Set program counter for debug output
         self.reg.prev_pc = self.reg.pc;
-        self.reg.pc = ret as u16;
+        self.reg.pc = ret;
         self.reg.sp = self.reg.sp.wrapping_add(2);
         self.adv_cycles(10);
     }
 
+    // Return from interrupt, RETN also restores IFF1 from IFF2
+    fn reti(&mut self, nmi: bool) {
+        if nmi {
+            self.int.iff1 = self.int.iff2;
+        }
+        self.ret();
+        self.adv_cycles(4);
+        self.int.reti |= !nmi;
+        self.events.push(Event::RetiExecuted);
+        self.break_on.trigger(BreakEvent::Reti { nmi });
+    }
+
+    // Paging registers see every port write, devices on the same port still get it as well
+    fn port_write(&mut self, port: u16, value: u8) {
+        self.events.push(Event::IoWrite { port, value });
+        self.memory.paging_write(port, value);
+        self.io.write(port, value);
+    }
+
     // Extended opcode
     fn in_c(&mut self, reg: Register) {
-        self.write_reg(reg, self.reg.c);
-        self.flags.zf = self.read_reg(reg) == 0;
+        let value = self.in_f();
+        self.write_reg(reg, value);
+    }
+    // IN (C), sets the flags like IN r,(C) but doesn't store the value
+    fn in_f(&mut self) -> u8 {
+        // BC is placed on the address bus
+        let port = self.read_pair(BC);
+        self.events.push(Event::IoRead { port });
+        let value = self.port_read(port);
+        self.flags.sf = (value & 0x80) != 0;
+        self.flags.zf = value == 0;
+        self.flags.yf = (value & 0x20) != 0;
         self.flags.hf = false;
+        self.flags.xf = (value & 0x08) != 0;
         self.flags.nf = false;
-        self.flags.pf = self.parity(self.read_reg(reg));
+        self.flags.pf = self.parity(value);
         self.adv_cycles(12);
         self.adv_pc(2);
+        value
     }
     fn in_a(&mut self) {
-        self.io.port = self.read8(self.reg.pc + 1);
-        self.reg.a = 0xFF; // TODO: hack (other emu's do this for zexdoc??)
-                           // self.reg.a = self.io.port;
+        // A is placed on A8-A15
+        let port = (self.reg.a as u16) << 8 | self.read8(self.reg.pc.wrapping_add(1)) as u16;
+        self.events.push(Event::IoRead { port });
+        self.reg.a = self.port_read(port);
         self.adv_cycles(11);
         self.adv_pc(2);
     }
 
+    fn port_read(&mut self, port: u16) -> u8 {
+        if !self.input.active() {
+            return self.io.read(port);
+        }
+        let io = &mut self.io;
+        self.input.port(self.cycles as u64, port, || io.read(port))
+    }
+
     fn out(&mut self, reg: Register) {
-        // Set port:
-        let port = self.read8(self.reg.pc + 1);
-        // println!("Out port: {:02x}, value: {:02x}", port, self.read_reg(reg));
-        self.io.value = self.read_reg(reg);
-        self.io.port = port;
+        let value = self.read_reg(reg);
+        let port = (self.reg.a as u16) << 8 | self.read8(self.reg.pc.wrapping_add(1)) as u16;
+        self.port_write(port, value);
         self.adv_cycles(11);
         self.adv_pc(2);
     }
+
+    // OUT (C), r
+    fn out_c(&mut self, reg: Register) {
+        let value = self.read_reg(reg);
+        self.out_c_value(value);
+    }
+    // OUT (C), 0 is OUT (C), r with the register field pointing at (HL), an NMOS Z80 puts 0
+    // on the data bus for it
+    fn out_c_value(&mut self, value: u8) {
+        let port = self.read_pair(BC);
+        self.port_write(port, value);
+        self.adv_cycles(12);
+        self.adv_pc(2);
+    }
     // TODO: Consolidate ORA & ORI (pass value directly)
     fn ora(&mut self, reg: Register) {
         let value = if reg != HL {
             self.read_reg(reg) as u16
         } else {
             self.adv_cycles(3);
-            self.memory[self.read_pair(HL)] as u16
+            self.read8(self.read_pair(HL)) as u16
         };
 
         if reg == IxIm || reg == IyIm {
             self.adv_pc(2);
             self.adv_cycles(15);
         }
-        let result = self.reg.a as u16 | value as u16;
+        let result = self.reg.a as u16 | value;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
@@ -1871,7 +2086,7 @@ impl Cpu {
 
     // Or Immediate with Accumulator
     fn ori(&mut self) {
-        let result = self.reg.a as u16 | self.read8(self.reg.pc + 1) as u16;
+        let result = self.reg.a as u16 | self.read8(self.reg.pc.wrapping_add(1)) as u16;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
@@ -1890,10 +2105,10 @@ impl Cpu {
     // RESET (used for interrupt jump / calls)
     pub fn rst(&mut self, value: u16) {
         // Address to return to after interrupt is finished.
-        let ret: u16 = self.reg.pc.wrapping_add(3);
-        self.memory[self.reg.sp.wrapping_sub(1)] = (ret >> 8) as u8;
-        self.memory[self.reg.sp.wrapping_sub(2)] = ret as u8;
+        let ret: u16 = self.reg.pc.wrapping_add(1);
         self.reg.sp = self.reg.sp.wrapping_sub(2);
+        self.write16(self.reg.sp, ret);
+        self.break_on.trigger(BreakEvent::Rst(value));
         self.reg.prev_pc = self.reg.pc;
         self.adv_pc(1);
         self.reg.pc = value;
@@ -1908,7 +2123,7 @@ impl Cpu {
 
     // Store H & L direct
     fn shld(&mut self, reg: Register) {
-        let ptr = self.read16(self.reg.pc + 1);
+        let ptr = self.read16(self.reg.pc.wrapping_add(1));
         self.write16(ptr, self.read_pair(reg));
         self.adv_cycles(16);
         self.adv_pc(3);
@@ -1921,11 +2136,10 @@ impl Cpu {
     fn neg(&mut self) {
         let value = self.reg.a;
         let result = 0_u16.wrapping_sub(value as u16);
-        let overflow = 0_i8.overflowing_sub(value as i8).1;
 
         self.flags.sf = (result & 0x80) != 0;
         self.flags.zf = (result & 0xFF) == 0;
-        self.flags.hf = self.hf_sub(self.reg.a, value as u8, false);
+        self.flags.hf = self.hf_sub(self.reg.a, value, false);
         // self.flags.pf = overflow;
         self.flags.pf = self.overflow_sub(
             self.reg.a,
@@ -1942,14 +2156,65 @@ impl Cpu {
     }
 
     pub fn execute(&mut self) {
+        self.watchpoints.pc = self.reg.pc;
+        if self.break_on.unmapped_exec != Trap::Off
+            && self.memory.region(self.reg.pc).1 == Region::Unmapped
+        {
+            self.break_on.trigger(BreakEvent::UnmappedExec(self.reg.pc));
+        }
+        // A halted CPU keeps running NOPs (refreshing memory) without moving PC off the
+        // instruction after the HALT until an interrupt or NMI is taken
+        if self.int.halt {
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(1) & 0x7f);
+            self.adv_cycles(4);
+            return;
+        }
         self.fetch();
+        if let Some(filter) = self.m1_filter {
+            self.opcode = filter(self.reg.pc, self.opcode as u8) as u16;
+        }
         self.decode(self.opcode);
     }
 
+    // Reports SP leaving `break_on.stack_bounds` as a break and error event, once per
+    // excursion. `pc` is the instruction (or interrupt) that moved SP.
+    pub(crate) fn check_stack_bounds(&mut self, pc: u16) {
+        let sp = self.reg.sp;
+        let outside = match &self.break_on.stack_bounds {
+            Some(bounds) => !bounds.contains(&sp),
+            None => return,
+        };
+        if outside && !self.break_on.outside_stack {
+            self.events.push(Event::StackBounds { sp, pc });
+            self.break_on.trigger(BreakEvent::StackBounds { sp, pc });
+        }
+        self.break_on.outside_stack = outside;
+    }
+
+    // Address of the instruction currently (or last) being executed
+    pub fn instruction_pc(&self) -> u16 {
+        self.watchpoints.pc
+    }
+
     #[inline]
     pub(crate) fn fetch(&mut self) {
-        self.opcode = self.read8(self.reg.pc) as u16;
-        self.next_opcode = self.read8(self.reg.pc.wrapping_add(1)) as u16;
+        self.opcode = self.fetch8(self.reg.pc) as u16;
+        self.next_opcode = self.fetch8(self.reg.pc.wrapping_add(1)) as u16;
+    }
+
+    // Memory read that isn't reported to watchpoints
+    #[inline]
+    fn fetch8(&self, addr: u16) -> u8 {
+        if let Some(byte) = self.memory.hook_read(addr) {
+            return byte;
+        }
+        if self.input.active() && self.memory.is_device(addr) {
+            let cycles = self.cycles as u64;
+            return self
+                .input
+                .mem(cycles, addr, || self.memory.read_mapped(addr));
+        }
+        self.memory.read_mapped(addr)
     }
 
     #[inline]
@@ -1984,7 +2249,7 @@ impl Cpu {
             0x15 => self.dec(D),
             0x16 => self.mvi(D),
             0x17 => self.rla(),
-            0x18 => self.jr(self.read8(self.reg.pc) as i16),
+            0x18 => self.jr_cond(true),
             0x19 => self.add_hl(DE),
 
             0x1A => self.ld(A, DE),
@@ -2495,7 +2760,7 @@ impl Cpu {
             0xDC => self.call_cond(0xDC, self.flags.cf),
             0xDD => {
                 self.reg.r = (self.reg.r & 0x80) | self.reg.r.wrapping_add(1) & 0x7f;
-                match self.read8(self.reg.pc + 1) {
+                match self.read8(self.reg.pc.wrapping_add(1)) {
                     0x09 => self.add_rp(IX, BC),
                     0x19 => self.add_rp(IX, DE),
                     0x21 => self.ld_rp_nn(IX),
@@ -2557,7 +2822,7 @@ impl Cpu {
 
                     0x7E => {
                         // byte is the signed displacement byte
-                        let byte = self.read8(self.reg.pc + 2) as i8;
+                        let byte = self.read8(self.reg.pc.wrapping_add(2)) as i8;
                         let addr = self.reg.ix.wrapping_add(byte as u16);
                         self.reg.a = self.read8(addr) as i8 as u8;
                         self.adv_pc(3);
@@ -2590,7 +2855,7 @@ impl Cpu {
                     // DDCB
                     0xCB => {
                         // self.next_opcode = self.read8(self.reg.pc.wrapping_add(1)) as u16;
-                        match self.read8(self.reg.pc + 2) {
+                        match self.read8(self.reg.pc.wrapping_add(2)) {
                             0x00 => self.rlc(B),
                             0x01 => self.rlc(C),
                             0x02 => self.rlc(D),
@@ -2602,7 +2867,7 @@ impl Cpu {
                                 "DDCB instruction: Opcode:{:02X}{:02X}{:02X}",
                                 self.opcode,
                                 self.next_opcode,
-                                self.read8(self.reg.pc + 2)
+                                self.read8(self.reg.pc.wrapping_add(2))
                             ),
                         }
                     }
@@ -2636,7 +2901,23 @@ impl Cpu {
             0xED => {
                 self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(1)) & 0x7f;
                 match self.next_opcode {
-                    0x08 => self.in_c(C),
+                    0x40 => self.in_c(B),
+                    0x41 => self.out_c(B),
+                    0x48 => self.in_c(C),
+                    0x49 => self.out_c(C),
+                    0x51 => self.out_c(D),
+                    0x58 => self.in_c(E),
+                    0x59 => self.out_c(E),
+                    0x60 => self.in_c(H),
+                    0x61 => self.out_c(H),
+                    0x68 => self.in_c(L),
+                    0x69 => self.out_c(L),
+                    0x78 => self.in_c(A),
+                    0x79 => self.out_c(A),
+                    0x70 => {
+                        self.in_f();
+                    }
+                    0x71 => self.out_c_value(0),
 
                     0x42 => self.sbc_hl(BC),
                     0x43 => self.ld_mem_nn_rp(BC),
@@ -2645,7 +2926,8 @@ impl Cpu {
                     0x47 => self.ld(I, A),
                     0x4A => self.adc_hl(BC),
                     0x4B => self.ld_rp_mem_nn(BC),
-                    0x4D => unimplemented!("RETI"),
+                    0x45 => self.reti(true),
+                    0x4D => self.reti(false),
                     0x4F => self.ld(R, A),
                     0x50 => self.in_c(D),
                     0x52 => self.sbc_hl(DE),
@@ -2658,7 +2940,8 @@ impl Cpu {
                     0x5F => self.ld(A, R),
                     0x5A => self.adc_hl(DE),
                     0x5B => self.ld_rp_mem_nn(DE),
-                    0x5D => unimplemented!("RETN"),
+                    0x55 => self.reti(true),
+                    0x5D => self.reti(true),
                     0x62 => self.sbc_hl(HL),
                     0x63 => self.ld_mem_nn_rp(HL),
                     0x64 => self.neg(),
@@ -2667,7 +2950,8 @@ impl Cpu {
                     0x6A => self.adc_hl(HL),
                     0x6B => self.ld_rp_mem_nn(HL),
                     0x6C => self.neg(),
-                    0x6D => unimplemented!("RETN"),
+                    0x65 => self.reti(true),
+                    0x6D => self.reti(true),
                     0x6E => self.set_interrupt_mode(1), // IM 0/1
                     0x6F => self.rld(),
                     0x72 => self.sbc_hl(SP),
@@ -2677,20 +2961,25 @@ impl Cpu {
                     0x7A => self.adc_hl(SP),
                     0x7B => self.ld_rp_mem_nn(SP),
                     0x7C => self.neg(),
-                    0x7D => unimplemented!("RETN"),
+                    0x75 => self.reti(true),
+                    0x7D => self.reti(true),
                     0x7E => self.set_interrupt_mode(2),
                     0xA0 => self.ldi(),
                     0xA1 => self.cpi(),
                     0xA8 => self.ldd(),
                     0xA9 => self.cpd(),
-                    0xAA => unimplemented!("IND"),
-                    0xAB => unimplemented!("OUTD"),
+                    0xA2 => self.ini(1),
+                    0xA3 => self.outi(1),
+                    0xAA => self.ini(-1),
+                    0xAB => self.outi(-1),
                     0xB0 => self.ldir(),
                     0xB8 => self.lddr(),
                     0xB1 => self.cpir(),
                     0xB9 => self.cpdr(),
-                    0xBA => unimplemented!("INDR"),
-                    0xBB => unimplemented!("OUTDR"),
+                    0xB2 => self.inir(1),
+                    0xB3 => self.otir(1),
+                    0xBA => self.inir(-1),
+                    0xBB => self.otir(-1),
                     _ => unimplemented!(
                         "Unimplemented ED instruction:{:02X}{:02X}",
                         self.opcode,
@@ -2774,7 +3063,7 @@ impl Cpu {
                     0x77 => self.ld(IyIm, A),
                     0x7E => {
                         // byte is the signed displacement byte
-                        let byte = self.read8(self.reg.pc + 2) as i8;
+                        let byte = self.read8(self.reg.pc.wrapping_add(2)) as i8;
                         let addr = self.reg.iy.wrapping_add(byte as u16);
                         self.reg.a = self.read8(addr) as i8 as u8;
                         self.adv_pc(3);
@@ -2811,7 +3100,7 @@ impl Cpu {
                     0xBD => self.cp(IYH),
                     0xBE => self.cp(IyIm),
                     0xCB => {
-                        let next_opcode = self.read8(self.reg.pc + 2);
+                        let next_opcode = self.read8(self.reg.pc.wrapping_add(2));
                         match next_opcode {
                             0x00 => self.rlc_ex(IyIm, B),
                             0x01 => self.rlc_ex(IyIm, C),
@@ -2837,7 +3126,7 @@ impl Cpu {
             }
             0xFE => self.cp_im(),
             0xFF => self.rst(0x0038),
-            _ => panic!("Unknown or unimplemented instruction:{:#?}"), // Instruction::decode(self)
+            _ => panic!("Unknown or unimplemented instruction: {:02X}", opcode), // Instruction::decode(self)
         }
     }
 
@@ -2862,6 +3151,7 @@ impl Cpu {
     // http://www.z80.info/z80syntx.htm#HALT
     fn halt(&mut self) {
         self.int.halt = true;
+        self.events.push(Event::Halt);
         // self.int.nmi_pending = true; // We're pending on an interrupt, finish this instruction first
         self.adv_cycles(4);
         self.nop();
@@ -2930,65 +3220,88 @@ impl Cpu {
     }
 
     pub(crate) fn poll_interrupt(&mut self) {
+        let cycles = self.cycles as u64;
+        let ei = std::mem::take(&mut self.int.ei_pending);
+        // A replay takes the interrupts accepted in the recording instead of the devices'
+        let replaying = self.input.replaying();
+        if replaying {
+            self.int.irq = false;
+            self.int.nmi_pending = false;
+            match self.input.take_interrupt(cycles) {
+                Some(Input::Nmi) => self.int.nmi_pending = true,
+                Some(Input::Int(vector)) => self.int_request(vector),
+                _ => {}
+            }
+        }
         // Accepting an NMI
         if self.int.nmi_pending {
+            if !replaying {
+                self.input.record(cycles, Input::Nmi);
+            }
             self.int.nmi_pending = false;
             self.int.iff1 = false;
             self.int.halt = false;
-            self.reg.r = self.reg.r.wrapping_add(1);
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(1) & 0x7f);
+            self.break_on.trigger(BreakEvent::Interrupt {
+                nmi: true,
+                vector: 0,
+            });
             self.adv_cycles(11);
-            self.rst(0x66);
+            self.interrupt_call(0x66);
             return;
         }
-        if (self.int.nmi_pending || self.int.irq) || self.int.iff1 {
+        if self.int.irq && self.int.iff1 && !ei {
+            if !replaying {
+                self.input.record(cycles, Input::Int(self.int.vector));
+            }
             self.int_pending = false;
+            self.int.irq = false;
             self.int.halt = false;
             self.int.iff1 = false;
             self.int.iff2 = false;
-            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(0) as u8 & 0x7f);
+            self.int.acknowledged = true;
+            self.reg.r = (self.reg.r & 0x80) | (self.reg.r.wrapping_add(1) & 0x7f);
+            self.events.push(Event::InterruptAck {
+                vector: self.int.vector,
+            });
+            self.break_on.trigger(BreakEvent::Interrupt {
+                nmi: false,
+                vector: self.int.vector,
+            });
 
             // Interrupt Mode 0 is the 8080 compatibility mode
             // Most commonly the instruction executed on the bus is RST,
             // but it can be any instruction (technically)
             // The I register is not used for IM0
-            // TODO investigate interrupt processing
             match self.int.mode {
                 0 => {
-                    if self.int.vector != 0 || self.io.input {
-                        self.adv_cycles(11);
-                        if self.debug {
-                            println!("Servicing interrupt, mode 0");
-                        }
+                    debug!("Servicing interrupt, mode 0");
+                    if self.int.vector & 0xC7 == 0xC7 {
+                        self.adv_cycles(13);
+                        self.interrupt_call((self.int.vector & 0x38) as u16);
+                    } else {
+                        self.adv_cycles(2);
                         self.decode(self.int.vector as u16);
                     }
                 }
                 1 => {
                     // Mode 1, RST38h, regardless of bus value or I reg value.
-                    if self.debug {
-                        println!("Servicing interrupt, mode 1");
-                    }
+                    debug!("Servicing interrupt, mode 1");
                     self.adv_cycles(13);
-                    self.rst(0x38);
+                    self.interrupt_call(0x38);
                 }
                 2 => {
                     // http://z80.info/1653.htm Interrupt MODE 2 details
-                    self.adv_cycles(2);
-                    if self.io.port == 0 {
-                        self.int.vector = self.io.value;
-                    }
                     // The interrupt vector is two part, composed by the I register and the lower
                     // 8-bits of the vector is placed on the bus. The resulting address is a vector
                     // that points to the beginning of RAM, the resulting address from reading this
                     // is the interrupt handler routine.
-                    // let vector = self.read16((self.reg.i.wrapping_shl(8) | self.int.vector) as u16);
-                    let vector = self.reg.i.wrapping_shl(8) | self.io.value;
-                    self.call(vector as u16);
-
+                    let table = (self.reg.i as u16) << 8 | self.int.vector as u16;
+                    let handler = self.read16(table);
+                    self.adv_cycles(19);
+                    self.interrupt_call(handler);
                     self.int.int = false;
-                    self.int.irq = false;
-                    if self.debug {
-                        println!("Servicing interrupt: Mode 2");
-                    }
+                    debug!("Servicing interrupt, mode 2, handler {:04X}", handler);
                 }
                 _ => panic!("Unhandled interrupt mode"),
             }
@@ -1,9 +1,12 @@
 #[cfg(test)]
 mod tests {
+    use crate::device::Device;
+    use crate::event::Event;
     use crate::instruction_info::Register;
     use crate::instruction_info::Register::{BC, DE, HL, IX, IXH, IY, R, SP};
-    use crate::interconnect::Interconnect;
-    use crate::memory::MemoryRW;
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::memory::{MemoryRW, Page, Region};
+    use crate::pacman;
 
     #[test]
     fn test_overflow_flag_add() {
@@ -22,6 +25,39 @@ mod tests {
         assert_eq!(i.cpu.flags.pf, true);
     }
 
+    #[test]
+    fn test_alu_iyh() {
+        // ADD A,IYH; CP IYH; XOR IYH are two bytes and 8 T states like their IYL forms
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.iy = 0x1234;
+        i.cpu.reg.a = 0x01;
+        let program = [0xFD, 0x84, 0xFD, 0xBC, 0xFD, 0xAC];
+        for (offset, byte) in program.iter().enumerate() {
+            i.cpu.write8(offset as u16, *byte);
+        }
+        i.cpu.execute();
+        assert_eq!((i.cpu.reg.a, i.cpu.reg.pc, i.cpu.cycles), (0x13, 2, 8));
+        i.cpu.execute();
+        assert_eq!((i.cpu.reg.pc, i.cpu.cycles), (4, 16));
+        assert!(!i.cpu.flags.zf);
+        i.cpu.execute();
+        assert_eq!((i.cpu.reg.a, i.cpu.reg.pc, i.cpu.cycles), (0x01, 6, 24));
+    }
+
+    #[test]
+    fn test_rst_return_address() {
+        // RST is a single byte, the return address is the instruction after it
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.pc = 0x0100;
+        i.cpu.reg.sp = 0x8000;
+        i.cpu.write8(0x0100, 0xFF);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x38);
+        assert_eq!(i.cpu.read16(i.cpu.reg.sp), 0x0101);
+    }
+
     #[test]
     #[ignore]
     fn test_ld_hl_indexed() {
@@ -90,6 +126,1968 @@ mod tests {
         assert_eq!(i.cpu.flags.hf, true);
     }
 
+    #[derive(Default)]
+    struct Timer {
+        cycles: usize,
+    }
+
+    impl Device for Timer {
+        fn tick(&mut self, cycles: usize) {
+            self.cycles += cycles;
+        }
+        fn pending_interrupt(&self) -> Option<u8> {
+            if self.cycles >= 8 {
+                Some(0xFF)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_device_tick() {
+        // Devices should advance by the same amount of cycles as the CPU (NOP = 4 T states)
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let timer = i.add_device(Timer::default());
+        assert_eq!(i.step().cycles, 4);
+        assert!(!i.cpu.int.irq);
+        i.step();
+        assert_eq!(timer.borrow().cycles, i.cpu.cycles);
+        assert!(i.cpu.int.irq);
+        assert_eq!(i.cpu.int.vector, 0xFF);
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.events.enabled = true;
+        i.cpu.events.watch = Some(0x8000..=0x80FF);
+        // LD A, 0x42; OUT (0x10), A; LD (0x8000), A; LD (0x9000), A; HALT
+        let program = [
+            0x3E, 0x42, 0xD3, 0x10, 0x32, 0x00, 0x80, 0x32, 0x00, 0x90, 0x76,
+        ];
+        i.cpu.memory.load_slice(0x0000, &program);
+        assert!(i.cpu.events.is_empty());
+        for _ in 0..5 {
+            i.step();
+        }
+        let events: Vec<Event> = i.cpu.events.drain().collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::IoWrite {
+                    port: 0x4210,
+                    value: 0x42
+                },
+                Event::MemWrite {
+                    addr: 0x8000,
+                    value: 0x42
+                },
+                Event::Halt,
+            ]
+        );
+        assert!(i.cpu.events.is_empty());
+    }
+
+    #[test]
+    fn test_reti_event() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.events.enabled = true;
+        i.cpu.reg.sp = 0x8000;
+        i.cpu.write16(0x8000, 0x1234);
+        i.cpu.write8(0, 0xED);
+        i.cpu.write8(1, 0x4D);
+        assert_eq!(i.step().cycles, 14);
+        assert_eq!(i.cpu.reg.pc, 0x1234);
+        assert_eq!(i.cpu.events.drain().next(), Some(Event::RetiExecuted));
+    }
+
+    #[test]
+    fn test_memory_hooks() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let latch = Rc::new(RefCell::new(0));
+        let writes = latch.clone();
+        i.cpu
+            .memory
+            .on_read(|addr| if addr == 0x5000 { Some(0xAA) } else { None });
+        i.cpu.memory.on_write(move |addr, byte| {
+            if addr == 0x5000 {
+                *writes.borrow_mut() = byte;
+                return true;
+            }
+            false
+        });
+        i.cpu.write8(0x5000, 0x01);
+        i.cpu.write8(0x5001, 0x02);
+        assert_eq!(*latch.borrow(), 0x01);
+        assert_eq!(i.cpu.read8(0x5000), 0xAA);
+        assert_eq!(i.cpu.read8(0x5001), 0x02);
+        assert_eq!(i.cpu.memory[0x5000], 0x00);
+
+        i.cpu.memory.clear_hooks();
+        assert_eq!(i.cpu.read8(0x5000), 0x00);
+    }
+
+    #[derive(Default)]
+    struct Latch {
+        last_write: Option<(u16, u8)>,
+    }
+
+    impl Device for Latch {
+        fn io_read(&mut self, port: u16) -> u8 {
+            (port as u8).wrapping_add(1)
+        }
+        fn io_write(&mut self, port: u16, value: u8) {
+            self.last_write = Some((port, value));
+        }
+    }
+
+    #[test]
+    fn test_port_io() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let latch = i.add_device(Latch::default());
+        i.register_port(0x10..=0x1F, latch.clone());
+        // LD A, 0x42; OUT (0x12), A; IN A, (0x14); LD C, 0x1F; IN B, (C); IN A, (0x20)
+        let program = [
+            0x3E, 0x42, 0xD3, 0x12, 0xDB, 0x14, 0x0E, 0x1F, 0xED, 0x40, 0xDB, 0x20,
+        ];
+        i.cpu.memory.load_slice(0x0000, &program);
+        i.step();
+        i.step();
+        assert_eq!(latch.borrow().last_write, Some((0x4212, 0x42)));
+        i.step();
+        assert_eq!(i.cpu.reg.a, 0x15);
+        i.step();
+        assert_eq!(i.step().cycles, 12);
+        assert_eq!(i.cpu.reg.b, 0x20);
+        // Nothing is listening on port 0x20
+        i.step();
+        assert_eq!(i.cpu.reg.a, 0xFF);
+    }
+
+    #[test]
+    fn test_in_f_out_c0() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let latch = i.add_device(Latch::default());
+        i.register_port(0x10..=0x1F, latch.clone());
+        // LD BC, 0x10FF; IN (C); LD C, 0x1F; IN (C); OUT (C), 0
+        let program = [
+            0x01, 0xFF, 0x10, 0xED, 0x70, 0x0E, 0x1F, 0xED, 0x70, 0xED, 0x71,
+        ];
+        for (offset, byte) in program.iter().enumerate() {
+            i.cpu.write8(offset as u16, *byte);
+        }
+        i.step();
+        // Port FF floats high, only the flags change
+        assert_eq!(i.step().cycles, 12);
+        assert!(i.cpu.flags.sf && i.cpu.flags.pf && !i.cpu.flags.zf);
+        i.step();
+        i.step();
+        assert!(!i.cpu.flags.sf && !i.cpu.flags.pf);
+        assert_eq!((i.cpu.reg.a, i.cpu.reg.pc), (0x00, 9));
+        assert_eq!(i.step().cycles, 12);
+        assert_eq!(latch.borrow().last_write, Some((0x101F, 0x00)));
+        assert_eq!(i.cpu.reg.pc, 11);
+    }
+
+    #[test]
+    fn test_block_io() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let out = output.clone();
+        i.cpu
+            .set_out_handler(move |port, value| out.borrow_mut().push((port, value)));
+        i.cpu.set_in_handler(|port| (port >> 8) as u8);
+        i.cpu.memory.load_slice(0x0100, &[0x11, 0x22, 0xFE]);
+        // LD HL, 0x0100; LD BC, 0x0398; OTIR
+        // LD HL, 0x0200; LD BC, 0x0299; INIR
+        // LD B, 1; LD HL, 0x0102; OUTD
+        // LD B, 1; LD HL, 0x0300; IND
+        let program = [
+            0x21, 0x00, 0x01, 0x01, 0x98, 0x03, 0xED, 0xB3, 0x21, 0x00, 0x02, 0x01, 0x99, 0x02,
+            0xED, 0xB2, 0x06, 0x01, 0x21, 0x02, 0x01, 0xED, 0xAB, 0x06, 0x01, 0x21, 0x00, 0x03,
+            0xED, 0xAA,
+        ];
+        i.cpu.memory.load_slice(0x0000, &program);
+        i.step();
+        i.step();
+        // B is decremented before it's put on the address bus
+        assert_eq!(i.step().cycles, 21);
+        assert_eq!(i.cpu.reg.pc, 6);
+        assert_eq!(i.step().cycles, 21);
+        assert_eq!(i.step().cycles, 16);
+        assert_eq!(i.cpu.reg.pc, 8);
+        assert_eq!(
+            *output.borrow(),
+            vec![(0x0298, 0x11), (0x0198, 0x22), (0x0098, 0xFE)]
+        );
+        assert_eq!(i.cpu.read_pair(HL), 0x0103);
+        // FE + L (03) carries, N is bit 7 of FE
+        assert!(i.cpu.flags.zf && i.cpu.flags.cf && i.cpu.flags.hf && i.cpu.flags.nf);
+        assert!(!i.cpu.flags.pf);
+
+        // The port is read with B before it's decremented
+        while i.cpu.reg.pc != 16 {
+            i.step();
+        }
+        assert_eq!((i.cpu.memory[0x0200], i.cpu.memory[0x0201]), (0x02, 0x01));
+        assert_eq!((i.cpu.read_pair(HL), i.cpu.reg.b), (0x0202, 0));
+        assert!(i.cpu.flags.zf && !i.cpu.flags.nf);
+
+        i.step();
+        i.step();
+        assert_eq!(i.step().cycles, 16);
+        assert_eq!(output.borrow().last(), Some(&(0x0099, 0xFE)));
+        assert_eq!(i.cpu.read_pair(HL), 0x0101);
+        i.step();
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.memory[0x0300], 0x01);
+        assert_eq!((i.cpu.read_pair(HL), i.cpu.reg.pc), (0x02FF, 30));
+    }
+
+    #[test]
+    fn test_io_float() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.io.float = 0x7F;
+        // IN A, (0xFE)
+        i.cpu.write8(0, 0xDB);
+        i.cpu.write8(1, 0xFE);
+        i.step();
+        assert_eq!(i.cpu.reg.a, 0x7F);
+    }
+
+    #[test]
+    fn test_port_decode_16bit() {
+        // Spectrum 128 style paging port: selected when A15 and A1 are low
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let paging = i.add_device(Latch::default());
+        i.register_port_decoded(0x8002, 0x0000, paging.clone());
+        // LD BC, 0x7FFD; LD A, 0x17; OUT (C), A; LD BC, 0xFFFD; OUT (C), A
+        let program = [
+            0x01, 0xFD, 0x7F, 0x3E, 0x17, 0xED, 0x79, 0x01, 0xFD, 0xFF, 0xED, 0x79,
+        ];
+        i.cpu.memory.load_slice(0x0000, &program);
+        for _ in 0..3 {
+            i.step();
+        }
+        assert_eq!(paging.borrow().last_write, Some((0x7FFD, 0x17)));
+        paging.borrow_mut().last_write = None;
+        i.step();
+        i.step();
+        assert_eq!(paging.borrow().last_write, None);
+    }
+
+    #[test]
+    fn test_io_handlers() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        let latch = i.add_device(Latch::default());
+        i.register_port(0x10..=0x10, latch.clone());
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let out = output.clone();
+        i.cpu
+            .set_out_handler(move |port, value| out.borrow_mut().push((port, value)));
+        i.cpu.set_in_handler(|port| port as u8);
+        // LD A, 0x01; OUT (0x10), A; OUT (0x20), A; IN A, (0x30)
+        let program = [0x3E, 0x01, 0xD3, 0x10, 0xD3, 0x20, 0xDB, 0x30];
+        i.cpu.memory.load_slice(0x0000, &program);
+        for _ in 0..4 {
+            i.step();
+        }
+        // The registered device takes precedence over the handler
+        assert_eq!(latch.borrow().last_write, Some((0x0110, 0x01)));
+        assert_eq!(*output.borrow(), vec![(0x0120, 0x01)]);
+        assert_eq!(i.cpu.reg.a, 0x30);
+    }
+
+    #[test]
+    fn test_int_request_mode1() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.sp = 0x8000;
+        // IM 1; NOP; EI; NOP
+        let program = [0xED, 0x56, 0x00, 0xFB, 0x00];
+        i.cpu.memory.load_slice(0x0000, &program);
+        i.cpu.int_request(0xFF);
+        i.step();
+        i.step();
+        // Interrupts are disabled, the request is held
+        assert_eq!(i.cpu.reg.pc, 3);
+        assert!(i.cpu.int.irq);
+        // Not accepted until the instruction after EI is done
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 4);
+        assert!(i.cpu.int.irq);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x38);
+        assert_eq!(i.cpu.read16(i.cpu.reg.sp), 5);
+        assert!(!i.cpu.int.iff1);
+        assert!(!i.cpu.int.irq);
+    }
+
+    #[test]
+    fn test_int_held_after_ei() {
+        // A handler ending in EI; RET with /INT still held returns before it's entered again
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.sp = 0x8000;
+        // IM 1; EI; LOOP: NOP; JP LOOP
+        let program = [0xED, 0x56, 0xFB, 0x00, 0xC3, 0x03, 0x00];
+        for (offset, byte) in program.iter().enumerate() {
+            i.cpu.write8(offset as u16, *byte);
+        }
+        // EI; RET
+        i.cpu.write8(0x38, 0xFB);
+        i.cpu.write8(0x39, 0xC9);
+        let mut entries = 0;
+        for _ in 0..30 {
+            i.cpu.int_request(0xFF);
+            i.step();
+            assert!(i.cpu.reg.sp >= 0x7FFE);
+            if i.cpu.reg.pc == 0x38 {
+                entries += 1;
+                assert!(i.cpu.read16(i.cpu.reg.sp) < 0x38);
+            }
+        }
+        assert!(entries > 5);
+    }
+
+    #[test]
+    fn test_int_request_mode2() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.sp = 0x8000;
+        i.cpu.reg.i = 0x30;
+        i.cpu.write16(0x3010, 0x1234);
+        // IM 2; EI; NOP
+        let program = [0xED, 0x5E, 0xFB, 0x00];
+        i.cpu.memory.load_slice(0x0000, &program);
+        i.step();
+        i.cpu.int_request(0x10);
+        i.cpu.int_clear();
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 3);
+        i.cpu.int_request(0x10);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x1234);
+        assert_eq!(i.cpu.read16(i.cpu.reg.sp), 4);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut i = Interconnect::default();
+        i.cpu.cpm_compat = true;
+        i.cpu.reg.sp = 0x8000;
+        i.cpu.int.iff1 = true;
+        i.cpu.int.iff2 = true;
+        i.cpu.nmi();
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x66);
+        assert_eq!(i.cpu.read16(i.cpu.reg.sp), 1);
+        assert!(!i.cpu.int.iff1);
+        assert!(i.cpu.int.iff2);
+        // RETN restores IFF1 from IFF2
+        i.cpu.write8(0x66, 0xED);
+        i.cpu.write8(0x67, 0x45);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 1);
+        assert!(i.cpu.int.iff1);
+    }
+
+    #[test]
+    fn test_builder() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let timer = Rc::new(RefCell::new(Timer::default()));
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .sp(0xF000)
+            .interrupt_mode(1)
+            .clock_speed(3_500_000)
+            .device(timer.clone())
+            .port_device(0x10..=0x10, latch.clone())
+            .build();
+        assert!(i.cpu.cpm_compat);
+        assert_eq!(i.cpu.reg.pc, 0x0100);
+        assert_eq!(i.cpu.reg.sp, 0xF000);
+        assert_eq!(i.cpu.int.mode, 1);
+        assert_eq!(i.clock_speed, 3_500_000);
+        assert_eq!(i.devices.len(), 2);
+
+        // OUT (0x10), A
+        i.cpu.write8(0x0100, 0xD3);
+        i.cpu.write8(0x0101, 0x10);
+        i.step();
+        assert_eq!(timer.borrow().cycles, 11);
+        assert_eq!(latch.borrow().last_write, Some((0xFF10, 0xFF)));
+    }
+
+    #[test]
+    fn test_16bit_wrap() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.write16(0xFFFF, 0x1234);
+        assert_eq!(i.cpu.read8(0xFFFF), 0x34);
+        assert_eq!(i.cpu.read8(0x0000), 0x12);
+        assert_eq!(i.cpu.read16(0xFFFF), 0x1234);
+
+        // PUSH BC with SP at 0x0001 wraps the high byte to 0x0000 and the low byte to 0xFFFF
+        i.cpu.reg.sp = 0x0001;
+        i.cpu.reg.b = 0xAB;
+        i.cpu.reg.c = 0xCD;
+        i.cpu.write8(0x0100, 0xC5);
+        i.step();
+        assert_eq!(i.cpu.reg.sp, 0xFFFF);
+        assert_eq!(i.cpu.read16(0xFFFF), 0xABCD);
+
+        // POP DE reads back across the boundary
+        i.cpu.write8(0x0101, 0xD1);
+        i.step();
+        assert_eq!(i.cpu.reg.sp, 0x0001);
+        assert_eq!(i.cpu.read_pair(DE), 0xABCD);
+
+        // LD BC, nn with the operand wrapping around to 0x0000
+        i.cpu.reg.pc = 0xFFFE;
+        i.cpu.write8(0xFFFE, 0x01);
+        i.cpu.write8(0xFFFF, 0x78);
+        i.cpu.write8(0x0000, 0x56);
+        i.step();
+        assert_eq!(i.cpu.read_pair(BC), 0x5678);
+        assert_eq!(i.cpu.reg.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_memory_pages() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.write8(0x2000, 0x11);
+        i.cpu.memory.load_rom(0x2000, &[0xC3, 0x00, 0x01]);
+        assert_eq!(i.cpu.memory.page(8), Page::Rom(0));
+        assert_eq!(i.cpu.memory.rom.len(), 0x400);
+        assert_eq!(i.cpu.read8(0x2000), 0xC3);
+        // The rest of the page is padded
+        assert_eq!(i.cpu.read8(0x2003), 0xFF);
+
+        // ROM is write protected, the RAM underneath is untouched
+        i.cpu.write8(0x2000, 0x22);
+        assert_eq!(i.cpu.read8(0x2000), 0xC3);
+        assert_eq!(i.cpu.memory.ram[0x2000], 0x11);
+
+        // Map the RAM page back in
+        i.cpu.memory.map_page(8, Page::Ram(0x2000));
+        assert_eq!(i.cpu.read8(0x2000), 0x11);
+
+        // ROM over RAM that still takes the writes
+        i.cpu.memory.map_page(8, Page::Rom(0));
+        i.cpu.memory.map_ram_under_rom(8, Some(0x2000));
+        i.cpu.write8(0x2000, 0x33);
+        assert_eq!(i.cpu.read8(0x2000), 0xC3);
+        i.cpu.memory.map_page(8, Page::Ram(0x2000));
+        assert_eq!(i.cpu.read8(0x2000), 0x33);
+    }
+
+    #[test]
+    fn test_load_rom_unaligned() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        // Into a RAM page, the rest goes to a ROM page of its own
+        let data: Vec<u8> = (0..0x300).map(|n| n as u8).collect();
+        i.cpu.memory.load_rom(0x2200, &data);
+        assert_eq!(i.cpu.memory.page(8), Page::Ram(0x2000));
+        assert_eq!(i.cpu.memory.ram[0x2200], 0x00);
+        assert_eq!(i.cpu.memory.ram[0x23FF], 0xFF);
+        assert_eq!(i.cpu.memory.page(9), Page::Rom(0));
+        assert_eq!(i.cpu.read8(0x2400), 0x00);
+        assert_eq!(i.cpu.read8(0x24FF), 0xFF);
+        assert_eq!(i.cpu.read8(0x2500), 0xFF);
+
+        // Over ROM, the page is copied so other mappings of it don't change
+        i.cpu.memory.map_page(10, Page::Rom(0));
+        i.cpu.memory.load_rom(0x2810, &[0xAA, 0xBB]);
+        assert_eq!(i.cpu.memory.page(10), Page::Rom(0x400));
+        assert_eq!(i.cpu.read8(0x280F), 0x0F);
+        assert_eq!(i.cpu.read8(0x2811), 0xBB);
+        assert_eq!(i.cpu.read8(0x2411), 0x11);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        // 128K of RAM in 16K banks, bank 0 is mapped at 0xC000
+        let banks = i.cpu.memory.alloc_ram(8 * 0x4000);
+        i.cpu.memory.map_bank(0xC000, 0x4000, Page::Ram(banks));
+        i.cpu
+            .memory
+            .add_paging_register(0x8002, 0x0000, move |memory, value| {
+                let bank = (value & 0x07) as usize;
+                memory.map_bank(0xC000, 0x4000, Page::Ram(banks + bank * 0x4000));
+            });
+
+        i.cpu.write8(0xC000, 0xAA);
+        // LD BC, 0x7FFD; LD A, 3; OUT (C), A
+        i.cpu.reg.pc = 0x0100;
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x01, 0xFD, 0x7F, 0x3E, 0x03, 0xED, 0x79]);
+        i.step();
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.memory.page(0x30), Page::Ram(banks + 3 * 0x4000));
+        assert_eq!(i.cpu.read8(0xC000), 0x00);
+        i.cpu.write8(0xC000, 0xBB);
+
+        // Back to bank 0 through OUT (n), A with A8-A15 = 0x00
+        i.cpu.reg.a = 0x00;
+        i.cpu.write8(0x0107, 0xD3);
+        i.cpu.write8(0x0108, 0xFD);
+        i.step();
+        assert_eq!(i.cpu.read8(0xC000), 0xAA);
+        assert_eq!(i.cpu.memory.ram[banks + 3 * 0x4000], 0xBB);
+        // The unbanked RAM underneath is separate storage
+        assert_eq!(i.cpu.memory.ram[0xC000], 0x00);
+    }
+
+    #[test]
+    fn test_memory_image() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let banks = i.cpu.memory.alloc_ram(2 * 0x4000);
+        i.cpu.memory.load_rom(0x0000, &[0xC3, 0x00, 0x01]);
+        i.cpu
+            .memory
+            .map_bank(0xC000, 0x4000, Page::Ram(banks + 0x4000));
+        i.cpu.write8(0xC000, 0x11);
+        i.cpu.write8(0x8000, 0x22);
+        let path = std::env::temp_dir().join("z80-rs-memory.img");
+        i.cpu.memory.dump(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 0x10000);
+
+        // Restoring puts bank 1 back at C000 and fills it
+        i.cpu.memory.map_bank(0xC000, 0x4000, Page::Ram(banks));
+        i.cpu.write8(0xC000, 0x33);
+        i.cpu.write8(0x8000, 0x00);
+        i.cpu.memory.restore(&path).unwrap();
+        assert_eq!(i.cpu.memory.page(0x30), Page::Ram(banks + 0x4000));
+        assert_eq!(i.cpu.read8(0xC000), 0x11);
+        assert_eq!(i.cpu.read8(0x8000), 0x22);
+        assert_eq!(i.cpu.memory.page(0), Page::Rom(0));
+        // The bank that was mapped out keeps its contents
+        assert_eq!(i.cpu.memory.ram[banks], 0x33);
+
+        // A page map for bigger storage than there is
+        let mut banks_file = path.clone().into_os_string();
+        banks_file.push(".banks");
+        std::fs::write(&banks_file, "C000 ram 0F0000\n").unwrap();
+        assert!(i.cpu.memory.restore(&path).is_err());
+        std::fs::remove_file(banks_file).unwrap();
+        i.cpu.memory.restore(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mmio() {
+        use crate::peripherals;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let latch = Rc::new(RefCell::new(peripherals::Latch::default()));
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .mmio_device(0x6000..=0x6000, latch.clone())
+            .build();
+        // LD A, 0x5A; LD (0x6000), A
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x5A, 0x32, 0x00, 0x60]);
+        i.step();
+        i.step();
+        assert_eq!(latch.borrow().value, 0x5A);
+        assert_eq!(i.cpu.read8(0x6000), 0x5A);
+        // Memory underneath is untouched and peeking doesn't reach the device
+        assert_eq!(i.cpu.memory[0x6000], 0x00);
+
+        // Pac-Man's I/O block is reachable through the upper mirror, 5000 reads IN0 and
+        // writes the interrupt enable
+        let mut i = Interconnect::builder().preset(Preset::PacMan).build();
+        i.cpu.write8(0xD000, 0x01);
+        assert_eq!(i.cpu.read8(0x5000), 0xFF);
+        assert_eq!(i.cpu.read8(0xD080), pacman::DEFAULT_DSW1);
+        assert_eq!(i.cpu.memory[0x5000], 0x00);
+        while i.cpu.cycles < pacman::FRAME_CYCLES {
+            i.step();
+        }
+        assert!(i.cpu.int.irq);
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut i = Interconnect::builder().preset(Preset::PacMan).build();
+        // Pokes reach ROM, the CPU can't write it
+        i.poke(0x0000, 0x3E);
+        i.cpu.write8(0x0000, 0x00);
+        assert_eq!(i.peek(0x0000), 0x3E);
+        i.poke(0x4C00, 0x01);
+        i.poke(0x4C01, 0x02);
+        assert_eq!(i.peek16(0x4C00), 0x0201);
+        // Mirrored and unmapped addresses
+        assert_eq!(i.peek(0xCC00), 0x01);
+        assert_eq!(i.peek(0x6000), 0xFF);
+    }
+
+    #[test]
+    fn test_fill_copy() {
+        let mut i = Interconnect::builder().preset(Preset::PacMan).build();
+        i.cpu.memory.fill(0x4000..=0x43FF, 0x20);
+        assert_eq!(i.peek(0x4000), 0x20);
+        assert_eq!(i.peek(0x43FF), 0x20);
+        assert_eq!(i.peek(0x4400), 0x00);
+
+        // Overlapping copy forwards
+        i.cpu.memory.load_slice(0x4C00, &[1, 2, 3, 4]);
+        i.cpu.memory.copy(0x4C00, 0x4C02, 4);
+        assert_eq!(
+            (0..6).map(|n| i.peek(0x4C00 + n)).collect::<Vec<_>>(),
+            vec![1, 2, 1, 2, 3, 4]
+        );
+
+        // Copying into ROM goes through the map as well
+        i.cpu.memory.copy(0x4C00, 0x0000, 2);
+        assert_eq!(i.peek16(0x0000), 0x0201);
+    }
+
+    #[test]
+    fn test_load_origin() {
+        use crate::memory::parse_origin;
+
+        assert_eq!(parse_origin("basic.bin@8000"), ("basic.bin", Some(0x8000)));
+        assert_eq!(
+            parse_origin("basic.bin@0xC000"),
+            ("basic.bin", Some(0xC000))
+        );
+        assert_eq!(parse_origin("basic.bin"), ("basic.bin", None));
+        assert_eq!(parse_origin("me@host.bin"), ("me@host.bin", None));
+        // Unless a file by that name exists
+        let file = std::env::temp_dir().join("z80-rs-game@bad");
+        std::fs::write(&file, [0x00]).unwrap();
+        let arg = file.to_str().unwrap();
+        assert_eq!(parse_origin(arg), (arg, None));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(parse_origin(arg).1, Some(0x0BAD));
+
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let len = i
+            .cpu
+            .memory
+            .load_bin_at("tests/prelim.com", 0x8000)
+            .unwrap();
+        assert!(len > 0);
+        let mut expected = Interconnect::builder().preset(Preset::Cpm).build();
+        expected.cpu.memory.load_tests("tests/prelim.com");
+        assert!((0..len as u16).all(|n| i.peek(0x8000 + n) == expected.peek(0x0100 + n)));
+        assert!(i
+            .cpu
+            .memory
+            .load_bin_at("tests/prelim.com", 0xFFF0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_load_records() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let hex = ":030100002100805B\n:018000007708\n:00000001FF\n";
+        assert_eq!(i.cpu.memory.read_ihex(hex.as_bytes()).unwrap(), 4);
+        assert_eq!(i.dump_range(0x0100, 3).get(6..14), Some("21 00 80"));
+        assert_eq!(i.peek(0x8000), 0x77);
+        assert!(i.cpu.memory.read_ihex(":0180000077FF".as_bytes()).is_err());
+
+        let srec = "S10401023EBA\nS9030100FB\n";
+        assert_eq!(i.cpu.memory.read_srec(srec.as_bytes()).unwrap(), 1);
+        assert_eq!(i.peek(0x0102), 0x3E);
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .cpm_traps()
+            .build();
+        assert_eq!(
+            i.dump_range(0x0000, 8).get(6..29),
+            Some("D3 00 00 00 00 DB 00 C9")
+        );
+
+        i.cpu
+            .memory
+            .apply_patches(&[(0x1000, &[0xC3, 0x00, 0x20]), (0x2000, &[0x76])]);
+        assert_eq!(i.peek(0x1000), 0xC3);
+        assert_eq!(i.peek16(0x1001), 0x2000);
+        assert_eq!(i.peek(0x2000), 0x76);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.reg.a = 0x12;
+        i.cpu.flags.set(0x41);
+        i.cpu.reg.h_ = 0x80;
+        i.cpu.reg.ix = 0x1234;
+        i.cpu.int.mode = 1;
+        i.cpu.int.iff1 = true;
+        assert_eq!(
+            i.cpu.to_json(),
+            "{\"pc\":256,\"sp\":65535,\"af\":4673,\"bc\":0,\"de\":0,\"hl\":0,\"ix\":4660,\
+             \"iy\":0,\"af_\":0,\"bc_\":0,\"de_\":0,\"hl_\":32768,\"i\":0,\"r\":0,\
+             \"flags\":{\"s\":false,\"z\":true,\"y\":false,\"h\":false,\"x\":false,\
+             \"p\":false,\"n\":false,\"c\":true},\"im\":1,\"iff1\":true,\"iff2\":false,\
+             \"halted\":false,\"cycles\":0}"
+        );
+        assert_eq!(
+            crate::snapshot::Snapshot::capture(&i.cpu).to_json(),
+            i.cpu.to_json()
+        );
+    }
+
+    #[test]
+    fn test_cpm_console() {
+        // LD C, 9; LD DE, 0x0200; CALL 0x0005; LD C, 2; LD E, '!'; CALL 0x0005; JP 0x0000
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .cpm_traps()
+            .sp(0x8000)
+            .build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0x0E, 0x09, 0x11, 0x00, 0x02, 0xCD, 0x05, 0x00, 0x0E, 0x02, 0x1E, 0x21, 0xCD, 0x05,
+                0x00, 0xC3, 0x00, 0x00,
+            ],
+        );
+        i.cpu.memory.load_slice(0x0200, b"Hello$");
+        while i.cpu.opcode != 0xD3 {
+            i.run_tests();
+        }
+        assert_eq!(i.console, "Hello!");
+        assert_eq!(i.take_console(), "Hello!");
+        assert!(i.console.is_empty());
+    }
+
+    #[test]
+    fn test_pc_hooks() {
+        use crate::interconnect::HookAction;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LD C, 9; LD DE, 0x0200; CALL 0x0005; LD C, 2; LD E, '!'; CALL 0x0005; HALT
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .sp(0x8000)
+            .build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0x0E, 0x09, 0x11, 0x00, 0x02, 0xCD, 0x05, 0x00, 0x0E, 0x02, 0x1E, 0x21, 0xCD, 0x05,
+                0x00, 0x76,
+            ],
+        );
+        i.cpu.memory.load_slice(0x0200, b"Hello$");
+        let output = Rc::new(RefCell::new(String::new()));
+        let console = output.clone();
+        i.on_pc(0x0005, move |cpu| {
+            let mut console = console.borrow_mut();
+            match cpu.reg.c {
+                2 => console.push(cpu.reg.e as char),
+                9 => {
+                    let mut addr = cpu.read_pair(DE);
+                    while cpu.memory[addr] != b'$' {
+                        console.push(cpu.memory[addr] as char);
+                        addr += 1;
+                    }
+                }
+                _ => {}
+            }
+            HookAction::Return
+        });
+        // Skipping HALT and jumping elsewhere
+        i.on_pc(0x010F, |cpu| {
+            cpu.reg.pc = 0x0300;
+            HookAction::Skip
+        });
+
+        for _ in 0..8 {
+            i.step();
+        }
+        assert_eq!(*output.borrow(), "Hello!");
+        assert_eq!(i.cpu.reg.sp, 0x8000);
+        assert_eq!(i.cpu.reg.pc, 0x010F);
+        assert_eq!(i.step().cycles, 0);
+        assert_eq!(i.cpu.reg.pc, 0x0300);
+
+        assert!(i.remove_pc_hook(0x0005));
+        assert!(!i.remove_pc_hook(0x0005));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        use crate::debugger::CallKind;
+
+        // 0100: LD SP, 0x8000; CALL 0x0200; HALT
+        // 0200: CALL 0x0300; RET
+        // 0300: RST 0x08; POP HL; JP 0x0203
+        // 0008: RET
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x31, 0x00, 0x80, 0xCD, 0x00, 0x02, 0x76]);
+        i.cpu.memory.load_slice(0x0200, &[0xCD, 0x00, 0x03, 0xC9]);
+        i.cpu
+            .memory
+            .load_slice(0x0300, &[0xCF, 0xE1, 0xC3, 0x03, 0x02]);
+        i.cpu.memory.load_slice(0x0008, &[0xC9]);
+        i.call_stack.enabled = true;
+        for _ in 0..4 {
+            i.step();
+        }
+        assert_eq!(i.cpu.reg.pc, 0x0008);
+        let kinds: Vec<CallKind> = i.call_stack.frames().iter().map(|f| f.kind).collect();
+        assert_eq!(kinds, vec![CallKind::Call, CallKind::Call, CallKind::Rst]);
+        assert_eq!(
+            i.backtrace(),
+            "#0  0008\n\
+             #1  0301  RST 08 at 0300\n\
+             #2  0203  CALL 0300 at 0200\n\
+             #3  0106  CALL 0200 at 0103\n"
+        );
+
+        // RET drops the RST frame, then an interrupt is taken at the return address
+        i.cpu.int.iff1 = true;
+        i.cpu.int_request(0xFF);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0038);
+        assert_eq!(i.call_stack.depth(), 3);
+        let frame = i.call_stack.frames()[2];
+        assert_eq!((frame.kind, frame.ret), (CallKind::Interrupt, 0x0301));
+
+        // Leave the handler by resetting SP, then POP HL discards the return address pushed
+        // by CALL 0x0300 and JP goes back to 0x0203
+        i.cpu.reg.sp = 0x7FFC;
+        i.cpu.reg.pc = 0x0301;
+        i.step();
+        assert_eq!(i.call_stack.depth(), 1);
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0106);
+        assert_eq!(i.call_stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_coverage() {
+        use crate::coverage::Coverage;
+
+        // LD A, 1; JP 0x0200; (never executed) NOP
+        // 0200: DEC A; HALT
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x01, 0xC3, 0x00, 0x02, 0x00]);
+        i.cpu.memory.load_slice(0x0200, &[0x3D, 0x76]);
+        i.coverage = Some(Coverage::new(true));
+        for _ in 0..4 {
+            i.step();
+        }
+        let coverage = i.coverage.as_ref().unwrap();
+        assert_eq!(coverage.ranges(), vec![0x0100..=0x0104, 0x0200..=0x0201]);
+        assert!(!coverage.is_executed(0x0105));
+        assert_eq!(coverage.hits(0x0200), Some(1));
+        assert_eq!(coverage.hits(0x0101), Some(0));
+    }
+
+    #[test]
+    fn test_halt_and_jr() {
+        // JR $+4; (skipped) NOP; NOP; HALT; NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x18, 0x02, 0x00, 0x00, 0x76, 0x00]);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0104);
+        i.step();
+        assert!(i.cpu.int.halt);
+        assert_eq!(i.cpu.reg.pc, 0x0105);
+
+        // Halted, the CPU runs NOPs without moving on
+        i.cpu.reg.r = 0xFF;
+        let cycles = i.cpu.cycles;
+        for _ in 0..3 {
+            i.step();
+        }
+        assert!(i.cpu.int.halt);
+        assert_eq!(i.cpu.reg.pc, 0x0105);
+        assert_eq!(i.cpu.cycles - cycles, 12);
+        assert_eq!(i.cpu.reg.r, 0x82);
+
+        // Until an interrupt, which returns past the HALT
+        i.cpu.reg.sp = 0x8000;
+        i.cpu.nmi();
+        i.step();
+        assert!(!i.cpu.int.halt);
+        assert_eq!(i.cpu.reg.pc, 0x0066);
+        assert_eq!(i.peek16(0x7FFE), 0x0105);
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        use crate::profile::{AccessCounts, MemoryStats};
+
+        // LD (0x8000), A; LD A, (0x8000); PUSH BC
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .sp(0x9000)
+            .build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x32, 0x00, 0x80, 0x3A, 0x00, 0x80, 0xC5]);
+        i.cpu.mem_stats = Some(MemoryStats::default());
+        for _ in 0..3 {
+            i.step();
+        }
+        let stats = i.cpu.mem_stats.as_ref().unwrap();
+        assert_eq!(
+            stats.get(0x8000),
+            AccessCounts {
+                reads: 1,
+                writes: 1
+            }
+        );
+        assert_eq!(stats.range(0x8FFE..=0x8FFF).writes, 2);
+        // Opcode fetches aren't counted
+        assert_eq!(stats.get(0x0100).reads, 0);
+    }
+
+    #[test]
+    fn test_decode_instruction() {
+        use crate::instruction_info::{Condition, Instruction, Mnemonic, Operand};
+
+        let decode = |bytes: &[u8]| Instruction::decode_bytes(bytes).unwrap();
+
+        let i = decode(&[0x3E, 0x42]);
+        assert_eq!(i.mnemonic, Mnemonic::Ld);
+        assert_eq!(i.dst, Some(Operand::Reg(Register::A)));
+        assert_eq!(i.src, Some(Operand::Imm8(0x42)));
+        assert_eq!((i.size, i.cycles), (2, 7));
+        assert_eq!(i.to_string(), "LD A, $42");
+
+        let i = decode(&[0xC4, 0x34, 0x12]);
+        assert_eq!(i.dst, Some(Operand::Condition(Condition::NZ)));
+        assert_eq!(i.src, Some(Operand::Imm16(0x1234)));
+        assert_eq!((i.cycles, i.cycles_taken), (10, 17));
+        assert_eq!(i.to_string(), "CALL NZ, $1234");
+
+        assert_eq!(decode(&[0x18, 0xFE]).to_string(), "JR $+0");
+        // Branch destinations resolve once the address is known
+        assert_eq!(decode(&[0x20, 0x03]).at(0x1C40).to_string(), "JR NZ, $1C45");
+        assert_eq!(decode(&[0x10, 0xFE]).at(0x0000).to_string(), "DJNZ $0000");
+        assert_eq!(decode(&[0x10, 0xFC]).branch_target(0x0001), Some(0xFFFF));
+        assert_eq!(decode(&[0xCD, 0x34, 0x12]).branch_target(0), Some(0x1234));
+        assert_eq!(decode(&[0xDA, 0x34, 0x12]).branch_target(0), Some(0x1234));
+        assert_eq!(decode(&[0xEF]).branch_target(0x0100), Some(0x0028));
+        assert_eq!(decode(&[0xE9]).branch_target(0), None);
+        assert_eq!(decode(&[0x21, 0x34, 0x12]).branch_target(0), None);
+        assert_eq!(
+            decode(&[0xFD, 0x77, 0x80]).at(0x0100).to_string(),
+            "LD (IY-$80), A"
+        );
+        assert_eq!(decode(&[0x08]).to_string(), "EX AF, AF'");
+        assert_eq!(decode(&[0xD3, 0x10]).to_string(), "OUT ($10), A");
+        assert_eq!(decode(&[0x96]).to_string(), "SUB (HL)");
+        assert_eq!(decode(&[0xCB, 0x7E]).to_string(), "BIT 7, (HL)");
+        assert_eq!(decode(&[0xED, 0x5E]).to_string(), "IM 2");
+        assert_eq!(decode(&[0xED, 0x71]).to_string(), "OUT (C), 0");
+
+        let i = decode(&[0xED, 0xB0]);
+        assert_eq!(i.prefix, 0xED);
+        assert_eq!(i.opcode, 0xB0);
+        assert_eq!((i.size, i.cycles, i.cycles_taken), (2, 16, 21));
+
+        // Index registers replace HL, H and L, but not when (IX+d) is also used
+        let i = decode(&[0xDD, 0x66, 0xFB]);
+        assert_eq!(i.to_string(), "LD H, (IX-$05)");
+        assert_eq!((i.size, i.cycles), (3, 19));
+        assert_eq!(decode(&[0xFD, 0x65]).to_string(), "LD IYH, IYL");
+        assert_eq!(decode(&[0xDD, 0x36, 0x02, 0x99]).cycles, 19);
+        assert_eq!(decode(&[0xDD, 0xE9]).to_string(), "JP (IX)");
+
+        let i = decode(&[0xFD, 0xCB, 0x03, 0xC0]);
+        assert_eq!(i.to_string(), "SET 0, (IY+$03), B");
+        assert_eq!(
+            (i.prefix, i.opcode, i.size, i.cycles),
+            (0xFDCB, 0xC0, 4, 23)
+        );
+
+        // Truncated instructions
+        assert!(Instruction::decode_bytes(&[0x01, 0x00]).is_none());
+        assert!(Instruction::decode_bytes(&[]).is_none());
+    }
+
+    #[test]
+    fn test_decode_exhaustive() {
+        use crate::instruction_info::Instruction;
+
+        // Every opcode in every table decodes, reports the number of bytes it consumed and
+        // needs all of them
+        let check = |bytes: [u8; 4]| {
+            let i = Instruction::decode_bytes(&bytes).unwrap();
+            let size = i.size as usize;
+            assert!((1..=4).contains(&size), "{:02X?}", bytes);
+            // DD / FD followed by another prefix is a one byte NOP, but takes a peek at the
+            // next byte to find out
+            let lookahead = matches!(bytes, [0xDD | 0xFD, 0xDD | 0xED | 0xFD, ..]);
+            let needed = if lookahead { 2 } else { size };
+            assert_eq!(Instruction::decode_bytes(&bytes[..needed]), Some(i));
+            assert_eq!(Instruction::decode_bytes(&bytes[..needed - 1]), None);
+            assert!(i.cycles > 0 && i.cycles <= i.cycles_taken, "{:02X?}", bytes);
+        };
+        for first in 0..=255u8 {
+            for second in 0..=255u8 {
+                check([first, second, 0x12, 0x34]);
+            }
+        }
+        for prefix in [0xDD, 0xFD] {
+            for op in 0..=255u8 {
+                check([prefix, 0xCB, 0x80, op]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_instruction_info() {
+        use crate::instruction_info::{InstructionInfo, Mnemonic, Prefix};
+
+        let tables = [
+            Prefix::None,
+            Prefix::CB,
+            Prefix::ED,
+            Prefix::DD,
+            Prefix::FD,
+            Prefix::DDCB,
+            Prefix::FDCB,
+        ];
+        for prefix in tables.iter() {
+            let known = (0..=255u8)
+                .filter(|op| InstructionInfo::lookup(*prefix, *op).is_some())
+                .count();
+            let expected = match prefix {
+                Prefix::None | Prefix::DD | Prefix::FD => 252,
+                _ => 256,
+            };
+            assert_eq!(known, expected, "{:?}", prefix);
+        }
+
+        let info = |prefix, op| InstructionInfo::lookup(prefix, op).unwrap();
+        let djnz = info(Prefix::None, 0x10);
+        assert_eq!(djnz.mnemonic, Mnemonic::Djnz);
+        assert_eq!(
+            (djnz.length, djnz.t_states, djnz.t_states_taken),
+            (2, 8, 13)
+        );
+        assert_eq!(info(Prefix::ED, 0x4B).length, 4);
+        assert_eq!(info(Prefix::DD, 0x21).t_states, 14);
+        assert_eq!(info(Prefix::FD, 0x34).t_states, 23);
+        assert_eq!(info(Prefix::CB, 0x46).t_states, 12);
+        let bit = info(Prefix::DDCB, 0x46);
+        assert_eq!(
+            (bit.mnemonic, bit.length, bit.t_states),
+            (Mnemonic::Bit, 4, 20)
+        );
+        assert!(InstructionInfo::lookup(Prefix::None, 0xCB).is_none());
+    }
+
+    #[test]
+    fn test_dump_range() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, b"Hello, Z80!\x00\x01\xFF");
+        assert_eq!(
+            i.dump_range(0x0100, 20),
+            "0100  48 65 6C 6C 6F 2C 20 5A  38 30 21 00 01 FF 00 00  |Hello, Z80!.....|\n\
+             0110  00 00 00 00                                       |....|\n"
+        );
+        assert_eq!(i.dump_range(0x0100, 0), "");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        use crate::debugger::StopReason;
+        // NOPs followed by JP 0x0100
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x00, 0x00, 0x00, 0xC3, 0x00, 0x01]);
+        assert!(i.breakpoints.add(0x0102));
+        assert!(!i.breakpoints.add(0x0102));
+        assert!(i.breakpoints.add(0x0100));
+
+        assert_eq!(i.step().stop, None);
+        let result = i.step();
+        assert_eq!(result.cycles, 4);
+        assert_eq!(result.stop, Some(StopReason::Breakpoint(0x0102)));
+        // Resuming executes the instruction at the breakpoint
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, Some(StopReason::Breakpoint(0x0100)));
+
+        assert!(i.breakpoints.enable(0x0102, false));
+        assert!(!i.breakpoints.enable(0x0200, false));
+        i.step();
+        assert_eq!(i.step().stop, None);
+
+        let hits: Vec<(u16, usize)> = i.breakpoints.list().map(|b| (b.addr, b.hits)).collect();
+        assert_eq!(hits, vec![(0x0100, 1), (0x0102, 1)]);
+
+        // A frame is cut short once a breakpoint is hit
+        assert!(i.breakpoints.remove(0x0102));
+        i.execute_cpu();
+        assert_eq!(i.stopped, Some(StopReason::Breakpoint(0x0100)));
+        assert_eq!(i.cpu.reg.pc, 0x0100);
+    }
+
+    #[test]
+    fn test_step_over_out() {
+        // LD SP, 0x8000; CALL 0x0200; NOP
+        // 0x0200: DEC A; CALL NZ, 0x0200; RET
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x31, 0x00, 0x80, 0xCD, 0x00, 0x02, 0x00]);
+        i.cpu
+            .memory
+            .load_slice(0x0200, &[0x3D, 0xC4, 0x00, 0x02, 0xC9]);
+        i.cpu.reg.a = 3;
+
+        // Not a call, same as a single step
+        assert_eq!(i.step_over().cycles, 10);
+        assert_eq!(i.cpu.reg.pc, 0x0103);
+        let result = i.step_over();
+        assert_eq!(result.stop, None);
+        assert_eq!(
+            (i.cpu.reg.pc, i.cpu.reg.sp, i.cpu.reg.a),
+            (0x0106, 0x8000, 0)
+        );
+        assert!(result.cycles > 17 * 3);
+
+        // The recursive calls return to 0x0204 as well but deeper in the stack
+        i.cpu.reg.a = 3;
+        i.cpu.reg.pc = 0x0103;
+        i.step();
+        i.step();
+        i.step_over();
+        assert_eq!(
+            (i.cpu.reg.pc, i.cpu.reg.sp, i.cpu.reg.a),
+            (0x0204, 0x7FFE, 0)
+        );
+        i.step_out();
+        assert_eq!((i.cpu.reg.pc, i.cpu.reg.sp), (0x0106, 0x8000));
+
+        // Breakpoints still stop execution
+        i.cpu.reg.a = 3;
+        i.cpu.reg.pc = 0x0103;
+        i.step();
+        i.breakpoints.add(0x0204);
+        assert!(i.step_out().stop.is_some());
+        assert_eq!((i.cpu.reg.pc, i.cpu.reg.sp), (0x0204, 0x7FFA));
+        i.breakpoints.clear();
+        i.step_out();
+        assert_eq!((i.cpu.reg.pc, i.cpu.reg.sp), (0x0204, 0x7FFC));
+    }
+
+    #[test]
+    fn test_history() {
+        // LD A, 5; DEC A; JR NZ, -3; LD HL, 0x1234
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x3D, 0x20, 0xFD, 0x21, 0x34, 0x12]);
+        i.step();
+        assert!(i.history.is_empty());
+
+        i.history.set_depth(3);
+        for _ in 0..4 {
+            i.step();
+        }
+        let pcs: Vec<u16> = i.history.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![0x0103, 0x0102, 0x0103]);
+        let last = i.history.last().unwrap();
+        assert_eq!(last.opcode_bytes(), &[0x20, 0xFD]);
+        assert_eq!(last.af >> 8, 3);
+        assert_eq!(last.cycles, i.cpu.cycles);
+        assert_eq!(
+            i.history.dump(1),
+            format!(
+                "0103  20 FD       JR NZ, $0102        \
+                 AF:0303 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:FFFF cyc:{}\n",
+                i.cpu.cycles
+            )
+        );
+
+        i.history.set_depth(1);
+        assert_eq!(i.history.len(), 1);
+        i.history.set_depth(0);
+        assert!(i.history.is_empty());
+        i.step();
+        assert!(i.history.is_empty());
+    }
+
+    #[test]
+    fn test_trace_file() {
+        use crate::trace::TraceFormat;
+        let path = std::env::temp_dir().join("z80-rs-trace-test.log");
+        // LD A, 5; LD BC, 0x1234; NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x01, 0x34, 0x12, 0x00]);
+        i.trace_to_file(&path, TraceFormat::Text).unwrap();
+        for _ in 0..3 {
+            i.step();
+        }
+        i.flush_trace();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            trace,
+            "0100 3E05     AF=05FF BC=0000 DE=0000 HL=0000 IX=0000 IY=0000 SP=FFFF CYC=7\n\
+             0102 013412   AF=05FF BC=1234 DE=0000 HL=0000 IX=0000 IY=0000 SP=FFFF CYC=17\n\
+             0105 00       AF=05FF BC=1234 DE=0000 HL=0000 IX=0000 IY=0000 SP=FFFF CYC=21\n"
+        );
+
+        i.cpu.reg.pc = 0x0100;
+        i.trace_to_file(&path, TraceFormat::Csv).unwrap();
+        i.step();
+        i.trace_to_file(path.with_extension("json"), TraceFormat::Json)
+            .unwrap();
+        i.step();
+        i.flush_trace();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "pc,op,af,bc,de,hl,ix,iy,sp,cycles\n0100,3E05,05FF,1234,0000,0000,0000,0000,FFFF,28\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path.with_extension("json")).unwrap(),
+            "{\"pc\":258,\"op\":\"013412\",\"af\":1535,\"bc\":4660,\"de\":0,\"hl\":0,\
+             \"ix\":0,\"iy\":0,\"sp\":65535,\"cycles\":38}\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+    }
+
+    #[test]
+    fn test_trace_template() {
+        use crate::trace::TraceTemplate;
+        // LD BC, 0x12AB; SCF
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &[0x01, 0xAB, 0x12, 0x37]);
+        i.cpu.flags.set(0);
+        i.history.set_depth(2);
+        i.step();
+        i.step();
+        let ld = *i.history.iter().next().unwrap();
+        let scf = *i.history.last().unwrap();
+
+        let parse = |s: &str| s.parse::<TraceTemplate>().unwrap();
+        let default = parse(
+            "{pc} {op:8} AF={af} BC={bc} DE={de} HL={hl} IX={ix} IY={iy} SP={sp} CYC={cycles}",
+        );
+        assert_eq!(default.line(&ld).to_string(), ld.line());
+        let custom = parse("{pc:x}: {bytes:<9}|{asm:14}|{b}{c:x} {flags} {cyc:>3} {{}}");
+        assert_eq!(
+            custom.line(&ld).to_string(),
+            "0100: 01 AB 12 |LD BC, $12AB  |12ab ........  10 {}"
+        );
+        assert_eq!(
+            custom.line(&scf).to_string(),
+            "0103: 37       |SCF           |12ab ..Y.X..C  14 {}"
+        );
+
+        assert!("{pc".parse::<TraceTemplate>().is_err());
+        assert!("{ix:y}".parse::<TraceTemplate>().is_err());
+        assert!("{foo}".parse::<TraceTemplate>().is_err());
+        assert!("pc}".parse::<TraceTemplate>().is_err());
+    }
+
+    #[test]
+    fn test_trace_compression() {
+        use crate::trace::TraceFormat;
+        let path = std::env::temp_dir().join("z80-rs-trace-loop.log");
+        // LD A, 4; DEC A; JR NZ, -3; JP 0x0105
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x04, 0x3D, 0x20, 0xFD, 0xC3, 0x05, 0x01]);
+        i.trace_to_file(&path, TraceFormat::Text).unwrap();
+        i.tracer.as_mut().unwrap().set_compression(8);
+        for _ in 0..15 {
+            i.step();
+        }
+        // Dropping the writer reports the loop that is still running
+        i.tracer = None;
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let pcs: Vec<&str> = trace.lines().map(|l| &l[..4]).collect();
+        assert_eq!(
+            pcs,
+            vec!["0100", "0102", "0103", "0102", "0103", "# ..", "0105", "0105", "# .."]
+        );
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[5],
+            "# ... last 2 instructions repeated 2 times (4 lines skipped)"
+        );
+        assert_eq!(
+            lines[8],
+            "# ... last 1 instructions repeated 4 times (4 lines skipped)"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        use crate::trace::{OpClass, TraceFormat};
+        let path = std::env::temp_dir().join("z80-rs-trace-filter.log");
+        // LD SP, 0x8000; CALL 0x0200; OUT (0x10), A; NOP
+        // 0x0200: NOP; RET
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[0x31, 0x00, 0x80, 0xCD, 0x00, 0x02, 0xD3, 0x10, 0x00],
+        );
+        i.cpu.memory.load_slice(0x0200, &[0x00, 0xC9]);
+        i.trace_to_file(&path, TraceFormat::Text).unwrap();
+        let filter = &mut i.tracer.as_mut().unwrap().filter;
+        filter.add_ranges("0100-01FF").unwrap();
+        filter.add_classes("call,ret,io").unwrap();
+        assert_eq!(
+            filter.classes,
+            vec![OpClass::Call, OpClass::Return, OpClass::Io]
+        );
+        assert!(filter.add_ranges("0100-XYZ").is_err());
+        assert!(filter.add_classes("alu").is_err());
+        for _ in 0..6 {
+            i.step();
+        }
+        i.tracer = None;
+
+        // RET at 0x0201 is outside the range, NOP and LD aren't in the classes
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let pcs: Vec<&str> = trace.lines().map(|l| &l[..4]).collect();
+        assert_eq!(pcs, vec!["0103", "0106"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trace_symbols() {
+        use crate::trace::TraceFormat;
+        let path = std::env::temp_dir().join("z80-rs-trace-symbols.log");
+        // start: LD A, 5; JP done; done: NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0xC3, 0x05, 0x01, 0x00]);
+        i.symbols.insert("start", 0x0100);
+        i.symbols.insert("done", 0x0105);
+        i.trace_to_file(&path, TraceFormat::Text).unwrap();
+        for _ in 0..3 {
+            i.step();
+        }
+        i.flush_trace();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "# start:");
+        assert!(lines[1].starts_with("0100 3E05"));
+        assert!(lines[2].starts_with("0102 C30501"));
+        assert_eq!(lines[3], "# done:");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Returns a new value for every read and pulses /INT every 100 cycles
+    #[derive(Default)]
+    struct Sensor {
+        cycles: usize,
+        reads: u8,
+        irq: bool,
+    }
+
+    impl Device for Sensor {
+        fn tick(&mut self, cycles: usize) {
+            self.irq = (self.cycles + cycles) / 100 != self.cycles / 100;
+            self.cycles += cycles;
+        }
+        fn io_read(&mut self, _port: u16) -> u8 {
+            self.reads = self.reads.wrapping_add(7);
+            self.reads
+        }
+        fn pending_interrupt(&self) -> Option<u8> {
+            self.irq.then_some(0xFF)
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        use crate::snapshot::Snapshot;
+        // EI; loop: IN A, (0x10); ADD A, B; LD B, A; JP loop
+        // 0x38: INC C; EI; RET
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0xFB, 0xDB, 0x10, 0x80, 0x47, 0xC3, 0x01, 0x01]);
+        i.cpu.memory.load_slice(0x0038, &[0x0C, 0xFB, 0xC9]);
+        i.cpu.int.mode = 1;
+        let sensor = i.add_device(Sensor::default());
+        i.register_port(0x10..=0x10, sensor);
+        // A checkpoint every 50 cycles
+        i.clock_speed = 60 * 50;
+        i.enable_rewind(100);
+
+        let mut states = vec![Snapshot::capture(&i.cpu)];
+        for _ in 0..300 {
+            i.step();
+            states.push(Snapshot::capture(&i.cpu));
+        }
+        assert!(i.cpu.reg.c > 0);
+
+        assert!(i.step_back(1));
+        assert_eq!(Snapshot::capture(&i.cpu), states[299]);
+        assert!(i.step_back(57));
+        assert_eq!(Snapshot::capture(&i.cpu), states[242]);
+        assert!(i.cpu.input.replaying());
+        // Going forward again repeats the same reads and interrupts
+        for state in &states[243..] {
+            i.step();
+            assert_eq!(&Snapshot::capture(&i.cpu), state);
+        }
+        assert!(!i.cpu.input.replaying());
+
+        assert!(i.rewind_frames(2));
+        assert!(i.cpu.cycles < states[300].cycles as usize);
+        assert!(!i.step_back(10_000));
+        i.disable_rewind();
+        assert!(!i.step_back(1));
+    }
+
+    #[test]
+    fn test_rewind_paging() {
+        use crate::snapshot::Snapshot;
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let banks = i.cpu.memory.alloc_ram(2 * 0x4000);
+        i.cpu.memory.map_bank(0xC000, 0x4000, Page::Ram(banks));
+        i.cpu
+            .memory
+            .add_paging_register(0x8002, 0x0000, move |memory, value| {
+                let bank = (value & 0x01) as usize;
+                memory.map_bank(0xC000, 0x4000, Page::Ram(banks + bank * 0x4000));
+            });
+        // LD A, 11; LD (C000), A; LD A, 1; OUT (FD), A; LD A, 22; LD (C000), A; JP $
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0x3E, 0x11, 0x32, 0x00, 0xC0, 0x3E, 0x01, 0xD3, 0xFD, 0x3E, 0x22, 0x32, 0x00, 0xC0,
+                0xC3, 0x0E, 0x01,
+            ],
+        );
+        i.cpu.reg.pc = 0x0100;
+        // /INT held with interrupts disabled
+        i.cpu.int_request(0xD7);
+        // A checkpoint every step
+        i.clock_speed = 60;
+        i.enable_rewind(10);
+
+        let state = |i: &Interconnect| (Snapshot::capture(&i.cpu), i.cpu.memory.ram.clone());
+        let mut states = vec![state(&i)];
+        for _ in 0..6 {
+            i.step();
+            states.push(state(&i));
+        }
+        assert_eq!(i.cpu.memory.ram[banks + 0x4000], 0x22);
+
+        // The paging register isn't checkpointed, so there's no going back past the OUT
+        assert_eq!(i.rewind.as_ref().unwrap().oldest(), Some(4));
+        assert!(!i.step_back(4));
+
+        // After it the banks and the page map are put back
+        assert!(i.step_back(1));
+        assert_eq!(i.cpu.memory.page(0x30), Page::Ram(banks + 0x4000));
+        assert_eq!(i.cpu.memory.ram[banks], 0x11);
+        assert_eq!(i.cpu.memory.ram[banks + 0x4000], 0x00);
+        assert!(i.cpu.int.irq);
+        assert_eq!(state(&i), states[5]);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        use crate::snapshot::Snapshot;
+        let path = std::env::temp_dir().join("z80-rs-inputs.log");
+        let machine = |sensor: Sensor| {
+            let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+            i.cpu
+                .memory
+                .load_slice(0x0100, &[0xFB, 0xDB, 0x10, 0x80, 0x47, 0xC3, 0x01, 0x01]);
+            i.cpu.memory.load_slice(0x0038, &[0x0C, 0xFB, 0xC9]);
+            i.cpu.int.mode = 1;
+            let sensor = i.add_device(sensor);
+            i.register_port(0x10..=0x10, sensor);
+            i
+        };
+        let mut i = machine(Sensor::default());
+        i.record_inputs();
+        for _ in 0..200 {
+            i.step();
+        }
+        i.save_inputs(&path).unwrap();
+        let recorded = Snapshot::capture(&i.cpu);
+
+        // A sensor that reads and interrupts differently is overridden by the log
+        let mut replayed = machine(Sensor {
+            cycles: 50,
+            reads: 100,
+            irq: false,
+        });
+        replayed.replay_inputs(&path).unwrap();
+        for _ in 0..200 {
+            replayed.step();
+        }
+        assert_eq!(Snapshot::capture(&replayed.cpu), recorded);
+        assert!(!replayed.cpu.input.replaying());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_trace_mame() {
+        use crate::trace::{GoldenTrace, TraceFormat};
+        use std::io::{BufReader, Cursor};
+
+        let path = std::env::temp_dir().join("z80-rs-trace-mame.log");
+        // LD IX, 0x8000; LD (IX-2), A; INC (IX+5); OUT (0xFE), A; EX AF, AF'; IN A, (C)
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0xDD, 0x21, 0x00, 0x80, 0xDD, 0x77, 0xFE, 0xDD, 0x34, 0x05, 0xD3, 0xFE, 0x08, 0xED,
+                0x78,
+            ],
+        );
+        i.trace_to_file(&path, TraceFormat::Mame).unwrap();
+        for _ in 0..6 {
+            i.step();
+        }
+        i.flush_trace();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "0100: ld   ix,$8000\n\
+             0104: ld   (ix-$02),a\n\
+             0107: inc  (ix+$05)\n\
+             010A: out  ($FE),a\n\
+             010C: ex   af,af'\n\
+             010D: in   a,(c)\n"
+        );
+
+        // LD A, 4; DEC A; JR NZ, -3; JP 0x0105
+        let program = [0x3E, 0x04, 0x3D, 0x20, 0xFD, 0xC3, 0x05, 0x01];
+        i.cpu.memory.load_slice(0x0100, &program);
+        i.cpu.reg.pc = 0x0100;
+        i.trace_to_file(&path, TraceFormat::Mame).unwrap();
+        i.tracer.as_mut().unwrap().set_compression(64);
+        for _ in 0..12 {
+            i.step();
+        }
+        i.tracer = None;
+        let trace = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            trace,
+            "0100: ld   a,$04\n\
+             0102: dec  a\n\
+             0103: jr   nz,$0102\n\
+             \n   (loops for 6 instructions)\n\n\
+             0105: jp   $0105\n\
+             \n   (loops for 2 instructions)\n\n"
+        );
+
+        // The trace works as a reference, loops included
+        i.cpu.reg.pc = 0x0100;
+        i.golden = Some(GoldenTrace::new(BufReader::new(Cursor::new(trace))));
+        for _ in 0..12 {
+            assert_eq!(i.step().stop, None);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // Registers logged in front of the PC are from before the instruction
+        let reference = "AF=0000 0100: ld   a,$04\n\
+                         AF=0400 0102: dec  a\n\
+                         AF=0400 0103: jr   nz,$0102\n";
+        i.cpu.reg.pc = 0x0100;
+        i.cpu.flags.set(0);
+        i.golden = Some(GoldenTrace::new(Cursor::new(reference)));
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(crate::debugger::StopReason::TraceMismatch(3))
+        );
+    }
+
+    #[test]
+    fn test_golden_trace() {
+        use crate::debugger::StopReason;
+        use crate::trace::GoldenTrace;
+        use std::io::Cursor;
+
+        // LD A, 5; LD BC, 0x1234; INC A; NOP
+        let reference = "# comment\n\
+            0100 3E05     AF=05FF BC=0000 DE=0000 HL=0000 IX=0000 IY=0000 SP=FFFF CYC=7\n\
+            \n\
+            0102 pc:0102 BC:1234 CYC=999\n\
+            0105 3C       AF=0701 BC=1234\n";
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x01, 0x34, 0x12, 0x3C, 0x00]);
+        i.history.set_depth(8);
+        i.golden = Some(GoldenTrace::new(Cursor::new(reference)));
+
+        assert_eq!(i.step().stop, None);
+        // Cycles are only compared on request
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, Some(StopReason::TraceMismatch(5)));
+        let report = i.golden.as_ref().unwrap().report(&i.history, 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Trace mismatch at reference line 5");
+        assert_eq!(lines[1], "expected: 0105 3C       AF=0701 BC=1234");
+        assert!(lines[2].starts_with("actual:   0105 3C       AF=0601 BC=1234"));
+        assert_eq!(lines[3], "differs:  AF");
+        assert_eq!(lines[4], "Last 2 instructions:");
+        assert!(lines[5].starts_with("0102  01 34 12"));
+        assert!(lines[6].starts_with("0105  3C"));
+
+        assert_eq!(i.step().stop, Some(StopReason::TraceEnd(5)));
+        assert!(i.golden.is_none());
+
+        i.cpu.reg.pc = 0x0100;
+        i.golden = Some(GoldenTrace::new(Cursor::new("0100 CYC=1\n")));
+        i.golden.as_mut().unwrap().compare_cycles = true;
+        assert_eq!(i.step().stop, Some(StopReason::TraceMismatch(1)));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        use crate::debugger::StopReason;
+        use crate::expr::Expr;
+        // LD A, 5; DEC A; JR NZ, -3; NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x3D, 0x20, 0xFD, 0x00]);
+        i.breakpoints.add(0x0103);
+        let condition = Expr::parse("A == 2 && !ZF").unwrap();
+        assert!(i.breakpoints.set_condition(0x0103, Some(condition)));
+        assert!(!i.breakpoints.set_condition(0x0200, None));
+
+        let mut steps = 0;
+        while i.step().stop.is_none() {
+            steps += 1;
+        }
+        assert_eq!(steps, 5);
+        assert_eq!(i.cpu.reg.a, 2);
+        assert_eq!(i.breakpoints.get(0x0103).unwrap().hits, 1);
+
+        i.breakpoints.set_condition(0x0103, None);
+        i.step();
+        assert_eq!(i.step().stop, Some(StopReason::Breakpoint(0x0103)));
+        assert_eq!(i.cpu.reg.a, 1);
+    }
+
+    #[test]
+    fn test_break_on_events() {
+        use crate::debugger::{BreakEvent, StopReason};
+        // LD SP, 0x8000; RST 08; NOP
+        // 0x0008: LD (0x7FF0), A; RETI
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x31, 0x00, 0x80, 0xCF, 0x00]);
+        i.cpu
+            .memory
+            .load_slice(0x0008, &[0x32, 0xF0, 0x7F, 0xED, 0x4D]);
+
+        // Nothing stops while the switches are off
+        assert!(!i.cpu.break_on.any());
+        for _ in 0..4 {
+            assert_eq!(i.step().stop, None);
+        }
+        assert_eq!(i.cpu.reg.pc, 0x0104);
+
+        i.cpu.break_on.set_all(true);
+        i.cpu.reg.pc = 0x0100;
+        // Pushing the return address doesn't count as a stack write
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::Rst(0x0008)))
+        );
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::StackWrite {
+                addr: 0x7FF0,
+                sp: 0x7FFE
+            }))
+        );
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::Reti { nmi: false }))
+        );
+        assert_eq!(i.cpu.reg.pc, 0x0104);
+
+        i.cpu.int.mode = 1;
+        i.cpu.int.iff1 = true;
+        i.cpu.int_request(0xFF);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::Interrupt {
+                nmi: false,
+                vector: 0xFF
+            }))
+        );
+        assert_eq!(i.cpu.reg.pc, 0x0038);
+
+        i.cpu.break_on.stack_write = false;
+        i.cpu.reg.pc = 0x0008;
+        assert_eq!(i.step().stop, None);
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        use crate::debugger::{BreakEvent, StopReason};
+
+        // LD SP, 0x8000; PUSH BC; PUSH BC; POP BC; POP BC; POP BC
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x31, 0x00, 0x80, 0xC5, 0xC5, 0xC1, 0xC1, 0xC1]);
+        i.cpu.events.enabled = true;
+        i.cpu.break_on.stack_bounds = Some(0x7FFE..=0x8000);
+        assert!(i.cpu.break_on.any());
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::StackBounds {
+                sp: 0x7FFC,
+                pc: 0x0104
+            }))
+        );
+        // Reported once until SP is back within bounds
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::StackBounds {
+                sp: 0x8002,
+                pc: 0x0107
+            }))
+        );
+        let events: Vec<Event> = i.cpu.events.drain().collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::StackBounds {
+                    sp: 0x7FFC,
+                    pc: 0x0104
+                },
+                Event::StackBounds {
+                    sp: 0x8002,
+                    pc: 0x0107
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assembled_program() {
+        use crate::assembler::assemble;
+
+        // Sums 1 to 10 and stores the result
+        let program = assemble(
+            "
+        ORG 0100h
+        XOR A
+        LD B, 10
+loop:   ADD A, B
+        DJNZ loop
+        LD (result), A
+        HALT
+result: DB 0
+",
+        )
+        .unwrap();
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        program.load(&mut i.cpu.memory);
+        while !i.cpu.int.halt {
+            i.step();
+        }
+        assert_eq!(i.cpu.reg.a, 55);
+        assert_eq!(i.peek(program.symbols.addr("result").unwrap()), 55);
+    }
+
+    #[test]
+    fn test_memory_traps() {
+        use crate::debugger::{BreakEvent, StopReason, Trap};
+
+        // LD A, 0x55; LD (0x0010), A; LD (0x8000), A; JP 0x5100
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0x3E, 0x55, 0x32, 0x10, 0x00, 0x32, 0x00, 0x80, 0xC3, 0x00, 0x51,
+            ],
+        );
+        i.cpu.memory.map(0x0000..=0x00FF, Region::Rom);
+        i.cpu.memory.map(0x5100..=0x51FF, Region::Unmapped);
+        i.cpu.break_on.rom_write = Trap::Break;
+        i.cpu.break_on.unmapped_exec = Trap::Break;
+
+        i.step();
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::RomWrite {
+                addr: 0x0010,
+                value: 0x55,
+                pc: 0x0102
+            }))
+        );
+        assert_eq!(i.peek(0x0010), 0x00);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Event(BreakEvent::UnmappedExec(0x5100)))
+        );
+
+        // Logged traps keep running
+        i.cpu.break_on.unmapped_exec = Trap::Log;
+        i.cpu.reg.pc = 0x5100;
+        assert_eq!(i.step().stop, None);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        use crate::debugger::{StopReason, WatchHit, WatchKind};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LD A, 0x42; LD (0x8000), A; LD A, (0x8001); LD (0x9000), A; NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[
+                0x3E, 0x42, 0x32, 0x00, 0x80, 0x3A, 0x01, 0x80, 0x32, 0x00, 0x90, 0x00,
+            ],
+        );
+        i.poke(0x8000, 0x11);
+        i.poke(0x8001, 0x22);
+        let write = i.cpu.watchpoints.add(0x8000..=0x80FF, WatchKind::Write);
+        let read = i.cpu.watchpoints.add(0x8000..=0x80FF, WatchKind::Read);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        i.cpu
+            .watchpoints
+            .add_callback(0x9000..=0x9000, WatchKind::Access, move |hit| {
+                log.borrow_mut().push(*hit)
+            });
+
+        // Opcode and operand fetches don't trigger watchpoints
+        i.cpu.watchpoints.add(0x0100..=0x0101, WatchKind::Read);
+        assert_eq!(i.step().stop, None);
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Watchpoint(WatchHit {
+                id: write,
+                pc: 0x0102,
+                addr: 0x8000,
+                kind: WatchKind::Write,
+                old: 0x11,
+                new: 0x42,
+            }))
+        );
+        assert_eq!(
+            i.step().stop,
+            Some(StopReason::Watchpoint(WatchHit {
+                id: read,
+                pc: 0x0105,
+                addr: 0x8001,
+                kind: WatchKind::Read,
+                old: 0x22,
+                new: 0x22,
+            }))
+        );
+        // Callbacks don't stop execution
+        assert_eq!(i.step().stop, None);
+        assert_eq!(seen.borrow().len(), 1);
+        assert_eq!(seen.borrow()[0].pc, 0x0108);
+        assert_eq!(seen.borrow()[0].new, 0x22);
+
+        assert!(i.cpu.watchpoints.enable(write, false));
+        assert!(i.cpu.watchpoints.remove(read));
+        assert!(!i.cpu.watchpoints.remove(read));
+        i.cpu.reg.pc = 0x0102;
+        assert_eq!(i.step().stop, None);
+        assert_eq!(i.peek(0x8000), 0x22);
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.map(0x0000..=0x0FFF, Region::Rom);
+        i.cpu.memory.map(0x8000..=0x8FFF, Region::Ram);
+        i.cpu.memory.map(0x9000..=0x9FFF, Region::Mirror(0x8000));
+        i.cpu.memory.map(0xA000..=0xFFFF, Region::Unmapped);
+        i.cpu.memory.open_bus = 0x7F;
+
+        i.cpu.memory[0x0010] = 0x11;
+        i.cpu.write8(0x0010, 0x22);
+        assert_eq!(i.cpu.read8(0x0010), 0x11);
+
+        i.cpu.write8(0x9010, 0x33);
+        assert_eq!(i.cpu.read8(0x8010), 0x33);
+        assert_eq!(i.cpu.read8(0x9010), 0x33);
+
+        i.cpu.write8(0xB000, 0x44);
+        assert_eq!(i.cpu.read8(0xB000), 0x7F);
+        assert_eq!(i.cpu.memory[0xB000], 0x7F);
+
+        // Indexing resolves mirrors the same way
+        i.cpu.memory[0x9020] = 0x55;
+        assert_eq!(i.cpu.read8(0x8020), 0x55);
+        assert_eq!(i.cpu.memory[0x8020], 0x55);
+
+        // Pac-Man mirrors the lower 32K and protects its ROM
+        let mut i = Interconnect::builder().preset(Preset::PacMan).build();
+        i.cpu.write8(0x4C00, 0x55);
+        assert_eq!(i.cpu.read8(0xCC00), 0x55);
+        i.cpu.write8(0x8000, 0x66);
+        assert_eq!(i.cpu.read8(0x0000), 0x00);
+    }
+
     #[test]
     fn fast_z80() {
         // Assert the tests executed CPU cycle amount vs real hardware cycle
@@ -117,27 +2115,17 @@ mod tests {
     }
 
     fn exec_test(bin: &str) -> usize {
-        let mut i = Interconnect::default();
-        i.cpu.reset();
-        i.cpu.memory.load_tests(bin);
-
-        // Patches the test rom(s) to intercept CP/M bdos routine
-        // Inject OUT *, A at 0x0000.
-        // Inject RET (0xC9) at 0x0007 to handle the return call.
-        // Inject IN, A * to store BDOS output
-        // If successful it should return to 0x0007.
-
-        i.cpu.memory.rom[0x0000] = 0xD3;
-        i.cpu.memory.rom[0x0001] = 0x00;
-        i.cpu.memory.rom[0x0005] = 0xDB;
-        i.cpu.memory.rom[0x0006] = 0x00;
-        i.cpu.memory.rom[0x0007] = 0xC9;
-
+        // Turn CPM Compatibility on. This turns off any memory mapping
         // All test binaries start at 0x0100.
-        i.cpu.reg.pc = 0x0100;
+        // The CP/M traps intercept BDOS calls, which return through 0x0007.
+        // A warm boot hits the OUT at 0x0000 and ends the test.
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .cpm_traps()
+            .build();
+        i.cpu.memory.load_tests(bin);
+        i.echo_console = true;
 
-        // Turn CPM Compatibility on. This turns off any memory mapping
-        i.cpu.cpm_compat = true;
         // i.cpu.debug = true;
 
         loop {
@@ -150,23 +2138,6 @@ mod tests {
                 assert_ne!(i.cpu.reg.pc, 0x76);
             }
 
-            if i.cpu.reg.pc == 07 {
-                if i.cpu.reg.c == 9 {
-                    let mut de = i.cpu.read_pair(DE);
-                    'print: loop {
-                        let output = i.cpu.memory.rom[de as usize];
-                        if output as char == '$' {
-                            break 'print;
-                        } else if output as char != '$' {
-                            de += 1;
-                        }
-                        print!("{}", output as char);
-                    }
-                }
-                if i.cpu.reg.c == 2 {
-                    print!("{}", i.cpu.reg.e as char);
-                }
-            }
             if i.cpu.opcode == 0xD3 {
                 break;
             } else if i.cpu.reg.pc == 0 {
@@ -180,6 +2151,8 @@ mod tests {
         }
         println!("Cycles executed: {}\n", i.cpu.cycles);
 
+        // The exercisers report failures on the console
+        assert!(!i.console.contains("ERROR"), "{}", i.console);
         i.cpu.cycles
     }
 }
@@ -0,0 +1,531 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use log::warn;
+
+use crate::cpu::Cpu;
+use crate::expr::Expr;
+use crate::symbols::Symbols;
+
+// Why `Interconnect::step` stopped before the next instruction
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Event(BreakEvent),
+    // Execution diverged from the golden trace at the given reference line
+    TraceMismatch(usize),
+    // The golden trace ran out after the given amount of lines
+    TraceEnd(usize),
+    // A PC hook registered at the address asked to stop
+    Hook(u16),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint(addr) => write!(f, "Breakpoint at {:04X}", addr),
+            StopReason::Watchpoint(hit) => write!(
+                f,
+                "Watchpoint {}: {:?} {:04X} {:02X} -> {:02X} at PC {:04X}",
+                hit.id, hit.kind, hit.addr, hit.old, hit.new, hit.pc
+            ),
+            StopReason::Event(BreakEvent::Interrupt { nmi: true, .. }) => write!(f, "NMI accepted"),
+            StopReason::Event(BreakEvent::Interrupt { vector, .. }) => {
+                write!(f, "Interrupt accepted, vector {:02X}", vector)
+            }
+            StopReason::Event(BreakEvent::Reti { nmi: true }) => write!(f, "RETN executed"),
+            StopReason::Event(BreakEvent::Reti { nmi: false }) => write!(f, "RETI executed"),
+            StopReason::Event(BreakEvent::Rst(addr)) => write!(f, "RST {:02X}", addr),
+            StopReason::Event(BreakEvent::StackWrite { addr, sp }) => {
+                write!(f, "Write to {:04X} below SP {:04X}", addr, sp)
+            }
+            StopReason::Event(BreakEvent::StackBounds { sp, pc }) => {
+                write!(f, "SP {:04X} out of stack bounds at PC {:04X}", sp, pc)
+            }
+            StopReason::Event(BreakEvent::RomWrite { addr, value, pc }) => write!(
+                f,
+                "Write of {:02X} to ROM {:04X} at PC {:04X}",
+                value, addr, pc
+            ),
+            StopReason::Event(BreakEvent::UnmappedExec(pc)) => {
+                write!(f, "Executing unmapped memory at {:04X}", pc)
+            }
+            StopReason::TraceMismatch(line) => {
+                write!(f, "Trace mismatch at reference line {}", line)
+            }
+            StopReason::TraceEnd(lines) => write!(f, "Reference trace ended after {} lines", lines),
+            StopReason::Hook(addr) => write!(f, "Stopped by the hook at {:04X}", addr),
+        }
+    }
+}
+
+// Outcome of a single `Interconnect::step`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StepResult {
+    pub cycles: usize,
+    pub stop: Option<StopReason>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub enabled: bool,
+    pub hits: usize,
+    // Only break when the condition evaluates to non zero
+    pub condition: Option<Expr>,
+}
+
+// Execution breakpoints keyed by address. A breakpoint is hit once PC lands on it,
+// i.e. the instruction at the address has not been executed yet.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    points: BTreeMap<u16, Breakpoint>,
+}
+
+impl Breakpoints {
+    // Returns false if a breakpoint already existed at the address
+    pub fn add(&mut self, addr: u16) -> bool {
+        if self.points.contains_key(&addr) {
+            return false;
+        }
+        self.points.insert(
+            addr,
+            Breakpoint {
+                addr,
+                enabled: true,
+                hits: 0,
+                condition: None,
+            },
+        );
+        true
+    }
+
+    pub fn remove(&mut self, addr: u16) -> bool {
+        self.points.remove(&addr).is_some()
+    }
+
+    // Returns false if there is no breakpoint at the address
+    pub fn enable(&mut self, addr: u16, enabled: bool) -> bool {
+        match self.points.get_mut(&addr) {
+            Some(bp) => {
+                bp.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Attaches (or with `None` removes) a condition, returns false if there is no
+    // breakpoint at the address
+    pub fn set_condition(&mut self, addr: u16, condition: Option<Expr>) -> bool {
+        match self.points.get_mut(&addr) {
+            Some(bp) => {
+                bp.condition = condition;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&Breakpoint> {
+        self.points.get(&addr)
+    }
+
+    // Breakpoints in address order
+    pub fn list(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.points.values()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    // Records a hit if an enabled breakpoint exists at PC and its condition holds
+    pub fn check(&mut self, cpu: &Cpu) -> bool {
+        match self.points.get_mut(&cpu.reg.pc) {
+            Some(bp) if bp.enabled && bp.condition.as_ref().is_none_or(|c| c.is_true(cpu)) => {
+                bp.hits += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BreakEvent {
+    Interrupt { nmi: bool, vector: u8 },
+    Reti { nmi: bool },
+    Rst(u16),
+    // Write to the free stack area just below SP, i.e. data the next push will clobber
+    StackWrite { addr: u16, sp: u16 },
+    // SP left `BreakOn::stack_bounds` while executing the instruction at `pc`
+    StackBounds { sp: u16, pc: u16 },
+    RomWrite { addr: u16, value: u8, pc: u16 },
+    // PC entered an address nothing is mapped at
+    UnmappedExec(u16),
+}
+
+// What happens when a trap is hit
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Trap {
+    #[default]
+    Off,
+    // Print the event and keep going
+    Log,
+    Break,
+}
+
+impl FromStr for Trap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Trap::Off),
+            "log" => Ok(Trap::Log),
+            "on" | "break" => Ok(Trap::Break),
+            _ => Err(format!("Expected off, log or break, found `{}`", s)),
+        }
+    }
+}
+
+// Switches for stopping on whole classes of events rather than specific addresses
+pub struct BreakOn {
+    pub interrupt: bool,
+    // RETI and RETN
+    pub reti: bool,
+    pub rst: bool,
+    pub stack_write: bool,
+    // How far below SP a write counts as a stack write
+    pub stack_window: u16,
+    // Legal values for SP, including the initial (empty stack) value
+    pub stack_bounds: Option<RangeInclusive<u16>>,
+    // SP is currently outside the bounds, so an excursion is only reported once
+    pub(crate) outside_stack: bool,
+    pub rom_write: Trap,
+    pub unmapped_exec: Trap,
+    triggered: Option<BreakEvent>,
+}
+
+impl Default for BreakOn {
+    fn default() -> Self {
+        Self {
+            interrupt: false,
+            reti: false,
+            rst: false,
+            stack_write: false,
+            stack_window: 0x100,
+            stack_bounds: None,
+            outside_stack: false,
+            rom_write: Trap::Off,
+            unmapped_exec: Trap::Off,
+            triggered: None,
+        }
+    }
+}
+
+impl BreakOn {
+    pub fn any(&self) -> bool {
+        self.interrupt
+            || self.reti
+            || self.rst
+            || self.stack_write
+            || self.stack_bounds.is_some()
+            || self.rom_write != Trap::Off
+            || self.unmapped_exec != Trap::Off
+    }
+
+    pub fn set_all(&mut self, enabled: bool) {
+        self.interrupt = enabled;
+        self.reti = enabled;
+        self.rst = enabled;
+        self.stack_write = enabled;
+        let trap = if enabled { Trap::Break } else { Trap::Off };
+        self.rom_write = trap;
+        self.unmapped_exec = trap;
+    }
+
+    // Records the event if its class is switched on, only the first event per step is kept
+    pub(crate) fn trigger(&mut self, event: BreakEvent) {
+        let enabled = match event {
+            BreakEvent::Interrupt { .. } => self.interrupt,
+            BreakEvent::Reti { .. } => self.reti,
+            BreakEvent::Rst(_) => self.rst,
+            BreakEvent::StackWrite { .. } => self.stack_write,
+            BreakEvent::StackBounds { .. } => self.stack_bounds.is_some(),
+            BreakEvent::RomWrite { .. } => self.trap(self.rom_write, event),
+            BreakEvent::UnmappedExec(_) => self.trap(self.unmapped_exec, event),
+        };
+        if enabled && self.triggered.is_none() {
+            self.triggered = Some(event);
+        }
+    }
+
+    // Logged traps don't stop execution
+    fn trap(&self, trap: Trap, event: BreakEvent) -> bool {
+        if trap == Trap::Log {
+            warn!("{}", StopReason::Event(event));
+        }
+        trap == Trap::Break
+    }
+
+    pub(crate) fn take(&mut self) -> Option<BreakEvent> {
+        self.triggered.take()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Either a read or a write
+    Access,
+}
+
+// A triggered watchpoint. `pc` is the address of the instruction performing the access,
+// for reads `old` and `new` are both the value read.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: u16,
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub old: u8,
+    pub new: u8,
+}
+
+pub type WatchCallback = Box<dyn FnMut(&WatchHit)>;
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    // Watchpoints with a callback invoke it instead of stopping execution
+    callback: Option<WatchCallback>,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u16, kind: WatchKind) -> bool {
+        self.enabled
+            && self.range.contains(&addr)
+            && (self.kind == kind || self.kind == WatchKind::Access)
+    }
+}
+
+// Memory watchpoints, checked by the CPU on every memory access. Hits are queued while
+// the instruction executes and handled by `Interconnect::step` once it completes, reads
+// of the instruction's own opcode and operand bytes are dropped at that point.
+#[derive(Default)]
+pub struct Watchpoints {
+    points: Vec<Watchpoint>,
+    next_id: usize,
+    // Address of the instruction currently executing
+    pub(crate) pc: u16,
+    hits: RefCell<Vec<WatchHit>>,
+}
+
+impl Watchpoints {
+    // Stops execution when `range` is accessed, returns the watchpoint id
+    pub fn add(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> usize {
+        self.insert(range, kind, None)
+    }
+
+    // Calls `callback` when `range` is accessed without stopping execution
+    pub fn add_callback<F: FnMut(&WatchHit) + 'static>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kind: WatchKind,
+        callback: F,
+    ) -> usize {
+        self.insert(range, kind, Some(Box::new(callback)))
+    }
+
+    fn insert(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kind: WatchKind,
+        callback: Option<WatchCallback>,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push(Watchpoint {
+            id,
+            range,
+            kind,
+            enabled: true,
+            callback,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.points.len();
+        self.points.retain(|w| w.id != id);
+        self.points.len() != len
+    }
+
+    // Returns false if there is no watchpoint with the given id
+    pub fn enable(&mut self, id: usize, enabled: bool) -> bool {
+        match self.points.iter_mut().find(|w| w.id == id) {
+            Some(w) => {
+                w.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.points.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.hits.borrow_mut().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    #[inline]
+    pub(crate) fn check(&self, addr: u16, kind: WatchKind, old: u8, new: u8) {
+        for w in self.points.iter().filter(|w| w.matches(addr, kind)) {
+            self.hits.borrow_mut().push(WatchHit {
+                id: w.id,
+                pc: self.pc,
+                addr,
+                kind,
+                old,
+                new,
+            });
+        }
+    }
+
+    pub(crate) fn triggered(&self) -> bool {
+        !self.hits.borrow().is_empty()
+    }
+
+    // Runs callbacks for the queued hits and returns the first one that should stop execution.
+    // `fetched` is the size of the instruction that was executed.
+    pub(crate) fn service(&mut self, fetched: u8) -> Option<WatchHit> {
+        let hits: Vec<WatchHit> = self.hits.borrow_mut().drain(..).collect();
+        let mut stop = None;
+        for hit in hits {
+            if hit.kind == WatchKind::Read && hit.addr.wrapping_sub(hit.pc) < fetched as u16 {
+                continue;
+            }
+            match self.points.iter_mut().find(|w| w.id == hit.id) {
+                Some(Watchpoint {
+                    callback: Some(callback),
+                    ..
+                }) => callback(&hit),
+                Some(_) if stop.is_none() => stop = Some(hit),
+                _ => {}
+            }
+        }
+        stop
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    Interrupt,
+    Nmi,
+}
+
+// An active subroutine or interrupt handler. `site` is the CALL / RST instruction, or the
+// interrupted PC for interrupts, `sp` is where the return address was pushed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    pub site: u16,
+    pub target: u16,
+    pub ret: u16,
+    pub sp: u16,
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            CallKind::Call => write!(f, "CALL {:04X} at {:04X}", self.target, self.site),
+            CallKind::Rst => write!(f, "RST {:02X} at {:04X}", self.target, self.site),
+            CallKind::Interrupt => write!(f, "INT {:04X}", self.target),
+            CallKind::Nmi => write!(f, "NMI {:04X}", self.target),
+        }
+    }
+}
+
+// Frames deeper than this are dropped from the bottom of the stack
+const MAX_FRAMES: usize = 1024;
+
+// Shadow call stack built from CALL / RST / interrupt entries. Returns aren't matched
+// against specific frames, instead any frame whose return address slot is popped (SP moves
+// above it) is dropped. That also copes with code that discards return addresses with POP,
+// or resets SP outright.
+#[derive(Debug, Default)]
+pub struct CallStack {
+    pub enabled: bool,
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    // Innermost frame last
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub(crate) fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn unwind(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    // `#0` is the current PC, followed by the return address of each frame
+    // with the nearest symbol where there is one
+    pub fn backtrace(&self, pc: u16, symbols: &Symbols) -> String {
+        let at = |addr: u16| match symbols.describe(addr) {
+            Some(name) => format!("{:04X} {}", addr, name),
+            None => format!("{:04X}", addr),
+        };
+        let mut out = format!("#0  {}\n", at(pc));
+        for (n, frame) in self.frames.iter().rev().enumerate() {
+            out.push_str(&format!("#{:<2} {}  {}\n", n + 1, at(frame.ret), frame));
+        }
+        out
+    }
+}
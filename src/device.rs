@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use crate::memory::Memory;
+
+pub type DeviceRef = Rc<RefCell<dyn Device>>;
+
+// A peripheral attached to the Interconnect (timers, UARTs, video latches etc).
+// Devices are ticked after every instruction with the amount of T states the CPU spent on it,
+// so they advance in lockstep with the CPU.
+pub trait Device {
+    fn tick(&mut self, _cycles: usize) {}
+
+    // Value placed on the data bus for an IN from one of the device's ports.
+    // The port is the full 16-bit address bus value (A, B or the upper byte on A8-A15).
+    fn io_read(&mut self, _port: u16) -> u8 {
+        0xFF
+    }
+
+    fn io_write(&mut self, _port: u16, _value: u8) {}
+
+    // Memory mapped registers, called for CPU accesses within a range the device is mapped to.
+    // The address is the one seen after mirrors are resolved.
+    fn mem_read(&mut self, _addr: u16) -> u8 {
+        0xFF
+    }
+
+    fn mem_write(&mut self, _addr: u16, _value: u8) {}
+
+    // Returns the vector (data bus value) if the device is asserting /INT
+    fn pending_interrupt(&self) -> Option<u8> {
+        None
+    }
+
+    // True if the request only lasts while `pending_interrupt` returns it, so it's withdrawn
+    // if the device lets go of /INT before the CPU takes it (the Spectrum's ULA holds it for
+    // 32 T states a frame). Otherwise the CPU holds a request until it's taken, which pulses
+    // from simple timers rely on.
+    fn level_interrupt(&self) -> bool {
+        false
+    }
+
+    // True if the device is pulling /NMI
+    fn pending_nmi(&self) -> bool {
+        false
+    }
+
+    // Z80 family peripherals (CTC, PIO, SIO) form an interrupt daisy chain: once the CPU
+    // accepts a device's interrupt it is under service until the device sees a RETI, and
+    // devices further down the chain can't interrupt meanwhile. The interconnect's device
+    // list is the chain, the first device has the highest priority.
+    fn interrupt_acknowledged(&mut self) {}
+
+    // Only sent to the highest priority device with an interrupt under service
+    fn reti(&mut self) {}
+
+    fn interrupt_in_service(&self) -> bool {
+        false
+    }
+
+    // True while the device pulls /BUSRQ. The CPU gives up the bus (BUSAK) before its next
+    // instruction and `bus_master` is called with it.
+    fn bus_request(&self) -> bool {
+        false
+    }
+
+    // Bus cycles run while the CPU is off the bus, returns the T states they took. The CPU
+    // waits for as long.
+    fn bus_master(&mut self, _memory: &mut Memory, _io: &mut IoBus) -> usize {
+        0
+    }
+
+    // Called after every `tick` so a video device can fetch the display from main memory as
+    // the beam goes over it
+    fn scan(&mut self, _memory: &Memory) {}
+
+    // Called after every `tick` with the last refresh address the CPU put on the bus (I in
+    // the high byte, R in the low), which the ZX81 takes its /INT from
+    fn refresh(&mut self, _ir: u16) {}
+}
+
+// How a device decodes the port address
+pub enum PortDecode {
+    // Only A0-A7 are decoded, as on most simple machines
+    Low(RangeInclusive<u8>),
+    // The device is selected when `port & mask == value` (e.g. 0x7FFD on the Spectrum 128)
+    Mask { mask: u16, value: u16 },
+}
+
+impl PortDecode {
+    #[inline]
+    pub(crate) fn matches(&self, port: u16) -> bool {
+        match self {
+            PortDecode::Low(range) => range.contains(&(port as u8)),
+            PortDecode::Mask { mask, value } => port & mask == *value,
+        }
+    }
+}
+
+pub type InHandler = Box<dyn FnMut(u16) -> u8>;
+pub type OutHandler = Box<dyn FnMut(u16, u8)>;
+
+// Routes IN / OUT instructions to the device registered for the port.
+// Ports without a device fall back to the in / out handlers if set, and reads from
+// ports nothing responds to return the floating bus value (0xFF on most machines).
+pub struct IoBus {
+    ports: Vec<(PortDecode, DeviceRef)>,
+    in_handler: Option<InHandler>,
+    out_handler: Option<OutHandler>,
+    pub float: u8,
+}
+
+impl Default for IoBus {
+    fn default() -> Self {
+        Self {
+            ports: Vec::new(),
+            in_handler: None,
+            out_handler: None,
+            float: 0xFF,
+        }
+    }
+}
+
+impl IoBus {
+    // Later registrations take precedence over earlier overlapping ones
+    pub fn register_port(&mut self, ports: RangeInclusive<u8>, device: DeviceRef) {
+        self.ports.insert(0, (PortDecode::Low(ports), device));
+    }
+
+    pub fn register_port_decoded(&mut self, mask: u16, value: u16, device: DeviceRef) {
+        self.ports
+            .insert(0, (PortDecode::Mask { mask, value }, device));
+    }
+
+    pub fn set_in_handler<F: FnMut(u16) -> u8 + 'static>(&mut self, handler: F) {
+        self.in_handler = Some(Box::new(handler));
+    }
+
+    pub fn set_out_handler<F: FnMut(u16, u8) + 'static>(&mut self, handler: F) {
+        self.out_handler = Some(Box::new(handler));
+    }
+
+    pub fn unregister_port(&mut self, port: u16) {
+        self.ports.retain(|(decode, _)| !decode.matches(port));
+    }
+
+    fn device(&self, port: u16) -> Option<&DeviceRef> {
+        self.ports
+            .iter()
+            .find(|(decode, _)| decode.matches(port))
+            .map(|(_, device)| device)
+    }
+
+    pub fn read(&mut self, port: u16) -> u8 {
+        match self.device(port) {
+            Some(device) => device.borrow_mut().io_read(port),
+            None => match self.in_handler {
+                Some(ref mut handler) => handler(port),
+                None => self.float,
+            },
+        }
+    }
+
+    // Returns false if nothing is listening on the port
+    pub fn write(&mut self, port: u16, value: u8) -> bool {
+        match self.device(port) {
+            Some(device) => {
+                device.borrow_mut().io_write(port, value);
+                true
+            }
+            None => match self.out_handler {
+                Some(ref mut handler) => {
+                    handler(port, value);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
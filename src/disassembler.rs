@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::formatter::HexBytes;
+use crate::instruction_info::{Instruction, Mnemonic, Operand};
+use crate::memory::{Memory, ADDRESS_SPACE};
+use crate::symbols::Symbols;
+
+// A disassembled instruction. Bytes that don't form a complete instruction are shown as a
+// single data byte.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+}
+
+impl DisasmLine {
+    pub fn size(&self) -> u8 {
+        self.bytes.len() as u8
+    }
+
+    // Address of the following instruction
+    pub fn next(&self) -> u16 {
+        self.addr.wrapping_add(self.size() as u16)
+    }
+
+    // The mnemonic, with branch targets named if there's a symbol for them
+    pub fn text(&self, symbols: &Symbols) -> String {
+        let instruction = match &self.instruction {
+            Some(instruction) => instruction,
+            None => return format!("DB ${:02X}", self.bytes[0]),
+        };
+        let text = instruction.at(self.addr).to_string();
+        match instruction
+            .branch_target(self.addr)
+            .filter(|_| instruction.mnemonic != Mnemonic::Rst)
+            .and_then(|target| Some((target, symbols.name(target)?)))
+        {
+            Some((target, name)) => text.replace(&format!("${:04X}", target), name),
+            None => text,
+        }
+    }
+
+    // The Display line with symbol names, see `text`
+    pub fn with_symbols(&self, symbols: &Symbols) -> String {
+        let hex = HexBytes(&self.bytes, " ");
+        format!("{:04X}  {:<12}{}", self.addr, hex, self.text(symbols))
+    }
+}
+
+// `addr  bytes  mnemonic`, e.g. `0100  3E 05       LD A, $05`. Relative jumps show the
+// destination address.
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.with_symbols(&Symbols::default()))
+    }
+}
+
+// Disassembles the instruction at `addr`. Memory is read without side effects, so memory
+// mapped devices aren't touched.
+pub fn disassemble_at(memory: &Memory, addr: u16) -> DisasmLine {
+    let bytes: Vec<u8> = (0..4).map(|n| memory.peek(addr.wrapping_add(n))).collect();
+    let instruction = Instruction::decode_bytes(&bytes);
+    let size = instruction.map_or(1, |i| i.size) as usize;
+    DisasmLine {
+        addr,
+        bytes: bytes[..size].to_vec(),
+        instruction,
+    }
+}
+
+// Disassembles every instruction starting between `start` and `end` (inclusive), the last
+// one may extend past `end`
+pub fn disassemble(memory: &Memory, start: u16, end: u16) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let line = disassemble_at(memory, addr as u16);
+        addr += line.size() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+// Finds where to start disassembling so that up to `before` instructions are shown ahead of
+// `addr`. Code can't be decoded backwards reliably so this picks the furthest start address
+// whose instruction stream lands exactly on `addr`.
+pub fn context_start(memory: &Memory, addr: u16, before: usize) -> u16 {
+    for back in (1..=before as u16 * 4).rev() {
+        let start = addr.wrapping_sub(back);
+        let mut pc = start;
+        let mut starts = Vec::new();
+        while pc.wrapping_sub(start) < back {
+            starts.push(pc);
+            pc = disassemble_at(memory, pc).next();
+        }
+        if pc == addr {
+            return starts[starts.len().saturating_sub(before)];
+        }
+    }
+    addr
+}
+
+// Up to `before` instructions leading to `addr`, the one at `addr` and `after` more
+pub fn context(memory: &Memory, addr: u16, before: usize, after: usize) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut pc = context_start(memory, addr, before);
+    while pc != addr {
+        let line = disassemble_at(memory, pc);
+        pc = line.next();
+        lines.push(line);
+    }
+    for _ in 0..=after {
+        let line = disassemble_at(memory, pc);
+        pc = line.next();
+        lines.push(line);
+    }
+    lines
+}
+
+// Whether the assembler would encode the instruction's text back to the same bytes.
+// Prefixes that have no effect, undefined ED opcodes and the ED duplicates of NEG, RETN, IM
+// and LD (nn), HL / LD HL, (nn) don't, so they're emitted as data.
+fn reassembles(line: &DisasmLine) -> bool {
+    let instruction = match &line.instruction {
+        Some(instruction) => instruction,
+        None => return false,
+    };
+    match (instruction.prefix, instruction.mnemonic) {
+        (_, Mnemonic::Nop) => line.bytes == [0x00],
+        (0xDD | 0xFD, _) => {
+            let text = instruction.to_string();
+            text.contains("IX") || text.contains("IY")
+        }
+        (0xED, Mnemonic::Neg) => instruction.opcode == 0x44,
+        (0xED, Mnemonic::Retn) => instruction.opcode == 0x45,
+        (0xED, Mnemonic::Im) => matches!(instruction.opcode, 0x46 | 0x56 | 0x5E),
+        (0xED, Mnemonic::Ld) => !matches!(instruction.opcode, 0x63 | 0x6B),
+        _ => true,
+    }
+}
+
+// Execution doesn't continue with the following instruction
+fn ends_flow(instruction: &Instruction) -> bool {
+    let conditional = matches!(instruction.dst, Some(Operand::Condition(_)));
+    match instruction.mnemonic {
+        Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Ret => !conditional,
+        Mnemonic::Reti | Mnemonic::Retn => true,
+        _ => false,
+    }
+}
+
+// Two pass disassembly of `range`. The first pass follows the code reachable from `entries`
+// through jumps and calls, the second emits it with labels on entry points and branch
+// targets, named after `symbols` where possible and `L_xxxx` otherwise. Symbols outside
+// the listing that are branched to get an EQU.
+// Anything that wasn't reached is emitted as DB directives, so assembling the listing
+// gives back the original bytes.
+pub fn listing(
+    memory: &Memory,
+    range: RangeInclusive<u16>,
+    entries: &[u16],
+    symbols: &Symbols,
+) -> String {
+    let mut code: BTreeMap<u16, DisasmLine> = BTreeMap::new();
+    let mut covered = vec![false; ADDRESS_SPACE];
+    let mut targets: BTreeSet<u16> = entries.iter().copied().collect();
+    let mut pending: Vec<u16> = entries.to_vec();
+    while let Some(mut addr) = pending.pop() {
+        while range.contains(&addr) && !code.contains_key(&addr) {
+            let line = disassemble_at(memory, addr);
+            let end = addr as u32 + line.size() as u32 - 1;
+            let overlaps = (addr as u32..=end).any(|a| a > 0xFFFF || covered[a as usize]);
+            let instruction = match line.instruction {
+                Some(instruction) if end <= *range.end() as u32 && !overlaps => instruction,
+                _ => break,
+            };
+            (addr as usize..=end as usize).for_each(|a| covered[a] = true);
+            if let Some(target) = instruction.branch_target(addr) {
+                if instruction.mnemonic != Mnemonic::Rst {
+                    targets.insert(target);
+                }
+                pending.push(target);
+            }
+            let next = line.next();
+            code.insert(addr, line);
+            if ends_flow(&instruction) || next < addr {
+                break;
+            }
+            addr = next;
+        }
+    }
+
+    let label = |addr: u16| match symbols.name(addr) {
+        Some(name) if code.contains_key(&addr) => Some(name.to_string()),
+        _ if code.contains_key(&addr) && targets.contains(&addr) => Some(format!("L_{:04X}", addr)),
+        _ => None,
+    };
+    let mut external = BTreeMap::new();
+    let mut out = format!("        ORG ${:04X}\n", range.start());
+    let mut addr = *range.start() as u32;
+    while addr <= *range.end() as u32 {
+        let start = addr as u16;
+        if let Some(name) = label(start) {
+            out.push_str(&format!("{}:\n", name));
+        }
+        let (text, comment, size) = match code.get(&start) {
+            Some(line) if reassembles(line) => {
+                let instruction = line.instruction.unwrap_or_default();
+                let mut text = instruction.at(start).to_string();
+                if let Some(target) = instruction
+                    .branch_target(start)
+                    .filter(|_| instruction.mnemonic != Mnemonic::Rst)
+                {
+                    let name = match (label(target), symbols.name(target)) {
+                        (Some(name), _) => Some(name),
+                        (None, Some(name)) if !range.contains(&target) => {
+                            external.insert(name.to_string(), target);
+                            Some(name.to_string())
+                        }
+                        _ => None,
+                    };
+                    if let Some(name) = name {
+                        text = text.replace(&format!("${:04X}", target), &name);
+                    }
+                }
+                (text, format!("{:04X}", start), line.size() as u32)
+            }
+            Some(line) => (
+                data(&line.bytes),
+                format!("{:04X}  {}", start, line.instruction.unwrap_or_default()),
+                line.size() as u32,
+            ),
+            None => {
+                // Data runs up to the next instruction, 8 bytes per line
+                let mut bytes = Vec::new();
+                while bytes.len() < 8
+                    && addr + (bytes.len() as u32) <= *range.end() as u32
+                    && (bytes.is_empty()
+                        || !code.contains_key(&((addr + bytes.len() as u32) as u16)))
+                {
+                    bytes.push(memory.peek((addr + bytes.len() as u32) as u16));
+                }
+                (data(&bytes), format!("{:04X}", start), bytes.len() as u32)
+            }
+        };
+        out.push_str(&format!("        {:<32}; {}\n", text, comment));
+        addr += size;
+    }
+    let mut equates: String = external
+        .iter()
+        .map(|(name, addr)| format!("{:<7} EQU ${:04X}\n", name, addr))
+        .collect();
+    equates.push_str(&out);
+    equates
+}
+
+fn data(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    format!("DB {}", bytes.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{context, disassemble, disassemble_at, listing};
+    use crate::memory::Memory;
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn ranges() {
+        let mut memory = Memory::default();
+        // LD A, 5; LD (IX-2), 0x99; BIT 7, (HL); ED FF (undocumented NOP)
+        memory.load_slice(
+            0xFFF0,
+            &[0x3E, 0x05, 0xDD, 0x36, 0xFE, 0x99, 0xCB, 0x7E, 0xED, 0xFF],
+        );
+        let lines = disassemble(&memory, 0xFFF0, 0xFFF8);
+        let text: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "FFF0  3E 05       LD A, $05",
+                "FFF2  DD 36 FE 99 LD (IX-$02), $99",
+                "FFF6  CB 7E       BIT 7, (HL)",
+                "FFF8  ED FF       NOP",
+            ]
+        );
+        assert_eq!(lines[1].size(), 4);
+        assert_eq!(lines[1].next(), 0xFFF6);
+        // Doesn't wrap past the end of the address space
+        assert_eq!(disassemble(&memory, 0xFFFE, 0xFFFF).len(), 2);
+
+        // LD A, 5 comes out whole ahead of BIT 7, (HL)
+        let lines = context(&memory, 0xFFF6, 2, 1);
+        let addrs: Vec<u16> = lines.iter().map(|line| line.addr).collect();
+        assert_eq!(addrs, vec![0xFFF0, 0xFFF2, 0xFFF6, 0xFFF8]);
+
+        // A truncated instruction at the top of memory decodes from the wrapped bytes
+        memory.load_slice(0xFFFF, &[0x01]);
+        assert_eq!(disassemble_at(&memory, 0xFFFF).size(), 3);
+    }
+
+    #[test]
+    fn two_pass_listing() {
+        let mut memory = Memory::default();
+        // 0100: LD B, 3; CALL 0x010B; DJNZ -5; JP 0x0000
+        // 010B: NEG (ED 4C duplicate); RET
+        // 010E: data
+        memory.load_slice(
+            0x0100,
+            &[
+                0x06, 0x03, 0xCD, 0x0B, 0x01, 0x10, 0xFB, 0xC3, 0x00, 0x00, 0x00, 0xED, 0x4C, 0xC9,
+                0x48, 0x69, 0x24,
+            ],
+        );
+        assert_eq!(
+            listing(&memory, 0x0100..=0x0110, &[0x0100], &Symbols::default()),
+            "        ORG $0100\n\
+             L_0100:\n\
+             \x20       LD B, $03                       ; 0100\n\
+             L_0102:\n\
+             \x20       CALL L_010B                     ; 0102\n\
+             \x20       DJNZ L_0102                     ; 0105\n\
+             \x20       JP $0000                        ; 0107\n\
+             \x20       DB $00                          ; 010A\n\
+             L_010B:\n\
+             \x20       DB $ED, $4C                     ; 010B  NEG\n\
+             \x20       RET                             ; 010D\n\
+             \x20       DB $48, $69, $24                ; 010E\n"
+        );
+
+        let mut symbols = Symbols::default();
+        symbols.insert("start", 0x0100);
+        symbols.insert("negate", 0x010B);
+        symbols.insert("boot", 0x0000);
+        let text = listing(&memory, 0x0100..=0x0110, &[0x0100], &symbols);
+        assert!(text.starts_with("boot    EQU $0000\n        ORG $0100\nstart:\n"));
+        assert!(text.contains("CALL negate "));
+        assert!(text.contains("JP boot "));
+        assert_eq!(
+            disassemble_at(&memory, 0x0102).with_symbols(&symbols),
+            "0102  CD 0B 01    CALL negate"
+        );
+    }
+}
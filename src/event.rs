@@ -0,0 +1,52 @@
+use std::collections::vec_deque::Drain;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+// Hardware level events emitted by the CPU while executing, so frontends can react
+// to port or memory accesses without patching the core.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event {
+    IoWrite { port: u16, value: u8 },
+    IoRead { port: u16 },
+    Halt,
+    InterruptAck { vector: u8 },
+    RetiExecuted,
+    MemWrite { addr: u16, value: u8 },
+    // SP left the configured stack bounds, see `BreakOn::stack_bounds`
+    StackBounds { sp: u16, pc: u16 },
+}
+
+// Events are only queued once the queue is enabled, memory writes are only
+// reported if they fall within the watched range.
+#[derive(Default)]
+pub struct EventQueue {
+    pub enabled: bool,
+    pub watch: Option<RangeInclusive<u16>>,
+    events: VecDeque<Event>,
+}
+
+impl EventQueue {
+    #[inline]
+    pub fn push(&mut self, event: Event) {
+        if self.enabled {
+            self.events.push_back(event);
+        }
+    }
+
+    #[inline]
+    pub fn watches(&self, addr: u16) -> bool {
+        self.enabled && self.watch.as_ref().is_some_and(|r| r.contains(&addr))
+    }
+
+    pub fn drain(&mut self) -> Drain<'_, Event> {
+        self.events.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
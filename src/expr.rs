@@ -0,0 +1,581 @@
+use std::fmt;
+
+use crate::cpu::Cpu;
+use crate::symbols::Symbols;
+
+// Small expression language used for conditional breakpoints, e.g.
+// `A == 0x3F && BC < 0x100` or `mem[0x4000] != 0`.
+//
+// Operands are registers (A, BC, IX, SP, AF', ...), flags (SF, ZF, HF, PF, NF, CF),
+// numbers (42, 0x2A, $2A, 2Ah, 0b101010, 'A'), memory reads (mem[addr], mem16[addr]) and,
+// through `parse_with`, symbol names. Registers win over symbols of the same name.
+// Operators follow C precedence: unary ! - ~, * / %, + -, << >>, < <= > >=, == !=, &, ^, |,
+// && and ||. Comparisons and logical operators evaluate to 0 or 1.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Num(i64),
+    Var(Var),
+    Mem(Box<Node>),
+    Mem16(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Var {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    F,
+    I,
+    R,
+    Ixh,
+    Ixl,
+    Iyh,
+    Iyl,
+    AF,
+    BC,
+    DE,
+    HL,
+    AF_,
+    BC_,
+    DE_,
+    HL_,
+    IX,
+    IY,
+    SP,
+    PC,
+    SF,
+    ZF,
+    HF,
+    PF,
+    NF,
+    CF,
+}
+
+// Binary operators by precedence level, lowest first
+const LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        Self::parse_with(source, &Symbols::default())
+    }
+
+    // Parses with symbol names resolved to their addresses
+    pub fn parse_with(source: &str, symbols: &Symbols) -> Result<Expr, String> {
+        Self::parse_in(source, symbols, None)
+    }
+
+    // Parses an assembler operand, where `$` is the address of the instruction
+    pub fn parse_at(source: &str, symbols: &Symbols, addr: u16) -> Result<Expr, String> {
+        Self::parse_in(source, symbols, Some(addr))
+    }
+
+    fn parse_in(source: &str, symbols: &Symbols, here: Option<u16>) -> Result<Expr, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            symbols,
+            here,
+        };
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("Unexpected `{}`", token));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> i64 {
+        self.root.eval(cpu)
+    }
+
+    // The value of an expression without registers or memory reads, None if it has any
+    pub fn value(&self) -> Option<i64> {
+        self.root.fold(&|_| None)
+    }
+
+    // True if the expression evaluates to a non zero value
+    pub fn is_true(&self, cpu: &Cpu) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+// Value of a register or flag by its name in expressions, None for unknown names
+pub fn register(cpu: &Cpu, name: &str) -> Option<i64> {
+    Var::from_name(name).map(|var| var.eval(cpu))
+}
+
+// Sets a register or flag by name (8-bit registers and flags take the low bits of `value`),
+// returns false for unknown names
+pub fn set_register(cpu: &mut Cpu, name: &str, value: u16) -> bool {
+    match Var::from_name(name) {
+        Some(var) => {
+            var.set(cpu, value);
+            true
+        }
+        None => false,
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Node {
+    fn eval(&self, cpu: &Cpu) -> i64 {
+        let leaf = |node: &Node| match node {
+            Node::Var(var) => Some(var.eval(cpu)),
+            Node::Mem(addr) => Some(cpu.memory.peek(addr.eval(cpu) as u16) as i64),
+            Node::Mem16(addr) => Some(cpu.memory.peek16(addr.eval(cpu) as u16) as i64),
+            _ => None,
+        };
+        self.fold(&leaf).unwrap_or(0)
+    }
+
+    // Evaluates with `leaf` giving the values of registers and memory reads, None if it
+    // has no value for one that's needed
+    fn fold(&self, leaf: &dyn Fn(&Node) -> Option<i64>) -> Option<i64> {
+        Some(match self {
+            Node::Num(n) => *n,
+            Node::Var(_) | Node::Mem(_) | Node::Mem16(_) => leaf(self)?,
+            Node::Unary(op, node) => {
+                let value = node.fold(leaf)?;
+                match *op {
+                    "!" => (value == 0) as i64,
+                    "-" => value.wrapping_neg(),
+                    _ => !value,
+                }
+            }
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.fold(leaf)?;
+                // Short circuit so `HL != 0 && mem[HL]` style guards behave as expected
+                match *op {
+                    "&&" => return Some((lhs != 0 && rhs.fold(leaf)? != 0) as i64),
+                    "||" => return Some((lhs != 0 || rhs.fold(leaf)? != 0) as i64),
+                    _ => {}
+                }
+                let rhs = rhs.fold(leaf)?;
+                match *op {
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "&" => lhs & rhs,
+                    "==" => (lhs == rhs) as i64,
+                    "!=" => (lhs != rhs) as i64,
+                    "<=" => (lhs <= rhs) as i64,
+                    ">=" => (lhs >= rhs) as i64,
+                    "<" => (lhs < rhs) as i64,
+                    ">" => (lhs > rhs) as i64,
+                    "<<" => lhs.wrapping_shl(rhs as u32),
+                    ">>" => lhs.wrapping_shr(rhs as u32),
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    // Division by zero evaluates to 0 rather than aborting the emulator
+                    "/" => lhs.checked_div(rhs).unwrap_or(0),
+                    _ => lhs.checked_rem(rhs).unwrap_or(0),
+                }
+            }
+        })
+    }
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Var> {
+        let var = match name.to_ascii_uppercase().as_str() {
+            "A" => Var::A,
+            "B" => Var::B,
+            "C" => Var::C,
+            "D" => Var::D,
+            "E" => Var::E,
+            "H" => Var::H,
+            "L" => Var::L,
+            "F" => Var::F,
+            "I" => Var::I,
+            "R" => Var::R,
+            "IXH" => Var::Ixh,
+            "IXL" => Var::Ixl,
+            "IYH" => Var::Iyh,
+            "IYL" => Var::Iyl,
+            "AF" => Var::AF,
+            "BC" => Var::BC,
+            "DE" => Var::DE,
+            "HL" => Var::HL,
+            "AF'" => Var::AF_,
+            "BC'" => Var::BC_,
+            "DE'" => Var::DE_,
+            "HL'" => Var::HL_,
+            "IX" => Var::IX,
+            "IY" => Var::IY,
+            "SP" => Var::SP,
+            "PC" => Var::PC,
+            "SF" => Var::SF,
+            "ZF" => Var::ZF,
+            "HF" => Var::HF,
+            "PF" => Var::PF,
+            "NF" => Var::NF,
+            "CF" => Var::CF,
+            _ => return None,
+        };
+        Some(var)
+    }
+
+    fn eval(self, cpu: &Cpu) -> i64 {
+        let reg = &cpu.reg;
+        let pair = |h: u8, l: u8| (h as u16) << 8 | l as u16;
+        let value = match self {
+            Var::A => reg.a as u16,
+            Var::B => reg.b as u16,
+            Var::C => reg.c as u16,
+            Var::D => reg.d as u16,
+            Var::E => reg.e as u16,
+            Var::H => reg.h as u16,
+            Var::L => reg.l as u16,
+            Var::F => cpu.flags.get() as u16,
+            Var::I => reg.i as u16,
+            Var::R => reg.r as u16,
+            Var::Ixh => reg.ix >> 8,
+            Var::Ixl => reg.ix & 0xFF,
+            Var::Iyh => reg.iy >> 8,
+            Var::Iyl => reg.iy & 0xFF,
+            Var::AF => pair(reg.a, cpu.flags.get()),
+            Var::BC => pair(reg.b, reg.c),
+            Var::DE => pair(reg.d, reg.e),
+            Var::HL => pair(reg.h, reg.l),
+            Var::AF_ => pair(reg.a_, cpu.flags.get_shadow()),
+            Var::BC_ => pair(reg.b_, reg.c_),
+            Var::DE_ => pair(reg.d_, reg.e_),
+            Var::HL_ => pair(reg.h_, reg.l_),
+            Var::IX => reg.ix,
+            Var::IY => reg.iy,
+            Var::SP => reg.sp,
+            Var::PC => reg.pc,
+            Var::SF => cpu.flags.sf as u16,
+            Var::ZF => cpu.flags.zf as u16,
+            Var::HF => cpu.flags.hf as u16,
+            Var::PF => cpu.flags.pf as u16,
+            Var::NF => cpu.flags.nf as u16,
+            Var::CF => cpu.flags.cf as u16,
+        };
+        value as i64
+    }
+}
+
+impl Var {
+    fn set(self, cpu: &mut Cpu, value: u16) {
+        let [high, low] = value.to_be_bytes();
+        let reg = &mut cpu.reg;
+        match self {
+            Var::A => reg.a = low,
+            Var::B => reg.b = low,
+            Var::C => reg.c = low,
+            Var::D => reg.d = low,
+            Var::E => reg.e = low,
+            Var::H => reg.h = low,
+            Var::L => reg.l = low,
+            Var::F => cpu.flags.set(low),
+            Var::I => reg.i = low,
+            Var::R => reg.r = low,
+            Var::Ixh => reg.ix = (reg.ix & 0x00FF) | (low as u16) << 8,
+            Var::Ixl => reg.ix = (reg.ix & 0xFF00) | low as u16,
+            Var::Iyh => reg.iy = (reg.iy & 0x00FF) | (low as u16) << 8,
+            Var::Iyl => reg.iy = (reg.iy & 0xFF00) | low as u16,
+            Var::AF => {
+                reg.a = high;
+                cpu.flags.set(low);
+            }
+            Var::BC => [reg.b, reg.c] = [high, low],
+            Var::DE => [reg.d, reg.e] = [high, low],
+            Var::HL => [reg.h, reg.l] = [high, low],
+            Var::AF_ => {
+                reg.a_ = high;
+                cpu.flags.set_shadow(low);
+            }
+            Var::BC_ => [reg.b_, reg.c_] = [high, low],
+            Var::DE_ => [reg.d_, reg.e_] = [high, low],
+            Var::HL_ => [reg.h_, reg.l_] = [high, low],
+            Var::IX => reg.ix = value,
+            Var::IY => reg.iy = value,
+            Var::SP => reg.sp = value,
+            Var::PC => reg.pc = value,
+            Var::SF => cpu.flags.sf = low & 1 != 0,
+            Var::ZF => cpu.flags.zf = low & 1 != 0,
+            Var::HF => cpu.flags.hf = low & 1 != 0,
+            Var::PF => cpu.flags.pf = low & 1 != 0,
+            Var::NF => cpu.flags.nf = low & 1 != 0,
+            Var::CF => cpu.flags.cf = low & 1 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+// Longest operators first so `<=` isn't read as `<`
+const OPERATORS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == ']' {
+            tokens.push(Token::Op("]"));
+            rest = &rest[1..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if let Some(n) = char_literal(rest) {
+            tokens.push(Token::Num(n));
+            rest = &rest[3..];
+        } else if c.is_ascii_alphanumeric() || "$_.@".contains(c) {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "$_.@?'".contains(c)))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push(match parse_number(word) {
+                Some(n) => Token::Num(n),
+                None if word == "$" => Token::Ident(word.to_string()),
+                None if c.is_ascii_digit() || c == '$' => {
+                    return Err(format!("Invalid number `{}`", word))
+                }
+                None => Token::Ident(word.to_string()),
+            });
+            rest = &rest[end..];
+        } else {
+            return Err(format!("Unexpected character `{}`", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// 'A' style character constants, ASCII only
+fn char_literal(rest: &str) -> Option<i64> {
+    match rest.as_bytes() {
+        [b'\'', c, b'\'', ..] if c.is_ascii() => Some(*c as i64),
+        _ => None,
+    }
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('$') {
+        (hex, 16)
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) && lower.ends_with('h') {
+        // Before 0b, 0BEh is hex
+        (&lower[..lower.len() - 1], 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (bin, 2)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a Symbols,
+    // What `$` stands for
+    here: Option<u16>,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            Some(token) => Err(format!("Expected `{}`, found `{}`", op, token)),
+            None => Err(format!("Expected `{}`", op)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.eat(LEVELS[level]) {
+            let rhs = self.binary(level + 1)?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if let Some(op) = self.eat(&["!", "-", "~"]) {
+            return Ok(Node::Unary(op, Box::new(self.unary()?)));
+        }
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Node::Num(n)),
+            Some(Token::Op("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Ident(name)) => match name.to_ascii_lowercase().as_str() {
+                "mem" | "mem16" => {
+                    self.expect("[")?;
+                    let addr = Box::new(self.binary(0)?);
+                    self.expect("]")?;
+                    Ok(if name.len() == 3 {
+                        Node::Mem(addr)
+                    } else {
+                        Node::Mem16(addr)
+                    })
+                }
+                "$" => match self.here {
+                    Some(addr) => Ok(Node::Num(addr as i64)),
+                    None => Err("`$` is only known in assembler operands".to_string()),
+                },
+                _ => match (Var::from_name(&name), self.symbols.addr(&name)) {
+                    (Some(var), _) => Ok(Node::Var(var)),
+                    (None, Some(addr)) => Ok(Node::Num(addr as i64)),
+                    (None, None) => Err(format!("Unknown register or symbol `{}`", name)),
+                },
+            },
+            Some(token) => Err(format!("Unexpected `{}`", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expr;
+    use crate::cpu::Cpu;
+    use crate::memory::MemoryRW;
+    use crate::symbols::Symbols;
+
+    fn eval(source: &str, cpu: &Cpu) -> i64 {
+        Expr::parse(source).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut cpu = Cpu::default();
+        cpu.reg.a = 0x3F;
+        cpu.reg.b = 0x00;
+        cpu.reg.c = 0xFF;
+        cpu.reg.ix = 0x1234;
+        cpu.flags.zf = true;
+        cpu.write16(0x4000, 0xBEEF);
+
+        assert_eq!(eval("A == 0x3F && BC < 0x100", &cpu), 1);
+        assert_eq!(eval("a == $3f && bc > 0x100", &cpu), 0);
+        assert_eq!(eval("mem[0x4000] != 0", &cpu), 1);
+        assert_eq!(eval("mem[0x3FFF + 1] == 0xEF", &cpu), 1);
+        assert_eq!(eval("mem16[4000h]", &cpu), 0xBEEF);
+        assert_eq!(eval("IXH * 0x100 + IXL == IX", &cpu), 1);
+        assert_eq!(eval("ZF && !CF", &cpu), 1);
+        assert_eq!(eval("1 + 2 * 3 == 7", &cpu), 1);
+        assert_eq!(eval("(1 + 2) * 3", &cpu), 9);
+        assert_eq!(eval("0b1010 & 0b0110 | 1 << 4", &cpu), 0x12);
+        assert_eq!(eval("0BEh + 0b1h", &cpu), 0x16F);
+        assert_eq!(eval("A == 0b111111", &cpu), 1);
+        assert_eq!(eval("-1 < 0", &cpu), 1);
+        assert_eq!(eval("A / 0", &cpu), 0);
+        assert_eq!(Expr::parse(" A == 1 ").unwrap().to_string(), "A == 1");
+    }
+
+    #[test]
+    fn constants() {
+        let mut symbols = Symbols::default();
+        symbols.insert("table", 0x8000);
+        let value = |source| Expr::parse_at(source, &symbols, 0x4000).unwrap().value();
+        assert_eq!(value("table + 'A' - $"), Some(0x4041));
+        assert_eq!(value("A + 1"), None);
+        assert!(Expr::parse("$ + 1").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for source in &[
+            "",
+            "A ==",
+            "Q == 1",
+            "(A == 1",
+            "mem 0x4000",
+            "0xZZ",
+            "A == 1 B",
+            "A # 1",
+        ] {
+            assert!(Expr::parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        let mut cpu = Cpu::default();
+        cpu.reg.pc = 0x8003;
+        cpu.reg.c = 1;
+        let mut symbols = Symbols::default();
+        symbols.insert("main_loop", 0x8000);
+        symbols.insert("C", 0x1234);
+        let eval = |source| Expr::parse_with(source, &symbols).unwrap().eval(&cpu);
+        assert_eq!(eval("main_loop"), 0x8000);
+        assert_eq!(eval("PC - main_loop"), 3);
+        assert_eq!(eval("C"), 1);
+        assert!(Expr::parse("main_loop").is_err());
+    }
+}
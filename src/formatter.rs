@@ -1,7 +1,83 @@
 use crate::cpu::{Cpu, Registers};
+use crate::disassembler::context;
 use crate::memory::MemoryRW;
 use std::fmt;
-use std::fmt::{Debug, Display, Formatter, Result};
+use std::fmt::{Debug, Display, Formatter, Result, Write};
+
+// Fixed size text buffer, lets Display impls that are built from several pieces honour
+// width and alignment without allocating. Text past the capacity is dropped.
+pub struct FmtBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for FmtBuf<N> {
+    fn default() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> FmtBuf<N> {
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Write for FmtBuf<N> {
+    fn write_str(&mut self, s: &str) -> Result {
+        let mut end = s.len().min(N - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+// Writes `value` padded to the width in `f`, for Display impls that are built from several
+// pieces and would otherwise ignore it. With a width the text goes through a stack buffer,
+// so it has to fit in 64 bytes.
+pub fn pad(f: &mut Formatter, value: &dyn Display) -> Result {
+    if f.width().is_none() {
+        return value.fmt(f);
+    }
+    let mut buf = FmtBuf::<64>::default();
+    write!(buf, "{}", value)?;
+    f.pad(buf.as_str())
+}
+
+// Bytes as hex digits with `separator` between them, e.g. `3E 05`
+pub struct HexBytes<'a>(pub &'a [u8], pub &'static str);
+
+struct Digits<'a>(&'a HexBytes<'a>);
+
+impl Display for Digits<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let HexBytes(bytes, separator) = self.0;
+        for (n, byte) in bytes.iter().enumerate() {
+            if n > 0 {
+                f.write_str(separator)?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        pad(f, &Digits(self))
+    }
+}
 
 impl Display for Registers {
     fn fmt(&self, fmt: &mut Formatter) -> Result {
@@ -16,9 +92,12 @@ impl Display for Registers {
     }
 }
 
+// Instructions shown around PC by the alternate Debug format
+const CONTEXT_BEFORE: usize = 3;
+const CONTEXT_AFTER: usize = 3;
+
 impl Debug for Cpu {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
-        fmt.align();
         write!(fmt, "PC: {:>04X}, ", self.reg.pc)?;
         write!(fmt, "AF: {:>02X}{:02X}, ", self.reg.a, self.flags.get())?;
         write!(fmt, "BC: {:>02X}{:02X}, ", self.reg.b, self.reg.c)?;
@@ -37,13 +116,20 @@ impl Debug for Cpu {
             self.read8(self.reg.pc.wrapping_add(2)),
             self.read8(self.reg.pc.wrapping_add(3))
         )?;
-        write!(fmt, "cyc: {}", self.cycles)
+        write!(fmt, "cyc: {}", self.cycles)?;
+        // `{:#?}` adds the code around PC
+        if fmt.alternate() {
+            for line in context(&self.memory, self.reg.pc, CONTEXT_BEFORE, CONTEXT_AFTER) {
+                let marker = if line.addr == self.reg.pc { '>' } else { ' ' };
+                write!(fmt, "\n{}{}", marker, line)?;
+            }
+        }
+        Ok(())
     }
 }
 impl Display for Cpu {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
-        fmt.align();
-        write!(fmt, "{:w$}", &self.current_instruction, w = 12)?;
+        write!(fmt, "{:w$}", self.instruction, w = 12)?;
         write!(
             fmt,
             "({:02X} {:02X} {:02X} {:02X})\t",
@@ -67,7 +153,7 @@ impl Display for Cpu {
         write!(fmt, "P:{} ", self.flags.pf as u8)?;
         write!(fmt, "C:{} ", self.flags.cf as u8)?;
         write!(fmt, "H:{} ", self.flags.hf as u8)?;
-        write!(fmt, "I:{} ", self.reg.i as u8)?;
+        write!(fmt, "I:{} ", { self.reg.i })?;
         write!(fmt, "Cycles:{}", self.cycles)
     }
 }
@@ -96,7 +182,7 @@ impl Debug for Cpu {
         writeln!(
             fmt,
             "{}\t{:04X}\t{:04X}\t{:02X}\t{:02X}{:02X}\t{:02X}{:02X}\t{:02X}{:02X}\t{:0>4X}\t{}\t{}\t{}\t{}\t{}\t{}",
-            self.current_instruction,
+            self.instruction,
             self.opcode,
             self.reg.prev_pc,
             self.reg.a,
@@ -116,3 +202,45 @@ impl Debug for Cpu {
         )
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::{FmtBuf, HexBytes};
+    use crate::cpu::Cpu;
+    use crate::instruction_info::Instruction;
+    use std::fmt::Write;
+
+    #[test]
+    fn padding_without_allocating() {
+        let mut buf = FmtBuf::<8>::default();
+        write!(buf, "{}", HexBytes(&[0xDD, 0x36, 0xFE, 0x99], " ")).unwrap();
+        assert_eq!(buf.as_str(), "DD 36 FE");
+        buf.clear();
+        write!(buf, "{:<6}|", HexBytes(&[0x3E, 0x05], "")).unwrap();
+        assert_eq!(buf.as_str(), "3E05  |");
+
+        let jr = Instruction::decode_bytes(&[0x20, 0xFD, 0, 0]).unwrap();
+        assert_eq!(format!("{:>14}|", jr.at(0x0103)), "  JR NZ, $0102|");
+        assert_eq!(format!("{:<12}|", jr), "JR NZ, $-1  |");
+    }
+
+    #[test]
+    fn debug_context() {
+        // LD A, 5; DEC A; JR NZ, -3; HALT
+        let mut cpu = Cpu::default();
+        cpu.memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x3D, 0x20, 0xFD, 0x76]);
+        cpu.reg.pc = 0x0103;
+        let plain = format!("{:?}", cpu);
+        assert!(!plain.contains('\n'));
+        let dump = format!("{:#?}", cpu);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], plain);
+        assert_eq!(lines[1], " 00FF  00          NOP");
+        assert_eq!(lines[2], " 0100  3E 05       LD A, $05");
+        assert_eq!(lines[3], " 0102  3D          DEC A");
+        assert_eq!(lines[4], ">0103  20 FD       JR NZ, $0102");
+        assert_eq!(lines[5], " 0105  76          HALT");
+        assert_eq!(lines.len(), 8);
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use log::error;
+
+use crate::cpu::Cpu;
+use crate::memory::PAGE_SIZE;
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+// Hash of the CPU state and RAM at the end of every frame, so runs of a ROM can be checked
+// for identical behaviour across changes to the core. RAM is hashed per 1K page and only
+// pages written during the frame are rehashed. ROM isn't included.
+#[derive(Default)]
+pub struct FrameHash {
+    pages: Vec<u64>,
+    // Hash of every frame so far, oldest first
+    pub hashes: Vec<u64>,
+    log: Option<Box<dyn Write>>,
+}
+
+impl FrameHash {
+    // Also writes a `frame hash` line per frame to `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            log: Some(Box::new(BufWriter::new(File::create(path)?))),
+            ..Self::default()
+        })
+    }
+
+    // Hashes the state at the end of a frame and returns it
+    pub fn update(&mut self, cpu: &mut Cpu) -> u64 {
+        let dirty = cpu.memory.take_dirty();
+        let ram = &cpu.memory.ram;
+        let count = ram.len().div_ceil(PAGE_SIZE);
+        // Pages that are new (first frame, or RAM grown since) haven't been hashed yet
+        let fresh = self.pages.len()..count;
+        self.pages.resize(count, 0);
+        for page in dirty.into_iter().filter(|&p| p < count).chain(fresh) {
+            let end = ((page + 1) * PAGE_SIZE).min(ram.len());
+            self.pages[page] = fnv1a(FNV_OFFSET, &ram[page * PAGE_SIZE..end]);
+        }
+
+        let reg = &cpu.reg;
+        let registers = [
+            reg.a,
+            cpu.flags.get(),
+            reg.b,
+            reg.c,
+            reg.d,
+            reg.e,
+            reg.h,
+            reg.l,
+            reg.a_,
+            cpu.flags.get_shadow(),
+            reg.b_,
+            reg.c_,
+            reg.d_,
+            reg.e_,
+            reg.h_,
+            reg.l_,
+            reg.i,
+            reg.r,
+            cpu.int.mode,
+            cpu.int.iff1 as u8 | (cpu.int.iff2 as u8) << 1 | (cpu.int.halt as u8) << 2,
+        ];
+        let mut hash = fnv1a(FNV_OFFSET, &registers);
+        for word in [reg.pc, reg.sp, reg.ix, reg.iy] {
+            hash = fnv1a(hash, &word.to_le_bytes());
+        }
+        hash = fnv1a(hash, &(cpu.cycles as u64).to_le_bytes());
+        for page in &self.pages {
+            hash = fnv1a(hash, &page.to_le_bytes());
+        }
+
+        self.hashes.push(hash);
+        if let Some(log) = &mut self.log {
+            if let Err(e) = writeln!(log, "{} {:016X}", self.hashes.len(), hash) {
+                error!("Frame hash log disabled: {}", e);
+                self.log = None;
+            }
+        }
+        hash
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.log {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameHash;
+    use crate::cpu::Cpu;
+    use crate::memory::MemoryRW;
+
+    #[test]
+    fn dirty_pages_and_registers() {
+        let mut cpu = Cpu::default();
+        let mut hasher = FrameHash::default();
+        let first = hasher.update(&mut cpu);
+        assert_eq!(hasher.update(&mut cpu), first);
+
+        cpu.write8(0x8000, 1);
+        let written = hasher.update(&mut cpu);
+        assert_ne!(written, first);
+        cpu.write8(0x8000, 0);
+        assert_eq!(hasher.update(&mut cpu), first);
+
+        // Poked memory is seen too, and a fresh hasher agrees with the incremental one
+        cpu.memory.poke(0x1234, 0x56);
+        cpu.reg.pc = 0x0100;
+        let changed = hasher.update(&mut cpu);
+        assert_eq!(FrameHash::default().update(&mut cpu), changed);
+        assert_eq!(hasher.hashes.len(), 5);
+    }
+}
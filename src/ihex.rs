@@ -0,0 +1,103 @@
+// Intel HEX as emitted by most Z80 assemblers and SDCC (`.hex`, `.ihx`). Each line is
+// `:LLAAAATT<data>CC`, a byte count, address, record type, the data and a checksum making
+// the sum of all bytes zero. Types handled:
+//
+//   00  data
+//   01  end of file, anything after it is ignored
+//   02  extended segment address, later addresses are offset by the value * 16
+//   04  extended linear address, later addresses are offset by the value << 16
+//   03, 05  start address, ignored
+//
+// Offsets are accepted as long as the data still lands in the 64K address space, which is
+// what toolchains emitting 02/04 records for small images end up with.
+
+// Bytes and the address they load at, what HEX and S-record files boil down to
+pub type Segment = (u16, Vec<u8>);
+
+// Data records in file order
+pub fn parse(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut base = 0u32;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(decode)
+            .ok_or_else(|| err("not a HEX record"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        let value = || match data {
+            [high, low] => Ok(u16::from_be_bytes([*high, *low]) as u32),
+            _ => Err(err("expected a 2 byte address")),
+        };
+        match bytes[3] {
+            0x00 => {
+                let start = base + addr;
+                if start as usize + data.len() > 0x1_0000 {
+                    return Err(err(&format!(
+                        "data at {:X} is outside the 64K address space",
+                        start
+                    )));
+                }
+                segments.push((start as u16, data.to_vec()));
+            }
+            0x01 => break,
+            0x02 => base = value()? << 4,
+            0x04 => base = value()? << 16,
+            0x03 | 0x05 => {}
+            kind => return Err(err(&format!("unknown record type {:02X}", kind))),
+        }
+    }
+    Ok(segments)
+}
+
+// True for the file extensions HEX files usually have
+pub fn is_ihex(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.ends_with(".hex") || path.ends_with(".ihx")
+}
+
+// Hex digit pairs to bytes
+pub(crate) fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(hex.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_ihex, parse};
+
+    #[test]
+    fn records() {
+        let text = ":030000002100805C\n\
+                    :020000020010EC\n\
+                    :01000100C935\n\
+                    :00000001FF\n\
+                    :0100020000FD\n";
+        assert_eq!(
+            parse(text),
+            Ok(vec![(0x0000, vec![0x21, 0x00, 0x80]), (0x0101, vec![0xC9])])
+        );
+        assert!(parse(":030000002100805D").unwrap_err().contains("checksum"));
+        assert!(parse(":020000040001F9\n:01000000C936").is_err());
+        assert!(parse(":02FFFF001234BA").is_err());
+        assert!(parse("030000002100805C").is_err());
+        assert!(is_ihex("ROM.IHX"));
+        assert!(!is_ihex("rom.bin"));
+    }
+}
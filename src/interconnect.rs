@@ -1,55 +1,823 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::{error, trace};
+
 use super::cpu::Cpu;
-use crate::instruction_info::Instruction;
+use crate::coverage::Coverage;
+use crate::cpm::CpmBios;
+use crate::debugger::{
+    Breakpoints, CallFrame, CallKind, CallStack, StepResult, StopReason, WatchHit,
+};
+use crate::device::{Device, DeviceRef};
+use crate::frame_hash::FrameHash;
+use crate::instruction_info::{Instruction, Mnemonic, Register};
+use crate::memory::{Memory, CPM_TRAPS};
+use crate::msx::{Cassette, STMOTR, TAPIN, TAPIOF, TAPION};
+use crate::pacman;
+use crate::profile::OpcodeProfile;
+use crate::replay::InputLog;
+use crate::rewind::Rewind;
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+use crate::symbols::Symbols;
+use crate::tape::{Signal, Tap, TapePlayer, LD_BYTES};
+use crate::trace::{GoldenTrace, TraceBuffer, TraceEntry, TraceFormat, TraceWriter};
+use crate::video::Framebuffer;
+use crate::zx81::{ZxProgram, LOAD as ZX81_LOAD};
+
+// What `Interconnect::step` does after a PC hook ran
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HookAction {
+    // Execute the instruction at PC as usual (the hook may have changed PC)
+    Execute,
+    // Don't execute anything this step
+    Skip,
+    // Return to the caller as if a RET had been executed
+    Return,
+    // Execute the instruction, then stop as if a breakpoint had been hit
+    Break,
+}
+
+// Called before executing the instruction at the address it was registered for
+pub type PcHook = Box<dyn FnMut(&mut Cpu) -> HookAction>;
 
 pub struct Interconnect {
     pub cpu: Cpu,
     pub frame_count: u32,
+    pub devices: Vec<DeviceRef>,
+    pub clock_speed: usize, // Hz
+    // T states run by `execute_cpu`, half a 60Hz frame if not set
+    pub frame_cycles: Option<usize>,
+    pub breakpoints: Breakpoints,
+    // Set when the last frame was cut short by the debugger, see `step`
+    pub stopped: Option<StopReason>,
+    // Recently executed instructions, disabled until given a depth
+    pub history: TraceBuffer,
+    pub tracer: Option<TraceWriter>,
+    // Reference trace execution is checked against, see `compare_with`
+    pub golden: Option<GoldenTrace>,
+    // Tracks CALL / RST / interrupt entries when enabled, see `backtrace`
+    pub call_stack: CallStack,
+    // Executed addresses, collected while set
+    pub coverage: Option<Coverage>,
+    // Executions and cycles per opcode, collected while set
+    pub profile: Option<OpcodeProfile>,
+    // Names used by the debugger, traces and disassembly
+    pub symbols: Symbols,
+    // State hash per frame, computed while set
+    pub frame_hash: Option<FrameHash>,
+    // Checkpoints for going back in time, see `enable_rewind`
+    pub rewind: Option<Rewind>,
+    // Picture drawn by the machine's video device, for frontends to show
+    pub display: Option<Rc<RefCell<Framebuffer>>>,
+    // Index of the device whose interrupt was last requested, see `tick_devices`
+    int_source: Option<usize>,
+    // Text printed through BDOS functions 2 and 9 by programs run with `run_tests`
+    pub console: String,
+    // Also print the console output to stdout as it's written
+    pub echo_console: bool,
+    pc_hooks: BTreeMap<u16, PcHook>,
 }
 
-impl Interconnect {
-    pub fn default() -> Self {
+impl Default for Interconnect {
+    fn default() -> Self {
         Self {
             cpu: Cpu::default(),
             frame_count: 0,
+            devices: Vec::new(),
+            clock_speed: 3_072_000,
+            frame_cycles: None,
+            breakpoints: Breakpoints::default(),
+            stopped: None,
+            history: TraceBuffer::default(),
+            tracer: None,
+            golden: None,
+            call_stack: CallStack::default(),
+            coverage: None,
+            profile: None,
+            symbols: Symbols::default(),
+            frame_hash: None,
+            rewind: None,
+            display: None,
+            int_source: None,
+            console: String::new(),
+            echo_console: false,
+            pc_hooks: BTreeMap::new(),
         }
     }
+}
+
+impl Interconnect {
+    pub fn builder() -> InterconnectBuilder {
+        InterconnectBuilder::default()
+    }
 
     pub fn execute_cpu(&mut self) -> u32 {
         // self.cpu.debug = true;
         let mut cycles_executed: usize = 0;
-        // Cycles per frame should be: 3072000
+        // Cycles per frame should be: clock speed (3072000 on Pac-Man)
         // Divide amount of cycles per frame with 60 FPS
         // Divide that by 2 to get half cycles per frame (for interrupts)
+        // unless the machine has its own frame length
+        let frame = self.frame_cycles.unwrap_or(self.clock_speed / 60 / 2);
 
-        while cycles_executed <= 25_600 {
-            let start_cycles = self.cpu.cycles;
-            self.cpu.execute();
-
-            cycles_executed += self.cpu.cycles - start_cycles;
-            self.cpu.poll_interrupt();
+        self.stopped = None;
+        while cycles_executed <= frame {
+            let result = self.step();
+            cycles_executed += result.cycles;
+            if result.stop.is_some() {
+                self.stopped = result.stop;
+                break;
+            }
         }
 
+        self.flush_trace();
         self.frame_count += 1;
+        if let Some(hasher) = &mut self.frame_hash {
+            hasher.update(&mut self.cpu);
+        }
         self.frame_count
     }
 
+    // Attaches a device to the bus, the returned handle can be used to inspect or drive it
+    pub fn add_device<D: Device + 'static>(&mut self, device: D) -> Rc<RefCell<D>> {
+        let device = Rc::new(RefCell::new(device));
+        self.devices.push(device.clone());
+        device
+    }
+
+    // Routes IN / OUT on the given ports to the device
+    pub fn register_port(&mut self, ports: RangeInclusive<u8>, device: DeviceRef) {
+        self.cpu.io.register_port(ports, device);
+    }
+
+    // Routes IN / OUT to the device when `port & mask == value` for the full 16-bit port address
+    pub fn register_port_decoded(&mut self, mask: u16, value: u16, device: DeviceRef) {
+        self.cpu.io.register_port_decoded(mask, value, device);
+    }
+
+    // Services CPU memory accesses within `range` with the device (memory mapped I/O)
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: DeviceRef) {
+        self.cpu.memory.map_device(range, device);
+    }
+
+    // Runs `hook` whenever execution reaches `addr`, replacing any previous hook there. This
+    // is the clean way to service OS calls in the host, e.g. the CP/M BDOS at 0x0005 can
+    // print the string at DE and return `HookAction::Return`.
+    pub fn on_pc<F>(&mut self, addr: u16, hook: F)
+    where
+        F: FnMut(&mut Cpu) -> HookAction + 'static,
+    {
+        self.pc_hooks.insert(addr, Box::new(hook));
+    }
+
+    // Runs `script` whenever PC reaches `addr`, a script error is printed and the
+    // instruction executed as usual
+    #[cfg(feature = "scripting")]
+    pub fn on_pc_script(&mut self, addr: u16, mut script: Script) {
+        self.on_pc(addr, move |cpu| {
+            script.run(cpu).unwrap_or_else(|e| {
+                log::warn!("Script at {:04X}: {}", addr, e);
+                HookAction::Execute
+            })
+        });
+    }
+
+    // Loads from `tap` instantly whenever the Spectrum ROM loader is called
+    pub fn insert_tap(&mut self, mut tap: Tap) {
+        self.on_pc(LD_BYTES, move |cpu| tap.fast_load(cpu));
+    }
+
+    // Plays `signal` into the EAR input of port FE (any even port), see `TapePlayer`
+    pub fn insert_tape(&mut self, signal: Vec<Signal>) -> Rc<RefCell<TapePlayer>> {
+        let player = self.add_device(TapePlayer::new(signal));
+        self.register_port_decoded(0x0001, 0x0000, player.clone());
+        player
+    }
+
+    // Loads `program` whenever the ZX81 ROM's LOAD routine is called
+    pub fn insert_p(&mut self, program: ZxProgram) {
+        self.on_pc(ZX81_LOAD, move |cpu| program.trap(cpu));
+    }
+
+    // Services the MSX BIOS cassette routines from `cas`, see `Cassette::trap`
+    pub fn insert_cas(&mut self, cas: Cassette) -> Rc<RefCell<Cassette>> {
+        let cas = Rc::new(RefCell::new(cas));
+        for entry in [TAPION, TAPIN, TAPIOF, STMOTR] {
+            let cas = cas.clone();
+            self.on_pc(entry, move |cpu| cas.borrow_mut().trap(cpu, entry));
+        }
+        cas
+    }
+
+    // Writes the BIOS jump table and disk tables, hooks every entry and cold boots from
+    // drive A, see `CpmBios`
+    pub fn install_cpm_bios(&mut self, mut bios: CpmBios) -> Result<Rc<RefCell<CpmBios>>, String> {
+        bios.build_tables(&mut self.cpu)?;
+        let entries: Vec<u16> = bios.entries().collect();
+        self.cpu.reg.pc = bios.base;
+        let bios = Rc::new(RefCell::new(bios));
+        for addr in entries {
+            let bios = bios.clone();
+            self.on_pc(addr, move |cpu| bios.borrow_mut().call(cpu, addr));
+        }
+        Ok(bios)
+    }
+
+    pub fn remove_pc_hook(&mut self, addr: u16) -> bool {
+        self.pc_hooks.remove(&addr).is_some()
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.memory.peek(addr)
+    }
+
+    pub fn peek16(&self, addr: u16) -> u16 {
+        self.cpu.memory.peek16(addr)
+    }
+
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.cpu.memory.poke(addr, value);
+    }
+
+    pub fn dump_range(&self, start: u16, len: usize) -> String {
+        self.cpu.memory.dump_range(start, len)
+    }
+
+    // Executes a single instruction (after running any PC hook registered for its address),
+    // advances all devices by the cycles it took and services any pending interrupt. Reports the cycles spent and whether execution
+    // should stop because PC landed on a breakpoint, a watchpoint was triggered or an
+    // event class enabled in `cpu.break_on` occurred.
+    pub fn step(&mut self) -> StepResult {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.before_step(&mut self.cpu);
+        }
+        let start_cycles = self.cpu.cycles;
+        self.grant_bus();
+        let start = (self.cpu.reg.pc, self.cpu.reg.sp);
+        let action = match self.pc_hooks.get_mut(&self.cpu.reg.pc) {
+            Some(hook) => hook(&mut self.cpu),
+            None => HookAction::Execute,
+        };
+        let divergence = match action {
+            HookAction::Execute => self.execute_traced(),
+            HookAction::Break => self.execute_traced().or(Some(StopReason::Hook(start.0))),
+            HookAction::Skip => None,
+            HookAction::Return => {
+                self.cpu.ret();
+                None
+            }
+        };
+        let executed = (self.cpu.reg.pc, self.cpu.reg.sp);
+        self.tick_devices(self.cpu.cycles - start_cycles);
+        self.cpu.poll_interrupt();
+        self.service_daisy_chain();
+        self.cpu.check_stack_bounds(start.0);
+        if self.call_stack.enabled {
+            let ran = matches!(action, HookAction::Execute | HookAction::Break);
+            let opcode = ran.then(|| self.peek(start.0));
+            self.track_calls(start, opcode, executed);
+        }
+        let pc = self.cpu.reg.pc;
+        let stop = if divergence.is_some() {
+            divergence
+        } else if let Some(hit) = self.service_watchpoints() {
+            Some(StopReason::Watchpoint(hit))
+        } else if let Some(event) = self.cpu.break_on.take() {
+            Some(StopReason::Event(event))
+        } else {
+            self.breakpoints
+                .check(&self.cpu)
+                .then_some(StopReason::Breakpoint(pc))
+        };
+        if let Some(rewind) = &mut self.rewind {
+            rewind.after_step();
+        }
+        // Go live at the end of a replay, or of the part replayed after going back
+        let behind = self.rewind.as_ref().is_some_and(Rewind::behind);
+        if self.cpu.input.replaying() && self.cpu.input.remaining() == 0 && !behind {
+            self.cpu.input.stop_replay();
+        }
+        StepResult {
+            cycles: self.cpu.cycles - start_cycles,
+            stop,
+        }
+    }
+
+    // Executes one instruction, recording it if tracing, profiling, collecting coverage or
+    // comparing against a reference
+    fn execute_traced(&mut self) -> Option<StopReason> {
+        let start_pc = self.cpu.reg.pc;
+        let start_cycles = self.cpu.cycles;
+        let tracing = self.history.enabled()
+            || self.tracer.is_some()
+            || self.golden.is_some()
+            || self.coverage.is_some()
+            || self.profile.is_some();
+        let bytes = tracing.then(|| self.peek_bytes(start_pc));
+        self.cpu.execute();
+        if let (Some(profile), Some(bytes)) = (&mut self.profile, &bytes) {
+            profile.record(bytes, self.cpu.cycles - start_cycles);
+        }
+        match bytes {
+            Some(bytes) => self.trace(TraceEntry::new(start_pc, bytes, &self.cpu)),
+            None => None,
+        }
+    }
+
+    // Updates the call stack from the PC / SP before and after the instruction, `opcode` is
+    // None if a PC hook skipped it
+    fn track_calls(&mut self, start: (u16, u16), opcode: Option<u8>, executed: (u16, u16)) {
+        let (start_pc, start_sp) = start;
+        let (exec_pc, exec_sp) = executed;
+        self.call_stack.unwind(exec_sp);
+        // CALL nn, CALL cc, nn and RST p, only when the return address was pushed
+        let kind = match opcode {
+            Some(0xCD) => Some(CallKind::Call),
+            Some(op) if op & 0xC7 == 0xC4 => Some(CallKind::Call),
+            Some(op) if op & 0xC7 == 0xC7 => Some(CallKind::Rst),
+            _ => None,
+        };
+        if let Some(kind) = kind.filter(|_| exec_sp == start_sp.wrapping_sub(2)) {
+            self.call_stack.push(CallFrame {
+                kind,
+                site: start_pc,
+                target: exec_pc,
+                ret: self.peek16(exec_sp),
+                sp: exec_sp,
+            });
+        }
+        let (pc, sp) = (self.cpu.reg.pc, self.cpu.reg.sp);
+        if sp == exec_sp.wrapping_sub(2) && self.peek16(sp) == exec_pc && pc != exec_pc {
+            self.call_stack.push(CallFrame {
+                kind: if pc == 0x0066 {
+                    CallKind::Nmi
+                } else {
+                    CallKind::Interrupt
+                },
+                site: exec_pc,
+                target: pc,
+                ret: exec_pc,
+                sp,
+            });
+        }
+    }
+
+    // Return addresses of the active subroutines, innermost first
+    pub fn backtrace(&self) -> String {
+        self.call_stack.backtrace(self.cpu.reg.pc, &self.symbols)
+    }
+
+    // Starts streaming a trace line per instruction to `path`
+    pub fn trace_to_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        format: TraceFormat,
+    ) -> io::Result<()> {
+        let mut tracer = TraceWriter::create(path, format)?;
+        tracer.symbols = self.symbols.clone();
+        self.tracer = Some(tracer);
+        Ok(())
+    }
+
+    // Compares execution against a reference trace, stepping stops at the first
+    // difference. Keeps some history around for `GoldenTrace::report`.
+    pub fn compare_with<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.golden = Some(GoldenTrace::open(path)?);
+        if self.history.depth() < 64 {
+            self.history.set_depth(64);
+        }
+        Ok(())
+    }
+
+    // Records the entry, returns a stop reason if it doesn't match the golden trace
+    fn trace(&mut self, entry: TraceEntry) -> Option<StopReason> {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(entry.pc, entry.len);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.write(&entry) {
+                error!("Trace disabled: {}", e);
+                self.tracer = None;
+            }
+        }
+        self.history.push(entry);
+        let golden = self.golden.as_mut()?;
+        match golden.check(&entry) {
+            Some(true) => None,
+            Some(false) => Some(StopReason::TraceMismatch(golden.line)),
+            None => {
+                let lines = golden.line;
+                self.golden = None;
+                Some(StopReason::TraceEnd(lines))
+            }
+        }
+    }
+
+    pub fn flush_trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.flush() {
+                error!("Trace disabled: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    // Keeps a checkpoint per frame (1/60 s of cycles), at most `frames` of them, and logs
+    // the CPU's inputs so execution can go back with `step_back` and `rewind_frames`
+    pub fn enable_rewind(&mut self, frames: usize) {
+        self.rewind = Some(Rewind::new(self.clock_speed / 60, frames));
+        if !self.cpu.input.keep {
+            self.cpu.input.clear();
+        }
+        self.cpu.input.recording = true;
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+        if !self.cpu.input.keep {
+            self.cpu.input.clear();
+            self.cpu.input.recording = false;
+        }
+    }
+
+    // Logs every input from here on for `save_inputs`
+    pub fn record_inputs(&mut self) {
+        self.cpu.input.recording = true;
+        self.cpu.input.keep = true;
+    }
+
+    // Writes the inputs recorded since `record_inputs`
+    pub fn save_inputs<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.cpu.input.save(path)
+    }
+
+    // Replays the inputs in a log written by `save_inputs`. Devices keep running but their
+    // reads and interrupts are replaced by the logged ones until the end of the log.
+    pub fn replay_inputs<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut log = InputLog::load(path)?;
+        log.recording = self.cpu.input.keep;
+        log.keep = self.cpu.input.keep;
+        self.cpu.input = log;
+        self.rewind = None;
+        Ok(())
+    }
+
+    // Goes back `count` instructions, returns false if that's before the oldest checkpoint
+    pub fn step_back(&mut self, count: u64) -> bool {
+        match &self.rewind {
+            Some(rewind) if count <= rewind.steps => self.rewind_to(rewind.steps - count),
+            _ => false,
+        }
+    }
+
+    // Goes back to the `frames`th checkpoint before the current instruction
+    pub fn rewind_frames(&mut self, frames: usize) -> bool {
+        match self.rewind.as_ref().and_then(|r| r.checkpoint_back(frames)) {
+            Some(step) => self.rewind_to(step),
+            None => false,
+        }
+    }
+
+    // Restores the checkpoint before `target` and replays up to it. Tracing, coverage and
+    // profiling are paused meanwhile so the replayed instructions aren't counted twice;
+    // history and the call stack are rebuilt from the checkpoint on.
+    fn rewind_to(&mut self, target: u64) -> bool {
+        let restored = match &mut self.rewind {
+            Some(rewind) => rewind.restore(&mut self.cpu, target),
+            None => false,
+        };
+        if !restored {
+            return false;
+        }
+        self.history.clear();
+        self.call_stack.clear();
+        let tracer = self.tracer.take();
+        let golden = self.golden.take();
+        let coverage = self.coverage.take();
+        let profile = self.profile.take();
+        while self.rewind.as_ref().is_some_and(|r| r.steps < target) {
+            self.step();
+        }
+        self.tracer = tracer;
+        self.golden = golden;
+        self.coverage = coverage;
+        self.profile = profile;
+        true
+    }
+
+    // Executes the instruction at PC, running a CALL or RST until it returns. SP is tracked
+    // so recursive calls back to the same return address don't end the step early.
+    pub fn step_over(&mut self) -> StepResult {
+        let pc = self.cpu.reg.pc;
+        let sp = self.cpu.reg.sp;
+        let instruction = self.decode_at(pc);
+        match instruction.map(|i| i.mnemonic) {
+            Some(Mnemonic::Call) | Some(Mnemonic::Rst) => {
+                let ret = pc.wrapping_add(instruction.map_or(1, |i| i.size) as u16);
+                self.run_until(|cpu, _| cpu.reg.pc == ret && cpu.reg.sp >= sp)
+            }
+            _ => self.step(),
+        }
+    }
+
+    // Runs until the current subroutine returns to its caller, i.e. until a RET, RETI or
+    // RETN pops a return address from above the stack pointer we started with.
+    pub fn step_out(&mut self) -> StepResult {
+        let sp = self.cpu.reg.sp;
+        self.run_until(|cpu, mnemonic| {
+            matches!(
+                mnemonic,
+                Some(Mnemonic::Ret) | Some(Mnemonic::Reti) | Some(Mnemonic::Retn)
+            ) && cpu.reg.sp > sp
+        })
+    }
+
+    // Steps until `done` returns true for the state after an instruction (along with the
+    // mnemonic of the instruction that was executed) or the debugger stops execution
+    fn run_until<F: Fn(&Cpu, Option<Mnemonic>) -> bool>(&mut self, done: F) -> StepResult {
+        let mut cycles = 0;
+        loop {
+            let mnemonic = self.decode_at(self.cpu.reg.pc).map(|i| i.mnemonic);
+            let result = self.step();
+            cycles += result.cycles;
+            if result.stop.is_some() || done(&self.cpu, mnemonic) {
+                return StepResult {
+                    cycles,
+                    stop: result.stop,
+                };
+            }
+        }
+    }
+
+    fn decode_at(&self, addr: u16) -> Option<Instruction> {
+        Instruction::decode_bytes(&self.peek_bytes(addr))
+    }
+
+    // The longest possible instruction at `addr`
+    fn peek_bytes(&self, addr: u16) -> [u8; 4] {
+        [0, 1, 2, 3].map(|n| self.peek(addr.wrapping_add(n)))
+    }
+
+    fn service_watchpoints(&mut self) -> Option<WatchHit> {
+        if !self.cpu.watchpoints.triggered() {
+            return None;
+        }
+        let size = self
+            .decode_at(self.cpu.watchpoints.pc)
+            .map_or(1, |i| i.size);
+        self.cpu.watchpoints.service(size)
+    }
+
+    // Ticks every device, the first one asserting /INT that no device ahead of it in the
+    // daisy chain blocks gets its interrupt requested
+    fn tick_devices(&mut self, cycles: usize) {
+        let mut requested = false;
+        let source = self.int_source;
+        let mut withdrawn = false;
+        // R is incremented after the refresh cycle it's put on the bus for
+        let r = self.cpu.reg.r;
+        let ir = (self.cpu.reg.i as u16) << 8 | (r & 0x80 | r.wrapping_sub(1) & 0x7F) as u16;
+        for (n, device) in self.devices.iter().enumerate() {
+            let mut device = device.borrow_mut();
+            device.tick(cycles);
+            device.scan(&self.cpu.memory);
+            device.refresh(ir);
+            let pending = device.pending_interrupt();
+            if source == Some(n) && pending.is_none() && device.level_interrupt() {
+                withdrawn = true;
+            }
+            if !requested {
+                if let Some(vector) = pending {
+                    self.cpu.int_request(vector);
+                    self.int_source = Some(n);
+                    requested = true;
+                }
+                requested |= device.interrupt_in_service();
+            }
+            if device.pending_nmi() {
+                self.cpu.nmi();
+            }
+        }
+        if withdrawn && self.int_source == source {
+            self.int_source = None;
+            self.cpu.int_clear();
+        }
+    }
+
+    // Hands the bus to the first device asking for it (a DMA controller) and holds the CPU
+    // for the cycles it takes
+    fn grant_bus(&mut self) {
+        let cpu = &mut self.cpu;
+        if let Some(device) = self
+            .devices
+            .iter()
+            .find(|device| device.borrow().bus_request())
+        {
+            cpu.cycles += device.borrow_mut().bus_master(&mut cpu.memory, &mut cpu.io);
+        }
+    }
+
+    // Passes a RETI and an accepted interrupt on to the daisy chain
+    fn service_daisy_chain(&mut self) {
+        if std::mem::take(&mut self.cpu.int.reti) {
+            if let Some(device) = self
+                .devices
+                .iter()
+                .find(|device| device.borrow().interrupt_in_service())
+            {
+                device.borrow_mut().reti();
+            }
+        }
+        if std::mem::take(&mut self.cpu.int.acknowledged) {
+            if let Some(device) = self.int_source.take().and_then(|n| self.devices.get(n)) {
+                device.borrow_mut().interrupt_acknowledged();
+            }
+        }
+    }
+
     pub fn run_tests(&mut self) {
         self.cpu.fetch();
         if self.cpu.debug {
             // self.debug_decode();
-            println!("{:#?}", self.cpu);
+            trace!("{:#?}", self.cpu);
         }
         self.cpu.decode(self.cpu.opcode);
+        // CALL 0x0005 ends up at the RET the CP/M traps put at 0x0007
+        if self.cpu.reg.pc == 0x0007 {
+            self.bdos_console();
+        }
     }
+
+    // Services the BDOS console output functions, C = 2 writes E and C = 9 writes the
+    // string at DE up to a '$'
+    fn bdos_console(&mut self) {
+        let start = self.console.len();
+        match self.cpu.reg.c {
+            2 => self.console.push(self.cpu.reg.e as char),
+            9 => {
+                let mut addr = self.cpu.read_pair(Register::DE);
+                while self.cpu.memory[addr] != b'$' {
+                    self.console.push(self.cpu.memory[addr] as char);
+                    addr = addr.wrapping_add(1);
+                }
+            }
+            _ => {}
+        }
+        if self.echo_console {
+            print!("{}", &self.console[start..]);
+        }
+    }
+
+    // The console output so far, clearing it
+    pub fn take_console(&mut self) -> String {
+        std::mem::take(&mut self.console)
+    }
+    #[allow(dead_code)]
     fn debug_decode(&mut self) {
-        self.cpu.instruction = Instruction::decode(&mut self.cpu)
-            .expect(format!("Unknown opcode:{:04X}", self.cpu.opcode).as_str());
+        self.cpu.instruction = Instruction::decode(&self.cpu)
+            .unwrap_or_else(|| panic!("Unknown opcode:{:04X}", self.cpu.opcode));
+        trace!("{:#?}", self.cpu);
+    }
+}
 
-        if self.cpu.instruction.name.to_string().len() < 1 {
-            self.cpu.current_instruction = format!("{:w$}", self.cpu.current_instruction, w = 12);
-        } else {
-            self.cpu.current_instruction = self.cpu.instruction.name.to_string();
+// Memory map & defaults the builder starts from
+pub enum Preset {
+    // Flat 64K RAM, programs start at 0x0100 (CP/M TPA)
+    Cpm,
+    // Pac-Man arcade memory map and I/O block with empty ROMs, see `pacman` for the whole
+    // machine
+    PacMan,
+    // Flat memory supplied by the caller (e.g. with read / write hooks installed)
+    Custom(Box<Memory>),
+}
+
+pub struct InterconnectBuilder {
+    preset: Preset,
+    pc: Option<u16>,
+    sp: Option<u16>,
+    interrupt_mode: Option<u8>,
+    clock_speed: Option<usize>,
+    devices: Vec<DeviceRef>,
+    ports: Vec<(RangeInclusive<u8>, DeviceRef)>,
+    mmio: Vec<(RangeInclusive<u16>, DeviceRef)>,
+    cpm_traps: bool,
+}
+
+impl Default for InterconnectBuilder {
+    fn default() -> Self {
+        Self {
+            preset: Preset::Cpm,
+            pc: None,
+            sp: None,
+            interrupt_mode: None,
+            clock_speed: None,
+            devices: Vec::new(),
+            ports: Vec::new(),
+            mmio: Vec::new(),
+            cpm_traps: false,
+        }
+    }
+}
+
+impl InterconnectBuilder {
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.preset = preset;
+        self
+    }
+
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    pub fn sp(mut self, sp: u16) -> Self {
+        self.sp = Some(sp);
+        self
+    }
+
+    pub fn interrupt_mode(mut self, mode: u8) -> Self {
+        assert!(mode <= 2, "Invalid interrupt mode: {}", mode);
+        self.interrupt_mode = Some(mode);
+        self
+    }
+
+    // Clock speed in Hz
+    pub fn clock_speed(mut self, hz: usize) -> Self {
+        self.clock_speed = Some(hz);
+        self
+    }
+
+    // Attaches a device that is ticked along with the CPU
+    pub fn device(mut self, device: DeviceRef) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    // Attaches a device and routes IN / OUT on the given ports to it
+    pub fn port_device(mut self, ports: RangeInclusive<u8>, device: DeviceRef) -> Self {
+        self.devices.push(device.clone());
+        self.ports.push((ports, device));
+        self
+    }
+
+    // Attaches a device and maps it into memory at the given addresses
+    pub fn mmio_device(mut self, range: RangeInclusive<u16>, device: DeviceRef) -> Self {
+        self.devices.push(device.clone());
+        self.mmio.push((range, device));
+        self
+    }
+
+    // Installs `memory::CPM_TRAPS` so BDOS calls can be intercepted at 0x0007
+    pub fn cpm_traps(mut self) -> Self {
+        self.cpm_traps = true;
+        self
+    }
+
+    pub fn build(self) -> Interconnect {
+        let mut i = Interconnect::default();
+        i.cpu.reset();
+
+        let (pc, clock_speed) = match self.preset {
+            Preset::Cpm => {
+                i.cpu.cpm_compat = true;
+                (0x0100, 4_000_000)
+            }
+            Preset::PacMan => {
+                i.cpu.cpm_compat = false;
+                pacman::map_memory(&mut i.cpu.memory, &[0; pacman::ROM_SIZE]);
+                pacman::attach_io(&mut i);
+                (0x0000, pacman::CLOCK)
+            }
+            Preset::Custom(memory) => {
+                i.cpu.cpm_compat = true;
+                i.cpu.memory = *memory;
+                (0x0000, 4_000_000)
+            }
+        };
+        i.cpu.reg.pc = self.pc.unwrap_or(pc);
+        i.clock_speed = self.clock_speed.unwrap_or(clock_speed);
+        if let Some(sp) = self.sp {
+            i.cpu.reg.sp = sp;
+        }
+        if let Some(mode) = self.interrupt_mode {
+            i.cpu.int.mode = mode;
+        }
+        i.devices.extend(self.devices);
+        for (ports, device) in self.ports {
+            i.register_port(ports, device);
+        }
+        for (range, device) in self.mmio {
+            i.map_device(range, device);
+        }
+        if self.cpm_traps {
+            i.cpu.memory.apply_patches(&CPM_TRAPS);
         }
-        println!("{:#?}", self.cpu);
+        i
     }
 }
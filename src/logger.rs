@@ -0,0 +1,99 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Minimal `log` backend that writes to stderr, for the command line front end. Library users
+// plug in whichever logger they like instead, or none to keep the crate quiet.
+//
+// Levels are given as a comma separated spec: a bare level sets the default, `target=level`
+// overrides it for a module and everything below it, e.g. `warn,z80_rs::cpu=debug` shows
+// serviced interrupts and hides load notices. The longest matching target wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Logger {
+    pub default: LevelFilter,
+    pub targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl Logger {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut logger = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let level = |s: &str| -> Result<LevelFilter, String> {
+                s.trim()
+                    .parse()
+                    .map_err(|_| format!("Unknown log level `{}`", s))
+            };
+            match part.split_once('=') {
+                Some((target, l)) => logger.targets.push((target.trim().to_string(), level(l)?)),
+                None => logger.default = level(part)?,
+            }
+        }
+        Ok(logger)
+    }
+
+    // Level enabled for messages from `target`
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || target
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    // Installs this as the global logger, fails if one is already set
+    pub fn init(self) -> Result<(), String> {
+        let max = self
+            .targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max);
+        log::set_logger(Box::leak(Box::new(self))).map_err(|e| e.to_string())?;
+        log::set_max_level(max);
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Logger;
+    use log::LevelFilter;
+
+    #[test]
+    fn spec() {
+        let logger = Logger::parse("warn, z80_rs::cpu=debug,z80_rs=error").unwrap();
+        assert_eq!(logger.default, LevelFilter::Warn);
+        assert_eq!(logger.level("z80_rs::cpu"), LevelFilter::Debug);
+        assert_eq!(logger.level("z80_rs::memory"), LevelFilter::Error);
+        assert_eq!(logger.level("z80_rs::cpu_tests"), LevelFilter::Error);
+        assert_eq!(logger.level("other"), LevelFilter::Warn);
+        assert_eq!(Logger::parse("").unwrap(), Logger::default());
+        assert!(Logger::parse("loud").is_err());
+        assert!(Logger::parse("z80_rs=").is_err());
+    }
+}
@@ -0,0 +1,748 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+
+use z80_rs::archive;
+use z80_rs::assembler::assemble;
+use z80_rs::config::MachineConfig;
+use z80_rs::coverage::Coverage;
+use z80_rs::cpc::Cpc;
+use z80_rs::cpm::{CpmBios, Disk, Dpb};
+use z80_rs::cpm_machine::CpmMachine;
+use z80_rs::debugger::{StopReason, Trap};
+use z80_rs::disassembler::listing;
+use z80_rs::frame_hash::FrameHash;
+use z80_rs::interconnect::Interconnect;
+use z80_rs::logger::Logger;
+use z80_rs::memory::{parse_origin, Memory};
+use z80_rs::monitor::{crash_report, print_stop, Monitor};
+use z80_rs::msx::{Cartridge, Cassette, Msx};
+use z80_rs::pacman::PacMan;
+use z80_rs::peripherals::serial::Stdio;
+use z80_rs::profile::{MemoryStats, OpcodeProfile};
+use z80_rs::rc2014::Rc2014;
+use z80_rs::remote;
+use z80_rs::sbc::Sbc;
+use z80_rs::sega::{is_sega_rom, SegaConsole, SegaMapper};
+use z80_rs::sg1000::{is_sg1000_rom, Sg1000};
+use z80_rs::snapshot::{self, Snapshot};
+use z80_rs::spectrum::Spectrum;
+use z80_rs::symbols::Symbols;
+use z80_rs::tape::Tap;
+use z80_rs::trace::{TraceFilter, TraceFormat, TraceTemplate};
+use z80_rs::trs80::Trs80;
+use z80_rs::tzx;
+use z80_rs::wav;
+use z80_rs::zx81::{Zx81, ZxProgram};
+use z80_rs::zx_snapshot::ZxSnapshot;
+
+fn usage() -> ! {
+    eprintln!("Usage: z80-rs [options] <rom files>[@origin] or <.hex / .s19 / .asm files>...");
+    eprintln!(
+        "       z80-rs [options] <.sms or .gg file> (Master System / Game Gear, RAM in .sav)"
+    );
+    eprintln!("       z80-rs [options] <.sg or .sc file> (SG-1000 / SC-3000 cartridge)");
+    eprintln!("       z80-rs [options] --machine <machine.toml>");
+    eprintln!("       z80-rs [options] --spectrum <16K 48K or 32K 128K rom file>");
+    eprintln!("       z80-rs [options] --msx <32K BIOS rom file> (MSX1, --msx-rom in slot 1)");
+    eprintln!("       z80-rs [options] --trs80 <Level I or II rom file> (Model I, 48K)");
+    eprintln!("       z80-rs [options] --cpc <32K OS and BASIC rom file> (CPC 464)");
+    eprintln!("       z80-rs [options] --zx81 <8K rom file>[,<RAM in K, 1-16>] (ZX81)");
+    eprintln!("       z80-rs [options] --pacman <rom directory or zip> (MAME's pacman set)");
+    eprintln!("       z80-rs [options] --rc2014 <rom file>[,pageable][,sio] (6850 by default)");
+    eprintln!(
+        "       z80-rs [options] --sbc <acia|sio>[@port][,ctc@port] <firmware files> (64K RAM)"
+    );
+    eprintln!(
+        "       z80-rs disasm [--symbols <file>] <rom file>[@origin] [entry points (hex)]..."
+    );
+    eprintln!("       z80-rs asm <source.asm> <output.bin> (binary from the lowest address)");
+    eprintln!("       z80-rs diff [--json] <snapshot> <snapshot> | diff <trace line> <trace line>");
+    eprintln!("Options: --debug, --tui, --trace <file>, --trace-format <text|json|csv|mame>,");
+    eprintln!("         --trace-template <e.g. \"{{pc}}: {{asm:20}} AF={{af}} {{flags}}\">,");
+    eprintln!("         --trace-compress <loop window>, --trace-range <0100-7FFF,...>,");
+    eprintln!(
+        "         --trace-class <jump,call,ret,io,block,stack>, --compare <reference trace>,"
+    );
+    eprintln!("         --coverage <file>, --profile, --trap-rom <log|break>,");
+    eprintln!("         --trap-unmapped <log|break>, --symbols <.sym, .map or .cdb file>,");
+    eprintln!("         --frame-hash <file>, --record <input log>, --replay <input log>,");
+    eprintln!("         --script <file.rhai>@<addr> (scripting builds),");
+    eprintln!("         --remote <host:port> (line based remote control instead of running),");
+    eprintln!("         --web <host:port> (HTTP / WebSocket debug server, web builds),");
+    eprintln!("         --log <level[,module=level]...> (or Z80_LOG, default info),");
+    eprintln!("         --snapshot <.sna, .z80 or .szx file> (loaded over the ROMs),");
+    eprintln!("         --tape <.tap, .cas, .p, .tzx or .wav file> (.tap, .cas and ZX81 .p");
+    eprintln!("         load instantly through the Spectrum / MSX / ZX81 ROM, the others play");
+    eprintln!("         into the EAR bit of port FE),");
+    eprintln!("         --msx-rom <.rom file>[,konami|konami-scc|ascii8|ascii16|plain@<addr>]");
+    eprintln!("         (MSX cartridge, the mapper is detected if not given),");
+    eprintln!("         --cpm-disk <.dsk or .img file>[,dpb field=value...] (A:, then B:...,");
+    eprintln!("         boots CP/M 2.2 through a host BIOS at FA00),");
+    eprintln!("         --cpm-machine (with --cpm-disk, boots them on an SIO and floppy");
+    eprintln!("         controller machine with its BIOS in a boot ROM instead)");
+    eprintln!("Files can be read from .gz files and zip archives (set.zip#file) in builds");
+    eprintln!("with the `compressed` feature.");
+    process::exit(1);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("disasm") => return disasm(args.split_off(2)),
+        Some("asm") => return asm(&args[2..]),
+        Some("diff") => return diff(&args[2..]),
+        _ => {}
+    }
+    let symbols = load_symbols(&mut args);
+    let mut scripts = Vec::new();
+    while let Some(script) = take_option(&mut args, "--script") {
+        scripts.push(script);
+    }
+    let debug = args.iter().any(|arg| arg == "--debug");
+    let tui = args.iter().any(|arg| arg == "--tui");
+    let profile = args.iter().any(|arg| arg == "--profile");
+    let cpm_machine = args.iter().any(|arg| arg == "--cpm-machine");
+    args.retain(|arg| {
+        arg != "--debug" && arg != "--tui" && arg != "--profile" && arg != "--cpm-machine"
+    });
+    // --log wins over Z80_LOG, --debug alone turns on debug messages (serviced interrupts)
+    let log = take_option(&mut args, "--log")
+        .or_else(|| env::var("Z80_LOG").ok())
+        .unwrap_or_else(|| if debug { "debug" } else { "info" }.to_string());
+    let logger = Logger::parse(&log).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        usage()
+    });
+    logger.init().expect("Logger already set");
+    let trace = take_option(&mut args, "--trace");
+    let trace_compress = take_option(&mut args, "--trace-compress")
+        .map(|window| window.parse::<usize>().unwrap_or_else(|_| usage()));
+    let mut trace_filter = TraceFilter::default();
+    if let Some(ranges) = take_option(&mut args, "--trace-range") {
+        trace_filter.add_ranges(&ranges).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            usage()
+        });
+    }
+    if let Some(classes) = take_option(&mut args, "--trace-class") {
+        trace_filter.add_classes(&classes).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            usage()
+        });
+    }
+    let trace_template: Option<TraceTemplate> =
+        take_option(&mut args, "--trace-template").map(|template| {
+            template.parse().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                usage()
+            })
+        });
+    let compare = take_option(&mut args, "--compare");
+    let coverage = take_option(&mut args, "--coverage");
+    let frame_hash = take_option(&mut args, "--frame-hash");
+    let record = take_option(&mut args, "--record");
+    let replay = take_option(&mut args, "--replay");
+    let remote = take_option(&mut args, "--remote");
+    let web = take_option(&mut args, "--web");
+    let snapshot = take_option(&mut args, "--snapshot");
+    let tape = take_option(&mut args, "--tape");
+    let msx_rom = take_option(&mut args, "--msx-rom");
+    let msx_bios = take_option(&mut args, "--msx");
+    let trs80_rom = take_option(&mut args, "--trs80");
+    let cpc_rom = take_option(&mut args, "--cpc");
+    let zx81_rom = take_option(&mut args, "--zx81");
+    let pacman = take_option(&mut args, "--pacman");
+    let rc2014 = take_option(&mut args, "--rc2014");
+    let sbc = take_option(&mut args, "--sbc");
+    let spectrum_rom = take_option(&mut args, "--spectrum");
+    let mut cpm_disks = Vec::new();
+    while let Some(disk) = take_option(&mut args, "--cpm-disk") {
+        cpm_disks.push(disk);
+    }
+    let trap = |args: &mut Vec<String>, name| -> Trap {
+        take_option(args, name)
+            .map(|trap| trap.parse().unwrap_or_else(|_| usage()))
+            .unwrap_or_default()
+    };
+    let trap_rom = trap(&mut args, "--trap-rom");
+    let trap_unmapped = trap(&mut args, "--trap-unmapped");
+    let trace_format: TraceFormat = take_option(&mut args, "--trace-format")
+        .map(|format| format.parse().unwrap_or_else(|_| usage()))
+        .unwrap_or_default();
+    let built_in = rc2014.is_some()
+        || spectrum_rom.is_some()
+        || msx_bios.is_some()
+        || trs80_rom.is_some()
+        || cpc_rom.is_some()
+        || zx81_rom.is_some()
+        || pacman.is_some();
+    if args.len() < 2 && cpm_disks.is_empty() && !built_in {
+        usage();
+    }
+
+    let mut sega = None;
+    let mut spectrum = None;
+    let mut msx = None;
+    let machine = args.iter().position(|arg| arg == "--machine");
+    let roms = (
+        &rc2014,
+        &spectrum_rom,
+        &msx_bios,
+        &trs80_rom,
+        &cpc_rom,
+        &zx81_rom,
+        &pacman,
+    );
+    let mut i = match (roms, machine) {
+        ((Some(spec), _, _, _, _, _, _), _) => load_rc2014(spec),
+        ((None, Some(path), _, _, _, _, _), _) => {
+            let mut i = Interconnect::builder().build();
+            spectrum = Some(load_spectrum(&mut i, path));
+            i
+        }
+        ((None, None, Some(path), _, _, _, _), _) => {
+            let mut i = Interconnect::builder().build();
+            msx = Some(Msx::load(&mut i, path).unwrap_or_else(|e| {
+                eprintln!("Failed to load MSX BIOS {}: {}", path, e);
+                process::exit(1);
+            }));
+            i
+        }
+        ((None, None, None, Some(path), _, _, _), _) => {
+            let mut i = Interconnect::builder().build();
+            Trs80::load(&mut i, path).unwrap_or_else(|e| {
+                eprintln!("Failed to load TRS-80 ROM {}: {}", path, e);
+                process::exit(1);
+            });
+            i
+        }
+        ((None, None, None, None, Some(path), _, _), _) => {
+            let mut i = Interconnect::builder().build();
+            Cpc::load(&mut i, path).unwrap_or_else(|e| {
+                eprintln!("Failed to load CPC ROM {}: {}", path, e);
+                process::exit(1);
+            });
+            i
+        }
+        ((None, None, None, None, None, Some(spec), _), _) => load_zx81(spec),
+        ((None, None, None, None, None, None, Some(set)), _) => {
+            let mut i = Interconnect::builder().build();
+            PacMan::load(&mut i, set).unwrap_or_else(|e| {
+                eprintln!("Failed to load Pac-Man ROMs from {}: {}", set, e);
+                process::exit(1);
+            });
+            i
+        }
+        (_, Some(pos)) => {
+            let path = args.get(pos + 1).unwrap_or_else(|| usage());
+            MachineConfig::load(path)
+                .and_then(|config| config.build())
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to load machine {}: {}", path, e);
+                    process::exit(1);
+                })
+        }
+        (_, None) if sbc.is_some() => {
+            let mut i = load_sbc(sbc.as_deref().unwrap_or_default());
+            i.cpu.memory.load_bin(&args);
+            i
+        }
+        (_, None) => {
+            let mut i = Interconnect::builder().pc(0).build();
+            let inner = |path: &String| archive::inner(Path::new(path)).to_path_buf();
+            match args.get(1) {
+                Some(path) if is_sega_rom(inner(path)) => {
+                    let console = SegaConsole::load(&mut i, path).unwrap_or_else(|e| {
+                        eprintln!("Failed to load {}: {}", path, e);
+                        process::exit(1);
+                    });
+                    sega = Some(console.mapper);
+                }
+                Some(path) if is_sg1000_rom(inner(path)) => {
+                    Sg1000::load(&mut i, path).unwrap_or_else(|e| {
+                        eprintln!("Failed to load {}: {}", path, e);
+                        process::exit(1);
+                    });
+                }
+                _ => i.cpu.memory.load_bin(&args),
+            }
+            i
+        }
+    };
+
+    if let Some(path) = snapshot {
+        ZxSnapshot::load(&path)
+            .and_then(|zx| match &spectrum {
+                Some(spectrum) => spectrum
+                    .restore(&mut i, &zx)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                None => {
+                    zx.restore(&mut i.cpu);
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+    }
+    if let Some(spec) = msx_rom {
+        let (path, mapper) = spec.split_once(',').unwrap_or((&spec, ""));
+        let loaded = Cartridge::load(path).and_then(|mut cart| {
+            if !mapper.is_empty() {
+                cart.mapper = mapper
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            }
+            match &msx {
+                Some(msx) => msx.insert(&mut i, &cart, 0),
+                None => cart.install(&mut i.cpu.memory),
+            }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+        loaded.unwrap_or_else(|e| {
+            eprintln!("Failed to load cartridge {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+    if let Some(path) = tape {
+        // .tap, .cas and ZX81 programs load through ROM traps, .tzx and .wav files play in
+        // real time
+        let lower = archive::inner(Path::new(&path))
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        let loaded = if lower.ends_with(".tzx") || lower.ends_with(".wav") {
+            let signal = match lower.ends_with(".tzx") {
+                true => tzx::load(&path),
+                false => wav::load(&path),
+            };
+            signal.map(|signal| {
+                i.insert_tape(signal);
+            })
+        } else if [".p", ".81", ".p81"].iter().any(|ext| lower.ends_with(ext)) {
+            ZxProgram::load(&path).map(|program| i.insert_p(program))
+        } else if lower.ends_with(".cas") {
+            Cassette::load(&path).map(|cas| {
+                i.insert_cas(cas);
+            })
+        } else {
+            Tap::load(&path).map(|tap| i.insert_tap(tap))
+        };
+        loaded.unwrap_or_else(|e| {
+            eprintln!("Failed to load tape {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+    if !cpm_disks.is_empty() {
+        install_cpm(&mut i, &cpm_disks, cpm_machine);
+    }
+    i.symbols = symbols;
+    if let Some(path) = trace {
+        i.trace_to_file(&path, trace_format).unwrap_or_else(|e| {
+            eprintln!("Failed to create trace {}: {}", path, e);
+            process::exit(1);
+        });
+        if let Some(tracer) = &mut i.tracer {
+            tracer.set_compression(trace_compress.unwrap_or(0));
+            tracer.filter = trace_filter;
+            tracer.template = trace_template;
+        }
+    }
+    if let Some(path) = compare {
+        i.compare_with(&path).unwrap_or_else(|e| {
+            eprintln!("Failed to open reference trace {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+    // Always keep some history so a panic in the core can show how it got there
+    i.history.set_depth(if tui || debug { 256 } else { 64 });
+    i.call_stack.enabled = true;
+    i.cpu.break_on.rom_write = trap_rom;
+    i.cpu.break_on.unmapped_exec = trap_unmapped;
+    if coverage.is_some() {
+        i.coverage = Some(Coverage::new(true));
+    }
+    if let Some(path) = frame_hash {
+        i.frame_hash = Some(FrameHash::create(&path).unwrap_or_else(|e| {
+            eprintln!("Failed to create frame hash log {}: {}", path, e);
+            process::exit(1);
+        }));
+    }
+    attach_scripts(&mut i, &scripts);
+    if let Some(path) = replay {
+        i.replay_inputs(&path).unwrap_or_else(|e| {
+            eprintln!("Failed to load input log {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+    if record.is_some() {
+        i.record_inputs();
+    }
+    if profile {
+        i.profile = Some(OpcodeProfile::default());
+        i.cpu.mem_stats = Some(MemoryStats::default());
+    }
+    let outputs = Outputs {
+        coverage: coverage.as_deref(),
+        inputs: record.as_deref(),
+        sega: sega.as_ref(),
+    };
+    if let Some(addr) = remote {
+        let listener = listen(&addr);
+        if let Err(e) = guard(&mut i, outputs, |i| remote::serve(i, listener)) {
+            eprintln!("Remote connection failed: {}", e);
+        }
+        finish(&mut i, outputs);
+    } else if let Some(addr) = web {
+        let listener = listen(&addr);
+        println!("Debug server on http://{}", addr);
+        if let Err(e) = guard(&mut i, outputs, |i| run_web(i, listener)) {
+            eprintln!("Debug server failed: {}", e);
+        }
+        finish(&mut i, outputs);
+    } else if tui {
+        run_tui(&mut i);
+        finish(&mut i, outputs);
+    } else if debug {
+        debug_loop(&mut i, outputs);
+        finish(&mut i, outputs);
+    } else {
+        loop {
+            guard(&mut i, outputs, |i| i.execute_cpu());
+            if let Some(stop) = i.stopped {
+                finish(&mut i, outputs);
+                print_stop(&i, stop, &mut io::stdout()).unwrap();
+                process::exit(match stop {
+                    StopReason::TraceEnd(_) => 0,
+                    _ => 1,
+                });
+            }
+        }
+    }
+}
+
+// A 48K or 128K Spectrum, going by the size of the ROM
+fn load_spectrum(i: &mut Interconnect, path: &str) -> Spectrum {
+    archive::read(path)
+        .and_then(|rom| {
+            Spectrum::install(i, &rom).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load Spectrum ROM {}: {}", path, e);
+            process::exit(1);
+        })
+}
+
+// An RC2014 running the ROM, with the console on stdin / stdout
+fn load_rc2014(spec: &str) -> Interconnect {
+    let (path, options) = spec.split_once(',').unwrap_or((spec, ""));
+    let machine: Rc2014 = options.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        usage()
+    });
+    archive::read(path)
+        .and_then(|rom| {
+            machine
+                .build(&rom, Stdio::default())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load RC2014 ROM {}: {}", path, e);
+            process::exit(1);
+        })
+}
+
+// A homebrew board with the console on stdin / stdout, the firmware is loaded into its RAM
+fn load_sbc(spec: &str) -> Interconnect {
+    let machine: Sbc = spec.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        usage()
+    });
+    machine.build(Stdio::default())
+}
+
+// A ZX81 running the ROM with the RAM given in K, 16K by default
+fn load_zx81(spec: &str) -> Interconnect {
+    let (path, ram) = spec.split_once(',').unwrap_or((spec, "16"));
+    let ram: usize = ram.parse().unwrap_or_else(|_| usage());
+    let mut i = Interconnect::builder().build();
+    archive::read(path)
+        .and_then(|rom| {
+            Zx81::install(&mut i, &rom, ram * 0x400)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load ZX81 ROM {}: {}", path, e);
+            process::exit(1);
+        });
+    i
+}
+
+// Mounts the disks in order from A: and boots CP/M with the console on stdin / stdout,
+// through the host BIOS or on the CP/M machine
+fn install_cpm(i: &mut Interconnect, disks: &[String], machine: bool) {
+    let disks = disks.iter().map(|spec| {
+        let (path, dpb) = spec.split_once(',').unwrap_or((spec, ""));
+        Dpb::parse(dpb)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .and_then(|dpb| Disk::open(path, dpb))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to mount CP/M disk {}: {}", path, e);
+                process::exit(1);
+            })
+    });
+    if machine {
+        if let Err(e) = CpmMachine::install(i, disks.collect(), Stdio::default()) {
+            eprintln!("Failed to set up the CP/M machine: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let mut bios = CpmBios::default();
+    bios.stdin = true;
+    bios.echo = true;
+    for (drive, disk) in disks.enumerate() {
+        bios.mount(drive, disk);
+    }
+    if let Err(e) = i.install_cpm_bios(bios) {
+        eprintln!("Failed to install the CP/M BIOS: {}", e);
+        process::exit(1);
+    }
+}
+
+// Files written on exit
+#[derive(Copy, Clone)]
+struct Outputs<'a> {
+    coverage: Option<&'a str>,
+    inputs: Option<&'a str>,
+    // Cartridge RAM is saved next to the ROM
+    sega: Option<&'a SegaMapper>,
+}
+
+// Flushes the trace and frame hashes, prints the opcode and memory profiles and writes the
+// coverage report, input log and cartridge RAM before exiting
+fn finish(i: &mut Interconnect, outputs: Outputs) {
+    i.flush_trace();
+    if let Some(Err(e)) = i.frame_hash.as_mut().map(FrameHash::flush) {
+        eprintln!("Failed to write frame hashes: {}", e);
+    }
+    if let Some(profile) = &i.profile {
+        print!("{}", profile.summary(40));
+    }
+    if let Some(stats) = &i.cpu.mem_stats {
+        print!("{}", stats.summary(20));
+    }
+    if let (Some(path), Some(report)) = (outputs.coverage, &i.coverage) {
+        if let Err(e) = report.save(path) {
+            eprintln!("Failed to write coverage {}: {}", path, e);
+        }
+    }
+    if let Some(path) = outputs.inputs {
+        if let Err(e) = i.save_inputs(path) {
+            eprintln!("Failed to write input log {}: {}", path, e);
+        }
+    }
+    if let Some(Err(e)) = outputs.sega.map(|mapper| mapper.save_ram(&i.cpu.memory)) {
+        eprintln!("Failed to save cartridge RAM: {}", e);
+    }
+}
+
+// Runs `f`, printing the crash report and exiting if the core panics
+fn guard<R>(i: &mut Interconnect, outputs: Outputs, f: impl FnOnce(&mut Interconnect) -> R) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(i))) {
+        Ok(result) => result,
+        Err(_) => {
+            finish(i, outputs);
+            crash_report(i, &mut io::stderr()).unwrap();
+            process::exit(101);
+        }
+    }
+}
+
+// Prints a re-assemblable listing of a ROM image, following code from the entry points
+// (the load address if none are given)
+fn disasm(mut args: Vec<String>) {
+    let symbols = load_symbols(&mut args);
+    let (file, org) = parse_origin(args.first().unwrap_or_else(|| usage()));
+    let org = org.unwrap_or(0);
+    let rom = archive::read(file).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", file, e);
+        process::exit(1);
+    });
+    if rom.is_empty() || org as usize + rom.len() > 0x1_0000 {
+        eprintln!("{} bytes don't fit at {:04X}", rom.len(), org);
+        process::exit(1);
+    }
+    let mut entries: Vec<u16> = args[1..]
+        .iter()
+        .map(|entry| {
+            u16::from_str_radix(entry.trim_start_matches("0x"), 16).unwrap_or_else(|_| usage())
+        })
+        .collect();
+    if entries.is_empty() {
+        entries.push(org);
+    }
+    let mut memory = Memory::default();
+    memory.load_slice(org, &rom);
+    let end = org + (rom.len() - 1) as u16;
+    print!("{}", listing(&memory, org..=end, &entries, &symbols));
+}
+
+// Assembles a source file to a binary image
+fn asm(args: &[String]) {
+    let (source, output) = match args {
+        [source, output] => (source, output),
+        _ => usage(),
+    };
+    let assembly = archive::read_to_string(source)
+        .map_err(|e| e.to_string())
+        .and_then(|text| assemble(&text))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to assemble {}: {}", source, e);
+            process::exit(1);
+        });
+    let binary = assembly.to_binary();
+    if let Err(e) = fs::write(output, &binary) {
+        eprintln!("Failed to write {}: {}", output, e);
+        process::exit(1);
+    }
+    println!(
+        "{} bytes at {:04X}, {} symbols",
+        binary.len(),
+        assembly.origin(),
+        assembly.symbols.len()
+    );
+}
+
+// Prints the registers, flags and memory that differ between two snapshot files, or
+// between two trace lines if the arguments aren't snapshots. With --json the state of both
+// snapshots is printed as `{"a":..,"b":..}` (see `Cpu::to_json`) ahead of the differences.
+fn diff(args: &[String]) {
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+    let (a, b) = match args[..] {
+        [a, b] => (a, b),
+        _ => usage(),
+    };
+    let text = match (Snapshot::load(a), Snapshot::load(b)) {
+        (Ok(a), Ok(b)) => {
+            if json {
+                println!("{{\"a\":{},\"b\":{}}}", a.to_json(), b.to_json());
+            }
+            snapshot::diff(&a, &b)
+        }
+        _ => snapshot::diff_lines(a, b),
+    };
+    print!("{}", text);
+    process::exit(if text.is_empty() { 0 } else { 1 });
+}
+
+// Loads and merges every `--symbols <file>` given
+fn load_symbols(args: &mut Vec<String>) -> Symbols {
+    let mut symbols = Symbols::default();
+    while let Some(path) = take_option(args, "--symbols") {
+        match Symbols::load(&path) {
+            Ok(loaded) => symbols.extend(&loaded),
+            Err(e) => {
+                eprintln!("Failed to load symbols {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    symbols
+}
+
+// Runs each `file@addr` script when PC reaches addr
+#[cfg(feature = "scripting")]
+fn attach_scripts(i: &mut Interconnect, scripts: &[String]) {
+    for arg in scripts {
+        let (path, addr) = match parse_origin(arg) {
+            (path, Some(addr)) => (path, addr),
+            _ => usage(),
+        };
+        match z80_rs::script::Script::load(path) {
+            Ok(script) => i.on_pc_script(addr, script),
+            Err(e) => {
+                eprintln!("Failed to load script {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn attach_scripts(_: &mut Interconnect, scripts: &[String]) {
+    if !scripts.is_empty() {
+        eprintln!("Built without the scripting feature");
+        process::exit(1);
+    }
+}
+
+// Removes `name <value>` from the arguments and returns the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() {
+        usage();
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+fn debug_loop(i: &mut Interconnect, outputs: Outputs) {
+    let mut monitor = Monitor::default();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    println!("{:?}", i.cpu);
+    loop {
+        print!("{}", monitor.prompt());
+        stdout.flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        match guard(i, outputs, |i| monitor.run_command(i, &line, &mut stdout)) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("{}", e),
+        }
+        i.flush_trace();
+    }
+}
+
+fn listen(addr: &str) -> TcpListener {
+    TcpListener::bind(addr).unwrap_or_else(|e| {
+        eprintln!("Failed to listen on {}: {}", addr, e);
+        process::exit(1);
+    })
+}
+
+#[cfg(feature = "web")]
+fn run_web(i: &mut Interconnect, listener: TcpListener) -> io::Result<()> {
+    z80_rs::web::serve(i, listener)
+}
+
+#[cfg(not(feature = "web"))]
+fn run_web(_: &mut Interconnect, _: TcpListener) -> io::Result<()> {
+    eprintln!("Built without the web feature");
+    process::exit(1);
+}
+
+#[cfg(feature = "debug-tui")]
+fn run_tui(i: &mut Interconnect) {
+    if let Err(e) = z80_rs::tui::run(i) {
+        eprintln!("Terminal error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "debug-tui"))]
+fn run_tui(_: &mut Interconnect) {
+    eprintln!("Built without the debug-tui feature");
+    process::exit(1);
+}
@@ -1,19 +1,105 @@
-use crate::cpu::{Cpu, Registers};
 use std::fmt;
-use std::fs::File;
+use std::fs;
+use std::io;
 use std::io::prelude::*;
-use std::ops::{Index, IndexMut};
+use std::ops::{Index, IndexMut, RangeInclusive};
 use std::path::Path;
+use std::rc::Rc;
 
+use log::{info, warn};
+
+use crate::archive;
+use crate::assembler;
+use crate::device::{DeviceRef, PortDecode};
+use crate::ihex::{self, Segment};
+use crate::srec;
+
+// Hooks are consulted before the regular memory map. A read hook returning `Some` supplies the
+// value, a write hook returning `true` consumes the write.
+pub type ReadHook = Box<dyn Fn(u16) -> Option<u8>>;
+pub type WriteHook = Box<dyn FnMut(u16, u8) -> bool>;
+
+pub const ADDRESS_SPACE: usize = 0x1_0000;
+pub const PAGE_SIZE: usize = 0x400;
+pub const PAGES: usize = ADDRESS_SPACE / PAGE_SIZE;
+
+// Backing storage for a 1K page of the CPU address space, offsets are in bytes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Page {
+    Ram(usize),
+    Rom(usize),
+}
+
+// Copy of the RAM and ROM storage and the page map, see `Memory::storage`
+#[derive(Clone, Eq, PartialEq)]
+pub struct Storage {
+    pub ram: Vec<u8>,
+    // Shared between copies while it doesn't change
+    pub rom: Rc<Vec<u8>>,
+    pages: [Page; PAGES],
+    ram_under_rom: [Option<usize>; PAGES],
+}
+
+// Traps for running CP/M programs without a BDOS. A warm boot (JP 0x0000) hits OUT (0x00), A,
+// and CALL 0x0005 executes IN A, (0x00) followed by RET at 0x0007 where the host can service
+// the BDOS function in C. They stay patched in rather than going through the CPU's in / out
+// handlers: a handler only sees the port and value, not C, DE or memory, and the CALL needs
+// code at 0x0005 to return through either way.
+pub const CPM_TRAPS: [(u16, &[u8]); 3] = [
+    (0x0000, &[0xD3, 0x00]),
+    (0x0005, &[0xDB, 0x00]),
+    (0x0007, &[0xC9]),
+];
+
+// Called with the value written to a paging register, remaps banks through `map_bank`
+pub type BankSelect = Box<dyn FnMut(&mut Memory, u8)>;
+
+// Attributes for an address range, applied on top of the page map. Addresses not covered by
+// any region are accessed through the page map as-is.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Region {
+    Ram,
+    // Writes are ignored
+    Rom,
+    // Aliases the memory starting at the given address
+    Mirror(u16),
+    // Nothing decodes the address, reads return the open bus value and writes are dropped
+    Unmapped,
+}
+
+// The 64K address space seen by the CPU. Each 1K page is backed by either ROM or RAM storage,
+// both of which may be larger than 64K for banked machines. By default every page is mapped
+// to the matching RAM page, giving a flat 64K of RAM.
 pub struct Memory {
     pub rom: Vec<u8>,
     pub ram: Vec<u8>,
+    pages: [Page; PAGES],
+    // RAM storage under ROM pages, see `map_ram_under_rom`
+    ram_under_rom: [Option<usize>; PAGES],
+    // Value read from unmapped addresses
+    pub open_bus: u8,
+    pub log_rom_writes: bool,
+    regions: Vec<(RangeInclusive<u16>, Region)>,
+    paging: Vec<(PortDecode, BankSelect)>,
+    // Paging registers at memory addresses, see `add_mapped_paging_register`
+    mapped_paging: Vec<(RangeInclusive<u16>, BankSelect)>,
+    // Writes to paging registers of either kind, see `paging_writes`
+    paging_writes: u64,
+    mmio: Vec<(RangeInclusive<u16>, DeviceRef)>,
+    read_hook: Option<ReadHook>,
+    write_hook: Option<WriteHook>,
+    // Bit per 1K page of RAM storage written since the last `take_dirty`
+    dirty: Vec<u64>,
 }
 
 impl fmt::Debug for Memory {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let val = self;
-        write!(f, "{:?}", val)
+        f.debug_struct("Memory")
+            .field("rom", &self.rom.len())
+            .field("ram", &self.ram.len())
+            .field("open_bus", &self.open_bus)
+            .field("regions", &self.regions)
+            .finish()
     }
 }
 
@@ -24,16 +110,36 @@ impl fmt::UpperHex for Memory {
     }
 }
 
+// Indexing goes through the address map, so mirrors resolve the same way they do for the CPU
+// and unmapped addresses read as the open bus value. Unlike `write8`, indexed writes poke ROM
+// (this is how images get loaded).
 impl IndexMut<u16> for Memory {
     fn index_mut(&mut self, index: u16) -> &mut u8 {
-        &mut self.rom[index as usize]
+        let (addr, _) = self.region(index);
+        let offset = addr as usize % PAGE_SIZE;
+        match self.pages[addr as usize / PAGE_SIZE] {
+            Page::Ram(base) => {
+                self.mark_dirty(base + offset);
+                &mut self.ram[base + offset]
+            }
+            Page::Rom(base) => &mut self.rom[base + offset],
+        }
     }
 }
 
 impl Index<u16> for Memory {
     type Output = u8;
     fn index(&self, index: u16) -> &u8 {
-        &self.rom[index as usize]
+        match self.region(index) {
+            (_, Region::Unmapped) => &self.open_bus,
+            (addr, _) => {
+                let offset = addr as usize % PAGE_SIZE;
+                match self.pages[addr as usize / PAGE_SIZE] {
+                    Page::Ram(base) => &self.ram[base + offset],
+                    Page::Rom(base) => &self.rom[base + offset],
+                }
+            }
+        }
     }
 }
 
@@ -45,39 +151,665 @@ pub trait MemoryRW {
     fn write8(&mut self, addr: u16, byte: u8);
 }
 
-impl Memory {
-    pub fn default() -> Memory {
+impl Default for Memory {
+    fn default() -> Memory {
         Memory {
-            rom: vec![0; 0x1_5000],
-            ram: vec![0; 0x1_0000],
+            rom: Vec::new(),
+            ram: vec![0; ADDRESS_SPACE],
+            pages: flat_pages(),
+            ram_under_rom: [None; PAGES],
+            open_bus: 0xFF,
+            log_rom_writes: false,
+            regions: Vec::new(),
+            paging: Vec::new(),
+            mapped_paging: Vec::new(),
+            paging_writes: 0,
+            mmio: Vec::new(),
+            read_hook: None,
+            write_hook: None,
+            dirty: Vec::new(),
+        }
+    }
+}
+
+fn flat_pages() -> [Page; PAGES] {
+    let mut pages = [Page::Ram(0); PAGES];
+    for (i, page) in pages.iter_mut().enumerate() {
+        *page = Page::Ram(i * PAGE_SIZE);
+    }
+    pages
+}
+
+impl Memory {
+    pub fn page(&self, index: usize) -> Page {
+        self.pages[index]
+    }
+
+    // Maps a 1K page of the address space to ROM or RAM storage
+    pub fn map_page(&mut self, index: usize, page: Page) {
+        let (len, base) = match page {
+            Page::Ram(base) => (self.ram.len(), base),
+            Page::Rom(base) => (self.rom.len(), base),
+        };
+        assert!(
+            base + PAGE_SIZE <= len,
+            "{:?} is outside of the backing storage ({} bytes)",
+            page,
+            len
+        );
+        self.pages[index] = page;
+    }
+
+    // Maps `size` bytes of the address space starting at `addr` to consecutive storage starting
+    // at `page`, e.g. a 16K RAM bank at 0xC000. Both must be page aligned.
+    pub fn map_bank(&mut self, addr: u16, size: usize, page: Page) {
+        assert!(
+            (addr as usize).is_multiple_of(PAGE_SIZE) && size.is_multiple_of(PAGE_SIZE),
+            "Bank {:04X} ({} bytes) is not page aligned",
+            addr,
+            size
+        );
+        assert!(addr as usize + size <= ADDRESS_SPACE);
+        let first = addr as usize / PAGE_SIZE;
+        for i in 0..size / PAGE_SIZE {
+            let page = match page {
+                Page::Ram(base) => Page::Ram(base + i * PAGE_SIZE),
+                Page::Rom(base) => Page::Rom(base + i * PAGE_SIZE),
+            };
+            self.map_page(first + i, page);
+        }
+    }
+
+    // Has writes to a page mapped to ROM go to the RAM storage page at `ram` instead of
+    // being dropped, for machines whose ROMs overlay RAM (on the Amstrad CPC the RAM under an
+    // enabled ROM is still written). `None` drops them again.
+    pub fn map_ram_under_rom(&mut self, index: usize, ram: Option<usize>) {
+        if let Some(base) = ram {
+            assert!(base + PAGE_SIZE <= self.ram.len());
+        }
+        self.ram_under_rom[index] = ram;
+    }
+
+    // Grows RAM storage by `size` bytes for extra banks, returns the offset of the new storage
+    pub fn alloc_ram(&mut self, size: usize) -> usize {
+        let base = self.ram.len();
+        self.ram.resize(base + size, 0);
+        base
+    }
+
+    // Copies `data` into RAM storage at `offset`, mapped in or not, marking its pages dirty
+    pub fn load_ram(&mut self, offset: usize, data: &[u8]) {
+        self.ram[offset..offset + data.len()].copy_from_slice(data);
+        for page in (offset..offset + data.len()).step_by(PAGE_SIZE) {
+            self.mark_dirty(page);
+        }
+        if let Some(last) = (offset + data.len()).checked_sub(1) {
+            self.mark_dirty(last);
+        }
+    }
+
+    // Copies all of RAM and ROM storage and the page map, unlike `dump` which only has the
+    // mapped in banks. ROM equal to `previous`'s is shared with it.
+    pub fn storage(&self, previous: Option<&Storage>) -> Storage {
+        let rom = match previous {
+            Some(previous) if *previous.rom == self.rom => previous.rom.clone(),
+            _ => Rc::new(self.rom.clone()),
+        };
+        Storage {
+            ram: self.ram.clone(),
+            rom,
+            pages: self.pages,
+            ram_under_rom: self.ram_under_rom,
+        }
+    }
+
+    // Puts back storage copied by `storage`, marking the RAM pages that change dirty. The
+    // paging registers' own state isn't part of it, see `paging_writes`.
+    pub fn restore_storage(&mut self, storage: &Storage) {
+        self.ram.resize(storage.ram.len(), 0);
+        for offset in (0..self.ram.len()).step_by(PAGE_SIZE) {
+            let end = (offset + PAGE_SIZE).min(self.ram.len());
+            if self.ram[offset..end] != storage.ram[offset..end] {
+                self.load_ram(offset, &storage.ram[offset..end]);
+            }
+        }
+        if self.rom != *storage.rom {
+            self.rom.clone_from(&storage.rom);
+        }
+        self.pages = storage.pages;
+        self.ram_under_rom = storage.ram_under_rom;
+    }
+
+    #[inline]
+    fn mark_dirty(&mut self, offset: usize) {
+        let page = offset / PAGE_SIZE;
+        if page / 64 >= self.dirty.len() {
+            self.dirty.resize(page / 64 + 1, 0);
+        }
+        self.dirty[page / 64] |= 1 << (page % 64);
+    }
+
+    // RAM storage pages (offset / PAGE_SIZE) written by the CPU or through indexing since
+    // the last call. Direct writes to `ram` aren't seen, `load_ram` marks its pages.
+    pub fn take_dirty(&mut self) -> Vec<usize> {
+        let mut pages = Vec::new();
+        for (n, word) in self.dirty.iter_mut().enumerate() {
+            let mut bits = std::mem::take(word);
+            while bits != 0 {
+                pages.push(n * 64 + bits.trailing_zeros() as usize);
+                bits &= bits - 1;
+            }
+        }
+        pages
+    }
+
+    // Installs a paging register, `select` is called for every OUT where
+    // `port & mask == value` (e.g. mask 0x8002, value 0 for the Spectrum 128's 0x7FFD)
+    pub fn add_paging_register<F: FnMut(&mut Memory, u8) + 'static>(
+        &mut self,
+        mask: u16,
+        value: u16,
+        select: F,
+    ) {
+        self.paging
+            .push((PortDecode::Mask { mask, value }, Box::new(select)));
+    }
+
+    pub(crate) fn paging_write(&mut self, port: u16, value: u8) {
+        if self.paging.is_empty() {
+            return;
+        }
+        // Taken out while the handlers run so they can remap memory
+        let mut paging = std::mem::take(&mut self.paging);
+        for (decode, select) in paging.iter_mut() {
+            if decode.matches(port) {
+                self.paging_writes += 1;
+                select(self, value);
+            }
+        }
+        // Keep any registers a handler installed
+        let added = std::mem::replace(&mut self.paging, paging);
+        self.paging.extend(added);
+    }
+
+    // Writes to paging registers so far. Their handlers usually latch the value (the Spectrum
+    // 128's 7FFD lock and screen select), so the page map alone can only be put back while
+    // this hasn't changed.
+    pub fn paging_writes(&self) -> u64 {
+        self.paging_writes
+    }
+
+    // Installs a paging register written through memory, `select` is called for every CPU
+    // write within `range` after the write itself went through the map as usual (e.g. the
+    // Sega mapper's registers at 0xFFFC-0xFFFF, which are also RAM)
+    pub fn add_mapped_paging_register<F: FnMut(&mut Memory, u8) + 'static>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        select: F,
+    ) {
+        self.mapped_paging.push((range, Box::new(select)));
+    }
+
+    pub(crate) fn mapped_paging_write(&mut self, addr: u16, value: u8) {
+        if !self
+            .mapped_paging
+            .iter()
+            .any(|(range, _)| range.contains(&addr))
+        {
+            return;
+        }
+        let mut paging = std::mem::take(&mut self.mapped_paging);
+        for (range, select) in paging.iter_mut() {
+            if range.contains(&addr) {
+                self.paging_writes += 1;
+                select(self, value);
+            }
+        }
+        let added = std::mem::replace(&mut self.mapped_paging, paging);
+        self.mapped_paging.extend(added);
+    }
+
+    // Appends `data` to ROM storage and maps it in at `addr`. The last page is padded with
+    // 0xFF like an erased EPROM. When `addr` isn't page aligned the first page keeps what it
+    // has before `addr`: RAM takes the bytes in place (still writable, use a `Region::Rom` to
+    // protect them) and ROM is copied with `data` laid over it.
+    pub fn load_rom(&mut self, addr: u16, data: &[u8]) {
+        assert!(addr as usize + data.len() <= ADDRESS_SPACE);
+        let skip = addr as usize % PAGE_SIZE;
+        let head = if skip == 0 {
+            0
+        } else {
+            (PAGE_SIZE - skip).min(data.len())
+        };
+        let (head, data) = data.split_at(head);
+        if !head.is_empty() {
+            let index = addr as usize / PAGE_SIZE;
+            match self.pages[index] {
+                Page::Ram(base) => self.load_ram(base + skip, head),
+                Page::Rom(base) => {
+                    let copy = self.rom.len();
+                    self.rom.extend_from_within(base..base + PAGE_SIZE);
+                    self.rom[copy + skip..copy + skip + head.len()].copy_from_slice(head);
+                    self.pages[index] = Page::Rom(copy);
+                }
+            }
+        }
+        let first = (addr as usize).div_ceil(PAGE_SIZE);
+        let base = self.rom.len();
+        let pages = data.len().div_ceil(PAGE_SIZE);
+        self.rom.extend_from_slice(data);
+        self.rom.resize(base + pages * PAGE_SIZE, 0xFF);
+        for i in 0..pages {
+            self.map_page(first + i, Page::Rom(base + i * PAGE_SIZE));
+        }
+    }
+
+    // Reads through the address map without triggering hooks or memory mapped devices
+    pub fn peek(&self, addr: u16) -> u8 {
+        self[addr]
+    }
+
+    pub fn peek16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self[addr], self[addr.wrapping_add(1)]])
+    }
+
+    // Writes through the address map, ROM pages included
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self[addr] = value;
+    }
+
+    // Hexdump of `len` bytes starting at `start`, 16 bytes per line with an ASCII column:
+    // 0100  3E 42 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |>B..............|
+    pub fn dump_range(&self, start: u16, len: usize) -> String {
+        let mut out = String::new();
+        for line in (0..len).step_by(16) {
+            let addr = start.wrapping_add(line as u16);
+            let count = (len - line).min(16);
+            let bytes: Vec<u8> = (0..count)
+                .map(|i| self.peek(addr.wrapping_add(i as u16)))
+                .collect();
+
+            out.push_str(&format!("{:04X} ", addr));
+            for i in 0..16 {
+                if i == 8 {
+                    out.push(' ');
+                }
+                match bytes.get(i) {
+                    Some(byte) => out.push_str(&format!(" {:02X}", byte)),
+                    None => out.push_str("   "),
+                }
+            }
+            out.push_str("  |");
+            for byte in &bytes {
+                out.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                });
+            }
+            out.push_str("|\n");
+        }
+        out
+    }
+
+    // Bulk helpers, these write through the address map like poke (ROM pages included)
+    // and wrap at 0xFFFF.
+    pub fn load_slice(&mut self, addr: u16, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.poke(addr.wrapping_add(i as u16), *byte);
         }
     }
 
+    // Pokes each (address, bytes) patch, e.g. to trap calls into a ROM routine
+    pub fn apply_patches(&mut self, patches: &[(u16, &[u8])]) {
+        for (addr, bytes) in patches {
+            self.load_slice(*addr, bytes);
+        }
+    }
+
+    pub fn fill(&mut self, range: RangeInclusive<u16>, value: u8) {
+        for addr in range {
+            self.poke(addr, value);
+        }
+    }
+
+    // Overlapping ranges are handled like memmove
+    pub fn copy(&mut self, src: u16, dst: u16, len: usize) {
+        let data: Vec<u8> = (0..len)
+            .map(|i| self.peek(src.wrapping_add(i as u16)))
+            .collect();
+        self.load_slice(dst, &data);
+    }
+
+    // Later mappings take precedence over earlier overlapping ones
+    pub fn map(&mut self, range: RangeInclusive<u16>, region: Region) {
+        self.regions.insert(0, (range, region));
+    }
+
+    pub fn clear_regions(&mut self) {
+        self.regions.clear();
+    }
+
+    // Resolves mirrors and returns the effective address along with its attributes
+    pub fn region(&self, addr: u16) -> (u16, Region) {
+        if self.regions.is_empty() {
+            return (addr, Region::Ram);
+        }
+        let mut addr = addr;
+        // Bound the lookups so a mirror pointing into itself can't loop forever
+        for _ in 0..8 {
+            match self.regions.iter().find(|(range, _)| range.contains(&addr)) {
+                Some((range, Region::Mirror(of))) => addr = of.wrapping_add(addr - range.start()),
+                Some((_, region)) => return (addr, *region),
+                None => return (addr, Region::Ram),
+            }
+        }
+        (addr, Region::Unmapped)
+    }
+
+    // CPU accesses within `range` are serviced by the device's mem_read / mem_write instead of
+    // memory. Indexing Memory directly bypasses devices so peeking has no side effects.
+    // Later mappings take precedence over earlier overlapping ones.
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: DeviceRef) {
+        self.mmio.insert(0, (range, device));
+    }
+
+    pub fn unmap_device(&mut self, addr: u16) {
+        self.mmio.retain(|(range, _)| !range.contains(&addr));
+    }
+
+    fn mmio_device(&self, addr: u16) -> Option<&DeviceRef> {
+        self.mmio
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, device)| device)
+    }
+
+    // Whether reading `addr` reaches a memory mapped device
+    pub(crate) fn is_device(&self, addr: u16) -> bool {
+        !self.mmio.is_empty() && self.mmio_device(self.region(addr).0).is_some()
+    }
+
+    #[inline]
+    pub(crate) fn read_mapped(&self, addr: u16) -> u8 {
+        if self.mmio.is_empty() {
+            return self[addr];
+        }
+        let (target, _) = self.region(addr);
+        match self.mmio_device(target) {
+            Some(device) => device.borrow_mut().mem_read(target),
+            None => self[addr],
+        }
+    }
+
+    // Returns false if the write was dropped because the address is read-only
+    #[inline]
+    pub(crate) fn write_mapped(&mut self, addr: u16, byte: u8) -> bool {
+        if !self.mmio.is_empty() {
+            let (target, _) = self.region(addr);
+            if let Some(device) = self.mmio_device(target) {
+                device.borrow_mut().mem_write(target, byte);
+                return true;
+            }
+        }
+        match self.region(addr) {
+            (target, Region::Rom) => {
+                if self.log_rom_writes {
+                    warn!(
+                        "Ignored write to ROM {:04X} ({:04X}): {:02X}",
+                        addr, target, byte
+                    );
+                }
+                false
+            }
+            (_, Region::Unmapped) => true,
+            (target, _) => match self.pages[target as usize / PAGE_SIZE] {
+                Page::Ram(base) => {
+                    let offset = base + target as usize % PAGE_SIZE;
+                    self.mark_dirty(offset);
+                    self.ram[offset] = byte;
+                    true
+                }
+                Page::Rom(_) => match self.ram_under_rom[target as usize / PAGE_SIZE] {
+                    Some(base) => {
+                        let offset = base + target as usize % PAGE_SIZE;
+                        self.mark_dirty(offset);
+                        self.ram[offset] = byte;
+                        true
+                    }
+                    None => {
+                        if self.log_rom_writes {
+                            warn!("Ignored write to ROM {:04X}: {:02X}", addr, byte);
+                        }
+                        false
+                    }
+                },
+            },
+        }
+    }
+
+    pub fn on_read<F: Fn(u16) -> Option<u8> + 'static>(&mut self, hook: F) {
+        self.read_hook = Some(Box::new(hook));
+    }
+
+    pub fn on_write<F: FnMut(u16, u8) -> bool + 'static>(&mut self, hook: F) {
+        self.write_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.read_hook = None;
+        self.write_hook = None;
+    }
+
+    #[inline]
+    pub(crate) fn hook_read(&self, addr: u16) -> Option<u8> {
+        match self.read_hook {
+            Some(ref hook) => hook(addr),
+            None => None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn hook_write(&mut self, addr: u16, byte: u8) -> bool {
+        match self.write_hook {
+            Some(ref mut hook) => hook(addr, byte),
+            None => false,
+        }
+    }
+
+    // Loads each file after the previous one starting at 0x0000, unless the argument gives an
+    // origin as `file.bin@8000` (hex). HEX and S-record files load where they say.
     pub fn load_bin(&mut self, rom: &[String]) {
-        let mut buf = Vec::new();
-        let mut collection: Vec<&str> = Vec::new();
+        let mut next = 0;
+        for arg in rom.iter().skip(1) {
+            let (file, org) = parse_origin(arg);
+            let org = org.unwrap_or(next);
+            let len = self
+                .load_file(file, org)
+                .unwrap_or_else(|e| panic!("Failed to load {}: {}", file, e));
+            if segment_parser(file).is_none() {
+                next = org.wrapping_add(len as u16);
+            }
+        }
+    }
 
-        for i in rom.iter().skip(1) {
-            collection.push(&i);
+    // Loads a HEX, S-record or assembler source file (picked by extension) at its own
+    // addresses, anything else as a binary at `org`
+    pub fn load_file(&mut self, path: &str, org: u16) -> io::Result<usize> {
+        match segment_parser(path) {
+            Some(parse) => self.load_segments(path, parse),
+            None => self.load_bin_at(path, org),
         }
+    }
 
-        for f in collection.iter() {
-            let path = Path::new(f);
-            let mut file = File::open(&path).unwrap();
-            file.read_to_end(&mut buf).expect("Failed to read binary");
-            self.rom[..buf.len()].clone_from_slice(&buf[..]);
-            println!("Loaded: {:?} Bytes: {:?}", path, buf.len());
+    // Loads a binary at `org` through the address map, returns the amount of bytes loaded
+    pub fn load_bin_at<P: AsRef<Path>>(&mut self, path: P, org: u16) -> io::Result<usize> {
+        let path = path.as_ref();
+        let buf = archive::read(path)?;
+        if org as usize + buf.len() > ADDRESS_SPACE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes don't fit at {:04X}", buf.len(), org),
+            ));
         }
+        self.load_slice(org, &buf);
+        info!("Loaded {:?} at {:04X}, {} bytes", path, org, buf.len());
+        Ok(buf.len())
+    }
+
+    // Loads an Intel HEX file through the address map, returns the amount of bytes loaded
+    pub fn load_ihex<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        self.load_segments(path, ihex::parse)
+    }
+
+    pub fn read_ihex<R: Read>(&mut self, reader: R) -> io::Result<usize> {
+        self.read_segments(reader, ihex::parse)
+    }
+
+    // Same for Motorola S-records
+    pub fn load_srec<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        self.load_segments(path, srec::parse)
+    }
+
+    pub fn read_srec<R: Read>(&mut self, reader: R) -> io::Result<usize> {
+        self.read_segments(reader, srec::parse)
+    }
+
+    fn load_segments<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        parse: ParseSegments,
+    ) -> io::Result<usize> {
+        let path = path.as_ref();
+        let len = self.read_segments(archive::open(path)?, parse)?;
+        info!("Loaded {:?}, {} bytes", path, len);
+        Ok(len)
+    }
+
+    fn read_segments<R: Read>(&mut self, mut reader: R, parse: ParseSegments) -> io::Result<usize> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let segments = parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (addr, data) in &segments {
+            self.load_slice(*addr, data);
+        }
+        Ok(segments.iter().map(|(_, data)| data.len()).sum())
+    }
+
+    // Writes the 64K address space as currently mapped to `path`, the raw image other tools
+    // can load, and the page map to `path.banks`: a line per 1K page with its address, the
+    // storage behind it and the offset in that storage, e.g. `C000 ram 01C000`.
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let image: Vec<u8> = (0..=0xFFFF).map(|addr| self.peek(addr)).collect();
+        fs::write(path, image)?;
+        let mut banks = format!("# ram {} rom {}\n", self.ram.len(), self.rom.len());
+        for (n, page) in self.pages.iter().enumerate() {
+            let (kind, offset) = match page {
+                Page::Ram(offset) => ("ram", offset),
+                Page::Rom(offset) => ("rom", offset),
+            };
+            banks.push_str(&format!("{:04X} {} {:06X}\n", n * PAGE_SIZE, kind, offset));
+        }
+        fs::write(banks_path(path), banks)
+    }
+
+    // Loads an image written by `dump`. The page map is put back first if `path.banks`
+    // exists, so the image lands in the banks that were mapped in when it was taken. Banks
+    // that weren't mapped in keep their contents.
+    pub fn restore<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let image = archive::read(path)?;
+        if image.len() != ADDRESS_SPACE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes, a memory image is 64K", image.len()),
+            ));
+        }
+        match fs::read_to_string(banks_path(path)) {
+            Ok(banks) => {
+                let pages = self
+                    .parse_banks(&banks)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.pages = pages;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.load_slice(0, &image);
+        Ok(())
+    }
+
+    fn parse_banks(&self, text: &str) -> Result<[Page; PAGES], String> {
+        let mut pages = self.pages;
+        let lines = text
+            .lines()
+            .filter(|l| !l.starts_with('#') && !l.trim().is_empty());
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let number = |n: usize| {
+                fields
+                    .get(n)
+                    .and_then(|f| usize::from_str_radix(f, 16).ok())
+                    .ok_or_else(|| format!("Bad page line `{}`", line))
+            };
+            let (addr, offset) = (number(0)?, number(2)?);
+            let (page, len) = match fields[1] {
+                "ram" => (Page::Ram(offset), self.ram.len()),
+                "rom" => (Page::Rom(offset), self.rom.len()),
+                kind => return Err(format!("Unknown storage `{}`", kind)),
+            };
+            if addr >= ADDRESS_SPACE || addr % PAGE_SIZE != 0 || offset + PAGE_SIZE > len {
+                return Err(format!("Page `{}` doesn't fit this memory", line));
+            }
+            pages[addr / PAGE_SIZE] = page;
+        }
+        Ok(pages)
     }
 
     pub fn load_tests(&mut self, file: &str) {
         let path = Path::new(file);
-        let mut file = File::open(&path).expect(&*format!("Couldn't load binary file {:?}", path));
-        let mut buf = Vec::new();
-
-        file.read_to_end(&mut buf).expect("Failed to read binary");
+        let buf = archive::read(path)
+            .unwrap_or_else(|e| panic!("Couldn't load binary file {:?}: {}", path, e));
         // Tests are loaded at 0x0100
-        self.rom[0x0100..(buf.len() + 0x0100)].clone_from_slice(&buf[..]);
-        println!("Test loaded: {:?} Bytes: {:?}\n", path, buf.len());
+        self.load_slice(0x0100, &buf);
+        info!("Test loaded: {:?}, {} bytes", path, buf.len());
+    }
+}
+
+fn banks_path(path: &Path) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".banks");
+    name.into()
+}
+
+type ParseSegments = fn(&str) -> Result<Vec<Segment>, String>;
+
+// Parser for files that carry their own load addresses, by extension
+fn segment_parser(path: &str) -> Option<ParseSegments> {
+    let path = archive::inner(Path::new(path)).to_str().unwrap_or(path);
+    if ihex::is_ihex(path) {
+        Some(ihex::parse)
+    } else if srec::is_srec(path) {
+        Some(srec::parse)
+    } else if assembler::is_source(path) {
+        Some(assembler::parse)
+    } else {
+        None
+    }
+}
+
+// Splits `file.bin@8000` into the path and origin, arguments without a valid hex origin are
+// treated as a plain path. So is an existing file, `game@bad` and `rom@c0` are file names too.
+pub fn parse_origin(arg: &str) -> (&str, Option<u16>) {
+    if Path::new(arg).exists() {
+        return (arg, None);
+    }
+    if let Some((file, org)) = arg.rsplit_once('@') {
+        let org = org.trim_start_matches("0x").trim_start_matches("0X");
+        if let Ok(org) = u16::from_str_radix(org, 16) {
+            return (file, Some(org));
+        }
     }
+    (arg, None)
 }
@@ -0,0 +1,840 @@
+use std::io::{self, Write};
+
+use crate::assembler::assemble_at;
+use crate::coverage::Coverage;
+use crate::debugger::{StopReason, Trap, WatchKind};
+use crate::disassembler::{self, disassemble_at};
+use crate::expr::Expr;
+use crate::interconnect::Interconnect;
+use crate::profile::{MemoryStats, OpcodeProfile};
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+use crate::snapshot::{self, Snapshot};
+use crate::symbols::Symbols;
+use crate::trace::{TraceEntry, TraceFilter, TraceFormat};
+use crate::zx_snapshot::{self, ZxSnapshot};
+
+const HELP: &str = "\
+s, step [n]              Execute n instructions (default 1)
+n, next                  Step over CALL and RST
+o, out                   Run until the current subroutine returns
+c, continue              Run until a breakpoint, watchpoint or break event
+back [n]                 Step back n instructions (default 1), needs rewind on
+rewind on [n] | off      Keep a checkpoint per frame for the last n frames (default 600)
+rewind <n>               Go back n frames
+record on | save <file>  Log device reads and interrupts, write the log to a file
+replay <file>            Feed a saved input log back in from here
+r, regs                  Show registers
+d, dis [addr] [n]        Disassemble n instructions (default PC, 10)
+m, mem <addr> [len]      Hex dump len bytes (default 64)
+a, asm <addr> [code]     Assemble into memory, statements separated by ` / `, e.g.
+                         a 8000 LD A, 5 / OUT (0xFE), A. Without code, assembles a line
+                         at a time until an empty line.
+b, break [addr] [if ..]  Set a breakpoint, optionally with a condition. Lists without addr.
+                         addr can also be file.c:line with SDCC debug info loaded
+delete <addr>            Remove a breakpoint
+enable <addr>            Enable a breakpoint
+disable <addr>           Disable a breakpoint
+w, watch <addr[..end]> [r|w|rw]  Set a memory watchpoint (default w)
+unwatch <id>             Remove a watchpoint
+breakon <class> [on|off] Toggle interrupt, reti, rst, stack or all
+breakon sp <lo> <hi>|off Stop when SP leaves lo..hi (lo and hi included)
+breakon rom|unmapped [on|off|log]  Trap writes to ROM or executing unmapped memory
+history [n]              Show the last n executed instructions (default 20)
+history depth <n>        Keep n instructions of history, 0 disables it
+bt, backtrace            Show the return addresses of active calls and interrupts
+compare <file>           Stop at the first difference from a reference trace
+trace <file> [fmt] [n] | off  Stream a trace line per instruction, fmt is text, json,
+                         csv or mame. Loops of up to n instructions are collapsed
+                         (MAME traces skip PCs among the last n written, as MAME does)
+tfilter range <spec>     Only trace PCs in hex ranges, e.g. 0100-7FFF,C000-C0FF
+tfilter class <spec>     Only trace jump, call, ret, io, block or stack instructions
+tfilter clear            Trace every instruction
+tformat <template> | off Lay text trace lines out with {field} placeholders, e.g.
+                         {pc}: {asm:20} AF={af:x} {flags} {cycles:>8}, off for the default
+coverage [on [counts]|off|clear]  Collect executed addresses, lists them without args
+coverage save <file>     Write the executed ranges (and hit counts) to a file
+profile [on|off|clear]   Count executions per opcode
+profile [n]              Show the n most executed opcodes (default 20)
+memstats [on|off|clear]  Count memory reads and writes per address
+memstats region <name> <start> <end>  Report totals for an address range
+memstats [n]             Show region totals and the n most accessed addresses
+sym load <file>          Load a .sym / .map file (name=addr per line) or SDCC .cdb file
+sym [name]               List the symbols, or those starting with name
+sym clear                Forget all symbols
+snap save|load|diff <file>  Save the CPU state and memory, restore it, or show what
+                         changed since it was saved. .sna, .z80 and .szx files hold
+                         Spectrum RAM and registers only.
+script <addr> <file>     Run a Rhai script when PC reaches addr (scripting builds)
+unhook <addr>            Remove the script or PC hook at addr
+save <file>              Write the 64K address space to a file, the page map to file.banks
+restore <file>           Load a 64K image written by save, with its page map if present
+q, quit                  Exit
+Addresses and counts accept expressions without spaces, e.g. HL+2, mem16[SP] or main+3.
+An empty line repeats the last command.";
+
+// Command interpreter for the interactive debugger. Kept separate from stdin so
+// frontends (and tests) can feed it lines and collect the output.
+#[derive(Default)]
+pub struct Monitor {
+    last: String,
+    // Where the next line goes while `asm` takes a line at a time
+    assembling: Option<u16>,
+}
+
+impl Monitor {
+    // Executes a single command line, returns false once the user asked to quit
+    pub fn run_command<W: Write>(
+        &mut self,
+        i: &mut Interconnect,
+        line: &str,
+        out: &mut W,
+    ) -> io::Result<bool> {
+        if let Some(addr) = self.assembling {
+            self.assembling = match line.trim() {
+                "" => None,
+                line => Some(assemble(i, addr, line, out)?),
+            };
+            return Ok(true);
+        }
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => line.to_string(),
+        };
+        self.last = line.clone();
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let args: Vec<&str> = args.collect();
+
+        match command {
+            "s" | "step" => {
+                let count = arg_or(i, &args, 0, 1)?;
+                for _ in 0..count {
+                    if let Some(stop) = i.step().stop {
+                        print_stop(i, stop, out)?;
+                        break;
+                    }
+                }
+                self.print_next(i, out)?;
+            }
+            "n" | "next" | "o" | "out" => {
+                let result = match command {
+                    "n" | "next" => i.step_over(),
+                    _ => i.step_out(),
+                };
+                if let Some(stop) = result.stop {
+                    print_stop(i, stop, out)?;
+                }
+                self.print_next(i, out)?;
+            }
+            "c" | "continue" => {
+                let stop = run(i);
+                print_stop(i, stop, out)?;
+                self.print_next(i, out)?;
+            }
+            "back" => {
+                let count = arg_or(i, &args, 0, 1)? as u64;
+                if i.rewind.is_none() {
+                    return Err(invalid(NO_REWIND));
+                }
+                if !i.step_back(count) {
+                    writeln!(out, "Can't go back that far")?;
+                }
+                self.print_next(i, out)?;
+            }
+            "rewind" => match args.first().copied() {
+                Some("on") => {
+                    let frames = arg_or(i, &args, 1, 600)? as usize;
+                    i.enable_rewind(frames);
+                }
+                Some("off") => i.disable_rewind(),
+                _ => {
+                    let frames = arg_or(i, &args, 0, 1)? as usize;
+                    if i.rewind.is_none() {
+                        return Err(invalid(NO_REWIND));
+                    }
+                    if !i.rewind_frames(frames) {
+                        writeln!(out, "Can't go back that far")?;
+                    }
+                    self.print_next(i, out)?;
+                }
+            },
+            "record" => match args.first().copied() {
+                Some("on") => i.record_inputs(),
+                Some("save") => {
+                    let path = args.get(1).ok_or_else(|| invalid("Missing file name"))?;
+                    i.save_inputs(path)?;
+                    writeln!(out, "Saved {} inputs to {}", i.cpu.input.len(), path)?;
+                }
+                _ => return Err(invalid("Expected on or save")),
+            },
+            "replay" => {
+                let path = args.first().ok_or_else(|| invalid("Missing file name"))?;
+                i.replay_inputs(path)?;
+                writeln!(out, "Replaying {} inputs", i.cpu.input.remaining())?;
+            }
+            "r" | "regs" => writeln!(out, "{:?}", i.cpu)?,
+            "d" | "dis" => {
+                let mut addr = arg_or(i, &args, 0, i.cpu.reg.pc as i64)? as u16;
+                for _ in 0..arg_or(i, &args, 1, 10)? {
+                    if let Some(name) = i.symbols.name(addr) {
+                        writeln!(out, "{}:", name)?;
+                    }
+                    if let Some(line) = i.symbols.line_at(addr) {
+                        writeln!(out, "; {}", line)?;
+                    }
+                    let (text, size) = disassemble(i, addr);
+                    writeln!(out, "{}", text)?;
+                    addr = addr.wrapping_add(size as u16);
+                }
+            }
+            "m" | "mem" => {
+                let addr = arg(i, &args, 0)? as u16;
+                let len = arg_or(i, &args, 1, 64)? as usize;
+                write!(out, "{}", i.dump_range(addr, len))?;
+            }
+            "a" | "asm" => {
+                let addr = arg(i, &args, 0)? as u16;
+                if args.len() == 1 {
+                    self.assembling = Some(addr);
+                    // An empty line ends it, rather than repeating this
+                    self.last.clear();
+                } else {
+                    let statements: Vec<String> = args[1..]
+                        .split(|arg| *arg == "/")
+                        .map(|statement| statement.join(" "))
+                        .collect();
+                    assemble(i, addr, &statements.join("\n"), out)?;
+                }
+            }
+            "b" | "break" if args.is_empty() => {
+                for bp in i.breakpoints.list() {
+                    write!(out, "{:04X}", bp.addr)?;
+                    if let Some(name) = i.symbols.describe(bp.addr) {
+                        write!(out, " {}", name)?;
+                    }
+                    write!(out, "  hits: {}", bp.hits)?;
+                    if !bp.enabled {
+                        write!(out, "  (disabled)")?;
+                    }
+                    if let Some(condition) = &bp.condition {
+                        write!(out, "  if {}", condition)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            "b" | "break" => {
+                let addr = location(i, args[0])?;
+                let condition = match args.get(1) {
+                    Some(&"if") => {
+                        Some(Expr::parse_with(&args[2..].join(" "), &i.symbols).map_err(invalid)?)
+                    }
+                    Some(other) => {
+                        return Err(invalid(format!("Expected `if`, found `{}`", other)))
+                    }
+                    None => None,
+                };
+                i.breakpoints.add(addr);
+                i.breakpoints.set_condition(addr, condition);
+                writeln!(out, "Breakpoint at {:04X}", addr)?;
+            }
+            "delete" | "enable" | "disable" => {
+                let addr = arg(i, &args, 0)? as u16;
+                let found = match command {
+                    "delete" => i.breakpoints.remove(addr),
+                    enable => i.breakpoints.enable(addr, enable == "enable"),
+                };
+                if !found {
+                    writeln!(out, "No breakpoint at {:04X}", addr)?;
+                }
+            }
+            "w" | "watch" => {
+                let range = args.first().ok_or_else(|| invalid("Missing address"))?;
+                let (start, end) = match range.split_once("..") {
+                    Some((start, end)) => (eval(i, start)?, eval(i, end)?),
+                    None => (eval(i, range)?, eval(i, range)?),
+                };
+                let kind = match args.get(1).copied().unwrap_or("w") {
+                    "r" => WatchKind::Read,
+                    "w" => WatchKind::Write,
+                    "rw" => WatchKind::Access,
+                    other => return Err(invalid(format!("Unknown watch kind `{}`", other))),
+                };
+                let id = i.cpu.watchpoints.add(start as u16..=end as u16, kind);
+                writeln!(out, "Watchpoint {} on {:04X}..{:04X}", id, start, end)?;
+            }
+            "unwatch" => {
+                let id = arg(i, &args, 0)? as usize;
+                if !i.cpu.watchpoints.remove(id) {
+                    writeln!(out, "No watchpoint {}", id)?;
+                }
+            }
+            "breakon" if args.first() == Some(&"sp") => {
+                let bounds = match args.get(1) {
+                    Some(&"off") => None,
+                    _ => Some(arg(i, &args, 1)? as u16..=arg(i, &args, 2)? as u16),
+                };
+                i.cpu.break_on.stack_bounds = bounds;
+                i.cpu.break_on.outside_stack = false;
+            }
+            "breakon" if matches!(args.first(), Some(&"rom") | Some(&"unmapped")) => {
+                let trap: Trap = args.get(1).unwrap_or(&"on").parse().map_err(invalid)?;
+                match args[0] {
+                    "rom" => i.cpu.break_on.rom_write = trap,
+                    _ => i.cpu.break_on.unmapped_exec = trap,
+                }
+            }
+            "breakon" => {
+                let enabled = match args.get(1).copied().unwrap_or("on") {
+                    "on" => true,
+                    "off" => false,
+                    other => return Err(invalid(format!("Expected on or off, found `{}`", other))),
+                };
+                let break_on = &mut i.cpu.break_on;
+                match args.first().copied() {
+                    Some("interrupt") => break_on.interrupt = enabled,
+                    Some("reti") => break_on.reti = enabled,
+                    Some("rst") => break_on.rst = enabled,
+                    Some("stack") => break_on.stack_write = enabled,
+                    Some("all") => break_on.set_all(enabled),
+                    _ => return Err(invalid("Expected interrupt, reti, rst, stack or all")),
+                }
+            }
+            "history" if args.first() == Some(&"depth") => {
+                let depth = arg(i, &args, 1)? as usize;
+                i.history.set_depth(depth);
+            }
+            "history" => {
+                if !i.history.enabled() {
+                    writeln!(out, "History is disabled, see `history depth`")?;
+                }
+                let count = arg_or(i, &args, 0, 20)? as usize;
+                write!(out, "{}", i.history.dump(count))?;
+            }
+            "bt" | "backtrace" => {
+                if !i.call_stack.enabled {
+                    writeln!(out, "Call tracking was off, enabling it from here on")?;
+                    i.call_stack.enabled = true;
+                }
+                write!(out, "{}", i.backtrace())?;
+            }
+            "compare" => {
+                let path = args.first().ok_or_else(|| invalid("Missing file name"))?;
+                i.compare_with(path)?;
+                writeln!(out, "Comparing against {}", path)?;
+            }
+            "trace" => match args.first() {
+                Some(&"off") => {
+                    i.flush_trace();
+                    i.tracer = None;
+                }
+                Some(path) => {
+                    let format = match args.get(1) {
+                        Some(format) => format.parse().map_err(invalid)?,
+                        None => TraceFormat::Text,
+                    };
+                    let window = arg_or(i, &args, 2, 0)? as usize;
+                    i.trace_to_file(path, format)?;
+                    if let Some(tracer) = &mut i.tracer {
+                        tracer.set_compression(window);
+                    }
+                    writeln!(out, "Tracing to {}", path)?;
+                }
+                None => return Err(invalid("Missing file name")),
+            },
+            "tfilter" => {
+                let tracer = i
+                    .tracer
+                    .as_mut()
+                    .ok_or_else(|| invalid("Not tracing, see `trace`"))?;
+                let spec = args.get(1).copied().unwrap_or("");
+                match args.first().copied() {
+                    Some("range") => tracer.filter.add_ranges(spec).map_err(invalid)?,
+                    Some("class") => tracer.filter.add_classes(spec).map_err(invalid)?,
+                    Some("clear") => tracer.filter = TraceFilter::default(),
+                    _ => return Err(invalid("Expected range, class or clear")),
+                }
+            }
+            "tformat" => {
+                let tracer = i
+                    .tracer
+                    .as_mut()
+                    .ok_or_else(|| invalid("Not tracing, see `trace`"))?;
+                tracer.template = match line[command.len()..].trim() {
+                    "" => return Err(invalid("Missing template")),
+                    "off" => None,
+                    template => Some(template.parse().map_err(invalid)?),
+                };
+            }
+            "coverage" => match args.first().copied() {
+                Some("on") => i.coverage = Some(Coverage::new(args.get(1) == Some(&"counts"))),
+                Some("off") => i.coverage = None,
+                Some("clear") => i.coverage.iter_mut().for_each(Coverage::clear),
+                Some("save") => {
+                    let path = args.get(1).ok_or_else(|| invalid("Missing file name"))?;
+                    let coverage = i.coverage.as_ref().ok_or_else(|| invalid(NO_COVERAGE))?;
+                    coverage.save(path)?;
+                }
+                Some(_) => return Err(invalid("Expected on, off, clear or save")),
+                None => {
+                    let coverage = i.coverage.as_ref().ok_or_else(|| invalid(NO_COVERAGE))?;
+                    let ranges = coverage.ranges();
+                    writeln!(
+                        out,
+                        "{} addresses executed in {} ranges",
+                        coverage.len(),
+                        ranges.len()
+                    )?;
+                    for range in ranges {
+                        writeln!(out, "{:04X}-{:04X}", range.start(), range.end())?;
+                    }
+                }
+            },
+            "profile" => match args.first().copied() {
+                Some("on") => i.profile = Some(OpcodeProfile::default()),
+                Some("off") => i.profile = None,
+                Some("clear") => i.profile.iter_mut().for_each(OpcodeProfile::clear),
+                _ => {
+                    let limit = arg_or(i, &args, 0, 20)? as usize;
+                    let profile = i
+                        .profile
+                        .as_ref()
+                        .ok_or_else(|| invalid("Profiling is off, see `profile on`"))?;
+                    write!(out, "{}", profile.summary(limit))?;
+                }
+            },
+            "memstats" => match args.first().copied() {
+                Some("on") => i.cpu.mem_stats = Some(MemoryStats::default()),
+                Some("off") => i.cpu.mem_stats = None,
+                Some("clear") => i.cpu.mem_stats.iter_mut().for_each(MemoryStats::clear),
+                Some("region") => {
+                    let name = args.get(1).ok_or_else(|| invalid("Missing region name"))?;
+                    let start = arg(i, &args, 2)? as u16;
+                    let end = arg(i, &args, 3)? as u16;
+                    i.cpu
+                        .mem_stats
+                        .as_mut()
+                        .ok_or_else(|| invalid(NO_MEM_STATS))?
+                        .add_region(name, start..=end);
+                }
+                _ => {
+                    let limit = arg_or(i, &args, 0, 10)? as usize;
+                    let stats = i
+                        .cpu
+                        .mem_stats
+                        .as_ref()
+                        .ok_or_else(|| invalid(NO_MEM_STATS))?;
+                    write!(out, "{}", stats.summary(limit))?;
+                }
+            },
+            "sym" => match args.first().copied() {
+                Some("clear") => i.symbols = Symbols::default(),
+                Some("load") => {
+                    let path = args.get(1).ok_or_else(|| invalid("Missing file name"))?;
+                    let symbols = Symbols::load(path)?;
+                    i.symbols.extend(&symbols);
+                    if let Some(tracer) = &mut i.tracer {
+                        tracer.symbols = i.symbols.clone();
+                    }
+                    writeln!(out, "Loaded {} symbols from {}", symbols.len(), path)?;
+                }
+                prefix => {
+                    for (addr, name) in i.symbols.iter() {
+                        if name.starts_with(prefix.unwrap_or("")) {
+                            writeln!(out, "{:04X}  {}", addr, name)?;
+                        }
+                    }
+                }
+            },
+            "snap" => {
+                let path = args.get(1).ok_or_else(|| invalid("Missing file name"))?;
+                let zx = zx_snapshot::is_zx_snapshot(path);
+                match args.first().copied() {
+                    // White, the border colour after reset
+                    Some("save") if zx => ZxSnapshot::capture(&i.cpu, 7).save(path)?,
+                    Some("load") if zx => ZxSnapshot::load(path)?.restore(&mut i.cpu),
+                    Some("save") => Snapshot::capture(&i.cpu).save(path)?,
+                    Some("load") => Snapshot::load(path)?.restore(&mut i.cpu),
+                    Some("diff") => {
+                        let saved = Snapshot::load(path)?;
+                        write!(
+                            out,
+                            "{}",
+                            snapshot::diff(&saved, &Snapshot::capture(&i.cpu))
+                        )?;
+                    }
+                    _ => return Err(invalid("Expected save, load or diff")),
+                }
+            }
+            #[cfg(feature = "scripting")]
+            "script" => {
+                let addr = location(i, args.first().ok_or_else(|| invalid("Missing address"))?)?;
+                let path = args.get(1).ok_or_else(|| invalid("Missing file name"))?;
+                i.on_pc_script(addr, Script::load(path)?);
+                writeln!(out, "Running {} at {:04X}", path, addr)?;
+            }
+            "unhook" => {
+                let addr = arg(i, &args, 0)? as u16;
+                if !i.remove_pc_hook(addr) {
+                    writeln!(out, "No hook at {:04X}", addr)?;
+                }
+            }
+            "save" => {
+                let path = args.first().ok_or_else(|| invalid("Missing file name"))?;
+                i.cpu.memory.dump(path)?;
+                writeln!(
+                    out,
+                    "Saved 64K to {} and the page map to {}.banks",
+                    path, path
+                )?;
+            }
+            "restore" => {
+                let path = args.first().ok_or_else(|| invalid("Missing file name"))?;
+                i.cpu.memory.restore(path)?;
+                writeln!(out, "Restored 64K from {}", path)?;
+            }
+            "q" | "quit" => return Ok(false),
+            "h" | "help" | "?" => writeln!(out, "{}", HELP)?,
+            _ => writeln!(out, "Unknown command `{}`, try `help`", command)?,
+        }
+        Ok(true)
+    }
+
+    // What frontends show before reading a command, the address while assembling
+    pub fn prompt(&self) -> String {
+        match self.assembling {
+            Some(addr) => format!("{:04X}> ", addr),
+            None => "> ".to_string(),
+        }
+    }
+
+    fn print_next<W: Write>(&self, i: &Interconnect, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{:?}", i.cpu)?;
+        if let Some(line) = i.symbols.source_line(i.cpu.reg.pc) {
+            writeln!(out, "; {}", line)?;
+        }
+        writeln!(out, "{}", disassemble(i, i.cpu.reg.pc).0)
+    }
+}
+
+// Prints why execution stopped, with the details of a golden trace mismatch
+pub fn print_stop<W: Write>(i: &Interconnect, stop: StopReason, out: &mut W) -> io::Result<()> {
+    match (stop, &i.golden) {
+        (StopReason::TraceMismatch(_), Some(golden)) => {
+            write!(out, "{}", golden.report(&i.history, 16))
+        }
+        _ => writeln!(out, "{}", stop),
+    }
+}
+
+const NO_COVERAGE: &str = "Coverage is off, see `coverage on`";
+const NO_REWIND: &str = "Rewind is off, see `rewind on`";
+const NO_MEM_STATS: &str = "Memory statistics are off, see `memstats on`";
+
+// Printed when the core panics: recent history, registers and the code around the
+// instruction that was executing
+pub fn crash_report<W: Write>(i: &Interconnect, out: &mut W) -> io::Result<()> {
+    let pc = i.cpu.instruction_pc();
+    if i.history.is_empty() {
+        writeln!(out, "No instruction history recorded")?;
+    } else {
+        writeln!(out, "Last {} instructions:", i.history.len().min(64))?;
+        write!(out, "{}", i.history.dump(64))?;
+    }
+    writeln!(out, "Registers:")?;
+    let entry = TraceEntry::new(pc, [0, 1, 2, 3].map(|n| i.peek(pc.wrapping_add(n))), &i.cpu);
+    writeln!(
+        out,
+        "{} I={:02X} R={:02X} IM={} IFF={}{}",
+        entry.line(),
+        i.cpu.reg.i,
+        i.cpu.reg.r,
+        i.cpu.int.mode,
+        i.cpu.int.iff1 as u8,
+        i.cpu.int.iff2 as u8
+    )?;
+    if i.call_stack.enabled {
+        writeln!(out, "Backtrace:")?;
+        write!(out, "{}", i.call_stack.backtrace(pc, &i.symbols))?;
+    }
+    writeln!(out, "Code:")?;
+    let mut addr = context_start(i, pc, 5);
+    for _ in 0..11 {
+        let (text, size) = disassemble(i, addr);
+        let marker = if addr == pc { '>' } else { ' ' };
+        writeln!(out, "{}{}", marker, text)?;
+        addr = addr.wrapping_add(size as u16);
+    }
+    Ok(())
+}
+
+// Runs until the debugger stops execution
+pub fn run(i: &mut Interconnect) -> StopReason {
+    loop {
+        if let Some(stop) = i.step().stop {
+            return stop;
+        }
+    }
+}
+
+// Formats the instruction at `addr` as `addr  bytes  mnemonic`, returns the line and
+// instruction size
+pub fn disassemble(i: &Interconnect, addr: u16) -> (String, u8) {
+    let line = disassemble_at(&i.cpu.memory, addr);
+    (line.with_symbols(&i.symbols), line.size())
+}
+
+// See `disassembler::context_start`
+pub fn context_start(i: &Interconnect, addr: u16, before: usize) -> u16 {
+    disassembler::context_start(&i.cpu.memory, addr, before)
+}
+
+// Assembles `source` into memory at `addr` and shows the disassembly of what was written,
+// returns the address after it
+fn assemble<W: Write>(
+    i: &mut Interconnect,
+    addr: u16,
+    source: &str,
+    out: &mut W,
+) -> io::Result<u16> {
+    let assembly = assemble_at(source, addr, &i.symbols).map_err(invalid)?;
+    assembly.load(&mut i.cpu.memory);
+    let mut next = addr;
+    for (start, data) in &assembly.segments {
+        let mut done = 0;
+        while done < data.len() {
+            let (text, size) = disassemble(i, start.wrapping_add(done as u16));
+            writeln!(out, "{}", text)?;
+            done += size as usize;
+        }
+        next = start.wrapping_add(data.len() as u16);
+    }
+    Ok(next)
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+fn eval(i: &Interconnect, source: &str) -> io::Result<i64> {
+    Ok(Expr::parse_with(source, &i.symbols)
+        .map_err(invalid)?
+        .eval(&i.cpu))
+}
+
+// An address expression, or `file:line` if source lines were loaded
+fn location(i: &Interconnect, source: &str) -> io::Result<u16> {
+    let line = source
+        .rsplit_once(':')
+        .and_then(|(file, line)| Some((file, line.parse().ok()?)));
+    match line {
+        Some((file, line)) => i
+            .symbols
+            .line_addr(file, line)
+            .ok_or_else(|| invalid(format!("No code for {}", source))),
+        None => Ok(eval(i, source)? as u16),
+    }
+}
+
+fn arg(i: &Interconnect, args: &[&str], n: usize) -> io::Result<i64> {
+    match args.get(n) {
+        Some(source) => eval(i, source),
+        None => Err(invalid("Missing argument")),
+    }
+}
+
+fn arg_or(i: &Interconnect, args: &[&str], n: usize, default: i64) -> io::Result<i64> {
+    match args.get(n) {
+        Some(source) => eval(i, source),
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{context_start, crash_report, Monitor};
+    use crate::interconnect::{Interconnect, Preset};
+
+    fn command(monitor: &mut Monitor, i: &mut Interconnect, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(monitor.run_command(i, line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn step_and_break() {
+        // LD A, 5; DEC A; JR NZ, -3; LD (0x8000), A
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x05, 0x3D, 0x20, 0xFD, 0x32, 0x00, 0x80]);
+        let mut monitor = Monitor::default();
+
+        let out = command(&mut monitor, &mut i, "d 0x100 3");
+        assert_eq!(
+            out,
+            "0100  3E 05       LD A, $05\n\
+             0102  3D          DEC A\n\
+             0103  20 FD       JR NZ, $0102\n"
+        );
+
+        command(&mut monitor, &mut i, "step");
+        assert_eq!(i.cpu.reg.pc, 0x0102);
+        command(&mut monitor, &mut i, "");
+        assert_eq!(i.cpu.reg.pc, 0x0103);
+
+        command(&mut monitor, &mut i, "b $103 if A == 2");
+        assert_eq!(
+            command(&mut monitor, &mut i, "b"),
+            "0103  hits: 0  if A == 2\n"
+        );
+        let out = command(&mut monitor, &mut i, "c");
+        assert!(out.starts_with("Breakpoint at 0103\n"), "{}", out);
+        assert_eq!(i.cpu.reg.a, 2);
+
+        command(&mut monitor, &mut i, "delete 0x103");
+        command(&mut monitor, &mut i, "watch 0x8000");
+        let out = command(&mut monitor, &mut i, "c");
+        assert!(out.starts_with("Watchpoint 0: Write 8000 00 -> 00 at PC 0105\n"));
+
+        let out = command(&mut monitor, &mut i, "m PC-8 8");
+        assert!(out.starts_with("0100  3E 05 3D 20 FD 32 00 80"), "{}", out);
+        assert!(command(&mut monitor, &mut i, "delete 0x103").starts_with("No breakpoint"));
+        assert!(command(&mut monitor, &mut i, "bogus").starts_with("Unknown command"));
+    }
+
+    #[test]
+    fn assemble() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.symbols.insert("port", 0xFE);
+        let mut monitor = Monitor::default();
+        assert_eq!(
+            command(&mut monitor, &mut i, "a 0x8000 LD A, 10/2 / OUT (port), A"),
+            "8000  3E 05       LD A, $05\n\
+             8002  D3 FE       OUT ($FE), A\n"
+        );
+
+        command(&mut monitor, &mut i, "asm 0x100");
+        assert_eq!(monitor.prompt(), "0100> ");
+        command(&mut monitor, &mut i, "loop: INC A");
+        let mut out = Vec::new();
+        assert!(monitor.run_command(&mut i, "JP (Q)", &mut out).is_err());
+        assert_eq!(
+            command(&mut monitor, &mut i, "JR $-1"),
+            "0101  18 FD       JR $0100\n"
+        );
+        command(&mut monitor, &mut i, "");
+        assert_eq!(monitor.prompt(), "> ");
+        assert_eq!(i.peek(0x0100), 0x3C);
+    }
+
+    #[test]
+    fn context_window() {
+        // LD HL, 0x1234; LD A, 5; NOP; INC A
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x21, 0x34, 0x12, 0x3E, 0x05, 0x00, 0x3C]);
+        assert_eq!(context_start(&i, 0x0106, 2), 0x0103);
+        assert_eq!(context_start(&i, 0x0106, 3), 0x0100);
+        assert_eq!(context_start(&i, 0x0106, 0), 0x0106);
+    }
+
+    #[test]
+    fn errors_and_quit() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let mut monitor = Monitor::default();
+        let mut out = Vec::new();
+        assert!(monitor.run_command(&mut i, "m", &mut out).is_err());
+        assert!(monitor
+            .run_command(&mut i, "b 0x100 when A", &mut out)
+            .is_err());
+        assert!(monitor.run_command(&mut i, "w 0x100 x", &mut out).is_err());
+        assert!(!monitor.run_command(&mut i, "quit", &mut out).unwrap());
+    }
+
+    #[test]
+    fn report_after_panic() {
+        // LD A, 1; NOP; ED 77 (unimplemented)
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x01, 0x00, 0xED, 0x77, 0x00]);
+        i.history.set_depth(64);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for _ in 0..3 {
+                i.step();
+            }
+        }));
+        assert!(result.is_err());
+
+        let mut out = Vec::new();
+        crash_report(&i, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Last 2 instructions:");
+        assert!(lines[1].starts_with("0100  3E 01       LD A, $01"));
+        assert!(lines[2].starts_with("0102  00          NOP"));
+        assert_eq!(lines[3], "Registers:");
+        assert!(lines[4].starts_with("0103 ED77     AF=01"), "{}", lines[4]);
+        assert!(report.contains(" 0102  00          NOP\n>0103  ED 77       NOP\n"));
+    }
+
+    #[test]
+    fn symbols() {
+        // start: LD B, 2; main_loop: CALL delay; DJNZ main_loop; HALT; delay: RET
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(
+            0x0100,
+            &[0x06, 0x02, 0xCD, 0x08, 0x01, 0x10, 0xFB, 0x76, 0xC9],
+        );
+        i.call_stack.enabled = true;
+        i.symbols =
+            crate::symbols::Symbols::parse("start = $0100\nmain_loop = $0102\ndelay: EQU $0108\n")
+                .unwrap();
+        let mut monitor = Monitor::default();
+
+        assert_eq!(
+            command(&mut monitor, &mut i, "d main_loop 2"),
+            "main_loop:\n\
+             0102  CD 08 01    CALL delay\n\
+             0105  10 FB       DJNZ main_loop\n"
+        );
+        assert_eq!(
+            command(&mut monitor, &mut i, "b delay if B == 1"),
+            "Breakpoint at 0108\n"
+        );
+        assert_eq!(
+            command(&mut monitor, &mut i, "b"),
+            "0108 delay  hits: 0  if B == 1\n"
+        );
+        command(&mut monitor, &mut i, "c");
+        assert_eq!(i.cpu.reg.pc, 0x0108);
+        assert_eq!(i.cpu.reg.b, 1);
+        assert_eq!(
+            command(&mut monitor, &mut i, "bt"),
+            "#0  0108 delay\n#1  0105 main_loop+3  CALL 0108 at 0102\n"
+        );
+        assert_eq!(command(&mut monitor, &mut i, "sym ma"), "0102  main_loop\n");
+        assert!(monitor
+            .run_command(&mut i, "b nowhere", &mut Vec::new())
+            .is_err());
+
+        i.symbols.extend(&crate::symbols::Symbols::parse_cdb(
+            "L:C$src/main.c$7$0_0$1:102\n",
+        ));
+        assert!(
+            command(&mut monitor, &mut i, "d 0x102 1").starts_with("main_loop:\n; src/main.c:7\n")
+        );
+        assert_eq!(
+            command(&mut monitor, &mut i, "b main.c:7"),
+            "Breakpoint at 0102\n"
+        );
+        assert!(monitor
+            .run_command(&mut i, "b main.c:8", &mut Vec::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,713 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use log::info;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::device::Device;
+use crate::interconnect::{HookAction, Interconnect};
+use crate::memory::{Memory, Page, PAGES, PAGE_SIZE};
+use crate::peripherals::ay::{AyBus, Chip};
+use crate::peripherals::ppi::{PORT_B, PORT_C};
+use crate::peripherals::{Ay, KeyMatrix, Ppi, Vdp};
+
+pub const CLOCK: usize = 3_579_545;
+// Main ROM with the BIOS and BASIC, in slot 0 at 0000-7FFF
+pub const BIOS_SIZE: usize = 0x8000;
+pub const BIOS_SLOT: Slot = (0, 0);
+// The two cartridge slots
+pub const CARTRIDGE_SLOTS: [Slot; 2] = [(1, 0), (2, 0)];
+// 64K of RAM in the first subslot of slot 3, which is expanded
+pub const RAM_SLOT: Slot = (3, 0);
+// Keyboard rows
+pub const KEY_ROWS: usize = 11;
+
+// Cartridge header, "AB" followed by the INIT, STATEMENT, DEVICE and TEXT addresses
+const HEADER: &[u8; 2] = b"AB";
+
+// How a cartridge's ROM appears in the address space
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mapper {
+    // Up to 64K mapped as-is at the given address
+    Plain(u16),
+    // 8K banks at 6000, 8000 and A000 picked by writing there, 4000 fixed to bank 0
+    Konami,
+    // 8K banks at 4000, 6000, 8000 and A000 picked by writes to 5000, 7000, 9000 and B000
+    KonamiScc,
+    // 8K banks at 4000, 6000, 8000 and A000 picked by writes to 6000, 6800, 7000 and 7800
+    Ascii8,
+    // 16K banks at 4000 and 8000 picked by writes to 6000 and 7000
+    Ascii16,
+}
+
+impl FromStr for Mapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "konami" => Ok(Mapper::Konami),
+            "konami-scc" => Ok(Mapper::KonamiScc),
+            "ascii8" => Ok(Mapper::Ascii8),
+            "ascii16" => Ok(Mapper::Ascii16),
+            _ => match s.strip_prefix("plain@") {
+                Some(addr) => u16::from_str_radix(addr, 16)
+                    .map(Mapper::Plain)
+                    .map_err(|_| format!("Bad address `{}`", addr)),
+                None => Err(format!(
+                    "Unknown mapper `{}`, expected konami, konami-scc, ascii8, ascii16 or \
+                     plain@<addr>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Mapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mapper::Plain(addr) => write!(f, "plain@{:04X}", addr),
+            Mapper::Konami => write!(f, "konami"),
+            Mapper::KonamiScc => write!(f, "konami-scc"),
+            Mapper::Ascii8 => write!(f, "ascii8"),
+            Mapper::Ascii16 => write!(f, "ascii16"),
+        }
+    }
+}
+
+impl Mapper {
+    // Guesses the mapper from the cartridge. ROMs up to 32K aren't banked and sit where their
+    // header says. Anything bigger is taken as a megarom unless it never writes to a mapper
+    // register: the `LD (nnnn), A` instructions in the ROM are counted per register address
+    // and the mapper with the most hits wins.
+    pub fn detect(rom: &[u8]) -> Self {
+        if rom.len() <= 0x8000 {
+            return Mapper::Plain(plain_address(rom));
+        }
+        // Konami, Konami SCC, ASCII 8K, ASCII 16K
+        let mut votes = [0; 4];
+        for store in rom.windows(3).filter(|w| w[0] == 0x32) {
+            match u16::from_le_bytes([store[1], store[2]]) {
+                0x8000 | 0xA000 => votes[0] += 1,
+                0x5000 | 0x9000 | 0xB000 => votes[1] += 1,
+                0x6800 | 0x7800 => votes[2] += 1,
+                0x77FF => votes[3] += 1,
+                0x6000 => {
+                    votes[0] += 1;
+                    votes[2] += 1;
+                    votes[3] += 1;
+                }
+                0x7000 => {
+                    votes[1] += 1;
+                    votes[2] += 1;
+                    votes[3] += 1;
+                }
+                _ => {}
+            }
+        }
+        let max = votes.iter().copied().max().unwrap_or(0);
+        if max == 0 && rom.len() <= 0x10000 {
+            return Mapper::Plain(plain_address(rom));
+        }
+        match votes.iter().position(|&v| v == max) {
+            Some(1) => Mapper::KonamiScc,
+            Some(2) => Mapper::Ascii8,
+            Some(3) => Mapper::Ascii16,
+            _ => Mapper::Konami,
+        }
+    }
+
+    fn bank_size(&self) -> usize {
+        match self {
+            Mapper::Ascii16 => 0x4000,
+            _ => 0x2000,
+        }
+    }
+
+    // Each window's address, the range of its bank register (none if fixed) and the bank
+    // mapped at power on
+    fn windows(&self) -> Vec<(u16, Option<RangeInclusive<u16>>, u8)> {
+        match self {
+            Mapper::Plain(_) => Vec::new(),
+            Mapper::Konami => vec![
+                (0x4000, None, 0),
+                (0x6000, Some(0x6000..=0x7FFF), 1),
+                (0x8000, Some(0x8000..=0x9FFF), 2),
+                (0xA000, Some(0xA000..=0xBFFF), 3),
+            ],
+            Mapper::KonamiScc => vec![
+                (0x4000, Some(0x5000..=0x57FF), 0),
+                (0x6000, Some(0x7000..=0x77FF), 1),
+                (0x8000, Some(0x9000..=0x97FF), 2),
+                (0xA000, Some(0xB000..=0xB7FF), 3),
+            ],
+            Mapper::Ascii8 => vec![
+                (0x4000, Some(0x6000..=0x67FF), 0),
+                (0x6000, Some(0x6800..=0x6FFF), 0),
+                (0x8000, Some(0x7000..=0x77FF), 0),
+                (0xA000, Some(0x7800..=0x7FFF), 0),
+            ],
+            Mapper::Ascii16 => vec![
+                (0x4000, Some(0x6000..=0x67FF), 0),
+                (0x8000, Some(0x7000..=0x77FF), 0),
+            ],
+        }
+    }
+}
+
+// Where an unbanked ROM goes: 16K ROMs whose code (or BASIC program) is above 8000 go
+// there, ROMs with their header 16K in start at 0000, the rest at 4000
+fn plain_address(rom: &[u8]) -> u16 {
+    let word = |n: usize| u16::from_le_bytes([rom[n], rom[n + 1]]);
+    if rom.len() >= 0x10 && rom.starts_with(HEADER) {
+        let (init, text) = (word(2), word(8));
+        match rom.len() <= 0x4000 && (init >= 0x8000 || init == 0 && text >= 0x8000) {
+            true => 0x8000,
+            false => 0x4000,
+        }
+    } else if rom.get(0x4000..0x4002) == Some(HEADER) {
+        0x0000
+    } else {
+        0x4000
+    }
+}
+
+// An MSX ROM cartridge (.rom). `install` maps the cartridge over whatever is at its
+// addresses, `insert` puts it in a slot of an `Msx`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Cartridge {
+    pub rom: Vec<u8>,
+    pub mapper: Mapper,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Self {
+        let mapper = Mapper::detect(&rom);
+        Self { rom, mapper }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let cart = Self::new(archive::read(&path)?);
+        info!(
+            "Loaded {:?}, {}K with the {} mapper",
+            path.as_ref(),
+            cart.rom.len() / 1024,
+            cart.mapper
+        );
+        Ok(cart)
+    }
+
+    // INIT address from the header, where the BIOS calls the cartridge at boot
+    pub fn init(&self) -> Option<u16> {
+        let start = match self.mapper {
+            Mapper::Plain(0x0000) => 0x4000,
+            _ => 0,
+        };
+        let header = self.rom.get(start..start + 4)?;
+        match &header[..2] == HEADER {
+            true => Some(u16::from_le_bytes([header[2], header[3]])),
+            false => None,
+        }
+    }
+
+    // Adds the ROM to ROM storage, padded to a whole number of banks (a power of two for
+    // megaroms), maps it in and installs the bank registers
+    pub fn install(&self, memory: &mut Memory) -> Result<(), String> {
+        if let Mapper::Plain(addr) = self.mapper {
+            self.check_fit(addr)?;
+            memory.load_rom(addr, &self.rom);
+            return Ok(());
+        }
+        let bank_at = self.store_banks(memory);
+        let size = self.mapper.bank_size();
+        for (addr, register, bank) in self.mapper.windows() {
+            memory.map_bank(addr, size, bank_at(bank));
+            if let Some(range) = register {
+                memory.add_mapped_paging_register(range, move |memory, bank| {
+                    memory.map_bank(addr, size, bank_at(bank));
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Like `install`, but into `slot`. The bank registers only see writes while the slot is
+    // selected at their address.
+    pub fn insert(
+        &self,
+        memory: &mut Memory,
+        slots: &Rc<RefCell<Slots>>,
+        slot: Slot,
+    ) -> Result<(), String> {
+        if let Mapper::Plain(addr) = self.mapper {
+            self.check_fit(addr)?;
+            let base = memory.rom.len();
+            let size = self.rom.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+            memory.rom.extend_from_slice(&self.rom);
+            memory.rom.resize(base + size, 0xFF);
+            slots.borrow_mut().set(slot, addr, size, Page::Rom(base));
+            Slots::remap(slots, memory);
+            return Ok(());
+        }
+        let bank_at = self.store_banks(memory);
+        let size = self.mapper.bank_size();
+        for (addr, register, bank) in self.mapper.windows() {
+            slots.borrow_mut().set(slot, addr, size, bank_at(bank));
+            if let Some(range) = register {
+                let slots = slots.clone();
+                let start = *range.start();
+                memory.add_mapped_paging_register(range, move |memory, bank| {
+                    if slots.borrow().selected(start) == slot {
+                        slots.borrow_mut().set(slot, addr, size, bank_at(bank));
+                        Slots::remap(&slots, memory);
+                    }
+                });
+            }
+        }
+        Slots::remap(slots, memory);
+        Ok(())
+    }
+
+    fn check_fit(&self, addr: u16) -> Result<(), String> {
+        if addr as usize + self.rom.len() > 0x1_0000 || !addr.is_multiple_of(0x400) {
+            return Err(format!(
+                "{}K don't fit at {:04X}",
+                self.rom.len() / 1024,
+                addr
+            ));
+        }
+        Ok(())
+    }
+
+    // Adds the banks of a megarom to ROM storage, returns where bank n is
+    fn store_banks(&self, memory: &mut Memory) -> impl Fn(u8) -> Page + Copy {
+        let base = memory.rom.len();
+        let size = self.mapper.bank_size();
+        let banks = self.rom.len().div_ceil(size).next_power_of_two();
+        memory.rom.extend_from_slice(&self.rom);
+        memory.rom.resize(base + banks * size, 0xFF);
+        move |bank: u8| Page::Rom(base + (bank as usize & (banks - 1)) * size)
+    }
+}
+
+// Primary and secondary slot numbers
+pub type Slot = (usize, usize);
+
+// The MSX slots: four primary slots, each of which can be expanded into four subslots,
+// with something (or nothing) at every address. Port A8 (PPI port A) picks the primary
+// slot of each 16K page, two bits per page from bit 0. In an expanded slot the subslot of
+// each page is picked the same way by the register at FFFF of that slot, which reads back
+// inverted.
+//
+// What every slot has is kept per 1K page and `remap` maps the selected ones into memory,
+// with the slot itself as the device at FFFF while page 3 is an expanded slot.
+pub struct Slots {
+    pages: [[[Page; PAGES]; 4]; 4],
+    pub expanded: [bool; 4],
+    pub primary: u8,
+    pub secondary: [u8; 4],
+}
+
+impl Slots {
+    // Nothing in any slot, which reads as FF from a page of ROM storage it adds
+    pub fn new(memory: &mut Memory) -> Self {
+        let empty = memory.rom.len();
+        memory.rom.resize(empty + PAGE_SIZE, 0xFF);
+        Self {
+            pages: [[[Page::Rom(empty); PAGES]; 4]; 4],
+            expanded: [false; 4],
+            primary: 0,
+            secondary: [0; 4],
+        }
+    }
+
+    // Puts `size` bytes of consecutive storage at `addr` of `slot`, both page aligned
+    pub fn set(&mut self, slot: Slot, addr: u16, size: usize, page: Page) {
+        let first = addr as usize / PAGE_SIZE;
+        for n in 0..size / PAGE_SIZE {
+            self.pages[slot.0][slot.1][first + n] = match page {
+                Page::Ram(base) => Page::Ram(base + n * PAGE_SIZE),
+                Page::Rom(base) => Page::Rom(base + n * PAGE_SIZE),
+            };
+        }
+    }
+
+    // Slot selected at `addr`
+    pub fn selected(&self, addr: u16) -> Slot {
+        let page = addr as usize >> 14;
+        let primary = (self.primary >> (page * 2) & 3) as usize;
+        match self.expanded[primary] {
+            true => (
+                primary,
+                (self.secondary[primary] >> (page * 2) & 3) as usize,
+            ),
+            false => (primary, 0),
+        }
+    }
+
+    pub fn remap(slots: &Rc<RefCell<Slots>>, memory: &mut Memory) {
+        let this = slots.borrow();
+        for page in 0..PAGES {
+            let (primary, secondary) = this.selected((page * PAGE_SIZE) as u16);
+            memory.map_page(page, this.pages[primary][secondary][page]);
+        }
+        memory.unmap_device(0xFFFF);
+        if this.expanded[this.selected(0xFFFF).0] {
+            memory.map_device(0xFFFF..=0xFFFF, slots.clone());
+        }
+    }
+}
+
+// The subslot register of whichever expanded slot page 3 is in
+impl Device for Slots {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        !self.secondary[self.selected(addr).0]
+    }
+
+    fn mem_write(&mut self, addr: u16, value: u8) {
+        let primary = self.selected(addr).0;
+        self.secondary[primary] = value;
+    }
+}
+
+// An MSX1 computer: the slots as above with the BIOS in slot 0, cartridges in 1 and 2 and
+// 64K of RAM in slot 3-0, a TMS9918A on ports 98-99 with its frame interrupt on /INT, an
+// AY-3-8910 on A0-A2 and the PPI on A8-AB. The PPI's port A selects the slots, port C bits
+// 0-3 select the keyboard row read on port B (keys down read 0).
+pub struct Msx {
+    pub slots: Rc<RefCell<Slots>>,
+    pub vdp: Rc<RefCell<Vdp>>,
+    pub psg: Rc<RefCell<Ay>>,
+    pub ppi: Rc<RefCell<Ppi>>,
+    pub keyboard: KeyMatrix,
+}
+
+impl Msx {
+    // Replaces the memory of `i` with the slots and attaches the devices
+    pub fn install(i: &mut Interconnect, bios: &[u8]) -> Result<Self, String> {
+        if bios.is_empty() || bios.len() > BIOS_SIZE {
+            return Err(format!(
+                "BIOS is {} bytes, expected up to {}",
+                bios.len(),
+                BIOS_SIZE
+            ));
+        }
+        let mut memory = Memory::default();
+        let base = memory.rom.len();
+        memory.rom.extend_from_slice(bios);
+        memory.rom.resize(base + BIOS_SIZE, 0xFF);
+        let mut slots = Slots::new(&mut memory);
+        slots.set(BIOS_SLOT, 0x0000, BIOS_SIZE, Page::Rom(base));
+        slots.set(RAM_SLOT, 0x0000, 0x1_0000, Page::Ram(0));
+        slots.expanded[RAM_SLOT.0] = true;
+        let slots = Rc::new(RefCell::new(slots));
+        Slots::remap(&slots, &mut memory);
+        let primary = slots.clone();
+        memory.add_paging_register(0x00FF, 0x00A8, move |memory, value| {
+            primary.borrow_mut().primary = value;
+            Slots::remap(&primary, memory);
+        });
+        let secondary = slots.clone();
+        memory.add_mapped_paging_register(0xFFFF..=0xFFFF, move |memory, _| {
+            Slots::remap(&secondary, memory);
+        });
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+
+        let vdp = Vdp::default();
+        i.frame_cycles = Some(vdp.frame_cycles());
+        i.display = Some(vdp.frame.clone());
+        let vdp = i.add_device(vdp);
+        i.register_port(0x98..=0x99, vdp.clone());
+
+        let mut psg = Ay::new(CLOCK as u64 / 2, CLOCK as u64, 44_100);
+        psg.chip = Chip::Ym2149;
+        psg.bus = AyBus::Msx;
+        let psg = i.add_device(psg);
+        i.register_port(0xA0..=0xA2, psg.clone());
+
+        let keyboard = KeyMatrix::default();
+        let row = Rc::new(Cell::new(0));
+        let mut ppi = Ppi::default();
+        let selected = row.clone();
+        ppi.on_output(PORT_C, move |lines| selected.set(lines as usize & 0x0F));
+        let keys = keyboard.clone();
+        ppi.on_input(PORT_B, move || match row.get() {
+            row if row < KEY_ROWS => !keys.row(row),
+            _ => 0xFF,
+        });
+        let ppi = Rc::new(RefCell::new(ppi));
+        i.register_port(0xA8..=0xAB, ppi.clone());
+        Ok(Self {
+            slots,
+            vdp,
+            psg,
+            ppi,
+            keyboard,
+        })
+    }
+
+    // Installs the BIOS (and BASIC) ROM at `path`
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let bios = archive::read(&path)?;
+        let msx =
+            Self::install(i, &bios).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        info!("Loaded MSX BIOS {:?}", path.as_ref());
+        Ok(msx)
+    }
+
+    // Puts `cartridge` in cartridge slot `n` (0 or 1)
+    pub fn insert(
+        &self,
+        i: &mut Interconnect,
+        cartridge: &Cartridge,
+        n: usize,
+    ) -> Result<(), String> {
+        cartridge.insert(&mut i.cpu.memory, &self.slots, CARTRIDGE_SLOTS[n])
+    }
+}
+
+// The BIOS cassette entries `Cassette` services
+pub const TAPION: u16 = 0x00E1;
+pub const TAPIN: u16 = 0x00E4;
+pub const TAPIOF: u16 = 0x00E7;
+pub const STMOTR: u16 = 0x00F3;
+
+// Marks the start of each block in a .cas file, always at a multiple of 8
+const CAS_HEADER: [u8; 8] = [0x1F, 0xA6, 0xDE, 0xBA, 0xCC, 0x13, 0x7D, 0x74];
+
+// An MSX .cas cassette image: the bytes the BIOS reads from tape, with a header in front
+// of each block where the tape has a long tone. Loaded through the BIOS cassette routines,
+// see `Interconnect::insert_cas`.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Cassette {
+    pub data: Vec<u8>,
+    pub pos: usize,
+}
+
+impl Cassette {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(&CAS_HEADER) {
+            return Err("Not a .cas file".to_string());
+        }
+        Ok(Self {
+            data: data.to_vec(),
+            pos: 0,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&archive::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Data of each block, without the headers
+    pub fn blocks(&self) -> Vec<&[u8]> {
+        let starts = self.headers().collect::<Vec<_>>();
+        starts
+            .iter()
+            .enumerate()
+            .map(|(n, &start)| {
+                let end = starts.get(n + 1).map_or(self.data.len(), |&next| next - 8);
+                &self.data[start..end]
+            })
+            .collect()
+    }
+
+    // Offsets just past each header
+    fn headers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.data.len())
+            .step_by(8)
+            .filter(move |&n| self.data[n..].starts_with(&CAS_HEADER))
+            .map(|n| n + 8)
+    }
+
+    // Does what the BIOS routine at `entry` would with the tape, for a PC hook there.
+    // TAPION finds the next header, TAPIN reads a byte into A; carry is set if the tape ran
+    // out. TAPION disables interrupts and TAPIOF enables them again, as the BIOS does.
+    pub fn trap(&mut self, cpu: &mut Cpu, entry: u16) -> HookAction {
+        match entry {
+            TAPION => {
+                let pos = self.pos;
+                let next = self.headers().find(|&n| n > pos);
+                if let Some(next) = next {
+                    self.pos = next;
+                }
+                cpu.flags.cf = next.is_none();
+                cpu.int.iff1 = false;
+                cpu.int.iff2 = false;
+            }
+            TAPIN => match self.data.get(self.pos) {
+                Some(&byte) => {
+                    cpu.reg.a = byte;
+                    self.pos += 1;
+                    cpu.flags.cf = false;
+                }
+                None => cpu.flags.cf = true,
+            },
+            TAPIOF => {
+                cpu.flags.cf = false;
+                cpu.int.iff1 = true;
+                cpu.int.iff2 = true;
+            }
+            _ => {}
+        }
+        HookAction::Return
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cartridge, Cassette, Mapper, Msx, CAS_HEADER};
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::memory::MemoryRW;
+
+    #[test]
+    fn cartridges() {
+        let mut rom = vec![0; 0x4000];
+        rom[..4].copy_from_slice(b"AB\x10\x80");
+        let cart = Cartridge::new(rom.clone());
+        assert_eq!(cart.mapper, Mapper::Plain(0x8000));
+        assert_eq!(cart.init(), Some(0x8010));
+        rom[3] = 0x40;
+        rom.resize(0x8000, 0);
+        assert_eq!(Cartridge::new(rom).mapper, Mapper::Plain(0x4000));
+
+        let cart = ascii8();
+        assert_eq!(cart.mapper, Mapper::Ascii8);
+
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        cart.install(&mut i.cpu.memory).unwrap();
+        assert_eq!(i.peek(0x6000), 0);
+        i.cpu.write8(0x6800, 5);
+        i.cpu.write8(0x7FFF, 18);
+        assert_eq!(i.peek(0x6000), 5);
+        assert_eq!(i.peek(0xA000), 2);
+        assert_eq!(i.peek(0x4000), 0);
+
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let konami = Cartridge {
+            mapper: "konami".parse().unwrap(),
+            ..cart
+        };
+        konami.install(&mut i.cpu.memory).unwrap();
+        assert_eq!(i.peek(0x8000), 2);
+        i.cpu.write8(0x8000, 7);
+        i.cpu.write8(0x4000, 7);
+        assert_eq!(i.peek(0x8000), 7);
+        assert_eq!(i.peek(0x4000), 0);
+        assert!("megarom".parse::<Mapper>().is_err());
+    }
+
+    // 128K in 8K banks filled with their number, switching with LD (6800), A
+    fn ascii8() -> Cartridge {
+        let mut rom = Vec::new();
+        for bank in 0..16 {
+            rom.extend(vec![bank; 0x2000]);
+        }
+        rom[0x10..0x13].copy_from_slice(&[0x32, 0x00, 0x68]);
+        rom[0x20..0x23].copy_from_slice(&[0x32, 0x00, 0x78]);
+        Cartridge::new(rom)
+    }
+
+    #[test]
+    fn slots() {
+        let mut bios = vec![0xF3; 0x4000];
+        bios.extend(vec![0xC9; 0x4000]);
+        let mut i = Interconnect::builder().build();
+        let msx = Msx::install(&mut i, &bios).unwrap();
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        assert_eq!(i.cpu.read8(0x4000), 0xC9);
+        assert_eq!(i.cpu.read8(0x8000), 0xFF);
+
+        // RAM everywhere, page 3's slot is expanded so FFFF is the subslot register
+        i.cpu.memory.paging_write(0xA8, 0xFF);
+        assert_eq!(msx.slots.borrow().primary, 0xFF);
+        i.cpu.write8(0x0000, 0x12);
+        assert_eq!(i.cpu.read8(0x0000), 0x12);
+        assert_eq!(i.cpu.read8(0xFFFF), 0xFF);
+        i.cpu.write8(0xFFFF, 0x55);
+        assert_eq!(i.cpu.read8(0xFFFF), 0xAA);
+        assert_eq!(i.cpu.read8(0x0000), 0xFF);
+        i.cpu.write8(0xFFFF, 0x00);
+        assert_eq!(i.cpu.read8(0x0000), 0x12);
+        // Slot 0 isn't expanded
+        i.cpu.memory.paging_write(0xA8, 0x3F);
+        assert_eq!(i.cpu.read8(0xFFFF), 0xFF);
+        i.cpu.write8(0xFFFF, 0x55);
+        assert_eq!(msx.slots.borrow().secondary[3], 0x00);
+
+        // A megarom in slot 1 at 4000-BFFF only switches banks while it's selected
+        msx.insert(&mut i, &ascii8(), 0).unwrap();
+        i.cpu.memory.paging_write(0xA8, 0xD4);
+        assert_eq!(i.cpu.read8(0x4000), 0);
+        i.cpu.write8(0x6800, 5);
+        assert_eq!(i.cpu.read8(0x6000), 5);
+        i.cpu.memory.paging_write(0xA8, 0xFF);
+        i.cpu.write8(0x6800, 7);
+        assert_eq!(i.cpu.read8(0x6800), 7);
+        i.cpu.memory.paging_write(0xA8, 0xD4);
+        assert_eq!(i.cpu.read8(0x6000), 5);
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        assert_eq!(i.cpu.read8(0xC000), 0x00);
+    }
+
+    #[test]
+    fn devices() {
+        let mut i = Interconnect::builder().build();
+        let msx = Msx::install(&mut i, &[0x00; 0x8000]).unwrap();
+        assert_eq!(i.frame_cycles, Some(59_736));
+        // Space is row 8 column 0
+        msx.keyboard.press(8, 0);
+        i.cpu.io.write(0xAB, 0x82);
+        i.cpu.io.write(0xAA, 0x08);
+        assert_eq!(i.cpu.io.read(0xA9), 0xFE);
+        i.cpu.io.write(0xAA, 0x07);
+        assert_eq!(i.cpu.io.read(0xA9), 0xFF);
+
+        i.cpu.io.write(0xA0, 0x07);
+        i.cpu.io.write(0xA1, 0xBF);
+        assert_eq!(msx.psg.borrow().regs[7], 0xBF);
+        assert_eq!(i.cpu.io.read(0xA2), 0xBF);
+        i.cpu.io.write(0x99, 0x1F);
+        i.cpu.io.write(0x99, 0x87);
+        assert_eq!(msx.vdp.borrow().regs[7], 0x1F);
+        assert!(Msx::install(&mut i, &[0x00; 0x8001]).is_err());
+    }
+
+    #[test]
+    fn cassette() {
+        let mut data = CAS_HEADER.to_vec();
+        data.extend([0xD3, 0xD3, 0x41]);
+        data.resize(16, 0);
+        data.extend(CAS_HEADER);
+        data.extend([0x42]);
+        let cas = Cassette::parse(&data).unwrap();
+        assert_eq!(cas.blocks(), [&data[8..16], &[0x42][..]]);
+
+        // CALL TAPION; CALL TAPIN; LD (8000), A; CALL TAPION; CALL TAPIN; LD (8001), A;
+        // CALL TAPION; HALT
+        let program = [
+            0xCD, 0xE1, 0x00, 0xCD, 0xE4, 0x00, 0x32, 0x00, 0x80, 0xCD, 0xE1, 0x00, 0xCD, 0xE4,
+            0x00, 0x32, 0x01, 0x80, 0xCD, 0xE1, 0x00, 0x76,
+        ];
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &program);
+        let cas = i.insert_cas(cas);
+        while !i.cpu.int.halt {
+            i.step();
+        }
+        assert_eq!(i.peek(0x8000), 0xD3);
+        assert_eq!(i.peek(0x8001), 0x42);
+        // No third block
+        assert!(i.cpu.flags.cf);
+        assert_eq!(cas.borrow().pos, data.len());
+    }
+}
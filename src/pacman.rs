@@ -0,0 +1,664 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::device::Device;
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Region};
+use crate::video::Framebuffer;
+
+pub const CLOCK: usize = 3_072_000;
+// 384 x 264 pixels at 6.144MHz, 60.61Hz
+pub const FRAME_CYCLES: usize = 50_688;
+pub const ROM_SIZE: usize = 0x4000;
+pub const VIDEO_RAM: u16 = 0x4000;
+pub const COLOR_RAM: u16 = 0x4400;
+// Code / flip and palette of each of the 8 sprites
+pub const SPRITE_ATTRIBUTES: u16 = 0x4FF0;
+pub const IO: u16 = 0x5000;
+// X and Y of each sprite, written to the I/O block
+pub const SPRITE_COORDS: u16 = 0x5060;
+
+// The program ROMs of MAME's pacman set, 4K each from 0000
+pub const PROGRAM_ROMS: [&str; 4] = ["pacman.6e", "pacman.6f", "pacman.6h", "pacman.6j"];
+// 256 tiles and 64 sprites, the 32 colors and the 64 palettes of 4 of them
+pub const TILE_ROM: &str = "pacman.5e";
+pub const SPRITE_ROM: &str = "pacman.5f";
+pub const COLOR_PROM: &str = "82s123.7f";
+pub const PALETTE_PROM: &str = "82s126.4a";
+
+// The monitor is on its side, the picture is 224x288 upright
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 288;
+// The screen as the hardware scans it, 36 columns of tiles across and 28 rows down
+const NATIVE_WIDTH: usize = 288;
+const NATIVE_HEIGHT: usize = 224;
+
+// Bit offsets of the pixels in a tile (16 bytes) and a sprite (64 bytes), counting from
+// the most significant bit of the first byte, as MAME lays them out
+const TILE_X: [usize; 8] = [64, 65, 66, 67, 0, 1, 2, 3];
+const TILE_Y: [usize; 8] = [0, 8, 16, 24, 32, 40, 48, 56];
+const SPRITE_X: [usize; 16] = [
+    64, 65, 66, 67, 128, 129, 130, 131, 192, 193, 194, 195, 0, 1, 2, 3,
+];
+const SPRITE_Y: [usize; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 256, 264, 272, 280, 288, 296, 304, 312,
+];
+
+// Outputs at 5000-5007, bit 0 of the byte written to each
+pub const INTERRUPT_ENABLE: usize = 0;
+pub const SOUND_ENABLE: usize = 1;
+pub const FLIP_SCREEN: usize = 3;
+pub const PLAYER1_LAMP: usize = 4;
+pub const PLAYER2_LAMP: usize = 5;
+pub const COIN_LOCKOUT: usize = 6;
+pub const COIN_COUNTER: usize = 7;
+
+// 1 coin 1 credit, 3 lives, bonus life at 10000, normal difficulty and ghost names
+pub const DEFAULT_DSW1: u8 = 0xC9;
+
+// The controls, read active low through IN0 (5000) and IN1 (5040)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Button {
+    Up,
+    Left,
+    Right,
+    Down,
+    RackTest,
+    Coin1,
+    Coin2,
+    Credit,
+    Up2,
+    Left2,
+    Right2,
+    Down2,
+    ServiceMode,
+    Start1,
+    Start2,
+}
+
+impl Button {
+    // The input port (0 or 1) and bit
+    fn bit(self) -> (usize, u8) {
+        match self {
+            Button::Up => (0, 0x01),
+            Button::Left => (0, 0x02),
+            Button::Right => (0, 0x04),
+            Button::Down => (0, 0x08),
+            Button::RackTest => (0, 0x10),
+            Button::Coin1 => (0, 0x20),
+            Button::Coin2 => (0, 0x40),
+            Button::Credit => (0, 0x80),
+            Button::Up2 => (1, 0x01),
+            Button::Left2 => (1, 0x02),
+            Button::Right2 => (1, 0x04),
+            Button::Down2 => (1, 0x08),
+            Button::ServiceMode => (1, 0x10),
+            Button::Start1 => (1, 0x20),
+            Button::Start2 => (1, 0x40),
+        }
+    }
+}
+
+// The memory map: ROM at 0000-3FFF, video and color RAM at 4000-47FF and work RAM up to
+// 4FFF, with the I/O block at 5000-50FF left to the caller. Nothing answers at
+// 5100-7FFF and A15 isn't decoded so the upper half mirrors the lower.
+pub fn map_memory(memory: &mut Memory, rom: &[u8]) {
+    memory.load_rom(0x0000, rom);
+    memory.map(0x5100..=0x7FFF, Region::Unmapped);
+    memory.map(0x8000..=0xFFFF, Region::Mirror(0x0000));
+}
+
+// Attaches the I/O block at 5000-50FF and port 0 with the frame timing it interrupts on
+pub fn attach_io(i: &mut Interconnect) -> Rc<RefCell<IoBlock>> {
+    let io = i.add_device(IoBlock::default());
+    i.map_device(IO..=IO + 0xFF, io.clone());
+    i.register_port(0x00..=0x00, io.clone());
+    i.frame_cycles = Some(FRAME_CYCLES);
+    io
+}
+
+// The I/O block at 5000-50FF. Reads return IN0 at 5000-503F, IN1 at 5040-507F and the
+// DIP switches at 5080 and 50C0 (the second bank isn't fitted on Pac-Man, it reads FF).
+// Writes go to the output latch at 5000-5007 (repeated to 503F), the sound registers at
+// 5040-505F, the sprite coordinates at 5060-506F and the watchdog at 50C0-50FF.
+//
+// At the start of vertical blank /INT is asserted if the interrupt enable is set, until
+// the CPU takes it or the enable is cleared. The game runs in mode 2 with the low byte of
+// the vector written to port 0, which the board puts on the bus when the CPU acknowledges.
+pub struct IoBlock {
+    // Inputs as on the pins, buttons down are 0
+    pub in0: u8,
+    pub in1: u8,
+    pub dsw1: u8,
+    pub dsw2: u8,
+    pub outputs: [bool; 8],
+    pub sound: [u8; 32],
+    pub sprite_coords: [u8; 16],
+    pub vector: u8,
+    // Frames since the watchdog was last cleared. Nothing resets the CPU when it runs out.
+    pub watchdog: usize,
+    pub frames: u64,
+    // T states into the current frame
+    pub cycles: usize,
+    irq: bool,
+}
+
+impl Default for IoBlock {
+    fn default() -> Self {
+        Self {
+            // Coin and credit high, IN1 bit 7 set for the upright cabinet
+            in0: 0xFF,
+            in1: 0xFF,
+            dsw1: DEFAULT_DSW1,
+            dsw2: 0xFF,
+            outputs: [false; 8],
+            sound: [0; 32],
+            sprite_coords: [0; 16],
+            vector: 0,
+            watchdog: 0,
+            frames: 0,
+            cycles: 0,
+            irq: false,
+        }
+    }
+}
+
+impl IoBlock {
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let (port, bit) = button.bit();
+        let input = match port {
+            0 => &mut self.in0,
+            _ => &mut self.in1,
+        };
+        *input = match pressed {
+            true => *input & !bit,
+            false => *input | bit,
+        };
+    }
+}
+
+impl Device for IoBlock {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        if self.cycles >= FRAME_CYCLES {
+            self.cycles -= FRAME_CYCLES;
+            self.frames += 1;
+            self.watchdog += 1;
+            self.irq |= self.outputs[INTERRUPT_ENABLE];
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        if port & 0xFF == 0 {
+            self.vector = value;
+        }
+    }
+
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match addr & 0xC0 {
+            0x00 => self.in0,
+            0x40 => self.in1,
+            0x80 => self.dsw1,
+            _ => self.dsw2,
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, value: u8) {
+        match addr & 0xFF {
+            0x00..=0x3F => {
+                self.outputs[addr as usize & 7] = value & 1 != 0;
+                if addr & 7 == INTERRUPT_ENABLE as u16 && value & 1 == 0 {
+                    self.irq = false;
+                }
+            }
+            n @ 0x40..=0x5F => self.sound[n as usize - 0x40] = value & 0x0F,
+            n @ 0x60..=0x6F => self.sprite_coords[n as usize - 0x60] = value,
+            0xC0..=0xFF => self.watchdog = 0,
+            _ => {}
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.irq.then_some(self.vector)
+    }
+
+    fn level_interrupt(&self) -> bool {
+        true
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        self.irq = false;
+    }
+}
+
+// Decodes a 2 bit per pixel graphic into pixel values (0-3) row by row. Each byte holds 4
+// pixels, the high bits of their values in bits 7-4 and the low bits in 3-0.
+fn decode(data: &[u8], xs: &[usize], ys: &[usize]) -> Vec<u8> {
+    let bit = |n: usize| data[n / 8] >> (7 - n % 8) & 1;
+    ys.iter()
+        .flat_map(|&y| xs.iter().map(move |&x| bit(x + y) << 1 | bit(x + y + 4)))
+        .collect()
+}
+
+// A byte of the color PROM: red in bits 0-2, green in 3-5 and blue in 6-7, each bit
+// through its own resistor
+fn rgb(byte: u8) -> [u8; 4] {
+    let bit = |n: u8| (byte >> n & 1) as u16;
+    let r = 0x21 * bit(0) + 0x47 * bit(1) + 0x97 * bit(2);
+    let g = 0x21 * bit(3) + 0x47 * bit(4) + 0x97 * bit(5);
+    let b = 0x51 * bit(6) + 0xAE * bit(7);
+    [r as u8, g as u8, b as u8, 0xFF]
+}
+
+// Video / color RAM offset of the tile at column `col` (0-35) and row `row` (0-27) of the
+// hardware's screen. The middle 32 columns are stored a column at a time, the two at
+// each end are the rows at the bottom and top of the upright picture.
+fn tile_offset(col: usize, row: usize) -> u16 {
+    let (col, row) = (col.wrapping_sub(2), row + 2);
+    match col & 0x20 {
+        0 => (col + (row << 5)) as u16,
+        _ => (row + ((col & 0x1F) << 5)) as u16,
+    }
+}
+
+// The graphics ROMs and PROMs, decoded
+pub struct Graphics {
+    pub tiles: Vec<Vec<u8>>,
+    pub sprites: Vec<Vec<u8>>,
+    pub colors: [[u8; 4]; 32],
+    // Color of each pixel value of each palette, 0 is transparent on sprites
+    pub palettes: [u8; 256],
+}
+
+impl Default for Graphics {
+    // All black
+    fn default() -> Self {
+        Self {
+            tiles: vec![vec![0; 64]; 256],
+            sprites: vec![vec![0; 256]; 64],
+            colors: [[0x00, 0x00, 0x00, 0xFF]; 32],
+            palettes: [0; 256],
+        }
+    }
+}
+
+impl Graphics {
+    pub fn decode(
+        tiles: &[u8],
+        sprites: &[u8],
+        colors: &[u8],
+        palettes: &[u8],
+    ) -> Result<Self, String> {
+        for (name, data, len) in [
+            (TILE_ROM, tiles, 0x1000),
+            (SPRITE_ROM, sprites, 0x1000),
+            (COLOR_PROM, colors, 32),
+            (PALETTE_PROM, palettes, 256),
+        ] {
+            if data.len() != len {
+                return Err(format!(
+                    "{} is {} bytes, expected {}",
+                    name,
+                    data.len(),
+                    len
+                ));
+            }
+        }
+        let mut graphics = Self {
+            tiles: tiles
+                .chunks(16)
+                .map(|tile| decode(tile, &TILE_X, &TILE_Y))
+                .collect(),
+            sprites: sprites
+                .chunks(64)
+                .map(|sprite| decode(sprite, &SPRITE_X, &SPRITE_Y))
+                .collect(),
+            ..Default::default()
+        };
+        for (color, &byte) in graphics.colors.iter_mut().zip(colors) {
+            *color = rgb(byte);
+        }
+        for (entry, &byte) in graphics.palettes.iter_mut().zip(palettes) {
+            *entry = byte & 0x0F;
+        }
+        Ok(graphics)
+    }
+}
+
+// Draws the tiles from video and color RAM and then the sprites over them at the start of
+// every vertical blank. Sprite 7 is drawn first so sprite 0 is in front, and the first
+// three are a pixel further along as on the board. Sprites aren't drawn over the two
+// columns at each end (the rows of text at the top and bottom of the upright picture).
+// The flip screen output for cocktail cabinets is ignored.
+pub struct Video {
+    pub frame: Rc<RefCell<Framebuffer>>,
+    pub graphics: Graphics,
+    pub io: Rc<RefCell<IoBlock>>,
+    frames_drawn: u64,
+}
+
+impl Video {
+    pub fn new(io: Rc<RefCell<IoBlock>>) -> Self {
+        Self {
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            graphics: Graphics::default(),
+            io,
+            frames_drawn: 0,
+        }
+    }
+
+    pub fn render(&self, memory: &Memory) {
+        let graphics = &self.graphics;
+        // Colors (0-15) as the hardware scans them
+        let mut screen = vec![0u8; NATIVE_WIDTH * NATIVE_HEIGHT];
+        for col in 0..NATIVE_WIDTH / 8 {
+            for row in 0..NATIVE_HEIGHT / 8 {
+                let offset = tile_offset(col, row);
+                let tile = &graphics.tiles[memory.peek(VIDEO_RAM + offset) as usize];
+                let palette = (memory.peek(COLOR_RAM + offset) & 0x1F) as usize * 4;
+                for (n, &value) in tile.iter().enumerate() {
+                    let (x, y) = (col * 8 + n % 8, row * 8 + n / 8);
+                    screen[y * NATIVE_WIDTH + x] = graphics.palettes[palette + value as usize];
+                }
+            }
+        }
+
+        let coords = self.io.borrow().sprite_coords;
+        for sprite in (0..8).rev() {
+            let attributes = memory.peek(SPRITE_ATTRIBUTES + sprite as u16 * 2);
+            let palette =
+                (memory.peek(SPRITE_ATTRIBUTES + sprite as u16 * 2 + 1) & 0x1F) as usize * 4;
+            let pixels = &graphics.sprites[attributes as usize >> 2];
+            let (flip_x, flip_y) = (attributes & 0x01 != 0, attributes & 0x02 != 0);
+            let left = 272 - coords[sprite * 2 + 1] as isize + (sprite < 3) as isize;
+            let top = coords[sprite * 2] as isize - 31;
+            for (n, &value) in pixels.iter().enumerate() {
+                let (dx, dy) = (n % 16, n / 16);
+                let x = left + if flip_x { 15 - dx } else { dx } as isize;
+                let y = top + if flip_y { 15 - dy } else { dy } as isize;
+                let color = graphics.palettes[palette + value as usize];
+                if color != 0 && (16..272).contains(&x) && (0..NATIVE_HEIGHT as isize).contains(&y)
+                {
+                    screen[y as usize * NATIVE_WIDTH + x as usize] = color;
+                }
+            }
+        }
+
+        // Turned a quarter clockwise, the hardware's bottom row is the picture's left column
+        let mut frame = self.frame.borrow_mut();
+        for y in 0..HEIGHT {
+            let row = frame.row_mut(y);
+            for x in 0..WIDTH {
+                let color = screen[(NATIVE_HEIGHT - 1 - x) * NATIVE_WIDTH + y];
+                row[x * 4..x * 4 + 4].copy_from_slice(&graphics.colors[color as usize]);
+            }
+        }
+    }
+}
+
+impl Device for Video {
+    fn scan(&mut self, memory: &Memory) {
+        let frames = self.io.borrow().frames;
+        if frames > self.frames_drawn {
+            self.frames_drawn = frames;
+            self.render(memory);
+        }
+    }
+}
+
+// The Pac-Man arcade board (Namco's original and Midway's licensed one)
+pub struct PacMan {
+    pub io: Rc<RefCell<IoBlock>>,
+    pub video: Rc<RefCell<Video>>,
+}
+
+impl PacMan {
+    // Replaces the memory of `i` with the 16K program ROM
+    pub fn install(i: &mut Interconnect, rom: &[u8]) -> Result<Self, String> {
+        if rom.len() != ROM_SIZE {
+            return Err(format!(
+                "Program ROM is {} bytes, expected {}",
+                rom.len(),
+                ROM_SIZE
+            ));
+        }
+        let mut memory = Memory::default();
+        map_memory(&mut memory, rom);
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.cpu.cpm_compat = false;
+        i.clock_speed = CLOCK;
+
+        let io = attach_io(i);
+        let video = Video::new(io.clone());
+        i.display = Some(video.frame.clone());
+        let video = i.add_device(video);
+        Ok(Self { io, video })
+    }
+
+    // Installs the program, graphics and color ROMs from `set`, a directory or zip file with
+    // MAME's file names
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, set: P) -> io::Result<Self> {
+        let set = set.as_ref();
+        let read = |name| archive::read(rom_path(set, name));
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut rom = Vec::with_capacity(ROM_SIZE);
+        for name in PROGRAM_ROMS {
+            rom.extend(read(name)?);
+        }
+        let graphics = Graphics::decode(
+            &read(TILE_ROM)?,
+            &read(SPRITE_ROM)?,
+            &read(COLOR_PROM)?,
+            &read(PALETTE_PROM)?,
+        )
+        .map_err(invalid)?;
+        let machine = Self::install(i, &rom).map_err(invalid)?;
+        machine.video.borrow_mut().graphics = graphics;
+        info!("Loaded Pac-Man ROMs from {:?}", set);
+        Ok(machine)
+    }
+
+    pub fn set_button(&self, button: Button, pressed: bool) {
+        self.io.borrow_mut().set_button(button, pressed);
+    }
+}
+
+// A file of a ROM set in a directory or zip archive
+pub fn rom_path(set: &Path, name: &str) -> PathBuf {
+    match archive::is_archive(set) {
+        true => PathBuf::from(format!("{}#{}", set.display(), name)),
+        false => set.join(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        rom_path, Button, Graphics, PacMan, COLOR_RAM, DEFAULT_DSW1, FRAME_CYCLES, ROM_SIZE,
+        SPRITE_ATTRIBUTES, SPRITE_COORDS, VIDEO_RAM,
+    };
+    use crate::assembler::assemble;
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+    use std::path::Path;
+
+    fn rom(source: &str) -> Vec<u8> {
+        let mut rom = assemble(source).unwrap().to_binary();
+        rom.resize(ROM_SIZE, 0);
+        rom
+    }
+
+    #[test]
+    fn memory_and_inputs() {
+        let mut i = Interconnect::builder().build();
+        let machine = PacMan::install(&mut i, &rom("JP 0")).unwrap();
+        assert_eq!(i.cpu.read8(0x8000), 0xC3);
+        i.cpu.write8(0x0000, 0x00);
+        assert_eq!(i.cpu.read8(0x0000), 0xC3);
+        i.cpu.write8(0xCC00, 0x12);
+        assert_eq!(i.cpu.read8(0x4C00), 0x12);
+        assert_eq!(i.cpu.read8(0x6000), 0xFF);
+
+        assert_eq!(i.cpu.read8(0x5000), 0xFF);
+        machine.set_button(Button::Left, true);
+        machine.set_button(Button::Coin1, true);
+        machine.set_button(Button::Start1, true);
+        assert_eq!(i.cpu.read8(0x5000), 0xDD);
+        assert_eq!(i.cpu.read8(0x503F), 0xDD);
+        assert_eq!(i.cpu.read8(0x5040), 0xDF);
+        machine.set_button(Button::Coin1, false);
+        assert_eq!(i.cpu.read8(0x5000), 0xFD);
+        assert_eq!(i.cpu.read8(0x5080), DEFAULT_DSW1);
+        assert_eq!(i.cpu.read8(0xD0C0), 0xFF);
+
+        // Outputs, sound and sprite registers don't read back or reach the RAM underneath
+        for (addr, value) in [(0x5003, 0x01), (0x504A, 0x37), (0x5061, 0x80), (0xD0C0, 0)] {
+            i.cpu.write8(addr, value);
+            assert_eq!(i.cpu.memory[addr & 0x7FFF], 0x00);
+        }
+        let io = machine.io.borrow();
+        assert!(io.outputs[3] && !io.outputs[0]);
+        assert_eq!(io.sound[0x0A], 0x07);
+        assert_eq!(io.sprite_coords[1], 0x80);
+
+        assert!(PacMan::install(&mut i, &[0; 0x1000]).is_err());
+        assert_eq!(
+            rom_path(Path::new("pacman.zip"), "pacman.6e"),
+            Path::new("pacman.zip#pacman.6e")
+        );
+        assert_eq!(
+            rom_path(Path::new("roms"), "pacman.6e"),
+            Path::new("roms/pacman.6e")
+        );
+    }
+
+    #[test]
+    fn vblank_interrupt() {
+        // Mode 2 with the vector from port 0, the handler counts frames at 4C00 and turns
+        // the interrupt off and on again like the game does
+        let source = "
+            LD SP,4FC0h
+            LD A,0CEh
+            OUT (0),A
+            LD A,30h
+            LD I,A
+            IM 2
+            LD A,1
+            LD (5000h),A
+            EI
+    IDLE:   HALT
+            JP IDLE
+    VBLANK: XOR A
+            LD (5000h),A
+            LD HL,4C00h
+            INC (HL)
+            INC A
+            LD (5000h),A
+            EI
+            RET
+            ORG 30CEh
+            DW VBLANK
+        ";
+        let mut i = Interconnect::builder().build();
+        let machine = PacMan::install(&mut i, &rom(source)).unwrap();
+        while i.cpu.cycles < FRAME_CYCLES * 3 + 1000 {
+            i.step();
+        }
+        assert_eq!(i.peek(0x4C00), 3);
+        assert_eq!(machine.io.borrow().frames, 3);
+        assert_eq!(machine.io.borrow().vector, 0xCE);
+        assert_eq!(i.frame_cycles, Some(FRAME_CYCLES));
+
+        // No interrupts with the enable cleared
+        i.cpu.write8(0x5000, 0x00);
+        while i.cpu.cycles < FRAME_CYCLES * 5 + 1000 {
+            i.step();
+        }
+        assert_eq!(i.peek(0x4C00), 3);
+        assert!(i.cpu.int.halt);
+    }
+
+    #[test]
+    fn graphics() {
+        const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+        const RED: [u8; 4] = [0xFF, 0x00, 0x00, 0xFF];
+        const GREEN: [u8; 4] = [0x00, 0xFF, 0x00, 0xFF];
+        const BLUE: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+        // Tile 1 has pixel value 3 in the top left of its lower half on the hardware's
+        // screen and 2 in its bottom left, sprite 1 is value 3 all over
+        let mut tiles = vec![0; 0x1000];
+        tiles[16] = 0x88;
+        tiles[31] = 0x80;
+        let mut sprites = vec![0; 0x1000];
+        sprites[64..128].fill(0xFF);
+        let colors = [0x00, 0x07, 0x38, 0xC0].repeat(8);
+        // Palette 1 maps 2 and 3 to green and red, palette 2 maps 3 to blue
+        let mut palettes = vec![0; 256];
+        palettes[4 + 2] = 2;
+        palettes[4 + 3] = 1;
+        palettes[8 + 3] = 3;
+        let graphics = Graphics::decode(&tiles, &sprites, &colors, &palettes).unwrap();
+        assert_eq!(graphics.colors[1], RED);
+        assert_eq!(graphics.colors[3], BLUE);
+        assert!(Graphics::decode(&tiles, &sprites, &colors[..16], &palettes).is_err());
+
+        let mut i = Interconnect::builder().build();
+        let machine = PacMan::install(&mut i, &rom("JP 0")).unwrap();
+        machine.video.borrow_mut().graphics = graphics;
+        // The top right of the maze, stored by column from 4040, and the top left of the
+        // score line, stored by row from 43DD leftwards
+        for offset in [0x040, 0x3DD] {
+            i.cpu.write8(VIDEO_RAM + offset, 0x01);
+            i.cpu.write8(COLOR_RAM + offset, 0x01);
+        }
+        // Sprite 3, and sprite 4 half off the bottom of the maze
+        for (sprite, x, y) in [(3, 131, 152), (4, 131, 8)] {
+            i.cpu.write8(SPRITE_ATTRIBUTES + sprite * 2, 0x01 << 2);
+            i.cpu.write8(SPRITE_ATTRIBUTES + sprite * 2 + 1, 0x02);
+            i.cpu.write8(SPRITE_COORDS + sprite * 2, x);
+            i.cpu.write8(SPRITE_COORDS + sprite * 2 + 1, y);
+        }
+        while machine.io.borrow().frames == 0 {
+            i.step();
+        }
+        let frame = machine.video.borrow().frame.clone();
+        let frame = frame.borrow();
+        assert_eq!((frame.width, frame.height), (224, 288));
+        assert_eq!(frame.pixel(216, 16), GREEN);
+        assert_eq!(frame.pixel(223, 20), RED);
+        assert_eq!(frame.pixel(217, 16), BLACK);
+        assert_eq!(frame.pixel(0, 0), GREEN);
+        assert_eq!(frame.pixel(7, 4), RED);
+
+        for (x, y) in [(108, 120), (123, 135), (115, 128)] {
+            assert_eq!(frame.pixel(x, y), BLUE);
+        }
+        for (x, y) in [(107, 120), (124, 135), (108, 119), (108, 136)] {
+            assert_eq!(frame.pixel(x, y), BLACK);
+        }
+        assert_eq!(frame.pixel(108, 271), BLUE);
+        assert_eq!(frame.pixel(108, 272), BLACK);
+        drop(frame);
+
+        // Sprite 0 goes over the others a pixel further down, transparent where its
+        // palette maps to color 0
+        i.cpu.write8(SPRITE_ATTRIBUTES, 0x01 << 2);
+        i.cpu.write8(SPRITE_ATTRIBUTES + 1, 0x02);
+        i.cpu.write8(SPRITE_COORDS, 131);
+        i.cpu.write8(SPRITE_COORDS + 1, 152);
+        i.cpu.write8(SPRITE_ATTRIBUTES + 9, 0x00);
+        machine.video.borrow().render(&i.cpu.memory);
+        let frame = machine.video.borrow().frame.clone();
+        let frame = frame.borrow();
+        assert_eq!(frame.pixel(108, 136), BLUE);
+        assert_eq!(frame.pixel(108, 120), BLUE);
+        assert_eq!(frame.pixel(108, 271), BLACK);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::device::Device;
+use crate::peripherals::serial::SerialBackend;
+
+// Status register bits
+const RDRF: u8 = 0x01;
+const TDRE: u8 = 0x02;
+const OVRN: u8 = 0x20;
+const IRQ: u8 = 0x80;
+
+// Motorola 6850 ACIA, the serial chip of most RC2014 and Grant Searle style boards. Even
+// ports are control (write) / status (read), odd ports data.
+//
+// Characters move once every `char_cycles` T states between the chip and its backend, the
+// counter divide and word select bits are stored but don't change timing. /IRQ is wired
+// to /INT with nothing driving the data bus, so the interrupt reads as RST 38 in mode 1.
+pub struct Acia {
+    pub control: u8,
+    // Receive data register, full when `Some`
+    pub rx: Option<u8>,
+    // Transmit data register, sent at the next character time
+    pub tx: Option<u8>,
+    pub overrun: bool,
+    pub char_cycles: usize,
+    // T states into the current character time
+    elapsed: usize,
+    backend: Option<Box<dyn SerialBackend>>,
+}
+
+impl Default for Acia {
+    // 115200 baud, the /64 divider on a 7.3728MHz clock
+    fn default() -> Self {
+        Self::new(640)
+    }
+}
+
+impl Acia {
+    pub fn new(char_cycles: usize) -> Self {
+        assert!(char_cycles > 0, "Character time must be non zero");
+        Self {
+            // Held in master reset until the first control write
+            control: 0x03,
+            rx: None,
+            tx: None,
+            overrun: false,
+            char_cycles,
+            elapsed: 0,
+            backend: None,
+        }
+    }
+
+    pub fn connect<B: SerialBackend + 'static>(&mut self, backend: B) {
+        self.backend = Some(Box::new(backend));
+    }
+
+    fn in_reset(&self) -> bool {
+        self.control & 0x03 == 0x03
+    }
+
+    fn rx_int_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    // Control bits 5-6 = 01 is RTS low with transmit interrupts
+    fn tx_int_enabled(&self) -> bool {
+        self.control & 0x60 == 0x20
+    }
+
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.rx.is_some() {
+            status |= RDRF;
+        }
+        if self.tx.is_none() {
+            status |= TDRE;
+        }
+        if self.overrun {
+            status |= OVRN;
+        }
+        if self.irq() {
+            status |= IRQ;
+        }
+        status
+    }
+
+    fn irq(&self) -> bool {
+        if self.in_reset() {
+            return false;
+        }
+        let rx = self.rx_int_enabled() && (self.rx.is_some() || self.overrun);
+        let tx = self.tx_int_enabled() && self.tx.is_none();
+        rx || tx
+    }
+
+    // One character time: sends the transmit register and takes in a character
+    fn character(&mut self) {
+        if self.in_reset() {
+            return;
+        }
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+        if let Some(byte) = self.tx.take() {
+            backend.send(byte);
+        }
+        // A full register leaves the byte with the backend rather than overrunning
+        if self.rx.is_none() {
+            self.rx = backend.receive();
+        }
+    }
+}
+
+impl Device for Acia {
+    fn tick(&mut self, cycles: usize) {
+        self.elapsed += cycles;
+        while self.elapsed >= self.char_cycles {
+            self.elapsed -= self.char_cycles;
+            self.character();
+        }
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        if port & 1 == 0 {
+            return self.status();
+        }
+        self.overrun = false;
+        self.rx.take().unwrap_or(0xFF)
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        if port & 1 != 0 {
+            self.tx = Some(value);
+            return;
+        }
+        self.control = value;
+        if self.in_reset() {
+            self.rx = None;
+            self.tx = None;
+            self.overrun = false;
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.irq().then_some(0xFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Acia;
+    use crate::device::Device;
+    use crate::peripherals::serial::Pipe;
+
+    #[test]
+    fn transmit_and_receive() {
+        let mut acia = Acia::new(10);
+        let pipe = Pipe::default();
+        acia.connect(pipe.clone());
+        pipe.write(b"hi");
+        // Nothing moves during master reset
+        acia.tick(100);
+        assert_eq!(acia.io_read(0), 0x02);
+
+        // Divide by 64, 8N1, receive interrupts
+        acia.io_write(0, 0x96);
+        acia.io_write(1, b'A');
+        assert_eq!(acia.io_read(0) & 0x02, 0);
+        acia.tick(10);
+        assert_eq!(pipe.take_output(), b"A");
+        assert_eq!(acia.io_read(0), 0x83);
+        assert_eq!(acia.pending_interrupt(), Some(0xFF));
+        assert_eq!(acia.io_read(1), b'h');
+        assert_eq!(acia.pending_interrupt(), None);
+        acia.tick(10);
+        assert_eq!(acia.io_read(1), b'i');
+
+        // Transmit interrupts while the data register is empty
+        acia.io_write(0, 0x36);
+        assert_eq!(acia.pending_interrupt(), Some(0xFF));
+        acia.io_write(1, b'B');
+        assert_eq!(acia.pending_interrupt(), None);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::device::Device;
+
+// Register numbers
+pub const MIXER: usize = 7;
+pub const ENVELOPE_SHAPE: usize = 13;
+pub const PORT_A: usize = 14;
+pub const PORT_B: usize = 15;
+
+// Bits that exist in each register, the rest read back as 0
+const MASKS: [u8; 16] = [
+    0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF, 0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF,
+];
+
+// Measured output of the AY's 16 levels
+const AY_LEVELS: [f32; 16] = [
+    0.0, 0.0137, 0.0205, 0.0291, 0.0423, 0.0618, 0.0847, 0.1369, 0.1691, 0.2647, 0.3527, 0.4499,
+    0.5704, 0.6873, 0.8482, 1.0,
+];
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Chip {
+    #[default]
+    Ay8910,
+    // Same registers, but the envelope has 32 levels instead of 16 in 1.5dB steps
+    Ym2149,
+}
+
+// How the register select, write and read strobes are decoded from the port
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum AyBus {
+    // Consecutive ports: select, write, read (MSX A0-A2)
+    #[default]
+    Msx,
+    // A14 set selects (FFFD) or reads, clear writes (BFFD)
+    Spectrum,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Tone {
+    counter: u16,
+    output: bool,
+}
+
+// General Instrument AY-3-8910 / Yamaha YM2149 PSG: three square wave tone channels, a
+// noise generator and an envelope, mixed down to a mono audio buffer.
+//
+// The generators run at the chip clock / 16, derived from the T states the CPU spends, and
+// each sample is the average output over its time so tones above half the sample rate
+// don't alias badly. Samples go from 0.0 to 1.0 and pile up in `samples` until taken.
+pub struct Ay {
+    pub regs: [u8; 16],
+    // Selected register
+    pub address: usize,
+    pub chip: Chip,
+    pub bus: AyBus,
+    // Levels on the I/O port pins, read back while the port is an input (R7 bits 6-7)
+    pub inputs: [u8; 2],
+    pub clock: u64,
+    pub cpu_clock: u64,
+    pub sample_rate: u64,
+    pub samples: Vec<f32>,
+    tones: [Tone; 3],
+    noise_counter: u16,
+    // 17-bit LFSR
+    rng: u32,
+    envelope_counter: u32,
+    // 0-31, through the 32 level steps of one envelope cycle
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+    // Chip clocks * CPU clock towards the next generator step
+    clock_phase: u64,
+    // Generator steps * sample rate towards the next sample
+    sample_phase: u64,
+    sample_sum: f32,
+    sample_count: u32,
+}
+
+impl Default for Ay {
+    // Spectrum 128 clocks
+    fn default() -> Self {
+        Self::new(1_773_400, 3_546_900, 44_100)
+    }
+}
+
+impl Ay {
+    pub fn new(clock: u64, cpu_clock: u64, sample_rate: u64) -> Self {
+        assert!(clock > 0 && cpu_clock > 0 && sample_rate > 0);
+        Self {
+            regs: [0; 16],
+            address: 0,
+            chip: Chip::default(),
+            bus: AyBus::default(),
+            inputs: [0xFF; 2],
+            clock,
+            cpu_clock,
+            sample_rate,
+            samples: Vec::new(),
+            tones: Default::default(),
+            noise_counter: 0,
+            rng: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+            clock_phase: 0,
+            sample_phase: 0,
+            sample_sum: 0.0,
+            sample_count: 0,
+        }
+    }
+
+    pub fn select(&mut self, register: u8) {
+        // Registers above 15 aren't selected by the chip address bits
+        if register < 16 {
+            self.address = register as usize;
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        let reg = self.address;
+        self.regs[reg] = value & MASKS[reg];
+        if reg == ENVELOPE_SHAPE {
+            self.restart_envelope();
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let reg = self.address;
+        match reg {
+            PORT_A | PORT_B if self.regs[MIXER] & (0x40 << (reg - PORT_A)) == 0 => {
+                self.inputs[reg - PORT_A]
+            }
+            _ => self.regs[reg],
+        }
+    }
+
+    // Everything synthesized so far, clearing the buffer
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = self.regs[channel * 2] as u16 | (self.regs[channel * 2 + 1] as u16) << 8;
+        period.max(1)
+    }
+
+    fn restart_envelope(&mut self) {
+        self.envelope_counter = 0;
+        self.envelope_step = 0;
+        self.envelope_attack = self.regs[ENVELOPE_SHAPE] & 0x04 != 0;
+        self.envelope_holding = false;
+    }
+
+    // One of the 32 envelope steps. Shapes without the continue bit drop to 0 after the
+    // first cycle, hold stops at the end of it (after flipping, with alternate) and
+    // alternate without hold goes back and forth.
+    fn envelope_advance(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.regs[ENVELOPE_SHAPE];
+        let (cont, alternate, hold) = (shape & 0x08 != 0, shape & 0x02 != 0, shape & 0x01 != 0);
+        if !cont {
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if hold {
+            self.envelope_attack ^= alternate;
+            self.envelope_holding = true;
+        } else {
+            self.envelope_attack ^= alternate;
+            self.envelope_step = 0;
+        }
+    }
+
+    // 0-31
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    fn level(&self, level: u8) -> f32 {
+        match self.chip {
+            Chip::Ay8910 => AY_LEVELS[level as usize >> 1],
+            Chip::Ym2149 if level == 0 => 0.0,
+            Chip::Ym2149 => 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0),
+        }
+    }
+
+    // Output level of a channel, `on` being its tone and noise outputs after the mixer
+    fn channel_output(&self, channel: usize, on: bool) -> f32 {
+        if !on {
+            return 0.0;
+        }
+        let volume = self.regs[8 + channel];
+        if volume & 0x10 != 0 {
+            self.level(self.envelope_level())
+        } else if volume == 0 {
+            0.0
+        } else {
+            // Fixed volumes line up with every other envelope level
+            self.level(volume << 1 | 1)
+        }
+    }
+
+    // The generators one clock / 16 on, returns the mixed output
+    fn step(&mut self) -> f32 {
+        // Tone counters count at clock / 8 and flip the output every period, so a channel
+        // plays clock / (16 * TP)
+        for _ in 0..2 {
+            for channel in 0..3 {
+                let period = self.tone_period(channel);
+                let tone = &mut self.tones[channel];
+                tone.counter += 1;
+                if tone.counter >= period {
+                    tone.counter = 0;
+                    tone.output = !tone.output;
+                }
+            }
+        }
+        // The noise shift register moves once a period at clock / 16, clock / (16 * NP)
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.regs[6] as u16).max(1) {
+            self.noise_counter = 0;
+            let bit = (self.rng ^ (self.rng >> 3)) & 1;
+            self.rng = (self.rng >> 1) | (bit << 16);
+        }
+        // 32 envelope steps a cycle, one every half period (the AY's 16 last a period each)
+        let period = (self.regs[11] as u32 | (self.regs[12] as u32) << 8).max(1);
+        self.envelope_counter += 2;
+        while self.envelope_counter >= period {
+            self.envelope_counter -= period;
+            self.envelope_advance();
+        }
+
+        let mixer = self.regs[MIXER];
+        let noise = self.rng & 1 != 0;
+        let mut output = 0.0;
+        for channel in 0..3 {
+            let tone_off = mixer & (1 << channel) != 0;
+            let noise_off = mixer & (8 << channel) != 0;
+            let on = (self.tones[channel].output || tone_off) && (noise || noise_off);
+            output += self.channel_output(channel, on);
+        }
+        output / 3.0
+    }
+}
+
+impl Device for Ay {
+    fn tick(&mut self, cycles: usize) {
+        self.clock_phase += cycles as u64 * self.clock;
+        let step = self.cpu_clock * 16;
+        let steps_per_second = self.clock / 16;
+        while self.clock_phase >= step {
+            self.clock_phase -= step;
+            self.sample_sum += self.step();
+            self.sample_count += 1;
+            self.sample_phase += self.sample_rate;
+            if self.sample_phase >= steps_per_second {
+                self.sample_phase -= steps_per_second;
+                self.samples
+                    .push(self.sample_sum / self.sample_count as f32);
+                self.sample_sum = 0.0;
+                self.sample_count = 0;
+                // Nothing taking samples keeps one to two seconds of them
+                let keep = self.sample_rate as usize;
+                if self.samples.len() >= keep * 2 {
+                    self.samples.drain(..keep);
+                }
+            }
+        }
+    }
+
+    fn io_read(&mut self, _port: u16) -> u8 {
+        self.read()
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        let select = match self.bus {
+            AyBus::Msx => port & 3 == 0,
+            AyBus::Spectrum => port & 0x4000 != 0,
+        };
+        if select {
+            self.select(value);
+        } else {
+            self.write(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ay, AyBus, Chip};
+    use crate::device::Device;
+
+    // One sample per generator step
+    fn ay() -> Ay {
+        Ay::new(16_000, 16_000, 1000)
+    }
+
+    fn set(ay: &mut Ay, regs: &[(u8, u8)]) {
+        for &(reg, value) in regs {
+            ay.select(reg);
+            ay.write(value);
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut ay = ay();
+        ay.bus = AyBus::Spectrum;
+        ay.io_write(0xFFFD, 1);
+        ay.io_write(0xBFFD, 0xFF);
+        assert_eq!(ay.io_read(0xFFFD), 0x0F);
+        // Port A is an input until R7 bit 6 is set
+        ay.inputs[0] = 0x3F;
+        set(&mut ay, &[(14, 0x12)]);
+        assert_eq!(ay.read(), 0x3F);
+        set(&mut ay, &[(7, 0x40)]);
+        ay.select(14);
+        assert_eq!(ay.read(), 0x12);
+    }
+
+    #[test]
+    fn tone() {
+        let mut ay = ay();
+        // Channel A only, period 8 (clock / 128, 4 steps high and 4 low), full volume
+        set(&mut ay, &[(0, 8), (7, 0x3E), (8, 0x0F)]);
+        ay.tick(16 * 16);
+        let high = 1.0 / 3.0;
+        let expected: Vec<f32> = (1..=16)
+            .map(|n| if n / 4 % 2 == 1 { high } else { 0.0 })
+            .collect();
+        assert_eq!(ay.take_samples(), expected);
+        assert!(ay.samples.is_empty());
+
+        // Averaged down to a quarter of the rate, the output goes high for the last step
+        // of the first sample and low for the last of the second
+        ay.sample_rate = 250;
+        ay.tick(16 * 8);
+        let samples = ay.take_samples();
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0] - high / 4.0).abs() < 1e-6);
+        assert!((samples[1] - high * 3.0 / 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn envelope() {
+        let mut ay = ay();
+        // Tone and noise off so channel A plays its volume, envelope period 2, decay then
+        // hold at the top (shape 0x0B)
+        set(&mut ay, &[(7, 0x3F), (8, 0x10), (11, 2), (13, 0x0B)]);
+        ay.tick(16 * 40);
+        let samples = ay.take_samples();
+        assert!(samples.windows(2).take(30).all(|w| w[0] >= w[1]));
+        assert_eq!(samples[30], 0.0);
+        assert_eq!(samples[31..], [1.0 / 3.0; 9]);
+
+        // The YM's envelope has 32 distinct levels
+        ay.chip = Chip::Ym2149;
+        set(&mut ay, &[(13, 0x0D)]);
+        ay.tick(16 * 32);
+        let mut samples = ay.take_samples();
+        samples.dedup();
+        assert_eq!(samples.len(), 31);
+    }
+}
@@ -0,0 +1,23 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::device::Device;
+
+// Minimal single port console: OUT writes a character to stdout,
+// IN returns the next queued input byte (0 if none is available).
+#[derive(Default)]
+pub struct Console {
+    pub input: VecDeque<u8>,
+}
+
+impl Device for Console {
+    fn io_read(&mut self, _port: u16) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[value]).ok();
+        stdout.flush().ok();
+    }
+}
@@ -0,0 +1,205 @@
+use crate::device::Device;
+
+// Register numbers
+pub const HORIZONTAL_TOTAL: usize = 0;
+pub const HORIZONTAL_DISPLAYED: usize = 1;
+pub const HSYNC_POSITION: usize = 2;
+pub const SYNC_WIDTHS: usize = 3;
+pub const VERTICAL_TOTAL: usize = 4;
+pub const VERTICAL_ADJUST: usize = 5;
+pub const VERTICAL_DISPLAYED: usize = 6;
+pub const VSYNC_POSITION: usize = 7;
+pub const MAX_RASTER: usize = 9;
+pub const START_HIGH: usize = 12;
+pub const START_LOW: usize = 13;
+
+// Bits that exist in each register
+const MASKS: [u8; 18] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x1F, 0x7F, 0x7F, 0x03, 0x1F, 0x7F, 0x1F, 0x3F, 0xFF, 0x3F, 0xFF,
+    0x3F, 0xFF,
+];
+
+// Motorola 6845 CRT controller: counts characters along a line and scanlines down the
+// frame from its registers, generating the syncs and the memory address of each
+// character. It only gives the timing, whoever it's attached to fetches and draws the
+// display and steps it once per character with `step`.
+//
+// The register select line (RS) is A0 of the port, or a higher bit with `address_shift`
+// (A8 on the CPC). RS low selects the register on writes, high writes or reads it. Only
+// the start address and cursor registers (12-17) read back, the rest read as 0.
+#[derive(Default)]
+pub struct Crtc {
+    pub regs: [u8; 18],
+    pub address: usize,
+    pub address_shift: u32,
+    // Character within the line, character row and scanline within the row
+    pub hc: u8,
+    pub vc: u8,
+    pub ra: u8,
+    // Scanline within the frame
+    pub line: usize,
+    pub hsync: bool,
+    pub vsync: bool,
+    // Scanlines of vertical adjust so far, while in it
+    adjust: Option<u8>,
+    vsync_lines: u8,
+}
+
+impl Crtc {
+    pub fn write(&mut self, value: u8) {
+        if self.address < MASKS.len() {
+            self.regs[self.address] = value & MASKS[self.address];
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        match self.address {
+            12..=17 => self.regs[self.address],
+            _ => 0,
+        }
+    }
+
+    pub fn lines_per_frame(&self) -> usize {
+        let rows = self.regs[VERTICAL_TOTAL] as usize + 1;
+        rows * (self.regs[MAX_RASTER] as usize + 1) + self.regs[VERTICAL_ADJUST] as usize
+    }
+
+    // Whether the current character is in the displayed area
+    pub fn display(&self) -> bool {
+        self.hc < self.regs[HORIZONTAL_DISPLAYED]
+            && self.vc < self.regs[VERTICAL_DISPLAYED]
+            && self.adjust.is_none()
+    }
+
+    // The 14-bit memory address of the current character
+    pub fn ma(&self) -> u16 {
+        let start = (self.regs[START_HIGH] as u16) << 8 | self.regs[START_LOW] as u16;
+        let row = self.vc as u16 * self.regs[HORIZONTAL_DISPLAYED] as u16;
+        start.wrapping_add(row).wrapping_add(self.hc as u16) & 0x3FFF
+    }
+
+    // On to the next character
+    pub fn step(&mut self) {
+        if self.hc == self.regs[HORIZONTAL_TOTAL] {
+            self.hc = 0;
+            self.end_line();
+        } else {
+            self.hc = self.hc.wrapping_add(1);
+        }
+        let width = self.regs[SYNC_WIDTHS] as u16 & 0x0F;
+        let start = self.regs[HSYNC_POSITION] as u16;
+        self.hsync = (start..start + width).contains(&(self.hc as u16));
+    }
+
+    fn end_line(&mut self) {
+        self.line += 1;
+        if self.vsync {
+            self.vsync_lines += 1;
+            let width = match self.regs[SYNC_WIDTHS] >> 4 {
+                0 => 16,
+                width => width,
+            };
+            self.vsync = self.vsync_lines < width;
+        }
+        match self.adjust {
+            Some(lines) if lines + 1 >= self.regs[VERTICAL_ADJUST] => self.start_frame(),
+            Some(lines) => self.adjust = Some(lines + 1),
+            None if self.ra < self.regs[MAX_RASTER] => self.ra += 1,
+            None if self.vc >= self.regs[VERTICAL_TOTAL] => match self.regs[VERTICAL_ADJUST] {
+                0 => self.start_frame(),
+                _ => {
+                    self.ra = 0;
+                    self.adjust = Some(0);
+                }
+            },
+            None => {
+                self.ra = 0;
+                self.vc = (self.vc + 1) & 0x7F;
+            }
+        }
+        if self.ra == 0 && self.adjust.is_none() && self.vc == self.regs[VSYNC_POSITION] {
+            self.vsync = true;
+            self.vsync_lines = 0;
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.vc = 0;
+        self.ra = 0;
+        self.line = 0;
+        self.adjust = None;
+    }
+}
+
+impl Device for Crtc {
+    fn io_read(&mut self, port: u16) -> u8 {
+        match port >> self.address_shift & 1 {
+            0 => 0xFF,
+            _ => self.read(),
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        match port >> self.address_shift & 1 {
+            0 => self.address = (value & 0x1F) as usize,
+            _ => self.write(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crtc;
+    use crate::device::Device;
+
+    // 4 characters of which 2 are shown, hsync at 3 for 1; 3 rows of 2 scanlines shown and
+    // 1 more row plus 1 line of adjust, vsync at row 2 for 2 lines; starting at 0100
+    fn crtc() -> Crtc {
+        let mut crtc = Crtc::default();
+        for (reg, value) in [3, 2, 3, 0x21, 3, 1, 3, 2, 0, 1, 0, 0, 0x01, 0x00]
+            .iter()
+            .enumerate()
+        {
+            crtc.io_write(0x00, reg as u8);
+            crtc.io_write(0x01, *value);
+        }
+        crtc
+    }
+
+    #[test]
+    fn timing() {
+        let mut crtc = crtc();
+        assert_eq!(crtc.lines_per_frame(), 9);
+        assert_eq!(crtc.io_read(0x01), 0x00);
+        assert!(crtc.display());
+        assert_eq!(crtc.ma(), 0x0100);
+        crtc.step();
+        assert_eq!(crtc.ma(), 0x0101);
+        crtc.step();
+        assert!(!crtc.display());
+        crtc.step();
+        assert!(crtc.hsync);
+        crtc.step();
+        assert!(!crtc.hsync);
+        assert_eq!((crtc.hc, crtc.ra, crtc.line), (0, 1, 1));
+        // Second row
+        (0..4).for_each(|_| crtc.step());
+        assert_eq!((crtc.vc, crtc.ra), (1, 0));
+        assert_eq!(crtc.ma(), 0x0102);
+        // Vsync from row 2 for 2 lines
+        (0..8).for_each(|_| crtc.step());
+        assert!(crtc.vsync);
+        (0..8).for_each(|_| crtc.step());
+        assert!(!crtc.vsync);
+        // Row 3 isn't displayed, then a line of adjust and the next frame
+        assert_eq!(crtc.vc, 3);
+        assert!(!crtc.display());
+        (0..8).for_each(|_| crtc.step());
+        assert_eq!((crtc.vc, crtc.line), (3, 8));
+        (0..4).for_each(|_| crtc.step());
+        assert_eq!((crtc.vc, crtc.ra, crtc.line), (0, 0, 0));
+
+        crtc.io_write(0x00, 12);
+        assert_eq!(crtc.io_read(0x01), 0x01);
+    }
+}
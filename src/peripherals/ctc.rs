@@ -0,0 +1,311 @@
+use crate::device::Device;
+
+// Control word bits, a word with bit 0 clear written to channel 0 is the interrupt vector
+const CONTROL: u8 = 0x01;
+const RESET: u8 = 0x02;
+const TIME_CONSTANT: u8 = 0x04;
+const TRIGGER: u8 = 0x08;
+const RISING: u8 = 0x10;
+const PRESCALE_256: u8 = 0x20;
+const COUNTER: u8 = 0x40;
+const INTERRUPT: u8 = 0x80;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Channel {
+    pub control: u8,
+    pub time_constant: u8,
+    pub counter: u8,
+    // Counting, as opposed to reset or waiting for a time constant / trigger
+    pub running: bool,
+    // Next control write is the time constant
+    awaiting_constant: bool,
+    // Timer mode T states towards the next prescaler tick
+    prescale: usize,
+    // Last level on CLK/TRG, for edge detection
+    level: bool,
+    pub pending: bool,
+    pub in_service: bool,
+}
+
+impl Channel {
+    fn prescaler(&self) -> usize {
+        if self.control & PRESCALE_256 != 0 {
+            256
+        } else {
+            16
+        }
+    }
+
+    // 0 stands for 256
+    fn reload(&mut self) {
+        self.counter = self.time_constant;
+    }
+
+    // One count, returns true when it reached zero (ZC/TO pulses)
+    fn count(&mut self) -> bool {
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter != 0 {
+            return false;
+        }
+        self.reload();
+        if self.control & INTERRUPT != 0 {
+            self.pending = true;
+        }
+        true
+    }
+}
+
+// Z80 CTC, four counter / timer channels on consecutive ports (the port's low 2 bits pick
+// the channel). In timer mode a channel counts down every 16 or 256 T states, in counter
+// mode on each active CLK/TRG edge, fed with `trigger` or from the previous channel's
+// ZC/TO output where `cascade` says it's wired that way. Reaching zero reloads the time
+// constant and, with interrupts enabled, requests an interrupt with vector
+// `vector | channel << 1`. Channel 0 has the highest priority.
+#[derive(Debug, Default)]
+pub struct Ctc {
+    pub channels: [Channel; 4],
+    pub vector: u8,
+    // ZC/TO of channel n drives CLK/TRG of channel n + 1, e.g. to chain timers into a
+    // longer period
+    pub cascade: [bool; 3],
+    // Zero counts per channel, for polling ZC/TO from the outside (e.g. a baud rate clock)
+    pub zero_counts: [usize; 4],
+}
+
+impl Ctc {
+    // A level change on a channel's CLK/TRG input. Active edges count in counter mode
+    // and start a timer waiting for its trigger.
+    pub fn trigger(&mut self, channel: usize, level: bool) {
+        let ch = &mut self.channels[channel];
+        let rising = !ch.level && level;
+        let falling = ch.level && !level;
+        ch.level = level;
+        let active = if ch.control & RISING != 0 {
+            rising
+        } else {
+            falling
+        };
+        if active {
+            self.pulse(channel);
+        }
+    }
+
+    // One active edge on CLK/TRG
+    fn pulse(&mut self, channel: usize) {
+        let ch = &mut self.channels[channel];
+        if ch.awaiting_constant || ch.control & RESET != 0 && !ch.running {
+            return;
+        }
+        if ch.control & COUNTER != 0 {
+            ch.running = true;
+            if ch.count() {
+                self.zero_count(channel);
+            }
+        } else if !ch.running {
+            // Timer waiting for its trigger
+            ch.running = true;
+            ch.prescale = 0;
+        }
+    }
+
+    fn zero_count(&mut self, channel: usize) {
+        self.zero_counts[channel] += 1;
+        if channel < 3 && self.cascade[channel] {
+            self.pulse(channel + 1);
+        }
+    }
+
+    fn write_control(&mut self, channel: usize, value: u8) {
+        let ch = &mut self.channels[channel];
+        ch.control = value;
+        if value & INTERRUPT == 0 {
+            ch.pending = false;
+        }
+        if value & TIME_CONSTANT != 0 {
+            ch.awaiting_constant = true;
+        }
+        if value & RESET != 0 {
+            ch.running = false;
+            ch.pending = false;
+        }
+    }
+
+    fn write_time_constant(&mut self, channel: usize, value: u8) {
+        let ch = &mut self.channels[channel];
+        ch.awaiting_constant = false;
+        ch.time_constant = value;
+        // A running channel picks up the new constant when it next reaches zero
+        if ch.running {
+            return;
+        }
+        ch.reload();
+        ch.prescale = 0;
+        // Counters start counting edges right away, timers unless they wait for a trigger
+        ch.running = ch.control & COUNTER != 0 || ch.control & TRIGGER == 0;
+        ch.control &= !RESET;
+    }
+
+    // The highest priority channel that isn't blocked by one under service
+    fn requesting(&self) -> Option<usize> {
+        for (n, ch) in self.channels.iter().enumerate() {
+            if ch.in_service {
+                return None;
+            }
+            if ch.pending {
+                return Some(n);
+            }
+        }
+        None
+    }
+}
+
+impl Device for Ctc {
+    fn tick(&mut self, cycles: usize) {
+        for channel in 0..4 {
+            let ch = &mut self.channels[channel];
+            if !ch.running || ch.control & COUNTER != 0 {
+                continue;
+            }
+            ch.prescale += cycles;
+            let prescaler = ch.prescaler();
+            let mut zeros = 0;
+            while ch.prescale >= prescaler {
+                ch.prescale -= prescaler;
+                zeros += ch.count() as usize;
+            }
+            for _ in 0..zeros {
+                self.zero_count(channel);
+            }
+        }
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        self.channels[port as usize & 3].counter
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        let channel = port as usize & 3;
+        if self.channels[channel].awaiting_constant {
+            self.write_time_constant(channel, value);
+        } else if value & CONTROL != 0 {
+            self.write_control(channel, value);
+        } else if channel == 0 {
+            self.vector = value & 0xF8;
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.requesting()
+            .map(|channel| self.vector | (channel as u8) << 1)
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        if let Some(channel) = self.requesting() {
+            let ch = &mut self.channels[channel];
+            ch.pending = false;
+            ch.in_service = true;
+        }
+    }
+
+    fn reti(&mut self) {
+        if let Some(ch) = self.channels.iter_mut().find(|ch| ch.in_service) {
+            ch.in_service = false;
+        }
+    }
+
+    fn interrupt_in_service(&self) -> bool {
+        self.channels.iter().any(|ch| ch.in_service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ctc;
+    use crate::device::Device;
+    use crate::interconnect::{Interconnect, Preset};
+
+    #[test]
+    fn timer_and_counter() {
+        let mut ctc = Ctc::default();
+        // Channel 0: timer, prescaler 16, time constant 4, interrupts on
+        ctc.io_write(0, 0x85);
+        ctc.io_write(0, 4);
+        ctc.io_write(0, 0x40);
+        ctc.tick(16 * 3);
+        assert_eq!(ctc.io_read(0), 1);
+        assert_eq!(ctc.pending_interrupt(), None);
+        ctc.tick(16);
+        assert_eq!(ctc.io_read(0), 4);
+        assert_eq!(ctc.zero_counts[0], 1);
+        assert_eq!(ctc.pending_interrupt(), Some(0x40));
+
+        // Channel 2: counter on rising edges, time constant 2, fed by channel 0
+        ctc.cascade = [true, true, false];
+        ctc.io_write(1, 0x57);
+        ctc.io_write(1, 2);
+        ctc.io_write(2, 0xD5);
+        ctc.io_write(2, 2);
+        ctc.trigger(2, true);
+        assert_eq!(ctc.io_read(2), 1);
+        ctc.trigger(2, false);
+        assert_eq!(ctc.io_read(2), 1);
+        // Two zero counts on channel 0 make one on channel 1, which counts channel 2 down
+        ctc.tick(16 * 8);
+        assert_eq!(ctc.zero_counts, [3, 1, 1, 0]);
+
+        // Channel 0 is ahead of channel 2, and blocks it while under service
+        ctc.interrupt_acknowledged();
+        assert!(ctc.channels[0].in_service);
+        assert_eq!(ctc.pending_interrupt(), None);
+        ctc.reti();
+        assert_eq!(ctc.pending_interrupt(), Some(0x44));
+
+        // Reset stops the channel until a new time constant
+        ctc.io_write(0, 0x03);
+        ctc.tick(16 * 8);
+        assert_eq!(ctc.zero_counts[0], 3);
+    }
+
+    #[test]
+    fn triggered_timer() {
+        let mut ctc = Ctc::default();
+        // Timer started by a falling edge on CLK/TRG, prescaler 256
+        ctc.io_write(3, 0x2D);
+        ctc.io_write(3, 0);
+        ctc.tick(1000);
+        assert_eq!(ctc.io_read(3), 0);
+        ctc.trigger(3, true);
+        ctc.trigger(3, false);
+        ctc.tick(256 * 2);
+        assert_eq!(ctc.io_read(3), 254);
+        assert_eq!(ctc.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn mode_2_interrupts() {
+        // IM 2 with the table at 0x0200, the CTC vector is 0x10 so channel 1 uses 0x0212.
+        // 0100: JR 0100, handler at 0300: EI; RETI
+        let mut i = Interconnect::builder()
+            .preset(Preset::Cpm)
+            .interrupt_mode(2)
+            .build();
+        i.cpu.reg.i = 0x02;
+        i.cpu.int.iff1 = true;
+        i.cpu.memory.load_slice(0x0100, &[0xC3, 0x00, 0x01]);
+        i.cpu.memory.load_slice(0x0212, &[0x00, 0x03]);
+        i.cpu.memory.load_slice(0x0300, &[0xFB, 0xED, 0x4D]);
+        let ctc = i.add_device(Ctc::default());
+        i.register_port(0x00..=0x03, ctc.clone());
+        for (port, value) in [(0, 0x10), (1, 0x85), (1, 1)] {
+            ctc.borrow_mut().io_write(port, value);
+        }
+        while i.cpu.reg.pc != 0x0300 {
+            i.step();
+        }
+        assert!(ctc.borrow().channels[1].in_service);
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0100);
+        assert!(!ctc.borrow().channels[1].in_service);
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::VecDeque;
+
+use log::warn;
+
+use crate::device::{Device, IoBus};
+use crate::memory::Memory;
+
+pub const PORT_A: usize = 0;
+pub const PORT_B: usize = 1;
+
+// Interrupt control byte (WR4)
+const INT_MATCH: u8 = 0x01;
+const INT_END_OF_BLOCK: u8 = 0x02;
+const STATUS_AFFECTS_VECTOR: u8 = 0x20;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Addressing {
+    Decrement,
+    #[default]
+    Increment,
+    Fixed,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum DmaMode {
+    // The bus goes back to the CPU after every byte
+    #[default]
+    Byte,
+    // The bus is held for the whole block
+    Continuous,
+    // The bus is held for as long as RDY is active
+    Burst,
+}
+
+// Parameter bytes that follow a register's base byte, in the order they're written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Param {
+    PortALow,
+    PortAHigh,
+    LengthLow,
+    LengthHigh,
+    PortATiming,
+    PortBTiming,
+    Prescaler,
+    Mask,
+    Match,
+    PortBLow,
+    PortBHigh,
+    IntControl,
+    Pulse,
+    Vector,
+    ReadMask,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DmaPort {
+    // Starting address, copied to `address` by LOAD
+    pub start: u16,
+    pub address: u16,
+    pub io: bool,
+    pub addressing: Addressing,
+    // T states per read or write
+    pub cycles: usize,
+}
+
+impl Default for DmaPort {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            address: 0,
+            io: false,
+            addressing: Addressing::default(),
+            cycles: 3,
+        }
+    }
+}
+
+impl DmaPort {
+    fn set(&mut self, value: u8) {
+        self.io = value & 0x08 != 0;
+        self.addressing = match value >> 4 & 3 {
+            0 => Addressing::Decrement,
+            1 => Addressing::Increment,
+            _ => Addressing::Fixed,
+        };
+    }
+
+    fn set_timing(&mut self, value: u8) {
+        self.cycles = match value & 3 {
+            1 => 3,
+            2 => 2,
+            _ => 4,
+        };
+    }
+
+    fn read(&self, memory: &mut Memory, io: &mut IoBus) -> u8 {
+        if self.io {
+            io.read(self.address)
+        } else {
+            memory.read_mapped(self.address)
+        }
+    }
+
+    fn write(&self, value: u8, memory: &mut Memory, io: &mut IoBus) {
+        if self.io {
+            memory.paging_write(self.address, value);
+            io.write(self.address, value);
+        } else {
+            memory.write_mapped(self.address, value);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.address = match self.addressing {
+            Addressing::Decrement => self.address.wrapping_sub(1),
+            Addressing::Increment => self.address.wrapping_add(1),
+            Addressing::Fixed => self.address,
+        };
+    }
+}
+
+// Z80 DMA on a single port, programmed through write registers WR0-WR6 (a base byte,
+// followed by the parameter bytes its bits announce) and read back through the status,
+// byte counter and address registers picked by the read mask.
+//
+// Once enabled and with RDY active it requests the bus, and copies bytes between port A
+// and B (memory or I/O), looks for a byte matching `match_byte` (bits set in `mask` are
+// ignored), or both. As on the Zilog part a block is one byte longer than the programmed
+// length. It can interrupt on a match or at the end of the block, and takes part in the
+// interrupt daisy chain.
+pub struct Dma {
+    pub ports: [DmaPort; 2],
+    pub length: u16,
+    // Bytes transferred or searched since the last LOAD / CONTINUE
+    pub counter: u16,
+    pub a_to_b: bool,
+    pub transfer: bool,
+    pub search: bool,
+    pub mode: DmaMode,
+    pub match_byte: u8,
+    pub mask: u8,
+    pub stop_on_match: bool,
+    pub auto_restart: bool,
+    pub enabled: bool,
+    // RDY input, machines that only copy memory tie it active
+    pub ready: bool,
+    pub int_enable: bool,
+    pub int_control: u8,
+    pub vector: u8,
+    pub pending: bool,
+    pub in_service: bool,
+    // Status: a byte has been transferred, a match found, the end of the block reached
+    pub transferred: bool,
+    pub matched: bool,
+    pub end_of_block: bool,
+    read_mask: u8,
+    reads: VecDeque<u8>,
+    params: VecDeque<Param>,
+    // What caused the pending interrupt, for status affects vector
+    cause: u8,
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self {
+            ports: Default::default(),
+            length: 0,
+            counter: 0,
+            a_to_b: true,
+            transfer: true,
+            search: false,
+            mode: DmaMode::default(),
+            match_byte: 0,
+            mask: 0,
+            stop_on_match: false,
+            auto_restart: false,
+            enabled: false,
+            ready: true,
+            int_enable: false,
+            int_control: 0,
+            vector: 0,
+            pending: false,
+            in_service: false,
+            transferred: false,
+            matched: false,
+            end_of_block: false,
+            read_mask: 0x7F,
+            reads: VecDeque::new(),
+            params: VecDeque::new(),
+            cause: 0,
+        }
+    }
+}
+
+impl Dma {
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    pub fn status(&self) -> u8 {
+        // Active low, bit 2 always reads as 1
+        let mut status = 0x04;
+        status |= self.transferred as u8;
+        status |= (self.ready as u8) << 1;
+        status |= (!self.pending as u8) << 3;
+        status |= (!self.matched as u8) << 4;
+        status |= (!self.end_of_block as u8) << 5;
+        status
+    }
+
+    fn load(&mut self) {
+        for port in &mut self.ports {
+            port.address = port.start;
+        }
+        self.counter = 0;
+    }
+
+    fn interrupt(&mut self, cause: u8) {
+        if self.int_enable && self.int_control & cause != 0 {
+            self.pending = true;
+            self.cause |= cause;
+        }
+    }
+
+    fn write_base(&mut self, value: u8) {
+        let follow = |params: &mut VecDeque<Param>, bits: &[(u8, Param)]| {
+            for &(bit, param) in bits {
+                if value & bit != 0 {
+                    params.push_back(param);
+                }
+            }
+        };
+        if value & 0x80 == 0 {
+            if value & 0x03 != 0 {
+                // WR0
+                self.a_to_b = value & 0x04 != 0;
+                self.transfer = value & 0x01 != 0;
+                self.search = value & 0x02 != 0;
+                follow(
+                    &mut self.params,
+                    &[
+                        (0x08, Param::PortALow),
+                        (0x10, Param::PortAHigh),
+                        (0x20, Param::LengthLow),
+                        (0x40, Param::LengthHigh),
+                    ],
+                );
+            } else {
+                // WR1 for port A, WR2 for port B
+                let (port, timing) = if value & 0x04 != 0 {
+                    (PORT_A, Param::PortATiming)
+                } else {
+                    (PORT_B, Param::PortBTiming)
+                };
+                self.ports[port].set(value);
+                follow(&mut self.params, &[(0x40, timing)]);
+            }
+            return;
+        }
+        match value & 0x03 {
+            0 => {
+                // WR3
+                self.stop_on_match = value & 0x04 != 0;
+                self.int_enable = value & 0x20 != 0;
+                if value & 0x40 != 0 {
+                    self.enabled = true;
+                }
+                follow(
+                    &mut self.params,
+                    &[(0x08, Param::Mask), (0x10, Param::Match)],
+                );
+            }
+            1 => {
+                // WR4
+                self.mode = match value >> 5 & 3 {
+                    0 => DmaMode::Byte,
+                    1 => DmaMode::Continuous,
+                    _ => DmaMode::Burst,
+                };
+                follow(
+                    &mut self.params,
+                    &[
+                        (0x04, Param::PortBLow),
+                        (0x08, Param::PortBHigh),
+                        (0x10, Param::IntControl),
+                    ],
+                );
+            }
+            2 => {
+                // WR5, RDY polarity and CE/WAIT only matter to the wiring
+                self.auto_restart = value & 0x20 != 0;
+            }
+            _ => self.command(value),
+        }
+    }
+
+    fn write_param(&mut self, param: Param, value: u8) {
+        let set_low = |word: &mut u16| *word = *word & 0xFF00 | value as u16;
+        let set_high = |word: &mut u16| *word = *word & 0x00FF | (value as u16) << 8;
+        match param {
+            Param::PortALow => set_low(&mut self.ports[PORT_A].start),
+            Param::PortAHigh => set_high(&mut self.ports[PORT_A].start),
+            Param::PortBLow => set_low(&mut self.ports[PORT_B].start),
+            Param::PortBHigh => set_high(&mut self.ports[PORT_B].start),
+            Param::LengthLow => set_low(&mut self.length),
+            Param::LengthHigh => set_high(&mut self.length),
+            Param::PortATiming => self.ports[PORT_A].set_timing(value),
+            Param::PortBTiming => {
+                self.ports[PORT_B].set_timing(value);
+                if value & 0x20 != 0 {
+                    self.params.push_front(Param::Prescaler);
+                }
+            }
+            Param::Mask => self.mask = value,
+            Param::Match => self.match_byte = value,
+            Param::IntControl => {
+                self.int_control = value;
+                if value & 0x10 != 0 {
+                    self.params.push_front(Param::Vector);
+                }
+                if value & 0x08 != 0 {
+                    self.params.push_front(Param::Pulse);
+                }
+            }
+            Param::Vector => self.vector = value,
+            Param::ReadMask => {
+                self.read_mask = value & 0x7F;
+                self.reads.clear();
+            }
+            // Only used in search mode with RDY pulses, nothing here generates them
+            Param::Prescaler | Param::Pulse => {}
+        }
+    }
+
+    // WR6
+    fn command(&mut self, value: u8) {
+        match value {
+            // Reset
+            0xC3 => {
+                let ready = self.ready;
+                *self = Self {
+                    ready,
+                    ..Self::default()
+                };
+            }
+            // Reset port A / B timing
+            0xC7 => self.ports[PORT_A].cycles = 3,
+            0xCB => self.ports[PORT_B].cycles = 3,
+            0xCF => {
+                self.load();
+                self.matched = false;
+                self.end_of_block = false;
+            }
+            // Continue, from the current addresses with a new block
+            0xD3 => {
+                self.counter = 0;
+                self.matched = false;
+                self.end_of_block = false;
+            }
+            0xAF => self.int_enable = false,
+            0xAB => self.int_enable = true,
+            // Reset and disable interrupts
+            0xA3 => {
+                self.int_enable = false;
+                self.pending = false;
+                self.in_service = false;
+                self.cause = 0;
+            }
+            // Reinitialize status byte
+            0x8B => {
+                self.matched = false;
+                self.end_of_block = false;
+            }
+            // Read status byte
+            0xBF => {
+                self.reads.clear();
+                self.reads.push_back(self.status());
+            }
+            // Initiate read sequence
+            0xA7 => self.start_reads(),
+            0xBB => self.params.push_back(Param::ReadMask),
+            0x87 => self.enabled = true,
+            0x83 => self.enabled = false,
+            // Enable after RETI (interrupts are held off until the RETI anyway), force ready
+            0xB7 | 0xB3 => {}
+            _ => warn!("DMA: unknown command {:02X}", value),
+        }
+    }
+
+    fn start_reads(&mut self) {
+        let values = [
+            self.status(),
+            self.counter as u8,
+            (self.counter >> 8) as u8,
+            self.ports[PORT_A].address as u8,
+            (self.ports[PORT_A].address >> 8) as u8,
+            self.ports[PORT_B].address as u8,
+            (self.ports[PORT_B].address >> 8) as u8,
+        ];
+        self.reads = values
+            .iter()
+            .enumerate()
+            .filter(|(n, _)| self.read_mask & 1 << n != 0)
+            .map(|(_, &value)| value)
+            .collect();
+    }
+
+    // One byte read from the source port and written to the destination, returns the T
+    // states it took
+    fn cycle(&mut self, memory: &mut Memory, io: &mut IoBus) -> usize {
+        let (from, to) = if self.a_to_b {
+            (PORT_A, PORT_B)
+        } else {
+            (PORT_B, PORT_A)
+        };
+        let byte = self.ports[from].read(memory, io);
+        let mut cycles = self.ports[from].cycles;
+        if self.transfer {
+            self.ports[to].write(byte, memory, io);
+            cycles += self.ports[to].cycles;
+        }
+        for port in &mut self.ports {
+            port.advance();
+        }
+        self.counter = self.counter.wrapping_add(1);
+        self.transferred = true;
+        if self.search && byte | self.mask == self.match_byte | self.mask {
+            self.matched = true;
+            self.interrupt(INT_MATCH);
+            if self.stop_on_match {
+                self.enabled = false;
+            }
+        }
+        if self.counter == self.length.wrapping_add(1) {
+            self.end_of_block = true;
+            self.interrupt(INT_END_OF_BLOCK);
+            if self.auto_restart {
+                self.load();
+            } else {
+                self.enabled = false;
+            }
+        }
+        cycles
+    }
+}
+
+impl Device for Dma {
+    fn io_read(&mut self, _port: u16) -> u8 {
+        // The read sequence wraps around to the first register picked by the mask
+        if self.reads.is_empty() {
+            self.start_reads();
+        }
+        self.reads.pop_front().unwrap_or(0xFF)
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        match self.params.pop_front() {
+            Some(param) => self.write_param(param, value),
+            None => self.write_base(value),
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        if !self.pending || self.in_service {
+            return None;
+        }
+        if self.int_control & STATUS_AFFECTS_VECTOR == 0 {
+            return Some(self.vector);
+        }
+        // Bits 1-2: 01 match, 10 end of block, 11 both
+        let status = (self.cause & (INT_MATCH | INT_END_OF_BLOCK)) << 1;
+        Some(self.vector & 0xF9 | status)
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        if self.pending {
+            self.pending = false;
+            self.cause = 0;
+            self.in_service = true;
+        }
+    }
+
+    fn reti(&mut self) {
+        self.in_service = false;
+    }
+
+    fn interrupt_in_service(&self) -> bool {
+        self.in_service
+    }
+
+    fn bus_request(&self) -> bool {
+        self.enabled && self.ready
+    }
+
+    fn bus_master(&mut self, memory: &mut Memory, io: &mut IoBus) -> usize {
+        let mut cycles = 0;
+        loop {
+            cycles += self.cycle(memory, io);
+            // Continuous and burst mode keep the bus until the block ends or RDY drops. The
+            // counter is back at 0 after an auto restart.
+            if self.mode == DmaMode::Byte || self.counter == 0 || !self.bus_request() {
+                return cycles;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dma, DmaMode, PORT_A, PORT_B};
+    use crate::device::{Device, IoBus};
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::memory::Memory;
+
+    fn program(dma: &mut Dma, bytes: &[u8]) {
+        for &byte in bytes {
+            dma.io_write(0, byte);
+        }
+    }
+
+    #[test]
+    fn search() {
+        let mut dma = Dma::default();
+        let mut memory = Memory::default();
+        let mut io = IoBus::default();
+        memory.load_slice(0x4000, b"HELLO, WORLD");
+        program(
+            &mut dma,
+            &[
+                // WR0: search port A from 0x4000, length 11
+                0x7E, 0x00, 0x40, 0x0B, 0x00, // WR1: port A memory, incrementing
+                0x14, // WR3: stop on match, match ',' ignoring nothing, interrupts on
+                0xBC, 0x00, b',',
+                // WR4: burst, interrupt on match, vector 0x60 with status affects vector
+                0xD1, 0x31, 0x60, // LOAD, ENABLE
+                0xCF, 0x87,
+            ],
+        );
+        assert_eq!(dma.mode, DmaMode::Burst);
+        assert!(dma.bus_request());
+        dma.set_ready(false);
+        assert!(!dma.bus_request());
+        dma.set_ready(true);
+        // Six 3 T state reads, only the source is accessed when searching
+        assert_eq!(dma.bus_master(&mut memory, &mut io), 18);
+        assert!(dma.matched);
+        assert!(!dma.bus_request());
+        assert_eq!(dma.ports[PORT_A].address, 0x4006);
+        assert_eq!(dma.pending_interrupt(), Some(0x62));
+
+        // Status, counter and port A address with a read mask of 0x19
+        program(&mut dma, &[0xBB, 0x19, 0xA7]);
+        let reads: Vec<u8> = (0..4).map(|_| dma.io_read(0)).collect();
+        assert_eq!(reads, [0x27, 0x06, 0x40, 0x27]);
+    }
+
+    #[test]
+    fn block_copy() {
+        // 0100: LD HL, 0200; LD B, 0E; loop: LD A, (HL); OUT (10), A; INC HL; DEC B;
+        // JP NZ, loop; JP 010D. Sets the DMA on port 0x10 up to copy 16 bytes from 1000 to
+        // 2000 in byte mode.
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        let code = [
+            0x21, 0x00, 0x02, 0x06, 0x0E, 0x7E, 0xD3, 0x10, 0x23, 0x05, 0xC2, 0x05, 0x01, 0xC3,
+            0x0D, 0x01,
+        ];
+        i.cpu.memory.load_slice(0x0100, &code);
+        let setup = [
+            0x83, 0x7D, 0x00, 0x10, 0x0F, 0x00, 0x14, 0x10, 0x8D, 0x00, 0x20, 0x82, 0xCF, 0x87,
+        ];
+        i.cpu.memory.load_slice(0x0200, &setup);
+        i.cpu.memory.load_slice(0x1000, b"0123456789ABCDEF");
+        let dma = i.add_device(Dma::default());
+        i.register_port(0x10..=0x10, dma.clone());
+        // Byte mode, the CPU runs an instruction between bytes
+        let mut steps = 0;
+        while !dma.borrow().end_of_block {
+            i.step();
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert_eq!(i.cpu.reg.pc, 0x010D);
+        assert_eq!(dma.borrow().ports[PORT_B].address, 0x2010);
+        let copied: Vec<u8> = (0..16).map(|n| i.cpu.memory.peek(0x2000 + n)).collect();
+        assert_eq!(copied, b"0123456789ABCDEF");
+        assert_eq!(i.cpu.memory.peek(0x2010), 0);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io;
+
+use crate::cpm::{Disk, RECORD};
+use crate::device::{Device, IoBus};
+use crate::memory::Memory;
+
+// Registers, from the controller's first port
+pub const DRIVE: u16 = 0;
+pub const TRACK: u16 = 1;
+pub const SECTOR: u16 = 2;
+pub const COMMAND: u16 = 3;
+pub const STATUS: u16 = 4;
+pub const DMA_LOW: u16 = 5;
+pub const DMA_HIGH: u16 = 6;
+pub const TRACK_HIGH: u16 = 7;
+
+pub const READ: u8 = 0x00;
+pub const WRITE: u8 = 0x01;
+
+// Status after a command
+pub const OK: u8 = 0x00;
+pub const NO_DISK: u8 = 0x01;
+pub const NO_RECORD: u8 = 0x02;
+pub const WRITE_FAULT: u8 = 0x03;
+pub const BAD_COMMAND: u8 = 0x04;
+pub const BUSY: u8 = 0x80;
+
+// T states the controller holds the bus for each byte
+const BYTE_CYCLES: usize = 4;
+
+// A simple DMA floppy controller for CP/M disk images, in the style of the controllers
+// z80pack and SIMH emulate rather than a real FDC: no seeks, index pulses or sector IDs.
+// Set the drive, track and sector (from 1) and the DMA address, then a command moves the
+// 128 byte record between the disk and memory. The controller takes the bus for it as
+// soon as the CPU finishes the OUT, so the status has the result by the next IN.
+#[derive(Default)]
+pub struct Fdc {
+    pub disks: Vec<Option<Disk>>,
+    pub drive: u8,
+    pub track: u16,
+    pub sector: u8,
+    pub dma: u16,
+    pub status: u8,
+    command: Option<u8>,
+}
+
+impl Fdc {
+    // Puts `disk` in drive 0 to 15
+    pub fn mount(&mut self, drive: usize, disk: Disk) {
+        if self.disks.len() <= drive {
+            self.disks.resize_with(drive + 1, || None);
+        }
+        self.disks[drive] = Some(disk);
+    }
+
+    // Runs `command`, returns the status
+    fn transfer(&mut self, memory: &mut Memory, command: u8) -> u8 {
+        let (track, dma) = (self.track, self.dma);
+        let disk = match self.disks.get_mut(self.drive as usize) {
+            Some(Some(disk)) => disk,
+            _ => return NO_DISK,
+        };
+        let record = match self.sector {
+            0 => return NO_RECORD,
+            sector => sector as u16 - 1,
+        };
+        match command {
+            READ => match disk.read(track, record) {
+                Some(data) => {
+                    for (n, &byte) in data.iter().enumerate() {
+                        memory.write_mapped(dma.wrapping_add(n as u16), byte);
+                    }
+                    OK
+                }
+                None => NO_RECORD,
+            },
+            _ => {
+                let data: Vec<u8> = (0..RECORD as u16)
+                    .map(|n| memory.read_mapped(dma.wrapping_add(n)))
+                    .collect();
+                match disk.write(track, record, &data) {
+                    Ok(()) => OK,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => NO_RECORD,
+                    Err(_) => WRITE_FAULT,
+                }
+            }
+        }
+    }
+}
+
+impl Device for Fdc {
+    fn io_read(&mut self, port: u16) -> u8 {
+        match port & 7 {
+            DRIVE => self.drive,
+            TRACK => self.track as u8,
+            SECTOR => self.sector,
+            STATUS => self.status,
+            DMA_LOW => self.dma as u8,
+            DMA_HIGH => (self.dma >> 8) as u8,
+            TRACK_HIGH => (self.track >> 8) as u8,
+            _ => 0xFF,
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        match port & 7 {
+            DRIVE => self.drive = value,
+            TRACK => self.track = self.track & 0xFF00 | value as u16,
+            SECTOR => self.sector = value,
+            COMMAND => match value {
+                READ | WRITE => {
+                    self.command = Some(value);
+                    self.status = BUSY;
+                }
+                _ => self.status = BAD_COMMAND,
+            },
+            DMA_LOW => self.dma = self.dma & 0xFF00 | value as u16,
+            DMA_HIGH => self.dma = self.dma & 0x00FF | (value as u16) << 8,
+            TRACK_HIGH => self.track = self.track & 0x00FF | (value as u16) << 8,
+            _ => {}
+        }
+    }
+
+    fn bus_request(&self) -> bool {
+        self.command.is_some()
+    }
+
+    fn bus_master(&mut self, memory: &mut Memory, _io: &mut IoBus) -> usize {
+        match self.command.take() {
+            Some(command) => {
+                self.status = self.transfer(memory, command);
+                RECORD * BYTE_CYCLES
+            }
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fdc, BAD_COMMAND, BUSY, NO_DISK, NO_RECORD, OK};
+    use crate::cpm::{Disk, Dpb, RECORD};
+    use crate::device::{Device, IoBus};
+    use crate::memory::Memory;
+
+    #[test]
+    fn transfers() {
+        let dpb = Dpb::parse("spt=4,off=1,dsm=7,drm=15,cks=4,skew=").unwrap();
+        let mut image = vec![0xE5; 8 * RECORD];
+        image[5 * RECORD] = 0x12;
+        let mut fdc = Fdc::default();
+        fdc.mount(1, Disk::new(image, dpb));
+        let mut memory = Memory::default();
+        let mut io = IoBus::default();
+        // Track 1 sector 2 of B: to 8000
+        for (port, value) in [(0, 1), (1, 1), (2, 2), (5, 0x00), (6, 0x80), (3, 0)] {
+            fdc.io_write(port, value);
+        }
+        assert!(fdc.bus_request());
+        assert_eq!(fdc.io_read(4), BUSY);
+        assert_eq!(fdc.bus_master(&mut memory, &mut io), 512);
+        assert!(!fdc.bus_request());
+        assert_eq!(fdc.io_read(4), OK);
+        assert_eq!(memory[0x8000], 0x12);
+        assert_eq!(memory[0x8001], 0xE5);
+
+        // Written back to sector 1 of track 0
+        memory[0x8001] = 0x34;
+        for (port, value) in [(1, 0), (2, 1), (3, 1)] {
+            fdc.io_write(port, value);
+        }
+        fdc.bus_master(&mut memory, &mut io);
+        assert_eq!(fdc.io_read(4), OK);
+        let disk = fdc.disks[1].as_ref().unwrap();
+        assert_eq!(disk.data[..2], [0x12, 0x34]);
+
+        for (port, value, status) in [(2, 0, NO_RECORD), (2, 5, NO_RECORD), (0, 0, NO_DISK)] {
+            fdc.io_write(port, value);
+            fdc.io_write(3, 0);
+            fdc.bus_master(&mut memory, &mut io);
+            assert_eq!(fdc.io_read(4), status);
+        }
+        fdc.io_write(3, 7);
+        assert_eq!(fdc.io_read(4), BAD_COMMAND);
+        assert!(!fdc.bus_request());
+    }
+}
@@ -0,0 +1,49 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Down,
+    Up,
+    Fire,
+    Fire2,
+    // Pause or start, on the console rather than the pad on Sega's machines
+    Start,
+}
+
+impl Button {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// State of a host joystick or gamepad as the machine should see it. Clones share the
+// state, so the frontend keeps one to press and release buttons while the interface device
+// reading it owns another.
+#[derive(Debug, Default, Clone)]
+pub struct Joystick {
+    // Pressed buttons, bit n being the button with discriminant n
+    pub buttons: Rc<Cell<u8>>,
+}
+
+impl Joystick {
+    pub fn set(&self, button: Button, pressed: bool) {
+        let buttons = self.buttons.get() & !button.bit();
+        self.buttons
+            .set(buttons | if pressed { button.bit() } else { 0 });
+    }
+
+    pub fn press(&self, button: Button) {
+        self.set(button, true);
+    }
+
+    pub fn release(&self, button: Button) {
+        self.set(button, false);
+    }
+
+    pub fn pressed(&self, button: Button) -> bool {
+        self.buttons.get() & button.bit() != 0
+    }
+}
@@ -0,0 +1,59 @@
+use crate::device::Device;
+use crate::peripherals::joystick::{Button, Joystick};
+
+// Kempston joystick interface, read on port 1F. Most interfaces only decode A5 low, so
+// it's attached to any port with A5-A7 low to stay off the ULA and the 128K's ports.
+//
+// Bits 0-4 are right, left, down, up and fire, set while pressed, the rest read as 0.
+#[derive(Default)]
+pub struct Kempston {
+    pub joystick: Joystick,
+}
+
+impl Kempston {
+    pub fn new(joystick: Joystick) -> Self {
+        Self { joystick }
+    }
+
+    pub fn state(&self) -> u8 {
+        [
+            Button::Right,
+            Button::Left,
+            Button::Down,
+            Button::Up,
+            Button::Fire,
+        ]
+        .iter()
+        .enumerate()
+        .filter(|(_, &button)| self.joystick.pressed(button))
+        .fold(0, |state, (bit, _)| state | 1 << bit)
+    }
+}
+
+impl Device for Kempston {
+    fn io_read(&mut self, _port: u16) -> u8 {
+        self.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Kempston;
+    use crate::device::Device;
+    use crate::peripherals::joystick::{Button, Joystick};
+
+    #[test]
+    fn buttons() {
+        let joystick = Joystick::default();
+        let mut kempston = Kempston::new(joystick.clone());
+        assert_eq!(kempston.io_read(0x1F), 0x00);
+        joystick.press(Button::Up);
+        joystick.press(Button::Fire);
+        joystick.press(Button::Left);
+        assert_eq!(kempston.io_read(0x1F), 0x1A);
+        joystick.release(Button::Left);
+        // No second fire button on a Kempston
+        joystick.press(Button::Fire2);
+        assert_eq!(kempston.io_read(0x1F), 0x18);
+    }
+}
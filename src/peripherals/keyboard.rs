@@ -0,0 +1,41 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const ROWS: usize = 16;
+
+// Keys held down on the host, as a matrix of up to 16 rows of 8 columns the way most
+// machines scan their keyboards. Clones share the state like `Joystick`, the frontend
+// presses keys while the machine's keyboard interface reads the rows. Each machine has its
+// own layout of keys to rows and columns.
+#[derive(Debug, Default, Clone)]
+pub struct KeyMatrix {
+    // Bit n of a row is set while the key in column n is down
+    pub rows: Rc<RefCell<[u8; ROWS]>>,
+}
+
+impl KeyMatrix {
+    pub fn set(&self, row: usize, column: usize, pressed: bool) {
+        let mut rows = self.rows.borrow_mut();
+        rows[row] &= !(1 << column);
+        if pressed {
+            rows[row] |= 1 << column;
+        }
+    }
+
+    pub fn press(&self, row: usize, column: usize) {
+        self.set(row, column, true);
+    }
+
+    pub fn release(&self, row: usize, column: usize) {
+        self.set(row, column, false);
+    }
+
+    pub fn release_all(&self) {
+        *self.rows.borrow_mut() = [0; ROWS];
+    }
+
+    // Keys down in `row`
+    pub fn row(&self, row: usize) -> u8 {
+        self.rows.borrow()[row]
+    }
+}
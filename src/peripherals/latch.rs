@@ -0,0 +1,26 @@
+use crate::device::Device;
+
+// A write latch that reads back the last value written, through either a port or a memory
+// mapped address (e.g. Pac-Man's interrupt enable at 0x5000)
+#[derive(Default)]
+pub struct Latch {
+    pub value: u8,
+}
+
+impl Device for Latch {
+    fn io_read(&mut self, _port: u16) -> u8 {
+        self.value
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        self.value = value;
+    }
+
+    fn mem_read(&mut self, _addr: u16) -> u8 {
+        self.value
+    }
+
+    fn mem_write(&mut self, _addr: u16, value: u8) {
+        self.value = value;
+    }
+}
@@ -0,0 +1,38 @@
+pub mod acia;
+pub mod ay;
+pub mod console;
+pub mod crtc;
+pub mod ctc;
+pub mod dma;
+pub mod fdc;
+pub mod joystick;
+pub mod kempston;
+pub mod keyboard;
+pub mod latch;
+pub mod pio;
+pub mod ppi;
+pub mod psg;
+pub mod serial;
+pub mod sio;
+pub mod timer;
+pub mod ula;
+pub mod vdp;
+
+pub use self::acia::Acia;
+pub use self::ay::Ay;
+pub use self::console::Console;
+pub use self::crtc::Crtc;
+pub use self::ctc::Ctc;
+pub use self::dma::Dma;
+pub use self::fdc::Fdc;
+pub use self::joystick::Joystick;
+pub use self::kempston::Kempston;
+pub use self::keyboard::KeyMatrix;
+pub use self::latch::Latch;
+pub use self::pio::Pio;
+pub use self::ppi::Ppi;
+pub use self::psg::Psg;
+pub use self::sio::Sio;
+pub use self::timer::IntervalTimer;
+pub use self::ula::Ula;
+pub use self::vdp::Vdp;
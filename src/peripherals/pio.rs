@@ -0,0 +1,326 @@
+use crate::device::Device;
+
+pub const PORT_A: usize = 0;
+pub const PORT_B: usize = 1;
+
+// Called with the port's output lines whenever the CPU writes them
+pub type LinesOut = Box<dyn FnMut(u8)>;
+// Samples the port's input lines when the CPU reads them in bit control mode
+pub type LinesIn = Box<dyn FnMut() -> u8>;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum PioMode {
+    #[default]
+    Output,
+    Input,
+    // Port A only, port B's handshake lines are used for the input direction
+    Bidirectional,
+    // Each line an input or output as set by `io_mask`
+    Control,
+}
+
+// The control word that comes next, if it isn't a command
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+enum Expect {
+    #[default]
+    Command,
+    IoMask,
+    IntMask,
+}
+
+#[derive(Default)]
+pub struct PioPort {
+    pub mode: PioMode,
+    pub output: u8,
+    // Latched by /STB in input and bidirectional mode
+    pub input: u8,
+    // Levels on the lines driven from outside, see `Pio::set_lines`
+    pub lines: u8,
+    // Bit control mode directions, 1 is an input
+    pub io_mask: u8,
+    pub vector: u8,
+    pub int_enable: bool,
+    // Bit control mode interrupts: all monitored lines active rather than any, and the
+    // active level. Lines with a 0 in `int_mask` are monitored.
+    pub int_and: bool,
+    pub int_high: bool,
+    pub int_mask: u8,
+    // RDY handshake output
+    pub ready: bool,
+    pub pending: bool,
+    pub in_service: bool,
+    expect: Expect,
+    // Last bit control condition, interrupts trigger when it becomes true
+    condition: bool,
+    lines_out: Option<LinesOut>,
+    lines_in: Option<LinesIn>,
+}
+
+impl PioPort {
+    fn interrupt(&mut self) {
+        if self.int_enable {
+            self.pending = true;
+        }
+    }
+
+    fn sample(&mut self) -> u8 {
+        if let Some(lines_in) = &mut self.lines_in {
+            self.lines = lines_in();
+        }
+        self.lines
+    }
+
+    // Bit control mode: monitored input lines at the active level
+    fn check_condition(&mut self) {
+        let monitored = !self.int_mask & self.io_mask;
+        let active = if self.int_high {
+            self.lines
+        } else {
+            !self.lines
+        } & monitored;
+        let condition = self.mode == PioMode::Control
+            && monitored != 0
+            && if self.int_and {
+                active == monitored
+            } else {
+                active != 0
+            };
+        if condition && !self.condition {
+            self.interrupt();
+        }
+        self.condition = condition;
+    }
+
+    fn write_control(&mut self, value: u8) {
+        match std::mem::take(&mut self.expect) {
+            Expect::IoMask => {
+                self.io_mask = value;
+                self.check_condition();
+                return;
+            }
+            Expect::IntMask => {
+                self.int_mask = value;
+                self.check_condition();
+                return;
+            }
+            Expect::Command => {}
+        }
+        if value & 0x01 == 0 {
+            self.vector = value;
+        } else if value & 0x0F == 0x0F {
+            self.mode = match value >> 6 {
+                0 => PioMode::Output,
+                1 => PioMode::Input,
+                2 => PioMode::Bidirectional,
+                _ => PioMode::Control,
+            };
+            self.ready = self.mode == PioMode::Input;
+            if self.mode == PioMode::Control {
+                self.expect = Expect::IoMask;
+            }
+        } else if value & 0x0F == 0x07 {
+            self.int_enable = value & 0x80 != 0;
+            self.int_and = value & 0x40 != 0;
+            self.int_high = value & 0x20 != 0;
+            if value & 0x10 != 0 {
+                self.expect = Expect::IntMask;
+                self.pending = false;
+            }
+        } else if value & 0x0F == 0x03 {
+            self.int_enable = value & 0x80 != 0;
+        }
+    }
+}
+
+// Z80 PIO, two 8-bit parallel ports with handshaking. Port addresses follow the usual
+// wiring of B/A on A0 and C/D on A1: A data, B data, A control, B control.
+//
+// The peripheral side is driven through `set_lines` and `strobe` and watched through
+// `ready` or the `on_output` callback. Output mode interrupts when the peripheral strobes
+// to take the data, input mode when it strobes data in, bit control mode when the
+// monitored lines meet the condition. Port A has the higher interrupt priority.
+#[derive(Default)]
+pub struct Pio {
+    pub ports: [PioPort; 2],
+}
+
+impl Pio {
+    // Called with the new output whenever the CPU writes the port's data register
+    pub fn on_output<F: FnMut(u8) + 'static>(&mut self, port: usize, f: F) {
+        self.ports[port].lines_out = Some(Box::new(f));
+    }
+
+    // Supplies the input lines when the CPU reads the port in bit control mode, instead of
+    // the levels given to `set_lines`
+    pub fn on_input<F: FnMut() -> u8 + 'static>(&mut self, port: usize, f: F) {
+        self.ports[port].lines_in = Some(Box::new(f));
+    }
+
+    pub fn set_lines(&mut self, port: usize, value: u8) {
+        let p = &mut self.ports[port];
+        p.lines = value;
+        p.check_condition();
+    }
+
+    // A pulse on the port's /STB input. In input mode the lines are latched, in output
+    // mode the peripheral has taken the data. In bidirectional mode port A's strobe is
+    // for output and port B's latches port A's input.
+    pub fn strobe(&mut self, port: usize) {
+        let bidirectional = self.ports[PORT_A].mode == PioMode::Bidirectional;
+        let target = if bidirectional { PORT_A } else { port };
+        let p = &mut self.ports[target];
+        let input = match p.mode {
+            PioMode::Input => true,
+            PioMode::Bidirectional => port == PORT_B,
+            _ => false,
+        };
+        if p.mode == PioMode::Control {
+            return;
+        }
+        if input {
+            p.input = p.lines;
+        }
+        p.ready = false;
+        // Port B's interrupt stands for input in bidirectional mode
+        self.ports[port].interrupt();
+    }
+
+    fn requesting(&self) -> Option<usize> {
+        for (n, port) in self.ports.iter().enumerate() {
+            if port.in_service {
+                return None;
+            }
+            if port.pending {
+                return Some(n);
+            }
+        }
+        None
+    }
+}
+
+impl Device for Pio {
+    fn io_read(&mut self, port: u16) -> u8 {
+        let n = port as usize & 1;
+        if port & 2 != 0 {
+            // Control registers can't be read, the bus floats
+            return 0xFF;
+        }
+        let p = &mut self.ports[n];
+        match p.mode {
+            PioMode::Output => p.output,
+            PioMode::Input | PioMode::Bidirectional => {
+                p.ready = true;
+                p.input
+            }
+            PioMode::Control => {
+                let lines = p.sample();
+                p.check_condition();
+                lines & p.io_mask | p.output & !p.io_mask
+            }
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        let p = &mut self.ports[port as usize & 1];
+        if port & 2 != 0 {
+            p.write_control(value);
+            return;
+        }
+        p.output = value;
+        if p.mode != PioMode::Input {
+            p.ready = p.mode != PioMode::Control;
+            let lines = match p.mode {
+                PioMode::Control => value & !p.io_mask,
+                _ => value,
+            };
+            if let Some(lines_out) = &mut p.lines_out {
+                lines_out(lines);
+            }
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.requesting().map(|n| self.ports[n].vector)
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        if let Some(n) = self.requesting() {
+            self.ports[n].pending = false;
+            self.ports[n].in_service = true;
+        }
+    }
+
+    fn reti(&mut self) {
+        if let Some(port) = self.ports.iter_mut().find(|port| port.in_service) {
+            port.in_service = false;
+        }
+    }
+
+    fn interrupt_in_service(&self) -> bool {
+        self.ports.iter().any(|port| port.in_service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pio, PioMode, PORT_A, PORT_B};
+    use crate::device::Device;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn handshake_modes() {
+        let mut pio = Pio::default();
+        let printed = Rc::new(Cell::new(0));
+        let printer = printed.clone();
+        pio.on_output(PORT_A, move |value| printer.set(value));
+        // Port A: vector 0x20, output mode, interrupts enabled
+        for value in [0x20, 0x0F, 0x83] {
+            pio.io_write(2, value);
+        }
+        pio.io_write(0, b'Z');
+        assert_eq!(printed.get(), b'Z');
+        assert!(pio.ports[PORT_A].ready);
+        assert_eq!(pio.pending_interrupt(), None);
+        pio.strobe(PORT_A);
+        assert!(!pio.ports[PORT_A].ready);
+        assert_eq!(pio.pending_interrupt(), Some(0x20));
+
+        // Port B: vector 0x22, input mode, interrupts enabled
+        for value in [0x22, 0x4F, 0x83] {
+            pio.io_write(3, value);
+        }
+        assert_eq!(pio.ports[PORT_B].mode, PioMode::Input);
+        pio.set_lines(PORT_B, 0x41);
+        pio.strobe(PORT_B);
+        pio.set_lines(PORT_B, 0x00);
+        assert_eq!(pio.io_read(1), 0x41);
+        assert!(pio.ports[PORT_B].ready);
+
+        // A is ahead of B in the chain
+        pio.interrupt_acknowledged();
+        assert_eq!(pio.pending_interrupt(), None);
+        pio.reti();
+        assert_eq!(pio.pending_interrupt(), Some(0x22));
+    }
+
+    #[test]
+    fn bit_control() {
+        let mut pio = Pio::default();
+        // Port B: bit control, bits 0-3 inputs, interrupt when bits 0 and 1 are both low
+        for value in [0x30, 0xCF, 0x0F, 0xD7, 0xFC] {
+            pio.io_write(3, value);
+        }
+        pio.io_write(1, 0xA5);
+        pio.set_lines(PORT_B, 0x0F);
+        assert_eq!(pio.io_read(1), 0xAF);
+        pio.set_lines(PORT_B, 0x0E);
+        assert_eq!(pio.pending_interrupt(), None);
+        pio.set_lines(PORT_B, 0x0C);
+        assert_eq!(pio.pending_interrupt(), Some(0x30));
+
+        // Lines sampled by the host when read
+        pio.on_input(PORT_B, || 0x03);
+        assert_eq!(pio.io_read(1), 0xA3);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::device::Device;
+
+pub const PORT_A: usize = 0;
+pub const PORT_B: usize = 1;
+pub const PORT_C: usize = 2;
+
+// Called with the port's output lines whenever the CPU writes them
+pub type LinesOut = Box<dyn FnMut(u8)>;
+// Samples the port's input lines when the CPU reads them
+pub type LinesIn = Box<dyn FnMut() -> u8>;
+
+// Intel 8255 programmable peripheral interface in mode 0 (plain inputs and outputs), as in
+// the MSX and the Amstrad CPC. Ports A, B and C then the control register are at
+// consecutive addresses, the two low bits of the port pick one unless `address_shift` moves
+// them higher (A8 and A9 on the CPC).
+//
+// The control register either sets the directions (bit 7 set: A, upper C, B and lower C
+// are inputs with bits 4, 3, 1 and 0) or sets / resets one bit of port C. Lines of input
+// ports come from `on_input`, or read high. Outputs go to `on_output`, with the lines of
+// any input half of port C high.
+pub struct Ppi {
+    pub outputs: [u8; 3],
+    // Lines that are inputs, per port
+    pub inputs: [u8; 3],
+    pub address_shift: u32,
+    lines_out: [Option<LinesOut>; 3],
+    lines_in: [Option<LinesIn>; 3],
+}
+
+impl Default for Ppi {
+    // Everything's an input after a reset
+    fn default() -> Self {
+        Self {
+            outputs: [0; 3],
+            inputs: [0xFF; 3],
+            address_shift: 0,
+            lines_out: Default::default(),
+            lines_in: Default::default(),
+        }
+    }
+}
+
+impl Ppi {
+    pub fn on_output<F: FnMut(u8) + 'static>(&mut self, port: usize, f: F) {
+        self.lines_out[port] = Some(Box::new(f));
+    }
+
+    pub fn on_input<F: FnMut() -> u8 + 'static>(&mut self, port: usize, f: F) {
+        self.lines_in[port] = Some(Box::new(f));
+    }
+
+    pub fn read(&mut self, port: usize) -> u8 {
+        let lines = match &mut self.lines_in[port] {
+            Some(lines_in) => lines_in(),
+            None => 0xFF,
+        };
+        self.outputs[port] & !self.inputs[port] | lines & self.inputs[port]
+    }
+
+    pub fn write(&mut self, port: usize, value: u8) {
+        self.outputs[port] = value;
+        self.output(port);
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        if value & 0x80 == 0 {
+            let bit = 1 << (value >> 1 & 7);
+            let set = if value & 1 != 0 { bit } else { 0 };
+            self.outputs[PORT_C] = self.outputs[PORT_C] & !bit | set;
+            self.output(PORT_C);
+            return;
+        }
+        let input = |bit: u8, lines: u8| if value & bit != 0 { lines } else { 0 };
+        self.inputs = [
+            input(0x10, 0xFF),
+            input(0x02, 0xFF),
+            input(0x08, 0xF0) | input(0x01, 0x0F),
+        ];
+        // Setting the mode clears the outputs
+        self.outputs = [0; 3];
+        for port in [PORT_A, PORT_B, PORT_C] {
+            self.output(port);
+        }
+    }
+
+    fn output(&mut self, port: usize) {
+        if self.inputs[port] == 0xFF {
+            return;
+        }
+        let lines = self.outputs[port] | self.inputs[port];
+        if let Some(lines_out) = &mut self.lines_out[port] {
+            lines_out(lines);
+        }
+    }
+}
+
+impl Device for Ppi {
+    fn io_read(&mut self, port: u16) -> u8 {
+        match (port >> self.address_shift) as usize & 3 {
+            3 => 0xFF,
+            port => self.read(port),
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        match (port >> self.address_shift) as usize & 3 {
+            3 => self.write_control(value),
+            port => self.write(port, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::{Ppi, PORT_C};
+    use crate::device::Device;
+
+    #[test]
+    fn ports() {
+        let mut ppi = Ppi::default();
+        let lines = Rc::new(Cell::new(0));
+        let out = lines.clone();
+        ppi.on_output(PORT_C, move |value| out.set(value));
+        ppi.on_input(PORT_C, || 0x05);
+        // A out, B in, upper C out, lower C in
+        ppi.io_write(0xAB, 0x83);
+        ppi.io_write(0xA8, 0x12);
+        assert_eq!(ppi.io_read(0xA8), 0x12);
+        assert_eq!(ppi.io_read(0xA9), 0xFF);
+        ppi.io_write(0xAA, 0xA0);
+        assert_eq!(lines.get(), 0xAF);
+        assert_eq!(ppi.io_read(0xAA), 0xA5);
+        // Bit 6 of C set, then bit 5 reset
+        ppi.io_write(0xAB, 0x0D);
+        ppi.io_write(0xAB, 0x0A);
+        assert_eq!(ppi.outputs[PORT_C], 0xC0);
+        assert_eq!(lines.get(), 0xCF);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::device::Device;
+
+const NOISE: usize = 3;
+
+// Texas Instruments SN76489 PSG (and the copy in Sega's VDPs): three square wave tone
+// channels and a noise channel, each with a 4-bit attenuation, mixed down to a mono audio
+// buffer. Write only, every port it's decoded at takes the same latch / data bytes.
+//
+// The Game Gear's has a stereo register (port 06) turning each channel on or off per side.
+// With `stereo_output` the samples are left / right pairs, otherwise both sides averaged.
+//
+// Like the AY, the generators run at the chip clock / 16 off the T states the CPU spends
+// and each sample is the average output over its time. Samples go from 0.0 to 1.0 and
+// pile up in `samples` until taken.
+pub struct Psg {
+    // 10-bit periods of the tone channels
+    pub tones: [u16; 3],
+    // Bit 2 white noise (else periodic), bits 0-1 the rate
+    pub noise: u8,
+    // Attenuation in 2dB steps, 15 is off. The noise channel's is last.
+    pub volumes: [u8; 4],
+    // Shift register length and the bits fed back for white noise: 15 and bits 0 and 1 on
+    // the SN76489, 16 and bits 0 and 3 on Sega's
+    pub lfsr_bits: u32,
+    pub feedback: u16,
+    // Channels 0-3 on the right in bits 0-3, on the left in bits 4-7
+    pub stereo: u8,
+    pub stereo_output: bool,
+    pub clock: u64,
+    pub cpu_clock: u64,
+    pub sample_rate: u64,
+    pub samples: Vec<f32>,
+    // Register the last latch byte picked: channel, and volume rather than tone / noise
+    latched: (usize, bool),
+    counters: [u16; 4],
+    outputs: [bool; 4],
+    lfsr: u16,
+    // Chip clocks * CPU clock towards the next generator step
+    clock_phase: u64,
+    // Generator steps * sample rate towards the next sample
+    sample_phase: u64,
+    sample_sum: (f32, f32),
+    sample_count: u32,
+}
+
+impl Default for Psg {
+    // Clocked along with a 3.58MHz CPU
+    fn default() -> Self {
+        Self::new(3_579_545, 3_579_545, 44_100)
+    }
+}
+
+impl Psg {
+    pub fn new(clock: u64, cpu_clock: u64, sample_rate: u64) -> Self {
+        assert!(clock > 0 && cpu_clock > 0 && sample_rate > 0);
+        Self {
+            tones: [0; 3],
+            noise: 0,
+            volumes: [15; 4],
+            lfsr_bits: 15,
+            feedback: 0x0003,
+            stereo: 0xFF,
+            stereo_output: false,
+            clock,
+            cpu_clock,
+            sample_rate,
+            samples: Vec::new(),
+            latched: (0, false),
+            counters: [0; 4],
+            outputs: [false; 4],
+            lfsr: 1 << 14,
+            clock_phase: 0,
+            sample_phase: 0,
+            sample_sum: (0.0, 0.0),
+            sample_count: 0,
+        }
+    }
+
+    // Bit 7 set latches a register (channel in bits 5-6, volume if bit 4) and writes the
+    // low 4 bits. Otherwise the byte goes to the latched register: the upper 6 bits of a
+    // tone period, or all of a volume or noise setting.
+    pub fn write(&mut self, value: u8) {
+        if value & 0x80 != 0 {
+            self.latched = ((value >> 5 & 3) as usize, value & 0x10 != 0);
+        }
+        let latch = value & 0x80 != 0;
+        match self.latched {
+            (channel, true) => self.volumes[channel] = value & 0x0F,
+            (NOISE, false) => {
+                self.noise = value & 0x07;
+                self.lfsr = 1 << (self.lfsr_bits - 1);
+            }
+            (channel, false) if latch => {
+                self.tones[channel] = self.tones[channel] & 0x3F0 | (value & 0x0F) as u16;
+            }
+            (channel, false) => {
+                self.tones[channel] = self.tones[channel] & 0x0F | (value as u16 & 0x3F) << 4;
+            }
+        }
+    }
+
+    // Everything synthesized so far, clearing the buffer
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn noise_period(&self) -> u16 {
+        match self.noise & 3 {
+            3 => self.tones[2],
+            rate => 0x10 << rate,
+        }
+    }
+
+    // The generators one clock / 16 on, returns the left and right outputs
+    fn step(&mut self) -> (f32, f32) {
+        for channel in 0..4 {
+            let period = match channel {
+                NOISE => self.noise_period(),
+                _ => self.tones[channel],
+            };
+            self.counters[channel] = self.counters[channel].saturating_sub(1);
+            if self.counters[channel] > 0 {
+                continue;
+            }
+            self.counters[channel] = period.max(1);
+            self.outputs[channel] = !self.outputs[channel];
+            // The shift register moves on the noise divider's rising edges
+            if channel == NOISE && self.outputs[NOISE] {
+                let bit = match self.noise & 0x04 {
+                    0 => self.lfsr & 1,
+                    _ => (self.lfsr & self.feedback).count_ones() as u16 & 1,
+                };
+                self.lfsr = self.lfsr >> 1 | bit << (self.lfsr_bits - 1);
+            }
+        }
+        let (mut left, mut right) = (0.0, 0.0);
+        for channel in 0..4 {
+            let high = match channel {
+                NOISE => self.lfsr & 1 != 0,
+                // Periods of 0 and 1 hold the output high, used for sampled sound
+                _ => self.outputs[channel] || self.tones[channel] <= 1,
+            };
+            if high && self.volumes[channel] < 15 {
+                let output = 10f32.powf(-(self.volumes[channel] as f32) * 2.0 / 20.0);
+                if self.stereo & 0x10 << channel != 0 {
+                    left += output;
+                }
+                if self.stereo & 1 << channel != 0 {
+                    right += output;
+                }
+            }
+        }
+        (left / 4.0, right / 4.0)
+    }
+
+    fn push_sample(&mut self) {
+        let count = self.sample_count as f32;
+        let (left, right) = self.sample_sum;
+        if self.stereo_output {
+            self.samples.extend([left / count, right / count]);
+        } else {
+            self.samples.push((left + right) / 2.0 / count);
+        }
+        self.sample_sum = (0.0, 0.0);
+        self.sample_count = 0;
+    }
+}
+
+impl Device for Psg {
+    fn tick(&mut self, cycles: usize) {
+        self.clock_phase += cycles as u64 * self.clock;
+        let step = self.cpu_clock * 16;
+        let steps_per_second = self.clock / 16;
+        while self.clock_phase >= step {
+            self.clock_phase -= step;
+            let (left, right) = self.step();
+            self.sample_sum.0 += left;
+            self.sample_sum.1 += right;
+            self.sample_count += 1;
+            self.sample_phase += self.sample_rate;
+            if self.sample_phase >= steps_per_second {
+                self.sample_phase -= steps_per_second;
+                self.push_sample();
+                // Nothing taking samples keeps one to two seconds of them
+                let channels = if self.stereo_output { 2 } else { 1 };
+                let keep = self.sample_rate as usize * channels;
+                if self.samples.len() >= keep * 2 {
+                    self.samples.drain(..keep);
+                }
+            }
+        }
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        self.write(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Psg;
+    use crate::device::Device;
+
+    // One sample per generator step
+    fn psg() -> Psg {
+        Psg::new(16_000, 16_000, 1000)
+    }
+
+    #[test]
+    fn registers() {
+        let mut psg = psg();
+        // Channel 1 period 0x123, full volume, then just the upper bits
+        psg.io_write(0x7F, 0xA3);
+        psg.io_write(0x7F, 0x12);
+        psg.io_write(0x7F, 0xB0);
+        assert_eq!(psg.tones[1], 0x123);
+        assert_eq!(psg.volumes[1], 0);
+        psg.io_write(0x7F, 0xA3);
+        psg.io_write(0x7F, 0x3F);
+        assert_eq!(psg.tones[1], 0x3F3);
+        // A data byte after a volume latch changes the volume
+        psg.io_write(0x7F, 0xF2);
+        psg.io_write(0x7F, 0x07);
+        assert_eq!(psg.volumes[3], 7);
+        psg.io_write(0x7F, 0xE5);
+        assert_eq!(psg.noise, 5);
+    }
+
+    #[test]
+    fn tone() {
+        let mut psg = psg();
+        // Channel 0, period 2, full volume
+        psg.write(0x82);
+        psg.write(0x00);
+        psg.write(0x90);
+        psg.tick(16 * 8);
+        let high = 0.25;
+        assert_eq!(
+            psg.take_samples(),
+            [high, high, 0.0, 0.0, high, high, 0.0, 0.0]
+        );
+
+        // Periodic noise at the fastest rate, on its own at -2dB: one pulse per 15 shifts
+        psg.write(0x9F);
+        psg.write(0xE0);
+        psg.write(0xF1);
+        psg.tick(16 * 0x20 * 30);
+        let samples = psg.take_samples();
+        let pulses = samples
+            .windows(2)
+            .filter(|w| w[0] == 0.0 && w[1] > 0.0)
+            .count();
+        assert_eq!(pulses, 2);
+        assert!(samples
+            .iter()
+            .all(|&s| s == 0.0 || (s - 0.7943 / 4.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn stereo() {
+        let mut psg = psg();
+        psg.stereo_output = true;
+        // Channel 0 held high at full volume, left only
+        psg.write(0x81);
+        psg.write(0x00);
+        psg.write(0x90);
+        psg.stereo = 0x10;
+        psg.tick(16 * 2);
+        assert_eq!(psg.take_samples(), [0.25, 0.0, 0.25, 0.0]);
+        psg.stereo = 0x01;
+        psg.stereo_output = false;
+        psg.tick(16);
+        assert_eq!(psg.take_samples(), [0.125]);
+    }
+}
@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use log::warn;
+
+// The other end of a serial line. Polled once per character time, so neither call should
+// block.
+pub trait SerialBackend {
+    // The next byte sent to the machine, if there is one
+    fn receive(&mut self) -> Option<u8>;
+
+    fn send(&mut self, byte: u8);
+}
+
+// The terminal the emulator runs in. Input is read a line at a time by a background thread
+// and line feeds are sent as carriage returns, which is what CP/M and ROM BASICs expect
+// from the Enter key.
+pub struct Stdio {
+    input: Receiver<u8>,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        let (tx, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            while let Ok(len @ 1..) = io::stdin().read(&mut buf) {
+                for &byte in &buf[..len] {
+                    let byte = if byte == b'\n' { b'\r' } else { byte };
+                    if tx.send(byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Self { input }
+    }
+}
+
+impl SerialBackend for Stdio {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn send(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).ok();
+        stdout.flush().ok();
+    }
+}
+
+// A TCP port a terminal program (telnet, nc) can connect to. One client at a time, a new
+// one can connect after the last disconnects. Output without a client is dropped.
+pub struct Tcp {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+}
+
+impl Tcp {
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn client(&mut self) -> Option<&mut TcpStream> {
+        if self.client.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                match stream.set_nonblocking(true) {
+                    Ok(()) => self.client = Some(stream),
+                    Err(e) => warn!("Serial client dropped: {}", e),
+                }
+            }
+        }
+        self.client.as_mut()
+    }
+}
+
+impl SerialBackend for Tcp {
+    fn receive(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.client()?.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            // Closed or failed
+            _ => {
+                self.client = None;
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, byte: u8) {
+        if let Some(client) = self.client() {
+            if client.write_all(&[byte]).is_err() {
+                self.client = None;
+            }
+        }
+    }
+}
+
+// In-memory serial line, clones share the buffers so a test can keep one end while the
+// device owns the other
+#[derive(Debug, Default, Clone)]
+pub struct Pipe {
+    // Bytes waiting to be received by the machine
+    pub input: Rc<RefCell<VecDeque<u8>>>,
+    // Everything the machine has sent
+    pub output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Pipe {
+    pub fn write(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    // The output so far, clearing it
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.output.borrow_mut())
+    }
+}
+
+impl SerialBackend for Pipe {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+
+use crate::device::Device;
+use crate::peripherals::serial::SerialBackend;
+
+pub const CHANNEL_A: usize = 0;
+pub const CHANNEL_B: usize = 1;
+
+// Interrupt sources of a channel, highest priority first
+const RX: usize = 0;
+const TX: usize = 1;
+const EXT: usize = 2;
+
+// Received characters the chip holds before overrunning
+const FIFO: usize = 3;
+
+#[derive(Default)]
+pub struct SioChannel {
+    // Write registers WR0-WR7 as last written
+    pub wr: [u8; 8],
+    // Register the next control access goes to, set through WR0
+    pointer: usize,
+    pub rx: VecDeque<u8>,
+    // Transmit buffer, sent at the next character time
+    pub tx: Option<u8>,
+    // Modem inputs, read back in RR0
+    pub dcd: bool,
+    pub cts: bool,
+    // Rx interrupts on first character mode waits for this
+    first_char: bool,
+    // T states into the current character time
+    elapsed: usize,
+    pub pending: [bool; 3],
+    pub in_service: [bool; 3],
+    backend: Option<Box<dyn SerialBackend>>,
+}
+
+impl SioChannel {
+    fn rx_enabled(&self) -> bool {
+        self.wr[3] & 0x01 != 0
+    }
+
+    fn tx_enabled(&self) -> bool {
+        self.wr[5] & 0x08 != 0
+    }
+
+    // WR1 bits 3-4: 0 disabled, 1 on the first character, 2 and 3 on every character
+    fn rx_int_mode(&self) -> u8 {
+        (self.wr[1] >> 3) & 3
+    }
+
+    fn reset(&mut self) {
+        self.wr = [0; 8];
+        self.pointer = 0;
+        self.rx.clear();
+        self.tx = None;
+        self.first_char = false;
+        self.pending = [false; 3];
+    }
+
+    // One character time: sends the transmit buffer and takes in a character
+    fn character(&mut self) {
+        let (tx_enabled, rx_enabled) = (self.tx_enabled(), self.rx_enabled());
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+        if tx_enabled {
+            if let Some(byte) = self.tx.take() {
+                backend.send(byte);
+                self.pending[TX] |= self.wr[1] & 0x02 != 0;
+            }
+        }
+        // A full FIFO leaves the byte with the backend rather than overrunning
+        if rx_enabled && self.rx.len() < FIFO {
+            if let Some(byte) = backend.receive() {
+                self.rx.push_back(byte);
+                match self.rx_int_mode() {
+                    0 => {}
+                    1 => self.pending[RX] |= std::mem::take(&mut self.first_char),
+                    _ => self.pending[RX] = true,
+                }
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let byte = self.rx.pop_front().unwrap_or(0xFF);
+        self.pending[RX] = self.rx_int_mode() >= 2 && !self.rx.is_empty();
+        byte
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.tx = Some(value);
+        self.pending[TX] = false;
+    }
+
+    fn set_modem(&mut self, dcd: bool, cts: bool) {
+        if (dcd, cts) != (self.dcd, self.cts) && self.wr[1] & 0x01 != 0 {
+            self.pending[EXT] = true;
+        }
+        self.dcd = dcd;
+        self.cts = cts;
+    }
+}
+
+// Z80 SIO/2, two asynchronous serial channels. Ports are A control, A data, B control,
+// B data (C/D on A0 inverted and B/A on A1, as on the RC2014). Control accesses go to
+// register 0 unless WR0 pointed at another one, so reading RR1 is `OUT (ctl), 1` followed
+// by `IN A, (ctl)`.
+//
+// Characters move once every `char_cycles` T states between the channel and its backend
+// (none attached drops output). Sync modes, CRCs, parity and framing errors aren't
+// emulated, the format set in WR3-WR5 is stored but doesn't change timing.
+//
+// Interrupt priority runs from channel A receive, transmit and external / status down to
+// channel B's. The vector is WR2 of channel B, with bits 1-3 replaced by the source when
+// WR1 bit 2 (status affects vector) is set on channel B.
+pub struct Sio {
+    pub channels: [SioChannel; 2],
+    pub char_cycles: usize,
+}
+
+impl Default for Sio {
+    // 115200 baud with a 7.3728MHz clock
+    fn default() -> Self {
+        Self::new(640)
+    }
+}
+
+impl Sio {
+    pub fn new(char_cycles: usize) -> Self {
+        assert!(char_cycles > 0, "Character time must be non zero");
+        let mut sio = Self {
+            channels: Default::default(),
+            char_cycles,
+        };
+        for channel in &mut sio.channels {
+            channel.dcd = true;
+            channel.cts = true;
+        }
+        sio
+    }
+
+    pub fn connect<B: SerialBackend + 'static>(&mut self, channel: usize, backend: B) {
+        self.channels[channel].backend = Some(Box::new(backend));
+    }
+
+    // DCD and CTS inputs, a change raises an external / status interrupt if enabled
+    pub fn set_modem(&mut self, channel: usize, dcd: bool, cts: bool) {
+        self.channels[channel].set_modem(dcd, cts);
+    }
+
+    fn requesting(&self) -> Option<(usize, usize)> {
+        for (n, channel) in self.channels.iter().enumerate() {
+            for source in [RX, TX, EXT] {
+                if channel.in_service[source] {
+                    return None;
+                }
+                if channel.pending[source] {
+                    return Some((n, source));
+                }
+            }
+        }
+        None
+    }
+
+    // WR2 with the source of the highest priority interrupt in bits 1-3 if status affects
+    // vector, 011 (channel B special receive) when nothing is pending
+    fn vector(&self) -> u8 {
+        let b = &self.channels[CHANNEL_B];
+        if b.wr[1] & 0x04 == 0 {
+            return b.wr[2];
+        }
+        let status = match self.requesting() {
+            Some((channel, source)) => {
+                let code = match source {
+                    TX => 0,
+                    EXT => 1,
+                    _ => 2,
+                };
+                if channel == CHANNEL_A {
+                    code | 4
+                } else {
+                    code
+                }
+            }
+            None => 3,
+        };
+        b.wr[2] & 0xF1 | status << 1
+    }
+
+    fn read_control(&mut self, n: usize) -> u8 {
+        let any_pending = self.requesting().is_some();
+        let vector = self.vector();
+        let channel = &mut self.channels[n];
+        let register = std::mem::take(&mut channel.pointer);
+        match register {
+            0 => {
+                !channel.rx.is_empty() as u8
+                    | ((n == CHANNEL_A && any_pending) as u8) << 1
+                    | (channel.tx.is_none() as u8) << 2
+                    | (channel.dcd as u8) << 3
+                    | (channel.cts as u8) << 5
+            }
+            // All sent, no errors
+            1 => channel.tx.is_none() as u8,
+            2 if n == CHANNEL_B => vector,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_control(&mut self, n: usize, value: u8) {
+        let channel = &mut self.channels[n];
+        if channel.pointer != 0 {
+            let register = std::mem::take(&mut channel.pointer);
+            channel.wr[register] = value;
+            return;
+        }
+        channel.pointer = value as usize & 7;
+        match (value >> 3) & 7 {
+            // Reset external / status interrupts
+            2 => channel.pending[EXT] = false,
+            3 => channel.reset(),
+            // Enable interrupt on next Rx character
+            4 => channel.first_char = true,
+            // Reset Tx interrupt pending
+            5 => channel.pending[TX] = false,
+            // Return from interrupt, for CPUs that don't decode RETI
+            7 if n == CHANNEL_A => self.reti(),
+            _ => {}
+        }
+    }
+}
+
+impl Device for Sio {
+    fn tick(&mut self, cycles: usize) {
+        for channel in &mut self.channels {
+            channel.elapsed += cycles;
+            while channel.elapsed >= self.char_cycles {
+                channel.elapsed -= self.char_cycles;
+                channel.character();
+            }
+        }
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        let n = (port as usize >> 1) & 1;
+        if port & 1 == 0 {
+            self.read_control(n)
+        } else {
+            self.channels[n].read_data()
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        let n = (port as usize >> 1) & 1;
+        if port & 1 == 0 {
+            self.write_control(n, value);
+        } else {
+            self.channels[n].write_data(value);
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        self.requesting().map(|_| self.vector())
+    }
+
+    fn interrupt_acknowledged(&mut self) {
+        if let Some((n, source)) = self.requesting() {
+            // Receive interrupts stay pending until the character is read
+            if source != RX {
+                self.channels[n].pending[source] = false;
+            }
+            self.channels[n].in_service[source] = true;
+        }
+    }
+
+    fn reti(&mut self) {
+        for channel in &mut self.channels {
+            if let Some(source) = channel.in_service.iter().position(|&s| s) {
+                channel.in_service[source] = false;
+                return;
+            }
+        }
+    }
+
+    fn interrupt_in_service(&self) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| channel.in_service.contains(&true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sio, CHANNEL_A, CHANNEL_B};
+    use crate::device::Device;
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::peripherals::serial::{Pipe, SerialBackend, Tcp};
+    use std::io::{Read, Write};
+
+    // WR3 Rx enable, WR4 x16 clock and 1 stop bit, WR5 Tx enable and RTS
+    const INIT: [u8; 6] = [0x03, 0xC1, 0x04, 0x44, 0x05, 0x6A];
+
+    #[test]
+    fn registers_and_interrupts() {
+        let mut sio = Sio::new(100);
+        let pipe = Pipe::default();
+        sio.connect(CHANNEL_A, pipe.clone());
+        for value in INIT {
+            sio.io_write(0, value);
+        }
+        // Channel B: vector 0x40, status affects vector; channel A: Rx on every character
+        // and Tx interrupts
+        for value in [0x02, 0x40, 0x01, 0x04] {
+            sio.io_write(2, value);
+        }
+        for value in [0x01, 0x12] {
+            sio.io_write(0, value);
+        }
+        assert_eq!(sio.io_read(0) & 0x05, 0x04);
+
+        sio.io_write(1, b'A');
+        assert_eq!(sio.io_read(0) & 0x04, 0x00);
+        pipe.write(b"hi");
+        sio.tick(100);
+        assert_eq!(pipe.take_output(), b"A");
+        // Receive is ahead of transmit
+        assert_eq!(sio.io_read(0) & 0x07, 0x07);
+        assert_eq!(sio.pending_interrupt(), Some(0x4C));
+        sio.interrupt_acknowledged();
+        assert_eq!(sio.pending_interrupt(), None);
+        assert_eq!(sio.io_read(1), b'h');
+        sio.reti();
+        assert_eq!(sio.pending_interrupt(), Some(0x48));
+        sio.io_write(0, 0x28);
+        assert_eq!(sio.pending_interrupt(), None);
+        sio.tick(100);
+        assert_eq!(sio.pending_interrupt(), Some(0x4C));
+        assert_eq!(sio.io_read(1), b'i');
+        assert_eq!(sio.pending_interrupt(), None);
+
+        // RR2 through the pointer, then back to RR0
+        sio.io_write(2, 0x02);
+        assert_eq!(sio.io_read(2), 0x46);
+        assert_eq!(sio.io_read(2) & 0x2C, 0x2C);
+
+        // External / status interrupts on channel B
+        sio.io_write(2, 0x01);
+        sio.io_write(2, 0x05);
+        sio.set_modem(CHANNEL_B, false, true);
+        assert_eq!(sio.pending_interrupt(), Some(0x42));
+        sio.io_write(2, 0x10);
+        assert_eq!(sio.pending_interrupt(), None);
+    }
+
+    #[test]
+    fn echo_program() {
+        // Polls channel A and echoes upper cased characters until a '.'
+        // 0100: IN A, (0x80); RRCA; JR NC, 0100; IN A, (0x81); CP '.'; HALT if equal;
+        //       AND 0xDF; LD B, A; 010F: IN A, (0x80); AND 4; JR Z, 010F; LD A, B;
+        //       OUT (0x81), A; JP 0100
+        let program = [
+            0xDB, 0x80, 0x0F, 0x30, 0xFB, 0xDB, 0x81, 0xFE, 0x2E, 0xCA, 0x1E, 0x01, 0xE6, 0xDF,
+            0x47, 0xDB, 0x80, 0xE6, 0x04, 0x28, 0xFA, 0x78, 0xD3, 0x81, 0xC3, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0x76,
+        ];
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &program);
+        let sio = i.add_device(Sio::new(50));
+        i.register_port(0x80..=0x83, sio.clone());
+        let pipe = Pipe::default();
+        sio.borrow_mut().connect(CHANNEL_A, pipe.clone());
+        for value in INIT {
+            sio.borrow_mut().io_write(0, value);
+        }
+        pipe.write(b"abc.");
+        while i.peek(i.cpu.reg.pc) != 0x76 {
+            i.step();
+        }
+        i.step();
+        assert_eq!(pipe.take_output(), b"ABC");
+    }
+
+    #[test]
+    fn tcp_backend() {
+        let mut tcp = Tcp::listen("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        client.write_all(b"x").unwrap();
+        let mut received = None;
+        for _ in 0..1000 {
+            received = tcp.receive();
+            if received.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(received, Some(b'x'));
+        tcp.send(b'y');
+        let mut byte = [0];
+        client.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"y");
+    }
+}
@@ -0,0 +1,45 @@
+use crate::device::Device;
+
+// Raises an interrupt (or NMI) every `period` T states, e.g. a 50Hz frame interrupt
+pub struct IntervalTimer {
+    pub period: usize,
+    pub vector: u8,
+    pub nmi: bool,
+    elapsed: usize,
+    fired: bool,
+}
+
+impl IntervalTimer {
+    pub fn new(period: usize, vector: u8, nmi: bool) -> Self {
+        assert!(period > 0, "Timer period must be non zero");
+        Self {
+            period,
+            vector,
+            nmi,
+            elapsed: 0,
+            fired: false,
+        }
+    }
+}
+
+impl Device for IntervalTimer {
+    fn tick(&mut self, cycles: usize) {
+        self.elapsed += cycles;
+        self.fired = self.elapsed >= self.period;
+        if self.fired {
+            self.elapsed %= self.period;
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        if self.fired && !self.nmi {
+            Some(self.vector)
+        } else {
+            None
+        }
+    }
+
+    fn pending_nmi(&self) -> bool {
+        self.fired && self.nmi
+    }
+}
@@ -0,0 +1,315 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::device::Device;
+use crate::memory::Memory;
+use crate::video::Framebuffer;
+
+// Port FE output bits
+const BORDER: u8 = 0x07;
+const SPEAKER: u8 = 0x10;
+
+// The picture: 256x192 pixels inside a border of 32 pixels at the sides and 48 lines above
+// and below
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 288;
+const BORDER_LEFT: usize = 32;
+const BORDER_TOP: usize = 48;
+
+const ATTRIBUTES: usize = 0x1800;
+const FLASH: u8 = 0x80;
+const BRIGHT: u8 = 0x40;
+// Flash swaps ink and paper every 16 frames
+const FLASH_FRAMES: u64 = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timing {
+    pub line_cycles: usize,
+    pub lines: usize,
+    // Scanline of the first row of pixels
+    pub first_line: usize,
+    // T states /INT is held at the start of each frame
+    pub int_cycles: usize,
+}
+
+pub const TIMING_48K: Timing = Timing {
+    line_cycles: 224,
+    lines: 312,
+    first_line: 64,
+    int_cycles: 32,
+};
+
+pub const TIMING_128K: Timing = Timing {
+    line_cycles: 228,
+    lines: 311,
+    first_line: 63,
+    int_cycles: 36,
+};
+
+impl Timing {
+    pub fn frame_cycles(&self) -> usize {
+        self.line_cycles * self.lines
+    }
+}
+
+// Colour 0-7 (G R B bits) as RGBA
+fn colour(colour: u8, bright: bool) -> [u8; 4] {
+    let level = if bright { 0xFF } else { 0xD7 };
+    let on = |bit: u8| if colour & bit != 0 { level } else { 0 };
+    [on(2), on(4), on(1), 0xFF]
+}
+
+// The Spectrum's ULA as seen on port FE (any even port): the border colour and the
+// beeper, and the display.
+//
+// The beeper is bit 4 of writes. Writes land in the last M cycle of an OUT, so each edge is
+// placed at the end of the instruction that made it, and the square wave is averaged over
+// each sample period of the host rate. Samples go from 0.0 to 1.0 and pile up in `samples`
+// until taken.
+//
+// Each scanline is drawn into `frame` from the display memory and border colour of the
+// moment it ends, so changes to either between lines show up as on the real machine
+// (changes within a line land on the next one).
+//
+// /INT is held for the first `int_cycles` T states of every frame, the 50Hz interrupt the
+// ROM scans the keyboard on. A program with interrupts disabled for that long misses it.
+pub struct Ula {
+    pub border: u8,
+    pub speaker: bool,
+    pub cpu_clock: u64,
+    pub sample_rate: u64,
+    pub samples: Vec<f32>,
+    pub timing: Timing,
+    // Offset of the display file in RAM storage
+    pub screen: Rc<Cell<usize>>,
+    pub frame: Rc<RefCell<Framebuffer>>,
+    // T states since power on
+    pub cycles: u64,
+    // Scanlines drawn since power on
+    lines_drawn: u64,
+    // Level set by an OUT during the instruction being ticked
+    edge: Option<bool>,
+    // T states * sample rate into the current sample, and the speaker level summed over it
+    phase: u64,
+    level_sum: u64,
+}
+
+impl Default for Ula {
+    fn default() -> Self {
+        Self::new(3_500_000, 44_100)
+    }
+}
+
+impl Ula {
+    pub fn new(cpu_clock: u64, sample_rate: u64) -> Self {
+        assert!(cpu_clock > 0 && sample_rate > 0);
+        Self {
+            border: 0,
+            speaker: false,
+            cpu_clock,
+            sample_rate,
+            samples: Vec::new(),
+            timing: TIMING_48K,
+            screen: Rc::new(Cell::new(0x4000)),
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            cycles: 0,
+            lines_drawn: 0,
+            edge: None,
+            phase: 0,
+            level_sum: 0,
+        }
+    }
+
+    // Everything synthesized so far, clearing the buffer
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Frames since power on
+    pub fn frames(&self) -> u64 {
+        self.cycles / self.timing.frame_cycles() as u64
+    }
+
+    // Redraws the whole picture from the display memory as it is now
+    pub fn render(&mut self, memory: &Memory) {
+        for line in 0..self.timing.lines {
+            self.draw_line(memory, line, self.frames());
+        }
+    }
+
+    // Draws the picture rows of a scanline of frame number `frame`
+    fn draw_line(&self, memory: &Memory, line: usize, frame: u64) {
+        let y = match (line + BORDER_TOP).checked_sub(self.timing.first_line) {
+            Some(y) if y < HEIGHT => y,
+            _ => return,
+        };
+        let mut picture = self.frame.borrow_mut();
+        let row = picture.row_mut(y);
+        let border = colour(self.border, false);
+        row.chunks_exact_mut(4)
+            .for_each(|pixel| pixel.copy_from_slice(&border));
+        let pixel_row = match y.checked_sub(BORDER_TOP) {
+            Some(r) if r < 192 => r,
+            _ => return,
+        };
+        let screen = self.screen.get();
+        let flash = frame / FLASH_FRAMES % 2 == 1;
+        // Thirds of 64 rows, then character rows of 8, then the row within a character
+        let bitmap = screen
+            + ((pixel_row & 0xC0) << 5)
+            + ((pixel_row & 0x07) << 8)
+            + ((pixel_row & 0x38) << 2);
+        let attributes = screen + ATTRIBUTES + pixel_row / 8 * 32;
+        for column in 0..32 {
+            let mut bits = memory.ram[bitmap + column];
+            let attribute = memory.ram[attributes + column];
+            if flash && attribute & FLASH != 0 {
+                bits = !bits;
+            }
+            let bright = attribute & BRIGHT != 0;
+            let ink = colour(attribute & 0x07, bright);
+            let paper = colour(attribute >> 3 & 0x07, bright);
+            for bit in 0..8 {
+                let x = BORDER_LEFT + column * 8 + bit;
+                let pixel = if bits & (0x80 >> bit) != 0 {
+                    ink
+                } else {
+                    paper
+                };
+                row[x * 4..x * 4 + 4].copy_from_slice(&pixel);
+            }
+        }
+    }
+
+    // `cycles` T states at the current speaker level
+    fn play(&mut self, cycles: usize) {
+        let mut units = cycles as u64 * self.sample_rate;
+        let level = self.speaker as u64;
+        while self.phase + units >= self.cpu_clock {
+            let take = self.cpu_clock - self.phase;
+            self.level_sum += level * take;
+            units -= take;
+            self.samples
+                .push(self.level_sum as f32 / self.cpu_clock as f32);
+            self.phase = 0;
+            self.level_sum = 0;
+        }
+        self.phase += units;
+        self.level_sum += level * units;
+        // Nothing taking samples keeps one to two seconds of them
+        let keep = self.sample_rate as usize;
+        if self.samples.len() >= keep * 2 {
+            self.samples.drain(..keep);
+        }
+    }
+}
+
+impl Device for Ula {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+        self.play(cycles);
+        if let Some(level) = self.edge.take() {
+            self.speaker = level;
+        }
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        self.border = value & BORDER;
+        self.edge = Some(value & SPEAKER != 0);
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        let frame_cycle = self.cycles % self.timing.frame_cycles() as u64;
+        (frame_cycle < self.timing.int_cycles as u64).then_some(0xFF)
+    }
+
+    fn level_interrupt(&self) -> bool {
+        true
+    }
+
+    fn scan(&mut self, memory: &Memory) {
+        let line_cycles = self.timing.line_cycles as u64;
+        let lines = self.timing.lines as u64;
+        while (self.lines_drawn + 1) * line_cycles <= self.cycles {
+            let line = self.lines_drawn;
+            self.draw_line(memory, (line % lines) as usize, line / lines);
+            self.lines_drawn += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ula, HEIGHT, TIMING_48K, WIDTH};
+    use crate::device::Device;
+    use crate::memory::Memory;
+
+    #[test]
+    fn beeper() {
+        // 10 T states per sample
+        let mut ula = Ula::new(1000, 100);
+        ula.io_write(0xFE, 0x12);
+        assert_eq!(ula.border, 2);
+        // The edge is at the end of the 15 T state instruction
+        ula.tick(15);
+        ula.tick(10);
+        ula.io_write(0xFE, 0x00);
+        ula.tick(4);
+        ula.tick(11);
+        assert_eq!(ula.take_samples(), [0.0, 0.5, 0.9, 0.0]);
+        assert!(ula.samples.is_empty());
+    }
+
+    #[test]
+    fn display() {
+        let mut ula = Ula::default();
+        let mut memory = Memory::default();
+        // Top left character: bright red ink on blue paper, flashing, a vertical bar in its
+        // first row. Second third, first row: all ink, black on black
+        memory.ram[0x4000] = 0xF0;
+        memory.ram[0x5800] = 0xCA;
+        memory.ram[0x4800] = 0xFF;
+        ula.io_write(0xFE, 0x06);
+        ula.render(&memory);
+        {
+            let frame = ula.frame.borrow();
+            assert_eq!((frame.width, frame.height), (WIDTH, HEIGHT));
+            assert_eq!(frame.pixel(0, 0), [0xD7, 0xD7, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(32, 48), [0xFF, 0x00, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(36, 48), [0x00, 0x00, 0xFF, 0xFF]);
+            assert_eq!(frame.pixel(32, 49), [0x00, 0x00, 0xFF, 0xFF]);
+            assert_eq!(frame.pixel(40, 48 + 64), [0x00, 0x00, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(319, 287), [0xD7, 0xD7, 0x00, 0xFF]);
+        }
+
+        // Lines are drawn as the frame goes: the border goes green on the first pixel row,
+        // and by the next frame's first pixel row flash has swapped ink and paper
+        let frame_cycles = TIMING_48K.frame_cycles();
+        let line = TIMING_48K.line_cycles;
+        ula.tick(TIMING_48K.first_line * line);
+        ula.scan(&memory);
+        ula.io_write(0xFE, 0x04);
+        ula.tick(line);
+        ula.scan(&memory);
+        assert_eq!(ula.frame.borrow().pixel(0, 47), [0xD7, 0xD7, 0x00, 0xFF]);
+        assert_eq!(ula.frame.borrow().pixel(0, 48), [0x00, 0xD7, 0x00, 0xFF]);
+        ula.tick(16 * frame_cycles);
+        ula.scan(&memory);
+        assert_eq!(ula.frames(), 16);
+        assert_eq!(ula.frame.borrow().pixel(32, 48), [0x00, 0x00, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut ula = Ula::default();
+        assert!(ula.level_interrupt());
+        ula.tick(31);
+        assert_eq!(ula.pending_interrupt(), Some(0xFF));
+        ula.tick(1);
+        assert_eq!(ula.pending_interrupt(), None);
+        ula.tick(TIMING_48K.frame_cycles() - 33);
+        assert_eq!(ula.pending_interrupt(), None);
+        ula.tick(1);
+        assert_eq!(ula.pending_interrupt(), Some(0xFF));
+    }
+}
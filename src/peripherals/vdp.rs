@@ -0,0 +1,832 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::device::Device;
+use crate::video::Framebuffer;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+pub const VRAM_SIZE: usize = 0x4000;
+
+// Status register bits, the low 5 are the number of the fifth sprite on a line
+const FRAME: u8 = 0x80;
+const FIFTH_SPRITE: u8 = 0x40;
+const COLLISION: u8 = 0x20;
+
+// Register 0 bits of Sega's VDPs
+const LOCK_VSCROLL: u8 = 0x80;
+const LOCK_HSCROLL: u8 = 0x40;
+const MASK_COLUMN: u8 = 0x20;
+const LINE_INT: u8 = 0x10;
+const SHIFT_SPRITES: u8 = 0x08;
+const MODE_4: u8 = 0x04;
+
+// Register 1 bits
+const DISPLAY: u8 = 0x40;
+const FRAME_INT: u8 = 0x20;
+const LARGE_SPRITES: u8 = 0x02;
+const MAGNIFIED_SPRITES: u8 = 0x01;
+
+// Sprite attribute Y ending the sprite list
+const SPRITES_END: u8 = 0xD0;
+const SPRITES_PER_LINE: usize = 4;
+const MODE_4_SPRITES_PER_LINE: usize = 8;
+
+// The part of the picture the Game Gear's LCD shows
+pub const GG_WIDTH: usize = 160;
+pub const GG_HEIGHT: usize = 144;
+const GG_LEFT: usize = 48;
+const GG_TOP: usize = 24;
+
+// The 15 colours as RGB, colour 0 is transparent
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x21, 0xC8, 0x42],
+    [0x5E, 0xDC, 0x78],
+    [0x54, 0x55, 0xED],
+    [0x7D, 0x76, 0xFC],
+    [0xD4, 0x52, 0x4D],
+    [0x42, 0xEB, 0xF5],
+    [0xFC, 0x55, 0x54],
+    [0xFF, 0x79, 0x78],
+    [0xD4, 0xC1, 0x54],
+    [0xE6, 0xCE, 0x80],
+    [0x21, 0xB0, 0x3B],
+    [0xC9, 0x5B, 0xBA],
+    [0xCC, 0xCC, 0xCC],
+    [0xFF, 0xFF, 0xFF],
+];
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Chip {
+    #[default]
+    Tms9918,
+    // Adds mode 4 with its colour RAM of 32 6-bit colours, scrolling and a line interrupt
+    MasterSystem,
+    // The Master System's, with 12-bit colours and only the middle 160x144 on screen
+    GameGear,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    // 32x24 tiles, a colour pair per 8 patterns
+    Graphics1,
+    // 32x24 tiles, three pattern and colour tables down the screen, a colour pair per row
+    Graphics2,
+    // 64x48 blocks of 4x4 pixels
+    Multicolor,
+    // 40x24 tiles of 6x8, two colours and no sprites
+    Text,
+    // Sega's: 32x28 scrolling 16 colour tiles from two palettes, 64 sprites
+    Mode4,
+}
+
+// TI TMS9918A video display processor, with its own 16K of VRAM, as used by the SG-1000,
+// MSX1 and ColecoVision, or Sega's extended version of it in the Master System and Game
+// Gear. Even ports are data, odd ports control (write) / status (read).
+//
+// Lines are drawn into `frame` as they end, with the VRAM and registers of the moment. The
+// frame flag is set at the end of the 192 active lines and /INT is held while it's set and
+// enabled, until the status is read. Sega's VDPs also count lines down from register 10
+// through the active display and raise the line interrupt each time it runs out.
+pub struct Vdp {
+    pub chip: Chip,
+    pub vram: Vec<u8>,
+    // Colour RAM of Sega's VDPs, 32 bytes on the Master System and 32 words on the Game Gear
+    pub cram: [u8; 64],
+    pub regs: [u8; 16],
+    pub status: u8,
+    // VRAM address of the next data port access
+    pub address: u16,
+    // T states per line and lines per frame, 262 for NTSC and 313 for PAL
+    pub line_cycles: usize,
+    pub lines: usize,
+    pub frame: Rc<RefCell<Framebuffer>>,
+    // T states since power on
+    pub cycles: u64,
+    // First byte of a control port pair
+    latch: Option<u8>,
+    // Sega's VDPs write to colour RAM instead of VRAM after a control word with code 3
+    cram_target: bool,
+    // Low byte of a Game Gear colour, written along with the high one
+    cram_latch: u8,
+    line_counter: u8,
+    line_int: bool,
+    // Data port reads come from here, it's refilled from the address after each access
+    read_buffer: u8,
+    lines_done: u64,
+}
+
+impl Default for Vdp {
+    // NTSC, with a 3.58MHz CPU
+    fn default() -> Self {
+        Self::new(228, 262)
+    }
+}
+
+impl Vdp {
+    pub fn new(line_cycles: usize, lines: usize) -> Self {
+        assert!(line_cycles > 0 && lines > HEIGHT);
+        Self {
+            chip: Chip::Tms9918,
+            vram: vec![0; VRAM_SIZE],
+            cram: [0; 64],
+            regs: [0; 16],
+            status: 0,
+            address: 0,
+            line_cycles,
+            lines,
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            cycles: 0,
+            latch: None,
+            cram_target: false,
+            cram_latch: 0,
+            line_counter: 0,
+            line_int: false,
+            read_buffer: 0,
+            lines_done: 0,
+        }
+    }
+
+    // NTSC, with the picture sized for the chip's screen
+    pub fn with_chip(chip: Chip) -> Self {
+        let mut vdp = Self {
+            chip,
+            ..Self::default()
+        };
+        if chip == Chip::GameGear {
+            vdp.frame = Rc::new(RefCell::new(Framebuffer::new(GG_WIDTH, GG_HEIGHT)));
+        }
+        vdp
+    }
+
+    pub fn frame_cycles(&self) -> usize {
+        self.line_cycles * self.lines
+    }
+
+    pub fn mode(&self) -> Mode {
+        if self.chip != Chip::Tms9918 && self.regs[0] & MODE_4 != 0 {
+            Mode::Mode4
+        } else if self.regs[1] & 0x10 != 0 {
+            Mode::Text
+        } else if self.regs[1] & 0x08 != 0 {
+            Mode::Multicolor
+        } else if self.regs[0] & 0x02 != 0 {
+            Mode::Graphics2
+        } else {
+            Mode::Graphics1
+        }
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        self.latch = None;
+        let value = self.read_buffer;
+        self.read_buffer = self.vram[self.address as usize];
+        self.address = (self.address + 1) & 0x3FFF;
+        value
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.latch = None;
+        if self.cram_target {
+            self.write_cram(value);
+        } else {
+            self.vram[self.address as usize] = value;
+        }
+        self.read_buffer = value;
+        self.address = (self.address + 1) & 0x3FFF;
+    }
+
+    fn write_cram(&mut self, value: u8) {
+        let address = self.address as usize;
+        match self.chip {
+            Chip::GameGear if address & 1 == 0 => self.cram_latch = value,
+            Chip::GameGear => {
+                self.cram[address & 0x3E] = self.cram_latch;
+                self.cram[address & 0x3F] = value & 0x0F;
+            }
+            _ => self.cram[address & 0x1F] = value & 0x3F,
+        }
+    }
+
+    // Clears the flags and the interrupts
+    pub fn read_status(&mut self) -> u8 {
+        self.latch = None;
+        self.line_int = false;
+        let status = self.status;
+        self.status &= !(FRAME | FIFTH_SPRITE | COLLISION);
+        status
+    }
+
+    // Two bytes: the low address bits or register value, then the high address bits with
+    // 00 (read) or 01 (write) on top, or 1 and a register number. On Sega's VDPs the top
+    // bits are a code: 10 writes a register and 11 sets up colour RAM writes.
+    pub fn write_control(&mut self, value: u8) {
+        let low = match self.latch.take() {
+            None => {
+                // The low byte goes straight into the address
+                self.latch = Some(value);
+                self.address = self.address & 0x3F00 | value as u16;
+                return;
+            }
+            Some(low) => low,
+        };
+        let sega = self.chip != Chip::Tms9918;
+        if sega {
+            self.cram_target = value & 0xC0 == 0xC0;
+        }
+        match value & 0xC0 {
+            0x80 if sega => {
+                self.regs[(value & 0x0F) as usize] = low;
+                return;
+            }
+            0x80 | 0xC0 if !sega => {
+                self.regs[(value & 0x07) as usize] = low;
+                return;
+            }
+            _ => {}
+        }
+        self.address = (value as u16 & 0x3F) << 8 | low as u16;
+        if value & 0x40 == 0 {
+            self.read_buffer = self.vram[self.address as usize];
+            self.address = (self.address + 1) & 0x3FFF;
+        }
+    }
+
+    fn table(&self, reg: usize, mask: u8, shift: u32) -> usize {
+        ((self.regs[reg] & mask) as usize) << shift
+    }
+
+    // Line and frame position as read on Sega's V counter port, jumping back from DA to D5
+    // to fit 262 lines in a byte
+    pub fn v_counter(&self) -> u8 {
+        let line = (self.cycles / self.line_cycles as u64 % self.lines as u64) as usize;
+        match line {
+            0..=0xDA => line as u8,
+            _ => (line - 6) as u8,
+        }
+    }
+
+    // Half the pixel clocks into the line, roughly
+    pub fn h_counter(&self) -> u8 {
+        let cycle = self.cycles % self.line_cycles as u64;
+        (cycle * 171 / self.line_cycles as u64) as u8
+    }
+
+    // Backdrop colour of mode 4, from the sprite palette
+    fn mode4_backdrop(&self) -> u8 {
+        16 + (self.regs[7] & 0x0F)
+    }
+
+    // Colours 0-15 of one line, 0 being the backdrop, or colour RAM entries in mode 4
+    fn line_colours(&mut self, y: usize) -> [u8; WIDTH] {
+        if self.mode() == Mode::Mode4 {
+            return self.mode4_line(y);
+        }
+        let mut line = [0; WIDTH];
+        if self.regs[1] & DISPLAY == 0 {
+            return line;
+        }
+        let names = self.table(2, 0x0F, 10);
+        let row = y / 8;
+        let pixels = |line: &mut [u8], x: usize, bits: u8, colours: u8, width: usize| {
+            for bit in 0..width {
+                line[x + bit] = match bits & (0x80 >> bit) {
+                    0 => colours & 0x0F,
+                    _ => colours >> 4,
+                };
+            }
+        };
+        match self.mode() {
+            Mode::Graphics1 => {
+                let patterns = self.table(4, 0x07, 11);
+                let colours = self.table(3, 0xFF, 6);
+                for column in 0..32 {
+                    let name = self.vram[names + row * 32 + column] as usize;
+                    let bits = self.vram[patterns + name * 8 + y % 8];
+                    let colour = self.vram[colours + name / 8];
+                    pixels(&mut line, column * 8, bits, colour, 8);
+                }
+            }
+            Mode::Graphics2 => {
+                // The low register bits mask the pattern number, mirroring the tables
+                let patterns = self.table(4, 0x04, 11);
+                let pattern_mask = self.table(4, 0x03, 8) | 0xFF;
+                let colours = self.table(3, 0x80, 6);
+                let colour_mask = self.table(3, 0x7F, 3) | 0x07;
+                for column in 0..32 {
+                    let name = (y / 64) << 8 | self.vram[names + row * 32 + column] as usize;
+                    let bits = self.vram[patterns + (((name & pattern_mask) << 3) | (y % 8))];
+                    let colour = self.vram[colours + (((name & colour_mask) << 3) | (y % 8))];
+                    pixels(&mut line, column * 8, bits, colour, 8);
+                }
+            }
+            Mode::Multicolor => {
+                let patterns = self.table(4, 0x07, 11);
+                for column in 0..32 {
+                    let name = self.vram[names + row * 32 + column] as usize;
+                    let colours = self.vram[patterns + name * 8 + (row & 3) * 2 + ((y / 4) & 1)];
+                    pixels(&mut line, column * 8, 0xF0, colours, 8);
+                }
+            }
+            Mode::Mode4 => unreachable!(),
+            Mode::Text => {
+                let patterns = self.table(4, 0x07, 11);
+                let colours = self.regs[7];
+                for column in 0..40 {
+                    let name = self.vram[names + row * 40 + column] as usize;
+                    let bits = self.vram[patterns + name * 8 + y % 8];
+                    pixels(&mut line, 8 + column * 6, bits, colours, 6);
+                }
+            }
+        }
+        if self.mode() != Mode::Text {
+            self.draw_sprites(y, &mut line);
+        }
+        line
+    }
+
+    // Up to four sprites a line, the lowest numbered in front. Pixels of two sprites on top
+    // of each other set the collision flag, whatever their colour.
+    fn draw_sprites(&mut self, y: usize, line: &mut [u8; WIDTH]) {
+        let attributes = self.table(5, 0x7F, 7);
+        let patterns = self.table(6, 0x07, 11);
+        let large = self.regs[1] & LARGE_SPRITES != 0;
+        let zoom = if self.regs[1] & MAGNIFIED_SPRITES != 0 {
+            2
+        } else {
+            1
+        };
+        let size = if large { 16 } else { 8 } * zoom;
+        let mut covered = [false; WIDTH];
+        let mut count = 0;
+        for n in 0..32 {
+            let sprite = &self.vram[attributes + n * 4..attributes + n * 4 + 4];
+            if sprite[0] == SPRITES_END {
+                break;
+            }
+            // Y is the line above the sprite, from E1 up it's partly above the screen
+            let top = match sprite[0] {
+                y if y > 0xE0 => y as i32 - 255,
+                y => y as i32 + 1,
+            };
+            let row = y as i32 - top;
+            if !(0..size).contains(&row) {
+                continue;
+            }
+            count += 1;
+            if count > SPRITES_PER_LINE {
+                if self.status & FIFTH_SPRITE == 0 {
+                    self.status = self.status & 0xE0 | FIFTH_SPRITE | n as u8;
+                }
+                break;
+            }
+            // Early clock shifts the sprite 32 pixels left
+            let x = sprite[1] as i32 - if sprite[3] & 0x80 != 0 { 32 } else { 0 };
+            let colour = sprite[3] & 0x0F;
+            let pattern = match large {
+                true => sprite[2] & 0xFC,
+                false => sprite[2],
+            } as usize;
+            let base = patterns + pattern * 8 + (row / zoom) as usize;
+            let right = if large { self.vram[base + 16] } else { 0 };
+            let bits = (self.vram[base] as u16) << 8 | right as u16;
+            for pixel in 0..size {
+                let sx = x + pixel;
+                if !(0..WIDTH as i32).contains(&sx) || bits & (0x8000 >> (pixel / zoom)) == 0 {
+                    continue;
+                }
+                let sx = sx as usize;
+                if covered[sx] {
+                    self.status |= COLLISION;
+                } else {
+                    covered[sx] = true;
+                    if colour != 0 {
+                        line[sx] = colour;
+                    }
+                }
+            }
+        }
+    }
+
+    // Colour 0-15 of pixel `x` of a mode 4 tile row starting at `base`
+    fn tile_pixel(&self, base: usize, x: usize) -> u8 {
+        (0..4).fold(0, |colour, plane| {
+            colour | (self.vram[base + plane] >> (7 - x) & 1) << plane
+        })
+    }
+
+    // Background tiles, then up to 8 sprites in front of them unless the tile has priority
+    // (colour 0 of a tile is always behind)
+    fn mode4_line(&mut self, y: usize) -> [u8; WIDTH] {
+        let backdrop = self.mode4_backdrop();
+        let mut line = [backdrop; WIDTH];
+        if self.regs[1] & DISPLAY == 0 {
+            return line;
+        }
+        let names = self.table(2, 0x0E, 10);
+        let mut priority = [false; WIDTH];
+        for (x, pixel) in line.iter_mut().enumerate() {
+            let hscroll = match self.regs[0] & LOCK_HSCROLL != 0 && y < 16 {
+                true => 0,
+                false => self.regs[8],
+            };
+            let vscroll = match self.regs[0] & LOCK_VSCROLL != 0 && x >= 192 {
+                true => 0,
+                false => self.regs[9] as usize,
+            };
+            let bx = (x as u8).wrapping_sub(hscroll) as usize;
+            let by = (y + vscroll) % 224;
+            let entry = names + (by / 8 * 32 + bx / 8) * 2;
+            let (low, high) = (self.vram[entry], self.vram[entry + 1]);
+            let tile = (high as usize & 1) << 8 | low as usize;
+            let row = if high & 0x04 != 0 { 7 - by % 8 } else { by % 8 };
+            let column = if high & 0x02 != 0 { 7 - bx % 8 } else { bx % 8 };
+            let colour = self.tile_pixel(tile * 32 + row * 4, column);
+            *pixel = (high & 0x08) << 1 | colour;
+            priority[x] = high & 0x10 != 0 && colour != 0;
+        }
+
+        let attributes = self.table(5, 0x7E, 7);
+        let tall = self.regs[1] & LARGE_SPRITES != 0;
+        let zoom = if self.regs[1] & MAGNIFIED_SPRITES != 0 {
+            2
+        } else {
+            1
+        };
+        let height = if tall { 16 } else { 8 } * zoom;
+        let shift = if self.regs[0] & SHIFT_SPRITES != 0 {
+            8
+        } else {
+            0
+        };
+        let mut covered = [false; WIDTH];
+        let mut count = 0;
+        for n in 0..64 {
+            let sprite_y = self.vram[attributes + n];
+            if sprite_y == SPRITES_END {
+                break;
+            }
+            let top = match sprite_y {
+                y if y > 0xF0 => y as i32 - 255,
+                y => y as i32 + 1,
+            };
+            let row = y as i32 - top;
+            if !(0..height).contains(&row) {
+                continue;
+            }
+            count += 1;
+            if count > MODE_4_SPRITES_PER_LINE {
+                self.status |= FIFTH_SPRITE;
+                break;
+            }
+            let x = self.vram[attributes + 0x80 + n * 2] as i32 - shift;
+            let mut tile = self.vram[attributes + 0x81 + n * 2] as usize;
+            if self.regs[6] & 0x04 != 0 {
+                tile |= 0x100;
+            }
+            if tall {
+                tile &= !1;
+            }
+            let base = tile * 32 + (row / zoom) as usize * 4;
+            for pixel in 0..8 * zoom {
+                let sx = x + pixel;
+                if !(0..WIDTH as i32).contains(&sx) {
+                    continue;
+                }
+                let colour = self.tile_pixel(base, (pixel / zoom) as usize);
+                let sx = sx as usize;
+                if colour == 0 {
+                    continue;
+                }
+                if covered[sx] {
+                    self.status |= COLLISION;
+                    continue;
+                }
+                covered[sx] = true;
+                if !priority[sx] {
+                    line[sx] = 16 + colour;
+                }
+            }
+        }
+        if self.regs[0] & MASK_COLUMN != 0 {
+            line[..8].fill(backdrop);
+        }
+        line
+    }
+
+    fn rgb(&self, colour: u8) -> [u8; 3] {
+        let colour = colour as usize;
+        match (self.mode(), self.chip) {
+            (Mode::Mode4, Chip::GameGear) => {
+                let (low, high) = (self.cram[colour * 2], self.cram[colour * 2 + 1]);
+                [(low & 0x0F) * 17, (low >> 4) * 17, (high & 0x0F) * 17]
+            }
+            (Mode::Mode4, _) => {
+                let bgr = self.cram[colour];
+                [(bgr & 3) * 85, (bgr >> 2 & 3) * 85, (bgr >> 4 & 3) * 85]
+            }
+            _ => PALETTE[colour],
+        }
+    }
+
+    fn draw_line(&mut self, y: usize) {
+        let line = self.line_colours(y);
+        let backdrop = self.regs[7] & 0x0F;
+        let mode4 = self.mode() == Mode::Mode4;
+        let rgb: Vec<[u8; 3]> = line
+            .iter()
+            .map(|&colour| match colour {
+                0 if !mode4 => self.rgb(backdrop),
+                _ => self.rgb(colour),
+            })
+            .collect();
+        let (left, y) = match self.chip {
+            Chip::GameGear if !(GG_TOP..GG_TOP + GG_HEIGHT).contains(&y) => return,
+            Chip::GameGear => (GG_LEFT, y - GG_TOP),
+            _ => (0, y),
+        };
+        let mut frame = self.frame.borrow_mut();
+        let width = frame.width;
+        let row = frame.row_mut(y);
+        for (x, [r, g, b]) in rgb[left..left + width].iter().enumerate() {
+            row[x * 4..x * 4 + 4].copy_from_slice(&[*r, *g, *b, 0xFF]);
+        }
+    }
+
+    // Sega's line counter, counting down through the active display and the line after
+    fn count_line(&mut self, line: usize) {
+        if line > HEIGHT {
+            self.line_counter = self.regs[10];
+            return;
+        }
+        match self.line_counter.checked_sub(1) {
+            Some(counter) => self.line_counter = counter,
+            None => {
+                self.line_counter = self.regs[10];
+                self.line_int = true;
+            }
+        }
+    }
+}
+
+impl Device for Vdp {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+        let line_cycles = self.line_cycles as u64;
+        while (self.lines_done + 1) * line_cycles <= self.cycles {
+            let line = (self.lines_done % self.lines as u64) as usize;
+            if line < HEIGHT {
+                self.draw_line(line);
+            }
+            if line == HEIGHT - 1 {
+                self.status |= FRAME;
+            }
+            if self.chip != Chip::Tms9918 {
+                self.count_line(line);
+            }
+            self.lines_done += 1;
+        }
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        match port & 1 {
+            0 => self.read_data(),
+            _ => self.read_status(),
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        match port & 1 {
+            0 => self.write_data(value),
+            _ => self.write_control(value),
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        let frame = self.status & FRAME != 0 && self.regs[1] & FRAME_INT != 0;
+        let line = self.line_int && self.regs[0] & LINE_INT != 0;
+        (frame || line).then_some(0xFF)
+    }
+
+    fn level_interrupt(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chip, Mode, Vdp, GG_HEIGHT, GG_WIDTH, HEIGHT};
+    use crate::device::Device;
+
+    // Register writes and VRAM loads through the ports
+    fn setup(vdp: &mut Vdp, regs: &[(u8, u8)], vram: &[(u16, &[u8])]) {
+        for &(reg, value) in regs {
+            vdp.io_write(0xBF, value);
+            vdp.io_write(0xBF, 0x80 | reg);
+        }
+        for &(addr, data) in vram {
+            vdp.io_write(0xBF, addr as u8);
+            vdp.io_write(0xBF, 0x40 | (addr >> 8) as u8);
+            for &byte in data {
+                vdp.io_write(0xBE, byte);
+            }
+        }
+    }
+
+    // Colour RAM from address 0
+    fn cram(vdp: &mut Vdp, data: &[u8]) {
+        vdp.io_write(0xBF, 0x00);
+        vdp.io_write(0xBF, 0xC0);
+        for &byte in data {
+            vdp.io_write(0xBE, byte);
+        }
+    }
+
+    #[test]
+    fn ports() {
+        let mut vdp = Vdp::default();
+        setup(&mut vdp, &[(7, 0x1F)], &[(0x3FFF, &[0x12, 0x34])]);
+        assert_eq!(vdp.regs[7], 0x1F);
+        // The address wraps at 16K
+        assert_eq!(vdp.vram[0], 0x34);
+        // Setting up a read fills the buffer
+        vdp.io_write(0xBF, 0xFF);
+        vdp.io_write(0xBF, 0x3F);
+        assert_eq!(vdp.io_read(0xBE), 0x12);
+        assert_eq!(vdp.io_read(0xBE), 0x34);
+    }
+
+    #[test]
+    fn frame_interrupt() {
+        let mut vdp = Vdp::default();
+        setup(&mut vdp, &[(1, 0x20)], &[]);
+        vdp.tick(228 * HEIGHT - 1);
+        assert_eq!(vdp.pending_interrupt(), None);
+        vdp.tick(1);
+        assert_eq!(vdp.pending_interrupt(), Some(0xFF));
+        assert_eq!(vdp.io_read(0xBF) & 0x80, 0x80);
+        assert_eq!(vdp.pending_interrupt(), None);
+        assert_eq!(vdp.io_read(0xBF) & 0x80, 0);
+    }
+
+    #[test]
+    fn graphics1_and_sprites() {
+        let mut vdp = Vdp::default();
+        // Graphics I with the display on, names at 1800, colours at 2000, patterns at 0,
+        // sprite attributes at 1B00 and patterns at 3800, backdrop dark blue
+        setup(
+            &mut vdp,
+            &[
+                (0, 0),
+                (1, 0x40),
+                (2, 6),
+                (3, 0x80),
+                (4, 0),
+                (5, 0x36),
+                (6, 7),
+                (7, 4),
+            ],
+            &[
+                // Tile 1 in the top left: top row half set, white on transparent
+                (0x0008, &[0xF0]),
+                (0x1800, &[1]),
+                (0x2000, &[0xF0]),
+                // Five red sprites on line 0 of sprite pattern 0 (a single pixel), the first
+                // two overlapping at x 100
+                (0x3800, &[0x80]),
+                (
+                    0x1B00,
+                    &[
+                        0xFF, 100, 0, 8, 0xFF, 100, 0, 8, 0xFF, 110, 0, 8, 0xFF, 120, 0, 8, 0xFF,
+                        130, 0, 8, 0xD0,
+                    ],
+                ),
+            ],
+        );
+        assert_eq!(vdp.mode(), Mode::Graphics1);
+        vdp.tick(228);
+        let frame = vdp.frame.borrow();
+        assert_eq!(frame.pixel(0, 0), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(4, 0), [0x54, 0x55, 0xED, 0xFF]);
+        assert_eq!(frame.pixel(100, 0), [0xFC, 0x55, 0x54, 0xFF]);
+        assert_eq!(frame.pixel(120, 0), [0xFC, 0x55, 0x54, 0xFF]);
+        // The fifth isn't drawn
+        assert_eq!(frame.pixel(130, 0), [0x54, 0x55, 0xED, 0xFF]);
+        assert_eq!(vdp.status, 0x64);
+    }
+
+    #[test]
+    fn text_mode() {
+        let mut vdp = Vdp::default();
+        setup(
+            &mut vdp,
+            &[(1, 0x50), (2, 0), (4, 1), (7, 0xF1)],
+            &[(0x0000, &[1]), (0x0808, &[0xFC])],
+        );
+        assert_eq!(vdp.mode(), Mode::Text);
+        vdp.tick(228);
+        let frame = vdp.frame.borrow();
+        // 8 pixel margin, then 6 pixels of the first character
+        assert_eq!(frame.pixel(7, 0), [0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(frame.pixel(13, 0), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(14, 0), [0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn mode4() {
+        let mut vdp = Vdp::with_chip(Chip::MasterSystem);
+        // Names at 3800, sprite attributes at 3F00 and sprite tiles from 2000, backdrop
+        // sprite colour 0
+        setup(
+            &mut vdp,
+            &[
+                (0, 0x04),
+                (1, 0x40),
+                (2, 0xFF),
+                (5, 0xFF),
+                (6, 0xFF),
+                (7, 0),
+            ],
+            &[
+                // Tile 1 starts its first two rows with colours 1 and 3
+                (0x0020, &[0xC0, 0x40, 0x00, 0x00, 0xC0, 0x40, 0x00, 0x00]),
+                // Twice, the second flipped and from the sprite palette
+                (0x3800, &[1, 0x00, 1, 0x0A]),
+                // A sprite of colour 1 at 20, 0
+                (0x2000, &[0xFF, 0x00, 0x00, 0x00]),
+                (0x3F00, &[0xFF, 0xD0]),
+                (0x3F80, &[20, 0]),
+            ],
+        );
+        // Black, red, _, blue, ..., grey, green, _, white
+        let mut colours = vec![0; 20];
+        colours[..4].copy_from_slice(&[0x00, 0x03, 0x00, 0x30]);
+        colours[16..].copy_from_slice(&[0x15, 0x0C, 0x00, 0x3F]);
+        cram(&mut vdp, &colours);
+        assert_eq!(vdp.mode(), Mode::Mode4);
+        vdp.tick(228);
+        {
+            let frame = vdp.frame.borrow();
+            assert_eq!(frame.pixel(0, 0), [0xFF, 0x00, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(1, 0), [0x00, 0x00, 0xFF, 0xFF]);
+            assert_eq!(frame.pixel(2, 0), [0x00, 0x00, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(14, 0), [0xFF, 0xFF, 0xFF, 0xFF]);
+            assert_eq!(frame.pixel(15, 0), [0x00, 0xFF, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(20, 0), [0x00, 0xFF, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(27, 0), [0x00, 0xFF, 0x00, 0xFF]);
+            assert_eq!(frame.pixel(28, 0), [0x00, 0x00, 0x00, 0xFF]);
+        }
+
+        // Scrolled 8 to the right, with the left column masked off
+        setup(&mut vdp, &[(0, 0x24), (8, 8)], &[]);
+        vdp.tick(228);
+        let frame = vdp.frame.borrow();
+        assert_eq!(frame.pixel(0, 1), [0x55, 0x55, 0x55, 0xFF]);
+        assert_eq!(frame.pixel(8, 1), [0xFF, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn line_interrupt() {
+        let mut vdp = Vdp::with_chip(Chip::MasterSystem);
+        // Every other line, loaded during the blanking
+        setup(&mut vdp, &[(0, 0x14), (10, 1)], &[]);
+        vdp.tick(vdp.frame_cycles());
+        vdp.io_read(0xBF);
+        assert_eq!(vdp.pending_interrupt(), None);
+        vdp.tick(228);
+        assert_eq!(vdp.pending_interrupt(), None);
+        vdp.tick(228);
+        assert_eq!(vdp.pending_interrupt(), Some(0xFF));
+        vdp.io_read(0xBF);
+        assert_eq!(vdp.pending_interrupt(), None);
+        // Lines 0xDB on show up as 0xD5 on
+        assert_eq!(vdp.v_counter(), 2);
+        vdp.tick(228 * (0xDB - 2));
+        assert_eq!(vdp.v_counter(), 0xD5);
+    }
+
+    #[test]
+    fn game_gear() {
+        let mut vdp = Vdp::with_chip(Chip::GameGear);
+        let name = 0x3800 + (3 * 32 + 6) * 2;
+        setup(
+            &mut vdp,
+            &[(0, 0x04), (1, 0x40), (2, 0xFF)],
+            &[(0x0020, &[0x80, 0x00, 0x00, 0x00]), (name, &[1, 0x00])],
+        );
+        // Colour 1 is 12-bit magenta, only set once both bytes are written
+        cram(&mut vdp, &[0x00, 0x00, 0x0F]);
+        assert_eq!(vdp.cram[2], 0x00);
+        cram(&mut vdp, &[0x00, 0x00, 0x0F, 0x0F]);
+        assert_eq!(vdp.cram[2..4], [0x0F, 0x0F]);
+        assert_eq!(vdp.frame.borrow().width, GG_WIDTH);
+        assert_eq!(vdp.frame.borrow().height, GG_HEIGHT);
+        // The screen starts at line 24, column 48
+        vdp.tick(228 * 25);
+        let frame = vdp.frame.borrow();
+        assert_eq!(frame.pixel(0, 0), [0xFF, 0x00, 0xFF, 0xFF]);
+        assert_eq!(frame.pixel(1, 0), [0x00, 0x00, 0x00, 0xFF]);
+    }
+}
@@ -0,0 +1,341 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::ops::RangeInclusive;
+
+use crate::instruction_info::{Instruction, InstructionInfo, Prefix};
+use crate::memory::ADDRESS_SPACE;
+
+const TABLES: [Prefix; 7] = [
+    Prefix::None,
+    Prefix::CB,
+    Prefix::ED,
+    Prefix::DD,
+    Prefix::FD,
+    Prefix::DDCB,
+    Prefix::FDCB,
+];
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct OpcodeStats {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+// Executions and T states spent per opcode, with each prefixed table counted separately
+pub struct OpcodeProfile {
+    stats: Vec<OpcodeStats>,
+}
+
+impl Default for OpcodeProfile {
+    fn default() -> Self {
+        Self {
+            stats: vec![OpcodeStats::default(); TABLES.len() * 256],
+        }
+    }
+}
+
+// Opcode table and opcode of the instruction starting with `bytes`. Repeated DD / FD
+// prefixes are treated as the last one, like the CPU does.
+fn key(bytes: &[u8; 4]) -> (Prefix, u8) {
+    match *bytes {
+        [0xCB, op, ..] => (Prefix::CB, op),
+        [0xED, op, ..] => (Prefix::ED, op),
+        [0xDD, 0xCB, _, op] => (Prefix::DDCB, op),
+        [0xFD, 0xCB, _, op] => (Prefix::FDCB, op),
+        [0xDD, op, ..] => (Prefix::DD, op),
+        [0xFD, op, ..] => (Prefix::FD, op),
+        [op, ..] => (Prefix::None, op),
+    }
+}
+
+fn index(prefix: Prefix, opcode: u8) -> usize {
+    let table = TABLES.iter().position(|&p| p == prefix).unwrap_or(0);
+    table * 256 + opcode as usize
+}
+
+// Prefix bytes, with `nn` standing in for the DDCB / FDCB displacement
+fn prefix_bytes(prefix: Prefix) -> &'static str {
+    match prefix {
+        Prefix::None => "",
+        Prefix::CB => "CB ",
+        Prefix::ED => "ED ",
+        Prefix::DD => "DD ",
+        Prefix::FD => "FD ",
+        Prefix::DDCB => "DD CB nn ",
+        Prefix::FDCB => "FD CB nn ",
+    }
+}
+
+impl OpcodeProfile {
+    #[inline]
+    pub(crate) fn record(&mut self, bytes: &[u8; 4], cycles: usize) {
+        let (prefix, opcode) = key(bytes);
+        let stats = &mut self.stats[index(prefix, opcode)];
+        stats.count += 1;
+        stats.cycles += cycles as u64;
+    }
+
+    pub fn get(&self, prefix: Prefix, opcode: u8) -> OpcodeStats {
+        self.stats[index(prefix, opcode)]
+    }
+
+    pub fn total(&self) -> OpcodeStats {
+        self.stats
+            .iter()
+            .fold(OpcodeStats::default(), |total, s| OpcodeStats {
+                count: total.count + s.count,
+                cycles: total.cycles + s.cycles,
+            })
+    }
+
+    pub fn clear(&mut self) {
+        self.stats
+            .iter_mut()
+            .for_each(|s| *s = OpcodeStats::default());
+    }
+
+    // Executed opcodes, most frequent first
+    pub fn sorted(&self) -> Vec<(Prefix, u8, OpcodeStats)> {
+        let mut sorted: Vec<(Prefix, u8, OpcodeStats)> = TABLES
+            .iter()
+            .flat_map(|&prefix| (0..=255).map(move |op| (prefix, op)))
+            .map(|(prefix, op)| (prefix, op, self.get(prefix, op)))
+            .filter(|(_, _, stats)| stats.count > 0)
+            .collect();
+        sorted.sort_by_key(|&(_, _, stats)| Reverse(stats.count));
+        sorted
+    }
+
+    // The `limit` most frequent opcodes with their share of the executed T states
+    pub fn summary(&self, limit: usize) -> String {
+        let total = self.total();
+        let mut out = format!(
+            "{:>12} {:>14} {:>6}  {:<14}{}\n",
+            "count", "cycles", "time", "opcode", "instruction"
+        );
+        for (prefix, op, stats) in self.sorted().into_iter().take(limit) {
+            let share = stats.cycles as f64 * 100.0 / total.cycles.max(1) as f64;
+            let opcode = format!("{}{:02X}", prefix_bytes(prefix), op);
+            out.push_str(&format!(
+                "{:>12} {:>14} {:>5.1}%  {:<14}{}\n",
+                stats.count,
+                stats.cycles,
+                share,
+                opcode,
+                mnemonic(prefix, op)
+            ));
+        }
+        out.push_str(&format!(
+            "{:>12} {:>14}         total\n",
+            total.count, total.cycles
+        ));
+        out
+    }
+}
+
+// Disassembly of the opcode with zeroed operands
+fn mnemonic(prefix: Prefix, opcode: u8) -> String {
+    let bytes: &[u8] = match prefix {
+        Prefix::None => &[opcode, 0, 0, 0],
+        Prefix::CB => &[0xCB, opcode, 0, 0],
+        Prefix::ED => &[0xED, opcode, 0, 0],
+        Prefix::DD => &[0xDD, opcode, 0, 0],
+        Prefix::FD => &[0xFD, opcode, 0, 0],
+        Prefix::DDCB => &[0xDD, 0xCB, 0, opcode],
+        Prefix::FDCB => &[0xFD, 0xCB, 0, opcode],
+    };
+    match InstructionInfo::lookup(prefix, opcode).and(Instruction::decode_bytes(bytes)) {
+        Some(instruction) => instruction.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct AccessCounts {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+// Memory reads and writes made by the CPU, counted per address. Opcode fetches aren't
+// counted, operand and data accesses are. Regions are only used for reporting, so they can
+// be added or changed at any time.
+pub struct MemoryStats {
+    reads: Vec<Cell<u32>>,
+    writes: Vec<u32>,
+    pub regions: Vec<(String, RangeInclusive<u16>)>,
+}
+
+impl Default for MemoryStats {
+    fn default() -> Self {
+        Self {
+            reads: vec![Cell::new(0); ADDRESS_SPACE],
+            writes: vec![0; ADDRESS_SPACE],
+            regions: Vec::new(),
+        }
+    }
+}
+
+impl MemoryStats {
+    pub fn add_region(&mut self, name: &str, range: RangeInclusive<u16>) {
+        self.regions.push((name.to_string(), range));
+    }
+
+    #[inline]
+    pub(crate) fn read(&self, addr: u16) {
+        let count = &self.reads[addr as usize];
+        count.set(count.get().saturating_add(1));
+    }
+
+    #[inline]
+    pub(crate) fn write(&mut self, addr: u16) {
+        let count = &mut self.writes[addr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn get(&self, addr: u16) -> AccessCounts {
+        AccessCounts {
+            reads: self.reads[addr as usize].get() as u64,
+            writes: self.writes[addr as usize] as u64,
+        }
+    }
+
+    pub fn range(&self, range: RangeInclusive<u16>) -> AccessCounts {
+        range.fold(AccessCounts::default(), |total, addr| {
+            let counts = self.get(addr);
+            AccessCounts {
+                reads: total.reads + counts.reads,
+                writes: total.writes + counts.writes,
+            }
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.iter().for_each(|count| count.set(0));
+        self.writes.iter_mut().for_each(|count| *count = 0);
+    }
+
+    // Most accessed addresses first
+    pub fn hottest(&self, limit: usize) -> Vec<(u16, AccessCounts)> {
+        let mut hot: Vec<(u16, AccessCounts)> = (0..=0xFFFF)
+            .map(|addr| (addr, self.get(addr)))
+            .filter(|(_, counts)| counts.reads + counts.writes > 0)
+            .collect();
+        hot.sort_by_key(|&(_, counts)| Reverse(counts.reads + counts.writes));
+        hot.truncate(limit);
+        hot
+    }
+
+    // Totals per region (the whole address space if none were added) followed by the
+    // `limit` most accessed addresses
+    pub fn summary(&self, limit: usize) -> String {
+        let all = [("all".to_string(), 0..=0xFFFF)];
+        let regions = if self.regions.is_empty() {
+            &all[..]
+        } else {
+            &self.regions[..]
+        };
+        let mut out = format!(
+            "{:<12} {:<9}  {:>12} {:>12}\n",
+            "region", "range", "reads", "writes"
+        );
+        for (name, range) in regions {
+            let counts = self.range(range.clone());
+            out.push_str(&format!(
+                "{:<12} {:04X}-{:04X}  {:>12} {:>12}\n",
+                name,
+                range.start(),
+                range.end(),
+                counts.reads,
+                counts.writes
+            ));
+        }
+        for (addr, counts) in self.hottest(limit) {
+            out.push_str(&format!(
+                "{:<12} {:04X}       {:>12} {:>12}\n",
+                "", addr, counts.reads, counts.writes
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessCounts, MemoryStats, OpcodeProfile, OpcodeStats};
+    use crate::instruction_info::Prefix;
+
+    #[test]
+    fn counts_per_table() {
+        let mut profile = OpcodeProfile::default();
+        profile.record(&[0x3D, 0x00, 0x00, 0x00], 4);
+        profile.record(&[0x3D, 0xC2, 0x00, 0x01], 4);
+        profile.record(&[0xDD, 0xCB, 0x05, 0x46], 20);
+        profile.record(&[0xED, 0xB0, 0x00, 0x00], 21);
+        assert_eq!(
+            profile.get(Prefix::None, 0x3D),
+            OpcodeStats {
+                count: 2,
+                cycles: 8
+            }
+        );
+        assert_eq!(profile.get(Prefix::DDCB, 0x46).count, 1);
+        assert_eq!(profile.get(Prefix::CB, 0x46).count, 0);
+        assert_eq!(profile.total().cycles, 49);
+
+        let sorted = profile.sorted();
+        assert_eq!((sorted[0].0, sorted[0].1), (Prefix::None, 0x3D));
+        assert_eq!(sorted.len(), 3);
+
+        let summary = profile.summary(2);
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(
+            lines[1].ends_with("16.3%  3D            DEC A"),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[3].trim_start().starts_with("4"));
+
+        profile.clear();
+        assert_eq!(profile.total(), OpcodeStats::default());
+    }
+
+    #[test]
+    fn memory_regions() {
+        let mut stats = MemoryStats::default();
+        stats.add_region("rom", 0x0000..=0x3FFF);
+        stats.add_region("ram", 0x4000..=0xFFFF);
+        stats.read(0x0010);
+        stats.read(0x0010);
+        stats.write(0x0010);
+        stats.write(0x8000);
+        assert_eq!(
+            stats.get(0x0010),
+            AccessCounts {
+                reads: 2,
+                writes: 1
+            }
+        );
+        assert_eq!(stats.range(0x4000..=0xFFFF).writes, 1);
+        assert_eq!(
+            stats.hottest(1),
+            vec![(
+                0x0010,
+                AccessCounts {
+                    reads: 2,
+                    writes: 1
+                }
+            )]
+        );
+        assert_eq!(
+            stats.summary(2),
+            "region       range             reads       writes\n\
+             rom          0000-3FFF             2            1\n\
+             ram          4000-FFFF             0            1\n\
+             \x20            0010                  2            1\n\
+             \x20            8000                  0            1\n"
+        );
+        stats.clear();
+        assert_eq!(stats.range(0x0000..=0xFFFF), AccessCounts::default());
+    }
+}
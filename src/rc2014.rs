@@ -0,0 +1,165 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::device::DeviceRef;
+use crate::interconnect::{Interconnect, Preset};
+use crate::memory::{Memory, Page, PAGE_SIZE};
+use crate::peripherals::serial::SerialBackend;
+use crate::peripherals::sio::CHANNEL_A;
+use crate::peripherals::{Acia, Sio};
+
+pub const CLOCK: usize = 7_372_800;
+pub const ROM_SIZE: usize = 0x8000;
+// Any write with bit 0 set pages the ROM out of the pageable variant, clear pages it back
+pub const PAGE_PORT: u8 = 0x38;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Console {
+    // 6850 serial module, decoded at 80-BF
+    #[default]
+    Acia,
+    // Dual serial SIO/2 module at 80-83, the console on channel A
+    Sio,
+}
+
+// An RC2014 with the console on the standard ports and a 7.3728MHz clock. The classic
+// board has 32K of ROM at 0000-7FFF (smaller images are mirrored, as the ROM module's
+// upper address lines come from jumpers) and 32K of RAM above. The pageable variant has
+// 64K of RAM with the ROM over the lower 32K until it's paged out through port 38, which
+// is how CP/M gets a RAM page zero.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Rc2014 {
+    pub pageable: bool,
+    pub console: Console,
+}
+
+// Comma separated options as given after the ROM file: "pageable", "sio" or "acia"
+impl FromStr for Rc2014 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut machine = Rc2014::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option {
+                "pageable" => machine.pageable = true,
+                "sio" => machine.console = Console::Sio,
+                "acia" => machine.console = Console::Acia,
+                _ => return Err(format!("Unknown RC2014 option: {}", option)),
+            }
+        }
+        Ok(machine)
+    }
+}
+
+impl Rc2014 {
+    // The machine at reset with `rom` installed and the console connected to `backend`
+    pub fn build<B: SerialBackend + 'static>(
+        &self,
+        rom: &[u8],
+        backend: B,
+    ) -> Result<Interconnect, String> {
+        if rom.is_empty() || rom.len() > ROM_SIZE {
+            return Err(format!(
+                "ROM image is {} bytes, expected up to {}",
+                rom.len(),
+                ROM_SIZE
+            ));
+        }
+        // Unused ROM space reads as erased EPROM
+        let mut image = match ROM_SIZE.is_multiple_of(rom.len()) {
+            true => rom.repeat(ROM_SIZE / rom.len()),
+            false => rom.to_vec(),
+        };
+        image.resize(ROM_SIZE, 0xFF);
+        let mut memory = Memory::default();
+        memory.load_rom(0x0000, &image);
+        if self.pageable {
+            memory.add_paging_register(0x00FF, PAGE_PORT as u16, |memory, value| {
+                for page in 0..ROM_SIZE / PAGE_SIZE {
+                    let storage = match value & 1 {
+                        0 => Page::Rom(page * PAGE_SIZE),
+                        _ => Page::Ram(page * PAGE_SIZE),
+                    };
+                    memory.map_page(page, storage);
+                }
+            });
+        }
+
+        let console: DeviceRef = match self.console {
+            Console::Acia => {
+                let mut acia = Acia::default();
+                acia.connect(backend);
+                Rc::new(RefCell::new(acia))
+            }
+            Console::Sio => {
+                let mut sio = Sio::default();
+                sio.connect(CHANNEL_A, backend);
+                Rc::new(RefCell::new(sio))
+            }
+        };
+        let ports = match self.console {
+            Console::Acia => 0x80..=0xBF,
+            Console::Sio => 0x80..=0x83,
+        };
+        let mut i = Interconnect::builder()
+            .preset(Preset::Custom(Box::new(memory)))
+            .clock_speed(CLOCK)
+            .port_device(ports, console)
+            .build();
+        i.cpu.cpm_compat = false;
+        Ok(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Console, Rc2014};
+    use crate::memory::MemoryRW;
+    use crate::peripherals::serial::Pipe;
+
+    // Sends "OK" through the ACIA: 0000: LD A, 16; OUT (80), A; LD HL, 0023
+    // next: LD A, (HL); OR A; JP Z, done; wait: IN A, (80); AND 2; JP Z, wait
+    // LD A, (HL); OUT (81), A; INC HL; JP next; done: JP done
+    const HELLO: [u8; 38] = [
+        0x3E, 0x16, 0xD3, 0x80, 0x21, 0x23, 0x00, 0x7E, 0xB7, 0xCA, 0x20, 0x00, 0xDB, 0x80, 0xE6,
+        0x02, 0xCA, 0x0C, 0x00, 0x7E, 0xD3, 0x81, 0x23, 0xC3, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xC3, 0x20, 0x00, b'O', b'K', 0x00,
+    ];
+
+    #[test]
+    fn acia_console() {
+        let machine: Rc2014 = "".parse().unwrap();
+        assert_eq!(machine.console, Console::Acia);
+        let pipe = Pipe::default();
+        let mut rom = HELLO.to_vec();
+        rom.resize(0x2000, 0xFF);
+        let mut i = machine.build(&rom, pipe.clone()).unwrap();
+        for _ in 0..500 {
+            i.step();
+        }
+        assert_eq!(pipe.take_output(), b"OK");
+        // An 8K ROM shows up four times, then RAM
+        assert_eq!(i.cpu.memory[0x6000], 0x3E);
+        i.cpu.write8(0x8000, 0x12);
+        assert_eq!(i.cpu.read8(0x8000), 0x12);
+        assert!("pageable,floppy".parse::<Rc2014>().is_err());
+    }
+
+    #[test]
+    fn pageable_rom() {
+        // LD A, 1; OUT (38), A, then the code runs on in RAM
+        let rom = [0x3E, 0x01, 0xD3, 0x38];
+        let machine: Rc2014 = "pageable,sio".parse().unwrap();
+        let mut i = machine.build(&rom, Pipe::default()).unwrap();
+        i.cpu.write8(0x1000, 0x55);
+        assert_eq!(i.cpu.read8(0x1000), 0x3E);
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.read8(0x0000), 0x00);
+        i.cpu.write8(0x1000, 0x55);
+        assert_eq!(i.cpu.read8(0x1000), 0x55);
+        i.cpu.memory.paging_write(0x38, 0);
+        assert_eq!(i.cpu.read8(0x1000), 0x3E);
+    }
+}
@@ -0,0 +1,233 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+use crate::expr;
+use crate::formatter::HexBytes;
+use crate::interconnect::Interconnect;
+use crate::memory::parse_origin;
+
+// Line based remote control over TCP, for running Z80 code from build pipelines and
+// checking the results without linking against the crate. Every command gets a single
+// line reply, `OK` followed by the result or `ERR` followed by a message. Addresses, lengths
+// and values are hex (with or without `0x` / `$`), cycle and instruction counts decimal.
+//
+//   load <addr> <hex bytes>       write bytes to memory, e.g. `load 0100 3E05C9`
+//   loadfile <file>[@addr]        load a binary from the server's file system (default 0000),
+//                                 or a HEX / S-record file at its own addresses
+//   run <cycles>                  run for at least this many cycles, replies `OK <cycles>`
+//                                 or `OK <cycles> <stop reason>` if stopped early
+//   step [n]                      execute n instructions
+//   mem <addr> <len>              replies `OK <hex bytes>`
+//   regs                          replies `OK PC=0100 SP=FFFF AF=.. .. CYC=<decimal>`
+//   reg <name> [value]            read or set a register, e.g. `reg HL 8000`
+//   break <addr> | delete <addr>  add or remove a breakpoint
+//   close                         end this connection
+//   quit                          end this connection and stop the server
+pub fn serve(i: &mut Interconnect, listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let reply = match line.trim() {
+                "quit" => {
+                    writeln!(out, "OK")?;
+                    return Ok(());
+                }
+                "close" => {
+                    writeln!(out, "OK")?;
+                    break;
+                }
+                line => match handle(i, line) {
+                    Ok(reply) if reply.is_empty() => "OK".to_string(),
+                    Ok(reply) => format!("OK {}", reply),
+                    Err(e) => format!("ERR {}", e),
+                },
+            };
+            writeln!(out, "{}", reply)?;
+        }
+    }
+    Ok(())
+}
+
+// Executes one command, returns what follows `OK` in the reply
+pub fn handle(i: &mut Interconnect, line: &str) -> Result<String, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let hex = |n: usize| -> Result<u16, String> {
+        let value = args.get(n).ok_or("Missing argument")?;
+        let digits = value.trim_start_matches("0x").trim_start_matches('$');
+        u16::from_str_radix(digits, 16).map_err(|_| format!("Bad hex number `{}`", value))
+    };
+    match args.first().copied() {
+        Some("load") => {
+            let addr = hex(1)?;
+            let bytes = parse_bytes(args.get(2).copied().unwrap_or(""))?;
+            i.cpu.memory.load_slice(addr, &bytes);
+            Ok(String::new())
+        }
+        Some("loadfile") => {
+            let (path, addr) = parse_origin(args.get(1).ok_or("Missing file name")?);
+            let size = i
+                .cpu
+                .memory
+                .load_file(path, addr.unwrap_or(0))
+                .map_err(|e| e.to_string())?;
+            Ok(size.to_string())
+        }
+        Some("run") => {
+            let target: usize = args
+                .get(1)
+                .and_then(|cycles| cycles.parse().ok())
+                .ok_or("Expected a cycle count")?;
+            let mut cycles = 0;
+            while cycles < target {
+                let result = i.step();
+                cycles += result.cycles;
+                if let Some(stop) = result.stop {
+                    return Ok(format!("{} {}", cycles, stop));
+                }
+            }
+            Ok(cycles.to_string())
+        }
+        Some("step") => {
+            let count: usize = match args.get(1) {
+                Some(count) => count.parse().map_err(|_| "Expected a count")?,
+                None => 1,
+            };
+            for _ in 0..count {
+                if let Some(stop) = i.step().stop {
+                    return Ok(stop.to_string());
+                }
+            }
+            Ok(String::new())
+        }
+        Some("mem") => {
+            let addr = hex(1)?;
+            let len = hex(2)?;
+            let bytes: Vec<u8> = (0..len).map(|n| i.peek(addr.wrapping_add(n))).collect();
+            Ok(HexBytes(&bytes, "").to_string())
+        }
+        Some("regs") => Ok(registers(i)),
+        Some("reg") => {
+            let name = args.get(1).ok_or("Missing register name")?;
+            if args.len() > 2 {
+                if !expr::set_register(&mut i.cpu, name, hex(2)?) {
+                    return Err(format!("Unknown register {}", name));
+                }
+                return Ok(String::new());
+            }
+            let wide = PAIRS.contains(&name.to_ascii_uppercase().as_str());
+            match expr::register(&i.cpu, name) {
+                Some(value) if wide => Ok(format!("{:04X}", value)),
+                Some(value) => Ok(format!("{:02X}", value)),
+                None => Err(format!("Unknown register {}", name)),
+            }
+        }
+        Some("break") => {
+            i.breakpoints.add(hex(1)?);
+            Ok(String::new())
+        }
+        Some("delete") => match i.breakpoints.remove(hex(1)?) {
+            true => Ok(String::new()),
+            false => Err("No breakpoint there".to_string()),
+        },
+        Some(command) => Err(format!("Unknown command `{}`", command)),
+        None => Err("Empty command".to_string()),
+    }
+}
+
+// 16-bit registers, in the order `regs` shows them
+const PAIRS: [&str; 12] = [
+    "PC", "SP", "AF", "BC", "DE", "HL", "IX", "IY", "AF'", "BC'", "DE'", "HL'",
+];
+
+fn registers(i: &Interconnect) -> String {
+    let mut out = String::new();
+    for name in PAIRS {
+        let value = expr::register(&i.cpu, name).unwrap_or(0);
+        out.push_str(&format!("{}={:04X} ", name, value));
+    }
+    out.push_str(&format!(
+        "I={:02X} R={:02X} IM={} IFF={}{} CYC={}",
+        i.cpu.reg.i,
+        i.cpu.reg.r,
+        i.cpu.int.mode,
+        i.cpu.int.iff1 as u8,
+        i.cpu.int.iff2 as u8,
+        i.cpu.cycles
+    ));
+    out
+}
+
+fn parse_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return Err("Expected an even number of hex digits".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| {
+            hex.get(n..n + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("Bad hex bytes `{}`", hex))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, serve};
+    use crate::interconnect::{Interconnect, Preset};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn commands() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        // LD A, 5; LD HL, 0x8000; LD (HL), A; NOP
+        assert_eq!(handle(&mut i, "load 0100 3E05210080770000"), Ok("".into()));
+        assert_eq!(handle(&mut i, "break 0106"), Ok("".into()));
+        assert_eq!(
+            handle(&mut i, "run 1000"),
+            Ok("24 Breakpoint at 0106".into())
+        );
+        assert_eq!(handle(&mut i, "step"), Ok("".into()));
+        assert_eq!(handle(&mut i, "mem 8000 2"), Ok("0500".into()));
+        assert_eq!(handle(&mut i, "reg HL"), Ok("8000".into()));
+        assert_eq!(handle(&mut i, "reg A"), Ok("05".into()));
+        assert_eq!(handle(&mut i, "reg af"), Ok("05FF".into()));
+        assert_eq!(handle(&mut i, "reg BC 0x1234"), Ok("".into()));
+        assert!(handle(&mut i, "regs")
+            .unwrap()
+            .starts_with("PC=0107 SP=FFFF"));
+        assert!(handle(&mut i, "regs").unwrap().contains("BC=1234"));
+        assert_eq!(handle(&mut i, "run 8"), Ok("8".into()));
+        assert!(handle(&mut i, "load 0100 3E0").is_err());
+        assert!(handle(&mut i, "reg XY").is_err());
+        assert!(handle(&mut i, "bogus").is_err());
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut replies = Vec::new();
+            for command in ["load 0100 3E42", "step", "reg A", "nope", "quit"] {
+                writeln!(stream, "{}", command).unwrap();
+                let mut reply = String::new();
+                reader.read_line(&mut reply).unwrap();
+                replies.push(reply.trim_end().to_string());
+            }
+            replies
+        });
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        serve(&mut i, listener).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            ["OK", "OK", "OK 42", "ERR Unknown command `nope`", "OK"]
+        );
+    }
+}
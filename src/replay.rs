@@ -0,0 +1,312 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Something the CPU got from outside that execution depends on
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Input {
+    // Value read by an IN from `port`
+    Port { port: u16, value: u8 },
+    // Value read from a memory mapped device
+    Mem { addr: u16, value: u8 },
+    // Maskable interrupt accepted with `vector` on the bus
+    Int(u8),
+    // Non maskable interrupt accepted
+    Nmi,
+}
+
+// An input and the CPU cycle count when it happened
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub cycles: u64,
+    pub input: Input,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.input {
+            Input::Port { port, value } => {
+                write!(f, "{} in {:04X} {:02X}", self.cycles, port, value)
+            }
+            Input::Mem { addr, value } => {
+                write!(f, "{} mem {:04X} {:02X}", self.cycles, addr, value)
+            }
+            Input::Int(vector) => write!(f, "{} int {:02X}", self.cycles, vector),
+            Input::Nmi => write!(f, "{} nmi", self.cycles),
+        }
+    }
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let hex16 = |n: usize| u16::from_str_radix(tokens.get(n)?, 16).ok();
+        let hex8 = |n: usize| u8::from_str_radix(tokens.get(n)?, 16).ok();
+        let input = match (tokens.get(1).copied(), tokens.len()) {
+            (Some("in"), 4) => Input::Port {
+                port: hex16(2)?,
+                value: hex8(3)?,
+            },
+            (Some("mem"), 4) => Input::Mem {
+                addr: hex16(2)?,
+                value: hex8(3)?,
+            },
+            (Some("int"), 3) => Input::Int(hex8(2)?),
+            (Some("nmi"), 2) => Input::Nmi,
+            _ => return None,
+        };
+        Some(Self {
+            cycles: tokens[0].parse().ok()?,
+            input,
+        })
+    }
+}
+
+// Log of the inputs the CPU received, in order. Execution from a given state can be
+// repeated exactly by feeding the inputs after that state back in: while replaying, device
+// reads return the logged values and only the logged interrupts are accepted, when they
+// were accepted in the recording. Interrupts are logged when accepted rather than
+// requested so the timing doesn't depend on when a device raised them.
+//
+// Replay goes on past the end of the log, with no interrupts accepted, until `stop_replay`
+// or the first read that doesn't match the next record (execution went another way, e.g.
+// after editing memory in the debugger). The rest of the log is dropped then and execution
+// continues live, recording if enabled.
+//
+// Saved logs are text, one record per line: the cycle count, then `in port value`,
+// `mem addr value`, `int vector` or `nmi` with the values in hex. Replaying one only makes
+// sense from the state the recording started in, i.e. the same machine and ROMs from reset.
+//
+// Reads happen through `&self` (memory reads don't take `&mut Cpu`), hence the cells.
+#[derive(Debug, Default)]
+pub struct InputLog {
+    pub recording: bool,
+    // Keep every record, even those rewinding doesn't need any more. Set when the log is
+    // going to be saved.
+    pub keep: bool,
+    records: RefCell<Vec<Record>>,
+    // Index of the next record while replaying
+    replay: Cell<Option<usize>>,
+}
+
+impl InputLog {
+    pub fn active(&self) -> bool {
+        self.recording || self.replaying()
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.replay.get().is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.borrow().is_empty()
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        self.records.borrow().clone()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.get_mut().clear();
+        self.replay.set(None);
+    }
+
+    // Replays the records from index `pos` on
+    pub fn replay_from(&mut self, pos: usize) {
+        self.replay.set(Some(pos.min(self.len())));
+    }
+
+    // Records not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.replay.get().map_or(0, |pos| self.len() - pos)
+    }
+
+    // Goes live, dropping the records not replayed yet
+    pub fn stop_replay(&mut self) {
+        if let Some(pos) = self.replay.get() {
+            self.diverged(pos);
+        }
+    }
+
+    // Drops the first `count` records
+    pub fn trim(&mut self, count: usize) {
+        let records = self.records.get_mut();
+        let count = count.min(records.len());
+        records.drain(..count);
+        self.replay
+            .set(self.replay.get().and_then(|pos| pos.checked_sub(count)));
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut records = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = Record::parse(line)
+                .ok_or_else(|| format!("line {}: can't parse `{}`", n + 1, line))?;
+            records.push(record);
+        }
+        Ok(Self {
+            records: RefCell::new(records),
+            ..Self::default()
+        })
+    }
+
+    // Reads a saved log, ready to replay from the start
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut log = Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        log.replay_from(0);
+        Ok(log)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut text = String::from("# z80-rs input log\n");
+        for record in self.records.borrow().iter() {
+            text.push_str(&format!("{}\n", record));
+        }
+        fs::write(path, text)
+    }
+
+    // Value read from an IO port, `live` reads the device when not replaying
+    pub fn port<F: FnOnce() -> u8>(&self, cycles: u64, port: u16, live: F) -> u8 {
+        self.read(cycles, |value| Input::Port { port, value }, live)
+    }
+
+    // Value read from a memory mapped device
+    pub fn mem<F: FnOnce() -> u8>(&self, cycles: u64, addr: u16, live: F) -> u8 {
+        self.read(cycles, |value| Input::Mem { addr, value }, live)
+    }
+
+    // The interrupt accepted at `cycles` in the recording, if any
+    pub fn take_interrupt(&self, cycles: u64) -> Option<Input> {
+        let pos = self.replay.get()?;
+        let record = self.records.borrow().get(pos).copied()?;
+        match record.input {
+            Input::Int(_) | Input::Nmi if record.cycles == cycles => {
+                self.replay.set(Some(pos + 1));
+                Some(record.input)
+            }
+            _ => None,
+        }
+    }
+
+    // Logs an input if recording
+    pub fn record(&self, cycles: u64, input: Input) {
+        if self.recording {
+            self.records.borrow_mut().push(Record { cycles, input });
+        }
+    }
+
+    fn read<M, F>(&self, cycles: u64, make: M, live: F) -> u8
+    where
+        M: Fn(u8) -> Input,
+        F: FnOnce() -> u8,
+    {
+        if let Some(pos) = self.replay.get() {
+            let record = self.records.borrow().get(pos).copied();
+            match record {
+                Some(Record {
+                    cycles: at,
+                    input: input @ (Input::Port { value, .. } | Input::Mem { value, .. }),
+                }) if at == cycles && input == make(value) => {
+                    self.replay.set(Some(pos + 1));
+                    return value;
+                }
+                _ => self.diverged(pos),
+            }
+        }
+        let value = live();
+        self.record(cycles, make(value));
+        value
+    }
+
+    fn diverged(&self, pos: usize) {
+        self.records.borrow_mut().truncate(pos);
+        self.replay.set(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Input, InputLog, Record};
+
+    #[test]
+    fn record_and_replay() {
+        let mut log = InputLog {
+            recording: true,
+            ..InputLog::default()
+        };
+        assert_eq!(log.port(10, 0x01, || 0x55), 0x55);
+        log.record(20, Input::Int(0xFF));
+        assert_eq!(log.mem(30, 0x5000, || 0x0F), 0x0F);
+        assert_eq!(log.len(), 3);
+
+        log.replay_from(0);
+        assert_eq!(log.port(10, 0x01, || 0), 0x55);
+        assert_eq!(log.take_interrupt(15), None);
+        assert_eq!(log.take_interrupt(20), Some(Input::Int(0xFF)));
+        assert_eq!(log.mem(30, 0x5000, || 0), 0x0F);
+        assert_eq!(log.remaining(), 0);
+        assert_eq!(log.take_interrupt(40), None);
+        assert!(log.replaying());
+        assert_eq!(log.len(), 3);
+
+        // A read that doesn't match ends the replay and drops the rest of the log
+        log.replay_from(1);
+        log.take_interrupt(20);
+        assert_eq!(log.port(30, 0x02, || 0x77), 0x77);
+        assert!(!log.replaying());
+        assert_eq!(
+            log.records()[2].input,
+            Input::Port {
+                port: 2,
+                value: 0x77
+            }
+        );
+
+        log.trim(2);
+        assert_eq!(log.len(), 1);
+    }
+
+    #[test]
+    fn text_format() {
+        let text = "# z80-rs input log\n\
+                    120 in 00FE 1F\n\
+                    4000 int FF\n\
+                    4100 nmi\n\
+                    5000 mem 5000 0F\n";
+        let log = InputLog::parse(text).unwrap();
+        assert_eq!(
+            log.records()[..2],
+            [
+                Record {
+                    cycles: 120,
+                    input: Input::Port {
+                        port: 0xFE,
+                        value: 0x1F
+                    }
+                },
+                Record {
+                    cycles: 4000,
+                    input: Input::Int(0xFF)
+                }
+            ]
+        );
+        let lines: Vec<String> = log.records().iter().map(Record::to_string).collect();
+        assert_eq!(
+            lines.join("\n"),
+            text.lines().skip(1).collect::<Vec<_>>().join("\n")
+        );
+        assert!(InputLog::parse("12 in 00FE").is_err());
+        assert!(InputLog::parse("x nmi").is_err());
+    }
+}
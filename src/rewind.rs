@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Cpu;
+use crate::memory::Storage;
+use crate::snapshot::Snapshot;
+
+// The CPU and memory state at a step
+struct Checkpoint {
+    // `Rewind::steps` when it was taken
+    step: u64,
+    // Length of the input log at that point, replay starts from there
+    inputs: usize,
+    // Registers and interrupt state
+    registers: Snapshot,
+    // All RAM and ROM banks and the page map, not just what's mapped in
+    storage: Storage,
+    // `Memory::paging_writes` when it was taken
+    paging_writes: u64,
+}
+
+// Periodic checkpoints that let the debugger go back in time. Going back restores the latest
+// checkpoint before the target and executes forward to it again with the CPU's input log
+// replaying, so registers, every memory bank and the page map end up exactly as they were.
+//
+// Only the CPU and memory are restored. Devices keep their state and are ticked again for
+// the replayed instructions, and output written by them (or by PC hooks) is repeated. While
+// replaying, interrupts come from the input log rather than the devices. Paging registers
+// latch what they select like any device, so a write to one drops the checkpoints before it
+// and execution can't go back past it.
+pub struct Rewind {
+    // Cycles between checkpoints
+    pub interval: usize,
+    // Number of checkpoints kept, the oldest are dropped beyond that
+    pub capacity: usize,
+    // Steps executed since rewinding was enabled
+    pub steps: u64,
+    // Furthest step reached, inputs are replayed up to here after going back
+    horizon: u64,
+    checkpoints: VecDeque<Checkpoint>,
+    last: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: usize, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity: capacity.max(1),
+            steps: 0,
+            horizon: 0,
+            checkpoints: VecDeque::new(),
+            last: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    // Step of the earliest state that can be gone back to
+    pub fn oldest(&self) -> Option<u64> {
+        self.checkpoints.front().map(|c| c.step)
+    }
+
+    // Takes a checkpoint if one is due, called before each step
+    pub(crate) fn before_step(&mut self, cpu: &mut Cpu) {
+        let paged = self
+            .checkpoints
+            .back()
+            .is_some_and(|c| c.paging_writes != cpu.memory.paging_writes());
+        if paged {
+            if let Some(last) = self.checkpoints.back().filter(|_| !cpu.input.keep) {
+                cpu.input.trim(last.inputs);
+            }
+            self.checkpoints.clear();
+        }
+        let due = match self.checkpoints.back() {
+            Some(last) => {
+                last.step != self.steps && cpu.cycles.wrapping_sub(self.last) >= self.interval
+            }
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        if self.checkpoints.len() == self.capacity {
+            self.checkpoints.pop_front();
+            // Inputs before the oldest checkpoint can't be replayed any more
+            if let Some(oldest) = self.checkpoints.front().filter(|_| !cpu.input.keep) {
+                let count = oldest.inputs;
+                cpu.input.trim(count);
+                self.checkpoints.iter_mut().for_each(|c| c.inputs -= count);
+            }
+        }
+        self.last = cpu.cycles;
+        let storage = cpu
+            .memory
+            .storage(self.checkpoints.back().map(|c| &c.storage));
+        self.checkpoints.push_back(Checkpoint {
+            step: self.steps,
+            inputs: cpu.input.len(),
+            registers: Snapshot::capture_registers(cpu),
+            storage,
+            paging_writes: cpu.memory.paging_writes(),
+        });
+    }
+
+    pub(crate) fn after_step(&mut self) {
+        self.steps += 1;
+        self.horizon = self.horizon.max(self.steps);
+    }
+
+    // True while replaying up to where execution was rewound from
+    pub(crate) fn behind(&self) -> bool {
+        self.steps < self.horizon
+    }
+
+    // Step of the `count`th checkpoint before the current step
+    pub fn checkpoint_back(&self, count: usize) -> Option<u64> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .filter(|c| c.step < self.steps)
+            .nth(count.checked_sub(1)?)
+            .map(|c| c.step)
+    }
+
+    // Restores the latest checkpoint at or before step `target` and starts replaying the
+    // inputs that followed it. Later checkpoints are dropped, they're taken again as
+    // execution moves forward. Returns false if `target` is before the oldest checkpoint.
+    pub(crate) fn restore(&mut self, cpu: &mut Cpu, target: u64) -> bool {
+        let index = match self.checkpoints.iter().rposition(|c| c.step <= target) {
+            Some(index) => index,
+            None => return false,
+        };
+        if !cpu.input.replaying() {
+            self.horizon = self.steps;
+        }
+        self.checkpoints.truncate(index + 1);
+        let checkpoint = &self.checkpoints[index];
+        cpu.memory.restore_storage(&checkpoint.storage);
+        checkpoint.registers.restore_registers(cpu);
+        cpu.cycles = checkpoint.registers.cycles as usize;
+        cpu.input.replay_from(checkpoint.inputs);
+        self.steps = checkpoint.step;
+        self.last = cpu.cycles;
+        true
+    }
+}
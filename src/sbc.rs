@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::device::DeviceRef;
+use crate::interconnect::{Interconnect, Preset};
+use crate::peripherals::serial::SerialBackend;
+use crate::peripherals::sio::CHANNEL_A;
+use crate::peripherals::{Acia, Ctc, Sio};
+
+pub const CLOCK: usize = 7_372_800;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Uart {
+    // 6850, control / status then data
+    #[default]
+    Acia,
+    // SIO/2 with the console on channel A, A control, A data, B control, B data
+    Sio,
+}
+
+impl Uart {
+    fn ports(&self) -> u8 {
+        match self {
+            Uart::Acia => 2,
+            Uart::Sio => 4,
+        }
+    }
+}
+
+// A homebrew single board computer with nothing but 64K of RAM, a serial console and
+// optionally a CTC, for running firmware without writing a machine file. The firmware is
+// loaded into the RAM and runs from 0000 at 7.3728MHz. The chips only decode the low
+// address lines, so the console's ports start on a multiple of 2 (ACIA) or 4 (SIO) and
+// the CTC's on a multiple of 4. The CTC's channels run as timers off the CPU clock, it's
+// after the SIO on the interrupt daisy chain.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Sbc {
+    pub console: Uart,
+    pub port: u8,
+    pub ctc: Option<u8>,
+}
+
+impl Default for Sbc {
+    fn default() -> Self {
+        Self {
+            console: Uart::Acia,
+            port: 0x80,
+            ctc: None,
+        }
+    }
+}
+
+// Comma separated options: "acia" or "sio" with an optional "@<hex port>" (80 by default)
+// for the console, "ctc@<hex port>" to add a CTC
+impl FromStr for Sbc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut machine = Sbc::default();
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let (name, port) = match option.split_once('@') {
+                Some((name, port)) => {
+                    let port = port.trim_start_matches("0x");
+                    let port =
+                        u8::from_str_radix(port, 16).map_err(|_| format!("Bad port `{}`", port))?;
+                    (name, Some(port))
+                }
+                None => (option, None),
+            };
+            let aligned = |port: u8, ports: u8| match port % ports {
+                0 => Ok(port),
+                _ => Err(format!(
+                    "The {} port must be a multiple of {}, got {:02X}",
+                    name, ports, port
+                )),
+            };
+            match (name, port) {
+                ("acia", _) | ("sio", _) => {
+                    machine.console = match name {
+                        "acia" => Uart::Acia,
+                        _ => Uart::Sio,
+                    };
+                    let port = port.unwrap_or(machine.port);
+                    machine.port = aligned(port, machine.console.ports())?;
+                }
+                ("ctc", Some(port)) => machine.ctc = Some(aligned(port, 4)?),
+                ("ctc", None) => return Err("Expected ctc@<port>".to_string()),
+                _ => return Err(format!("Unknown SBC option: {}", option)),
+            }
+        }
+        if let Some(ctc) = machine.ctc {
+            let console = machine.port..=machine.port + (machine.console.ports() - 1);
+            if console.contains(&ctc) {
+                return Err(format!("The CTC at {:02X} overlaps the console", ctc));
+            }
+        }
+        Ok(machine)
+    }
+}
+
+impl Sbc {
+    // The machine at reset with empty RAM and the console connected to `backend`
+    pub fn build<B: SerialBackend + 'static>(&self, backend: B) -> Interconnect {
+        let console: DeviceRef = match self.console {
+            Uart::Acia => {
+                let mut acia = Acia::default();
+                acia.connect(backend);
+                Rc::new(RefCell::new(acia))
+            }
+            Uart::Sio => {
+                let mut sio = Sio::default();
+                sio.connect(CHANNEL_A, backend);
+                Rc::new(RefCell::new(sio))
+            }
+        };
+        let ports = self.port..=self.port + (self.console.ports() - 1);
+        let mut builder = Interconnect::builder()
+            .preset(Preset::Custom(Box::default()))
+            .clock_speed(CLOCK)
+            .port_device(ports, console);
+        if let Some(port) = self.ctc {
+            builder = builder.port_device(port..=port + 3, Rc::new(RefCell::new(Ctc::default())));
+        }
+        let mut i = builder.build();
+        i.cpu.cpm_compat = false;
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sbc, Uart};
+    use crate::assembler::assemble;
+    use crate::peripherals::serial::Pipe;
+
+    #[test]
+    fn options() {
+        assert_eq!("".parse::<Sbc>().unwrap(), Sbc::default());
+        let machine: Sbc = "sio@10, ctc@0x20".parse().unwrap();
+        assert_eq!(machine.console, Uart::Sio);
+        assert_eq!((machine.port, machine.ctc), (0x10, Some(0x20)));
+        assert_eq!("sio".parse::<Sbc>().unwrap().port, 0x80);
+        assert!("acia@81".parse::<Sbc>().is_err());
+        assert!("sio@82".parse::<Sbc>().is_err());
+        assert!("ctc".parse::<Sbc>().is_err());
+        assert!("acia@10,ctc@10".parse::<Sbc>().is_err());
+        assert!("uart@10".parse::<Sbc>().is_err());
+    }
+
+    #[test]
+    fn echo() {
+        // Upper cases whatever comes in on the ACIA at 10-11
+        let source = "
+            LD A,16h
+            OUT (10h),A
+    WAIT:   IN A,(10h)
+            AND 1
+            JP Z,WAIT
+            IN A,(11h)
+            AND 0DFh
+            OUT (11h),A
+            JP WAIT
+        ";
+        let machine: Sbc = "acia@10".parse().unwrap();
+        let pipe = Pipe::default();
+        let mut i = machine.build(pipe.clone());
+        assemble(source).unwrap().load(&mut i.cpu.memory);
+        pipe.write(b"ok");
+        for _ in 0..2000 {
+            i.step();
+        }
+        assert_eq!(pipe.take_output(), b"OK");
+    }
+
+    #[test]
+    fn ctc_tick() {
+        // CTC channel 0 interrupting every 256 * 100 T states in mode 2, counted at 8000
+        let source = "
+            LD SP,0
+            LD A,1
+            LD I,A
+            IM 2
+            LD A,10h
+            OUT (08h),A
+            LD A,0A7h
+            OUT (08h),A
+            LD A,100
+            OUT (08h),A
+            EI
+    IDLE:   HALT
+            JP IDLE
+            ORG 0110h
+            DW TICK
+    TICK:   LD HL,8000h
+            INC (HL)
+            EI
+            RETI
+        ";
+        let machine: Sbc = "sio@00,ctc@08".parse().unwrap();
+        let mut i = machine.build(Pipe::default());
+        assemble(source).unwrap().load(&mut i.cpu.memory);
+        while i.cpu.cycles < 256 * 100 * 5 + 1000 {
+            i.step();
+        }
+        assert_eq!(i.cpu.memory[0x8000], 5);
+    }
+}
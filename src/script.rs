@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, Scope, AST, INT};
+
+use crate::cpu::Cpu;
+use crate::expr;
+use crate::interconnect::HookAction;
+
+// A Rhai script run as a PC hook (see `Interconnect::on_pc_script`). Scripts see the CPU
+// through these functions:
+//
+//   reg("HL"), set_reg("A", 5)    registers and flags, named as in breakpoint conditions
+//   peek(addr), peek16(addr)      memory, without device side effects
+//   poke(addr, v), poke16(addr, v)
+//   dump(addr, len)               print a hex dump
+//   cycles()                      T states executed so far
+//
+// The value the script ends with decides what happens next: "skip" doesn't execute the
+// instruction at PC, "return" returns from the current subroutine, "break" stops the
+// debugger after the instruction, anything else executes it as usual. E.g. to skip a
+// ROM's self test:
+//
+//   set_reg("PC", 0x0150); "skip"
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    // The CPU being hooked is swapped in here while the script runs so the registered
+    // functions can reach it
+    cpu: Rc<RefCell<Cpu>>,
+}
+
+impl Script {
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let cpu = Rc::new(RefCell::new(Cpu::default()));
+        let engine = engine(&cpu);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        Ok(Self { engine, ast, cpu })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::compile(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn run(&mut self, cpu: &mut Cpu) -> Result<HookAction, String> {
+        std::mem::swap(cpu, &mut self.cpu.borrow_mut());
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut Scope::new(), &self.ast);
+        std::mem::swap(cpu, &mut self.cpu.borrow_mut());
+        let value = result.map_err(|e| e.to_string())?;
+        Ok(match value.into_string().as_deref() {
+            Ok("skip") => HookAction::Skip,
+            Ok("return") => HookAction::Return,
+            Ok("break") => HookAction::Break,
+            _ => HookAction::Execute,
+        })
+    }
+}
+
+fn engine(cpu: &Rc<RefCell<Cpu>>) -> Engine {
+    let mut engine = Engine::new();
+    let c = cpu.clone();
+    engine.register_fn(
+        "reg",
+        move |name: &str| -> Result<INT, Box<rhai::EvalAltResult>> {
+            expr::register(&c.borrow(), name)
+                .ok_or_else(|| format!("Unknown register {}", name).into())
+        },
+    );
+    let c = cpu.clone();
+    engine.register_fn(
+        "set_reg",
+        move |name: &str, value: INT| -> Result<(), Box<rhai::EvalAltResult>> {
+            match expr::set_register(&mut c.borrow_mut(), name, value as u16) {
+                true => Ok(()),
+                false => Err(format!("Unknown register {}", name).into()),
+            }
+        },
+    );
+    let c = cpu.clone();
+    engine.register_fn("peek", move |addr: INT| {
+        c.borrow().memory.peek(addr as u16) as INT
+    });
+    let c = cpu.clone();
+    engine.register_fn("peek16", move |addr: INT| {
+        c.borrow().memory.peek16(addr as u16) as INT
+    });
+    let c = cpu.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| {
+        c.borrow_mut().memory.poke(addr as u16, value as u8)
+    });
+    let c = cpu.clone();
+    engine.register_fn("poke16", move |addr: INT, value: INT| {
+        let memory = &mut c.borrow_mut().memory;
+        let [low, high] = (value as u16).to_le_bytes();
+        memory.poke(addr as u16, low);
+        memory.poke((addr as u16).wrapping_add(1), high);
+    });
+    let c = cpu.clone();
+    engine.register_fn("dump", move |addr: INT, len: INT| {
+        print!(
+            "{}",
+            c.borrow().memory.dump_range(addr as u16, len as usize)
+        )
+    });
+    let c = cpu.clone();
+    engine.register_fn("cycles", move || c.borrow().cycles as INT);
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Script;
+    use crate::interconnect::{HookAction, Interconnect, Preset};
+
+    #[test]
+    fn registers_memory_and_actions() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.reg.h = 0x80;
+        let mut script = Script::compile(
+            "poke16(reg(\"HL\"), 0x1234);\n\
+             set_reg(\"A\", peek(0x8001));\n\
+             set_reg(\"CF\", 1);\n\
+             if reg(\"A\") == 0x12 { \"skip\" }",
+        )
+        .unwrap();
+        assert_eq!(script.run(&mut i.cpu), Ok(HookAction::Skip));
+        assert_eq!(i.cpu.memory.peek16(0x8000), 0x1234);
+        assert_eq!(i.cpu.reg.a, 0x12);
+        assert!(i.cpu.flags.cf);
+
+        assert!(Script::compile("set_reg(").is_err());
+        let mut bad = Script::compile("reg(\"XY\")").unwrap();
+        assert!(bad.run(&mut i.cpu).is_err());
+        // The hooked CPU is put back after an error too
+        assert_eq!(i.cpu.reg.a, 0x12);
+    }
+
+    #[test]
+    fn pc_hook() {
+        // LD A, 1; LD A, 2; NOP
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu
+            .memory
+            .load_slice(0x0100, &[0x3E, 0x01, 0x3E, 0x02, 0x00]);
+        let script = Script::compile("set_reg(\"PC\", 0x0102); \"break\"").unwrap();
+        i.on_pc_script(0x0100, script);
+        let stop = i.step().stop;
+        assert_eq!(i.cpu.reg.a, 2);
+        assert_eq!(
+            stop.map(|s| s.to_string()).as_deref(),
+            Some("Stopped by the hook at 0100")
+        );
+    }
+}
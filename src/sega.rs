@@ -0,0 +1,393 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::device::Device;
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Page, Region, PAGE_SIZE};
+use crate::peripherals::joystick::{Button, Joystick};
+use crate::peripherals::vdp::Chip;
+use crate::peripherals::{Psg, Vdp};
+use crate::sg1000::Joypads;
+
+pub const CLOCK: usize = 3_579_545;
+
+pub const BANK_SIZE: usize = 0x4000;
+// Two 16K banks of battery backed cartridge RAM
+pub const CART_RAM_SIZE: usize = 0x8000;
+// First of the paging registers, FFFC controls the cartridge RAM and FFFD-FFFF pick the
+// ROM banks of the three slots
+pub const CONTROL: u16 = 0xFFFC;
+// Some dumps start with the header of the copier they were made with
+const COPIER_HEADER: usize = 512;
+
+// The standard Sega mapper of Master System and Game Gear cartridges. Memory is mapped as:
+//
+//   0000-03FF  first 1K of the ROM, never paged so the interrupt vectors stay put
+//   0400-3FFF  slot 0, ROM bank FFFD
+//   4000-7FFF  slot 1, ROM bank FFFE
+//   8000-BFFF  slot 2, ROM bank FFFF, or cartridge RAM bank (FFFC bit 2) if FFFC bit 3
+//   C000-DFFF  8K system RAM, mirrored at E000-FFFF
+//
+// The registers are RAM as well, so they read back what was written and are restored along
+// with it. Bank numbers wrap at the ROM size.
+#[derive(Debug, Clone)]
+pub struct SegaMapper {
+    // Offset of the cartridge ROM in ROM storage
+    rom: usize,
+    // Power of two
+    banks: usize,
+    // Offset of the cartridge RAM in RAM storage
+    ram: usize,
+    // Set once the cartridge RAM has been mapped in, only then is there anything to save
+    used: Rc<Cell<bool>>,
+    // Where the cartridge RAM is kept between runs
+    pub save: Option<PathBuf>,
+}
+
+impl SegaMapper {
+    // Adds `rom` to ROM storage and the cartridge RAM to RAM storage, maps them in with the
+    // registers set as the BIOS leaves them (banks 0, 1 and 2)
+    pub fn install(memory: &mut Memory, rom: &[u8]) -> Self {
+        let rom = match rom.len() % BANK_SIZE {
+            COPIER_HEADER => &rom[COPIER_HEADER..],
+            _ => rom,
+        };
+        let banks = rom.len().div_ceil(BANK_SIZE).max(1).next_power_of_two();
+        let base = memory.rom.len();
+        memory.rom.extend_from_slice(rom);
+        memory.rom.resize(base + banks * BANK_SIZE, 0xFF);
+        let mapper = Self {
+            rom: base,
+            banks,
+            ram: memory.alloc_ram(CART_RAM_SIZE),
+            used: Rc::new(Cell::new(false)),
+            save: None,
+        };
+
+        memory.map(0xE000..=0xFFFF, Region::Mirror(0xC000));
+        memory.load_slice(CONTROL, &[0x00, 0x00, 0x01, 0x02]);
+        for addr in CONTROL..=0xFFFF {
+            let mapper = mapper.clone();
+            memory.add_mapped_paging_register(addr..=addr, move |memory, _| mapper.remap(memory));
+        }
+        mapper.remap(memory);
+        mapper
+    }
+
+    // Installs a .sms / .gg file, with the cartridge RAM from the .sav file next to it if
+    // there is one
+    pub fn load<P: AsRef<Path>>(memory: &mut Memory, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let rom = archive::read(path)?;
+        let mut mapper = Self::install(memory, &rom);
+        let save = path.with_extension("sav");
+        if let Ok(data) = fs::read(&save) {
+            let len = data.len().min(CART_RAM_SIZE);
+            memory.load_ram(mapper.ram, &data[..len]);
+            mapper.used.set(true);
+            info!("Loaded cartridge RAM from {:?}", save);
+        }
+        mapper.save = Some(save);
+        info!(
+            "Loaded {:?}, {} banks of ROM with the Sega mapper",
+            path, mapper.banks
+        );
+        Ok(mapper)
+    }
+
+    // Writes the cartridge RAM to `save` if the game used it
+    pub fn save_ram(&self, memory: &Memory) -> io::Result<()> {
+        match &self.save {
+            Some(path) if self.used.get() => fs::write(path, self.cart_ram(memory)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cart_ram<'a>(&self, memory: &'a Memory) -> &'a [u8] {
+        &memory.ram[self.ram..self.ram + CART_RAM_SIZE]
+    }
+
+    // Maps the slots from the registers, after a write to one of them or a restore
+    pub fn remap(&self, memory: &mut Memory) {
+        let control = memory.peek(CONTROL);
+        let bank = |n: u16| {
+            let bank = memory.peek(CONTROL + n) as usize & (self.banks - 1);
+            self.rom + bank * BANK_SIZE
+        };
+        let (slot0, slot1, slot2) = (bank(1), bank(2), bank(3));
+        memory.map_bank(0x0000, PAGE_SIZE, Page::Rom(self.rom));
+        memory.map_bank(
+            PAGE_SIZE as u16,
+            BANK_SIZE - PAGE_SIZE,
+            Page::Rom(slot0 + PAGE_SIZE),
+        );
+        memory.map_bank(0x4000, BANK_SIZE, Page::Rom(slot1));
+        if control & 0x08 != 0 {
+            self.used.set(true);
+            let ram = self.ram + (control as usize >> 2 & 1) * BANK_SIZE;
+            memory.map_bank(0x8000, BANK_SIZE, Page::Ram(ram));
+        } else {
+            memory.map_bank(0x8000, BANK_SIZE, Page::Rom(slot2));
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Model {
+    MasterSystem,
+    GameGear,
+}
+
+impl Model {
+    // From the cartridge's extension, .gg for the Game Gear
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match extension(path.as_ref()).as_deref() {
+            Some("gg") => Model::GameGear,
+            _ => Model::MasterSystem,
+        }
+    }
+}
+
+// Ports 40-7F: the V counter (even) and H counter (odd) of the VDP are read here and the
+// PSG written
+struct Counters {
+    vdp: Rc<RefCell<Vdp>>,
+    psg: Rc<RefCell<Psg>>,
+}
+
+impl Device for Counters {
+    fn io_read(&mut self, port: u16) -> u8 {
+        let vdp = self.vdp.borrow();
+        match port & 1 {
+            0 => vdp.v_counter(),
+            _ => vdp.h_counter(),
+        }
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        self.psg.borrow_mut().write(value);
+    }
+}
+
+// The Game Gear's own ports 00-06: Start and the region on 00, the link port's registers
+// on 01-05 and the PSG's stereo register on 06
+struct GearPorts {
+    start: Joystick,
+    psg: Rc<RefCell<Psg>>,
+    regs: [u8; 7],
+}
+
+impl GearPorts {
+    fn new(start: Joystick, psg: Rc<RefCell<Psg>>) -> Self {
+        Self {
+            start,
+            psg,
+            regs: [0x00, 0x7F, 0xFF, 0x00, 0xFF, 0x00, 0xFF],
+        }
+    }
+}
+
+impl Device for GearPorts {
+    // Bit 7 is Start, 0 while pressed, bit 6 set for an export console and bit 5 clear
+    // for NTSC
+    fn io_read(&mut self, port: u16) -> u8 {
+        match port as u8 {
+            0 if self.start.pressed(Button::Start) => 0x40,
+            0 => 0xC0,
+            port => self.regs[port as usize],
+        }
+    }
+
+    fn io_write(&mut self, port: u16, value: u8) {
+        match port as u8 {
+            0 => {}
+            6 => self.psg.borrow_mut().stereo = value,
+            port => self.regs[port as usize] = value,
+        }
+    }
+}
+
+// A Sega Master System or Game Gear with a cartridge on the Sega mapper. The ports are
+// decoded on A6-A7 and A0: the V / H counters are read and the PSG written at 40-7F, the
+// VDP is at 80-BF (data on even ports, control on odd) and the pads are read at C0-FF. The
+// Game Gear adds ports 00-06 and shows the middle 160x144 of the picture with 4096 colours.
+pub struct SegaConsole {
+    pub model: Model,
+    pub mapper: SegaMapper,
+    pub vdp: Rc<RefCell<Vdp>>,
+    pub psg: Rc<RefCell<Psg>>,
+    pub pads: Rc<RefCell<Joypads>>,
+}
+
+impl SegaConsole {
+    // Maps the cartridge into the memory of `i` and attaches the devices
+    pub fn install(i: &mut Interconnect, rom: &[u8], model: Model) -> Self {
+        let mut memory = Memory::default();
+        let mapper = SegaMapper::install(&mut memory, rom);
+        i.cpu.memory = memory;
+        Self::attach(i, mapper, model)
+    }
+
+    // Installs a .sms or .gg cartridge, with the cartridge RAM from the .sav file next to it
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut memory = Memory::default();
+        let mapper = SegaMapper::load(&mut memory, path)?;
+        i.cpu.memory = memory;
+        let model = Model::from_path(archive::inner(path));
+        info!("Running {:?} as a {:?}", path, model);
+        Ok(Self::attach(i, mapper, model))
+    }
+
+    fn attach(i: &mut Interconnect, mapper: SegaMapper, model: Model) -> Self {
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+
+        let vdp = Vdp::with_chip(match model {
+            Model::MasterSystem => Chip::MasterSystem,
+            Model::GameGear => Chip::GameGear,
+        });
+        i.frame_cycles = Some(vdp.frame_cycles());
+        i.display = Some(vdp.frame.clone());
+        let vdp = i.add_device(vdp);
+        i.register_port_decoded(0x00C0, 0x0080, vdp.clone());
+
+        let mut psg = Psg::new(CLOCK as u64, CLOCK as u64, 44_100);
+        psg.lfsr_bits = 16;
+        psg.feedback = 0x0009;
+        psg.stereo_output = model == Model::GameGear;
+        let psg = i.add_device(psg);
+        let counters = Counters {
+            vdp: vdp.clone(),
+            psg: psg.clone(),
+        };
+        i.register_port_decoded(0x00C0, 0x0040, Rc::new(RefCell::new(counters)));
+
+        let mut pads = Joypads::default();
+        pads.pause = model == Model::MasterSystem;
+        let pads = i.add_device(pads);
+        i.register_port_decoded(0x00C0, 0x00C0, pads.clone());
+        if model == Model::GameGear {
+            let start = pads.borrow().joysticks[0].clone();
+            let ports = GearPorts::new(start, psg.clone());
+            i.register_port(0x00..=0x06, Rc::new(RefCell::new(ports)));
+        }
+        Self {
+            model,
+            mapper,
+            vdp,
+            psg,
+            pads,
+        }
+    }
+
+    pub fn joysticks(&self) -> [Joystick; 2] {
+        self.pads.borrow().joysticks.clone()
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    let ext = path.extension().and_then(|ext| ext.to_str())?;
+    Some(ext.to_ascii_lowercase())
+}
+
+pub fn is_sega_rom<P: AsRef<Path>>(path: P) -> bool {
+    matches!(extension(path.as_ref()).as_deref(), Some("sms" | "gg"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sega_rom, Model, SegaConsole, SegaMapper, BANK_SIZE};
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::memory::MemoryRW;
+    use crate::peripherals::joystick::Button;
+
+    #[test]
+    fn mapper() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        // 5 banks (padded to 8) each filled with its number, behind a copier header
+        let mut rom = vec![0xAA; 512];
+        for bank in 0..5 {
+            rom.extend(vec![bank; BANK_SIZE]);
+        }
+        let mapper = SegaMapper::install(&mut i.cpu.memory, &rom);
+        assert_eq!(i.peek(0x0000), 0);
+        assert_eq!(i.peek(0x4000), 1);
+        assert_eq!(i.peek(0x8000), 2);
+
+        i.cpu.write8(0xFFFD, 3);
+        i.cpu.write8(0xFFFF, 12);
+        assert_eq!(i.peek(0x0000), 0);
+        assert_eq!(i.peek(0x0400), 3);
+        assert_eq!(i.peek(0x8000), 4);
+        // Padding reads as erased
+        i.cpu.write8(0xFFFE, 6);
+        assert_eq!(i.peek(0x4000), 0xFF);
+        // The registers are mirrored system RAM
+        assert_eq!(i.peek(0xDFFF), 12);
+        i.cpu.write8(0x0400, 0x55);
+        assert_eq!(i.peek(0x0400), 3);
+
+        // Cartridge RAM, second bank
+        i.cpu.write8(0xFFFC, 0x0C);
+        i.cpu.write8(0x8001, 0x42);
+        assert_eq!(mapper.cart_ram(&i.cpu.memory)[BANK_SIZE + 1], 0x42);
+        assert!(mapper.used.get());
+        i.cpu.write8(0xFFFC, 0x00);
+        assert_eq!(i.peek(0x8001), 4);
+        i.cpu.write8(0xFFFC, 0x0C);
+        assert_eq!(i.peek(0x8001), 0x42);
+
+        assert!(is_sega_rom("sonic.SMS"));
+        assert!(is_sega_rom("columns.gg"));
+        assert!(!is_sega_rom("pacman.rom"));
+    }
+
+    #[test]
+    fn consoles() {
+        // JP $
+        let mut rom = vec![0xC3, 0x00, 0x00];
+        rom.resize(BANK_SIZE * 2, 0xFF);
+
+        let mut i = Interconnect::builder().build();
+        let console = SegaConsole::install(&mut i, &rom, Model::GameGear);
+        assert_eq!(i.display.as_ref().unwrap().borrow().width, 160);
+        assert_eq!(i.peek(0x0000), 0xC3);
+        i.cpu.write8(0xC000, 0x12);
+        assert_eq!(i.peek(0xE000), 0x12);
+        // Start is on port 00 and doesn't pause
+        let [one, _] = console.joysticks();
+        assert_eq!(i.cpu.io.read(0x00), 0xC0);
+        one.press(Button::Start);
+        assert_eq!(i.cpu.io.read(0x00), 0x40);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0000);
+        assert_eq!(i.cpu.io.read(0x01), 0x7F);
+        i.cpu.io.write(0x06, 0xF0);
+        assert_eq!(console.psg.borrow().stereo, 0xF0);
+        // PSG on 40-7F, which also has the V counter
+        i.cpu.io.write(0x7F, 0x9A);
+        assert_eq!(console.psg.borrow().volumes[0], 0x0A);
+        while console.vdp.borrow().cycles < 228 * 10 {
+            i.step();
+        }
+        assert_eq!(i.cpu.io.read(0x7E), 10);
+
+        let mut i = Interconnect::builder().build();
+        let console = SegaConsole::install(&mut i, &rom, Model::MasterSystem);
+        assert_eq!(i.display.as_ref().unwrap().borrow().width, 256);
+        // Start on pad 1 is the pause button
+        let [one, _] = console.joysticks();
+        one.press(Button::Start);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0066);
+        assert_eq!(Model::from_path("Sonic.GG"), Model::GameGear);
+        assert_eq!(Model::from_path("sonic.sms"), Model::MasterSystem);
+    }
+}
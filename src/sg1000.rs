@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::device::Device;
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Page, PAGE_SIZE};
+use crate::peripherals::joystick::{Button, Joystick};
+use crate::peripherals::{Psg, Vdp};
+
+pub const CLOCK: usize = 3_579_545;
+// Cartridges fill 0000-BFFF
+pub const ROM_SIZE: usize = 0xC000;
+const RAM_START: usize = 0xC000;
+
+// Bits of a pad as read on the pad ports, in order from bit 0
+const PAD: [Button; 6] = [
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+    Button::Fire,
+    Button::Fire2,
+];
+
+// The pad ports of Sega's consoles, buttons read 0 while pressed. DC (even ports) has all
+// of pad 1 and up / down of pad 2, DD the rest of pad 2 with the unused bits high. The
+// pause button is on the console and wired to /NMI, here it's pad 1's `Start`. The Game
+// Gear has no pause, its Start button is read on port 00 instead.
+pub struct Joypads {
+    pub joysticks: [Joystick; 2],
+    // Start raises an NMI
+    pub pause: bool,
+    pause_held: bool,
+    nmi: bool,
+}
+
+impl Default for Joypads {
+    fn default() -> Self {
+        Self {
+            joysticks: Default::default(),
+            pause: true,
+            pause_held: false,
+            nmi: false,
+        }
+    }
+}
+
+impl Joypads {
+    fn pressed(joystick: &Joystick, buttons: &[Button]) -> u8 {
+        buttons
+            .iter()
+            .enumerate()
+            .filter(|(_, &button)| joystick.pressed(button))
+            .fold(0, |bits, (bit, _)| bits | 1 << bit)
+    }
+}
+
+impl Device for Joypads {
+    fn tick(&mut self, _cycles: usize) {
+        let pause = self.pause && self.joysticks[0].pressed(Button::Start);
+        self.nmi = pause && !self.pause_held;
+        self.pause_held = pause;
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        let [one, two] = &self.joysticks;
+        match port & 1 {
+            0 => !(Self::pressed(one, &PAD) | Self::pressed(two, &PAD[..2]) << 6),
+            _ => !Self::pressed(two, &PAD[2..]),
+        }
+    }
+
+    fn pending_nmi(&self) -> bool {
+        self.nmi
+    }
+}
+
+// A Sega SG-1000 (or SC-3000 with 2K of RAM). The cartridge ROM is at 0000-BFFF and the
+// RAM mirrored through C000-FFFF. The ports are decoded on A6-A7: the PSG is written at
+// 40-7F, the TMS9918A is at 80-BF and the pads are read at C0-FF.
+pub struct Sg1000 {
+    pub vdp: Rc<RefCell<Vdp>>,
+    pub psg: Rc<RefCell<Psg>>,
+    pub pads: Rc<RefCell<Joypads>>,
+}
+
+impl Sg1000 {
+    // Replaces the memory of `i` with the cartridge and `ram` bytes of RAM, 1K on the
+    // SG-1000 and 2K on the SC-3000, and attaches the devices
+    pub fn install(i: &mut Interconnect, rom: &[u8], ram: usize) -> Result<Self, String> {
+        if rom.is_empty() || rom.len() > ROM_SIZE {
+            return Err(format!(
+                "Cartridge is {} bytes, expected up to {}",
+                rom.len(),
+                ROM_SIZE
+            ));
+        }
+        if ram == 0 || !ram.is_multiple_of(PAGE_SIZE) || !(0x10000 - RAM_START).is_multiple_of(ram)
+        {
+            return Err(format!("Unsupported RAM size: {}", ram));
+        }
+        let mut image = rom.to_vec();
+        image.resize(ROM_SIZE, 0xFF);
+        let mut memory = Memory::default();
+        memory.load_rom(0x0000, &image);
+        for page in RAM_START / PAGE_SIZE..0x10000 / PAGE_SIZE {
+            let offset = (page * PAGE_SIZE - RAM_START) % ram;
+            memory.map_page(page, Page::Ram(RAM_START + offset));
+        }
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+
+        let vdp = Vdp::default();
+        i.frame_cycles = Some(vdp.frame_cycles());
+        i.display = Some(vdp.frame.clone());
+        let vdp = i.add_device(vdp);
+        i.register_port_decoded(0x00C0, 0x0080, vdp.clone());
+        let psg = i.add_device(Psg::new(CLOCK as u64, CLOCK as u64, 44_100));
+        i.register_port_decoded(0x00C0, 0x0040, psg.clone());
+        let pads = i.add_device(Joypads::default());
+        i.register_port_decoded(0x00C0, 0x00C0, pads.clone());
+        Ok(Self { vdp, psg, pads })
+    }
+
+    // Installs a .sg (SG-1000) or .sc (SC-3000) cartridge
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let rom = archive::read(path)?;
+        let ram = match extension(archive::inner(path)).as_deref() {
+            Some("sc") => 0x800,
+            _ => 0x400,
+        };
+        let machine = Self::install(i, &rom, ram)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        info!("Loaded {:?} with {}K of RAM", path, ram / 1024);
+        Ok(machine)
+    }
+
+    pub fn joysticks(&self) -> [Joystick; 2] {
+        self.pads.borrow().joysticks.clone()
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    let ext = path.extension().and_then(|ext| ext.to_str())?;
+    Some(ext.to_ascii_lowercase())
+}
+
+pub fn is_sg1000_rom<P: AsRef<Path>>(path: P) -> bool {
+    matches!(extension(path.as_ref()).as_deref(), Some("sg" | "sc"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sg1000_rom, Sg1000};
+    use crate::assembler::assemble;
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+    use crate::peripherals::joystick::Button;
+
+    // DI; IM 1; LD SP, C400; LD A, E0; OUT (BF), A; LD A, 81; OUT (BF), A; EI; JP $
+    // 0038: PUSH AF; IN A, (BF); INC B; POP AF; EI; RET
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![
+            0xF3, 0xED, 0x56, 0x31, 0x00, 0xC4, 0x3E, 0xE0, 0xD3, 0xBF, 0x3E, 0x81, 0xD3, 0xBF,
+            0xFB, 0xC3, 0x0F, 0x00,
+        ];
+        rom.resize(0x38, 0x00);
+        rom.extend([0xF5, 0xDB, 0xBF, 0x04, 0xF1, 0xFB, 0xC9]);
+        rom.resize(0x2000, 0xFF);
+        rom
+    }
+
+    #[test]
+    fn frame_interrupts() {
+        let mut i = Interconnect::builder().build();
+        let machine = Sg1000::install(&mut i, &rom(), 0x400).unwrap();
+        assert_eq!(i.frame_cycles, Some(59_736));
+        while machine.vdp.borrow().cycles < 2 * 59_736 {
+            i.step();
+        }
+        assert_eq!(i.cpu.reg.b, 2);
+        assert!(i.display.is_some());
+
+        // 1K of RAM all the way up, the cartridge can't be written
+        i.cpu.write8(0xC000, 0x12);
+        assert_eq!(i.cpu.read8(0xFC00), 0x12);
+        i.cpu.write8(0x0000, 0x00);
+        assert_eq!(i.cpu.read8(0x0000), 0xF3);
+        assert_eq!(i.cpu.read8(0x8000), 0xFF);
+        assert!(Sg1000::install(&mut i, &rom(), 0x300).is_err());
+    }
+
+    #[test]
+    fn pads() {
+        let mut i = Interconnect::builder().build();
+        let machine = Sg1000::install(&mut i, &rom(), 0x800).unwrap();
+        let [one, two] = machine.joysticks();
+        one.press(Button::Up);
+        one.press(Button::Fire);
+        two.press(Button::Down);
+        two.press(Button::Fire2);
+        assert_eq!(i.cpu.io.read(0xDC), 0x6E);
+        assert_eq!(i.cpu.io.read(0xDD), 0xF7);
+        // Pause
+        one.press(Button::Start);
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0066);
+
+        i.cpu.write8(0xC000, 0x34);
+        assert_eq!(i.cpu.read8(0xC800), 0x34);
+        assert_ne!(i.cpu.read8(0xC400), 0x34);
+        assert!(is_sg1000_rom("Flicky.SG"));
+        assert!(!is_sg1000_rom("sonic.sms"));
+    }
+
+    #[test]
+    fn vram_block_io() {
+        // Uploads 4 bytes to VRAM 0000 with OTIR and reads them back to RAM with INIR
+        let source = "
+            DI
+            XOR A
+            OUT (0BFh), A
+            LD A, 40h
+            OUT (0BFh), A
+            LD HL, DATA
+            LD BC, 04BEh
+            OTIR
+            XOR A
+            OUT (0BFh), A
+            OUT (0BFh), A
+            LD HL, 0C000h
+            LD BC, 04BEh
+            INIR
+    DONE:   JP DONE
+    DATA:   DB 12h, 34h, 56h, 78h
+        ";
+        let program = assemble(source).unwrap();
+        let done = program.symbols.addr("DONE").unwrap();
+        let mut i = Interconnect::builder().build();
+        let machine = Sg1000::install(&mut i, &program.to_binary(), 0x400).unwrap();
+        while i.cpu.reg.pc != done {
+            i.step();
+        }
+        assert_eq!(machine.vdp.borrow().vram[..4], [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(i.cpu.memory.peek16(0xC000), 0x3412);
+        assert_eq!(i.cpu.memory.peek16(0xC002), 0x7856);
+    }
+}
@@ -0,0 +1,363 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::formatter::HexBytes;
+use crate::memory::ADDRESS_SPACE;
+
+const MAGIC: &[u8; 8] = b"Z80SNAP1";
+// Magic, 12 register pairs, I, R, IM, interrupt flags, the vector and the cycle count
+const HEADER: usize = 8 + 12 * 2 + 5 + 8;
+
+// CPU registers and the 64K address space as currently mapped, at one point in time
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub sp: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub af_: u16,
+    pub bc_: u16,
+    pub de_: u16,
+    pub hl_: u16,
+    pub i: u8,
+    pub r: u8,
+    pub im: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub halted: bool,
+    // Interrupt lines, the vector on the bus and whether an EI is holding off /INT
+    pub irq: bool,
+    pub nmi_pending: bool,
+    pub vector: u8,
+    pub ei_pending: bool,
+    pub cycles: u64,
+    pub memory: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &Cpu) -> Self {
+        Self {
+            memory: (0..=0xFFFF).map(|addr| cpu.memory.peek(addr)).collect(),
+            ..Self::capture_registers(cpu)
+        }
+    }
+
+    // Registers and interrupt state only, `memory` is left empty
+    pub fn capture_registers(cpu: &Cpu) -> Self {
+        let reg = &cpu.reg;
+        let pair = |h: u8, l: u8| (h as u16) << 8 | l as u16;
+        Self {
+            pc: reg.pc,
+            sp: reg.sp,
+            af: pair(reg.a, cpu.flags.get()),
+            bc: pair(reg.b, reg.c),
+            de: pair(reg.d, reg.e),
+            hl: pair(reg.h, reg.l),
+            ix: reg.ix,
+            iy: reg.iy,
+            af_: pair(reg.a_, cpu.flags.get_shadow()),
+            bc_: pair(reg.b_, reg.c_),
+            de_: pair(reg.d_, reg.e_),
+            hl_: pair(reg.h_, reg.l_),
+            i: reg.i,
+            r: reg.r,
+            im: cpu.int.mode,
+            iff1: cpu.int.iff1,
+            iff2: cpu.int.iff2,
+            halted: cpu.int.halt,
+            irq: cpu.int.irq,
+            nmi_pending: cpu.int.nmi_pending,
+            vector: cpu.int.vector,
+            ei_pending: cpu.int.ei_pending,
+            cycles: cpu.cycles as u64,
+            memory: Vec::new(),
+        }
+    }
+
+    // Writes the snapshot back. Memory goes through the current address map, ROM included.
+    pub fn restore(&self, cpu: &mut Cpu) {
+        self.restore_registers(cpu);
+        cpu.cycles = self.cycles as usize;
+        cpu.memory.load_slice(0, &self.memory);
+    }
+
+    // Registers and interrupt state only
+    pub fn restore_registers(&self, cpu: &mut Cpu) {
+        let reg = &mut cpu.reg;
+        reg.pc = self.pc;
+        reg.sp = self.sp;
+        reg.a = (self.af >> 8) as u8;
+        [reg.b, reg.c] = self.bc.to_be_bytes();
+        [reg.d, reg.e] = self.de.to_be_bytes();
+        [reg.h, reg.l] = self.hl.to_be_bytes();
+        reg.ix = self.ix;
+        reg.iy = self.iy;
+        reg.a_ = (self.af_ >> 8) as u8;
+        [reg.b_, reg.c_] = self.bc_.to_be_bytes();
+        [reg.d_, reg.e_] = self.de_.to_be_bytes();
+        [reg.h_, reg.l_] = self.hl_.to_be_bytes();
+        reg.i = self.i;
+        reg.r = self.r;
+        cpu.flags.set(self.af as u8);
+        cpu.flags.set_shadow(self.af_ as u8);
+        cpu.int.mode = self.im;
+        cpu.int.iff1 = self.iff1;
+        cpu.int.iff2 = self.iff2;
+        cpu.int.halt = self.halted;
+        cpu.int.irq = self.irq;
+        cpu.int.nmi_pending = self.nmi_pending;
+        cpu.int.vector = self.vector;
+        cpu.int.ei_pending = self.ei_pending;
+    }
+
+    // The registers in the `Cpu::to_json` layout, memory isn't included
+    pub fn to_json(&self) -> String {
+        let mut cpu = Cpu::default();
+        self.restore_registers(&mut cpu);
+        cpu.cycles = self.cycles as usize;
+        cpu.to_json()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        for pair in self.pairs() {
+            out.extend_from_slice(&pair.to_le_bytes());
+        }
+        let flags = self.iff1 as u8
+            | (self.iff2 as u8) << 1
+            | (self.halted as u8) << 2
+            | (self.irq as u8) << 3
+            | (self.nmi_pending as u8) << 4
+            | (self.ei_pending as u8) << 5;
+        out.extend_from_slice(&[self.i, self.r, self.im, flags, self.vector]);
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != HEADER + ADDRESS_SPACE || &data[..8] != MAGIC {
+            return Err("Not a snapshot".to_string());
+        }
+        let pair = |n: usize| u16::from_le_bytes([data[8 + n * 2], data[9 + n * 2]]);
+        let bytes = &data[32..37];
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&data[37..HEADER]);
+        Ok(Self {
+            pc: pair(0),
+            sp: pair(1),
+            af: pair(2),
+            bc: pair(3),
+            de: pair(4),
+            hl: pair(5),
+            ix: pair(6),
+            iy: pair(7),
+            af_: pair(8),
+            bc_: pair(9),
+            de_: pair(10),
+            hl_: pair(11),
+            i: bytes[0],
+            r: bytes[1],
+            im: bytes[2],
+            iff1: bytes[3] & 1 != 0,
+            iff2: bytes[3] & 2 != 0,
+            halted: bytes[3] & 4 != 0,
+            irq: bytes[3] & 8 != 0,
+            nmi_pending: bytes[3] & 0x10 != 0,
+            ei_pending: bytes[3] & 0x20 != 0,
+            vector: bytes[4],
+            cycles: u64::from_le_bytes(cycles),
+            memory: data[HEADER..].to_vec(),
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&archive::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn pairs(&self) -> [u16; 12] {
+        [
+            self.pc, self.sp, self.af, self.bc, self.de, self.hl, self.ix, self.iy, self.af_,
+            self.bc_, self.de_, self.hl_,
+        ]
+    }
+
+    // Register values by name, in the order `diff` prints them
+    fn registers(&self) -> Vec<(&'static str, u64)> {
+        let names = [
+            "PC", "SP", "AF", "BC", "DE", "HL", "IX", "IY", "AF'", "BC'", "DE'", "HL'",
+        ];
+        let mut registers: Vec<(&'static str, u64)> = names
+            .iter()
+            .zip(self.pairs())
+            .map(|(&name, value)| (name, value as u64))
+            .collect();
+        registers.extend([
+            ("I", self.i as u64),
+            ("R", self.r as u64),
+            ("IM", self.im as u64),
+            ("IFF1", self.iff1 as u64),
+            ("IFF2", self.iff2 as u64),
+            ("HALT", self.halted as u64),
+            ("INT", self.irq as u64),
+            ("NMI", self.nmi_pending as u64),
+            ("VEC", self.vector as u64),
+            ("EI", self.ei_pending as u64),
+            ("CYC", self.cycles),
+        ]);
+        registers
+    }
+}
+
+// Flag names for bits 7 to 0 of F, undocumented bits included
+const FLAGS: [&str; 8] = ["S", "Z", "Y", "H", "X", "P", "N", "C"];
+
+// Registers, flags and memory ranges that differ between `a` and `b`, one per line,
+// e.g. `AF   0544 0540  Z 1 0` or `mem  8000-8001  3E 05 / 00 00`. Empty if they're equal.
+pub fn diff(a: &Snapshot, b: &Snapshot) -> String {
+    let mut out = diff_registers(&a.registers(), &b.registers());
+    let mut addr = 0;
+    while addr < ADDRESS_SPACE {
+        if a.memory[addr] == b.memory[addr] {
+            addr += 1;
+            continue;
+        }
+        let start = addr;
+        while addr < ADDRESS_SPACE && a.memory[addr] != b.memory[addr] {
+            addr += 1;
+        }
+        // Long ranges only show their first bytes
+        let shown = start..addr.min(start + 8);
+        let more = if shown.end < addr { " .." } else { "" };
+        let hex = |memory: &[u8]| format!("{}{}", HexBytes(&memory[shown.clone()], " "), more);
+        out.push_str(&format!(
+            "mem  {:04X}-{:04X}  {} / {}\n",
+            start,
+            addr - 1,
+            hex(&a.memory),
+            hex(&b.memory)
+        ));
+    }
+    out
+}
+
+// Differences between two trace lines in the `TraceEntry::line` layout (PC first, then
+// `KEY=hex` tokens, CYC in decimal). Keys only present on one side are skipped.
+pub fn diff_lines(a: &str, b: &str) -> String {
+    diff_registers(&line_registers(a), &line_registers(b))
+}
+
+fn line_registers(line: &str) -> Vec<(&'static str, u64)> {
+    const KEYS: [&str; 13] = [
+        "PC", "SP", "AF", "BC", "DE", "HL", "IX", "IY", "AF'", "BC'", "DE'", "HL'", "CYC",
+    ];
+    let mut registers = Vec::new();
+    for (n, token) in line.split_whitespace().enumerate() {
+        let (key, value) = match token.split_once(['=', ':']) {
+            Some((key, value)) => (key.to_ascii_uppercase(), value),
+            None if n == 0 => ("PC".to_string(), token),
+            None => continue,
+        };
+        let radix = if key == "CYC" { 10 } else { 16 };
+        if let (Some(&name), Ok(value)) = (
+            KEYS.iter().find(|k| **k == key),
+            u64::from_str_radix(value, radix),
+        ) {
+            registers.push((name, value));
+        }
+    }
+    registers
+}
+
+fn diff_registers(a: &[(&'static str, u64)], b: &[(&'static str, u64)]) -> String {
+    let mut out = String::new();
+    for &(name, left) in a {
+        let right = match b.iter().find(|(n, _)| *n == name) {
+            Some(&(_, right)) if right != left => right,
+            _ => continue,
+        };
+        out.push_str(&match name {
+            "CYC" => format!("{:<4} {} {}", name, left, right),
+            "I" | "R" | "IM" | "IFF1" | "IFF2" | "HALT" | "INT" | "NMI" | "VEC" | "EI" => {
+                format!("{:<4} {:02X} {:02X}", name, left, right)
+            }
+            _ => format!("{:<4} {:04X} {:04X}", name, left, right),
+        });
+        if name.starts_with("AF") {
+            let changed: Vec<String> = (0..8)
+                .filter(|bit| (left ^ right) & (0x80 >> bit) != 0)
+                .map(|bit| {
+                    let flag = |value: u64| (value & (0x80 >> bit) != 0) as u8;
+                    format!("{} {} {}", FLAGS[bit], flag(left), flag(right))
+                })
+                .collect();
+            if !changed.is_empty() {
+                out.push_str(&format!("  {}", changed.join(", ")));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, diff_lines, Snapshot};
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn capture_restore_and_diff() {
+        let mut cpu = Cpu::default();
+        cpu.reg.pc = 0x0100;
+        cpu.reg.a = 0x05;
+        cpu.flags.set(0x44);
+        cpu.reg.h_ = 0x12;
+        cpu.int.iff1 = true;
+        cpu.int_request(0xCF);
+        cpu.memory.load_slice(0x8000, &[1, 2, 3]);
+        let a = Snapshot::capture(&cpu);
+        assert_eq!(Snapshot::from_bytes(&a.to_bytes()), Ok(a.clone()));
+        assert!(Snapshot::from_bytes(b"Z80SNAP1").is_err());
+        assert_eq!(diff(&a, &a), "");
+
+        cpu.reg.pc = 0x0103;
+        cpu.flags.set(0x01);
+        cpu.int.iff1 = false;
+        cpu.memory.load_slice(0x8001, &[0; 10]);
+        let b = Snapshot::capture(&cpu);
+        assert_eq!(
+            diff(&a, &b),
+            "PC   0100 0103\n\
+             AF   0544 0501  Z 1 0, P 1 0, C 0 1\n\
+             IFF1 01 00\n\
+             mem  8001-8002  02 03 / 00 00\n"
+        );
+
+        let mut restored = Cpu::default();
+        a.restore(&mut restored);
+        assert_eq!(Snapshot::capture(&restored), a);
+    }
+
+    #[test]
+    fn trace_lines() {
+        assert_eq!(
+            diff_lines(
+                "0100 3E05     AF=05FF BC=0000 SP=FFFF CYC=7",
+                "0100 3E05     AF=05FE BC=0000 CYC=11"
+            ),
+            "AF   05FF 05FE  C 1 0\nCYC  7 11\n"
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Page};
+use crate::peripherals::ay::AyBus;
+use crate::peripherals::ula::TIMING_128K;
+use crate::peripherals::{Ay, Joystick, Kempston, Ula};
+use crate::zx_snapshot::{ZxSnapshot, BANK_SIZE};
+
+pub const CLOCK_48K: usize = 3_500_000;
+pub const CLOCK_128K: usize = 3_546_900;
+
+// Port 7FFD bits: RAM bank at C000, the screen in bank 7 instead of 5, the 48K BASIC ROM
+// and paging locked until reset
+const BANK: u8 = 0x07;
+const SHADOW_SCREEN: u8 = 0x08;
+const ROM_48K: u8 = 0x10;
+const LOCK: u8 = 0x20;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Model {
+    Spectrum48,
+    Spectrum128,
+}
+
+impl Model {
+    // Told apart by the ROM: 16K on the 48K, the editor and BASIC ROMs (32K) on the 128K
+    pub fn from_rom_size(size: usize) -> Option<Self> {
+        match size {
+            0x4000 => Some(Model::Spectrum48),
+            0x8000 => Some(Model::Spectrum128),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Model::Spectrum48 => write!(f, "48K"),
+            Model::Spectrum128 => write!(f, "128K"),
+        }
+    }
+}
+
+// A ZX Spectrum's memory map, ULA (display, border and beeper), sound and a Kempston
+// joystick, with the ULA's picture as the Interconnect's display. The 48K has its ROM at
+// 0000-3FFF and RAM above. The 128K has eight 16K RAM banks: 5 at 4000, 2 at 8000 and the
+// one picked through port 7FFD (A15 and A1 low) at C000, which also selects the ROM, the
+// screen bank and can lock the paging until reset. Its AY is selected / read through FFFD
+// and written through BFFD.
+pub struct Spectrum {
+    pub model: Model,
+    // Last write to 7FFD
+    pub port_7ffd: Rc<Cell<u8>>,
+    // On port FE
+    pub ula: Rc<RefCell<Ula>>,
+    pub ay: Option<Rc<RefCell<Ay>>>,
+    // Read through the Kempston interface on port 1F
+    pub joystick: Joystick,
+    // Offset of the ROMs in ROM storage
+    rom: usize,
+}
+
+impl Spectrum {
+    // Replaces the memory of `i` with the machine's and attaches its devices
+    pub fn install(i: &mut Interconnect, rom: &[u8]) -> Result<Self, String> {
+        let model = Model::from_rom_size(rom.len()).ok_or_else(|| {
+            format!(
+                "Expected a 16K (48K) or 32K (128K) Spectrum ROM, got {} bytes",
+                rom.len()
+            )
+        })?;
+        let mut memory = Memory::default();
+        let base = memory.rom.len();
+        memory.rom.extend_from_slice(rom);
+        memory.map_bank(0x0000, BANK_SIZE, Page::Rom(base));
+        let clock = match model {
+            Model::Spectrum48 => CLOCK_48K,
+            Model::Spectrum128 => CLOCK_128K,
+        };
+        i.clock_speed = clock;
+        let mut ula = Ula::new(clock as u64, 44_100);
+        if model == Model::Spectrum128 {
+            ula.timing = TIMING_128K;
+            ula.screen.set(5 * BANK_SIZE);
+        }
+        let screen = ula.screen.clone();
+        i.frame_cycles = Some(ula.timing.frame_cycles());
+        i.display = Some(ula.frame.clone());
+        let ula = i.add_device(ula);
+        i.register_port_decoded(0x0001, 0x0000, ula.clone());
+        let joystick = Joystick::default();
+        let kempston = i.add_device(Kempston::new(joystick.clone()));
+        i.register_port_decoded(0x00E0, 0x0000, kempston);
+        let mut spectrum = Self {
+            model,
+            port_7ffd: Rc::new(Cell::new(0)),
+            ula,
+            ay: None,
+            joystick,
+            rom: base,
+        };
+        if model == Model::Spectrum128 {
+            // Banks 0-7 are RAM storage 0000-1FFFF
+            memory.alloc_ram(8 * BANK_SIZE - memory.ram.len());
+            let port = spectrum.port_7ffd.clone();
+            memory.add_paging_register(0x8002, 0x0000, move |memory, value| {
+                if port.get() & LOCK == 0 {
+                    port.set(value);
+                    screen.set(screen_bank(value) * BANK_SIZE);
+                    page(memory, base, value);
+                }
+            });
+            page(&mut memory, base, 0);
+
+            let mut ay = Ay::new(CLOCK_128K as u64 / 2, CLOCK_128K as u64, 44_100);
+            ay.bus = AyBus::Spectrum;
+            let ay = i.add_device(ay);
+            i.register_port_decoded(0xC002, 0xC000, ay.clone());
+            i.register_port_decoded(0xC002, 0x8000, ay.clone());
+            spectrum.ay = Some(ay);
+        }
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        Ok(spectrum)
+    }
+
+    // The RAM bank the ULA shows
+    pub fn screen_bank(&self) -> usize {
+        screen_bank(self.port_7ffd.get())
+    }
+
+    // Sets 7FFD, as written by the CPU with paging unlocked
+    fn set_port_7ffd(&self, memory: &mut Memory, value: u8) {
+        self.port_7ffd.set(value);
+        self.ula.borrow().screen.set(screen_bank(value) * BANK_SIZE);
+        page(memory, self.rom, value);
+    }
+
+    // Back to the power on paging, with the lock released
+    pub fn reset(&self, memory: &mut Memory) {
+        if self.model == Model::Spectrum128 {
+            self.set_port_7ffd(memory, 0);
+        }
+    }
+
+    // Loads the registers, RAM banks, paging and AY state of a snapshot. A 48K snapshot
+    // on a 128K runs with the 48K ROM and the paging locked, as if started from the menu.
+    pub fn restore(&self, i: &mut Interconnect, snapshot: &ZxSnapshot) -> Result<(), String> {
+        if self.model == Model::Spectrum48 {
+            if snapshot.is_128k() {
+                return Err("128K snapshot on a 48K Spectrum".to_string());
+            }
+            snapshot.restore(&mut i.cpu);
+            return Ok(());
+        }
+        let value = match snapshot.is_128k() {
+            true => snapshot.port_7ffd,
+            false => ROM_48K | LOCK,
+        };
+        self.set_port_7ffd(&mut i.cpu.memory, value);
+        snapshot.restore(&mut i.cpu);
+        for (bank, data) in snapshot.banks.iter().enumerate() {
+            i.cpu.memory.load_ram(bank * BANK_SIZE, data);
+        }
+        if let (Some(ay), Some(state)) = (&self.ay, &snapshot.ay) {
+            let mut ay = ay.borrow_mut();
+            for (reg, &value) in state.registers.iter().enumerate() {
+                ay.select(reg as u8);
+                ay.write(value);
+            }
+            ay.select(state.selected);
+        }
+        Ok(())
+    }
+}
+
+fn screen_bank(port_7ffd: u8) -> usize {
+    match port_7ffd & SHADOW_SCREEN {
+        0 => 5,
+        _ => 7,
+    }
+}
+
+// Maps the ROM and the bank at C000 selected by a 7FFD value
+fn page(memory: &mut Memory, rom: usize, value: u8) {
+    let rom = rom + ((value & ROM_48K) >> 4) as usize * BANK_SIZE;
+    let bank = (value & BANK) as usize;
+    memory.map_bank(0x0000, BANK_SIZE, Page::Rom(rom));
+    memory.map_bank(0x4000, BANK_SIZE, Page::Ram(5 * BANK_SIZE));
+    memory.map_bank(0x8000, BANK_SIZE, Page::Ram(2 * BANK_SIZE));
+    memory.map_bank(0xC000, BANK_SIZE, Page::Ram(bank * BANK_SIZE));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, Spectrum, BANK_SIZE};
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+    use crate::peripherals::joystick::Button;
+    use crate::zx_snapshot::{AyState, ZxSnapshot};
+
+    // Both ROMs, each filled with its number
+    fn rom_128k() -> Vec<u8> {
+        let mut rom = vec![0; BANK_SIZE];
+        rom.extend(vec![1; BANK_SIZE]);
+        rom
+    }
+
+    #[test]
+    fn paging() {
+        let mut i = Interconnect::builder().build();
+        assert!(Spectrum::install(&mut i, &[0; 100]).is_err());
+        let spectrum = Spectrum::install(&mut i, &rom_128k()).unwrap();
+        assert_eq!(spectrum.model, Model::Spectrum128);
+        assert_eq!(i.clock_speed, 3_546_900);
+        i.cpu.write8(0xC000, 0x42);
+        assert_eq!(i.cpu.memory.ram[0], 0x42);
+
+        // LD BC, 7FFD; LD A, 1B; OUT (C), A: bank 3, shadow screen, 48K ROM
+        i.cpu
+            .memory
+            .load_slice(0x8000, &[0x01, 0xFD, 0x7F, 0x3E, 0x1B, 0xED, 0x79]);
+        i.cpu.reg.pc = 0x8000;
+        for _ in 0..3 {
+            i.step();
+        }
+        assert_eq!(i.cpu.read8(0x0000), 1);
+        assert_eq!(spectrum.screen_bank(), 7);
+        assert_eq!(spectrum.ula.borrow().screen.get(), 7 * BANK_SIZE);
+        i.cpu.write8(0xC000, 0x43);
+        assert_eq!(i.cpu.memory.ram[3 * BANK_SIZE], 0x43);
+        // Bank 5 is also at 4000, bank 2 at 8000
+        i.cpu.memory.paging_write(0x7FFD, 0x05);
+        assert_eq!(i.cpu.read8(0x4000), i.cpu.read8(0xC000));
+        i.cpu.write8(0x4000, 0x44);
+        assert_eq!(i.cpu.read8(0xC000), 0x44);
+
+        // Locked until reset
+        i.cpu.memory.paging_write(0x7FFD, 0x20);
+        i.cpu.memory.paging_write(0x7FFD, 0x10);
+        assert_eq!(i.cpu.read8(0x0000), 0);
+        spectrum.reset(&mut i.cpu.memory);
+        i.cpu.memory.paging_write(0x7FFD, 0x10);
+        assert_eq!(i.cpu.read8(0x0000), 1);
+    }
+
+    #[test]
+    fn ay_ports() {
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &rom_128k()).unwrap();
+        // LD BC, FFFD; LD A, 8; OUT (C), A; LD B, BF; LD A, 0F; OUT (C), A; LD B, FF; IN A, (C)
+        i.cpu.memory.load_slice(
+            0x8000,
+            &[
+                0x01, 0xFD, 0xFF, 0x3E, 0x08, 0xED, 0x79, 0x06, 0xBF, 0x3E, 0x0F, 0xED, 0x79, 0x06,
+                0xFF, 0xED, 0x78,
+            ],
+        );
+        i.cpu.reg.pc = 0x8000;
+        for _ in 0..8 {
+            i.step();
+        }
+        let ay = spectrum.ay.unwrap();
+        assert_eq!(ay.borrow().regs[8], 0x0F);
+        assert_eq!(i.cpu.reg.a, 0x0F);
+
+        // Nothing on the 48K
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &[0; BANK_SIZE]).unwrap();
+        assert_eq!(spectrum.model, Model::Spectrum48);
+        assert!(spectrum.ay.is_none());
+        assert_eq!(i.devices.len(), 2);
+    }
+
+    #[test]
+    fn kempston() {
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &[0; BANK_SIZE]).unwrap();
+        spectrum.joystick.press(Button::Fire);
+        spectrum.joystick.press(Button::Right);
+        // IN A, (1F); LD BC, 00FE; IN A, (C)
+        i.cpu
+            .memory
+            .load_slice(0x8000, &[0xDB, 0x1F, 0x01, 0xFE, 0x00, 0xED, 0x78]);
+        i.cpu.reg.pc = 0x8000;
+        i.step();
+        assert_eq!(i.cpu.reg.a, 0x11);
+        // The ULA still has port FE
+        i.step();
+        i.step();
+        assert_eq!(i.cpu.reg.a, 0xFF);
+    }
+
+    #[test]
+    fn restore_128k() {
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &rom_128k()).unwrap();
+        let mut snapshot = ZxSnapshot::capture(&i.cpu, 0);
+        snapshot.banks = (0..8).map(|bank| vec![bank as u8; BANK_SIZE]).collect();
+        snapshot.port_7ffd = 0x16;
+        snapshot.cpu.memory[0xC000..].fill(6);
+        snapshot.cpu.memory[0x4000..0x8000].fill(5);
+        snapshot.cpu.memory[0x8000..0xC000].fill(2);
+        snapshot.cpu.pc = 0x1234;
+        snapshot.ay = Some(AyState {
+            selected: 7,
+            registers: [3; 16],
+        });
+        spectrum.restore(&mut i, &snapshot).unwrap();
+        assert_eq!(i.cpu.reg.pc, 0x1234);
+        assert_eq!(i.cpu.read8(0x0000), 1);
+        assert_eq!(i.cpu.read8(0xC000), 6);
+        assert_eq!(i.cpu.memory.ram[7 * BANK_SIZE], 7);
+        // Every bank is redrawn by the frame hash
+        assert_eq!(
+            i.cpu.memory.take_dirty(),
+            (0..8 * BANK_SIZE / 1024).collect::<Vec<_>>()
+        );
+        let ay = spectrum.ay.as_ref().unwrap().borrow();
+        assert_eq!((ay.address, ay.regs[0]), (7, 3));
+    }
+
+    #[test]
+    fn display() {
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &rom_128k()).unwrap();
+        // Red ink on white paper in bank 7, shown once it's the screen
+        i.cpu.memory.ram[7 * BANK_SIZE] = 0x80;
+        i.cpu.memory.ram[7 * BANK_SIZE + 0x1800] = 0x3A;
+        i.cpu.memory.paging_write(0x7FFD, 0x08);
+        // NOPs for a frame
+        i.cpu.reg.pc = 0x8000;
+        while spectrum.ula.borrow().frames() == 0 {
+            i.step();
+        }
+        let display = i.display.as_ref().unwrap().borrow();
+        assert_eq!(display.pixel(32, 48), [0xD7, 0x00, 0x00, 0xFF]);
+        assert_eq!(display.pixel(33, 48), [0xD7, 0xD7, 0xD7, 0xFF]);
+    }
+
+    #[test]
+    fn frame_interrupt() {
+        // Interrupt handler, returning once /INT is gone: INC B; EX (SP), HL; EX (SP), HL;
+        // EI; RET
+        let mut rom = vec![0; BANK_SIZE];
+        rom[0x38..0x3D].copy_from_slice(&[0x04, 0xE3, 0xE3, 0xFB, 0xC9]);
+        let mut i = Interconnect::builder().build();
+        let spectrum = Spectrum::install(&mut i, &rom).unwrap();
+        assert_eq!(i.frame_cycles, Some(69_888));
+        // IM 1; 8 NOPs; EI; JP $. Interrupts are enabled after /INT is gone in frame 0.
+        let mut program = vec![0xED, 0x56];
+        program.extend([0x00; 8]);
+        program.extend([0xFB, 0xC3, 0x0B, 0x80]);
+        i.cpu.memory.load_slice(0x8000, &program);
+        i.cpu.reg.pc = 0x8000;
+        i.cpu.reg.sp = 0xFF00;
+        let run_to = |i: &mut Interconnect, cycles: u64| {
+            while spectrum.ula.borrow().cycles < cycles {
+                i.step();
+            }
+        };
+        run_to(&mut i, 69_888 / 2);
+        assert!(!i.cpu.int.irq);
+        assert_eq!(i.cpu.reg.b, 0);
+        run_to(&mut i, 69_888 * 5 / 2);
+        assert_eq!(i.cpu.reg.b, 2);
+    }
+}
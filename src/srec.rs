@@ -0,0 +1,89 @@
+use crate::ihex::{decode, Segment};
+
+// Motorola S-records (`.s19`, `.s28`, `.s37`, `.srec`, `.mot`). Each line is
+// `S<type><count><address><data><checksum>`, the count covering the address, data and
+// checksum bytes and the checksum being the ones' complement of their sum with the count.
+// Types handled:
+//
+//   S0          header, ignored
+//   S1, S2, S3  data with a 16, 24 or 32-bit address
+//   S5, S6      record count, ignored
+//   S7, S8, S9  start address, ends the file
+//
+// Wide addresses are fine as long as the data is in the 64K address space.
+
+// Data records in file order
+pub fn parse(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        let (kind, bytes) = line
+            .strip_prefix('S')
+            .and_then(|rest| Some((rest.get(..1)?, decode(rest.get(1..)?)?)))
+            .ok_or_else(|| err("not an S-record"))?;
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err("wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+            return Err(err("bad checksum"));
+        }
+        let width = match kind {
+            "0" | "5" | "6" => continue,
+            "7" | "8" | "9" => break,
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            _ => return Err(err(&format!("unknown record type S{}", kind))),
+        };
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < width {
+            return Err(err("record too short for its address"));
+        }
+        let (addr, data) = body.split_at(width);
+        let start = addr.iter().fold(0u64, |a, b| a << 8 | *b as u64);
+        if start as usize + data.len() > 0x1_0000 {
+            return Err(err(&format!(
+                "data at {:X} is outside the 64K address space",
+                start
+            )));
+        }
+        segments.push((start as u16, data.to_vec()));
+    }
+    Ok(segments)
+}
+
+// True for the file extensions S-record files usually have
+pub fn is_srec(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    [".s19", ".s28", ".s37", ".srec", ".mot"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_srec, parse};
+
+    #[test]
+    fn records() {
+        let text = "S00600004844521B\n\
+                    S106010021008057\n\
+                    S2050080007703\n\
+                    S5030002FA\n\
+                    S9030100FB\n\
+                    S1040000C932\n";
+        assert_eq!(
+            parse(text),
+            Ok(vec![(0x0100, vec![0x21, 0x00, 0x80]), (0x8000, vec![0x77])])
+        );
+        assert!(parse("S106010021008058").unwrap_err().contains("checksum"));
+        assert!(parse("S2050100007782").is_err());
+        assert!(parse(":030000002100805C").is_err());
+        assert!(is_srec("ROM.S19"));
+        assert!(!is_srec("rom.hex"));
+    }
+}
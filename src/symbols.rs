@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Symbols further than this below an address aren't used to describe it
+const MAX_OFFSET: u16 = 0x100;
+
+// Names for addresses, loaded from an assembler's symbol or map file. One symbol per line
+// in any of these forms:
+//
+//   main_loop = $8000       name=addr, as written by zmac and most map files
+//   main_loop: EQU 0x8000   sjasmplus --sym
+//   main_loop EQU 8000h
+//   main_loop 8000          name and value separated by whitespace
+//
+// Values take the `$`, `#`, `0x` or trailing `h` hex prefixes and are hex without one,
+// which is what every symbol file seen so far uses. Values above 0xFFFF (banked symbols)
+// keep their low 16 bits. Blank lines and lines starting with `;`, `#` or `//` are skipped.
+//
+// SDCC's .cdb debug files are read with `parse_cdb`, which also maps addresses to C
+// source lines.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+// Source line the code at an address was generated from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl Symbols {
+    // Files ending in .cdb are read as SDCC debug info, anything else as a symbol file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let cdb = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("cdb"));
+        match cdb {
+            true => Ok(Self::parse_cdb(&text)),
+            false => Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with([';', '#']) || line.starts_with("//") {
+                continue;
+            }
+            let (name, addr) = parse_line(line)
+                .ok_or_else(|| format!("line {}: can't parse `{}`", n + 1, line))?;
+            symbols.insert(name, addr);
+        }
+        Ok(symbols)
+    }
+
+    // Reads the linker records of an SDCC .cdb file:
+    //
+    //   L:G$main$0_0$0:120             global function or variable `main` at 0x0120
+    //   L:F$game.c$tick$0_0$0:1A4      function or variable local to game.c
+    //   L:C$game.c$42$1_0$5:1B0        code for line 42 of game.c starts at 0x01B0
+    //
+    // Function end (XG, XF), local variable and assembler line records along with the
+    // type information in the other record kinds are skipped, as is anything that doesn't
+    // parse since the format has changed between SDCC releases.
+    pub fn parse_cdb(text: &str) -> Self {
+        let mut symbols = Self::default();
+        for record in text
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("L:"))
+        {
+            let (fields, addr) = match record.rsplit_once(':') {
+                Some((fields, addr)) => (fields, u32::from_str_radix(addr, 16)),
+                None => continue,
+            };
+            let addr = match addr {
+                Ok(addr) => addr as u16,
+                Err(_) => continue,
+            };
+            let fields: Vec<&str> = fields.split('$').collect();
+            match fields[..] {
+                ["G", name, ..] | ["F", _, name, ..] => symbols.insert(name, addr),
+                ["C", file, line, ..] => {
+                    if let Ok(line) = line.parse() {
+                        let file = file.to_string();
+                        symbols.lines.insert(addr, SourceLine { file, line });
+                    }
+                }
+                _ => {}
+            }
+        }
+        symbols
+    }
+
+    // Adds `name`, replacing an earlier symbol of the same name. When several names share
+    // an address the first one added is shown.
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            if self.by_addr.get(&old).map(String::as_str) == Some(name) {
+                self.by_addr.remove(&old);
+            }
+        }
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn extend(&mut self, other: &Symbols) {
+        // Names `other` shows go first so they're also the ones shown here
+        for (&addr, name) in &other.by_addr {
+            self.insert(name, addr);
+        }
+        for (name, &addr) in &other.by_name {
+            self.insert(name, addr);
+        }
+        self.lines
+            .extend(other.lines.iter().map(|(&addr, line)| (addr, line.clone())));
+    }
+
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    // `name` or `name+offset` using the closest symbol at or below `addr`
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (&base, name) = self.by_addr.range(..=addr).next_back()?;
+        match addr - base {
+            0 => Some(name.clone()),
+            offset if offset < MAX_OFFSET => Some(format!("{}+{:X}", name, offset)),
+            _ => None,
+        }
+    }
+
+    // The source line whose code starts at `addr`
+    pub fn line_at(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+
+    // The source line `addr` belongs to, taken as the closest line record below it
+    pub fn source_line(&self, addr: u16) -> Option<&SourceLine> {
+        let (&start, line) = self.lines.range(..=addr).next_back()?;
+        (addr - start < MAX_OFFSET).then_some(line)
+    }
+
+    // Start of the code for `line` in `file`, matching the file name with or without
+    // its directory
+    pub fn line_addr(&self, file: &str, line: u32) -> Option<u16> {
+        self.lines
+            .iter()
+            .find(|(_, l)| {
+                l.line == line && (l.file == file || l.file.rsplit('/').next() == Some(file))
+            })
+            .map(|(&addr, _)| addr)
+    }
+
+    // Symbols in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_addr
+            .iter()
+            .map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Option<(&str, u16)> {
+    if let Some((name, value)) = line.split_once('=') {
+        return Some((valid_name(name.trim())?, parse_value(value.trim())?));
+    }
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let name = valid_name(tokens[0].trim_end_matches(':'))?;
+    match tokens[1..] {
+        [equ, value] if equ.eq_ignore_ascii_case("equ") => Some((name, parse_value(value)?)),
+        [value] => Some((name, parse_value(value)?)),
+        _ => None,
+    }
+}
+
+fn valid_name(name: &str) -> Option<&str> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || "_.@?".contains(c))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.@?$".contains(c));
+    valid.then_some(name)
+}
+
+fn parse_value(value: &str) -> Option<u16> {
+    let lower = value.to_ascii_lowercase();
+    let digits = if let Some(hex) = lower.strip_prefix("0x") {
+        hex
+    } else if let Some(hex) = lower.strip_prefix(['$', '#']) {
+        hex
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        hex
+    } else {
+        &lower
+    };
+    u32::from_str_radix(digits, 16).ok().map(|v| v as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbols;
+
+    #[test]
+    fn formats() {
+        let symbols = Symbols::parse(
+            "; zmac\n\
+             start = $0100\n\
+             main_loop=0x0120\n\
+             \n\
+             # sjasmplus\n\
+             data.table: EQU 0x00018200\n\
+             print equ 0150h\n\
+             bdos 0005\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.addr("main_loop"), Some(0x0120));
+        assert_eq!(symbols.addr("data.table"), Some(0x8200));
+        assert_eq!(symbols.addr("print"), Some(0x0150));
+        assert_eq!(symbols.name(0x0005), Some("bdos"));
+        assert_eq!(symbols.name(0x0101), None);
+        assert!(Symbols::parse("start = zz").is_err());
+        assert!(Symbols::parse("1abc = 0100").is_err());
+        assert!(Symbols::parse("start").is_err());
+    }
+
+    #[test]
+    fn describe() {
+        let mut symbols = Symbols::default();
+        symbols.insert("start", 0x0100);
+        symbols.insert("alias", 0x0100);
+        symbols.insert("moved", 0x0200);
+        symbols.insert("moved", 0x0300);
+        assert_eq!(symbols.describe(0x0100).as_deref(), Some("start"));
+        assert_eq!(symbols.describe(0x0123).as_deref(), Some("start+23"));
+        assert_eq!(symbols.describe(0x0200), None);
+        assert_eq!(symbols.describe(0x00FF), None);
+        assert_eq!(symbols.name(0x0300), Some("moved"));
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            vec![(0x0100, "start"), (0x0300, "moved")]
+        );
+    }
+
+    #[test]
+    fn sdcc_cdb() {
+        let symbols = Symbols::parse_cdb(
+            "M:game\n\
+             F:G$main$0_0$0({2}DF,SV:S),C,0,0,0,0,0\n\
+             S:G$score$0_0$0({2}SI:S),E,0,0\n\
+             L:G$main$0_0$0:120\n\
+             L:XG$main$0$0:15F\n\
+             L:F$game.c$tick$0_0$0:1A4\n\
+             L:G$score$0_0$0:C000\n\
+             L:C$game.c$12$1_0$5:120\n\
+             L:C$game.c$13$1_0$5:126\n\
+             L:A$game$87:120\n\
+             L:G$broken:zz\n",
+        );
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.addr("main"), Some(0x0120));
+        assert_eq!(symbols.name(0x01A4), Some("tick"));
+        assert_eq!(symbols.addr("score"), Some(0xC000));
+        assert_eq!(symbols.line_at(0x0126).unwrap().to_string(), "game.c:13");
+        assert_eq!(symbols.line_at(0x0127), None);
+        assert_eq!(symbols.source_line(0x0124).unwrap().line, 12);
+        assert_eq!(symbols.line_addr("game.c", 13), Some(0x0126));
+        assert_eq!(symbols.line_addr("game.c", 14), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::io;
+use std::path::Path;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::device::Device;
+use crate::instruction_info::Register::{DE, IX};
+use crate::interconnect::HookAction;
+use crate::memory::MemoryRW;
+
+// LD-BYTES in the 48K Spectrum ROM. Called with the flag byte expected in A, the length in
+// DE, the destination in IX and carry set to load (reset to verify), returns with carry set
+// if the block loaded.
+pub const LD_BYTES: u16 = 0x0556;
+
+// A .tap tape image: the blocks the ROM saves, each stored as a 16-bit little endian length
+// followed by the block, which is a flag byte (00 for headers, FF for data), the data and a
+// checksum byte making the XOR of the whole block zero.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Tap {
+    pub blocks: Vec<Vec<u8>>,
+    // Index of the block the next load reads
+    pub next: usize,
+}
+
+impl Tap {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let block = rest
+                .get(2..)
+                .and_then(|tail| tail.get(..u16::from_le_bytes([rest[0], rest[1]]) as usize))
+                .ok_or_else(|| format!("Truncated block {}", blocks.len()))?;
+            rest = &rest[2 + block.len()..];
+            blocks.push(block.to_vec());
+        }
+        Ok(Self { blocks, next: 0 })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&archive::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for block in &self.blocks {
+            out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+        out
+    }
+
+    // Does what a call to `LD_BYTES` would with the next block on the tape, for a PC hook
+    // there. Memory is written as the CPU would, so ROM stays intact. A block with another
+    // flag is skipped and fails the load, like it does on real hardware. Once the tape has
+    // run out the ROM routine runs as usual, waiting for a signal that never comes.
+    pub fn fast_load(&mut self, cpu: &mut Cpu) -> HookAction {
+        let block = match self.blocks.get(self.next) {
+            Some(block) => block,
+            None => return HookAction::Execute,
+        };
+        self.next += 1;
+        let load = cpu.flags.cf;
+        let len = cpu.read_pair(DE) as usize;
+        let mut ok = block.first() == Some(&cpu.reg.a);
+        if ok {
+            // The data and the checksum
+            let data = &block[1..];
+            let count = len.min(data.len());
+            let start = cpu.read_pair(IX);
+            for (n, &byte) in data[..count].iter().enumerate() {
+                let addr = start.wrapping_add(n as u16);
+                if load {
+                    cpu.write8(addr, byte);
+                } else if cpu.memory.peek(addr) != byte {
+                    ok = false;
+                }
+            }
+            cpu.write_pair(IX, start.wrapping_add(count as u16));
+            cpu.write_pair(DE, (len - count) as u16);
+            // The byte after the data is taken as the checksum
+            ok &= len < data.len() && block[..len + 2].iter().fold(0, |sum, b| sum ^ b) == 0;
+        }
+        cpu.flags.cf = ok;
+        HookAction::Return
+    }
+}
+
+// Standard ROM loader timings, in T states at 3.5MHz
+pub const PILOT: u32 = 2168;
+pub const SYNC1: u32 = 667;
+pub const SYNC2: u32 = 735;
+pub const ZERO: u32 = 855;
+pub const ONE: u32 = 1710;
+// Pilot pulses before a header (flag below 80) and before data
+pub const HEADER_PILOTS: usize = 8063;
+pub const DATA_PILOTS: usize = 3223;
+pub const MILLISECOND: u32 = 3500;
+
+// What a tape plays into the EAR input, built from tape images by `Pulses`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Signal {
+    // Flip the level, then hold it for this many T states
+    Edge(u32),
+    // Set the level and hold it
+    Hold(bool, u32),
+    // Stop the tape, as with the stop blocks of a .tzx. `TapePlayer::play` continues.
+    Stop,
+}
+
+// Builds a signal from the parts tape blocks are made of
+#[derive(Debug, Default)]
+pub struct Pulses(pub Vec<Signal>);
+
+impl Pulses {
+    pub fn tone(&mut self, len: u32, count: usize) {
+        self.0.extend(std::iter::repeat_n(Signal::Edge(len), count));
+    }
+
+    pub fn pulse(&mut self, len: u32) {
+        self.0.push(Signal::Edge(len));
+    }
+
+    // Two pulses per bit, most significant bit first. Only the top `last_bits` of the
+    // last byte are played.
+    pub fn data(&mut self, data: &[u8], zero: u32, one: u32, last_bits: u8) {
+        for (n, &byte) in data.iter().enumerate() {
+            let bits = if n + 1 == data.len() { last_bits } else { 8 };
+            for bit in 0..bits.min(8) {
+                let len = if byte & (0x80 >> bit) != 0 { one } else { zero };
+                self.tone(len, 2);
+            }
+        }
+    }
+
+    // Silence, the level drops low
+    pub fn pause(&mut self, ms: u32) {
+        if ms > 0 {
+            self.0.push(Signal::Hold(false, ms * MILLISECOND));
+        }
+    }
+
+    // A block as the ROM saves it, `data` starting with the flag byte
+    pub fn standard(&mut self, data: &[u8], pause: u32) {
+        let pilots = match data.first() {
+            Some(flag) if *flag < 0x80 => HEADER_PILOTS,
+            _ => DATA_PILOTS,
+        };
+        self.tone(PILOT, pilots);
+        self.pulse(SYNC1);
+        self.pulse(SYNC2);
+        self.data(data, ZERO, ONE, 8);
+        self.pause(pause);
+    }
+}
+
+impl Tap {
+    // The tape as the ROM would have saved it, with a second between blocks, for loaders
+    // that read the EAR input instead of going through `LD_BYTES`
+    pub fn to_signal(&self) -> Vec<Signal> {
+        let mut pulses = Pulses::default();
+        for block in &self.blocks {
+            pulses.standard(block, 1000);
+        }
+        pulses.0
+    }
+}
+
+// Plays a signal into the EAR input, bit 6 of reads from the Spectrum's port FE (any even
+// port). Attach with `Interconnect::insert_tape`. The other bits read as 1, no keys
+// pressed. The level changes with the cycle count, at instruction granularity.
+#[derive(Debug)]
+pub struct TapePlayer {
+    pub signal: Vec<Signal>,
+    pub playing: bool,
+    pos: usize,
+    // T states left of the current signal
+    left: u64,
+    level: bool,
+}
+
+impl TapePlayer {
+    pub fn new(signal: Vec<Signal>) -> Self {
+        Self {
+            signal,
+            playing: true,
+            pos: 0,
+            left: 0,
+            level: false,
+        }
+    }
+
+    pub fn ear(&self) -> bool {
+        self.level
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.left = 0;
+        self.level = false;
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pos >= self.signal.len() && self.left == 0
+    }
+
+    pub fn advance(&mut self, mut cycles: u64) {
+        while self.playing && cycles > 0 {
+            if self.left > cycles {
+                self.left -= cycles;
+                return;
+            }
+            cycles -= self.left;
+            self.left = 0;
+            match self.signal.get(self.pos) {
+                Some(&Signal::Edge(len)) => {
+                    self.level = !self.level;
+                    self.left = len as u64;
+                }
+                Some(&Signal::Hold(level, len)) => {
+                    self.level = level;
+                    self.left = len as u64;
+                }
+                Some(Signal::Stop) | None => self.playing = false,
+            }
+            self.pos = (self.pos + 1).min(self.signal.len());
+        }
+    }
+}
+
+impl Device for TapePlayer {
+    fn tick(&mut self, cycles: usize) {
+        self.advance(cycles as u64);
+    }
+
+    fn io_read(&mut self, _port: u16) -> u8 {
+        0xBF | (self.level as u8) << 6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pulses, Signal, Tap, TapePlayer};
+    use crate::interconnect::{Interconnect, Preset};
+
+    // LD IX, 8000; LD DE, 3; LD A, FF; SCF; CALL 0556 (LD_BYTES); HALT
+    const LOADER: [u8; 15] = [
+        0xDD, 0x21, 0x00, 0x80, 0x11, 0x03, 0x00, 0x3E, 0xFF, 0x37, 0xCD, 0x56, 0x05, 0x76, 0x00,
+    ];
+
+    fn run(blocks: Vec<Vec<u8>>) -> Interconnect {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &LOADER);
+        i.insert_tap(Tap { blocks, next: 0 });
+        while !i.cpu.int.halt {
+            i.step();
+        }
+        i
+    }
+
+    #[test]
+    fn blocks() {
+        let data = [3, 0, 0xFF, 0x55, 0xAA, 2, 0, 0x00, 0x00];
+        let tap = Tap::parse(&data).unwrap();
+        assert_eq!(tap.blocks, [vec![0xFF, 0x55, 0xAA], vec![0x00, 0x00]]);
+        assert_eq!(tap.to_bytes(), data);
+        assert!(Tap::parse(&[4, 0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn fast_load() {
+        let block = vec![0xFF, 0x11, 0x22, 0x33, 0xFF ^ 0x11 ^ 0x22 ^ 0x33];
+        let i = run(vec![block.clone()]);
+        assert_eq!(i.dump_range(0x8000, 3).get(6..14), Some("11 22 33"));
+        assert!(i.cpu.flags.cf);
+        assert_eq!(i.cpu.reg.ix, 0x8003);
+
+        // A header where data was expected fails the load and is skipped
+        let i = run(vec![vec![0x00, 0x00], block.clone()]);
+        assert!(!i.cpu.flags.cf);
+        assert_eq!(i.peek(0x8000), 0x00);
+
+        let mut bad = block;
+        bad[4] ^= 1;
+        let i = run(vec![bad]);
+        assert!(!i.cpu.flags.cf);
+        assert_eq!(i.peek(0x8000), 0x11);
+    }
+
+    #[test]
+    fn pulses() {
+        let mut pulses = Pulses::default();
+        pulses.data(&[0x80, 0xFF], 100, 200, 1);
+        pulses.pause(2);
+        pulses.0.push(Signal::Stop);
+        pulses.pulse(50);
+        assert_eq!(pulses.0.len(), 2 * 9 + 3);
+        assert_eq!(
+            pulses.0[..3],
+            [Signal::Edge(200), Signal::Edge(200), Signal::Edge(100)]
+        );
+
+        let mut player = TapePlayer::new(pulses.0);
+        player.advance(1);
+        assert!(player.ear());
+        player.advance(199);
+        assert!(!player.ear());
+        player.advance(200);
+        assert!(player.ear());
+        // Through the remaining bits and the pause to the stop
+        player.advance(200 + 100 * 13 + 200 * 2 + 7000 + 1);
+        assert!(!player.playing);
+        player.play();
+        player.advance(1);
+        assert!(player.ear());
+        player.advance(50);
+        assert!(player.finished());
+
+        let tap = Tap {
+            blocks: vec![vec![0x00, 0x01], vec![0xFF]],
+            next: 0,
+        };
+        let signal = tap.to_signal();
+        assert_eq!(signal.len(), 8063 + 2 + 32 + 1 + 3223 + 2 + 16 + 1);
+    }
+}
@@ -0,0 +1,1005 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::formatter::{FmtBuf, HexBytes};
+use crate::instruction_info::{Instruction, Mnemonic, Operand, Register};
+use crate::symbols::Symbols;
+
+// An executed instruction along with the register state after executing it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub bytes: [u8; 4],
+    pub len: u8,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub cycles: usize,
+}
+
+impl TraceEntry {
+    // `pc` and `bytes` are taken before the instruction executes, registers after
+    pub fn new(pc: u16, bytes: [u8; 4], cpu: &Cpu) -> Self {
+        let reg = &cpu.reg;
+        let pair = |h: u8, l: u8| (h as u16) << 8 | l as u16;
+        Self {
+            pc,
+            bytes,
+            len: Instruction::decode_bytes(&bytes).map_or(1, |i| i.size),
+            af: pair(reg.a, cpu.flags.get()),
+            bc: pair(reg.b, reg.c),
+            de: pair(reg.d, reg.e),
+            hl: pair(reg.h, reg.l),
+            ix: reg.ix,
+            iy: reg.iy,
+            sp: reg.sp,
+            cycles: cpu.cycles,
+        }
+    }
+
+    pub fn instruction(&self) -> Option<Instruction> {
+        Instruction::decode_bytes(&self.bytes)
+    }
+
+    pub fn opcode_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    // Fixed width line meant for diffing against other emulators, no disassembly so
+    // decoder differences don't show up as divergence
+    pub fn line(&self) -> String {
+        Line(self).to_string()
+    }
+}
+
+// `TraceEntry::line` without the allocation, for the trace writer
+struct Line<'a>(&'a TraceEntry);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = self.0;
+        write!(
+            f,
+            "{:04X} {:<8} AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X} SP={:04X} CYC={}",
+            entry.pc,
+            HexBytes(entry.opcode_bytes(), ""),
+            entry.af,
+            entry.bc,
+            entry.de,
+            entry.hl,
+            entry.ix,
+            entry.iy,
+            entry.sp,
+            entry.cycles
+        )
+    }
+}
+
+// The line MAME's `trace` command writes for the instruction. Its Z80 disassembler pads the
+// mnemonic to 4 characters, separates operands with a bare comma and writes registers in
+// lower case, immediates in upper case hex and index displacements in lower case hex. The
+// undocumented DDCB / FDCB forms show the register the result is copied to first, as in
+// `rlc  b=(ix+$05)`.
+struct MameLine<'a>(&'a TraceEntry);
+
+impl fmt::Display for MameLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = self.0;
+        write!(f, "{:04X}: ", entry.pc)?;
+        let i = match entry.instruction() {
+            Some(i) => i,
+            None => return write!(f, "db   ${:02X}", entry.bytes[0]),
+        };
+        let target = i.branch_target(entry.pc).unwrap_or(entry.pc);
+        let mut operands = Vec::new();
+        // IN (C) that only sets the flags
+        if i.mnemonic == Mnemonic::In && i.dst.is_none() {
+            operands.push("0".to_string());
+        }
+        operands.extend(
+            i.dst
+                .iter()
+                .chain(i.src.iter())
+                .map(|&operand| mame_operand(operand, target)),
+        );
+        let name = i.mnemonic.name().to_ascii_lowercase();
+        if operands.is_empty() {
+            return f.write_str(&name);
+        }
+        write!(f, "{:<4} ", name)?;
+        if let Some(copy) = i.copy {
+            write!(f, "{}=", mame_register(copy))?;
+        }
+        f.write_str(&operands.join(","))
+    }
+}
+
+fn mame_register(reg: Register) -> String {
+    match reg {
+        Register::IXH => "hx".to_string(),
+        Register::IXL => "lx".to_string(),
+        Register::IYH => "hy".to_string(),
+        Register::IYL => "ly".to_string(),
+        _ => reg.to_string().to_ascii_lowercase(),
+    }
+}
+
+// `target` is where a relative jump goes, MAME shows the address rather than the offset
+fn mame_operand(operand: Operand, target: u16) -> String {
+    match operand {
+        Operand::Reg(reg) => mame_register(reg),
+        Operand::AltAf => "af'".to_string(),
+        Operand::Imm8(n) => format!("${:02X}", n),
+        Operand::Imm16(nn) => format!("${:04X}", nn),
+        Operand::Number(n) => n.to_string(),
+        Operand::Indirect(reg) => format!("({})", mame_register(reg)),
+        Operand::Indexed(reg, d) => format!(
+            "({}{}${:02x})",
+            mame_register(reg),
+            if d < 0 { '-' } else { '+' },
+            d.unsigned_abs()
+        ),
+        Operand::Absolute(nn) => format!("(${:04X})", nn),
+        Operand::Port(n) => format!("(${:02X})", n),
+        Operand::PortC => "(c)".to_string(),
+        Operand::Relative(_) => format!("${:04X}", target),
+        Operand::Condition(cc) => cc.to_string().to_ascii_lowercase(),
+    }
+}
+
+// Trace line layout given as text with `{field}` placeholders, for matching the traces of
+// other emulators. Fields are pc, af, bc, de, hl, ix, iy, sp and a, f, b, c, d, e, h, l in
+// upper case hex, cycles in decimal, bytes (`3E 05`), op (`3E05`), asm (the disassembly)
+// and flags (`SZYHXPNC` with `.` for those that are clear). `{field:x}` writes hex in lower
+// case and a width pads the field, left aligned unless it starts with `>`, e.g.
+// `{pc:x}: {asm:<20} {af:x} {cycles:>10}`. `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceTemplate {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(Field, Spec),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Field {
+    Pc,
+    Af,
+    Bc,
+    De,
+    Hl,
+    Ix,
+    Iy,
+    Sp,
+    // A, F, B, C, D, E, H or L by position
+    Byte(usize),
+    Cycles,
+    Bytes,
+    Op,
+    Asm,
+    Flags,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+struct Spec {
+    lower: bool,
+    right: bool,
+    width: usize,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        let field = match name {
+            "pc" => Field::Pc,
+            "af" => Field::Af,
+            "bc" => Field::Bc,
+            "de" => Field::De,
+            "hl" => Field::Hl,
+            "ix" => Field::Ix,
+            "iy" => Field::Iy,
+            "sp" => Field::Sp,
+            "a" | "f" | "b" | "c" | "d" | "e" | "h" | "l" => Field::Byte("afbcdehl".find(name)?),
+            "cycles" | "cyc" => Field::Cycles,
+            "bytes" => Field::Bytes,
+            "op" => Field::Op,
+            "asm" => Field::Asm,
+            "flags" => Field::Flags,
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+impl FromStr for TraceTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err("Unterminated `{` in trace template".to_string()),
+                        }
+                    }
+                    let (name, spec) = placeholder.split_once(':').unwrap_or((&placeholder, ""));
+                    let field = Field::parse(&name.trim().to_ascii_lowercase())
+                        .ok_or_else(|| format!("Unknown trace field: {{{}}}", placeholder))?;
+                    let spec = Spec::parse(spec).ok_or_else(|| {
+                        format!("Invalid trace field format: {{{}}}", placeholder)
+                    })?;
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Field(field, spec));
+                }
+                '}' => return Err("Unmatched `}` in trace template".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Self { pieces })
+    }
+}
+
+impl Spec {
+    // `[x][<|>][width]`
+    fn parse(spec: &str) -> Option<Spec> {
+        let mut out = Spec::default();
+        let mut spec = spec.trim();
+        if let Some(rest) = spec.strip_prefix('x') {
+            out.lower = true;
+            spec = rest;
+        }
+        if let Some(rest) = spec.strip_prefix('>') {
+            out.right = true;
+            spec = rest;
+        } else if let Some(rest) = spec.strip_prefix('<') {
+            spec = rest;
+        }
+        if !spec.is_empty() {
+            out.width = spec.parse().ok()?;
+        }
+        Some(out)
+    }
+}
+
+impl TraceTemplate {
+    pub fn line<'a>(&'a self, entry: &'a TraceEntry) -> impl fmt::Display + 'a {
+        TemplateLine(self, entry)
+    }
+}
+
+struct TemplateLine<'a>(&'a TraceTemplate, &'a TraceEntry);
+
+impl fmt::Display for TemplateLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let TemplateLine(template, entry) = *self;
+        for piece in &template.pieces {
+            match piece {
+                Piece::Text(text) => f.write_str(text)?,
+                Piece::Field(field, spec) => {
+                    let value = FieldValue(*field, spec.lower, entry);
+                    let width = spec.width;
+                    match spec.right {
+                        true => write!(f, "{:>width$}", value, width = width)?,
+                        false => write!(f, "{:<width$}", value, width = width)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct FieldValue<'a>(Field, bool, &'a TraceEntry);
+
+impl fmt::Display for FieldValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = FmtBuf::<64>::default();
+        let FieldValue(field, lower, entry) = *self;
+        let word = match field {
+            Field::Pc => Some(entry.pc),
+            Field::Af => Some(entry.af),
+            Field::Bc => Some(entry.bc),
+            Field::De => Some(entry.de),
+            Field::Hl => Some(entry.hl),
+            Field::Ix => Some(entry.ix),
+            Field::Iy => Some(entry.iy),
+            Field::Sp => Some(entry.sp),
+            _ => None,
+        };
+        match (field, word) {
+            (_, Some(word)) if lower => write!(buf, "{:04x}", word)?,
+            (_, Some(word)) => write!(buf, "{:04X}", word)?,
+            (Field::Byte(n), _) => {
+                let pair = [entry.af, entry.bc, entry.de, entry.hl][n / 2];
+                let byte = if n % 2 == 0 { pair >> 8 } else { pair & 0xFF };
+                match lower {
+                    true => write!(buf, "{:02x}", byte)?,
+                    false => write!(buf, "{:02X}", byte)?,
+                }
+            }
+            (Field::Cycles, _) => write!(buf, "{}", entry.cycles)?,
+            (Field::Bytes, _) | (Field::Op, _) => {
+                for (n, byte) in entry.opcode_bytes().iter().enumerate() {
+                    if n > 0 && field == Field::Bytes {
+                        buf.write_char(' ')?;
+                    }
+                    match lower {
+                        true => write!(buf, "{:02x}", byte)?,
+                        false => write!(buf, "{:02X}", byte)?,
+                    }
+                }
+            }
+            (Field::Asm, _) => match entry.instruction() {
+                Some(instruction) => write!(buf, "{}", instruction.at(entry.pc))?,
+                None => write!(buf, "DB ${:02X}", entry.bytes[0])?,
+            },
+            (Field::Flags, _) => {
+                for (bit, name) in "SZYHXPNC".chars().enumerate() {
+                    let set = entry.af & (0x80 >> bit) != 0;
+                    buf.write_char(if set { name } else { '.' })?;
+                }
+            }
+            _ => {}
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  {:<12}",
+            self.pc,
+            HexBytes(self.opcode_bytes(), " ")
+        )?;
+        match self.instruction() {
+            Some(instruction) => write!(f, "{:<20}", instruction.at(self.pc))?,
+            None => write!(f, "DB ${:02X}{:14}", self.bytes[0], "")?,
+        }
+        write!(
+            f,
+            "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} IX:{:04X} IY:{:04X} SP:{:04X} cyc:{}",
+            self.af, self.bc, self.de, self.hl, self.ix, self.iy, self.sp, self.cycles
+        )
+    }
+}
+
+// Circular buffer holding the last `depth` executed instructions, a depth of 0 disables it
+#[derive(Debug, Default)]
+pub struct TraceBuffer {
+    depth: usize,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl TraceBuffer {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            entries: VecDeque::with_capacity(depth),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // Resizing keeps the most recent entries
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.entries.len() > depth {
+            self.entries.pop_front();
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    // Oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    pub fn last(&self) -> Option<&TraceEntry> {
+        self.entries.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The last `count` entries one per line, oldest first
+    pub fn dump(&self, count: usize) -> String {
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries
+            .iter()
+            .skip(skip)
+            .map(|entry| format!("{}\n", entry))
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum TraceFormat {
+    // `TraceEntry::line`
+    #[default]
+    Text,
+    // One JSON object per line
+    Json,
+    // Comma separated with a header row
+    Csv,
+    // `0100: ld   a,$05`, what MAME's debugger `trace` command writes
+    Mame,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            "csv" => Ok(TraceFormat::Csv),
+            "mame" => Ok(TraceFormat::Mame),
+            _ => Err(format!("Unknown trace format: {}", s)),
+        }
+    }
+}
+
+// Instruction classes traces can be restricted to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpClass {
+    // JP, JR and DJNZ
+    Jump,
+    // CALL and RST
+    Call,
+    // RET, RETI and RETN
+    Return,
+    // IN, OUT and the block I/O instructions
+    Io,
+    // LDI, CPI and friends
+    Block,
+    // PUSH, POP and EX (SP)
+    Stack,
+}
+
+impl OpClass {
+    pub fn of(mnemonic: Mnemonic) -> Option<OpClass> {
+        use crate::instruction_info::Mnemonic::*;
+        let class = match mnemonic {
+            Jp | Jr | Djnz => OpClass::Jump,
+            Call | Rst => OpClass::Call,
+            Ret | Reti | Retn => OpClass::Return,
+            In | Ini | Inir | Ind | Indr | Out | Outi | Otir | Outd | Otdr => OpClass::Io,
+            Ldi | Ldir | Ldd | Lddr | Cpi | Cpir | Cpd | Cpdr => OpClass::Block,
+            Push | Pop | Ex => OpClass::Stack,
+            _ => return None,
+        };
+        Some(class)
+    }
+}
+
+impl FromStr for OpClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jump" | "jp" => Ok(OpClass::Jump),
+            "call" => Ok(OpClass::Call),
+            "return" | "ret" => Ok(OpClass::Return),
+            "io" => Ok(OpClass::Io),
+            "block" => Ok(OpClass::Block),
+            "stack" => Ok(OpClass::Stack),
+            _ => Err(format!("Unknown instruction class: {}", s)),
+        }
+    }
+}
+
+// Restricts which instructions are traced. Empty lists match everything, so an
+// instruction is traced if its PC is in any of the ranges and it belongs to any of the
+// classes.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub ranges: Vec<RangeInclusive<u16>>,
+    pub classes: Vec<OpClass>,
+}
+
+impl TraceFilter {
+    // Adds comma separated hex ranges, e.g. `0100-7FFF,C000-C0FF`
+    pub fn add_ranges(&mut self, spec: &str) -> Result<(), String> {
+        for range in spec.split(',') {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse = |s: &str| {
+                let s = s.trim().trim_start_matches("0x");
+                u16::from_str_radix(s, 16).map_err(|_| format!("Invalid range: {}", range))
+            };
+            self.ranges.push(parse(start)?..=parse(end)?);
+        }
+        Ok(())
+    }
+
+    // Adds comma separated classes, e.g. `call,ret,io`
+    pub fn add_classes(&mut self, spec: &str) -> Result<(), String> {
+        for class in spec.split(',') {
+            self.classes.push(class.trim().parse()?);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.classes.is_empty()
+    }
+
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|r| r.contains(&entry.pc)))
+            && (self.classes.is_empty()
+                || entry
+                    .instruction()
+                    .and_then(|i| OpClass::of(i.mnemonic))
+                    .is_some_and(|class| self.classes.contains(&class)))
+    }
+}
+
+const CSV_HEADER: &str = "pc,op,af,bc,de,hl,ix,iy,sp,cycles";
+
+// Streams one line per executed instruction in the chosen format
+pub struct TraceWriter {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    header: bool,
+    compress: Option<LoopDetector>,
+    mame_loops: Option<MameLoops>,
+    pub filter: TraceFilter,
+    // Layout of text lines, `TraceEntry::line` if not set
+    pub template: Option<TraceTemplate>,
+    // Instructions at a symbol are preceded by a `# name` line (a `sym` field in JSON)
+    pub symbols: Symbols,
+}
+
+// Collapses repeating PC sequences (LDIR, delay loops) in the output. Once the last `n`
+// PCs equal the `n` before them the loop is written out twice and further iterations are
+// only counted until the sequence breaks.
+struct LoopDetector {
+    window: usize,
+    recent: VecDeque<u16>,
+    period: usize,
+    skipped: usize,
+}
+
+impl LoopDetector {
+    fn new(window: usize) -> Self {
+        Self {
+            window,
+            recent: VecDeque::with_capacity(window * 2),
+            period: 0,
+            skipped: 0,
+        }
+    }
+
+    // Returns true if the instruction continues a detected loop and shouldn't be written
+    fn skip(&mut self, pc: u16) -> bool {
+        let repeats = self.period > 0 && self.recent[self.recent.len() - self.period] == pc;
+        if self.recent.len() == self.window * 2 {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(pc);
+        if repeats {
+            self.skipped += 1;
+        }
+        repeats
+    }
+
+    // Looks for a loop ending at the most recent PC, skipped instructions have to be
+    // reported before calling this
+    fn detect(&mut self) {
+        let len = self.recent.len();
+        let last = self.recent[len - 1];
+        self.period = (1..=self.window.min(len / 2))
+            .find(|&n| {
+                self.recent[len - 1 - n] == last
+                    && (0..n).all(|i| self.recent[len - 1 - i] == self.recent[len - 1 - n - i])
+            })
+            .unwrap_or(0);
+    }
+
+    fn take_skipped(&mut self) -> Option<(usize, usize)> {
+        match self.skipped {
+            0 => None,
+            skipped => {
+                self.skipped = 0;
+                Some((self.period, skipped))
+            }
+        }
+    }
+}
+
+// MAME's loop detection: an instruction at one of the last `n` PCs written is counted
+// instead of written, and the count is reported before the next line that is
+struct MameLoops {
+    recent: VecDeque<u16>,
+    window: usize,
+    loops: usize,
+}
+
+impl MameLoops {
+    fn new(window: usize) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(window),
+            window,
+            loops: 0,
+        }
+    }
+
+    // Returns true if the instruction is part of a loop and shouldn't be written
+    fn skip(&mut self, pc: u16) -> bool {
+        if self.recent.contains(&pc) {
+            self.loops += 1;
+            return true;
+        }
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(pc);
+        false
+    }
+}
+
+impl TraceWriter {
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?, format))
+    }
+
+    pub fn new<W: Write + 'static>(out: W, format: TraceFormat) -> Self {
+        Self {
+            out: Box::new(BufWriter::with_capacity(1 << 16, out)),
+            format,
+            header: format == TraceFormat::Csv,
+            compress: None,
+            mame_loops: None,
+            filter: TraceFilter::default(),
+            template: None,
+            symbols: Symbols::default(),
+        }
+    }
+
+    // Collapses loops of up to `window` instructions, 0 writes every instruction. MAME traces
+    // leave out instructions at any of the last `window` PCs written instead, like MAME does
+    // with a window of 64 unless `noloop` is given.
+    pub fn set_compression(&mut self, window: usize) {
+        if self.format == TraceFormat::Mame {
+            self.mame_loops = (window > 0).then(|| MameLoops::new(window));
+        } else {
+            self.compress = (window > 0).then(|| LoopDetector::new(window));
+        }
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        if self.header {
+            self.header = false;
+            writeln!(self.out, "{}", CSV_HEADER)?;
+        }
+        if !self.filter.matches(entry) {
+            return Ok(());
+        }
+        if let Some(loops) = &mut self.mame_loops {
+            if loops.skip(entry.pc) {
+                return Ok(());
+            }
+            self.write_skipped()?;
+        }
+        if let Some(detector) = &mut self.compress {
+            if detector.skip(entry.pc) {
+                return Ok(());
+            }
+            self.write_skipped()?;
+            self.write_entry(entry)?;
+            if let Some(detector) = &mut self.compress {
+                detector.detect();
+            }
+            return Ok(());
+        }
+        self.write_entry(entry)
+    }
+
+    fn write_skipped(&mut self) -> io::Result<()> {
+        if let Some(loops) = &mut self.mame_loops {
+            if loops.loops > 0 {
+                let count = std::mem::take(&mut loops.loops);
+                writeln!(self.out, "\n   (loops for {} instructions)\n", count)?;
+            }
+        }
+        let (period, skipped) = match self.compress.as_mut().and_then(|d| d.take_skipped()) {
+            Some(skipped) => skipped,
+            None => return Ok(()),
+        };
+        match self.format {
+            TraceFormat::Json => writeln!(
+                self.out,
+                "{{\"repeat\":{},\"skipped\":{}}}",
+                period, skipped
+            ),
+            TraceFormat::Text | TraceFormat::Csv | TraceFormat::Mame => writeln!(
+                self.out,
+                "# ... last {} instructions repeated {} times ({} lines skipped)",
+                period,
+                skipped / period,
+                skipped
+            ),
+        }
+    }
+
+    fn write_entry(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        let op = HexBytes(entry.opcode_bytes(), "");
+        let symbol = self.symbols.name(entry.pc);
+        if let (Some(name), TraceFormat::Text | TraceFormat::Csv) = (symbol, self.format) {
+            writeln!(self.out, "# {}:", name)?;
+        }
+        match self.format {
+            TraceFormat::Text => match &self.template {
+                Some(template) => writeln!(self.out, "{}", template.line(entry)),
+                None => writeln!(self.out, "{}", Line(entry)),
+            },
+            TraceFormat::Mame => writeln!(self.out, "{}", MameLine(entry)),
+            TraceFormat::Json => {
+                write!(self.out, "{{\"pc\":{},", entry.pc)?;
+                if let Some(name) = symbol {
+                    write!(self.out, "\"sym\":\"{}\",", name)?;
+                }
+                writeln!(
+                self.out,
+                "\"op\":\"{}\",\"af\":{},\"bc\":{},\"de\":{},\"hl\":{},\"ix\":{},\"iy\":{},\"sp\":{},\"cycles\":{}}}",
+                op,
+                entry.af,
+                entry.bc,
+                entry.de,
+                entry.hl,
+                entry.ix,
+                entry.iy,
+                entry.sp,
+                entry.cycles
+            )
+            }
+            TraceFormat::Csv => writeln!(
+                self.out,
+                "{:04X},{},{:04X},{:04X},{:04X},{:04X},{:04X},{:04X},{:04X},{}",
+                entry.pc,
+                op,
+                entry.af,
+                entry.bc,
+                entry.de,
+                entry.hl,
+                entry.ix,
+                entry.iy,
+                entry.sp,
+                entry.cycles
+            ),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        // A loop still running when tracing ends would otherwise go unreported
+        let _ = self.write_skipped();
+        let _ = self.out.flush();
+    }
+}
+
+// Reference trace from a known good emulator, compared instruction by instruction.
+//
+// Lines use the `TraceEntry::line` layout: the PC of the executed instruction first,
+// followed by `KEY=hex` register values after executing it. Keys are AF, BC, DE, HL, IX,
+// IY, SP and CYC (decimal); any of them may be left out and other tokens are ignored so
+// traces from other emulators only need light massaging. Blank lines and lines starting
+// with `#` are skipped.
+//
+// MAME traces are read as they are: the PC is the token ending in a colon and registers
+// logged in front of it with `tracelog` (e.g. `{tracelog "AF=%04X BC=%04X ",af,bc}`) are
+// from before the instruction, so they're compared with the state the previous
+// instruction left. `(loops for n instructions)` lines match the next n instructions
+// whatever they are.
+pub struct GoldenTrace {
+    reader: Box<dyn BufRead>,
+    // Reference line last compared
+    pub line: usize,
+    pub compare_cycles: bool,
+    mismatch: Option<Mismatch>,
+    // Registers after the previous instruction
+    before: Option<[u16; 8]>,
+    // Instructions left in a loop MAME didn't write out
+    looping: usize,
+}
+
+struct Mismatch {
+    expected: String,
+    actual: TraceEntry,
+    fields: Vec<&'static str>,
+}
+
+const FIELDS: [&str; 8] = ["PC", "AF", "BC", "DE", "HL", "IX", "IY", "SP"];
+
+impl GoldenTrace {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(archive::open(path)?)))
+    }
+
+    pub fn new<R: BufRead + 'static>(reader: R) -> Self {
+        Self {
+            reader: Box::new(reader),
+            line: 0,
+            compare_cycles: false,
+            mismatch: None,
+            before: None,
+            looping: 0,
+        }
+    }
+
+    // Compares against the next reference line. Returns None once the reference ends,
+    // otherwise whether the entry matched.
+    pub fn check(&mut self, entry: &TraceEntry) -> Option<bool> {
+        let actual = [
+            entry.pc, entry.af, entry.bc, entry.de, entry.hl, entry.ix, entry.iy, entry.sp,
+        ];
+        let before = self.before.replace(actual);
+        if self.looping > 0 {
+            self.looping -= 1;
+            return Some(true);
+        }
+        let expected = loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            self.line += 1;
+            let line = line.trim();
+            if let Some(count) = mame_loops(line) {
+                // This instruction is the first of them
+                self.looping = count.saturating_sub(1);
+                return Some(true);
+            }
+            if !line.is_empty() && !line.starts_with('#') {
+                break line.to_string();
+            }
+        };
+
+        let mame = expected.split_whitespace().find_map(mame_pc);
+        let mut fields = Vec::new();
+        for (n, token) in expected.split_whitespace().enumerate() {
+            let (key, value) = match (mame_pc(token), token.split_once(['=', ':'])) {
+                (Some(pc), _) => ("PC".to_string(), pc),
+                // The disassembly after a MAME PC
+                (None, _) if mame.is_some() && !token.contains('=') => continue,
+                (None, Some((key, value))) => (key.to_ascii_uppercase(), value),
+                (None, None) if n == 0 => ("PC".to_string(), token),
+                (None, None) => continue,
+            };
+            if key == "CYC" {
+                if self.compare_cycles && value.parse() != Ok(entry.cycles) {
+                    fields.push("CYC");
+                }
+                continue;
+            }
+            let index = match FIELDS.iter().position(|f| *f == key) {
+                Some(index) => index,
+                None => continue,
+            };
+            let value = u16::from_str_radix(value, 16);
+            let differs = match (mame, before) {
+                (Some(_), _) if index == 0 => value != Ok(entry.pc),
+                (Some(_), Some(before)) => value != Ok(before[index]),
+                // Nothing to compare the first MAME line's registers with
+                (Some(_), None) => false,
+                (None, _) => value != Ok(actual[index]),
+            };
+            if differs {
+                fields.push(FIELDS[index]);
+            }
+        }
+        if fields.is_empty() {
+            return Some(true);
+        }
+        self.mismatch = Some(Mismatch {
+            expected,
+            actual: *entry,
+            fields,
+        });
+        Some(false)
+    }
+
+    // Describes the last mismatch along with the last `count` instructions from `history`
+    pub fn report(&self, history: &TraceBuffer, count: usize) -> String {
+        let mismatch = match &self.mismatch {
+            Some(mismatch) => mismatch,
+            None => return format!("No mismatch after {} reference lines\n", self.line),
+        };
+        format!(
+            "Trace mismatch at reference line {}\n\
+             expected: {}\n\
+             actual:   {}\n\
+             differs:  {}\n\
+             Last {} instructions:\n{}",
+            self.line,
+            mismatch.expected,
+            mismatch.actual.line(),
+            mismatch.fields.join(" "),
+            count.min(history.len()),
+            history.dump(count)
+        )
+    }
+}
+
+// The PC in a MAME trace line, `0100:`
+fn mame_pc(token: &str) -> Option<&str> {
+    let pc = token.strip_suffix(':')?;
+    (pc.len() == 4 && pc.chars().all(|c| c.is_ascii_hexdigit())).then_some(pc)
+}
+
+// `(loops for 12 instructions)`, MAME's note for instructions it didn't write
+fn mame_loops(line: &str) -> Option<usize> {
+    line.strip_prefix("(loops for ")?
+        .strip_suffix(" instructions)")?
+        .parse()
+        .ok()
+}
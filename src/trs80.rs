@@ -0,0 +1,387 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::device::Device;
+use crate::interconnect::Interconnect;
+use crate::memory::{Memory, Region, PAGE_SIZE};
+use crate::peripherals::KeyMatrix;
+use crate::video::Framebuffer;
+
+pub const CLOCK: usize = 1_774_080;
+// Level I is 4K, Level II 12K
+pub const ROM_SIZE: usize = 0x3000;
+pub const KEYBOARD: u16 = 0x3800;
+pub const SCREEN: u16 = 0x3C00;
+pub const RAM_START: usize = 0x4000;
+pub const MAX_RAM: usize = 0xC000;
+
+// 64 characters of 6x12 pixels on each of 16 rows, or 32 twice as wide
+const COLUMNS: usize = 64;
+const ROWS: usize = 16;
+const CELL_WIDTH: usize = 6;
+const CELL_HEIGHT: usize = 12;
+pub const WIDTH: usize = COLUMNS * CELL_WIDTH;
+pub const HEIGHT: usize = ROWS * CELL_HEIGHT;
+const FRAME_CYCLES: usize = CLOCK / 60;
+const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+// Port FF output bits
+const CASSETTE_OUT: u8 = 0x03;
+const MOTOR: u8 = 0x04;
+const WIDE: u8 = 0x08;
+
+// Keyboard rows, column 0 first. The four arrows are up, down, left and right.
+pub const KEY_ROWS: [&str; 7] = [
+    "@ABCDEFG",
+    "HIJKLMNO",
+    "PQRSTUVW",
+    "XYZ",
+    "01234567",
+    "89:;,-./",
+    "\r\x0C\x1B\u{2191}\u{2193}\u{2190}\u{2192} ",
+];
+pub const SHIFT: (usize, usize) = (7, 0);
+
+// Row and column of the key for `c`: upper case letters, digits, the punctuation on the
+// keys, space, ENTER (\r), CLEAR (form feed), BREAK (escape) and the arrows
+pub fn key_position(c: char) -> Option<(usize, usize)> {
+    KEY_ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.chars().position(|key| key == c).map(|n| (row, n)))
+}
+
+// The MCM6670 character generator's 64 characters, '@' to '_' then ' ' to '?', as 5
+// columns of 7 pixels (bit 0 at the top). 5B-5E are arrows on the TRS-80.
+const FONT: [[u8; 5]; 64] = [
+    [0x32, 0x49, 0x79, 0x41, 0x3E],
+    [0x7E, 0x11, 0x11, 0x11, 0x7E],
+    [0x7F, 0x49, 0x49, 0x49, 0x36],
+    [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x22, 0x1C],
+    [0x7F, 0x49, 0x49, 0x49, 0x41],
+    [0x7F, 0x09, 0x09, 0x01, 0x01],
+    [0x3E, 0x41, 0x41, 0x51, 0x32],
+    [0x7F, 0x08, 0x08, 0x08, 0x7F],
+    [0x00, 0x41, 0x7F, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3F, 0x01],
+    [0x7F, 0x08, 0x14, 0x22, 0x41],
+    [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x04, 0x02, 0x7F],
+    [0x7F, 0x04, 0x08, 0x10, 0x7F],
+    [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06],
+    [0x3E, 0x41, 0x51, 0x21, 0x5E],
+    [0x7F, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7F, 0x01, 0x01],
+    [0x3F, 0x40, 0x40, 0x40, 0x3F],
+    [0x1F, 0x20, 0x40, 0x20, 0x1F],
+    [0x7F, 0x20, 0x18, 0x20, 0x7F],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x04, 0x02, 0x7F, 0x02, 0x04],
+    [0x10, 0x20, 0x7F, 0x20, 0x10],
+    [0x08, 0x1C, 0x2A, 0x08, 0x08],
+    [0x08, 0x08, 0x2A, 0x1C, 0x08],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5F, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1C, 0x00],
+    [0x14, 0x08, 0x3E, 0x08, 0x14],
+    [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3E, 0x51, 0x49, 0x45, 0x3E],
+    [0x00, 0x42, 0x7F, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4B, 0x31],
+    [0x18, 0x14, 0x12, 0x7F, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3C, 0x4A, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1E],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+];
+
+// The keyboard at 3800-3BFF: A0-A7 each select a row and a read gives the keys down in
+// all of the selected rows, set while pressed
+pub struct Keyboard {
+    pub keys: KeyMatrix,
+}
+
+impl Device for Keyboard {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        (0..8)
+            .filter(|row| addr & 1 << row != 0)
+            .fold(0, |keys, row| keys | self.keys.row(row))
+    }
+}
+
+// Port FF: cassette output (bits 0-1), motor (bit 2) and the 32 character mode (bit 3) on
+// writes, the cassette input in bit 7 of reads. Nothing plays or records yet, the input
+// is whatever `input` is set to.
+#[derive(Default)]
+pub struct CassettePort {
+    pub output: u8,
+    pub motor: bool,
+    pub input: bool,
+    pub wide: bool,
+}
+
+impl Device for CassettePort {
+    fn io_read(&mut self, _port: u16) -> u8 {
+        0x7F | (self.input as u8) << 7
+    }
+
+    fn io_write(&mut self, _port: u16, value: u8) {
+        self.output = value & CASSETTE_OUT;
+        self.motor = value & MOTOR != 0;
+        self.wide = value & WIDE != 0;
+    }
+}
+
+// The video of the Model I: the 1K of screen memory at 3C00 as 16 rows of 64 characters,
+// redrawn at the end of every 60Hz frame. Characters with bit 7 set are 2x3 blocks of
+// graphics, from bit 0 at the top left to bit 5 at the bottom right. There's no bit 6 in
+// screen memory without the lower case modification, so 00-1F show as 40-5F and 60-7F as
+// 20-3F. In the 32 character mode (port FF bit 3) the even columns are shown twice as wide.
+pub struct Video {
+    pub frame: Rc<RefCell<Framebuffer>>,
+    pub port: Rc<RefCell<CassettePort>>,
+    pub cycles: u64,
+    frames_drawn: u64,
+}
+
+impl Video {
+    pub fn new(port: Rc<RefCell<CassettePort>>) -> Self {
+        Self {
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            port,
+            cycles: 0,
+            frames_drawn: 0,
+        }
+    }
+
+    // Whether pixel `x`, `y` of the cell for `c` is lit
+    fn lit(c: u8, x: usize, y: usize) -> bool {
+        if c & 0x80 != 0 {
+            let bit = y / 4 * 2 + x / 3;
+            return c & 1 << bit != 0;
+        }
+        x < 5 && y < 7 && FONT[(c & 0x3F) as usize][x] & 1 << y != 0
+    }
+
+    pub fn render(&self, memory: &Memory) {
+        let wide = self.port.borrow().wide;
+        let mut frame = self.frame.borrow_mut();
+        for y in 0..HEIGHT {
+            let row = frame.row_mut(y);
+            for x in 0..WIDTH {
+                let (column, dx) = match wide {
+                    true => (x / (CELL_WIDTH * 2) * 2, x / 2 % CELL_WIDTH),
+                    false => (x / CELL_WIDTH, x % CELL_WIDTH),
+                };
+                let addr = SCREEN + (y / CELL_HEIGHT * COLUMNS + column) as u16;
+                let c = memory.peek(addr);
+                let pixel = match Self::lit(c, dx, y % CELL_HEIGHT) {
+                    true => WHITE,
+                    false => BLACK,
+                };
+                row[x * 4..x * 4 + 4].copy_from_slice(&pixel);
+            }
+        }
+    }
+}
+
+impl Device for Video {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+
+    fn scan(&mut self, memory: &Memory) {
+        let frames = self.cycles / FRAME_CYCLES as u64;
+        if frames > self.frames_drawn {
+            self.frames_drawn = frames;
+            self.render(memory);
+        }
+    }
+}
+
+// A TRS-80 Model I without the expansion interface: the Level I or II ROM at 0000, the
+// keyboard at 3800-3BFF, screen memory at 3C00-3FFF and RAM from 4000. There are no
+// interrupts. Nothing answers at 3000-37FF or above the RAM, which read as FF.
+pub struct Trs80 {
+    pub keyboard: KeyMatrix,
+    pub video: Rc<RefCell<Video>>,
+    pub cassette: Rc<RefCell<CassettePort>>,
+}
+
+impl Trs80 {
+    // Replaces the memory of `i` with the ROM and `ram` bytes of RAM (4K to 48K)
+    pub fn install(i: &mut Interconnect, rom: &[u8], ram: usize) -> Result<Self, String> {
+        if rom.is_empty() || rom.len() > ROM_SIZE {
+            return Err(format!(
+                "ROM is {} bytes, expected up to {}",
+                rom.len(),
+                ROM_SIZE
+            ));
+        }
+        if !(0x1000..=MAX_RAM).contains(&ram) || !ram.is_multiple_of(0x1000) {
+            return Err(format!("Unsupported RAM size: {}", ram));
+        }
+        let mut memory = Memory::default();
+        memory.load_rom(0x0000, rom);
+        let rom_end = rom.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        if rom_end < ROM_SIZE {
+            memory.map(rom_end as u16..=ROM_SIZE as u16 - 1, Region::Unmapped);
+        }
+        memory.map(ROM_SIZE as u16..=KEYBOARD - 1, Region::Unmapped);
+        if RAM_START + ram < 0x1_0000 {
+            memory.map((RAM_START + ram) as u16..=0xFFFF, Region::Unmapped);
+        }
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.clock_speed = CLOCK;
+
+        let keyboard = KeyMatrix::default();
+        let keys = Keyboard {
+            keys: keyboard.clone(),
+        };
+        i.map_device(KEYBOARD..=SCREEN - 1, Rc::new(RefCell::new(keys)));
+        let cassette = Rc::new(RefCell::new(CassettePort::default()));
+        i.register_port(0xFF..=0xFF, cassette.clone());
+        let video = Video::new(cassette.clone());
+        i.frame_cycles = Some(FRAME_CYCLES);
+        i.display = Some(video.frame.clone());
+        let video = i.add_device(video);
+        Ok(Self {
+            keyboard,
+            video,
+            cassette,
+        })
+    }
+
+    // Installs the ROM at `path` with 48K of RAM
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let rom = archive::read(&path)?;
+        let machine = Self::install(i, &rom, MAX_RAM)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        info!("Loaded TRS-80 ROM {:?}", path.as_ref());
+        Ok(machine)
+    }
+
+    // Presses or releases the key for `c`, false if there's no such key
+    pub fn set_key(&self, c: char, pressed: bool) -> bool {
+        match key_position(c) {
+            Some((row, column)) => {
+                self.keyboard.set(row, column, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_position, Trs80, CELL_HEIGHT, CELL_WIDTH, FRAME_CYCLES, SCREEN, SHIFT};
+    use crate::interconnect::Interconnect;
+    use crate::memory::MemoryRW;
+
+    const WHITE: [u8; 4] = [0xFF; 4];
+    const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+    #[test]
+    fn memory_and_keyboard() {
+        // JP $
+        let mut i = Interconnect::builder().build();
+        let machine = Trs80::install(&mut i, &[0xC3, 0x00, 0x00], 0x4000).unwrap();
+        assert_eq!(i.cpu.read8(0x0000), 0xC3);
+        assert_eq!(i.cpu.read8(0x1000), 0xFF);
+        assert_eq!(i.cpu.read8(0x37EC), 0xFF);
+        i.cpu.write8(0x7FFF, 0x12);
+        assert_eq!(i.cpu.read8(0x7FFF), 0x12);
+        i.cpu.write8(0x8000, 0x12);
+        assert_eq!(i.cpu.read8(0x8000), 0xFF);
+
+        assert_eq!(i.cpu.read8(0x38FF), 0x00);
+        assert!(machine.set_key('A', true));
+        assert!(machine.set_key('\r', true));
+        machine.keyboard.press(SHIFT.0, SHIFT.1);
+        assert_eq!(i.cpu.read8(0x3801), 0x02);
+        assert_eq!(i.cpu.read8(0x3840), 0x01);
+        assert_eq!(i.cpu.read8(0x3880), 0x01);
+        // Rows are ORed, A8-A9 aren't decoded
+        assert_eq!(i.cpu.read8(0x3BC1), 0x03);
+        assert_eq!(key_position(' '), Some((6, 7)));
+        assert_eq!(key_position('a'), None);
+
+        i.cpu.io.write(0xFF, 0x0E);
+        let cassette = machine.cassette.borrow();
+        assert_eq!(cassette.output, 2);
+        assert!(cassette.motor && cassette.wide);
+        assert!(Trs80::install(&mut i, &[0; 0x3001], 0x4000).is_err());
+        assert!(Trs80::install(&mut i, &[0; 0x3000], 0x800).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let mut i = Interconnect::builder().build();
+        let machine = Trs80::install(&mut i, &[0xC3, 0x00, 0x00], 0x4000).unwrap();
+        // 'A', a graphics character with the top left and bottom right blocks, then '@'
+        // stored without bit 6
+        i.cpu.write8(SCREEN, b'A');
+        i.cpu.write8(SCREEN + 1, 0xA1);
+        i.cpu.write8(SCREEN + 64, 0x00);
+        while machine.video.borrow().cycles < FRAME_CYCLES as u64 {
+            i.step();
+        }
+        let frame = machine.video.borrow().frame.clone();
+        let frame = frame.borrow();
+        // The top of the A's sides, and the point
+        assert_eq!(frame.pixel(0, 0), BLACK);
+        assert_eq!(frame.pixel(0, 1), WHITE);
+        assert_eq!(frame.pixel(2, 0), WHITE);
+        assert_eq!(frame.pixel(5, 0), BLACK);
+        assert_eq!(frame.pixel(CELL_WIDTH, 0), WHITE);
+        assert_eq!(frame.pixel(CELL_WIDTH + 2, 3), WHITE);
+        assert_eq!(frame.pixel(CELL_WIDTH + 3, 0), BLACK);
+        assert_eq!(frame.pixel(CELL_WIDTH + 5, 11), WHITE);
+        assert_eq!(frame.pixel(CELL_WIDTH, 11), BLACK);
+        assert_eq!(frame.pixel(1, CELL_HEIGHT), WHITE);
+        assert_eq!(frame.pixel(0, CELL_HEIGHT + 1), WHITE);
+        drop(frame);
+
+        // 32 characters: the A twice as wide and the graphics character hidden
+        i.cpu.io.write(0xFF, 0x08);
+        machine.video.borrow().render(&i.cpu.memory);
+        let frame = machine.video.borrow().frame.clone();
+        let frame = frame.borrow();
+        assert_eq!(frame.pixel(4, 0), WHITE);
+        assert_eq!(frame.pixel(5, 0), WHITE);
+        assert_eq!(frame.pixel(CELL_WIDTH + 2, 0), BLACK);
+    }
+}
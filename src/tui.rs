@@ -0,0 +1,310 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::interconnect::Interconnect;
+use crate::monitor::{context_start, crash_report, disassemble, Monitor};
+
+const HELP: &str =
+    "s step  n step over  o step out  c run/pause  b breakpoint  PgUp/PgDn memory  : command  q quit";
+// Instructions executed between checking the keyboard while running
+const RUN_SLICE: usize = 20_000;
+const LOG_LINES: usize = 200;
+
+// Full screen debugger with register, disassembly, memory and stack panes.
+// Commands typed after `:` go through the same `Monitor` as the line based debugger.
+pub struct Tui {
+    monitor: Monitor,
+    mem_addr: u16,
+    running: bool,
+    input: Option<String>,
+    log: Vec<String>,
+}
+
+impl Default for Tui {
+    fn default() -> Self {
+        Self {
+            monitor: Monitor::default(),
+            mem_addr: 0,
+            running: false,
+            input: None,
+            log: vec![HELP.to_string()],
+        }
+    }
+}
+
+pub fn run(i: &mut Interconnect) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        Tui::default().event_loop(&mut terminal, i)
+    }));
+    ratatui::restore();
+    result.unwrap_or_else(|e| {
+        crash_report(i, &mut io::stderr())?;
+        panic::resume_unwind(e)
+    })
+}
+
+impl Tui {
+    fn event_loop(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        i: &mut Interconnect,
+    ) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame, i))?;
+            if self.running {
+                self.run_slice(i);
+                if !event::poll(Duration::ZERO)? {
+                    continue;
+                }
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.key(key.code, i)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn run_slice(&mut self, i: &mut Interconnect) {
+        for _ in 0..RUN_SLICE {
+            if let Some(stop) = i.step().stop {
+                self.running = false;
+                self.print(stop.to_string());
+                return;
+            }
+        }
+    }
+
+    // Returns false when the user quits
+    fn key(&mut self, code: KeyCode, i: &mut Interconnect) -> io::Result<bool> {
+        if let Some(input) = &mut self.input {
+            match code {
+                KeyCode::Enter => {
+                    let line = self.input.take().unwrap_or_default();
+                    self.print(format!(": {}", line));
+                    let mut out = Vec::new();
+                    match self.monitor.run_command(i, &line, &mut out) {
+                        Ok(true) => {}
+                        Ok(false) => return Ok(false),
+                        Err(e) => self.print(e.to_string()),
+                    }
+                    for line in String::from_utf8_lossy(&out).lines() {
+                        self.print(line.to_string());
+                    }
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return Ok(true);
+        }
+
+        match code {
+            KeyCode::Char('q') => return Ok(false),
+            KeyCode::Char('s') | KeyCode::F(7) => {
+                self.running = false;
+                if let Some(stop) = i.step().stop {
+                    self.print(stop.to_string());
+                }
+            }
+            KeyCode::Char('n') | KeyCode::F(8) | KeyCode::Char('o') => {
+                self.running = false;
+                let result = match code {
+                    KeyCode::Char('o') => i.step_out(),
+                    _ => i.step_over(),
+                };
+                if let Some(stop) = result.stop {
+                    self.print(stop.to_string());
+                }
+            }
+            KeyCode::Char('c') | KeyCode::F(5) => self.running = !self.running,
+            KeyCode::Char('b') | KeyCode::F(9) => {
+                let pc = i.cpu.reg.pc;
+                if !i.breakpoints.remove(pc) {
+                    i.breakpoints.add(pc);
+                }
+            }
+            KeyCode::PageUp => self.mem_addr = self.mem_addr.wrapping_sub(0x80),
+            KeyCode::PageDown => self.mem_addr = self.mem_addr.wrapping_add(0x80),
+            KeyCode::Char(':') => self.input = Some(String::new()),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn print(&mut self, line: String) {
+        self.log.push(line);
+        if self.log.len() > LOG_LINES {
+            self.log.remove(0);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, i: &Interconnect) {
+        let [main, log] =
+            Layout::vertical([Constraint::Min(12), Constraint::Length(8)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Length(24), Constraint::Min(0)]).areas(main);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(12), Constraint::Min(0)]).areas(left);
+        let [code, memory] =
+            Layout::vertical([Constraint::Percentage(55), Constraint::Min(0)]).areas(right);
+
+        self.draw_registers(frame, registers, i);
+        self.draw_stack(frame, stack, i);
+        self.draw_code(frame, code, i);
+        self.draw_memory(frame, memory, i);
+        self.draw_log(frame, log);
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect, i: &Interconnect) {
+        let cpu = &i.cpu;
+        let reg = &cpu.reg;
+        let flags = &cpu.flags;
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        let pair = |h: u8, l: u8| (h as u16) << 8 | l as u16;
+        let lines = vec![
+            Line::from(format!(
+                "AF {:04X}  AF' {:04X}",
+                pair(reg.a, flags.get()),
+                pair(reg.a_, flags.get_shadow())
+            )),
+            Line::from(format!(
+                "BC {:04X}  BC' {:04X}",
+                pair(reg.b, reg.c),
+                pair(reg.b_, reg.c_)
+            )),
+            Line::from(format!(
+                "DE {:04X}  DE' {:04X}",
+                pair(reg.d, reg.e),
+                pair(reg.d_, reg.e_)
+            )),
+            Line::from(format!(
+                "HL {:04X}  HL' {:04X}",
+                pair(reg.h, reg.l),
+                pair(reg.h_, reg.l_)
+            )),
+            Line::from(format!("IX {:04X}  IY  {:04X}", reg.ix, reg.iy)),
+            Line::from(format!("SP {:04X}  PC  {:04X}", reg.sp, reg.pc)),
+            Line::from(format!("I  {:02X}    R   {:02X}", reg.i, reg.r)),
+            Line::from(format!(
+                "IM {}     IFF {}{}",
+                cpu.int.mode, cpu.int.iff1 as u8, cpu.int.iff2 as u8
+            )),
+            Line::from(format!(
+                "F  {}{}{}{}{}{}",
+                flag(flags.sf, 'S'),
+                flag(flags.zf, 'Z'),
+                flag(flags.hf, 'H'),
+                flag(flags.pf, 'P'),
+                flag(flags.nf, 'N'),
+                flag(flags.cf, 'C')
+            )),
+            Line::from(format!("T  {}", cpu.cycles)),
+        ];
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Registers")),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect, i: &Interconnect) {
+        let sp = i.cpu.reg.sp;
+        let lines: Vec<Line> = (0..area.height.saturating_sub(2))
+            .map(|n| {
+                let addr = sp.wrapping_add(n * 2);
+                Line::from(format!("{:04X}  {:04X}", addr, i.peek16(addr)))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Stack")),
+            area,
+        );
+    }
+
+    fn draw_code(&self, frame: &mut Frame, area: Rect, i: &Interconnect) {
+        let pc = i.cpu.reg.pc;
+        let rows = area.height.saturating_sub(2) as usize;
+        let mut addr = context_start(i, pc, rows / 3);
+        let lines: Vec<Line> = (0..rows)
+            .map(|_| {
+                let (text, size) = disassemble(i, addr);
+                let marker = if i.breakpoints.get(addr).is_some() {
+                    '*'
+                } else {
+                    ' '
+                };
+                let line = Line::from(format!("{}{}", marker, text));
+                let line = if addr == pc {
+                    line.style(
+                        Style::default()
+                            .fg(Color::Black)
+                            .bg(Color::Cyan)
+                            .add_modifier(Modifier::BOLD),
+                    )
+                } else {
+                    line
+                };
+                addr = addr.wrapping_add(size as u16);
+                line
+            })
+            .collect();
+        let title = if self.running {
+            "Disassembly (running)"
+        } else {
+            "Disassembly"
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect, i: &Interconnect) {
+        let rows = area.height.saturating_sub(2) as usize;
+        let dump = i.dump_range(self.mem_addr, rows * 16);
+        let lines: Vec<Line> = dump.lines().map(|l| Line::from(l.to_string())).collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Memory")),
+            area,
+        );
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        let rows = area.height.saturating_sub(2) as usize;
+        let mut lines: Vec<Line> = match &self.input {
+            Some(input) => {
+                let skip = self.log.len().saturating_sub(rows.saturating_sub(1));
+                let mut lines: Vec<Line> = self.log[skip..]
+                    .iter()
+                    .map(|l| Line::from(l.as_str()))
+                    .collect();
+                lines.push(Line::from(format!(": {}", input)));
+                lines
+            }
+            None => {
+                let skip = self.log.len().saturating_sub(rows);
+                self.log[skip..]
+                    .iter()
+                    .map(|l| Line::from(l.as_str()))
+                    .collect()
+            }
+        };
+        lines.truncate(rows);
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Log")),
+            area,
+        );
+    }
+}
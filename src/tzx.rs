@@ -0,0 +1,208 @@
+use std::io;
+use std::path::Path;
+
+use log::warn;
+
+use crate::archive;
+use crate::tape::{Pulses, Signal};
+
+// .tzx tape images, which describe the signal itself so custom and turbo loaders work.
+// A 10 byte header (`ZXTape!`, 1A and the version) is followed by blocks, each an id byte
+// and its data. Blocks played:
+//
+//   10  standard speed data, as saved by the ROM
+//   11  turbo speed data, with its own pilot, sync and bit timings
+//   12  pure tone, 13 pulse sequence, 14 pure data
+//   15  direct recording, one bit per sample
+//   20  pause (0 stops the tape), 2A stop the tape on 48K machines, 2B set signal level
+//   24, 25  loop start and end
+//
+// Group, text, archive info, hardware, custom info and glue blocks are skipped, as are
+// the blocks that need more than a linear signal (CSW, generalized data, jumps, calls and
+// selects), with a warning. All timings are in T states at 3.5MHz.
+pub fn parse(data: &[u8]) -> Result<Vec<Signal>, String> {
+    if data.len() < 10 || &data[..8] != b"ZXTape!\x1A" {
+        return Err("Not a .tzx tape".to_string());
+    }
+    let mut pulses = Pulses::default();
+    // Where the open loop starts in the signal and how many times it plays
+    let mut repeat: Option<(usize, u16)> = None;
+    let mut pos = 10;
+    while pos < data.len() {
+        let id = data[pos];
+        let block = &data[pos + 1..];
+        let byte = |n: usize| block.get(n).copied().map(u32::from);
+        let word = |n: usize| Some(byte(n)? | byte(n + 1)? << 8);
+        let triple = |n: usize| Some(word(n)? | byte(n + 2)? << 16);
+        let dword = |n: usize| Some(word(n)? | word(n + 2)? << 16);
+        let bytes = |n: usize, len: u32| block.get(n..n + len as usize);
+        let truncated = || format!("Truncated block {:02X} at {:X}", id, pos);
+        let len = (|| {
+            Some(match id {
+                0x10 => {
+                    let len = word(2)?;
+                    pulses.standard(bytes(4, len)?, word(0)?);
+                    4 + len
+                }
+                0x11 => {
+                    let len = triple(15)?;
+                    pulses.tone(word(0)?, word(10)? as usize);
+                    pulses.pulse(word(2)?);
+                    pulses.pulse(word(4)?);
+                    pulses.data(bytes(18, len)?, word(6)?, word(8)?, byte(12)? as u8);
+                    pulses.pause(word(13)?);
+                    18 + len
+                }
+                0x12 => {
+                    pulses.tone(word(0)?, word(2)? as usize);
+                    4
+                }
+                0x13 => {
+                    let count = byte(0)?;
+                    for n in 0..count as usize {
+                        pulses.pulse(word(1 + n * 2)?);
+                    }
+                    1 + count * 2
+                }
+                0x14 => {
+                    let len = triple(7)?;
+                    pulses.data(bytes(10, len)?, word(0)?, word(2)?, byte(4)? as u8);
+                    pulses.pause(word(5)?);
+                    10 + len
+                }
+                0x15 => {
+                    let len = triple(5)?;
+                    direct(&mut pulses, bytes(8, len)?, word(0)?, byte(4)? as u8);
+                    pulses.pause(word(2)?);
+                    8 + len
+                }
+                0x20 => {
+                    match word(0)? {
+                        0 => pulses.0.push(Signal::Stop),
+                        ms => pulses.pause(ms),
+                    }
+                    2
+                }
+                0x21 => 1 + byte(0)?,
+                0x22 | 0x27 => 0,
+                0x24 => {
+                    repeat = Some((pulses.0.len(), word(0)? as u16));
+                    2
+                }
+                0x25 => {
+                    if let Some((start, count)) = repeat.take() {
+                        let body = pulses.0[start..].to_vec();
+                        for _ in 1..count {
+                            pulses.0.extend_from_slice(&body);
+                        }
+                    }
+                    0
+                }
+                0x2A => {
+                    pulses.0.push(Signal::Stop);
+                    4 + dword(0)?
+                }
+                0x2B => {
+                    pulses.0.push(Signal::Hold(byte(4)? != 0, 0));
+                    4 + dword(0)?
+                }
+                0x30 => 1 + byte(0)?,
+                0x31 => 2 + byte(1)?,
+                0x32 => 2 + word(0)?,
+                0x33 => 1 + byte(0)? * 3,
+                0x35 => 20 + dword(16)?,
+                0x5A => 9,
+                0x18 | 0x19 => {
+                    warn!("Skipping unsupported .tzx block {:02X}", id);
+                    4 + dword(0)?
+                }
+                0x23 | 0x28 => {
+                    warn!("Skipping unsupported .tzx block {:02X}", id);
+                    match id {
+                        0x28 => 2 + word(0)?,
+                        _ => 2,
+                    }
+                }
+                0x26 => {
+                    warn!("Skipping unsupported .tzx block {:02X}", id);
+                    2 + word(0)? * 2
+                }
+                _ => return None,
+            })
+        })();
+        let len = match len {
+            Some(len) => len as usize,
+            None if is_known(id) => return Err(truncated()),
+            None => return Err(format!("Unknown .tzx block {:02X} at {:X}", id, pos)),
+        };
+        if block.len() < len {
+            return Err(truncated());
+        }
+        pos += 1 + len;
+    }
+    Ok(pulses.0)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Signal>> {
+    parse(&archive::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn is_known(id: u8) -> bool {
+    matches!(
+        id,
+        0x10..=0x15 | 0x18 | 0x19 | 0x20..=0x28 | 0x2A | 0x2B | 0x30..=0x33 | 0x35 | 0x5A
+    )
+}
+
+// Samples of `cycles` T states each, most significant bit first, consecutive equal
+// samples merged
+fn direct(pulses: &mut Pulses, data: &[u8], cycles: u32, last_bits: u8) {
+    for (n, &byte) in data.iter().enumerate() {
+        let bits = if n + 1 == data.len() { last_bits } else { 8 };
+        for bit in 0..bits.min(8) {
+            let level = byte & (0x80 >> bit) != 0;
+            match pulses.0.last_mut() {
+                Some(Signal::Hold(last, len)) if *last == level => *len += cycles,
+                _ => pulses.0.push(Signal::Hold(level, cycles)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::tape::{Pulses, Signal};
+
+    #[test]
+    fn blocks() {
+        let mut tzx = b"ZXTape!\x1A\x01\x14".to_vec();
+        // Standard block, 100ms pause
+        tzx.extend([0x10, 100, 0, 2, 0, 0xFF, 0xAA]);
+        // Text description
+        tzx.extend([0x30, 3, b'a', b'b', b'c']);
+        // Loop twice over a 3 pulse sequence
+        tzx.extend([0x24, 2, 0, 0x13, 3, 10, 0, 20, 0, 30, 0, 0x25]);
+        // Direct recording, 4 samples of 79 T states: 1 1 0 0
+        tzx.extend([0x15, 79, 0, 0, 0, 4, 1, 0, 0, 0xC0]);
+        // Stop the tape
+        tzx.extend([0x20, 0, 0]);
+        let signal = parse(&tzx).unwrap();
+
+        let mut expected = Pulses::default();
+        expected.standard(&[0xFF, 0xAA], 100);
+        for _ in 0..2 {
+            expected.pulse(10);
+            expected.pulse(20);
+            expected.pulse(30);
+        }
+        expected.0.push(Signal::Hold(true, 158));
+        expected.0.push(Signal::Hold(false, 158));
+        expected.0.push(Signal::Stop);
+        assert_eq!(signal, expected.0);
+
+        assert!(parse(b"ZXTape!\x1A\x01\x14\x10\x00").is_err());
+        assert!(parse(b"ZXTape!\x1A\x01\x14\x7F").is_err());
+        assert!(parse(b"ZXTape!").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+// A picture produced by a video device, 4 bytes (R, G, B, A) per pixel, row by row from
+// the top left
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    // All black
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut pixels = vec![0; width * height * 4];
+        pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 0xFF);
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let n = (y * self.width + x) * 4;
+        let pixel = &self.pixels[n..n + 4];
+        [pixel[0], pixel[1], pixel[2], pixel[3]]
+    }
+
+    // The pixels of row `y`
+    pub fn row_mut(&mut self, y: usize) -> &mut [u8] {
+        let width = self.width * 4;
+        &mut self.pixels[y * width..(y + 1) * width]
+    }
+}
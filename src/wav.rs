@@ -0,0 +1,152 @@
+use std::io;
+use std::path::Path;
+
+use crate::archive;
+use crate::tape::Signal;
+
+// T states per second the signal is timed in, as with .tzx files
+const CLOCK: u64 = 3_500_000;
+
+// Turns a .wav recording of a tape into a signal for `TapePlayer`. PCM files with 8 or 16
+// bit samples are read, channels are mixed down to mono, and the level switches when the
+// sample crosses a threshold above or below the average. Having two thresholds
+// (hysteresis) keeps noise around the middle from being taken as edges. Edge times are
+// converted from sample positions to T states without accumulating rounding errors.
+pub fn parse(data: &[u8]) -> Result<Vec<Signal>, String> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err("Not a .wav file".to_string());
+    }
+    let mut format = None;
+    let mut samples = None;
+    let mut chunks = &data[12..];
+    while chunks.len() >= 8 {
+        let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        let chunk = chunks.get(8..8 + len).ok_or("Truncated chunk")?;
+        match &chunks[..4] {
+            b"fmt " if len >= 16 => format = Some(chunk),
+            b"data" => samples = Some(chunk),
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        chunks = chunks.get(8 + len + len % 2..).unwrap_or(&[]);
+    }
+    let format = format.ok_or("No fmt chunk")?;
+    let samples = samples.ok_or("No data chunk")?;
+    let word = |n: usize| u16::from_le_bytes([format[n], format[n + 1]]);
+    let (kind, channels, bits) = (word(0), word(2) as usize, word(14));
+    let rate = u32::from_le_bytes([format[4], format[5], format[6], format[7]]) as u64;
+    if kind != 1 || !matches!(bits, 8 | 16) || channels == 0 || rate == 0 {
+        return Err(format!(
+            "Only 8 and 16-bit PCM is supported, not format {} with {} bits",
+            kind, bits
+        ));
+    }
+
+    let width = bits as usize / 8;
+    let mono: Vec<i32> = samples
+        .chunks_exact(width * channels)
+        .map(|frame| {
+            let sum: i32 = frame
+                .chunks_exact(width)
+                .map(|s| match width {
+                    1 => s[0] as i32 - 128,
+                    _ => i16::from_le_bytes([s[0], s[1]]) as i32,
+                })
+                .sum();
+            sum / channels as i32
+        })
+        .collect();
+    Ok(edges(&mono, rate))
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Signal>> {
+    parse(&archive::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn edges(samples: &[i32], rate: u64) -> Vec<Signal> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+    let mean = samples.iter().map(|&s| s as i64).sum::<i64>() / samples.len() as i64;
+    let peak = samples
+        .iter()
+        .map(|&s| (s as i64 - mean).abs())
+        .max()
+        .unwrap_or(0);
+    // A tenth of the loudest swing either side of the middle
+    let threshold = (peak / 10).max(1);
+    let at = |n: usize| n as u64 * CLOCK / rate;
+
+    let mut signal = Vec::new();
+    let mut level = false;
+    let mut since = 0;
+    for (n, &sample) in samples.iter().enumerate() {
+        let sample = sample as i64 - mean;
+        let new = match level {
+            false => sample > threshold,
+            true => sample >= -threshold,
+        };
+        if new != level {
+            signal.push(Signal::Hold(level, (at(n) - at(since)) as u32));
+            level = new;
+            since = n;
+        }
+    }
+    signal.push(Signal::Hold(level, (at(samples.len()) - at(since)) as u32));
+    signal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::tape::Signal;
+
+    fn wav(channels: u16, bits: u16, rate: u32, samples: &[u8]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend(1u16.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(rate.to_le_bytes());
+        let align = channels * bits / 8;
+        fmt.extend((rate * align as u32).to_le_bytes());
+        fmt.extend(align.to_le_bytes());
+        fmt.extend(bits.to_le_bytes());
+        let mut out = b"RIFF".to_vec();
+        out.extend((4 + 8 + 16 + 8 + samples.len() as u32).to_le_bytes());
+        out.extend(b"WAVEfmt ");
+        out.extend(16u32.to_le_bytes());
+        out.extend(fmt);
+        out.extend(b"data");
+        out.extend((samples.len() as u32).to_le_bytes());
+        out.extend(samples);
+        out
+    }
+
+    #[test]
+    fn edges() {
+        // 8-bit mono at 35kHz, 100 T states a sample. The small wobble stays low.
+        let samples = [0x20, 0x20, 0x82, 0x7E, 0xE0, 0xE0, 0xE0, 0x20, 0x20, 0xE0];
+        assert_eq!(
+            parse(&wav(1, 8, 35_000, &samples)).unwrap(),
+            [
+                Signal::Hold(false, 400),
+                Signal::Hold(true, 300),
+                Signal::Hold(false, 200),
+                Signal::Hold(true, 100)
+            ]
+        );
+
+        // 16-bit stereo, channels averaged
+        let mut samples = Vec::new();
+        for (left, right) in [(-9000i16, -7000i16), (8000, 6000), (7000, 9000)] {
+            samples.extend(left.to_le_bytes());
+            samples.extend(right.to_le_bytes());
+        }
+        assert_eq!(
+            parse(&wav(2, 16, 44_100, &samples)).unwrap(),
+            [Signal::Hold(false, 79), Signal::Hold(true, 159)]
+        );
+
+        assert!(parse(&wav(1, 24, 44_100, &[0; 6])).is_err());
+        assert!(parse(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+}
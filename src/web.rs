@@ -0,0 +1,353 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use tungstenite::{Message, WebSocket};
+
+use crate::disassembler::disassemble_at;
+use crate::formatter::HexBytes;
+use crate::interconnect::Interconnect;
+
+// Debug server for browser front panels. Requests are `METHOD /path?key=value` and get JSON
+// back, either as plain HTTP or as WebSocket text messages on `/ws` (send `GET /state`,
+// receive the same JSON as the HTTP request would). Numbers in paths are hex, numbers in
+// responses are decimal.
+//
+//   GET  /state                       `Cpu::to_json` and whether the CPU is running
+//   GET  /memory?addr=8000&len=100    bytes as a hex string (len defaults to a 256 byte page)
+//   GET  /disasm?addr=0100&count=10   instructions from addr (default PC)
+//   GET  /breakpoints                 breakpoint addresses
+//   POST /breakpoints?addr=0100       add a breakpoint, DELETE removes it
+//   POST /step?count=1                execute instructions
+//   POST /run, POST /pause            run a frame at a time until paused or stopped
+//   POST /quit                        stop the server
+//
+// When a run stops on a breakpoint or break event every WebSocket client is sent
+// `{"event":"stopped","reason":"..","pc":..}`.
+pub fn serve(i: &mut Interconnect, listener: TcpListener) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut server = Server::default();
+    while !server.quit {
+        let mut idle = true;
+        match listener.accept() {
+            Ok((stream, _)) => {
+                idle = false;
+                if let Err(e) = server.connect(i, stream) {
+                    warn!("Web client failed: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        idle &= !server.poll_sockets(i);
+        if server.running {
+            idle = false;
+            i.execute_cpu();
+            if let Some(stop) = i.stopped {
+                server.running = false;
+                let event = format!(
+                    "{{\"event\":\"stopped\",\"reason\":{},\"pc\":{}}}",
+                    json_str(&stop.to_string()),
+                    i.cpu.reg.pc
+                );
+                server.broadcast(&event);
+            }
+        }
+        if idle {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    sockets: Vec<WebSocket<TcpStream>>,
+    running: bool,
+    quit: bool,
+}
+
+impl Server {
+    // Answers a plain HTTP request, or upgrades the connection to a WebSocket
+    fn connect(&mut self, i: &mut Interconnect, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut peeked = [0; 16];
+        let len = stream.peek(&mut peeked)?;
+        if peeked[..len].starts_with(b"GET /ws ") {
+            let socket = tungstenite::accept(stream).map_err(io::Error::other)?;
+            socket.get_ref().set_nonblocking(true)?;
+            self.sockets.push(socket);
+            return Ok(());
+        }
+
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        // Bodies aren't used, everything is in the path
+        reader.by_ref().take(length).read_to_end(&mut Vec::new())?;
+
+        let (status, body) = match self.handle(i, &request) {
+            Ok(body) => ("200 OK", body),
+            Err((status, message)) => (status, format!("{{\"error\":{}}}", json_str(&message))),
+        };
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+             Access-Control-Allow-Origin: *\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    // Reads WebSocket requests, returns true if there were any
+    fn poll_sockets(&mut self, i: &mut Interconnect) -> bool {
+        let mut busy = false;
+        let mut n = 0;
+        while n < self.sockets.len() {
+            match self.sockets[n].read() {
+                Ok(Message::Text(request)) => {
+                    busy = true;
+                    let reply = match self.handle(i, request.as_str()) {
+                        Ok(body) => body,
+                        Err((_, message)) => format!("{{\"error\":{}}}", json_str(&message)),
+                    };
+                    if self.sockets[n].send(Message::text(reply)).is_err() {
+                        self.sockets.remove(n);
+                        continue;
+                    }
+                }
+                Ok(_) => busy = true,
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // Pongs and closes queued by `read` still need sending
+                    let _ = self.sockets[n].flush();
+                }
+                Err(_) => {
+                    self.sockets.remove(n);
+                    continue;
+                }
+            }
+            n += 1;
+        }
+        busy
+    }
+
+    fn broadcast(&mut self, text: &str) {
+        self.sockets
+            .retain_mut(|socket| socket.send(Message::text(text)).is_ok());
+    }
+
+    // Routes `METHOD /path?query [HTTP/1.1]` to a JSON response or an HTTP status and message
+    fn handle(&mut self, i: &mut Interconnect, request: &str) -> Result<String, Error> {
+        let mut words = request.split_whitespace();
+        let method = words.next().unwrap_or("");
+        let target = words
+            .next()
+            .ok_or((BAD_REQUEST, "Missing path".to_string()))?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let param = |key: &str| -> Result<Option<u16>, Error> {
+            let value = query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| *k == key);
+            match value {
+                Some((_, value)) => u16::from_str_radix(value.trim_start_matches("0x"), 16)
+                    .map(Some)
+                    .map_err(|_| (BAD_REQUEST, format!("Bad hex number `{}`", value))),
+                None => Ok(None),
+            }
+        };
+        match (method, path) {
+            ("GET", "/state") => Ok(self.state(i)),
+            ("GET", "/memory") => {
+                let addr = param("addr")?.unwrap_or(0);
+                let len = param("len")?.unwrap_or(0x100);
+                let bytes: Vec<u8> = (0..len).map(|n| i.peek(addr.wrapping_add(n))).collect();
+                Ok(format!(
+                    "{{\"addr\":{},\"data\":\"{}\"}}",
+                    addr,
+                    HexBytes(&bytes, "")
+                ))
+            }
+            ("GET", "/disasm") => {
+                let mut addr = param("addr")?.unwrap_or(i.cpu.reg.pc);
+                let count = param("count")?.unwrap_or(16);
+                let mut lines = Vec::new();
+                for _ in 0..count {
+                    let line = disassemble_at(&i.cpu.memory, addr);
+                    lines.push(format!(
+                        "{{\"addr\":{},\"bytes\":\"{}\",\"text\":{}}}",
+                        addr,
+                        HexBytes(&line.bytes, ""),
+                        json_str(&line.text(&i.symbols))
+                    ));
+                    addr = line.next();
+                }
+                Ok(format!("[{}]", lines.join(",")))
+            }
+            ("GET", "/breakpoints") => {
+                let addrs: Vec<String> =
+                    i.breakpoints.list().map(|bp| bp.addr.to_string()).collect();
+                Ok(format!("[{}]", addrs.join(",")))
+            }
+            ("POST", "/breakpoints") | ("DELETE", "/breakpoints") => {
+                let addr = param("addr")?.ok_or((BAD_REQUEST, "Missing addr".to_string()))?;
+                if method == "POST" {
+                    i.breakpoints.add(addr);
+                } else if !i.breakpoints.remove(addr) {
+                    return Err((NOT_FOUND, format!("No breakpoint at {:04X}", addr)));
+                }
+                Ok("{}".to_string())
+            }
+            ("POST", "/step") => {
+                self.running = false;
+                for _ in 0..param("count")?.unwrap_or(1) {
+                    if i.step().stop.is_some() {
+                        break;
+                    }
+                }
+                Ok(self.state(i))
+            }
+            ("POST", "/run") => {
+                self.running = true;
+                Ok(self.state(i))
+            }
+            ("POST", "/pause") => {
+                self.running = false;
+                Ok(self.state(i))
+            }
+            ("POST", "/quit") => {
+                self.quit = true;
+                Ok("{}".to_string())
+            }
+            _ => Err((NOT_FOUND, format!("No {} {}", method, path))),
+        }
+    }
+
+    // `Cpu::to_json` plus whether the CPU is running
+    fn state(&self, i: &Interconnect) -> String {
+        let cpu = i.cpu.to_json();
+        format!("{},\"running\":{}}}", &cpu[..cpu.len() - 1], self.running)
+    }
+}
+
+type Error = (&'static str, String);
+
+const BAD_REQUEST: &str = "400 Bad Request";
+const NOT_FOUND: &str = "404 Not Found";
+
+fn json_str(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, Server};
+    use crate::interconnect::{Interconnect, Preset};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use tungstenite::Message;
+
+    #[test]
+    fn routes() {
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &[0x3E, 0x05, 0x00]);
+        let mut server = Server::default();
+        let mut get = |request: &str| server.handle(&mut i, request);
+        assert!(get("GET /state HTTP/1.1")
+            .unwrap()
+            .starts_with("{\"pc\":256,\"sp\":65535,"));
+        assert_eq!(
+            get("GET /memory?addr=0100&len=3").unwrap(),
+            "{\"addr\":256,\"data\":\"3E0500\"}"
+        );
+        assert_eq!(
+            get("GET /disasm?count=2").unwrap(),
+            "[{\"addr\":256,\"bytes\":\"3E05\",\"text\":\"LD A, $05\"},\
+             {\"addr\":258,\"bytes\":\"00\",\"text\":\"NOP\"}]"
+        );
+        assert_eq!(get("POST /breakpoints?addr=0102").unwrap(), "{}");
+        assert_eq!(get("GET /breakpoints").unwrap(), "[258]");
+        assert!(get("POST /step?count=5")
+            .unwrap()
+            .starts_with("{\"pc\":258,"));
+        assert_eq!(get("DELETE /breakpoints?addr=0102").unwrap(), "{}");
+        assert_eq!(
+            get("DELETE /breakpoints?addr=0102").unwrap_err().0,
+            "404 Not Found"
+        );
+        assert!(get("GET /memory?addr=zz").is_err());
+        assert!(get("GET /nothing").is_err());
+    }
+
+    #[test]
+    fn http_and_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut http = TcpStream::connect(addr).unwrap();
+            write!(
+                http,
+                "GET /memory?addr=0100&len=2 HTTP/1.1\r\nHost: x\r\n\r\n"
+            )
+            .unwrap();
+            let mut response = String::new();
+            http.read_to_string(&mut response).unwrap();
+
+            let stream = TcpStream::connect(addr).unwrap();
+            let (mut socket, _) = tungstenite::client(format!("ws://{}/ws", addr), stream).unwrap();
+            socket
+                .send(Message::text("POST /breakpoints?addr=0102"))
+                .unwrap();
+            socket.read().unwrap();
+            socket.send(Message::text("POST /run")).unwrap();
+            let mut replies = Vec::new();
+            while replies.len() < 2 {
+                if let Message::Text(text) = socket.read().unwrap() {
+                    replies.push(text.to_string());
+                }
+            }
+            socket.send(Message::text("POST /quit")).unwrap();
+            socket.read().unwrap();
+            (response, replies)
+        });
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.cpu.memory.load_slice(0x0100, &[0x3E, 0x05, 0x00]);
+        serve(&mut i, listener).unwrap();
+
+        let (response, replies) = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"addr\":256,\"data\":\"3E05\"}"));
+        assert!(replies[0].contains("\"running\":true"));
+        assert_eq!(
+            replies[1],
+            "{\"event\":\"stopped\",\"reason\":\"Breakpoint at 0102\",\"pc\":258}"
+        );
+    }
+}
@@ -0,0 +1,579 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use log::info;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::device::Device;
+use crate::interconnect::{HookAction, Interconnect};
+use crate::memory::{Memory, Region};
+use crate::peripherals::KeyMatrix;
+use crate::video::Framebuffer;
+
+pub const CLOCK: usize = 3_250_000;
+pub const ROM_SIZE: usize = 0x2000;
+pub const RAM_START: u16 = 0x4000;
+pub const MAX_RAM: usize = 0x4000;
+
+// The NMI generator fires once a scanline, 64us
+pub const LINE_CYCLES: usize = 207;
+const FRAME_CYCLES: usize = CLOCK / 50;
+
+// 24 rows of 32 characters, 8x8 pixels each
+const COLUMNS: usize = 32;
+const ROWS: usize = 24;
+pub const WIDTH: usize = COLUMNS * 8;
+pub const HEIGHT: usize = ROWS * 8;
+const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+// Keyboard half rows, selected by A8-A15 of an IN from port FE, and their keys from bit 0.
+// SHIFT is bit 0 of the first row, which has no character.
+pub const KEY_ROWS: [&str; 8] = [
+    "\0ZXCV", "ASDFG", "QWERT", "12345", "09876", "POIUY", "\rLKJH", " .MNB",
+];
+pub const SHIFT: (usize, usize) = (0, 0);
+
+// Row and column of the key for `c`: upper case letters, digits, space, '.' and NEWLINE
+// (\r)
+pub fn key_position(c: char) -> Option<(usize, usize)> {
+    KEY_ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.chars().position(|key| key == c).map(|n| (row, n)))
+        .filter(|_| c != '\0')
+}
+
+// Where the ROM's LOAD routine starts reading the tape, after the program name has been
+// checked, and where it continues once the program is in
+pub const LOAD: u16 = 0x0347;
+pub const SLOW_FAST: u16 = 0x0207;
+
+// A program is saved from VERSN to the end of the variables (E_LINE), along with the
+// system variables pointing into it
+pub const VERSN: u16 = 0x4009;
+pub const D_FILE: u16 = 0x400C;
+pub const DF_CC: u16 = 0x400E;
+pub const VARS: u16 = 0x4010;
+pub const E_LINE: u16 = 0x4014;
+pub const S_POSN: u16 = 0x4039;
+// First address after the system variables, where the BASIC program starts
+pub const PROG: u16 = 0x407D;
+
+// The ZX81 character set, codes 00-3F. The graphics blocks are shown as the nearest ASCII
+// character; 80-BF are the same characters in inverse video.
+const CHARSET: &[u8; 64] = b" ##########\"$$:?()><=+-*/;,.0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+pub fn to_ascii(code: u8) -> char {
+    match code & 0x7F {
+        code @ 0x00..=0x3F => CHARSET[code as usize] as char,
+        0x76 => '\n',
+        _ => '?',
+    }
+}
+
+// A ZX81 program (.p, .81 or .p81, the last has the program name in front)
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ZxProgram {
+    pub name: Option<String>,
+    // From VERSN up to E_LINE
+    pub data: Vec<u8>,
+}
+
+impl ZxProgram {
+    pub fn parse(data: &[u8], named: bool) -> Result<Self, String> {
+        let (name, data) = match named {
+            true => {
+                let end = data
+                    .iter()
+                    .position(|&c| c & 0x80 != 0)
+                    .ok_or("Unterminated program name")?;
+                let name = data[..=end].iter().map(|&c| to_ascii(c)).collect();
+                (Some(name), &data[end + 1..])
+            }
+            false => (None, data),
+        };
+        let word = |addr: u16| {
+            let n = (addr - VERSN) as usize;
+            data.get(n..n + 2).map(|w| u16::from_le_bytes([w[0], w[1]]))
+        };
+        let e_line = word(E_LINE).ok_or("Too short for the system variables")?;
+        let (d_file, vars) = (word(D_FILE).unwrap_or(0), word(VARS).unwrap_or(0));
+        let len = e_line.wrapping_sub(VERSN) as usize;
+        if !(PROG <= d_file && d_file < vars && vars < e_line) || len > data.len() {
+            return Err(format!(
+                "Inconsistent pointers: D_FILE {:04X}, VARS {:04X}, E_LINE {:04X} with {} bytes",
+                d_file,
+                vars,
+                e_line,
+                data.len()
+            ));
+        }
+        Ok(Self {
+            name,
+            data: data[..len].to_vec(),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let named = archive::inner(path.as_ref())
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("p81"));
+        Self::parse(&archive::read(&path)?, named)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Writes the program at VERSN, with the print position reset to the top left of the
+    // display file so whatever the program was saved with doesn't print into the middle of it
+    pub fn install(&self, memory: &mut Memory) {
+        memory.load_slice(VERSN, &self.data);
+        let d_file = memory.peek16(D_FILE);
+        memory.load_slice(DF_CC, &d_file.wrapping_add(1).to_le_bytes());
+        memory.load_slice(S_POSN, &[33, 24]);
+    }
+
+    // Loads the program for the ROM's LOAD routine, for a PC hook at `LOAD`
+    pub fn trap(&self, cpu: &mut Cpu) -> HookAction {
+        self.install(&mut cpu.memory);
+        cpu.reg.pc = SLOW_FAST;
+        HookAction::Execute
+    }
+}
+
+// The ULA puts a NOP on the data bus for opcodes fetched from 8000-FFFF with bit 6 clear,
+// so the display file can be run as code: each character is fetched (and shown) as the
+// CPU goes over it, up to the HALT (76) ending the line
+pub fn display_nop(addr: u16, opcode: u8) -> u8 {
+    match addr & 0x8000 != 0 && opcode & 0x40 == 0 {
+        true => 0x00,
+        false => opcode,
+    }
+}
+
+// The ZX81's ULA. The picture is made by the CPU: in SLOW mode the NMI generator
+// interrupts every scanline so the ROM can count off the blank lines above and below the
+// display, then the ROM runs each line of the display file in the upper 32K. /INT is A6
+// of the refresh address, so the ROM loads R so that it drops once the line is done and
+// the interrupt takes the CPU out of the HALT at the end of the line.
+//
+// OUT to port FE (A0 low) turns the NMI generator on and FD (A1 low) off. An IN from FE
+// while it's off starts the vertical sync and any OUT ends it. The IN reads the keyboard
+// half rows with A8-A15 low, bits 0-4 low while a key is down, bit 6 set for 50Hz and bit 7
+// the tape input.
+//
+// Rather than shifting out pixels as the characters go past the picture is drawn from the
+// display file at the start of each vertical sync, with the character patterns from the
+// page I points at (1E00 in the ROM). Characters with bit 7 set are inverse.
+pub struct Ula {
+    pub keys: KeyMatrix,
+    pub frame: Rc<RefCell<Framebuffer>>,
+    pub nmi_enabled: bool,
+    pub vsync: bool,
+    pub tape_in: bool,
+    pub frames: u64,
+    // T states into the current scanline
+    pub cycles: usize,
+    nmi: bool,
+    refresh: u16,
+    render: bool,
+}
+
+impl Ula {
+    pub fn new(keys: KeyMatrix) -> Self {
+        Self {
+            keys,
+            frame: Rc::new(RefCell::new(Framebuffer::new(WIDTH, HEIGHT))),
+            nmi_enabled: false,
+            vsync: false,
+            tape_in: false,
+            frames: 0,
+            cycles: 0,
+            nmi: false,
+            refresh: 0,
+            render: false,
+        }
+    }
+
+    // Draws the display file D_FILE points at. Each of the 24 lines is ended by a 76, so a
+    // collapsed display file (on machines with under 3.25K of RAM) has lines shorter than
+    // 32 characters or just the 76.
+    pub fn render(&self, memory: &Memory) {
+        let charset = (self.refresh >> 8 & 0xFE) << 8;
+        let mut frame = self.frame.borrow_mut();
+        // Past the 76 in front of the first line
+        let mut addr = memory.peek16(D_FILE).wrapping_add(1);
+        for row in 0..ROWS {
+            let mut line = [0u8; COLUMNS];
+            let mut len = 0;
+            while len <= COLUMNS {
+                let c = memory.peek(addr);
+                addr = addr.wrapping_add(1);
+                if c == 0x76 {
+                    break;
+                }
+                if len < COLUMNS {
+                    line[len] = c;
+                }
+                len += 1;
+            }
+            for y in 0..8 {
+                let pixels = frame.row_mut(row * 8 + y);
+                for (column, &c) in line.iter().enumerate() {
+                    let pattern = match column < len {
+                        true => memory.peek(charset | (c as u16 & 0x3F) << 3 | y as u16),
+                        false => 0,
+                    };
+                    let pattern = match c & 0x80 != 0 && column < len {
+                        true => !pattern,
+                        false => pattern,
+                    };
+                    for x in 0..8 {
+                        let pixel = match pattern & 0x80 >> x != 0 {
+                            true => BLACK,
+                            false => WHITE,
+                        };
+                        let n = (column * 8 + x) * 4;
+                        pixels[n..n + 4].copy_from_slice(&pixel);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Device for Ula {
+    fn tick(&mut self, cycles: usize) {
+        self.nmi = false;
+        self.cycles += cycles;
+        while self.cycles >= LINE_CYCLES {
+            self.cycles -= LINE_CYCLES;
+            self.nmi |= self.nmi_enabled;
+        }
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        if port & 0x01 != 0 {
+            return 0xFF;
+        }
+        if !self.nmi_enabled && !self.vsync {
+            self.vsync = true;
+            self.render = true;
+        }
+        let keys = (0..8)
+            .filter(|row| port & 0x100 << row == 0)
+            .fold(0, |keys, row| keys | self.keys.row(row));
+        !keys & 0x1F | 0x40 | (self.tape_in as u8) << 7
+    }
+
+    fn io_write(&mut self, port: u16, _value: u8) {
+        self.vsync = false;
+        if port & 0x01 == 0 {
+            self.nmi_enabled = true;
+        } else if port & 0x02 == 0 {
+            self.nmi_enabled = false;
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<u8> {
+        match self.refresh & 0x40 {
+            0 => Some(0xFF),
+            _ => None,
+        }
+    }
+
+    fn level_interrupt(&self) -> bool {
+        true
+    }
+
+    fn pending_nmi(&self) -> bool {
+        self.nmi
+    }
+
+    fn scan(&mut self, memory: &Memory) {
+        if std::mem::take(&mut self.render) {
+            self.frames += 1;
+            self.render(memory);
+        }
+    }
+
+    fn refresh(&mut self, ir: u16) {
+        self.refresh = ir;
+    }
+}
+
+// A ZX81: the 8K ROM at 0000 and again at 2000, 1K to 16K of RAM at 4000 repeated up to
+// 7FFF, and 8000-FFFF the same as 0000-7FFF except for the ULA's display NOPs. Every port
+// goes to the ULA, which decodes A0 and A1.
+pub struct Zx81 {
+    pub keyboard: KeyMatrix,
+    pub ula: Rc<RefCell<Ula>>,
+}
+
+impl Zx81 {
+    // Replaces the memory of `i` with the ROM and `ram` bytes of RAM (1K to 16K)
+    pub fn install(i: &mut Interconnect, rom: &[u8], ram: usize) -> Result<Self, String> {
+        if rom.len() != ROM_SIZE {
+            return Err(format!("ROM is {} bytes, expected {}", rom.len(), ROM_SIZE));
+        }
+        if !(0x400..=MAX_RAM).contains(&ram) || !ram.is_multiple_of(0x400) {
+            return Err(format!("Unsupported RAM size: {}", ram));
+        }
+        let mut memory = Memory::default();
+        memory.load_rom(0x0000, rom);
+        memory.map(0x2000..=0x3FFF, Region::Mirror(0x0000));
+        for start in (RAM_START as usize + ram..0x8000).step_by(ram) {
+            let end = (start + ram).min(0x8000) - 1;
+            memory.map(start as u16..=end as u16, Region::Mirror(RAM_START));
+        }
+        memory.map(0x8000..=0xFFFF, Region::Mirror(0x0000));
+        i.cpu.memory = memory;
+        i.cpu.reg.pc = 0x0000;
+        i.cpu.m1_filter = Some(display_nop);
+        i.clock_speed = CLOCK;
+
+        let keyboard = KeyMatrix::default();
+        let ula = Ula::new(keyboard.clone());
+        i.frame_cycles = Some(FRAME_CYCLES);
+        i.display = Some(ula.frame.clone());
+        let ula = i.add_device(ula);
+        i.register_port(0x00..=0xFF, ula.clone());
+        Ok(Self { keyboard, ula })
+    }
+
+    // Installs the ROM at `path` with 16K of RAM
+    pub fn load<P: AsRef<Path>>(i: &mut Interconnect, path: P) -> io::Result<Self> {
+        let rom = archive::read(&path)?;
+        let machine = Self::install(i, &rom, MAX_RAM)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        info!("Loaded ZX81 ROM {:?}", path.as_ref());
+        Ok(machine)
+    }
+
+    // Presses or releases the key for `c`, false if there's no such key
+    pub fn set_key(&self, c: char, pressed: bool) -> bool {
+        match key_position(c) {
+            Some((row, column)) => {
+                self.keyboard.set(row, column, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        display_nop, key_position, to_ascii, Zx81, ZxProgram, DF_CC, D_FILE, E_LINE, LOAD,
+        ROM_SIZE, SHIFT, S_POSN, VARS, VERSN,
+    };
+    use crate::assembler::assemble;
+    use crate::interconnect::{Interconnect, Preset};
+    use crate::memory::MemoryRW;
+
+    const WHITE: [u8; 4] = [0xFF; 4];
+    const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+    fn rom(source: &str) -> Vec<u8> {
+        let mut rom = assemble(source).unwrap().to_binary();
+        rom.resize(ROM_SIZE, 0);
+        rom
+    }
+
+    fn program() -> Vec<u8> {
+        let mut data = vec![0; 0x4100 - VERSN as usize];
+        let mut set = |addr: u16, word: u16| {
+            let n = (addr - VERSN) as usize;
+            data[n..n + 2].copy_from_slice(&word.to_le_bytes());
+        };
+        set(D_FILE, 0x4090);
+        set(DF_CC, 0x40A0);
+        set(VARS, 0x40B0);
+        set(E_LINE, 0x4100);
+        data
+    }
+
+    #[test]
+    fn programs() {
+        let mut data = program();
+        data.extend([0x80; 4]);
+        let p = ZxProgram::parse(&data, false).unwrap();
+        assert_eq!(p.data.len(), 0x4100 - VERSN as usize);
+
+        let mut named = vec![0x2D, 0x2E, 0x3C + 0x80];
+        named.extend(program());
+        let p = ZxProgram::parse(&named, true).unwrap();
+        assert_eq!(p.name.as_deref(), Some("HIW"));
+
+        let mut i = Interconnect::builder().preset(Preset::Cpm).build();
+        i.insert_p(p);
+        i.cpu.reg.pc = LOAD;
+        i.step();
+        assert_eq!(i.cpu.reg.pc, 0x0208);
+        assert_eq!(i.peek16(E_LINE), 0x4100);
+        assert_eq!(i.peek16(DF_CC), 0x4091);
+        assert_eq!(i.peek16(S_POSN), 0x1821);
+
+        assert!(ZxProgram::parse(&program()[..20], false).is_err());
+        assert!(ZxProgram::parse(&program()[..0x80], false).is_err());
+        assert_eq!(to_ascii(0x26 | 0x80), 'A');
+    }
+
+    #[test]
+    fn memory_and_keyboard() {
+        let mut i = Interconnect::builder().build();
+        let machine = Zx81::install(&mut i, &rom("JP 0"), 0x400).unwrap();
+        assert_eq!(i.cpu.read8(0x2000), 0xC3);
+        assert_eq!(i.cpu.read8(0x8000), 0xC3);
+        i.cpu.write8(0x0000, 0x00);
+        assert_eq!(i.cpu.read8(0x0000), 0xC3);
+        // 1K repeated through 4000-7FFF and again at C000
+        i.cpu.write8(0x4001, 0x12);
+        assert_eq!(i.cpu.read8(0x4401), 0x12);
+        assert_eq!(i.cpu.read8(0x7C01), 0x12);
+        assert_eq!(i.cpu.read8(0xC001), 0x12);
+        i.cpu.write8(0xC002, 0x34);
+        assert_eq!(i.cpu.read8(0x4002), 0x34);
+
+        // Characters run from the upper 32K are NOPs, the HALT ending a line isn't
+        assert_eq!(display_nop(0xC000, 0x26), 0x00);
+        assert_eq!(display_nop(0xC000, 0xA6), 0x00);
+        assert_eq!(display_nop(0xC000, 0x76), 0x76);
+        assert_eq!(display_nop(0x4000, 0x26), 0x26);
+
+        assert_eq!(i.cpu.io.read(0xFDFE), 0x5F);
+        assert!(machine.set_key('A', true));
+        machine.keyboard.press(SHIFT.0, SHIFT.1);
+        assert_eq!(i.cpu.io.read(0xFDFE), 0x5E);
+        assert_eq!(i.cpu.io.read(0xFEFE), 0x5E);
+        // Both half rows selected
+        assert_eq!(i.cpu.io.read(0xFCFE), 0x5E);
+        assert_eq!(i.cpu.io.read(0xFBFE), 0x5F);
+        assert_eq!(key_position('\r'), Some((6, 0)));
+        assert_eq!(key_position('\0'), None);
+        assert!(!machine.set_key('a', true));
+
+        assert!(Zx81::install(&mut i, &[0; 0x1000], 0x400).is_err());
+        assert!(Zx81::install(&mut i, &rom("NOP"), 0x4400).is_err());
+        assert!(Zx81::install(&mut i, &rom("NOP"), 0x600).is_err());
+    }
+
+    #[test]
+    fn display_generation() {
+        // Waits for an NMI from the generator, then runs a display line at 4000 from the
+        // upper 32K with R loaded the way the ROM does it: A6 of the refresh address drops
+        // right after the line's HALT and the interrupt returns to the next line
+        let source = "
+            LD SP,4400h
+            IM 1
+            LD A,1Eh
+            LD I,A
+            OUT (0FEh),A
+            HALT
+            OUT (0FDh),A
+            LD HL,0C000h
+            LD A,0DDh
+            LD R,A
+            EI
+            JP (HL)
+            ORG 38h
+            POP HL
+            LD (4300h),HL
+            HALT
+            ORG 66h
+            EX AF,AF'
+            INC A
+            EX AF,AF'
+            RETN
+        ";
+        let mut i = Interconnect::builder().build();
+        let machine = Zx81::install(&mut i, &rom(source), 0x400).unwrap();
+        // 32 x LD H,26h if the ULA didn't turn them into NOPs
+        i.cpu.memory.load_slice(0x4000, &[0x26; 32]);
+        i.cpu.memory.load_slice(0x4020, &[0x76]);
+        i.cpu.reg.i = 0x00;
+
+        // Halted until the NMI, at most a line later
+        for _ in 0..7 {
+            i.step();
+        }
+        assert!(i.cpu.int.halt);
+        assert_eq!(i.cpu.reg.pc, 0x000C);
+        let cycles = i.cpu.cycles;
+        while i.cpu.int.halt {
+            i.step();
+        }
+        assert!(i.cpu.cycles - cycles <= 207);
+        assert_eq!(i.cpu.reg.pc, 0x0066);
+        assert_eq!(i.cpu.reg.sp, 0x43FE);
+        assert_eq!(i.peek16(0x43FE), 0x000C);
+
+        // Into the line, and stuck in its HALT only until R comes round
+        while i.cpu.reg.pc != 0xC000 {
+            i.step();
+        }
+        assert!(!machine.ula.borrow().nmi_enabled);
+        while i.cpu.reg.pc != 0x0038 {
+            i.step();
+            assert!(i.cpu.reg.pc < 0x0039 || i.cpu.reg.pc >= 0xC000);
+        }
+        // R was 80 after the HALT, then one NOP while halted and the interrupt acknowledge
+        assert_eq!(i.cpu.reg.r, 0x82);
+        while !i.cpu.int.halt {
+            i.step();
+        }
+        assert_eq!(i.peek16(0x4300), 0xC021);
+        assert_eq!(i.cpu.reg.h, 0xC0);
+        assert_eq!(i.cpu.reg.a_, 0x01);
+    }
+
+    #[test]
+    fn display_file() {
+        // A pattern in ROM for character 26 ('A') with the charset at 1E00
+        let mut rom = rom("JP 0");
+        rom[0x1E00 + 0x26 * 8..0x1E00 + 0x27 * 8]
+            .copy_from_slice(&[0x00, 0x3C, 0x42, 0x42, 0x7E, 0x42, 0x42, 0x00]);
+        let mut i = Interconnect::builder().build();
+        let machine = Zx81::install(&mut i, &rom, 0x400).unwrap();
+        i.cpu.reg.i = 0x1E;
+        // A collapsed display file: "A", an inverse space, then 23 empty lines except for
+        // an "A" in the last column of the second
+        let mut d_file = vec![0x76, 0x26, 0x80, 0x76];
+        d_file.extend([0x00; 31]);
+        d_file.extend([0x26, 0x76]);
+        d_file.extend([0x76; 22]);
+        i.cpu.memory.load_slice(0x4100, &d_file);
+        i.cpu.memory.load_slice(D_FILE, &0x4100u16.to_le_bytes());
+        i.step();
+
+        // Only an IN from FE with the NMI generator off starts a frame
+        i.cpu.io.read(0xFEFF);
+        i.step();
+        assert_eq!(machine.ula.borrow().frames, 0);
+        i.cpu.io.read(0xFEFE);
+        i.step();
+        assert_eq!(machine.ula.borrow().frames, 1);
+        assert!(machine.ula.borrow().vsync);
+        i.cpu.io.read(0xFEFE);
+        i.step();
+        assert_eq!(machine.ula.borrow().frames, 1);
+        i.cpu.io.write(0xFF, 0x00);
+        assert!(!machine.ula.borrow().vsync);
+
+        let frame = machine.ula.borrow().frame.clone();
+        let frame = frame.borrow();
+        assert_eq!(frame.pixel(0, 0), WHITE);
+        assert_eq!(frame.pixel(2, 1), BLACK);
+        assert_eq!(frame.pixel(1, 2), BLACK);
+        assert_eq!(frame.pixel(2, 2), WHITE);
+        assert_eq!(frame.pixel(8, 0), BLACK);
+        assert_eq!(frame.pixel(15, 7), BLACK);
+        assert_eq!(frame.pixel(16, 0), WHITE);
+        assert_eq!(frame.pixel(31 * 8 + 2, 9), BLACK);
+        assert_eq!(frame.pixel(30 * 8 + 2, 9), WHITE);
+        assert_eq!(frame.pixel(2, 23 * 8 + 1), WHITE);
+    }
+}
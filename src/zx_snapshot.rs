@@ -0,0 +1,745 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+use crate::archive;
+use crate::cpu::Cpu;
+use crate::memory::ADDRESS_SPACE;
+use crate::snapshot::Snapshot;
+
+// Start of RAM on the Spectrum, everything below is ROM and never part of a snapshot
+pub const RAM_START: u16 = 0x4000;
+
+// .sna header, see `ZxSnapshot::from_sna`
+const SNA_HEADER: usize = 27;
+const SNA_48K: usize = SNA_HEADER + 0xC000;
+// .z80 version 1 header, see `ZxSnapshot::from_z80`
+const Z80_HEADER: usize = 30;
+
+// 16K RAM bank of a 128K machine
+pub const BANK_SIZE: usize = 0x4000;
+
+// AY-3-8910 registers and the one selected through port FFFD
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct AyState {
+    pub selected: u8,
+    pub registers: [u8; 16],
+}
+
+// Machine state as stored by ZX Spectrum snapshot files. The ROM area of `cpu.memory` is
+// left zeroed when loading and ignored when restoring, the ROM comes from the machine.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ZxSnapshot {
+    pub cpu: Snapshot,
+    // Border colour, 0-7
+    pub border: u8,
+    // 128K machines: RAM banks 0-7 and the last value written to port 7FFD. Empty for a
+    // 48K machine. The banks paged in (5, 2 and the one selected by 7FFD) are in
+    // `cpu.memory` too.
+    pub banks: Vec<Vec<u8>>,
+    pub port_7ffd: u8,
+    pub ay: Option<AyState>,
+}
+
+impl ZxSnapshot {
+    pub fn capture(cpu: &Cpu, border: u8) -> Self {
+        Self {
+            cpu: Snapshot::capture(cpu),
+            border,
+            banks: Vec::new(),
+            port_7ffd: 0,
+            ay: None,
+        }
+    }
+
+    pub fn is_128k(&self) -> bool {
+        !self.banks.is_empty()
+    }
+
+    // Writes the registers and RAM back, the cycle count is left alone
+    pub fn restore(&self, cpu: &mut Cpu) {
+        self.cpu.restore_registers(cpu);
+        cpu.memory
+            .load_slice(RAM_START, &self.cpu.memory[RAM_START as usize..]);
+    }
+
+    // 48K .sna: a 27 byte header and the 48K of RAM. The header has I, HL', DE', BC', AF',
+    // HL, DE, BC, IY, IX (pairs little endian), the interrupt flags (bit 2 is IFF2), R, AF,
+    // SP, the interrupt mode and the border colour. PC isn't stored, it's pushed on the
+    // stack, so loading pops it.
+    pub fn from_sna(data: &[u8]) -> Result<Self, String> {
+        if data.len() != SNA_48K {
+            return Err(format!(
+                "Expected a {} byte 48K .sna, got {} bytes",
+                SNA_48K,
+                data.len()
+            ));
+        }
+        let pair = |n: usize| u16::from_le_bytes([data[n], data[n + 1]]);
+        let mut memory = vec![0; RAM_START as usize];
+        memory.extend_from_slice(&data[SNA_HEADER..]);
+        let sp = pair(23);
+        let pc = u16::from_le_bytes([memory[sp as usize], memory[sp.wrapping_add(1) as usize]]);
+        let iff = data[19] & 0x04 != 0;
+        Ok(Self {
+            cpu: Snapshot {
+                pc,
+                sp: sp.wrapping_add(2),
+                af: pair(21),
+                bc: pair(13),
+                de: pair(11),
+                hl: pair(9),
+                ix: pair(17),
+                iy: pair(15),
+                af_: pair(7),
+                bc_: pair(5),
+                de_: pair(3),
+                hl_: pair(1),
+                i: data[0],
+                r: data[20],
+                im: data[25] & 0x03,
+                // Loaders return with RETN, which copies IFF2 to IFF1
+                iff1: iff,
+                iff2: iff,
+                halted: false,
+                irq: false,
+                nmi_pending: false,
+                vector: 0,
+                ei_pending: false,
+                cycles: 0,
+                memory,
+            },
+            border: data[26] & 0x07,
+            banks: Vec::new(),
+            port_7ffd: 0,
+            ay: None,
+        })
+    }
+
+    pub fn to_sna(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let mut memory = cpu.memory.clone();
+        let sp = cpu.sp.wrapping_sub(2);
+        let [low, high] = cpu.pc.to_le_bytes();
+        memory[sp as usize] = low;
+        memory[sp.wrapping_add(1) as usize] = high;
+
+        let mut out = vec![cpu.i];
+        for pair in [
+            cpu.hl_, cpu.de_, cpu.bc_, cpu.af_, cpu.hl, cpu.de, cpu.bc, cpu.iy, cpu.ix,
+        ] {
+            out.extend_from_slice(&pair.to_le_bytes());
+        }
+        out.extend_from_slice(&[(cpu.iff2 as u8) << 2, cpu.r]);
+        out.extend_from_slice(&cpu.af.to_le_bytes());
+        out.extend_from_slice(&sp.to_le_bytes());
+        out.extend_from_slice(&[cpu.im, self.border]);
+        out.extend_from_slice(&memory[RAM_START as usize..]);
+        out
+    }
+
+    // .z80, versions 1 to 3. Version 1 is a 30 byte header followed by the 48K of RAM,
+    // optionally compressed. Later versions have PC zero in that header and an additional
+    // one (23 or 54-55 bytes, its length comes first) with the real PC, the hardware, the
+    // last write to 7FFD and the AY registers, followed by 16K memory blocks.
+    //
+    // Header: A, F, BC, HL, PC, SP, I, R, flags (bit 0 is bit 7 of R, bits 1-3 the
+    // border, bit 5 set if compressed), DE, BC', DE', HL', A', F', IY, IX, IFF1, IFF2 and
+    // the interrupt mode in bits 0-1. Pairs are little endian.
+    //
+    // Memory blocks are a 2 byte length (FFFF if stored uncompressed), the page number and
+    // the data. On 48K machines page 8 is 4000, 4 is 8000 and 5 is C000, on 128K machines
+    // page n is RAM bank n - 3.
+    //
+    // Compression replaces runs of 5 or more equal bytes, and of 2 or more EDs, with
+    // `ED ED count byte`. A byte following a single ED is never part of a run.
+    pub fn from_z80(data: &[u8]) -> Result<Self, String> {
+        if data.len() < Z80_HEADER {
+            return Err("File too short for a .z80 header".to_string());
+        }
+        let pair = |n: usize| u16::from_le_bytes([data[n], data[n + 1]]);
+        let pair_be = |n: usize| u16::from_be_bytes([data[n], data[n + 1]]);
+        let flags = if data[12] == 0xFF { 1 } else { data[12] };
+        let mut zx = Self {
+            cpu: Snapshot {
+                pc: pair(6),
+                sp: pair(8),
+                af: pair_be(0),
+                bc: pair(2),
+                de: pair(13),
+                hl: pair(4),
+                ix: pair(25),
+                iy: pair(23),
+                af_: pair_be(21),
+                bc_: pair(15),
+                de_: pair(17),
+                hl_: pair(19),
+                i: data[10],
+                r: data[11] & 0x7F | (flags & 0x01) << 7,
+                im: data[29] & 0x03,
+                iff1: data[27] != 0,
+                iff2: data[28] != 0,
+                halted: false,
+                irq: false,
+                nmi_pending: false,
+                vector: 0,
+                ei_pending: false,
+                cycles: 0,
+                memory: vec![0; ADDRESS_SPACE],
+            },
+            border: flags >> 1 & 0x07,
+            banks: Vec::new(),
+            port_7ffd: 0,
+            ay: None,
+        };
+        if zx.cpu.pc != 0 {
+            // Version 1
+            let ram = &data[Z80_HEADER..];
+            let ram = match flags & 0x20 != 0 {
+                // Ends with an 00 ED ED 00 marker
+                true => decompress(ram.strip_suffix(&[0x00, 0xED, 0xED, 0x00]).unwrap_or(ram))?,
+                false => ram.to_vec(),
+            };
+            if ram.len() != 0xC000 {
+                return Err(format!("Expected 48K of RAM, got {} bytes", ram.len()));
+            }
+            zx.cpu.memory[RAM_START as usize..].copy_from_slice(&ram);
+            return Ok(zx);
+        }
+
+        let extra = data
+            .get(30..32)
+            .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+            .ok_or("File too short for a .z80 v2 header")?;
+        let header = data
+            .get(32..32 + extra)
+            .filter(|_| matches!(extra, 23 | 54 | 55))
+            .ok_or_else(|| format!("Bad .z80 v2/v3 header length {}", extra))?;
+        zx.cpu.pc = u16::from_le_bytes([header[0], header[1]]);
+        let mode = header[2];
+        let is_128k = match extra {
+            23 => matches!(mode, 3 | 4),
+            _ => matches!(mode, 4..=7 | 9 | 12 | 13),
+        };
+        if is_128k {
+            zx.banks = vec![vec![0; BANK_SIZE]; 8];
+            zx.port_7ffd = header[3];
+        }
+        if is_128k || header[5] & 0x04 != 0 {
+            let mut registers = [0; 16];
+            registers.copy_from_slice(&header[7..23]);
+            zx.ay = Some(AyState {
+                selected: header[6] & 0x0F,
+                registers,
+            });
+        }
+
+        let mut blocks = &data[32 + extra..];
+        while !blocks.is_empty() {
+            if blocks.len() < 3 {
+                return Err("Truncated memory block".to_string());
+            }
+            let len = u16::from_le_bytes([blocks[0], blocks[1]]);
+            let page = blocks[2];
+            let stored = if len == 0xFFFF {
+                BANK_SIZE
+            } else {
+                len as usize
+            };
+            let block = blocks
+                .get(3..3 + stored)
+                .ok_or_else(|| format!("Truncated memory block for page {}", page))?;
+            blocks = &blocks[3 + stored..];
+            let block = match len {
+                0xFFFF => block.to_vec(),
+                _ => decompress(block)?,
+            };
+            if block.len() != BANK_SIZE {
+                return Err(format!("Page {} isn't 16K", page));
+            }
+            match (is_128k, page) {
+                (true, 3..=10) => zx.banks[page as usize - 3] = block,
+                (false, 4 | 5 | 8) => {
+                    let addr = match page {
+                        8 => 0x4000,
+                        4 => 0x8000,
+                        _ => 0xC000,
+                    };
+                    zx.cpu.memory[addr..addr + BANK_SIZE].copy_from_slice(&block);
+                }
+                // ROM pages and pages of interfaces that aren't emulated
+                _ => {}
+            }
+        }
+        if is_128k {
+            zx.page_banks();
+        }
+        Ok(zx)
+    }
+
+    // Writes version 3 with compressed memory blocks
+    pub fn to_z80(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let mut out = cpu.af.to_be_bytes().to_vec();
+        out.extend_from_slice(&cpu.bc.to_le_bytes());
+        out.extend_from_slice(&cpu.hl.to_le_bytes());
+        // PC zero, it's in the additional header
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&cpu.sp.to_le_bytes());
+        out.extend_from_slice(&[cpu.i, cpu.r & 0x7F, cpu.r >> 7 | (self.border & 0x07) << 1]);
+        for pair in [cpu.de, cpu.bc_, cpu.de_, cpu.hl_] {
+            out.extend_from_slice(&pair.to_le_bytes());
+        }
+        out.extend_from_slice(&cpu.af_.to_be_bytes());
+        out.extend_from_slice(&cpu.iy.to_le_bytes());
+        out.extend_from_slice(&cpu.ix.to_le_bytes());
+        out.extend_from_slice(&[cpu.iff1 as u8, cpu.iff2 as u8, cpu.im & 0x03]);
+
+        let mut header = vec![0; 54];
+        header[0..2].copy_from_slice(&cpu.pc.to_le_bytes());
+        header[2] = if self.is_128k() { 4 } else { 0 };
+        header[3] = self.port_7ffd;
+        if let Some(ay) = &self.ay {
+            if !self.is_128k() {
+                header[5] |= 0x04;
+            }
+            header[6] = ay.selected;
+            header[7..23].copy_from_slice(&ay.registers);
+        }
+        out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        out.extend_from_slice(&header);
+
+        let pages: Vec<(u8, &[u8])> = match self.is_128k() {
+            true => (0..8).map(|n| (n as u8 + 3, &self.banks[n][..])).collect(),
+            false => [(8, 0x4000), (4, 0x8000), (5, 0xC000)]
+                .iter()
+                .map(|&(page, addr)| (page, &cpu.memory[addr..addr + BANK_SIZE]))
+                .collect(),
+        };
+        for (page, data) in pages {
+            let block = compress(data);
+            out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            out.push(page);
+            out.extend_from_slice(&block);
+        }
+        out
+    }
+
+    // .szx (ZX-State): an 8 byte header, `ZXST`, the version, machine id (1 is 48K, 2 is
+    // 128K and up are 128K models) and flags, then chunks of a 4 character id, a 32-bit
+    // little endian length and the data. Chunks handled, others are skipped:
+    //
+    //   Z80R  AF, BC, DE, HL, AF', BC', DE', HL', IX, IY, SP, PC, I, R, IFF1, IFF2, IM,
+    //        the cycle count in the frame, hold int cycles, flags (bit 1 is HALT), MEMPTR
+    //   SPCR  border, last write to 7FFD, 1FFD and FE
+    //   RAMP  flags (bit 0 set if zlib compressed), the page number and its 16K
+    //   AY    flags, the selected register and the 16 registers
+    //
+    // On a 48K machine RAM pages 5, 2 and 0 are at 4000, 8000 and C000.
+    pub fn from_szx(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 8 || &data[..4] != b"ZXST" {
+            return Err("Not a .szx snapshot".to_string());
+        }
+        let is_128k = data[6] >= 2;
+        let mut zx = Self {
+            cpu: Snapshot::capture(&Cpu::default()),
+            border: 0,
+            banks: vec![vec![0; BANK_SIZE]; 8],
+            port_7ffd: 0,
+            ay: None,
+        };
+        zx.cpu.memory = vec![0; ADDRESS_SPACE];
+        let mut registers = false;
+        let mut chunks = &data[8..];
+        while !chunks.is_empty() {
+            let (id, chunk) = chunks
+                .get(8..)
+                .and_then(|rest| {
+                    let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]);
+                    Some((&chunks[..4], rest.get(..len as usize)?))
+                })
+                .ok_or("Truncated chunk")?;
+            chunks = &chunks[8 + chunk.len()..];
+            let short = || format!("{} chunk too short", String::from_utf8_lossy(id));
+            match id {
+                b"Z80R" => {
+                    if chunk.len() < 37 {
+                        return Err(short());
+                    }
+                    let pair = |n: usize| u16::from_le_bytes([chunk[n * 2], chunk[n * 2 + 1]]);
+                    let cpu = &mut zx.cpu;
+                    [cpu.af, cpu.bc, cpu.de, cpu.hl] = [pair(0), pair(1), pair(2), pair(3)];
+                    [cpu.af_, cpu.bc_, cpu.de_, cpu.hl_] = [pair(4), pair(5), pair(6), pair(7)];
+                    [cpu.ix, cpu.iy, cpu.sp, cpu.pc] = [pair(8), pair(9), pair(10), pair(11)];
+                    cpu.i = chunk[24];
+                    cpu.r = chunk[25];
+                    cpu.iff1 = chunk[26] != 0;
+                    cpu.iff2 = chunk[27] != 0;
+                    cpu.im = chunk[28] & 0x03;
+                    cpu.halted = chunk[34] & 0x02 != 0;
+                    registers = true;
+                }
+                b"SPCR" => {
+                    if chunk.len() < 4 {
+                        return Err(short());
+                    }
+                    zx.border = chunk[0] & 0x07;
+                    zx.port_7ffd = chunk[1];
+                }
+                b"RAMP" => {
+                    if chunk.len() < 3 {
+                        return Err(short());
+                    }
+                    let page = chunk[2] as usize;
+                    let ram = match chunk[0] & 0x01 != 0 {
+                        true => decompress_to_vec_zlib(&chunk[3..])
+                            .map_err(|e| format!("RAM page {}: {:?}", page, e.status))?,
+                        false => chunk[3..].to_vec(),
+                    };
+                    if ram.len() != BANK_SIZE || page > 7 {
+                        return Err(format!("Bad RAM page {}", page));
+                    }
+                    zx.banks[page] = ram;
+                }
+                b"AY\0\0" => {
+                    if chunk.len() < 18 {
+                        return Err(short());
+                    }
+                    let mut registers = [0; 16];
+                    registers.copy_from_slice(&chunk[2..18]);
+                    zx.ay = Some(AyState {
+                        selected: chunk[1] & 0x0F,
+                        registers,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if !registers {
+            return Err("No Z80R chunk".to_string());
+        }
+        if !is_128k {
+            // Paged the same way as a 128K machine after reset
+            zx.port_7ffd = 0;
+        }
+        zx.page_banks();
+        if !is_128k {
+            zx.banks.clear();
+        }
+        Ok(zx)
+    }
+
+    // Writes version 1.4 with compressed RAM pages
+    pub fn to_szx(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let machine = if self.is_128k() { 2 } else { 1 };
+        let mut out = b"ZXST".to_vec();
+        out.extend_from_slice(&[1, 4, machine, 0]);
+
+        let mut z80r = Vec::new();
+        for pair in [
+            cpu.af, cpu.bc, cpu.de, cpu.hl, cpu.af_, cpu.bc_, cpu.de_, cpu.hl_, cpu.ix, cpu.iy,
+            cpu.sp, cpu.pc,
+        ] {
+            z80r.extend_from_slice(&pair.to_le_bytes());
+        }
+        z80r.extend_from_slice(&[cpu.i, cpu.r, cpu.iff1 as u8, cpu.iff2 as u8, cpu.im]);
+        z80r.extend_from_slice(&[0; 5]);
+        z80r.extend_from_slice(&[(cpu.halted as u8) << 1, 0, 0]);
+        chunk(&mut out, b"Z80R", &z80r);
+        chunk(
+            &mut out,
+            b"SPCR",
+            &[self.border, self.port_7ffd, 0, self.border, 0, 0, 0, 0],
+        );
+
+        let pages: Vec<(u8, &[u8])> = match self.is_128k() {
+            true => (0..8).map(|n| (n as u8, &self.banks[n][..])).collect(),
+            false => [(5, 0x4000), (2, 0x8000), (0, 0xC000)]
+                .iter()
+                .map(|&(page, addr)| (page, &cpu.memory[addr..addr + BANK_SIZE]))
+                .collect(),
+        };
+        for (page, ram) in pages {
+            let mut ramp = vec![0x01, 0x00, page];
+            ramp.extend_from_slice(&compress_to_vec_zlib(ram, 6));
+            chunk(&mut out, b"RAMP", &ramp);
+        }
+        if let Some(ay) = &self.ay {
+            // Bit 1: AY on a 48K machine
+            let mut data = vec![if self.is_128k() { 0 } else { 0x02 }, ay.selected];
+            data.extend_from_slice(&ay.registers);
+            chunk(&mut out, b"AY\0\0", &data);
+        }
+        out
+    }
+
+    // Fills the RAM area of `cpu.memory` from the banks paged in by port 7FFD
+    fn page_banks(&mut self) {
+        let paged = (self.port_7ffd & 0x07) as usize;
+        for (addr, bank) in [(0x4000, 5), (0x8000, 2), (0xC000, paged)] {
+            self.cpu.memory[addr..addr + BANK_SIZE].copy_from_slice(&self.banks[bank]);
+        }
+    }
+
+    // Loads a snapshot, the format is picked by extension
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let data = archive::read(path)?;
+        match format(archive::inner(path)) {
+            Some(Format::Sna) => Self::from_sna(&data),
+            Some(Format::Z80) => Self::from_z80(&data),
+            Some(Format::Szx) => Self::from_szx(&data),
+            None => Err(format!("Unknown snapshot format {:?}", path)),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let data = match format(path) {
+            Some(Format::Sna) if self.is_128k() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Only 48K snapshots can be saved as .sna",
+                ))
+            }
+            Some(Format::Sna) => self.to_sna(),
+            Some(Format::Z80) => self.to_z80(),
+            Some(Format::Szx) => self.to_szx(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown snapshot format {:?}", path),
+                ))
+            }
+        };
+        fs::write(path, data)
+    }
+}
+
+enum Format {
+    Sna,
+    Z80,
+    Szx,
+}
+
+fn format(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "sna" => Some(Format::Sna),
+        "z80" => Some(Format::Z80),
+        "szx" => Some(Format::Szx),
+        _ => None,
+    }
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+// .z80 memory compression, see `ZxSnapshot::from_z80`
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut n = 0;
+    while n < data.len() {
+        let byte = data[n];
+        let run = data[n..]
+            .iter()
+            .take(255)
+            .take_while(|&&b| b == byte)
+            .count();
+        if run >= 5 || (byte == 0xED && run >= 2) {
+            out.extend_from_slice(&[0xED, 0xED, run as u8, byte]);
+            n += run;
+            continue;
+        }
+        out.push(byte);
+        n += 1;
+        // The byte after a single ED is taken as is
+        if byte == 0xED && n < data.len() {
+            out.push(data[n]);
+            n += 1;
+        }
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut n = 0;
+    while n < data.len() {
+        if data[n] == 0xED && data.get(n + 1) == Some(&0xED) {
+            let run = data.get(n + 2..n + 4).ok_or("Truncated compressed block")?;
+            out.extend(std::iter::repeat_n(run[1], run[0] as usize));
+            n += 4;
+        } else {
+            out.push(data[n]);
+            n += 1;
+        }
+    }
+    Ok(out)
+}
+
+// True for files `ZxSnapshot::load` understands
+pub fn is_zx_snapshot<P: AsRef<Path>>(path: P) -> bool {
+    format(path.as_ref()).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, is_zx_snapshot, AyState, ZxSnapshot, BANK_SIZE, SNA_48K};
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn sna() {
+        let mut cpu = Cpu::default();
+        cpu.reg.pc = 0x8123;
+        cpu.reg.sp = 0xFF00;
+        cpu.reg.a = 0x12;
+        cpu.reg.b_ = 0x34;
+        cpu.reg.iy = 0x5C3A;
+        cpu.reg.i = 0x3F;
+        cpu.int.mode = 1;
+        cpu.int.iff1 = true;
+        cpu.int.iff2 = true;
+        cpu.memory.load_slice(0x4000, &[0xAA; 32]);
+        cpu.memory.poke(0x0000, 0xF3);
+        let zx = ZxSnapshot::capture(&cpu, 2);
+
+        let data = zx.to_sna();
+        assert_eq!(data.len(), SNA_48K);
+        assert_eq!(data[26], 2);
+        // PC is pushed below SP
+        assert_eq!(data[27 + 0xFEFE - 0x4000..][..2], [0x23, 0x81]);
+        let loaded = ZxSnapshot::from_sna(&data).unwrap();
+        assert_eq!(loaded.cpu.pc, 0x8123);
+        assert_eq!(loaded.cpu.sp, 0xFF00);
+        assert_eq!(loaded.border, 2);
+
+        let mut restored = Cpu::default();
+        restored.memory.poke(0x0000, 0xC3);
+        restored.cycles = 1000;
+        loaded.restore(&mut restored);
+        assert_eq!(restored.reg.a, 0x12);
+        assert_eq!(restored.reg.b_, 0x34);
+        assert_eq!(restored.reg.iy, 0x5C3A);
+        assert_eq!(restored.reg.i, 0x3F);
+        assert_eq!(restored.int.mode, 1);
+        assert!(restored.int.iff1 && restored.int.iff2);
+        assert_eq!(restored.memory.peek(0x401F), 0xAA);
+        // ROM and the cycle count are left alone
+        assert_eq!(restored.memory.peek(0x0000), 0xC3);
+        assert_eq!(restored.cycles, 1000);
+
+        assert!(ZxSnapshot::from_sna(&data[1..]).is_err());
+        assert!(ZxSnapshot::from_sna(&data).unwrap().to_z80().len() < 1000);
+        assert!(is_zx_snapshot("GAME.SNA"));
+        assert!(is_zx_snapshot("game.z80"));
+        assert!(!is_zx_snapshot("game.snap"));
+    }
+
+    #[test]
+    fn z80_compression() {
+        let data = [
+            1, 2, 2, 2, 2, 2, 0xED, 0xED, 0xED, 0x00, 0xED, 3, 3, 3, 3, 3, 3,
+        ];
+        let packed = compress(&data);
+        assert_eq!(
+            packed,
+            [1, 0xED, 0xED, 5, 2, 0xED, 0xED, 3, 0xED, 0, 0xED, 3, 0xED, 0xED, 5, 3]
+        );
+        assert_eq!(decompress(&packed).unwrap(), data);
+        assert_eq!(compress(&[0; 300]), [0xED, 0xED, 255, 0, 0xED, 0xED, 45, 0]);
+        assert!(decompress(&[0xED, 0xED, 5]).is_err());
+    }
+
+    #[test]
+    fn z80() {
+        let mut cpu = Cpu::default();
+        cpu.reg.pc = 0x6000;
+        cpu.reg.sp = 0x7F00;
+        cpu.reg.a = 0x3E;
+        cpu.flags.set(0x41);
+        cpu.reg.r = 0x85;
+        cpu.reg.d_ = 0x99;
+        cpu.int.mode = 2;
+        cpu.int.iff2 = true;
+        cpu.memory.load_slice(0xC000, &[0xED, 0xED, 0x01]);
+        let zx = ZxSnapshot::capture(&cpu, 5);
+        let mut loaded = ZxSnapshot::from_z80(&zx.to_z80()).unwrap();
+        // The ROM area isn't saved
+        loaded.cpu.memory[..0x4000].copy_from_slice(&zx.cpu.memory[..0x4000]);
+        assert_eq!(loaded, zx);
+
+        // Version 1, compressed
+        let mut v1 = zx.to_z80()[..30].to_vec();
+        v1[6..8].copy_from_slice(&0x6000u16.to_le_bytes());
+        v1[12] |= 0x20;
+        v1.extend(compress(&zx.cpu.memory[0x4000..]));
+        v1.extend([0x00, 0xED, 0xED, 0x00]);
+        let loaded = ZxSnapshot::from_z80(&v1).unwrap();
+        assert_eq!(loaded.cpu.pc, 0x6000);
+        assert_eq!(loaded.cpu.r, 0x85);
+        assert_eq!(loaded.border, 5);
+        assert_eq!(loaded.cpu.memory[0xC000..0xC003], [0xED, 0xED, 0x01]);
+        assert!(ZxSnapshot::from_z80(&v1[..40]).is_err());
+
+        // 128K with bank 3 paged in at C000
+        let mut zx = zx;
+        zx.banks = (0..8).map(|n| vec![n as u8; BANK_SIZE]).collect();
+        zx.port_7ffd = 0x13;
+        zx.ay = Some(AyState {
+            selected: 7,
+            registers: [0x3F; 16],
+        });
+        let loaded = ZxSnapshot::from_z80(&zx.to_z80()).unwrap();
+        assert!(loaded.is_128k());
+        assert_eq!(loaded.banks, zx.banks);
+        assert_eq!(loaded.port_7ffd, 0x13);
+        assert_eq!(loaded.ay, zx.ay);
+        assert_eq!(loaded.cpu.memory[0x4000], 5);
+        assert_eq!(loaded.cpu.memory[0x8000], 2);
+        assert_eq!(loaded.cpu.memory[0xC000], 3);
+    }
+
+    #[test]
+    fn szx() {
+        let mut cpu = Cpu::default();
+        cpu.reg.pc = 0x8000;
+        cpu.reg.sp = 0x6000;
+        cpu.reg.ix = 0x1234;
+        cpu.flags.set(0xC3);
+        cpu.reg.r = 0x80;
+        cpu.int.mode = 1;
+        cpu.int.iff1 = true;
+        cpu.int.halt = true;
+        cpu.memory.load_slice(0x4000, &[0x11; 0x1800]);
+        cpu.memory.poke(0xFFFF, 0x22);
+        let mut zx = ZxSnapshot::capture(&cpu, 3);
+        zx.ay = Some(AyState {
+            selected: 14,
+            registers: [0x10; 16],
+        });
+        let data = zx.to_szx();
+        assert_eq!(data[..8], *b"ZXST\x01\x04\x01\x00");
+        let mut loaded = ZxSnapshot::from_szx(&data).unwrap();
+        loaded.cpu.memory[..0x4000].copy_from_slice(&zx.cpu.memory[..0x4000]);
+        assert_eq!(loaded, zx);
+
+        zx.banks = (0..8).map(|n| vec![n as u8 * 3; BANK_SIZE]).collect();
+        zx.port_7ffd = 0x07;
+        let loaded = ZxSnapshot::from_szx(&zx.to_szx()).unwrap();
+        assert_eq!(loaded.banks, zx.banks);
+        assert_eq!(loaded.cpu.memory[0xC000], 21);
+        assert_eq!(loaded.ay, zx.ay);
+
+        assert!(ZxSnapshot::from_szx(b"ZXST\x01\x04\x01\x00").is_err());
+        assert!(ZxSnapshot::from_szx(&data[..60]).is_err());
+    }
+}